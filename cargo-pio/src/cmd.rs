@@ -0,0 +1,48 @@
+//! The subcommands of cargo-pio, one module per subcommand or family of subcommands.
+
+pub mod account;
+pub mod assets;
+pub mod auth;
+pub mod board;
+pub mod build;
+pub mod bump;
+pub mod check_config;
+pub mod ci;
+pub mod compat;
+pub mod complete;
+pub mod containerize;
+pub mod coredump;
+pub mod daemon;
+pub mod device;
+pub mod diff;
+pub mod efuse;
+pub mod env;
+pub mod espidf;
+pub mod export;
+pub mod flash;
+pub mod home;
+pub mod layout;
+pub mod ldscript;
+pub mod licenses;
+pub mod linkcheck;
+pub mod matrix;
+pub mod metrics;
+pub mod monitor;
+pub mod ota;
+pub mod pkg;
+pub mod platformio;
+pub mod ports;
+pub mod probe;
+pub mod project;
+pub mod release;
+pub mod remote;
+pub mod report;
+pub mod run_task;
+pub mod schema;
+pub mod sdk;
+pub mod secure;
+pub mod self_test;
+pub mod setup;
+pub mod stack;
+pub mod symbols;
+pub mod zephyr;
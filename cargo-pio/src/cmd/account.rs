@@ -0,0 +1,88 @@
+use anyhow::Result;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub enum AccountCommand {
+    /// Logs into the PlatformIO account and stores its token, asking for the username and password
+    Login {
+        /// Stores an existing token (e.g. of 'pio account token') instead, read from the terminal (or stdin if it is piped)
+        #[structopt(long)]
+        token: bool,
+    },
+    /// Deletes the stored token
+    Logout,
+    /// Prints the account of the stored token
+    Status,
+    /// Lists the devices attached to the PIO Remote agents of the account, with their ports for '--port'
+    Devices,
+}
+
+pub fn run(
+    pio_install: PioInstallation,
+    cmd: AccountCommand,
+    pio_log_level: LogLevel,
+) -> Result<()> {
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+    match cmd {
+        AccountCommand::Login { token: true } => {
+            if terminal::is_interactive() {
+                eprint!("Token of the PlatformIO account: ");
+            }
+
+            let token = terminal::read_secret()?;
+            if terminal::is_interactive() {
+                eprintln!();
+            }
+
+            credentials::validate(&token)?;
+            credentials::store(account::HOST, &token)?;
+
+            info!("Stored the token of the PlatformIO account");
+        }
+        AccountCommand::Login { token: false } => {
+            eprint!("Username or email: ");
+
+            let mut username = String::new();
+            std::io::stdin().read_line(&mut username)?;
+
+            eprint!("Password: ");
+            let password = terminal::read_secret()?;
+            eprintln!();
+
+            account::login(&pio, username.trim(), &password)?;
+
+            info!("Logged in, stored the token of the PlatformIO account");
+        }
+        AccountCommand::Logout => {
+            if credentials::delete(account::HOST)? {
+                info!("Deleted the token of the PlatformIO account");
+            } else {
+                info!("No token of a PlatformIO account is stored");
+            }
+        }
+        AccountCommand::Status => {
+            let mut cmd = pio.cmd();
+            cmd.arg("account").arg("show");
+
+            if !account::authenticate(&mut cmd)? {
+                println!("Not logged in");
+                return Ok(());
+            }
+
+            pio.exec(&mut cmd)?;
+        }
+        AccountCommand::Devices => {
+            for device in account::remote_devices(&pio)? {
+                println!("{:<40} {}", device.port(), device.device.description);
+            }
+        }
+    }
+
+    Ok(())
+}
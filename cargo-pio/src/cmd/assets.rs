@@ -0,0 +1,18 @@
+use std::env;
+
+use anyhow::Result;
+use embuild::pio::*;
+use log::*;
+
+pub fn run() -> Result<()> {
+    let project = env::current_dir()?;
+    let config = config::Config::load(&project)?;
+
+    if config.assets.files.is_empty() {
+        warn!("No assets configured in {}", config::CONFIG_FILE_NAME);
+    }
+
+    assets::generate(&config.assets, &project)?;
+
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use anyhow::{anyhow, Result};
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum AuthCommand {
+    /// Stores the token for a host, read from the terminal (or stdin if it is piped)
+    Login {
+        /// The host, or a URL on it
+        host: String,
+    },
+    /// Deletes the token stored for a host
+    Logout {
+        /// The host, or a URL on it
+        host: String,
+    },
+    /// Prints whether a token is stored for a host
+    Status {
+        /// The host, or a URL on it
+        host: String,
+    },
+}
+
+pub fn run(cmd: AuthCommand) -> Result<()> {
+    let (AuthCommand::Login { host } | AuthCommand::Logout { host } | AuthCommand::Status { host }) =
+        &cmd;
+
+    let host = credentials::host(host).ok_or_else(|| anyhow!("'{}' is no host or URL", host))?;
+
+    match cmd {
+        AuthCommand::Login { .. } => {
+            if terminal::is_interactive() {
+                eprint!("Token for {}: ", host);
+            }
+
+            let token = terminal::read_secret()?;
+            if terminal::is_interactive() {
+                eprintln!();
+            }
+
+            credentials::validate(&token)?;
+            credentials::store(&host, &token)?;
+
+            info!("Stored the token for {}", host);
+        }
+        AuthCommand::Logout { .. } => {
+            if credentials::delete(&host)? {
+                info!("Deleted the token for {}", host);
+            } else {
+                info!("No token is stored for {}", host);
+            }
+        }
+        AuthCommand::Status { .. } => {
+            if credentials::load(&host)?.is_some() {
+                println!("{}: token stored", host);
+            } else {
+                println!("{}: no token", host);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::env;
+
+use anyhow::{anyhow, Result};
+use embuild::pio::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct BoardArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// PlatformIO environment whose board to show. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+}
+
+pub fn run(args: BoardArgs, pio_log_level: LogLevel) -> Result<()> {
+    let BoardArgs {
+        pio_install,
+        environment,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+    let project = env::current_dir()?;
+    let environment = environment.as_deref().unwrap_or("debug");
+
+    let config = config::Config::load(&project)?;
+    let board = board::BoardInfo::load(
+        &pio,
+        &config.board.unwrap_or_default(),
+        &project,
+        environment,
+    )?
+    .ok_or_else(|| anyhow!("Environment {} has no board", environment))?;
+
+    let size = |size: Option<u64>| {
+        size.map(graph::format_size)
+            .unwrap_or_else(|| "unknown".into())
+    };
+
+    println!("Board:         {} ({})", board.name, board.id);
+    println!("MCU:           {}", board.mcu);
+    println!(
+        "Frequency:     {}",
+        board
+            .f_cpu
+            .map(|f_cpu| format!("{} MHz", f_cpu / 1_000_000))
+            .unwrap_or_else(|| "unknown".into())
+    );
+    println!("RAM:           {}", size(board.ram_size));
+    println!("Flash:         {}", size(board.flash_size));
+    println!("Max. firmware: {}", size(board.max_firmware_size));
+
+    for (alias, pin) in &board.pins {
+        println!("Pin {}: {}", alias, pin);
+    }
+
+    Ok(())
+}
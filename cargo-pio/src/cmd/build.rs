@@ -0,0 +1,421 @@
+use std::path::Path;
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Context, Result};
+use embuild::error::HintExt;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+use super::linkcheck::{link_map, report_link_diagnostics};
+use super::metrics::record_metrics;
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct BuildArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// Performs a release build
+    ///
+    /// Equivalent to '-e release'
+    #[structopt(long, short, conflicts_with = "environment")]
+    release: bool,
+
+    /// PlatformIO environment to build. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// Builds the environment of the connected board, of the build type selected by '--release'
+    ///
+    /// The board is detected by the USB ids of the serial devices and debug probes, and the
+    /// chip reported by esptool. Prompts if several environments match
+    #[structopt(long, conflicts_with = "environment")]
+    detect: bool,
+}
+
+pub fn run(args: BuildArgs, pio_log_level: LogLevel) -> Result<()> {
+    let BuildArgs {
+        pio_install,
+        release,
+        environment,
+        detect,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+    let project = env::current_dir()?;
+
+    let environment = if detect {
+        Some(detect_environment(&pio, &project, release, false)?.0)
+    } else {
+        environment
+    };
+
+    build(
+        &pio,
+        &project,
+        environment
+            .as_deref()
+            .unwrap_or(if release { "release" } else { "debug" }),
+    )
+}
+
+/// The environment of the board connected to this machine, of the build type selected by
+/// `release` if both a debug and a release environment match, and the serial port of
+/// the board if `with_port`.
+///
+/// If several boards or environments match, the user selects one if stdin is a terminal.
+pub fn detect_environment(
+    pio: &Pio,
+    project: &Path,
+    release: bool,
+    with_port: bool,
+) -> Result<(String, Option<String>)> {
+    let platformio_ini = fs::read_to_string(project.join("platformio.ini"))
+        .context("Failed to read platformio.ini")?;
+    let board_config = config::Config::load(project)?.board.unwrap_or_default();
+
+    let mut environments = Vec::new();
+    for environment in pio_model::environments(&platformio_ini) {
+        if let Some(board) = board::BoardInfo::load(pio, &board_config, project, &environment)? {
+            environments.push((environment, board));
+        }
+    }
+
+    let probe_chips = environments
+        .iter()
+        .any(|(_, board)| board.mcu.to_lowercase().starts_with("esp"));
+    let devices = detect::devices(pio, probe_chips)?;
+
+    let build_type = |environment: &str| {
+        // PlatformIO builds release firmware if no build type is set
+        pio_model::env_option(&platformio_ini, environment, "build_type")
+            .unwrap_or_else(|| "release".to_owned())
+    };
+
+    let mut candidates = detect::candidates(
+        &environments,
+        build_type,
+        if release { "release" } else { "debug" },
+        &devices,
+    );
+
+    // A board with an onboard probe shows up both as a serial device and as a debug probe
+    let with_serial = candidates
+        .iter()
+        .filter(|c| c.device.port.is_some())
+        .map(|c| c.environment.clone())
+        .collect::<Vec<_>>();
+    candidates.retain(|c| c.device.port.is_some() || !with_serial.contains(&c.environment));
+    if !with_port {
+        candidates.dedup_by(|a, b| a.environment == b.environment);
+    }
+
+    let candidate = match candidates.len() {
+        0 => {
+            return Err(anyhow!(
+                "No connected board matches the board of an environment"
+            ))
+            .with_hint(|| {
+                format!(
+                    "Connected devices: {}. Select the environment with '-e <environment>'",
+                    if devices.is_empty() {
+                        "none".to_owned()
+                    } else {
+                        devices
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    }
+                )
+            })
+        }
+        1 => candidates.remove(0),
+        _ if terminal::is_interactive() => select_candidate(candidates)?,
+        _ => bail!(
+            "Several connected boards match, select one with '-e <environment>': {}",
+            candidates
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    info!("Detected {}", candidate);
+
+    Ok((candidate.environment, candidate.device.port))
+}
+
+fn select_candidate(mut candidates: Vec<detect::Candidate>) -> Result<detect::Candidate> {
+    use std::io::BufRead;
+
+    eprintln!("Several connected boards match:");
+    for (index, candidate) in candidates.iter().enumerate() {
+        eprintln!("  {}) {}", index + 1, candidate);
+    }
+
+    loop {
+        eprint!("Select [1-{}]: ", candidates.len());
+
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+
+        if let Some(index) = answer
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|index| (1..=candidates.len()).contains(index))
+        {
+            return Ok(candidates.swap_remove(index - 1));
+        }
+    }
+}
+
+pub fn build(pio: &Pio, project: impl AsRef<Path>, environment: &str) -> Result<()> {
+    let project = project.as_ref();
+
+    build_with_config(pio, &config::Config::load(project)?, project, environment)
+}
+
+pub fn build_with_config(
+    pio: &Pio,
+    config: &config::Config,
+    project: &Path,
+    environment: &str,
+) -> Result<()> {
+    // Other environments build concurrently, but not this one
+    let _lock = artifacts::lock(project, environment)?;
+
+    let start = std::time::SystemTime::now();
+
+    config.run_hook(config::Hook::PreBuild, pio, project, environment)?;
+
+    assets::generate(&config.assets, project)?;
+    ldscript::generate(pio, config, project, environment, false)?;
+    sdk::install_all(
+        project,
+        &config.sdk,
+        Some(&credentials::KeyringClient),
+        false,
+    )?;
+    if config.espidf.registry {
+        idf_registry::install_all(
+            project,
+            &credentials::KeyringClient,
+            false,
+            config.espidf.as_of.as_deref(),
+        )?;
+    }
+    components::apply(pio, &config.espidf, project, environment)?;
+    external::apply(&config.espidf, project, environment)?;
+
+    if let Err(err) = cargo_config::sync(pio, project, environment) {
+        warn!("Failed to update the Cargo config: {:#}", err);
+    }
+
+    let build_dir = project.join(".pio").join("build").join(environment);
+
+    let fingerprint = fingerprint::Fingerprint::collect(pio, project, environment)?;
+    if let Some(last) = fingerprint::Fingerprint::load(&build_dir) {
+        if last.key != fingerprint.key {
+            info!(
+                "The toolchain changed since the last build ({}), cleaning environment {}",
+                fingerprint.changes(&last).join(", "),
+                environment
+            );
+
+            notify::send(
+                &config.notify,
+                &notify::Notification {
+                    environment: Some(environment.to_owned()),
+                    toolchain: fingerprint.components.clone(),
+                    previous_toolchain: last.components.clone(),
+                    ..notify::Notification::new(notify::Event::ToolchainChange, true, project)
+                },
+            );
+
+            let mut cmd = pio.run_cmd();
+            cmd.arg("-d")
+                .arg(project)
+                .args(["-t", "clean", "-e", environment]);
+
+            pio.exec(&mut cmd)?;
+        }
+    }
+
+    abi::preflight(pio, project, environment, &fingerprint.key)?;
+    lint::preflight(
+        project,
+        environment,
+        config
+            .env(environment)
+            .and_then(|env| env.profile.as_deref()),
+    )?;
+
+    let (mut cmd, _) = build_cmd(pio, config, project, environment, &fingerprint)?;
+
+    let compiler_cache = config
+        .compiler_cache
+        .as_ref()
+        .map(compiler_cache::CompilerCache::new)
+        .transpose()?;
+    if let Some(compiler_cache) = &compiler_cache {
+        compiler_cache.zero_stats()?;
+    }
+
+    let (status, output) = pio
+        .clone()
+        .timeout(config.timeouts.build())
+        .exec_capture(&mut cmd)?;
+
+    // Failing to report the statistics should not fail the build
+    let cache_stats =
+        compiler_cache
+            .as_ref()
+            .and_then(|compiler_cache| match compiler_cache.stats() {
+                Ok(stats) => {
+                    info!(
+                        "{}: {} hit(s), {} miss(es){}",
+                        compiler_cache.launcher.name(),
+                        stats.hits,
+                        stats.misses,
+                        stats
+                            .hit_rate()
+                            .map(|rate| format!(", {:.0}% hit rate", rate * 100.0))
+                            .unwrap_or_default()
+                    );
+
+                    Some(stats)
+                }
+                Err(err) => {
+                    warn!("{:#}", err);
+                    None
+                }
+            });
+
+    fs::create_dir_all(&build_dir)?;
+    fs::write(build_dir.join(report::BUILD_LOG_FILE), &output)?;
+
+    notify::send(
+        &config.notify,
+        &notify::Notification {
+            environment: Some(environment.to_owned()),
+            toolchain: fingerprint.components.clone(),
+            ..notify::Notification::new(notify::Event::Build, status.success(), project)
+        },
+    );
+
+    if config.metrics.record {
+        // Failing to record the metrics should not fail the build either
+        if let Err(err) = record_metrics(
+            config,
+            project,
+            environment,
+            start,
+            status.success(),
+            cache_stats,
+        ) {
+            warn!("{:#}", err);
+        }
+    }
+
+    if status.success() {
+        // `--allow-multiple-definition` is passed to the linker, so duplicates of
+        // anything other than compiler intrinsics would otherwise go unnoticed
+        if let Some(map) = link_map(project, environment)? {
+            let diagnostics = map
+                .diagnostics(&[] as &[&str])
+                .into_iter()
+                .filter(|d| !matches!(d, linkmap::Diagnostic::DuplicateSymbol { symbol, .. } if symbol.starts_with("__")))
+                .collect::<Vec<_>>();
+
+            report_link_diagnostics(&diagnostics);
+        }
+
+        fingerprint.save(&build_dir)?;
+
+        if let Some(mcuboot) = config.env(environment).and_then(|env| env.mcuboot.as_ref()) {
+            mcuboot::image(pio, mcuboot, project, environment)?;
+        }
+
+        postprocess::run(pio, config, project, environment)?;
+
+        config.run_hook(config::Hook::PostBuild, pio, project, environment)
+    } else {
+        let mut diagnostics = linkmap::analyze_output(&output);
+
+        if let Some(map) = link_map(project, environment)? {
+            diagnostics.extend(map.diagnostics(&map.framework_entry_points()));
+        }
+
+        report_link_diagnostics(&diagnostics);
+
+        bail!("Building environment {} failed", environment)
+    }
+}
+
+/// The `pio run` command building `environment`, and the trace of where the environment
+/// variables it sets come from.
+pub fn build_cmd(
+    pio: &Pio,
+    config: &config::Config,
+    project: &Path,
+    environment: &str,
+    fingerprint: &fingerprint::Fingerprint,
+) -> Result<(std::process::Command, inspect::EnvTrace)> {
+    let mut trace = inspect::EnvTrace::new();
+
+    let mut cmd = pio.run_cmd();
+    trace.record(&cmd, "PlatformIO");
+
+    cmd.env(fingerprint::VAR_TOOLCHAIN_KEY, &fingerprint.key);
+    trace.record(
+        &cmd,
+        format!(
+            "toolchain fingerprint ({})",
+            fingerprint.components.join(", ")
+        ),
+    );
+
+    cmd.arg("-e").arg(environment);
+    config.apply_env(environment, &mut cmd);
+    trace.record(
+        &cmd,
+        format!("{} [env.{}]", config::CONFIG_FILE_NAME, environment),
+    );
+
+    if config.espidf.registry {
+        // The components are installed by cargo-pio
+        cmd.env("IDF_COMPONENT_MANAGER", "0");
+        trace.record(&cmd, format!("{} [espidf]", config::CONFIG_FILE_NAME));
+    }
+
+    if let Some(compiler_cache) = &config.compiler_cache {
+        compiler_cache::CompilerCache::new(compiler_cache)?.apply(&mut cmd);
+        trace.record(
+            &cmd,
+            format!("{} [compiler-cache]", config::CONFIG_FILE_NAME),
+        );
+    }
+
+    if let Some(stamp_config) = &config.stamp {
+        stamp::Stamp::collect(config, project, environment, stamp_config.timestamp)?.apply(
+            stamp_config,
+            project,
+            &mut cmd,
+        )?;
+        trace.record(&cmd, format!("{} [stamp]", config::CONFIG_FILE_NAME));
+    }
+
+    if let Some(board_config) = &config.board {
+        if let Some(board) = board::BoardInfo::load(pio, board_config, project, environment)? {
+            board.apply_to(board_config, project, &mut cmd)?;
+            trace.record(&cmd, format!("{} [board]", config::CONFIG_FILE_NAME));
+        }
+    }
+
+    Ok((cmd, trace))
+}
@@ -0,0 +1,31 @@
+use std::env;
+
+use anyhow::Result;
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct BumpArgs {
+    /// The version component to increment
+    #[structopt(possible_values = &["patch", "minor", "major"])]
+    level: bump::Level,
+
+    /// Bumps the version even if the project has uncommitted changes
+    #[structopt(long)]
+    force: bool,
+}
+
+pub fn run(args: BumpArgs) -> Result<()> {
+    let BumpArgs { level, force } = args;
+
+    let bump = bump::bump(env::current_dir()?, level, force)?;
+
+    for file in &bump.files {
+        info!("Updated {}", file.display());
+    }
+
+    println!("{}", bump.new);
+
+    Ok(())
+}
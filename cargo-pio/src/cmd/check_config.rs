@@ -0,0 +1,90 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct CheckConfigArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// The project directory. Defaults to the current directory
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+
+    /// Fixes the problems which can be fixed automatically
+    #[structopt(long)]
+    fix: bool,
+}
+
+pub fn run(args: CheckConfigArgs, pio_log_level: LogLevel) -> Result<()> {
+    let CheckConfigArgs {
+        pio_install,
+        path,
+        fix,
+    } = args;
+
+    check_config(
+        &path.unwrap_or(env::current_dir()?),
+        pio_install,
+        pio_log_level,
+        fix,
+    )
+}
+
+fn check_config(
+    project: &Path,
+    pio_install: PioInstallation,
+    pio_log_level: LogLevel,
+    fix: bool,
+) -> Result<()> {
+    // The MCUs of the boards are only needed for environments without `board_build.mcu`,
+    // so a missing PlatformIO only skips those checks
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)
+        .map_err(|err| warn!("Not checking the targets against the boards: {}", err))
+        .ok();
+
+    let board_mcu = |board: &str| {
+        pio.as_ref()?
+            .boards(Some(board))
+            .ok()?
+            .into_iter()
+            .find(|b| b.id == board)
+            .map(|b| b.mcu)
+    };
+
+    let mut issues = lint::check(project, board_mcu)?;
+
+    if fix {
+        for file in lint::fix(project, &issues)? {
+            info!("Fixed {}", file.display());
+        }
+
+        issues.retain(|issue| !issue.is_fixable());
+    }
+
+    if issues.is_empty() {
+        info!("No configuration problems found");
+        return Ok(());
+    }
+
+    for issue in &issues {
+        if issue.is_error() {
+            error!("{}", issue);
+        } else {
+            warn!("{}", issue);
+        }
+    }
+
+    let errors = issues.iter().filter(|issue| issue.is_error()).count();
+    if errors > 0 {
+        bail!("Found {} configuration error(s)", errors);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use std::env;
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum CiCommand {
+    /// Generates or updates a pipeline definition which fetches, builds, tests and reports the size of the project
+    Init {
+        /// CI provider: 'github' (.github/workflows/cargo-pio.yml) or 'gitlab' (.gitlab-ci.yml)
+        #[structopt(long, default_value = "github", possible_values = &["github", "gitlab"])]
+        provider: ci::Provider,
+
+        /// PlatformIO environment to build. Defaults to 'release'
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+
+        /// Print the diff of the pipeline definition and fail if it is not up-to-date, instead of writing it
+        #[structopt(long)]
+        check: bool,
+    },
+}
+
+pub fn run(cmd: CiCommand) -> Result<()> {
+    match cmd {
+        CiCommand::Init {
+            provider,
+            environment,
+            check,
+        } => {
+            let environment = environment.as_deref().unwrap_or("release");
+
+            if check {
+                if let Some(diff) = provider.diff(env::current_dir()?, environment)? {
+                    print!("{}", diff);
+                    bail!(
+                        "Not up-to-date: {}, run without --check to update",
+                        provider.path()
+                    );
+                }
+
+                return Ok(());
+            }
+
+            let outcome = provider.generate(env::current_dir()?, environment)?;
+
+            info!("{} {:?}", provider.path(), outcome);
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::{env, fs};
+
+use anyhow::{anyhow, Context, Result};
+use embuild::error::HintExt;
+use embuild::pio::*;
+use embuild::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub enum CompatCommand {
+    /// Prints the compatibility matrix of the platform, ESP-IDF, Arduino core and GCC versions
+    List,
+    /// Checks that the pinned platform, framework and toolchain versions of the environments fit together
+    Check {
+        /// PlatformIO environment to check, can be repeated. Defaults to all environments
+        #[structopt(long = "environment", short = "e")]
+        environments: Vec<String>,
+    },
+    /// Pins the platform, framework and toolchain versions compatible with an ESP-IDF or Arduino core version, and installs them
+    Pin {
+        #[structopt(flatten)]
+        pio_install: PioInstallation,
+
+        /// PlatformIO environment to pin, can be repeated. Defaults to all environments
+        #[structopt(long = "environment", short = "e")]
+        environments: Vec<String>,
+
+        /// The framework: 'espidf' or 'arduino'
+        #[structopt(long, default_value = "espidf", possible_values = &["espidf", "arduino"])]
+        framework: compat::Framework,
+
+        /// The framework version, e.g. '5.1' or '5.1.2'
+        version: String,
+    },
+}
+
+pub fn run(cmd: CompatCommand, pio_log_level: LogLevel) -> Result<()> {
+    let platformio_ini =
+        || fs::read_to_string("platformio.ini").context("Failed to read platformio.ini");
+
+    match cmd {
+        CompatCommand::List => {
+            print!("{}", compat::table());
+            Ok(())
+        }
+        CompatCommand::Check { mut environments } => {
+            let platformio_ini = platformio_ini()?;
+
+            if environments.is_empty() {
+                environments = pio_model::environments(&platformio_ini);
+            }
+
+            let mut consistent = true;
+            for environment in &environments {
+                for problem in compat::check(&platformio_ini, environment) {
+                    println!("{}: {}", environment, problem);
+                    consistent = false;
+                }
+            }
+
+            if !consistent {
+                return Err(anyhow!("The pinned versions do not fit together"))
+                    .hint("Pin a consistent set with `cargo pio compat pin <version>`");
+            }
+
+            Ok(())
+        }
+        CompatCommand::Pin {
+            pio_install,
+            mut environments,
+            framework,
+            version,
+        } => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+            if environments.is_empty() {
+                environments = pio_model::environments(&platformio_ini()?);
+            }
+
+            let project = env::current_dir()?;
+
+            for environment in &environments {
+                compat::pin_environment(&pio, &project, environment, framework, &version)?;
+            }
+
+            retention::enforce(
+                &pio,
+                &config::Config::load(&project)?.retention,
+                &project,
+                false,
+            )?;
+
+            Ok(())
+        }
+    }
+}
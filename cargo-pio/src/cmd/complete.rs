@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+use anyhow::Result;
+use embuild::pio::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct ListEnvsArgs {
+    /// The project directory. Defaults to the current directory
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ListBoardsArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// The project directory, whose own boards are listed too. Defaults to the current directory
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ListPortsArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+}
+
+pub fn list_envs(args: ListEnvsArgs) -> Result<()> {
+    let ListEnvsArgs { path } = args;
+
+    let path = path.unwrap_or(env::current_dir()?);
+    let platformio_ini = fs::read_to_string(path.join("platformio.ini")).unwrap_or_default();
+
+    for environment in complete::environments(&platformio_ini) {
+        println!("{}", environment);
+    }
+
+    Ok(())
+}
+
+pub fn list_boards(args: ListBoardsArgs) -> Result<()> {
+    let ListBoardsArgs { pio_install, path } = args;
+
+    let path = path.unwrap_or(env::current_dir()?);
+    let core_dir = complete::core_dir(pio_install.pio_path);
+
+    for board in complete::boards(core_dir.as_deref(), path) {
+        println!("{}", board);
+    }
+
+    Ok(())
+}
+
+pub fn list_ports(args: ListPortsArgs) -> Result<()> {
+    let ListPortsArgs { pio_install } = args;
+
+    let core_dir = complete::core_dir(pio_install.pio_path);
+
+    for port in complete::ports(core_dir.as_deref()) {
+        println!("{}", port);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct ContainerizeArgs {
+    /// The project directory. Defaults to the current directory
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+
+    /// Print the diffs of the files and fail if they are not up-to-date, instead of writing them
+    #[structopt(long)]
+    check: bool,
+}
+
+pub fn run(args: ContainerizeArgs) -> Result<()> {
+    let ContainerizeArgs { path, check } = args;
+
+    let project_dir = path.unwrap_or(env::current_dir()?);
+
+    let spec = container::Spec::from_project(&project_dir, env!("CARGO_PKG_VERSION"))?;
+
+    if check {
+        let diffs = spec.diffs(&project_dir)?;
+
+        if !diffs.is_empty() {
+            for (_, diff) in &diffs {
+                print!("{}", diff);
+            }
+
+            bail!(
+                "Not up-to-date: {}, run without --check to update",
+                diffs
+                    .iter()
+                    .map(|(path, _)| *path)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        return Ok(());
+    }
+
+    if spec.environments.is_empty() {
+        warn!("No environments found in platformio.ini, no packages will be pre-installed");
+    }
+
+    for (path, outcome) in spec.generate(&project_dir)? {
+        info!("{} {:?}", path, outcome);
+    }
+
+    Ok(())
+}
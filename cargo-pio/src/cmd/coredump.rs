@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{bail, Result};
+use embuild::error::HintExt;
+use embuild::messages::Message;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+use tempfile::TempDir;
+
+use super::flash::esptool_cmd;
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct CoredumpArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// PlatformIO environment whose firmware crashed. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// Port of the device to read the core dump from. Auto-detected if not specified
+    ///
+    /// May be a serial-over-TCP bridge ('rfc2217://<host>:<port>' or 'tcp://<host>:<port>')
+    #[structopt(long, short = "p")]
+    port: Option<String>,
+
+    /// Captured console output containing the core dump printed to UART
+    #[structopt(long, parse(from_os_str))]
+    log: Option<PathBuf>,
+
+    /// Firmware ELF file. Defaults to the firmware of the environment
+    #[structopt(long, parse(from_os_str))]
+    elf: Option<PathBuf>,
+
+    /// Binary partition table. Defaults to the partition table of the environment
+    #[structopt(long, parse(from_os_str))]
+    partition_table: Option<PathBuf>,
+
+    /// GDB executable. If not specified, it is searched in PATH and in the toolchains installed by PlatformIO
+    #[structopt(long, parse(from_os_str))]
+    gdb: Option<PathBuf>,
+
+    /// Save the extracted ELF core file to this path
+    #[structopt(long, parse(from_os_str))]
+    save: Option<PathBuf>,
+}
+
+pub fn run(args: CoredumpArgs, pio_log_level: LogLevel) -> Result<()> {
+    let CoredumpArgs {
+        pio_install,
+        environment,
+        port,
+        log,
+        elf,
+        partition_table,
+        gdb,
+        save,
+    } = args;
+
+    let project = env::current_dir()?;
+    let build_dir = project
+        .join(".pio")
+        .join("build")
+        .join(environment.as_deref().unwrap_or("debug"));
+
+    let elf_file = elf.unwrap_or_else(|| build_dir.join("firmware.elf"));
+    if !elf_file.is_file() {
+        return Err(Message::new("elf-not-built").arg("path", elf_file.display()))
+            .hint(Message::new("specify-elf"));
+    }
+
+    let coredump = if let Some(log) = log {
+        coredump::CoreDump::from_uart_log(&fs::read_to_string(&log)?)?
+    } else {
+        read_flash_coredump(
+            &Pio::get(pio_install.pio_path, pio_log_level, false)?,
+            &partition_table.unwrap_or_else(|| build_dir.join("partitions.bin")),
+            port.as_deref(),
+        )?
+    };
+
+    let coredump = match coredump {
+        Some(coredump) => coredump,
+        None => {
+            info!("No core dump found");
+            return Ok(());
+        }
+    };
+
+    let gdb = match gdb.or_else(|| {
+        elf::ElfInfo::from_file(&elf_file)
+            .ok()
+            .and_then(|elf| elf.find_tool("gdb"))
+    }) {
+        Some(gdb) => gdb,
+        None => bail!("No GDB executable found, please use the --gdb parameter"),
+    };
+
+    let temp_dir = TempDir::new()?;
+    let core_file = save.unwrap_or_else(|| temp_dir.path().join("core.elf"));
+
+    coredump.write_elf_core(&core_file)?;
+    coredump::print_backtraces(gdb, elf_file, core_file)
+}
+
+fn read_flash_coredump(
+    pio: &Pio,
+    partition_table: &Path,
+    port: Option<&str>,
+) -> Result<Option<coredump::CoreDump>> {
+    if !partition_table.is_file() {
+        bail!(
+            "Partition table {} does not exist, use --partition-table to specify it",
+            partition_table.display()
+        );
+    }
+
+    let partition = match coredump::find_partition(&fs::read(partition_table)?) {
+        Some(partition) => partition,
+        None => bail!("The partition table contains no core dump partition"),
+    };
+
+    let temp_dir = TempDir::new()?;
+    let image = temp_dir.path().join("coredump.bin");
+
+    let mut cmd = esptool_cmd(pio, port);
+    cmd.arg("read_flash")
+        .arg(format!("0x{:x}", partition.offset))
+        .arg(format!("0x{:x}", partition.size))
+        .arg(&image);
+
+    pio.exec(&mut cmd)?;
+
+    if !image.is_file() {
+        bail!("Reading the core dump partition from flash failed");
+    }
+
+    coredump::CoreDump::from_flash(&fs::read(image)?)
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct DaemonArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// Stops the running daemon
+    #[structopt(long, conflicts_with = "status")]
+    stop: bool,
+
+    /// Shows whether a daemon is running
+    #[structopt(long)]
+    status: bool,
+
+    /// Minutes after which an idle daemon exits
+    #[structopt(long, default_value = "30")]
+    idle_timeout: u64,
+}
+
+#[cfg(unix)]
+pub fn run(args: DaemonArgs, pio_log_level: LogLevel) -> Result<()> {
+    let DaemonArgs {
+        pio_install,
+        stop,
+        status,
+        idle_timeout,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+    if stop {
+        if !daemon::stop(&pio)? {
+            info!("No PlatformIO daemon is running");
+        }
+    } else if status {
+        if daemon::is_running(&pio) {
+            println!("running ({})", daemon::socket_path(&pio).display());
+        } else {
+            println!("not running");
+        }
+    } else {
+        daemon::start(&pio, std::time::Duration::from_secs(idle_timeout * 60))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn run(_args: DaemonArgs, _pio_log_level: LogLevel) -> Result<()> {
+    bail!("The PlatformIO daemon is only supported on Unix")
+}
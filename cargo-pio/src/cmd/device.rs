@@ -0,0 +1,106 @@
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub enum DeviceCommand {
+    /// Records the device connected to a port under a name, identified by its USB serial number or MAC address
+    ///
+    /// Ports of serial-over-TCP bridges ('rfc2217://...', 'tcp://...') are recorded as they are
+    Add {
+        /// The name of the device, e.g. 'lab-3'
+        name: String,
+
+        /// The port the device is connected to
+        #[structopt(long, short = "p")]
+        port: String,
+
+        /// Identifies the device by its MAC address even if it has a USB serial number
+        #[structopt(long)]
+        mac: bool,
+
+        /// PlatformIO environment to flash to the device by default
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+    },
+    /// Removes a known device
+    Remove {
+        /// The name of the device
+        name: String,
+    },
+    /// Lists the known devices, with the ports of the connected ones
+    List,
+}
+
+pub fn run(
+    pio_install: PioInstallation,
+    cmd: DeviceCommand,
+    pio_log_level: LogLevel,
+) -> Result<()> {
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+    let mut registry = device_registry::Registry::load(&pio)?;
+
+    match cmd {
+        DeviceCommand::Add {
+            name,
+            port,
+            mac,
+            environment,
+        } => {
+            let mut device = if port.contains("://") {
+                device_registry::KnownDevice {
+                    port: Some(port),
+                    ..Default::default()
+                }
+            } else {
+                device_registry::identify(&pio, &pio.serial_devices()?, &port, mac)?
+            };
+            device.environment = environment;
+
+            info!("Adding device {} ({})", name, device.identity());
+
+            registry.devices.insert(name, device);
+            registry.save(&pio)
+        }
+        DeviceCommand::Remove { name } => {
+            if registry.devices.remove(&name).is_none() {
+                bail!("Unknown device '{}'", name);
+            }
+
+            registry.save(&pio)
+        }
+        DeviceCommand::List => {
+            let serial_devices = pio.serial_devices()?;
+
+            for (name, device) in &registry.devices {
+                // Only by serial number, reading the MAC addresses would reset the devices
+                let connected = match &device.serial {
+                    Some(_) => device_registry::locate(
+                        &pio,
+                        &serial_devices,
+                        &device_registry::KnownDevice {
+                            mac: None,
+                            port: None,
+                            ..device.clone()
+                        },
+                    )
+                    .ok(),
+                    None => device.port.clone(),
+                };
+
+                println!(
+                    "{:<16} {:<28} {:<12} {}",
+                    name,
+                    device.identity(),
+                    device.environment.as_deref().unwrap_or("-"),
+                    connected.as_deref().unwrap_or("-")
+                );
+            }
+
+            Ok(())
+        }
+    }
+}
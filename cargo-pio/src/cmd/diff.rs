@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use embuild::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct DiffArgs {
+    /// The ELF file of the old (baseline) build
+    #[structopt(parse(from_os_str))]
+    old: PathBuf,
+
+    /// The ELF file of the new build
+    #[structopt(parse(from_os_str))]
+    new: PathBuf,
+
+    /// Maximum number of changed symbols to report, 0 for all
+    #[structopt(long, default_value = "30")]
+    limit: usize,
+}
+
+pub fn run(args: DiffArgs) -> Result<()> {
+    let DiffArgs { old, new, limit } = args;
+
+    diff(old, new, limit)
+}
+
+fn diff(old: impl AsRef<Path>, new: impl AsRef<Path>, limit: usize) -> Result<()> {
+    let diff = elf::Diff::new(
+        &elf::ElfInfo::from_file(old)?,
+        &elf::ElfInfo::from_file(new)?,
+    );
+
+    if diff.is_empty() {
+        println!("No differences");
+        return Ok(());
+    }
+
+    println!("Total: {:+} bytes", diff.size_delta());
+
+    if !diff.sections.is_empty() {
+        println!("\nSections:");
+
+        for change in &diff.sections {
+            match change {
+                elf::Change::Added(section) => println!(
+                    "  + {:<32} {:>8} bytes @ 0x{:08x}",
+                    section.name, section.size, section.address
+                ),
+                elf::Change::Removed(section) => println!(
+                    "  - {:<32} {:>8} bytes @ 0x{:08x}",
+                    section.name, section.size, section.address
+                ),
+                elf::Change::Changed { old, new } => println!(
+                    "  ~ {:<32} {:>+8} bytes{}",
+                    new.name,
+                    change.size_delta(),
+                    if change.moved() {
+                        format!(" (moved 0x{:08x} -> 0x{:08x})", old.address, new.address)
+                    } else {
+                        String::new()
+                    }
+                ),
+            }
+        }
+    }
+
+    if !diff.symbols.is_empty() {
+        println!("\nSymbols:");
+
+        let count = if limit == 0 {
+            diff.symbols.len()
+        } else {
+            limit
+        };
+
+        for change in diff.symbols.iter().take(count) {
+            let section = |symbol: &elf::Symbol| symbol.section.clone().unwrap_or_default();
+            // Static symbols of the same name are told apart by their files
+            let name = |symbol: &elf::Symbol| match &symbol.file {
+                Some(file) => format!("{} ({})", symbol.name, file),
+                None => symbol.name.clone(),
+            };
+
+            match change {
+                elf::Change::Added(symbol) => println!(
+                    "  + {:<48} {:>+8} bytes in {}",
+                    name(symbol),
+                    change.size_delta(),
+                    section(symbol)
+                ),
+                elf::Change::Removed(symbol) => println!(
+                    "  - {:<48} {:>+8} bytes in {}",
+                    name(symbol),
+                    change.size_delta(),
+                    section(symbol)
+                ),
+                elf::Change::Changed { old, new } => println!(
+                    "  ~ {:<48} {:>+8} bytes{}",
+                    name(new),
+                    change.size_delta(),
+                    if change.moved() {
+                        format!(" (moved {} -> {})", section(old), section(new))
+                    } else {
+                        String::new()
+                    }
+                ),
+            }
+        }
+
+        if diff.symbols.len() > count {
+            println!(
+                "  ... and {} more (use --limit 0 to show all)",
+                diff.symbols.len() - count
+            );
+        }
+    }
+
+    Ok(())
+}
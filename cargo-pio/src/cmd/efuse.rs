@@ -0,0 +1,40 @@
+use anyhow::Result;
+use embuild::pio::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub enum EfuseCommand {
+    /// Prints the chip, its MAC address, its security state (secure boot, flash encryption, JTAG, download mode) and its features
+    Summary {
+        /// Port of the device. Auto-detected if not specified
+        #[structopt(long, short = "p")]
+        port: Option<String>,
+
+        /// The chip, e.g. 'esp32' or 'esp32c3'. Auto-detected if not specified
+        #[structopt(long)]
+        chip: Option<String>,
+
+        /// Prints all efuses as JSON instead
+        #[structopt(long)]
+        json: bool,
+    },
+}
+
+pub fn run(pio_install: PioInstallation, cmd: EfuseCommand, pio_log_level: LogLevel) -> Result<()> {
+    match cmd {
+        EfuseCommand::Summary { port, chip, json } => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+            let summary = efuse::read(&pio, port.as_deref(), chip.as_deref())?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else {
+                print!("{}", summary.render());
+            }
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::env;
+
+use anyhow::Result;
+use embuild::pio::*;
+use structopt::StructOpt;
+
+use super::build::build_cmd;
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct EnvArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// PlatformIO environment whose build environment to show. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+}
+
+pub fn run(args: EnvArgs, pio_log_level: LogLevel) -> Result<()> {
+    let EnvArgs {
+        pio_install,
+        environment,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+    let project = env::current_dir()?;
+    let environment = environment.as_deref().unwrap_or("debug");
+
+    let config = config::Config::load(&project)?;
+    let fingerprint = fingerprint::Fingerprint::collect(&pio, &project, environment)?;
+    let (cmd, trace) = build_cmd(&pio, &config, &project, environment, &fingerprint)?;
+
+    println!("# {:?}", cmd);
+
+    for variable in trace.variables() {
+        let origin = match &variable.origin {
+            inspect::Origin::Inherited => "inherited".to_owned(),
+            inspect::Origin::Set {
+                by,
+                overrides: false,
+            } => by.clone(),
+            inspect::Origin::Set {
+                by,
+                overrides: true,
+            } => format!("{}, overriding the inherited value", by),
+            inspect::Origin::Removed { by } => format!("removed by {}", by),
+        };
+
+        match &variable.value {
+            Some(value) => println!("{}={}    # {}", variable.name, value, origin),
+            None => println!("# {} ({})", variable.name, origin),
+        }
+    }
+
+    Ok(())
+}
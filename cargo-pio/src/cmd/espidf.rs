@@ -0,0 +1,400 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+use tempfile::TempDir;
+
+use super::monitor::{
+    monitor_decoder_chain, parse_monitor_decoder, run_esp_idf_monitor, MonitorDecoder,
+    MonitorFilterArgs,
+};
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub enum EspidfCommand {
+    /// Generates or updates the ESP-IDF sdkconfig file using the ESP-IDF Menuconfig interactive system
+    Menuconfig {
+        /// Rust target for which the sdkconfig file will be generated or updated
+        #[structopt(short, long)]
+        target: Option<String>,
+
+        /// Indicates release configuration
+        ///
+        /// Equivalent to '-e release'
+        #[structopt(long, short)]
+        release: Option<bool>,
+
+        /// PlatformIO environment to configure
+        ///
+        /// If not specified, the PlatformIO project default environment will be used (or error will be generated if there isn't one)
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+    },
+    /// Invokes the PlatformIO monitor
+    Monitor {
+        /// Port
+        ///
+        /// May be a serial-over-TCP bridge ('rfc2217://<host>:<port>' or 'tcp://<host>:<port>'),
+        /// a WebSocket bridge ('ws://<host>:<port>/<path>') or a device of a PIO Remote agent
+        /// ('remote://<agent>/<port>')
+        #[structopt()]
+        port: String,
+
+        /// Baud rate. Defaults to 115200
+        #[structopt(short = "b", long)]
+        baud_rate: Option<u32>,
+
+        /// Do not apply encodings/transformations
+        #[structopt(long)]
+        raw: bool,
+
+        /// Binary name built by this crate for which the monitor will be invoked (necessary for access to the ELF file)
+        #[structopt(long)]
+        binary: Option<String>,
+
+        /// Rust target for which the monitor will be invoked (necessary for access to the ELF file)
+        #[structopt(short, long)]
+        target: Option<String>,
+
+        /// Indicates release configuration
+        ///
+        /// Equivalent to '-e release'
+        #[structopt(long, short)]
+        release: Option<bool>,
+
+        /// PlatformIO environment to monitor
+        ///
+        /// If not specified, the PlatformIO project default environment will be used (or error will be generated if there isn't one)
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+
+        /// Decoder to pass the device output through, can be repeated
+        ///
+        /// 'timestamp' prefixes every line with the elapsed time, 'backtrace' resolves the code
+        /// addresses of panics and backtraces to 'function at file:line' and 'defmt' decodes
+        /// defmt frames with 'defmt-print' (must be the last decoder).
+        /// If specified, PlatformIO's own filters are not used
+        #[structopt(long = "decoder", parse(from_str = parse_monitor_decoder),
+                    possible_values = &["timestamp", "backtrace", "defmt"])]
+        decoders: Vec<MonitorDecoder>,
+
+        /// The addr2line executable used by the 'backtrace' decoder
+        ///
+        /// If not specified, it is searched in PATH and in the toolchains installed by PlatformIO
+        #[structopt(long, parse(from_os_str))]
+        addr2line: Option<PathBuf>,
+
+        /// Log the session to this file
+        ///
+        /// The decoded output is written to the file, with every line timestamped, and the raw
+        /// device output to '<log file>.raw', which can be passed through the decoders again with
+        /// 'replay'
+        #[structopt(long, parse(from_os_str))]
+        log_file: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        filters: MonitorFilterArgs,
+    },
+    /// Replays a monitor session recorded with 'monitor --log-file' through decoders
+    Replay {
+        /// The raw recording of the session ('<log file>.raw')
+        #[structopt(parse(from_os_str))]
+        recording: PathBuf,
+
+        /// Binary name built by this crate which produced the recorded output (necessary for access to the ELF file)
+        #[structopt(long)]
+        binary: Option<String>,
+
+        /// Rust target of the binary which produced the recorded output (necessary for access to the ELF file)
+        #[structopt(short, long)]
+        target: Option<String>,
+
+        /// Indicates release configuration
+        ///
+        /// Equivalent to '-e release'
+        #[structopt(long, short)]
+        release: Option<bool>,
+
+        /// PlatformIO environment which built the firmware
+        ///
+        /// If not specified, the PlatformIO project default environment will be used (or error will be generated if there isn't one)
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+
+        /// Decoder to pass the recorded output through, can be repeated
+        ///
+        /// The decoders are the same as the ones of 'monitor', the 'timestamp' decoder prints
+        /// the time of the recording
+        #[structopt(long = "decoder", parse(from_str = parse_monitor_decoder),
+                    possible_values = &["timestamp", "backtrace", "defmt"])]
+        decoders: Vec<MonitorDecoder>,
+
+        /// The addr2line executable used by the 'backtrace' decoder
+        ///
+        /// If not specified, it is searched in PATH and in the toolchains installed by PlatformIO
+        #[structopt(long, parse(from_os_str))]
+        addr2line: Option<PathBuf>,
+    },
+    /// Installs the ESP Component Registry components of the project's idf_component.yml into managed_components
+    ///
+    /// Resolves the dependencies without the Python component manager of ESP-IDF. Builds install them
+    /// as well with 'registry = true' in the [espidf] section of cargo-pio.toml
+    Components {
+        /// Reinstall the components even if the resolved versions are installed already
+        #[structopt(long)]
+        force: bool,
+
+        /// Resolve the newest versions released by this date ('YYYY-MM-DD'), to reproduce an earlier build
+        ///
+        /// Defaults to 'as-of' in the [espidf] section of cargo-pio.toml
+        #[structopt(long)]
+        as_of: Option<String>,
+    },
+}
+
+pub fn run(
+    pio_install: PioInstallation,
+    cmd: EspidfCommand,
+    pio_log_level: LogLevel,
+) -> Result<()> {
+    match cmd {
+        EspidfCommand::Menuconfig {
+            target,
+            release,
+            environment,
+        } => {
+            run_esp_idf_menuconfig(
+                Pio::get(pio_install.pio_path, pio_log_level, false /*download*/)?,
+                env::current_dir()?,
+                target.as_deref(),
+                if environment.is_some() {
+                    environment.as_deref()
+                } else if let Some(true) = release {
+                    Some("release")
+                } else {
+                    None
+                },
+            )
+        }
+        EspidfCommand::Monitor {
+            port,
+            baud_rate,
+            raw,
+            binary,
+            target,
+            release,
+            environment,
+            decoders,
+            addr2line,
+            log_file,
+            filters,
+        } => {
+            run_esp_idf_monitor(
+                Pio::get(pio_install.pio_path, pio_log_level, false /*download*/)?,
+                env::current_dir()?,
+                &serial_port_url(&port),
+                baud_rate.unwrap_or(115200),
+                raw,
+                binary.as_deref(),
+                target.as_deref(),
+                if environment.is_some() {
+                    environment.as_deref()
+                } else if let Some(true) = release {
+                    Some("release")
+                } else {
+                    None
+                },
+                &decoders,
+                addr2line,
+                log_file.as_deref(),
+                &filters,
+            )
+        }
+        EspidfCommand::Replay {
+            recording,
+            binary,
+            target,
+            release,
+            environment,
+            decoders,
+            addr2line,
+        } => {
+            let environment = if environment.is_some() {
+                environment.as_deref()
+            } else if let Some(true) = release {
+                Some("release")
+            } else {
+                None
+            };
+
+            let mut chain = monitor_decoder_chain(
+                env::current_dir()?,
+                binary.as_deref(),
+                target.as_deref(),
+                environment,
+                &decoders,
+                addr2line,
+            )?;
+
+            monitor::replay(recording, std::io::stdout(), &mut chain)?;
+
+            Ok(())
+        }
+        EspidfCommand::Components { force, as_of } => {
+            let project = env::current_dir()?;
+            let config = config::Config::load(&project)?;
+
+            if idf_registry::manifest(&project).is_none() {
+                bail!("No idf_component.yml in the project, neither in src/ nor in the project directory");
+            }
+
+            let installed = idf_registry::install_all(
+                &project,
+                &credentials::KeyringClient,
+                force,
+                as_of.as_deref().or(config.espidf.as_of.as_deref()),
+            )?;
+
+            for component in &installed {
+                println!(
+                    "{} {} ({})",
+                    component.name,
+                    component.version.version,
+                    component.dir().display()
+                );
+            }
+
+            Ok(())
+        }
+    }
+}
+
+fn run_esp_idf_menuconfig<'a>(
+    pio: Pio,
+    project: impl AsRef<Path>,
+    target: Option<&'a str>,
+    environment: Option<&'a str>,
+) -> Result<()> {
+    // menuconfig takes over the terminal, which it does not give back when it crashes
+    let _terminal = terminal::Guard::save();
+
+    let args = if let Some(environment) = environment {
+        vec!["-t", "menuconfig", "-e", environment]
+    } else {
+        vec!["-t", "menuconfig"]
+    };
+
+    if check_pio_first_project(&project) {
+        call_in_dir(project, move || pio.run_with_args(&args))
+    } else {
+        let target = derive_target(project, target)?;
+
+        let resolution = resolve_esp_idf_target(pio.clone(), &target)?;
+
+        let sdkconfigs = &[
+            env::current_dir()?.join("sdkconfig"),
+            env::current_dir()?.join("sdkconfig.debug"),
+        ];
+
+        for sdkconfig in sdkconfigs {
+            if sdkconfig.exists() && sdkconfig.is_dir() {
+                bail!(
+                    "The sdkconfig entry {} is a directory, not a file",
+                    sdkconfig.display()
+                );
+            }
+        }
+
+        let temp_dir = TempDir::new()?;
+        let project_path = temp_dir.path().join("proj");
+
+        project::Builder::new(&project_path)
+            .enable_c_entry_points()
+            .generate(&resolution)?;
+
+        for sdkconfig in sdkconfigs {
+            if sdkconfig.exists() {
+                let dest_sdkconfig = project_path.join(sdkconfig.file_name().unwrap());
+
+                fs::copy(sdkconfig, &dest_sdkconfig)?;
+            }
+        }
+
+        call_in_dir(&project_path, move || pio.run_with_args(&args))?;
+
+        for sdkconfig in sdkconfigs {
+            let dest_sdkconfig = project_path.join(sdkconfig.file_name().unwrap());
+
+            if dest_sdkconfig.exists() {
+                fs::copy(dest_sdkconfig, sdkconfig)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn resolve_esp_idf_target(pio: Pio, target: impl AsRef<str>) -> Result<Resolution> {
+    Resolver::new(pio)
+        .params(ResolutionParams {
+            platform: Some("espressif32".into()),
+            frameworks: vec!["espidf".into()],
+            target: Some(target.as_ref().to_owned()),
+            ..Default::default()
+        })
+        .resolve(true)
+}
+
+pub fn check_pio_first_project(project: impl AsRef<Path>) -> bool {
+    let project = project.as_ref();
+
+    let platformio_ini = project.join("platformio.ini");
+
+    if platformio_ini.exists() && platformio_ini.is_file() {
+        // We are running the monitor on a Pio-first project (possibly a PIO->Cargo one)
+        // Just run the PlatformIO monitor then
+        info!("Found platformio.ini in {}", project.display());
+
+        true
+    } else {
+        info!(
+            "platformio.ini not found in {}, assuming a Cargo-first project",
+            project.display()
+        );
+
+        false
+    }
+}
+
+pub fn call_in_dir<F, R>(dir: impl AsRef<Path>, f: F) -> Result<R>
+where
+    F: FnOnce() -> Result<R>,
+{
+    let current_dir = env::current_dir()?;
+
+    env::set_current_dir(&dir)?;
+
+    let result = f();
+
+    env::set_current_dir(current_dir)?;
+
+    result
+}
+
+pub fn derive_target(project: impl AsRef<Path>, target: Option<&str>) -> Result<String> {
+    Ok(if let Some(target) = target {
+        info!("Using explicitly passed target {}", target);
+
+        target.to_owned()
+    } else if let Some(target) = cargo::Crate::new(project).get_default_target()? {
+        info!("Using pre-configured target {}", target);
+
+        target
+    } else {
+        bail!("Cannot find 'target=' specification in any Cargo configuration file. Please use the --target parameter to specify the target on the command line");
+    })
+}
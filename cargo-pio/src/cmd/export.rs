@@ -0,0 +1,63 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use structopt::StructOpt;
+
+use super::build::build;
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct ExportArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// Directory to export the project to, must not exist or be empty
+    #[structopt(long, parse(from_os_str))]
+    standalone: PathBuf,
+
+    /// PlatformIO environment to export, can be repeated. Defaults to all environments with Rust code
+    #[structopt(long = "environment", short = "e")]
+    environments: Vec<String>,
+
+    /// Exports the Rust libraries of the last builds instead of building the environments first
+    #[structopt(long)]
+    no_build: bool,
+}
+
+pub fn run(args: ExportArgs, pio_log_level: LogLevel) -> Result<()> {
+    let ExportArgs {
+        pio_install,
+        standalone,
+        environments,
+        no_build,
+    } = args;
+
+    let project = env::current_dir()?;
+
+    let config = config::Config::load(&project)?;
+    let environments = if environments.is_empty() {
+        export::environments_with_rust(&project)?
+    } else {
+        environments
+    };
+
+    if environments.is_empty() {
+        bail!("The project has no environments with Rust code to export");
+    }
+
+    if !no_build {
+        let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+        for environment in &environments {
+            build(&pio, &project, environment)?;
+        }
+    }
+
+    export::standalone(&project, &standalone, &config, &environments)?;
+
+    println!("{}", standalone.display());
+
+    Ok(())
+}
@@ -0,0 +1,711 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Context, Result};
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+use tempfile::TempDir;
+
+use super::build::{build, detect_environment};
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct FlashArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// Performs a release build
+    ///
+    /// Equivalent to '-e release'
+    #[structopt(long, short, conflicts_with = "environment")]
+    release: bool,
+
+    /// PlatformIO environment to build and flash. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// Build and flash all firmware images configured in cargo-pio.toml ('[[images]]'), in order
+    #[structopt(long, conflicts_with_all = &["environment", "release", "provision"])]
+    all: bool,
+
+    /// Port of the device to flash, can be repeated to flash several devices concurrently. Auto-detected if not specified
+    ///
+    /// Devices attached to another machine can be flashed through a serial-over-TCP bridge
+    /// with 'rfc2217://<host>:<port>' or 'tcp://<host>:<port>' (raw socket)
+    /// or through PIO Remote with 'remote://<agent>/<port>' (see 'cargo pio account devices')
+    #[structopt(long, short = "p", conflicts_with = "all-ports")]
+    port: Vec<String>,
+
+    /// Flash all connected USB serial devices concurrently
+    #[structopt(long)]
+    all_ports: bool,
+
+    /// Name of the known device to flash (see 'cargo pio device'), can be repeated
+    ///
+    /// The device is found by its USB serial number or MAC address on whichever port it is
+    /// connected to, and is flashed with its environment unless one is given. Devices with
+    /// different environments are built and flashed one environment after the other
+    #[structopt(long, conflicts_with_all = &["port", "all-ports", "detect"])]
+    device: Vec<String>,
+
+    /// Builds and flashes the environment of the connected board, of the build type selected by '--release'
+    ///
+    /// The board is detected like with 'build --detect', and flashed through its port
+    #[structopt(long, conflicts_with_all = &["environment", "all", "port", "all-ports"])]
+    detect: bool,
+
+    /// Only flash devices with this USB '<vid>:<pid>' (hex, either may be '*'), can be repeated
+    #[structopt(long = "match", requires = "all-ports")]
+    matches: Vec<String>,
+
+    /// Provisioning manifest (CSV or JSON) with one identity per device
+    ///
+    /// Every flashed device gets the next unused record of the manifest written into an NVS
+    /// partition. The next record is tracked in '<manifest>.counter', and which device got
+    /// which record is logged to '<manifest>.log.csv'
+    #[structopt(long, parse(from_os_str))]
+    provision: Option<PathBuf>,
+
+    /// Label of the NVS partition the provisioning data is written to
+    #[structopt(long, default_value = "nvs", requires = "provision")]
+    provision_partition: String,
+
+    /// NVS namespace of the provisioning data
+    #[structopt(long, default_value = provision::DEFAULT_NAMESPACE, requires = "provision")]
+    provision_namespace: String,
+
+    /// The executable Cargo passes to 'flash' as the runner of the Cargo config, which is ignored: the firmware of the environment is built and flashed instead
+    #[structopt(parse(from_os_str), hidden = true)]
+    executable: Option<PathBuf>,
+}
+
+pub fn run(args: FlashArgs, pio_log_level: LogLevel) -> Result<()> {
+    let FlashArgs {
+        pio_install,
+        release,
+        mut environment,
+        all,
+        mut port,
+        all_ports,
+        device,
+        detect,
+        matches,
+        provision,
+        provision_partition,
+        provision_namespace,
+        executable,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+    let project = env::current_dir()?;
+    let config = config::Config::load(&project)?;
+
+    if let Some(executable) = executable {
+        debug!(
+            "Flashing the firmware instead of the executable {}",
+            executable.display()
+        );
+    }
+
+    if detect {
+        let (detected, detected_port) = detect_environment(&pio, &project, release, true)?;
+
+        environment = Some(detected);
+        port.extend(detected_port);
+    }
+
+    // The devices to flash, grouped by the environment flashed to them
+    let groups = if !device.is_empty() {
+        let registry = device_registry::Registry::load(&pio)?;
+        let serial_devices = pio.serial_devices()?;
+
+        let mut known = Vec::new();
+        for name in &device {
+            let device = registry.get(name)?;
+            let device_port = device_registry::locate(&pio, &serial_devices, device)?;
+
+            info!("Device {} is connected to {}", name, device_port);
+
+            known.push((
+                device,
+                (
+                    Some(device_port),
+                    device.serial.clone().or_else(|| device.mac.clone()),
+                ),
+            ));
+        }
+
+        if environment.is_none() && !release && !all {
+            device_registry::by_environment(known)
+        } else {
+            vec![(
+                environment.clone(),
+                known.into_iter().map(|(_, device)| device).collect(),
+            )]
+        }
+    } else {
+        let devices = if all_ports {
+            let devices = pio
+                .serial_devices()?
+                .into_iter()
+                // Only USB devices, not the UARTs of the machine (e.g. ttyS*)
+                .filter(|d| d.vid_pid().is_some())
+                .filter(|d| matches.is_empty() || matches.iter().any(|m| d.matches(m)))
+                .map(|d| (Some(d.port.clone()), d.serial_number().map(str::to_owned)))
+                .collect::<Vec<_>>();
+
+            if devices.is_empty() {
+                bail!("No matching devices connected");
+            }
+
+            devices
+        } else if port.is_empty() {
+            vec![(None, None)]
+        } else {
+            port.iter().map(|port| (Some(port.clone()), None)).collect()
+        };
+
+        vec![(environment.clone(), devices)]
+    };
+
+    for (environment, devices) in groups {
+        let environments = if all {
+            if config.images.is_empty() {
+                bail!(
+                    "No images configured in {}, '--all' needs '[[images]]'",
+                    config::CONFIG_FILE_NAME
+                );
+            }
+
+            images::environments(&config.images)
+        } else {
+            vec![environment
+                .as_deref()
+                .unwrap_or(if release { "release" } else { "debug" })]
+        };
+
+        for environment in &environments {
+            build(&pio, &project, environment)?;
+        }
+
+        let environment = environments[0];
+        let images = if all {
+            images::resolve(&config.images, &project)?
+        } else {
+            vec![images::Image {
+                name: environment.to_owned(),
+                environment: environment.to_owned(),
+                placement: images::Placement::Upload,
+            }]
+        };
+
+        for port in devices.iter().filter_map(|d| d.0.as_deref()) {
+            if is_raw_tcp_port(port) {
+                warn!(
+                    "{} is a raw TCP serial bridge which cannot reset the device, put it into its bootloader manually",
+                    port
+                );
+            }
+        }
+
+        let provisioning = provision
+            .as_ref()
+            .map(|manifest| -> Result<_> {
+                let partition_table = project
+                    .join(".pio")
+                    .join("build")
+                    .join(environment)
+                    .join("partitions.bin");
+
+                let partition = partitions::Partition::find_by_label(
+                    &fs::read(&partition_table).with_context(|| {
+                        anyhow!(
+                            "Failed to read the partition table {}",
+                            partition_table.display()
+                        )
+                    })?,
+                    &provision_partition,
+                )
+                .ok_or_else(|| anyhow!("No partition labeled '{}'", provision_partition))?;
+
+                Ok(Provisioning {
+                    manifest: provision::Manifest::load(manifest)?,
+                    partition,
+                    namespace: provision_namespace.clone(),
+                })
+            })
+            .transpose()?;
+
+        for environment in &environments {
+            config.run_hook(config::Hook::PreFlash, &pio, &project, environment)?;
+        }
+
+        let concurrent = all_ports || devices.len() > 1;
+        flash(
+            &pio,
+            &config,
+            &project,
+            &images,
+            devices,
+            concurrent,
+            provisioning.as_ref(),
+        )?;
+
+        for environment in &environments {
+            config.run_hook(config::Hook::PostFlash, &pio, &project, environment)?;
+        }
+    }
+
+    Ok(())
+}
+
+struct Provisioning {
+    manifest: provision::Manifest,
+    partition: partitions::Partition,
+    namespace: String,
+}
+
+/// Flash the (already built) `images` onto `devices`, in order.
+#[allow(clippy::too_many_arguments)]
+fn flash(
+    pio: &Pio,
+    config: &config::Config,
+    project: &Path,
+    images: &[images::Image],
+    devices: Vec<(Option<String>, Option<String>)>,
+    concurrent: bool,
+    provisioning: Option<&Provisioning>,
+) -> Result<()> {
+    // Every image is written from the bootloader, so a custom reset precedes each of them
+    let write_cmds = |environment: &str,
+                      port: Option<&str>,
+                      offset: u32,
+                      file: &Path|
+     -> Result<Vec<std::process::Command>> {
+        if let Some(port) = port.filter(|port| account::remote_port(port).is_some()) {
+            bail!(
+                "Images at an offset and provisioning data cannot be written to the PIO Remote port {}",
+                port
+            );
+        }
+
+        let reset = reset::of_environment(config, project, environment).flash;
+
+        let mut cmd = esptool_cmd(pio, port);
+        cmd.arg("--before")
+            .arg(reset::esptool_before(&reset))
+            .arg("write_flash")
+            .arg(format!("0x{:x}", offset))
+            .arg(file);
+
+        Ok(reset::before_flash(pio, &reset, port)?
+            .into_iter()
+            .chain([cmd])
+            .collect())
+    };
+
+    let upload_cmds = |image: &images::Image, port: Option<&str>| match &image.placement {
+        images::Placement::Upload => {
+            // The agent uploads the firmware with the upload settings of the environment,
+            // custom resets only work locally
+            if let Some((agent, port)) = port.and_then(account::remote_port) {
+                let mut cmd = account::remote_cmd(pio, agent)?;
+                cmd.arg("run")
+                    .arg("-d")
+                    .arg(project)
+                    .arg("-e")
+                    .arg(&image.environment)
+                    .args(["-t", "upload", "--upload-port", port]);
+
+                return Ok(vec![cmd]);
+            }
+
+            let reset = reset::of_environment(config, project, &image.environment).flash;
+
+            let mut cmd = pio.run_cmd();
+
+            // The firmware was just built, building it once per device in parallel would
+            // make the builds step on each other
+            cmd.arg("-d")
+                .arg(project)
+                .arg("-e")
+                .arg(&image.environment)
+                .args(["-t", "nobuild", "-t", "upload"]);
+
+            if let Some(port) = port {
+                cmd.arg("--upload-port").arg(serial_port_url(port));
+            }
+
+            reset::apply_to_upload(&reset, project, &image.environment, &mut cmd);
+
+            Ok(reset::before_flash(pio, &reset, port)?
+                .into_iter()
+                .chain([cmd])
+                .collect())
+        }
+        images::Placement::Write { firmware, offset } => {
+            write_cmds(&image.environment, port, *offset, firmware)
+        }
+    };
+
+    let temp_dir = TempDir::new()?;
+
+    let records = match provisioning {
+        Some(provisioning) => provisioning
+            .manifest
+            .take(devices.len())?
+            .into_iter()
+            .map(Some)
+            .collect(),
+        None => vec![None; devices.len()],
+    };
+
+    // The commands to run per device: the firmware upload, followed by writing the
+    // provisioning data (if any)
+    let jobs = devices
+        .into_iter()
+        .zip(records)
+        .enumerate()
+        .map(|(index, ((port, serial), record))| -> Result<_> {
+            let mut cmds = Vec::new();
+            for image in images {
+                cmds.extend(upload_cmds(image, port.as_deref())?);
+            }
+
+            if let (Some(provisioning), Some(record)) = (provisioning, &record) {
+                let image = temp_dir.path().join(format!("provision-{}.bin", index));
+
+                fs::write(
+                    &image,
+                    record.nvs_image(
+                        &provisioning.namespace,
+                        provisioning.partition.size as usize,
+                    )?,
+                )?;
+
+                cmds.extend(write_cmds(
+                    &images[0].environment,
+                    port.as_deref(),
+                    provisioning.partition.offset,
+                    &image,
+                )?);
+            }
+
+            Ok((port.unwrap_or_else(|| "auto".into()), serial, record, cmds))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let log_provisioning = |port: &str,
+                            serial: Option<&str>,
+                            record: &Option<provision::Record>,
+                            success: bool|
+     -> Result<()> {
+        if let (Some(provisioning), Some(record)) = (provisioning, record) {
+            provisioning.manifest.log(record, port, serial, success)?;
+
+            if success {
+                info!("Provisioned {} with record {}", port, record.id());
+            }
+        }
+
+        Ok(())
+    };
+
+    if !concurrent {
+        for (port, serial, record, cmds) in jobs {
+            let mut result = Ok(());
+
+            for mut cmd in cmds {
+                let (status, _) = pio
+                    .clone()
+                    .timeout(config.timeouts.upload())
+                    .exec_capture(&mut cmd)?;
+
+                if !status.success() {
+                    result = Err(anyhow!("Flashing failed with {}", status));
+                    break;
+                }
+            }
+
+            log_provisioning(&port, serial.as_deref(), &record, result.is_ok())?;
+
+            result?;
+        }
+
+        return Ok(());
+    }
+
+    let log_dir = project
+        .join(".pio")
+        .join("build")
+        .join(&images[0].environment);
+
+    info!(
+        "Flashing {} device(s): {}",
+        jobs.len(),
+        jobs.iter()
+            .map(|job| job.0.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let timeout = config.timeouts.upload();
+
+    let handles = jobs
+        .into_iter()
+        .map(|(port, serial, record, cmds)| {
+            let log_file = log_dir.join(format!(
+                "flash-{}.log",
+                port.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+            ));
+
+            std::thread::spawn(move || {
+                let start = std::time::Instant::now();
+                let result = flash_device(&port, cmds, &log_file, timeout);
+
+                (port, serial, record, result, start.elapsed(), log_file)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let results = handles
+        .into_iter()
+        .map(|handle| handle.join().expect("Flashing thread panicked"))
+        .collect::<Vec<_>>();
+
+    for (port, serial, record, result, ..) in &results {
+        log_provisioning(port, serial.as_deref(), record, result.is_ok())?;
+    }
+
+    let ids = results
+        .iter()
+        .map(|r| r.2.as_ref().map(provision::Record::id))
+        .collect::<Vec<_>>();
+
+    let port_width = results.iter().map(|r| r.0.len()).max().unwrap_or(0).max(4);
+    let id_width = ids
+        .iter()
+        .flatten()
+        .map(String::len)
+        .max()
+        .map(|width| width.max("Identity".len()));
+
+    let id_column = |id: &str| match id_width {
+        Some(width) => format!("{:<width$}  ", id, width = width),
+        None => String::new(),
+    };
+
+    println!();
+    println!(
+        "{:<width$}  {:<6}  {:>8}  {}Log",
+        "Port",
+        "Result",
+        "Time",
+        id_column("Identity"),
+        width = port_width,
+    );
+
+    for ((port, _, _, result, elapsed, log_file), id) in results.iter().zip(&ids) {
+        println!(
+            "{:<width$}  {:<6}  {:>7.1}s  {}{}",
+            port,
+            match result {
+                Ok(()) => "OK",
+                Err(_) => "FAILED",
+            },
+            elapsed.as_secs_f32(),
+            id_column(id.as_deref().unwrap_or_default()),
+            log_file.display(),
+            width = port_width,
+        );
+    }
+
+    let failed = results.iter().filter(|r| r.3.is_err()).count();
+    if failed > 0 {
+        for (port, _, _, result, ..) in &results {
+            if let Err(err) = result {
+                error!("{}: {:#}", port, err);
+            }
+        }
+
+        bail!(
+            "Flashing failed for {} of {} device(s)",
+            failed,
+            results.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Run the commands `cmds` for the device at `port` one after the other, printing their
+/// progress and writing their complete output to `log_file`.
+fn flash_device(
+    port: &str,
+    cmds: Vec<std::process::Command>,
+    log_file: &Path,
+    timeout: Option<std::time::Duration>,
+) -> Result<()> {
+    let mut output = Vec::new();
+    let mut result = Ok(());
+
+    for mut cmd in cmds {
+        result = run_flash_cmd(port, &mut cmd, &mut output, timeout);
+
+        if result.is_err() {
+            break;
+        }
+    }
+
+    fs::write(log_file, &output)?;
+
+    if result.is_ok() {
+        println!("[{}] done", port);
+    }
+
+    result
+}
+
+fn run_flash_cmd(
+    port: &str,
+    cmd: &mut std::process::Command,
+    output: &mut Vec<u8>,
+    timeout: Option<std::time::Duration>,
+) -> Result<()> {
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    debug!("Running PlatformIO command: {:?}", cmd);
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let tracked = interrupt::track(&child);
+
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stderr.read_to_end(&mut output);
+        output
+    });
+
+    // Read on a thread of its own, so that the command can be killed once it times out
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let mut stdout = child.stdout.take().unwrap();
+    let stdout = {
+        let captured = captured.clone();
+        let port = port.to_owned();
+
+        std::thread::spawn(move || {
+            let mut buf = [0_u8; 512];
+            let mut last_progress = None;
+
+            while let Ok(len) = stdout.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+
+                let mut output = captured.lock().unwrap();
+                output.extend_from_slice(&buf[..len]);
+
+                // esptool & co. report their progress as "... (42 %)", separated by carriage returns
+                let progress = String::from_utf8_lossy(&output[output.len().saturating_sub(256)..])
+                    .rsplit(['\r', '\n'])
+                    .find_map(|line| {
+                        let percent = line.trim_end().strip_suffix("%)")?;
+                        let percent = percent[percent.rfind('(')? + 1..].trim();
+
+                        percent.parse::<u32>().ok()
+                    });
+
+                if let Some(progress) = progress {
+                    // Report in steps of 10% only, so that the output of the devices stays readable
+                    if last_progress
+                        .map(|last| progress / 10 != last / 10)
+                        .unwrap_or(true)
+                    {
+                        println!("[{}] {:>3}%", port, progress);
+                        last_progress = Some(progress);
+                    }
+                }
+            }
+
+            last_progress
+        })
+    };
+
+    let status = interrupt::wait_timeout(&mut child, timeout)?;
+    drop(tracked);
+
+    let status = match status {
+        Some(status) => status,
+        None => {
+            // The processes started by the command may still hold its output open
+            output.extend(captured.lock().unwrap().iter());
+            println!("[{}] timed out", port);
+
+            return Err(Timeout {
+                command: format!("{:?}", cmd),
+                timeout: timeout.unwrap_or_default(),
+                output: String::from_utf8_lossy(output).into_owned(),
+            }
+            .into());
+        }
+    };
+
+    let last_progress = stdout.join().unwrap_or_default();
+    output.extend(captured.lock().unwrap().iter());
+    output.extend(stderr.join().unwrap_or_default());
+
+    if interrupt::signal().is_some() {
+        // Finish the progress of the device, the output of the others follows
+        println!(
+            "[{}] interrupted at {}%",
+            port,
+            last_progress.unwrap_or_default()
+        );
+        interrupt::check()?;
+    }
+
+    if !status.success() {
+        bail!("{:?} failed with {}", cmd, status);
+    }
+
+    Ok(())
+}
+
+/// A command running PlatformIO's `esptool.py` for the device at `port` (auto-detected
+/// if `None`).
+pub fn esptool_cmd(pio: &Pio, port: Option<&str>) -> std::process::Command {
+    let mut cmd = esptool_pkg_cmd(pio, "esptool.py");
+
+    if let Some(port) = port {
+        cmd.arg("--port").arg(serial_port_url(port));
+    }
+
+    cmd
+}
+
+/// A command running the script `script` (`esptool.py`, `espsecure.py` or `espefuse.py`)
+/// of PlatformIO's esptool package.
+pub fn esptool_pkg_cmd(pio: &Pio, script: &str) -> std::process::Command {
+    let mut cmd = pio.cmd();
+    cmd.args(["pkg", "exec", "-p", "tool-esptoolpy", "--", script]);
+
+    cmd
+}
+
+pub fn run_esptool_pkg_cmd(pio: &Pio, cmd: &mut std::process::Command) -> Result<()> {
+    let (status, _) = pio.exec_capture(cmd)?;
+
+    if !status.success() {
+        bail!("{:?} failed with {}", cmd, status);
+    }
+
+    Ok(())
+}
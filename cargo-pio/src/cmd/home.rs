@@ -0,0 +1,49 @@
+use anyhow::Result;
+use embuild::pio::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct HomeArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// The port of PIO Home
+    #[structopt(long, default_value = "8008")]
+    port: u16,
+
+    /// Shows whether PIO Home is running
+    #[structopt(long)]
+    status: bool,
+
+    /// Minutes after which PIO Home shuts down when idle
+    #[structopt(long, default_value = "30")]
+    idle_timeout: u64,
+}
+
+pub fn run(args: HomeArgs, pio_log_level: LogLevel) -> Result<()> {
+    let HomeArgs {
+        pio_install,
+        port,
+        status,
+        idle_timeout,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+    if status {
+        match home::running(&pio.core_dir) {
+            Some(port) => println!("running (port {})", port),
+            None => println!("not running"),
+        }
+    } else {
+        home::start(
+            &pio,
+            port,
+            std::time::Duration::from_secs(idle_timeout * 60),
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Result};
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct LayoutArgs {
+    /// PlatformIO environment whose partition table and images to show. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// Binary partition table. Defaults to the partition table of the environment
+    #[structopt(long, parse(from_os_str))]
+    partition_table: Option<PathBuf>,
+
+    /// Flash size in bytes, e.g. '4MB', '4096KB' or '0x400000', to show the space after the last partition
+    #[structopt(long, parse(try_from_str = parse_flash_size))]
+    flash_size: Option<u32>,
+
+    /// Also render the layout as an SVG map into this file
+    #[structopt(long, parse(from_os_str))]
+    svg: Option<PathBuf>,
+}
+
+pub fn run(args: LayoutArgs) -> Result<()> {
+    let LayoutArgs {
+        environment,
+        partition_table,
+        flash_size,
+        svg,
+    } = args;
+
+    let build_dir = env::current_dir()?
+        .join(".pio")
+        .join("build")
+        .join(environment.as_deref().unwrap_or("debug"));
+
+    let partition_table = partition_table.unwrap_or_else(|| build_dir.join("partitions.bin"));
+    if !partition_table.is_file() {
+        bail!(
+            "Partition table {} does not exist, did you build your project first? Use --partition-table to specify it",
+            partition_table.display()
+        );
+    }
+
+    let mut layout = layout::Layout::new(
+        &partitions::Partition::parse_table(&fs::read(&partition_table)?),
+        flash_size,
+    );
+
+    // Which partitions the images of the build are flashed to
+    type Filter = fn(&partitions::Partition) -> bool;
+
+    let images: [(&str, Filter); 4] = [
+        ("firmware.bin", |p| p.kind == partitions::TYPE_APP),
+        ("littlefs.bin", |p| {
+            p.kind == partitions::TYPE_DATA
+                && matches!(
+                    p.subtype,
+                    partitions::SUBTYPE_SPIFFS | partitions::SUBTYPE_LITTLEFS
+                )
+        }),
+        ("spiffs.bin", |p| {
+            p.kind == partitions::TYPE_DATA && p.subtype == partitions::SUBTYPE_SPIFFS
+        }),
+        ("fatfs.bin", |p| {
+            p.kind == partitions::TYPE_DATA && p.subtype == partitions::SUBTYPE_FAT
+        }),
+    ];
+
+    for (image, filter) in images {
+        if let Ok(metadata) = fs::metadata(build_dir.join(image)) {
+            layout.place(image, metadata.len(), filter);
+        }
+    }
+
+    print!("{}", layout.table());
+
+    for warning in layout.warnings(flash_size) {
+        warn!("{}", warning);
+    }
+
+    if let Some(svg) = svg {
+        fs::write(&svg, layout.svg())?;
+        info!("Wrote the flash layout to {}", svg.display());
+    }
+
+    Ok(())
+}
+
+fn parse_flash_size(size: &str) -> Result<u32> {
+    let size = size.trim();
+
+    let (number, unit) = match size.find(|c: char| c.is_ascii_alphabetic() && c != 'x') {
+        Some(index) if !size.starts_with("0x") => size.split_at(index),
+        _ => (size, ""),
+    };
+
+    let number = match number.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => number.trim().parse()?,
+    };
+
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        _ => bail!("Unknown flash size unit '{}'", unit),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Flash size {} is too large", size))
+}
@@ -0,0 +1,63 @@
+use std::env;
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct LdscriptArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// PlatformIO environment of the board and partition layout. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// Only check that the linker scripts are up-to-date, printing the diffs
+    #[structopt(long)]
+    check: bool,
+}
+
+pub fn run(args: LdscriptArgs, pio_log_level: LogLevel) -> Result<()> {
+    let LdscriptArgs {
+        pio_install,
+        environment,
+        check,
+    } = args;
+
+    let project = env::current_dir()?;
+    let config = config::Config::load(&project)?;
+
+    if config.linker_scripts.is_empty() {
+        warn!(
+            "No linker scripts configured in {}",
+            config::CONFIG_FILE_NAME
+        );
+        return Ok(());
+    }
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+    let environment = environment.as_deref().unwrap_or("debug");
+
+    let changes = ldscript::generate(&pio, &config, &project, environment, check)?;
+
+    if check && !changes.is_empty() {
+        for (_, diff) in &changes {
+            print!("{}", diff);
+        }
+
+        bail!(
+            "Not up-to-date: {}, run without --check to update",
+            changes
+                .iter()
+                .map(|(path, _)| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+use anyhow::Result;
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct LicensesArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// PlatformIO environment whose libraries to include. Defaults to 'release'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// The file to write the attribution document (markdown) to. Defaults to stdout
+    #[structopt(long, short = "o", parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+pub fn run(args: LicensesArgs, pio_log_level: LogLevel) -> Result<()> {
+    let LicensesArgs {
+        pio_install,
+        environment,
+        output,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+    let project = env::current_dir()?;
+    let environment = environment.as_deref().unwrap_or("release");
+
+    let components = licenses::collect(&pio, &project, environment)?;
+    let report = licenses::report(
+        &project
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        &components,
+    );
+
+    match output {
+        Some(output) => {
+            fs::write(&output, report)?;
+            info!(
+                "Wrote the licenses of {} component(s) to {}",
+                components.len(),
+                output.display()
+            );
+        }
+        None => print!("{}", report),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::env;
+use std::path::Path;
+
+use anyhow::Result;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct LinkcheckArgs {
+    /// PlatformIO environment whose link should be analyzed. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// Symbol which the framework expects the Rust library to provide. Derived from the framework if not specified
+    #[structopt(long = "entry-point")]
+    entry_points: Vec<String>,
+}
+
+pub fn run(args: LinkcheckArgs) -> Result<()> {
+    let LinkcheckArgs {
+        environment,
+        entry_points,
+    } = args;
+
+    let map = link_map(
+        env::current_dir()?,
+        environment.as_deref().unwrap_or("debug"),
+    )?
+    .ok_or_else(|| {
+        anyhow::anyhow!("No linker map file found, did you build your project first?")
+    })?;
+
+    let entry_points = if entry_points.is_empty() {
+        map.framework_entry_points()
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    } else {
+        entry_points
+    };
+
+    let diagnostics = map.diagnostics(&entry_points);
+    if diagnostics.is_empty() {
+        info!("No link problems found");
+    } else {
+        report_link_diagnostics(&diagnostics);
+    }
+
+    Ok(())
+}
+
+pub fn link_map(project: impl AsRef<Path>, environment: &str) -> Result<Option<linkmap::MapFile>> {
+    let map_file = project
+        .as_ref()
+        .join(".pio")
+        .join("build")
+        .join(environment)
+        .join("firmware.map");
+
+    Ok(if map_file.is_file() {
+        Some(linkmap::MapFile::from_file(map_file)?)
+    } else {
+        None
+    })
+}
+
+pub fn report_link_diagnostics(diagnostics: &[linkmap::Diagnostic]) {
+    for diagnostic in diagnostics {
+        warn!("{}", diagnostic);
+    }
+}
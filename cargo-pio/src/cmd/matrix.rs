@@ -0,0 +1,122 @@
+use std::env;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+use super::build::build_with_config;
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct MatrixArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// The feature sets to build, as '<feature>,...;<feature>,...'
+    #[structopt(long)]
+    features: String,
+
+    /// PlatformIO environments to build. Defaults to 'release'
+    #[structopt(long = "environment", short = "e")]
+    environments: Vec<String>,
+
+    /// Prints the results as JSON instead of a table
+    #[structopt(long)]
+    json: bool,
+}
+
+pub fn run(args: MatrixArgs, pio_log_level: LogLevel) -> Result<()> {
+    let MatrixArgs {
+        pio_install,
+        features,
+        mut environments,
+        json,
+    } = args;
+
+    if environments.is_empty() {
+        environments.push("release".into());
+    }
+
+    let entries = build_matrix(
+        &Pio::get(pio_install.pio_path, pio_log_level, false)?,
+        &env::current_dir()?,
+        &environments,
+        &matrix::parse_feature_sets(&features),
+    )?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        print!("{}", matrix::table(&entries));
+    }
+
+    if entries.iter().all(|entry| !entry.success) {
+        bail!("All builds of the matrix failed");
+    }
+
+    Ok(())
+}
+
+/// Build every environment of `environments` with every feature set of `feature_sets`.
+fn build_matrix(
+    pio: &Pio,
+    project: &Path,
+    environments: &[String],
+    feature_sets: &[Vec<String>],
+) -> Result<Vec<matrix::Entry>> {
+    let config = config::Config::load(project)?;
+    let mut entries = Vec::new();
+
+    for environment in environments {
+        for features in feature_sets {
+            info!(
+                "Building environment {} with features [{}]",
+                environment,
+                features.join(", ")
+            );
+
+            let mut config = config.clone();
+            config
+                .env
+                .entry(environment.clone())
+                .or_default()
+                .features
+                .extend(features.iter().cloned());
+
+            let start = std::time::Instant::now();
+            let result = build_with_config(pio, &config, project, environment);
+            let build_time = start.elapsed().as_secs_f64();
+
+            let (flash, ram) = match result {
+                Ok(()) => {
+                    let elf_file = project
+                        .join(".pio")
+                        .join("build")
+                        .join(environment)
+                        .join("firmware.elf");
+                    let (flash, ram) = elf::ElfInfo::from_file(&elf_file)?.memory_usage();
+
+                    (Some(flash), Some(ram))
+                }
+                Err(err) => {
+                    warn!("{:#}", err);
+                    (None, None)
+                }
+            };
+
+            entries.push(matrix::Entry {
+                environment: environment.clone(),
+                features: features.clone(),
+                success: flash.is_some(),
+                build_time,
+                flash,
+                ram,
+            });
+        }
+    }
+
+    Ok(entries)
+}
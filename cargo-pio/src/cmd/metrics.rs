@@ -0,0 +1,145 @@
+use std::env;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum MetricsCommand {
+    /// Shows the recent builds of environments and flags the metrics which grew beyond the thresholds of cargo-pio.toml
+    Trend {
+        /// PlatformIO environment to show, can be repeated. Defaults to all recorded ones
+        #[structopt(long = "environment", short = "e")]
+        environments: Vec<String>,
+
+        /// The number of builds to show per environment
+        #[structopt(long, default_value = "10")]
+        limit: usize,
+
+        /// Fails if the latest build of an environment regressed since the previous one, for CI
+        #[structopt(long)]
+        check: bool,
+    },
+}
+
+pub fn run(cmd: MetricsCommand) -> Result<()> {
+    match cmd {
+        MetricsCommand::Trend {
+            environments,
+            limit,
+            check,
+        } => metrics_trend(&env::current_dir()?, environments, limit, check),
+    }
+}
+
+fn metrics_trend(
+    project: &Path,
+    mut environments: Vec<String>,
+    limit: usize,
+    check: bool,
+) -> Result<()> {
+    let config = config::Config::load(project)?.metrics;
+    let records = metrics::load(project)?;
+
+    if records.is_empty() {
+        bail!(
+            "No metrics recorded in {}, did you build your project first?",
+            metrics::file(project).display()
+        );
+    }
+
+    if environments.is_empty() {
+        for record in &records {
+            if !environments.contains(&record.environment) {
+                environments.push(record.environment.clone());
+            }
+        }
+    }
+
+    let mut regressed = Vec::new();
+
+    for environment in &environments {
+        let recent = metrics::recent(&records, environment, limit);
+
+        if recent.is_empty() {
+            warn!("No metrics recorded for environment {}", environment);
+            continue;
+        }
+
+        let features = &recent[recent.len() - 1].features;
+        if features.is_empty() {
+            println!("Environment {}:", environment);
+        } else {
+            println!(
+                "Environment {} (features {}):",
+                environment,
+                features.join(", ")
+            );
+        }
+        println!("{}", metrics::trend(&recent, &config));
+
+        let latest = recent[recent.len() - 1];
+        if let Some(previous) = recent[..recent.len() - 1]
+            .iter()
+            .rev()
+            .find(|record| record.success)
+        {
+            if !metrics::regressions(previous, latest, &config).is_empty() {
+                regressed.push(environment.as_str());
+            }
+        }
+    }
+
+    if check && !regressed.is_empty() {
+        bail!(
+            "The latest build of {} regressed beyond the thresholds",
+            regressed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+pub fn record_metrics(
+    config: &config::Config,
+    project: &Path,
+    environment: &str,
+    start: std::time::SystemTime,
+    success: bool,
+    cache_stats: Option<compiler_cache::Stats>,
+) -> Result<()> {
+    let (flash, ram) = if success {
+        let elf_file = project
+            .join(".pio")
+            .join("build")
+            .join(environment)
+            .join("firmware.elf");
+        let (flash, ram) = elf::ElfInfo::from_file(&elf_file)?.memory_usage();
+
+        (Some(flash), Some(ram))
+    } else {
+        (None, None)
+    };
+
+    metrics::append(
+        project,
+        &metrics::Record {
+            timestamp: start.duration_since(std::time::UNIX_EPOCH)?.as_secs(),
+            environment: environment.to_owned(),
+            features: config
+                .env(environment)
+                .map(|env| env.features.clone())
+                .unwrap_or_default(),
+            git_describe: stamp::git_describe(project),
+            success,
+            duration: start.elapsed()?.as_secs_f64(),
+            flash,
+            ram,
+            cache_hits: cache_stats.as_ref().map(|stats| stats.hits),
+            cache_misses: cache_stats.as_ref().map(|stats| stats.misses),
+        },
+    )
+}
@@ -0,0 +1,376 @@
+use std::path::{Path, PathBuf};
+use std::{fmt, fs};
+
+use anyhow::{bail, Result};
+use embuild::messages::Message;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+use tempfile::TempDir;
+
+use super::espidf::{call_in_dir, check_pio_first_project, derive_target, resolve_esp_idf_target};
+
+const PLATFORMIO_ESP32_EXCEPTION_DECODER_DIFF: &[u8] =
+    include_bytes!("../patches/filter_exception_decoder_esp32c3_external_conf_fix.diff");
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MonitorDecoder {
+    Timestamp,
+    Backtrace,
+    Defmt,
+}
+
+pub fn parse_monitor_decoder(s: &str) -> MonitorDecoder {
+    match s {
+        "timestamp" => MonitorDecoder::Timestamp,
+        "backtrace" => MonitorDecoder::Backtrace,
+        "defmt" => MonitorDecoder::Defmt,
+        _ => panic!(),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MonitorFilterArgs {
+    /// Highlight the matches of this regular expression, can be repeated
+    ///
+    /// Highlights of other colors can be configured in the [monitor] section of cargo-pio.toml
+    #[structopt(long)]
+    highlight: Vec<String>,
+
+    /// Do not print the lines matching this regular expression, can be repeated
+    #[structopt(long)]
+    suppress: Vec<String>,
+
+    /// End the session successfully once a line matches this regular expression, can be repeated
+    #[structopt(long)]
+    exit_on: Vec<String>,
+
+    /// End the session with exit code 1 once a line matches this regular expression, can be repeated
+    #[structopt(long)]
+    fail_on: Vec<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_esp_idf_monitor<'a>(
+    mut pio: Pio,
+    project: impl AsRef<Path>,
+    port: &'a str,
+    baud_rate: u32,
+    raw: bool,
+    binary: Option<&'a str>,
+    target: Option<&'a str>,
+    environment: Option<&'a str>,
+    decoders: &[MonitorDecoder],
+    addr2line: Option<PathBuf>,
+    log_file: Option<&Path>,
+    filters: &MonitorFilterArgs,
+) -> Result<()> {
+    // The monitor switches the terminal into raw mode
+    let _terminal = terminal::Guard::save();
+
+    let config = config::Config::load(&project)?;
+    let reset = reset::of_environment(&config, &project, environment.unwrap_or("debug")).monitor;
+    let filter = monitor_filter(&config.monitor, filters)?;
+
+    if let Some((agent, port)) = account::remote_port(port) {
+        if !decoders.is_empty() || log_file.is_some() || !filter.is_empty() {
+            warn!("The output of PIO Remote ports is neither decoded, logged nor filtered");
+        }
+
+        let mut cmd = account::remote_cmd(&pio, agent)?;
+        cmd.arg("device")
+            .arg("monitor")
+            .arg("-p")
+            .arg(port)
+            .arg("-b")
+            .arg(baud_rate.to_string());
+
+        if raw {
+            cmd.arg("--raw");
+        }
+
+        return pio.exec(&mut cmd);
+    }
+
+    // PlatformIO cannot monitor WebSocket bridges, nor reset the device once attached,
+    // nor filter the output
+    if !decoders.is_empty()
+        || log_file.is_some()
+        || monitor::transport::is_websocket(port)
+        || reset != config::Reset::NoReset
+        || !filter.is_empty()
+    {
+        let mut chain =
+            monitor_decoder_chain(&project, binary, target, environment, decoders, addr2line)?;
+
+        if !filter.is_empty() {
+            chain.push(Box::new(filter));
+        }
+
+        let mut log = log_file
+            .map(|log_file| {
+                monitor::SessionLog::create(
+                    log_file,
+                    !decoders.contains(&MonitorDecoder::Timestamp),
+                )
+            })
+            .transpose()?;
+
+        return run_decoded_monitor(&pio, port, baud_rate, &reset, &mut chain, log.as_mut());
+    }
+
+    let baud_rate = baud_rate.to_string();
+
+    let mut args = vec![
+        "device",
+        "monitor",
+        "-p",
+        port,
+        "-b",
+        &baud_rate,
+        "--filter",
+        "esp32_exception_decoder",
+    ];
+
+    if raw {
+        args.push("--raw");
+    }
+
+    if let Some(environment) = environment {
+        args.extend(&["-e", environment]);
+    }
+
+    if check_pio_first_project(&project) {
+        let pio = pio.timeout(config.timeouts.monitor());
+
+        call_in_dir(project, move || pio.exec_with_args(&args))
+    } else {
+        let target = derive_target(&project, target)?;
+
+        let resolution = resolve_esp_idf_target(pio.clone(), &target)?;
+
+        let elf_file = monitor_elf_file(&project, binary, Some(&target), environment)?;
+
+        let temp_dir = TempDir::new()?;
+        let project_path = temp_dir.path().join("proj");
+
+        project::Builder::new(&project_path)
+            .enable_c_entry_points()
+            .platform_package_patch(
+                PathBuf::from("patches")
+                    .join("filter_exception_decoder_esp32c3_external_conf_fix.diff"),
+                PathBuf::from("__platform__"),
+            )
+            .enable_scons_dump() // Just a trick to do an early termination of the build
+            .option(project::OPTION_TERMINATE_AFTER_DUMP, "true")
+            .option(project::OPTION_QUICK_DUMP, "true")
+            .generate(&resolution)?;
+
+        let patch_dir = project_path.join("patches");
+
+        fs::create_dir_all(&patch_dir)?;
+        fs::write(
+            patch_dir.join("filter_exception_decoder_esp32c3_external_conf_fix.diff"),
+            PLATFORMIO_ESP32_EXCEPTION_DECODER_DIFF,
+        )?;
+
+        // For now, we need to build the project, as ther build is patching the esp32_exception_decoder filter
+        // so that it supports the environment variables from below, and does proper stacktrace decoding for ESP32C3
+        pio = pio.log_level(LogLevel::Quiet);
+        pio.build(&project_path, Some("release") == environment)?;
+
+        // Need to re-generate the project again or else the filter fails with:
+        // Esp32ExceptionDecoder: disabling, exception while looking for addr2line: Warning! Ignore unknown configuration option `patches` in section [env]
+        // TODO: Address this issue to PlatformIO. Euither custom configurations are supported, or not
+        project::Builder::new(&project_path)
+            .enable_c_entry_points()
+            .generate(&resolution)?;
+
+        let mut cmd = pio.cmd();
+
+        cmd.env(
+            "esp32_exception_decoder_project_strip_dir",
+            project.as_ref().as_os_str(),
+        )
+        .env(
+            "esp32_exception_decoder_firmware_path",
+            elf_file.as_os_str(),
+        )
+        .args(args);
+
+        pio = pio
+            .log_level(LogLevel::Standard)
+            .timeout(config.timeouts.monitor());
+        call_in_dir(project_path, move || pio.exec(&mut cmd))
+    }
+}
+
+fn monitor_elf_file(
+    project: impl AsRef<Path>,
+    binary: Option<&str>,
+    target: Option<&str>,
+    environment: Option<&str>,
+) -> Result<PathBuf> {
+    let elf_file = cargo::Crate::new(&project).get_binary_location(
+        Some("release") == environment,
+        target,
+        binary,
+    )?;
+    if !elf_file.exists() {
+        bail!(Message::new("elf-not-built").arg("path", elf_file.display()));
+    } else if elf_file.is_dir() {
+        bail!("Elf file {} points to a directory", elf_file.display());
+    }
+
+    Ok(elf_file)
+}
+
+pub fn monitor_decoder_chain(
+    project: impl AsRef<Path>,
+    binary: Option<&str>,
+    target: Option<&str>,
+    environment: Option<&str>,
+    decoders: &[MonitorDecoder],
+    addr2line: Option<PathBuf>,
+) -> Result<Vec<Box<dyn monitor::Decoder>>> {
+    if let Some((_, decoders)) = decoders.split_last() {
+        if decoders.contains(&MonitorDecoder::Defmt) {
+            bail!("The defmt decoder must be the last decoder");
+        }
+    }
+
+    // Only the backtrace and defmt decoders need the ELF file
+    let elf_file = || -> Result<PathBuf> {
+        let elf_file = if check_pio_first_project(&project) {
+            project
+                .as_ref()
+                .join(".pio")
+                .join("build")
+                .join(environment.unwrap_or("debug"))
+                .join("firmware.elf")
+        } else {
+            let target = derive_target(&project, target)?;
+
+            monitor_elf_file(&project, binary, Some(&target), environment)?
+        };
+
+        if !elf_file.is_file() {
+            bail!(Message::new("elf-not-built").arg("path", elf_file.display()));
+        }
+
+        Ok(elf_file)
+    };
+
+    let mut addr2line = addr2line;
+    let mut chain = Vec::<Box<dyn monitor::Decoder>>::new();
+
+    for decoder in decoders {
+        chain.push(match decoder {
+            MonitorDecoder::Timestamp => Box::new(monitor::TimestampDecoder::new()),
+            MonitorDecoder::Backtrace => Box::new(monitor::BacktraceDecoder::new(
+                elf_file()?,
+                addr2line.take(),
+            )?),
+            MonitorDecoder::Defmt => {
+                Box::new(monitor::DefmtDecoder::new(elf_file()?, &[] as &[&str])?)
+            }
+        });
+    }
+
+    Ok(chain)
+}
+
+/// The filter of the monitor output, of the `[monitor]` section of the configuration
+/// and of the command line.
+fn monitor_filter(
+    config: &config::MonitorConfig,
+    args: &MonitorFilterArgs,
+) -> Result<monitor::filter::FilterDecoder> {
+    use monitor::filter::{Action, FilterDecoder, Trigger};
+
+    let mut filter = FilterDecoder::new();
+
+    for highlight in &config.highlight {
+        filter = filter.highlight(&highlight.pattern, &highlight.color)?;
+    }
+
+    for pattern in &args.highlight {
+        filter = filter.highlight(pattern, "yellow")?;
+    }
+
+    for pattern in config.suppress.iter().chain(&args.suppress) {
+        filter = filter.suppress(pattern)?;
+    }
+
+    for trigger in &config.trigger {
+        let mut actions = Vec::new();
+
+        if let Some(capture) = &trigger.capture {
+            actions.push(Action::Capture(capture.clone(), trigger.capture_lines));
+        }
+
+        if let Some(run) = &trigger.run {
+            actions.push(Action::Run(run.clone()));
+        }
+
+        if let Some(code) = trigger.exit {
+            actions.push(Action::Exit(code));
+        }
+
+        filter = filter.trigger(Trigger::new(&trigger.pattern, actions)?);
+    }
+
+    for (patterns, code) in [(&args.exit_on, 0), (&args.fail_on, 1)] {
+        for pattern in patterns {
+            filter = filter.trigger(Trigger::new(pattern, vec![Action::Exit(code)])?);
+        }
+    }
+
+    Ok(filter)
+}
+
+/// The end of a monitor session with a non-zero exit code, requested by a trigger.
+#[derive(Debug)]
+pub struct SessionExit(pub i32);
+
+impl fmt::Display for SessionExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The monitor session ended with exit code {}", self.0)
+    }
+}
+
+impl std::error::Error for SessionExit {}
+
+fn run_decoded_monitor(
+    pio: &Pio,
+    port: &str,
+    baud_rate: u32,
+    reset: &config::Reset,
+    chain: &mut [Box<dyn monitor::Decoder>],
+    log: Option<&mut monitor::SessionLog>,
+) -> Result<()> {
+    // The decoders need the unmodified device output
+    let output = monitor::transport::for_port(pio, port, baud_rate)?.connect()?;
+
+    if let Some(mut cmd) = reset::after_attach(pio, reset, Some(port))? {
+        // The serial transport opens the port in PlatformIO, give it the time to
+        if !is_raw_tcp_port(port) && !monitor::transport::is_websocket(port) {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+
+        debug!("Running reset command: {:?}", cmd);
+
+        // The monitor is running already, the output is still of use without the reset
+        match cmd.status() {
+            Ok(status) if status.success() => (),
+            Ok(status) => warn!("Resetting the device failed with {}", status),
+            Err(err) => warn!("Resetting the device failed: {}", err),
+        }
+    }
+
+    match monitor::pipe_logged(output, std::io::stdout(), chain, log)? {
+        Some(code) if code != 0 => Err(SessionExit(code).into()),
+        _ => Ok(()),
+    }
+}
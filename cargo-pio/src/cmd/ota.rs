@@ -0,0 +1,50 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use embuild::pio::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum OtaCommand {
+    /// Uploads a release packaged by 'cargo pio release' (its artifacts, manifest and signed checksums) to an OTA server
+    ///
+    /// The HTTP backend PUTs the files as '<server>/<package>/<version>/<path>' and the manifest as
+    /// '<server>/<package>/latest.json', as does the S3 backend, signed with the AWS credentials of
+    /// the environment. The hawkBit backend creates a software module and a distribution set
+    Push {
+        /// The URL of the server, or of the bucket for S3
+        #[structopt(long)]
+        server: String,
+
+        /// The backend of the server: http, s3 or hawkbit
+        #[structopt(long, default_value = "http")]
+        backend: ota::Backend,
+
+        /// The release directory. Defaults to the release of the current version in '.pio/release'
+        #[structopt(long, parse(from_os_str))]
+        release: Option<PathBuf>,
+
+        /// The token of the server, as '<user>:<password>' for basic authentication. Defaults to the token stored with 'cargo pio auth login'
+        #[structopt(long, env = "CARGO_PIO_OTA_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+}
+
+pub fn run(cmd: OtaCommand) -> Result<()> {
+    match cmd {
+        OtaCommand::Push {
+            server,
+            backend,
+            release,
+            token,
+        } => {
+            let release = match release {
+                Some(dir) => ota::Release::load(dir)?,
+                None => ota::Release::current(env::current_dir()?)?,
+            };
+
+            ota::push(&release, &server, backend, token)
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Result};
+use embuild::error::HintExt;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub enum PkgCommand {
+    /// Exports the dependency graph of the platform, packages and libraries of an environment, with their installed sizes
+    Graph {
+        /// PlatformIO environment whose graph to export. Defaults to 'debug'
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+
+        /// Export the graph as JSON instead of in the Graphviz DOT language
+        #[structopt(long, conflicts_with = "dot")]
+        json: bool,
+
+        /// Export the graph in the Graphviz DOT language (the default)
+        #[structopt(long)]
+        dot: bool,
+
+        /// The file to write the graph to. Defaults to stdout
+        #[structopt(long, short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Locks the installed platforms, packages and libraries of all environments, with their versions and digests
+    Lock {
+        /// The lockfile to write. Defaults to 'cargo-pio.lock'
+        #[structopt(long, short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Browses the platforms and packages in a terminal UI, with their versions, installed state and sizes, to install or remove them
+    Browse,
+    /// Checks the installed platforms, packages and libraries against a lockfile, failing on any drift
+    Check {
+        /// The lockfile to check against. Defaults to 'cargo-pio.lock'
+        #[structopt(long, parse(from_os_str))]
+        against: Option<PathBuf>,
+    },
+    /// Writes the digests of every file of the installed platform, packages and libraries of an environment, for 'compare' on another machine
+    Fingerprint {
+        /// PlatformIO environment whose packages to fingerprint. Defaults to 'debug'
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+
+        /// The file to write the fingerprint to. Defaults to stdout
+        #[structopt(long, short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Compares the installed packages of an environment with a fingerprint of another machine, listing the files that differ
+    Compare {
+        /// The fingerprint of the other machine, written by 'fingerprint'
+        #[structopt(parse(from_os_str))]
+        other: PathBuf,
+
+        /// PlatformIO environment whose packages to compare. Defaults to 'debug'
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+
+        /// Compare with this fingerprint instead of the installed packages
+        #[structopt(long, parse(from_os_str))]
+        with: Option<PathBuf>,
+    },
+    /// Removes the superseded versions of the installed platforms and packages, following the [retention] policy of cargo-pio.toml
+    Gc {
+        /// The number of versions kept of every platform and package. Defaults to 'keep' of the [retention] policy
+        #[structopt(long)]
+        keep: Option<usize>,
+
+        /// Only list the versions that would be removed
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Lists the entries of a package or SDK archive (.tar, .tar.gz, .tgz, .tar.xz, .tar.zst or .zip) without unpacking it
+    Inspect {
+        /// The archive
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
+    },
+    /// Prints the release notes of an installed platform or package between its installed and latest versions
+    Changelog {
+        /// The name of the platform or package, e.g. 'espressif32' or 'framework-espidf'
+        name: String,
+
+        /// The GitHub repository of the releases. Defaults to the repository of the manifest of the platform or package
+        #[structopt(long)]
+        repository: Option<String>,
+    },
+}
+
+pub fn run(pio_install: PioInstallation, cmd: PkgCommand, pio_log_level: LogLevel) -> Result<()> {
+    match cmd {
+        PkgCommand::Lock { output } => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+            let project = env::current_dir()?;
+
+            let lockfile = lock::Lockfile::collect(&pio, &project)?;
+            let output = output.unwrap_or_else(|| project.join(lock::LOCK_FILE_NAME));
+
+            lockfile.save(&output)?;
+
+            info!(
+                "Locked {} environment(s) in {}",
+                lockfile.environments.len(),
+                output.display()
+            );
+
+            Ok(())
+        }
+        PkgCommand::Browse => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+            if terminal::size().is_none() {
+                bail!("Browsing the packages needs a terminal");
+            }
+
+            info!("Listing the platforms and packages");
+
+            browse_packages(&pio, browse::Browser::new(browse::entries(&pio)))
+        }
+        PkgCommand::Changelog { name, repository } => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+            let changelog = changelog::fetch(
+                &credentials::KeyringClient,
+                &pio,
+                &name,
+                repository.as_deref(),
+            )?;
+
+            print!("{}", changelog.render());
+
+            Ok(())
+        }
+        PkgCommand::Inspect { archive } => {
+            let entries = unpack::list(&archive)?;
+
+            for entry in &entries {
+                println!("{}", entry);
+            }
+
+            println!(
+                "{} entries, {} unpacked",
+                entries.len(),
+                graph::format_size(unpack::unpacked_size(&entries))
+            );
+
+            Ok(())
+        }
+        PkgCommand::Check { against } => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+            let project = env::current_dir()?;
+
+            let against = against.unwrap_or_else(|| project.join(lock::LOCK_FILE_NAME));
+            let locked = lock::Lockfile::load(&against)?;
+
+            let drift = locked.drift(&lock::Lockfile::collect(&pio, &project)?);
+            for drift in &drift {
+                println!("{}", drift);
+            }
+
+            if !drift.is_empty() {
+                return Err(anyhow!(
+                    "The installed packages drifted from {} in {} place(s)",
+                    against.display(),
+                    drift.len()
+                ))
+                .hint(
+                    "Install the locked versions, or run `cargo pio pkg lock` to accept the installed ones",
+                );
+            }
+
+            info!("The installed packages match {}", against.display());
+
+            Ok(())
+        }
+        PkgCommand::Gc { keep, dry_run } => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+            let project = env::current_dir()?;
+
+            let mut retention = config::Config::load(&project)?.retention;
+            if keep.is_some() {
+                retention.keep = keep;
+            }
+
+            if retention.keep.is_none() {
+                return Err(anyhow!("No retention policy configured")).hint(
+                    "Set `keep` in the [retention] section of cargo-pio.toml, or pass --keep",
+                );
+            }
+
+            if retention::enforce(&pio, &retention, &project, dry_run)?.is_empty() {
+                info!("No superseded versions installed");
+            }
+
+            Ok(())
+        }
+        PkgCommand::Fingerprint {
+            environment,
+            output,
+        } => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+            let tree = tree_digest::TreeDigest::collect(
+                &pio,
+                env::current_dir()?,
+                environment.as_deref().unwrap_or("debug"),
+            )?;
+
+            match output {
+                Some(output) => {
+                    tree.save(&output)?;
+                    info!(
+                        "Wrote the fingerprint of {} package(s) to {}",
+                        tree.packages.len(),
+                        output.display()
+                    );
+                }
+                None => print!("{}", tree.render()),
+            }
+
+            Ok(())
+        }
+        PkgCommand::Compare {
+            other,
+            environment,
+            with,
+        } => {
+            let theirs = tree_digest::TreeDigest::load(&other)?;
+            let ours = match with {
+                Some(with) => tree_digest::TreeDigest::load(with)?,
+                None => tree_digest::TreeDigest::collect(
+                    &Pio::get(pio_install.pio_path, pio_log_level, false)?,
+                    env::current_dir()?,
+                    environment.as_deref().unwrap_or("debug"),
+                )?,
+            };
+
+            let differences = ours.compare(&theirs);
+            for difference in &differences {
+                println!("{}", difference);
+            }
+
+            if !differences.is_empty() {
+                bail!(
+                    "The packages differ from {} in {} place(s)",
+                    other.display(),
+                    differences.len()
+                );
+            }
+
+            info!("The packages match {}", other.display());
+
+            Ok(())
+        }
+        PkgCommand::Graph {
+            environment,
+            json,
+            dot,
+            output,
+        } => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+            let graph = graph::Graph::collect(
+                &pio,
+                env::current_dir()?,
+                environment.as_deref().unwrap_or("debug"),
+            )?;
+
+            let rendered = if dot || !json {
+                graph.dot()
+            } else {
+                graph.json()?
+            };
+
+            match output {
+                Some(output) => {
+                    fs::write(&output, rendered)?;
+                    info!(
+                        "Wrote the graph of {} package(s) ({} installed) to {}",
+                        graph.nodes.len(),
+                        graph::format_size(graph.size()),
+                        output.display()
+                    );
+                }
+                None => print!("{}", rendered),
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Run the terminal UI of `browser` until quit, running the installs and removals
+/// in between.
+fn browse_packages(pio: &Pio, mut browser: browse::Browser) -> Result<()> {
+    use std::io::{Read, Write};
+
+    loop {
+        let action = {
+            let _terminal = terminal::Guard::raw()?;
+
+            let mut stdout = std::io::stdout();
+            // The alternate screen, without the cursor
+            write!(stdout, "\x1b[?1049h\x1b[?25l")?;
+
+            let result = (|| -> Result<browse::Action> {
+                loop {
+                    let (columns, rows) = terminal::size().unwrap_or((80, 24));
+
+                    stdout.write_all(browser.render(columns as usize, rows as usize).as_bytes())?;
+                    stdout.flush()?;
+
+                    let mut input = [0; 64];
+                    let len = std::io::stdin().read(&mut input)?;
+                    if len == 0 {
+                        return Ok(browse::Action::Quit);
+                    }
+
+                    for key in browse::parse_keys(&input[..len]) {
+                        match browser.key(key, rows as usize) {
+                            browse::Action::None => (),
+                            action => return Ok(action),
+                        }
+                    }
+                }
+            })();
+
+            write!(stdout, "\x1b[?25h\x1b[?1049l")?;
+            stdout.flush()?;
+
+            result?
+        };
+
+        match action {
+            browse::Action::Pio(args) => {
+                if let Err(err) = pio.exec_with_args(&args) {
+                    error!("{:#}", err);
+                }
+
+                eprint!("Press Enter to return to the browser");
+                std::io::stdin().read_line(&mut String::new())?;
+
+                browser.set_entries(browse::entries(pio));
+            }
+            _ => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use embuild::*;
+use structopt::StructOpt;
+use tempfile::TempDir;
+
+use crate::{PioFrameworkArgs, PioInstallation};
+
+#[derive(Debug, StructOpt)]
+pub struct InstallpioArgs {
+    /// The directory where PlatformIO should be installed. Defaults to ~/.platformio
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CheckpioArgs {
+    /// PlatformIO installation directory to be checked. Defaults to ~/.platformio
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PrintsconsArgs {
+    #[structopt(flatten)]
+    framework_args: PioFrameworkArgs,
+
+    /// Precise Scons environment variables calculation. Simulates a real PlatformIO build
+    #[structopt(long)]
+    precise: bool,
+
+    /// Do a release build
+    #[structopt(short, long)]
+    release: bool,
+
+    /// PlatformIO Scons environment variable to print
+    #[structopt(short = "s", long,
+                possible_values = &["path", "incflags", "libflags", "libdirflags", "libs",
+                                    "linkflags", "link", "linkcom", "mcu", "clangargs"])]
+    var: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExecArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// Pass-through arguments down to PlatformIO
+    #[structopt(required = false, allow_hyphen_values = true, last = true)]
+    pio_args: Vec<OsString>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct WhichArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// The tool, e.g. 'python', 'esptool.py' or 'xtensa-esp32-elf-gcc'
+    tool: String,
+}
+
+pub fn installpio(args: InstallpioArgs, pio_log_level: LogLevel) -> Result<()> {
+    let InstallpioArgs { path } = args;
+
+    Pio::install(path, pio_log_level, false)?;
+
+    let project = env::current_dir()?;
+    notify::send(
+        &config::Config::load(&project)?.notify,
+        &notify::Notification::new(notify::Event::PkgInstall, true, &project),
+    );
+
+    Ok(())
+}
+
+pub fn checkpio(args: CheckpioArgs, pio_log_level: LogLevel) -> Result<()> {
+    let CheckpioArgs { path } = args;
+
+    Pio::get(path, pio_log_level, false)?;
+    Ok(())
+}
+
+pub fn printscons(args: PrintsconsArgs, pio_log_level: LogLevel) -> Result<()> {
+    let PrintsconsArgs {
+        mut framework_args,
+        precise,
+        var,
+        release,
+    } = args;
+
+    let pio = Pio::get(
+        framework_args.pio_install.pio_path.take(),
+        pio_log_level,
+        false,
+    )?;
+
+    let scons_vars = get_framework_scons_vars(
+        &pio,
+        release,
+        !precise,
+        &framework_args.resolve(pio.clone())?,
+    )?;
+
+    if let Some(var) = var {
+        let scons_var = match &var[..] {
+            "path" => scons_vars.path,
+            "incflags" => scons_vars.incflags,
+            "libflags" => scons_vars.libflags,
+            "libdirflags" => scons_vars.libdirflags,
+            "libs" => scons_vars.libs,
+            "linkflags" => scons_vars.linkflags,
+            "link" => scons_vars.link,
+            "linkcom" => scons_vars.linkcom,
+            "mcu" => scons_vars.mcu,
+            "clangargs" => scons_vars.clangargs.unwrap_or_else(|| "".into()),
+            _ => panic!(),
+        };
+
+        println!("{}", scons_var);
+    } else {
+        println!("{:?}", scons_vars);
+    }
+
+    Ok(())
+}
+
+pub fn exec(args: ExecArgs, pio_log_level: LogLevel) -> Result<()> {
+    let ExecArgs {
+        pio_install,
+        pio_args: args,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+    // The command may be interactive, e.g. 'device monitor'
+    let _terminal = terminal::Guard::save();
+
+    let mut cmd = pio.cmd();
+    cmd.args(&args);
+
+    if args
+        .first()
+        .map_or(false, |command| command == "remote" || command == "account")
+    {
+        account::authenticate(&mut cmd)?;
+    }
+
+    let result = pio.exec(&mut cmd);
+
+    if let Some(event) = notify::Event::of_pio_args(&args) {
+        let project = env::current_dir()?;
+
+        notify::send(
+            &config::Config::load(&project)?.notify,
+            &notify::Notification {
+                args: args
+                    .iter()
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect(),
+                ..notify::Notification::new(event, result.is_ok(), &project)
+            },
+        );
+    }
+
+    result
+}
+
+pub fn which(args: WhichArgs, pio_log_level: LogLevel) -> Result<()> {
+    let WhichArgs { pio_install, tool } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+    let locations = inspect::which(&pio, &tool);
+    if locations.is_empty() {
+        bail!("{} not found", tool);
+    }
+
+    for (index, location) in locations.iter().enumerate() {
+        println!(
+            "{}{} ({})",
+            if index == 0 { "" } else { "  shadowed: " },
+            location.path.display(),
+            location.source
+        );
+    }
+
+    Ok(())
+}
+
+fn get_framework_scons_vars(
+    pio: &Pio,
+    release: bool,
+    quick: bool,
+    resolution: &Resolution,
+) -> Result<project::SconsVariables> {
+    let temp_dir = TempDir::new()?;
+    let project_path = temp_dir.path().join("proj");
+
+    let mut builder = project::Builder::new(&project_path);
+
+    builder
+        .enable_scons_dump()
+        .option(project::OPTION_TERMINATE_AFTER_DUMP, "true");
+
+    if quick {
+        builder.option(project::OPTION_QUICK_DUMP, "true");
+    }
+
+    builder.generate(resolution)?;
+
+    pio.build(&project_path, release)?;
+
+    project::SconsVariables::from_dump(project_path)
+}
@@ -0,0 +1,70 @@
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct PortsArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// Keep watching the devices, printing a line for every device that is connected or disconnected
+    #[structopt(long, short = "w")]
+    watch: bool,
+
+    /// Seconds between two polls of the devices when watching
+    #[structopt(long, default_value = "1")]
+    interval: f64,
+
+    /// Print the devices or events as JSON, one object per line
+    #[structopt(long)]
+    json: bool,
+}
+
+pub fn run(args: PortsArgs, pio_log_level: LogLevel) -> Result<()> {
+    let PortsArgs {
+        pio_install,
+        watch,
+        interval,
+        json,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+    if !watch {
+        for device in detect::devices(&pio, false)? {
+            if json {
+                println!("{}", device.to_json());
+            } else {
+                println!("{}", device);
+            }
+        }
+
+        return Ok(());
+    }
+
+    if interval.is_nan() || interval <= 0.0 {
+        bail!("The interval must be positive, got {}", interval);
+    }
+
+    let mut stdout = std::io::stdout();
+    detect::watch(
+        &pio,
+        std::time::Duration::from_secs_f64(interval),
+        true,
+        |event| {
+            use std::io::Write;
+
+            if json {
+                writeln!(stdout, "{}", event.to_json())?;
+            } else {
+                writeln!(stdout, "{}", event)?;
+            }
+
+            // Flush for the scripts reading the events through a pipe.
+            stdout.flush()?;
+            Ok(())
+        },
+    )
+}
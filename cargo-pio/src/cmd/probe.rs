@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub enum ProbeCommand {
+    /// Prints the command line of the debug server and writes the OpenOCD configuration
+    Config {
+        #[structopt(flatten)]
+        probe: ProbeArgs,
+
+        /// Writes the OpenOCD configuration to this file instead of printing it
+        #[structopt(long, short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Runs the debug server, installing its PlatformIO package if necessary
+    Server {
+        #[structopt(flatten)]
+        probe: ProbeArgs,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ProbeArgs {
+    /// PlatformIO environment of the board. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// The debug tool (probe), e.g. 'stlink', 'jlink' or 'esp-prog'
+    ///
+    /// Defaults to the 'debug_tool' of the environment, else to the default tool of the board
+    #[structopt(long)]
+    tool: Option<String>,
+
+    /// The port of the GDB server
+    #[structopt(long, default_value = "3333")]
+    gdb_port: u16,
+}
+
+pub fn run(pio_install: PioInstallation, cmd: ProbeCommand, pio_log_level: LogLevel) -> Result<()> {
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+    let (ProbeCommand::Config { probe, .. } | ProbeCommand::Server { probe }) = &cmd;
+    let server = probe::Server::of_environment(
+        &pio,
+        env::current_dir()?,
+        probe.environment.as_deref().unwrap_or("debug"),
+        probe.tool.as_deref(),
+        probe.gdb_port,
+    )?;
+
+    match cmd {
+        ProbeCommand::Config { output, .. } => {
+            println!(
+                "{} {} (of package {})",
+                server.name(),
+                server.args().join(" "),
+                server.package()
+            );
+
+            match (server.openocd_cfg(), output) {
+                (Some(cfg), Some(output)) => {
+                    fs::write(&output, cfg)?;
+                    info!("Wrote the OpenOCD configuration to {}", output.display());
+                }
+                (Some(cfg), None) => print!("\n{}", cfg),
+                (None, Some(_)) => bail!("The J-Link GDB server has no configuration file"),
+                (None, None) => (),
+            }
+
+            Ok(())
+        }
+        ProbeCommand::Server { .. } => {
+            let mut cmd = server.command(&pio)?;
+
+            debug!("Running debug server: {:?}", cmd);
+
+            let status = interrupt::status(&mut cmd)?;
+            if !status.success() {
+                bail!("The debug server failed with {}", status);
+            }
+
+            Ok(())
+        }
+    }
+}
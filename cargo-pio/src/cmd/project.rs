@@ -0,0 +1,285 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use embuild::cargo::CargoCmd;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::{GitArgs, PioIniArgs};
+
+#[derive(Debug, StructOpt)]
+pub struct NewArgs {
+    #[structopt(flatten)]
+    pio_ini_args: PioIniArgs,
+
+    #[structopt(flatten)]
+    git_args: GitArgs,
+
+    /// The directory where the PIO->Cargo project should be created
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+
+    /// Pass-through arguments down to Cargo
+    #[structopt(required = false, allow_hyphen_values = true, last = true)]
+    cargo_args: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct InitArgs {
+    #[structopt(flatten)]
+    pio_ini_args: PioIniArgs,
+
+    #[structopt(flatten)]
+    git_args: GitArgs,
+
+    /// The directory where the PIO->Cargo project should be created
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+
+    /// Pass-through arguments down to Cargo
+    #[structopt(required = false, allow_hyphen_values = true, last = true)]
+    cargo_args: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct UpgradeArgs {
+    #[structopt(flatten)]
+    pio_ini_args: PioIniArgs,
+
+    /// The directory of the existing Cargo library crate. Defaults to the current directory
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+
+    /// Pass-through arguments down to Cargo
+    #[structopt(required = false, allow_hyphen_values = true, last = true)]
+    cargo_args: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct UpdateArgs {
+    /// The directory of the existing Cargo library crate. Defaults to the current directory
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+}
+
+pub fn new(args: NewArgs, pio_log_level: LogLevel) -> Result<()> {
+    let NewArgs {
+        pio_ini_args,
+        git_args,
+        path,
+        cargo_args,
+    } = args;
+
+    create(
+        CargoCmd::New(pio_ini_args.build_std),
+        pio_ini_args,
+        Some(git_args),
+        Some(path),
+        cargo_args,
+        pio_log_level,
+    )
+}
+
+pub fn init(args: InitArgs, pio_log_level: LogLevel) -> Result<()> {
+    let InitArgs {
+        pio_ini_args,
+        git_args,
+        path,
+        cargo_args,
+    } = args;
+
+    create(
+        CargoCmd::Init(pio_ini_args.build_std),
+        pio_ini_args,
+        Some(git_args),
+        path,
+        cargo_args,
+        pio_log_level,
+    )
+}
+
+pub fn upgrade(args: UpgradeArgs, pio_log_level: LogLevel) -> Result<()> {
+    let UpgradeArgs {
+        pio_ini_args,
+        path,
+        cargo_args,
+    } = args;
+
+    create(
+        CargoCmd::Upgrade,
+        pio_ini_args,
+        None,
+        path,
+        cargo_args,
+        pio_log_level,
+    )
+}
+
+pub fn update(args: UpdateArgs) -> Result<()> {
+    let UpdateArgs { path } = args;
+
+    update_project(path.unwrap_or(env::current_dir()?))?;
+    Ok(())
+}
+
+fn create(
+    cargo_cmd: CargoCmd,
+    mut pio_ini_args: PioIniArgs,
+    git_args: Option<GitArgs>,
+    path: Option<PathBuf>,
+    args: Vec<String>,
+    pio_log_level: LogLevel,
+) -> Result<()> {
+    let path = path.unwrap_or(env::current_dir()?);
+    let git = git_args
+        .map(|git_args| git_args.setup(&path))
+        .transpose()?
+        .flatten();
+
+    let pio_path = pio_ini_args.framework_args.pio_install.pio_path.take();
+    let panic = pio_ini_args.panic;
+    let pio = Pio::get(pio_path, pio_log_level, false /*download*/)?;
+    let resolution = pio_ini_args.framework_args.resolve(pio.clone())?;
+
+    let (panic_handler, memory) = if matches!(cargo_cmd, CargoCmd::Upgrade) {
+        (None, None)
+    } else {
+        (
+            select_panic_handler(&resolution, panic)?,
+            board_memory(&pio, &resolution),
+        )
+    };
+
+    create_project(
+        path,
+        cargo_cmd,
+        args.iter(),
+        &resolution,
+        panic_handler,
+        memory,
+        git,
+    )?;
+
+    Ok(())
+}
+
+pub fn create_project<I, S>(
+    project_path: impl AsRef<Path>,
+    cargo_cmd: CargoCmd,
+    cargo_args: I,
+    resolution: &Resolution,
+    panic_handler: Option<runtime::PanicHandler>,
+    memory: Option<runtime::Memory>,
+    git: Option<project::GitSetup>,
+) -> Result<PathBuf>
+where
+    I: Iterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut builder = project::Builder::new(project_path.as_ref());
+
+    builder
+        .enable_git_repos()
+        .enable_platform_packages_patches()
+        .enable_cargo(cargo_cmd)
+        .cargo_options(cargo_args);
+
+    if let Some(panic_handler) = panic_handler {
+        builder.panic_handler(panic_handler);
+    }
+
+    if let Some(memory) = memory {
+        builder.memory(memory);
+    }
+
+    if let Some(git) = git {
+        builder.git(git);
+    }
+
+    builder.generate(resolution)
+}
+
+/// The panic handler of a new crate: `panic` if set, otherwise the one the user selects if
+/// stdin is a terminal, otherwise the default one of its runtime.
+fn select_panic_handler(
+    resolution: &Resolution,
+    panic: Option<runtime::PanicHandler>,
+) -> Result<Option<runtime::PanicHandler>> {
+    let handlers = runtime::Runtime::detect(resolution).panic_handlers(&resolution.target);
+
+    if panic.is_some() || handlers.len() < 2 || !terminal::is_interactive() {
+        return Ok(panic);
+    }
+
+    use std::io::BufRead;
+
+    eprintln!("Panic handler of the crate:");
+    for (index, handler) in handlers.iter().enumerate() {
+        eprintln!(
+            "  {}) {:<12} {}{}",
+            index + 1,
+            handler.name(),
+            handler.description(),
+            if index == 0 { " (default)" } else { "" }
+        );
+    }
+
+    loop {
+        eprint!("Select [1-{}]: ", handlers.len());
+
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        let answer = answer.trim();
+
+        if answer.is_empty() {
+            return Ok(Some(handlers[0]));
+        }
+
+        let selected = answer
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| handlers.get(index.wrapping_sub(1)).copied())
+            .or_else(|| {
+                handlers
+                    .iter()
+                    .copied()
+                    .find(|handler| handler.name() == answer)
+            });
+
+        if let Some(handler) = selected {
+            return Ok(Some(handler));
+        }
+    }
+}
+
+/// The memory of the board of `resolution`, for the `memory.x` of crates without a
+/// framework.
+fn board_memory(pio: &Pio, resolution: &Resolution) -> Option<runtime::Memory> {
+    if !matches!(
+        runtime::Runtime::detect(resolution),
+        runtime::Runtime::CortexMRt | runtime::Runtime::RiscvRt
+    ) {
+        return None;
+    }
+
+    let board = pio
+        .boards(Some(&resolution.board))
+        .map_err(|err| {
+            warn!(
+                "Failed to get the memory of board {}: {}",
+                resolution.board, err
+            )
+        })
+        .ok()?
+        .into_iter()
+        .find(|board| board.id == resolution.board)?;
+
+    Some(runtime::Memory::of_mcu(&board.mcu, board.rom, board.ram))
+}
+
+fn update_project(project_path: impl AsRef<Path>) -> Result<PathBuf> {
+    project::Builder::new(project_path).update()
+}
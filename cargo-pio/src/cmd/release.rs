@@ -0,0 +1,59 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use embuild::pio::*;
+use structopt::StructOpt;
+
+use super::build::build;
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct ReleaseArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// PlatformIO environment to release, can be repeated. Defaults to the configured environments
+    #[structopt(long = "environment", short = "e")]
+    environments: Vec<String>,
+
+    /// Directory to write the release to. Defaults to '.pio/release'
+    #[structopt(long, short = "o", parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Packages the artifacts of the last builds instead of building the environments first
+    #[structopt(long)]
+    no_build: bool,
+}
+
+pub fn run(args: ReleaseArgs, pio_log_level: LogLevel) -> Result<()> {
+    let ReleaseArgs {
+        pio_install,
+        environments,
+        output,
+        no_build,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+    let project = env::current_dir()?;
+
+    let config = config::Config::load(&project)?;
+    let environments = if environments.is_empty() {
+        release::environments(&config)
+    } else {
+        environments
+    };
+
+    if !no_build {
+        for environment in &environments {
+            build(&pio, &project, environment)?;
+        }
+    }
+
+    let output = output.unwrap_or_else(|| project.join(".pio").join("release"));
+    let archive = release::package(&pio, &config, &project, &environments, output)?;
+
+    println!("{}", archive.display());
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+use tempfile::TempDir;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct RemoteBuildArgs {
+    /// The build agent, as '[tcp://]<host>[:<port>]'
+    #[structopt(long)]
+    host: String,
+
+    /// The token the build agent was started with
+    #[structopt(long, env = "CARGO_PIO_AGENT_TOKEN")]
+    token: Option<String>,
+
+    /// Performs a release build
+    ///
+    /// Equivalent to '-e release'
+    #[structopt(long, short, conflicts_with = "environment")]
+    release: bool,
+
+    /// PlatformIO environment to build. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AgentArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// The address to listen on
+    #[structopt(long, default_value = "127.0.0.1:7879")]
+    listen: String,
+
+    /// The directory the projects are built in. Defaults to a temporary directory
+    #[structopt(long, parse(from_os_str))]
+    work_dir: Option<PathBuf>,
+
+    /// Only accept requests carrying this token
+    #[structopt(long, env = "CARGO_PIO_AGENT_TOKEN")]
+    token: Option<String>,
+}
+
+pub fn build(args: RemoteBuildArgs) -> Result<()> {
+    let RemoteBuildArgs {
+        host,
+        token,
+        release,
+        environment,
+    } = args;
+
+    let environment = environment
+        .as_deref()
+        .unwrap_or(if release { "release" } else { "debug" });
+
+    let outcome = remote::build(&host, env::current_dir()?, environment, token.as_deref())?;
+
+    for artifact in &outcome.artifacts {
+        debug!("Received {}", artifact.display());
+    }
+
+    if !outcome.success() {
+        bail!("Building environment {} on {} failed", environment, host);
+    }
+
+    info!(
+        "Received {} artifact(s) of environment {}",
+        outcome.artifacts.len(),
+        environment
+    );
+
+    Ok(())
+}
+
+pub fn agent(args: AgentArgs, pio_log_level: LogLevel) -> Result<()> {
+    let AgentArgs {
+        pio_install,
+        listen,
+        work_dir,
+        token,
+    } = args;
+
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+    // Keep the temporary directory alive for as long as the agent serves
+    let temp_dir;
+    let work_dir = match work_dir {
+        Some(work_dir) => work_dir,
+        None => {
+            temp_dir = TempDir::new()?;
+            temp_dir.path().to_owned()
+        }
+    };
+
+    let mut agent = remote::Agent::new(pio, work_dir);
+    if let Some(token) = token {
+        agent = agent.token(token);
+    }
+
+    agent.serve(listen)
+}
@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+use anyhow::Result;
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct ReportArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// PlatformIO environment to report. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// The file to write the report (markdown) to
+    #[structopt(
+        long,
+        short = "o",
+        parse(from_os_str),
+        default_value = "cargo-pio-report.md"
+    )]
+    output: PathBuf,
+}
+
+pub fn run(args: ReportArgs, pio_log_level: LogLevel) -> Result<()> {
+    let ReportArgs {
+        pio_install,
+        environment,
+        output,
+    } = args;
+
+    // The report is most useful when something is broken, so a missing
+    // PlatformIO installation is reported rather than failed on
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)
+        .map_err(|err| warn!("PlatformIO not found: {:#}", err))
+        .ok();
+
+    let report = report::generate(
+        pio.as_ref(),
+        env::current_dir()?,
+        environment.as_deref().unwrap_or("debug"),
+        concat!("cargo-pio ", env!("CARGO_PKG_VERSION")),
+    )?;
+
+    fs::write(&output, report)?;
+
+    info!(
+        "Wrote {}, review it before attaching it to an issue",
+        output.display()
+    );
+
+    Ok(())
+}
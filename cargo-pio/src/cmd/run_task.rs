@@ -0,0 +1,38 @@
+use std::env;
+
+use anyhow::Result;
+use embuild::pio::*;
+use structopt::StructOpt;
+
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct RunTaskArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// The task to run
+    name: Option<String>,
+}
+
+pub fn run(args: RunTaskArgs, pio_log_level: LogLevel) -> Result<()> {
+    let RunTaskArgs { pio_install, name } = args;
+
+    let project = env::current_dir()?;
+    let config = config::Config::load(&project)?;
+
+    match name {
+        Some(name) => {
+            let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+
+            tasks::run(&pio, &config, &project, &name)
+        }
+        None => {
+            for (name, description) in tasks::list(&config) {
+                println!("{:<20} {}", name, description);
+            }
+
+            Ok(())
+        }
+    }
+}
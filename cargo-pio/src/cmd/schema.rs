@@ -0,0 +1,46 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct SchemaArgs {
+    /// The schema: 'release-manifest' (release.json) or 'package-graph' (pkg graph --json)
+    #[structopt(possible_values = pio_model::schema::SCHEMAS)]
+    name: String,
+
+    /// Validates this file against the schema instead, reporting unknown properties too
+    #[structopt(long, parse(from_os_str))]
+    validate: Option<PathBuf>,
+}
+
+pub fn run(args: SchemaArgs) -> Result<()> {
+    let SchemaArgs { name, validate } = args;
+
+    let schema =
+        pio_model::schema::schema(&name).ok_or_else(|| anyhow!("Unknown schema '{}'", name))?;
+
+    match validate {
+        Some(file) => {
+            let value = serde_json::from_str(&fs::read_to_string(&file)?)
+                .with_context(|| format!("{} is no JSON file", file.display()))?;
+
+            let errors = pio_model::schema::validate(&schema, &value);
+            for error in &errors {
+                println!("{}", error);
+            }
+
+            if !errors.is_empty() {
+                bail!("{} is no valid {}", file.display(), name);
+            }
+
+            info!("{} is a valid {}", file.display(), name);
+        }
+        None => println!("{}", serde_json::to_string_pretty(&schema)?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use std::env;
+
+use anyhow::Result;
+use embuild::pio::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct SdkArgs {
+    /// Install the SDKs again, even if they are installed from the same archives
+    #[structopt(long)]
+    force: bool,
+}
+
+pub fn run(args: SdkArgs) -> Result<()> {
+    let SdkArgs { force } = args;
+
+    let project = env::current_dir()?;
+    let config = config::Config::load(&project)?;
+
+    if config.sdk.is_empty() {
+        warn!("No [[sdk]] sections in {}", config::CONFIG_FILE_NAME);
+    }
+
+    sdk::install_all(
+        &project,
+        &config.sdk,
+        Some(&credentials::KeyringClient),
+        force,
+    )
+}
@@ -0,0 +1,341 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+use super::flash::{esptool_pkg_cmd, run_esptool_pkg_cmd};
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub enum SecureCommand {
+    /// Generates a secure boot (V2) signing key or a flash encryption key
+    Keygen {
+        /// The kind of key: 'signing' (RSA-3072 PEM) or 'encryption' (raw AES key)
+        #[structopt(long, possible_values = &["signing", "encryption"])]
+        kind: KeyKind,
+
+        /// The key file to generate, which must not exist yet
+        #[structopt(parse(from_os_str))]
+        key: PathBuf,
+    },
+    /// Signs the bootloader and application images of an environment for secure boot V2
+    ///
+    /// The signed images are written next to the images as '<image>-signed.bin'
+    Sign {
+        /// PlatformIO environment whose images to sign. Defaults to 'release'
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+
+        /// The signing key (PEM)
+        #[structopt(long, parse(from_os_str))]
+        key: PathBuf,
+    },
+    /// Encrypts the bootloader, partition table and application images of an environment for flash encryption
+    ///
+    /// The encrypted images are written next to the images as '<image>-encrypted.bin',
+    /// the signed images are encrypted instead if they exist
+    Encrypt {
+        /// PlatformIO environment whose images to encrypt. Defaults to 'release'
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+
+        /// The flash encryption key
+        #[structopt(long, parse(from_os_str))]
+        key: PathBuf,
+
+        /// The chip, e.g. 'esp32' or 'esp32c3'
+        #[structopt(long)]
+        chip: String,
+    },
+    /// Burns the digest of a signing key or a flash encryption key into the efuses of a chip
+    ///
+    /// THIS IS IRREVERSIBLE: a chip with a wrong or lost key can no longer be updated
+    BurnKey {
+        /// The kind of key: 'signing' or 'encryption'
+        #[structopt(long, possible_values = &["signing", "encryption"])]
+        kind: KeyKind,
+
+        /// The key file
+        #[structopt(long, parse(from_os_str))]
+        key: PathBuf,
+
+        /// The chip, e.g. 'esp32' or 'esp32c3'
+        #[structopt(long)]
+        chip: String,
+
+        /// The efuse key block. Defaults to 'BLOCK_KEY0' for signing and 'BLOCK_KEY1' for encryption keys (unused on the ESP32)
+        #[structopt(long)]
+        block: Option<String>,
+
+        /// Port of the device. Auto-detected if not specified
+        #[structopt(long, short = "p")]
+        port: Option<String>,
+
+        /// Skip the interactive confirmation, for provisioning scripts
+        #[structopt(long)]
+        yes_burn_efuses: bool,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum KeyKind {
+    Signing,
+    Encryption,
+}
+
+impl std::str::FromStr for KeyKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "signing" => Self::Signing,
+            "encryption" => Self::Encryption,
+            _ => bail!("Unknown key kind '{}'", s),
+        })
+    }
+}
+
+pub fn run(
+    pio_install: PioInstallation,
+    cmd: SecureCommand,
+    pio_log_level: LogLevel,
+) -> Result<()> {
+    let pio = Pio::get(pio_install.pio_path, pio_log_level, false)?;
+    let project = env::current_dir()?;
+    let build_dir = |environment: Option<String>| {
+        project
+            .join(".pio")
+            .join("build")
+            .join(environment.as_deref().unwrap_or("release"))
+    };
+
+    match cmd {
+        SecureCommand::Keygen { kind, key } => secure_keygen(&pio, kind, &key),
+        SecureCommand::Sign { environment, key } => {
+            secure_sign(&pio, &build_dir(environment), &key)
+        }
+        SecureCommand::Encrypt {
+            environment,
+            key,
+            chip,
+        } => secure_encrypt(&pio, &build_dir(environment), &key, &chip),
+        SecureCommand::BurnKey {
+            kind,
+            key,
+            chip,
+            block,
+            port,
+            yes_burn_efuses,
+        } => secure_burn_key(
+            &pio,
+            kind,
+            &key,
+            &chip,
+            block.as_deref(),
+            port.as_deref(),
+            yes_burn_efuses,
+        ),
+    }
+}
+
+fn secure_keygen(pio: &Pio, kind: KeyKind, key: &Path) -> Result<()> {
+    // Overwriting a key whose digest is already burned into chips bricks their updates
+    if key.exists() {
+        bail!("Key file {} already exists", key.display());
+    }
+
+    let mut cmd = esptool_pkg_cmd(pio, "espsecure.py");
+
+    match kind {
+        KeyKind::Signing => cmd.args([
+            "generate_signing_key",
+            "--version",
+            "2",
+            "--scheme",
+            "rsa3072",
+        ]),
+        KeyKind::Encryption => cmd.arg("generate_flash_encryption_key"),
+    };
+
+    run_esptool_pkg_cmd(pio, cmd.arg(key))?;
+
+    warn!(
+        "Generated {}, keep it secret and backed up: chips with its digest burned in can only run images signed with it",
+        key.display()
+    );
+
+    Ok(())
+}
+
+fn secure_sign(pio: &Pio, build_dir: &Path, key: &Path) -> Result<()> {
+    for image in ["bootloader", "firmware"] {
+        let input = build_dir.join(format!("{}.bin", image));
+        if !input.is_file() {
+            bail!(
+                "Image {} does not exist, did you build your project first?",
+                input.display()
+            );
+        }
+
+        let output = build_dir.join(format!("{}-signed.bin", image));
+
+        let mut cmd = esptool_pkg_cmd(pio, "espsecure.py");
+        cmd.args(["sign_data", "--version", "2", "--keyfile"])
+            .arg(key)
+            .arg("--output")
+            .arg(&output)
+            .arg(&input);
+
+        run_esptool_pkg_cmd(pio, &mut cmd)?;
+
+        info!("Signed {}", output.display());
+    }
+
+    Ok(())
+}
+
+fn secure_encrypt(pio: &Pio, build_dir: &Path, key: &Path, chip: &str) -> Result<()> {
+    let partition_table = build_dir.join("partitions.bin");
+    if !partition_table.is_file() {
+        bail!(
+            "Partition table {} does not exist, did you build your project first?",
+            partition_table.display()
+        );
+    }
+
+    let partitions = partitions::Partition::parse_table(&fs::read(&partition_table)?);
+
+    // The first application partition is the factory (or first OTA) partition
+    let app_offset = match partitions
+        .iter()
+        .find(|partition| partition.kind == partitions::TYPE_APP)
+    {
+        Some(partition) => partition.offset,
+        None => bail!("The partition table contains no application partition"),
+    };
+
+    let bootloader_offset = match chip {
+        "esp32" | "esp32s2" => 0x1000,
+        _ => 0x0,
+    };
+
+    for (image, offset) in [
+        ("bootloader", bootloader_offset),
+        ("partitions", 0x8000),
+        ("firmware", app_offset),
+    ] {
+        let signed = build_dir.join(format!("{}-signed.bin", image));
+        let input = if signed.is_file() {
+            signed
+        } else {
+            build_dir.join(format!("{}.bin", image))
+        };
+
+        let output = build_dir.join(format!("{}-encrypted.bin", image));
+
+        let mut cmd = esptool_pkg_cmd(pio, "espsecure.py");
+        cmd.arg("encrypt_flash_data");
+
+        // All chips but the ESP32 use XTS-AES
+        if chip != "esp32" {
+            cmd.arg("--aes_xts");
+        }
+
+        cmd.arg("--keyfile")
+            .arg(key)
+            .arg("--address")
+            .arg(format!("0x{:x}", offset))
+            .arg("--output")
+            .arg(&output)
+            .arg(&input);
+
+        run_esptool_pkg_cmd(pio, &mut cmd)?;
+
+        info!("Encrypted {} for offset 0x{:x}", output.display(), offset);
+    }
+
+    Ok(())
+}
+
+fn secure_burn_key(
+    pio: &Pio,
+    kind: KeyKind,
+    key: &Path,
+    chip: &str,
+    block: Option<&str>,
+    port: Option<&str>,
+    confirmed: bool,
+) -> Result<()> {
+    if !key.is_file() {
+        bail!("Key file {} does not exist", key.display());
+    }
+
+    let mut cmd = esptool_pkg_cmd(pio, "espefuse.py");
+    cmd.arg("--chip").arg(chip);
+
+    if let Some(port) = port {
+        cmd.arg("--port").arg(serial_port_url(port));
+    }
+
+    // cargo-pio asks for confirmation itself, so that scripts can skip it
+    cmd.arg("--do-not-confirm");
+
+    match (kind, chip) {
+        (KeyKind::Signing, "esp32") => cmd.arg("burn_key_digest").arg(key),
+        (KeyKind::Encryption, "esp32") => cmd.args(["burn_key", "flash_encryption"]).arg(key),
+        (KeyKind::Signing, _) => cmd
+            .args(["burn_key_digest", block.unwrap_or("BLOCK_KEY0")])
+            .arg(key)
+            .arg("SECURE_BOOT_DIGEST0"),
+        (KeyKind::Encryption, _) => cmd
+            .args(["burn_key", block.unwrap_or("BLOCK_KEY1")])
+            .arg(key)
+            .arg("XTS_AES_128_KEY"),
+    };
+
+    warn!(
+        "About to burn the {} key {} into the efuses of the {} at {}",
+        match kind {
+            KeyKind::Signing => "secure boot signing",
+            KeyKind::Encryption => "flash encryption",
+        },
+        key.display(),
+        chip,
+        port.unwrap_or("the auto-detected port")
+    );
+    warn!("Efuses can be burned only once: this is IRREVERSIBLE");
+
+    // Only informational, espefuse refuses to burn written key blocks itself
+    match efuse::read(pio, port, Some(chip)) {
+        Ok(summary) => {
+            let security = summary.security();
+
+            if security.secure_boot {
+                warn!("Secure boot is already enabled on this chip");
+            }
+            if security.flash_encryption {
+                warn!("Flash encryption is already enabled on this chip");
+            }
+        }
+        Err(err) => debug!("Failed to read the efuses: {:#}", err),
+    }
+
+    if !confirmed {
+        use std::io::BufRead;
+
+        eprint!("Type BURN to continue: ");
+
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+
+        if answer.trim() != "BURN" {
+            bail!("Aborted, no efuses were burned");
+        }
+    }
+
+    run_esptool_pkg_cmd(pio, &mut cmd)
+}
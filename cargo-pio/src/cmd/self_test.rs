@@ -0,0 +1,240 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{bail, Context, Result};
+use embuild::cargo::CargoCmd;
+use embuild::error::HintExt;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+use tempfile::TempDir;
+
+use super::build::build;
+use super::flash::esptool_pkg_cmd;
+use super::project::create_project;
+use crate::PioFrameworkArgs;
+
+#[derive(Debug, StructOpt)]
+pub struct SelfTestArgs {
+    #[structopt(flatten)]
+    framework_args: PioFrameworkArgs,
+
+    /// Also runs the firmware in this QEMU machine, e.g. 'esp32' or 'lm3s6965evb'
+    #[structopt(long)]
+    qemu: Option<String>,
+
+    /// Seconds to run the firmware in QEMU for
+    #[structopt(long, default_value = "10")]
+    qemu_timeout: u64,
+
+    /// Creates the project in this directory and keeps it, instead of a temporary one
+    #[structopt(long, parse(from_os_str))]
+    keep: Option<PathBuf>,
+}
+
+pub fn run(args: SelfTestArgs, pio_log_level: LogLevel) -> Result<()> {
+    let SelfTestArgs {
+        mut framework_args,
+        qemu,
+        qemu_timeout,
+        keep,
+    } = args;
+
+    if framework_args.board.is_none()
+        && framework_args.mcu.is_none()
+        && framework_args.platform.is_none()
+        && framework_args.target.is_none()
+    {
+        framework_args.board = Some("esp32dev".into());
+    }
+
+    let pio = Pio::get(
+        framework_args.pio_install.pio_path.take(),
+        pio_log_level,
+        false, /*download*/
+    )?;
+
+    let temp_dir = TempDir::new()?;
+    let project = keep.unwrap_or_else(|| temp_dir.path().join("self-test"));
+
+    self_test(
+        &pio,
+        framework_args,
+        &project,
+        qemu.as_deref(),
+        std::time::Duration::from_secs(qemu_timeout),
+    )
+}
+
+/// Create a PIO->Cargo project for the target of `framework_args` in `project`, fetch its
+/// packages, build it and verify its artifacts, running the firmware in the QEMU machine
+/// `qemu` for `qemu_timeout` if given.
+pub fn self_test(
+    pio: &Pio,
+    framework_args: PioFrameworkArgs,
+    project: &Path,
+    qemu: Option<&str>,
+    qemu_timeout: std::time::Duration,
+) -> Result<()> {
+    let resolution = framework_args
+        .resolve(pio.clone())
+        .context("Self-test failed to resolve the target")?;
+
+    info!(
+        "Self-testing board {} (MCU {}, platform {}, target {}) in {}",
+        resolution.board,
+        resolution.mcu,
+        resolution.platform,
+        resolution.target,
+        project.display()
+    );
+
+    let cargo_cmd = if project.exists() {
+        CargoCmd::Init(cargo::BuildStd::Core)
+    } else {
+        CargoCmd::New(cargo::BuildStd::Core)
+    };
+
+    create_project(
+        project,
+        cargo_cmd,
+        std::iter::empty::<&str>(),
+        &resolution,
+        None,
+        None,
+        None,
+    )
+    .context("Self-test failed to create the project")?;
+
+    let mut cmd = pio.cmd();
+    cmd.arg("pkg").arg("install").arg("-d").arg(project);
+
+    let (status, _) = pio.exec_capture(&mut cmd)?;
+    if !status.success() {
+        bail!("Self-test failed to fetch the packages of the project");
+    }
+
+    build(pio, project, "debug").context("Self-test failed to build the project")?;
+
+    let build_dir = project.join(".pio").join("build").join("debug");
+    let elf_file = build_dir.join("firmware.elf");
+
+    let elf = elf::ElfInfo::from_file(&elf_file)
+        .with_context(|| format!("Self-test found no valid firmware {}", elf_file.display()))?;
+
+    if !["firmware.bin", "firmware.hex"]
+        .iter()
+        .any(|image| build_dir.join(image).is_file())
+    {
+        bail!(
+            "Self-test found no firmware image (firmware.bin or firmware.hex) in {}",
+            build_dir.display()
+        );
+    }
+
+    if let Some(machine) = qemu {
+        run_in_qemu(
+            pio,
+            &resolution,
+            &build_dir,
+            elf.machine,
+            machine,
+            qemu_timeout,
+        )?;
+    }
+
+    info!("Self-test passed");
+
+    Ok(())
+}
+
+/// Run the firmware built in `build_dir` in the QEMU `machine` until it exits or
+/// `timeout` passes.
+///
+/// ESP chips boot from a flash image, which is merged from the bootloader, the partition
+/// table and the application; everything else boots the ELF file.
+fn run_in_qemu(
+    pio: &Pio,
+    resolution: &Resolution,
+    build_dir: &Path,
+    elf_machine: u16,
+    machine: &str,
+    timeout: std::time::Duration,
+) -> Result<()> {
+    let qemu = match elf_machine {
+        elf::EM_XTENSA => "qemu-system-xtensa",
+        elf::EM_RISCV => "qemu-system-riscv32",
+        _ => "qemu-system-arm",
+    };
+
+    let mut cmd = std::process::Command::new(qemu);
+    cmd.args(["-nographic", "-machine", machine]);
+
+    if resolution.platform == "espressif32" {
+        let image = build_dir.join("qemu-flash.bin");
+        let bootloader_offset = if matches!(resolution.mcu.as_str(), "esp32" | "esp32s2") {
+            "0x1000"
+        } else {
+            "0x0"
+        };
+
+        let mut merge = esptool_pkg_cmd(pio, "esptool.py");
+        merge
+            .args([
+                "--chip",
+                &resolution.mcu,
+                "merge_bin",
+                "--fill-flash-size",
+                "4MB",
+                "-o",
+            ])
+            .arg(&image)
+            .arg(bootloader_offset)
+            .arg(build_dir.join("bootloader.bin"))
+            .arg("0x8000")
+            .arg(build_dir.join("partitions.bin"))
+            .arg("0x10000")
+            .arg(build_dir.join("firmware.bin"));
+
+        let (status, _) = pio.exec_capture(&mut merge)?;
+        if !status.success() {
+            bail!("Self-test failed to merge the flash image for QEMU");
+        }
+
+        cmd.arg("-drive")
+            .arg(format!("file={},if=mtd,format=raw", image.display()));
+    } else {
+        cmd.arg("-kernel").arg(build_dir.join("firmware.elf"));
+    }
+
+    info!("Running the firmware in QEMU for {}s", timeout.as_secs());
+    debug!("Running QEMU command: {:?}", cmd);
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", qemu))
+        .hint(
+            "Install QEMU with the machines of the target, e.g. Espressif's fork for ESP chips",
+        )?;
+    let _tracked = interrupt::track(&child);
+
+    let start = std::time::Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                bail!("The firmware exited in QEMU with {}", status);
+            }
+
+            return Ok(());
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    child.kill()?;
+    child.wait()?;
+
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use anyhow::{anyhow, bail, Context, Result};
+use embuild::error::HintExt;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+use tempfile::TempDir;
+
+use super::self_test::self_test;
+use crate::{PioFrameworkArgs, PioInstallation};
+
+#[derive(Debug, StructOpt)]
+pub struct SetupArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// Ecosystems to set up: 'esp32', 'stm32', 'nrf' or 'rp2040', can be repeated. Asked for if not given
+    #[structopt(long, short = "e")]
+    ecosystem: Vec<setup::Ecosystem>,
+
+    /// Skips the hello-world builds
+    #[structopt(long)]
+    no_verify: bool,
+}
+
+pub fn run(args: SetupArgs, pio_log_level: LogLevel) -> Result<()> {
+    let SetupArgs {
+        pio_install,
+        ecosystem,
+        no_verify,
+    } = args;
+
+    info!("Setting up cargo-pio on {}", setup::host());
+
+    let ecosystems = if !ecosystem.is_empty() {
+        ecosystem
+    } else if terminal::is_interactive() {
+        select_ecosystems()?
+    } else {
+        return Err(anyhow!("No ecosystems selected"))
+            .hint("Select them with '--ecosystem esp32 --ecosystem rp2040'");
+    };
+
+    let missing = setup::missing_prerequisites(&ecosystems);
+    if !missing.is_empty() {
+        for (tool, hint) in &missing {
+            error!("Missing {}: {}", tool, hint);
+        }
+
+        return Err(anyhow!("Prerequisites missing"))
+            .hint("Install them and run 'cargo pio setup' again");
+    }
+
+    let pio = match Pio::get(pio_install.pio_path.clone(), pio_log_level, false) {
+        Ok(pio) => pio,
+        Err(_) => {
+            info!("Installing PlatformIO");
+            Pio::install(pio_install.pio_path, pio_log_level, false)?
+        }
+    };
+
+    setup::install(&pio, &ecosystems)?;
+
+    if !no_verify {
+        let temp_dir = TempDir::new()?;
+
+        for ecosystem in &ecosystems {
+            info!(
+                "Verifying {} with a hello-world build for board {}",
+                ecosystem,
+                ecosystem.board()
+            );
+
+            let framework_args = PioFrameworkArgs {
+                pio_install: PioInstallation { pio_path: None },
+                board: Some(ecosystem.board().into()),
+                mcu: None,
+                platform: Some(ecosystem.platform().into()),
+                frameworks: None,
+                target: None,
+            };
+
+            self_test(
+                &pio,
+                framework_args,
+                &temp_dir.path().join(ecosystem.platform()),
+                None,
+                std::time::Duration::from_secs(0),
+            )
+            .with_context(|| format!("Setting up {} failed", ecosystem))?;
+        }
+    }
+
+    info!(
+        "Set up {}",
+        ecosystems
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    Ok(())
+}
+
+fn select_ecosystems() -> Result<Vec<setup::Ecosystem>> {
+    use std::io::BufRead;
+
+    eprintln!("Which chips do you target?");
+    for (index, ecosystem) in setup::Ecosystem::ALL.iter().enumerate() {
+        eprintln!("  {}) {}", index + 1, ecosystem);
+    }
+
+    loop {
+        eprint!(
+            "Select one or more [1-{}, all]: ",
+            setup::Ecosystem::ALL.len()
+        );
+
+        let mut answer = String::new();
+        if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+            bail!("No ecosystems selected");
+        }
+
+        match setup::parse_selection(&answer) {
+            Ok(ecosystems) => return Ok(ecosystems),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::path::Path;
+use std::{env, fs};
+
+use anyhow::{bail, Result};
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+use super::linkcheck::link_map;
+use crate::PioInstallation;
+
+#[derive(Debug, StructOpt)]
+pub struct StackArgs {
+    #[structopt(flatten)]
+    pio_install: PioInstallation,
+
+    /// PlatformIO environment to analyze. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// Entry point (task function) to analyze. Derived from the framework if not specified
+    #[structopt(long = "entry-point")]
+    entry_points: Vec<String>,
+
+    /// Rebuild the environment with the GCC flags emitting the stack usage information first
+    #[structopt(long)]
+    rebuild: bool,
+}
+
+pub fn run(args: StackArgs, pio_log_level: LogLevel) -> Result<()> {
+    let StackArgs {
+        pio_install,
+        environment,
+        entry_points,
+        rebuild,
+    } = args;
+
+    stack_usage(
+        &Pio::get(pio_install.pio_path, pio_log_level, false)?,
+        env::current_dir()?,
+        environment.as_deref().unwrap_or("debug"),
+        entry_points,
+        rebuild,
+    )
+}
+
+fn stack_usage(
+    pio: &Pio,
+    project: impl AsRef<Path>,
+    environment: &str,
+    entry_points: Vec<String>,
+    rebuild: bool,
+) -> Result<()> {
+    let project = project.as_ref();
+    let build_dir = project.join(".pio").join("build").join(environment);
+
+    if rebuild {
+        let gcc_major = toolchain_gcc_major(pio, project, environment);
+        if gcc_major.map_or(true, |major| major < stackusage::CALLGRAPH_INFO_GCC) {
+            warn!(
+                "The toolchain is not known to be GCC {} or newer, so the call graph cannot be emitted and the worst cases are lower bounds",
+                stackusage::CALLGRAPH_INFO_GCC
+            );
+        }
+
+        let mut cmd = pio.run_cmd();
+
+        cmd.arg("-e")
+            .arg(environment)
+            .env("PLATFORMIO_BUILD_FLAGS", stackusage::build_flags(gcc_major));
+        config::Config::load(project)?.apply_env(environment, &mut cmd);
+
+        pio.exec(&mut cmd)?;
+    }
+
+    if !build_dir.is_dir() {
+        bail!(
+            "Build directory {} does not exist, did you build your project first?",
+            build_dir.display()
+        );
+    }
+
+    let mut graph = stackusage::CallGraph::new();
+    graph.add_dir(&build_dir)?;
+
+    let elf_file = build_dir.join("firmware.elf");
+    if elf_file.is_file() {
+        let count = graph.add_elf_stack_sizes(&elf_file)?;
+        if count == 0 {
+            info!("No Rust stack usage information found, build with RUSTFLAGS=\"-Z emit-stack-sizes\" to include it");
+        }
+    }
+
+    if graph.functions.is_empty() {
+        bail!("No stack usage information found, run with --rebuild to emit it");
+    }
+
+    let sdkconfig = ["sdkconfig", &format!("sdkconfig.{}", environment)]
+        .iter()
+        .map(|name| project.join(name))
+        .rfind(|path| path.is_file());
+    let sdkconfig = sdkconfig
+        .map(kconfig::try_from_config_file)
+        .transpose()?
+        .map(|values| values.collect::<std::collections::HashMap<_, _>>())
+        .unwrap_or_default();
+
+    let entry_points = if entry_points.is_empty() {
+        link_map(project, environment)?
+            .map(|map| map.framework_entry_points())
+            .unwrap_or_default()
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    } else {
+        entry_points
+    };
+
+    if entry_points.is_empty() {
+        bail!("Cannot derive the entry points of the framework, please use the --entry-point parameter");
+    }
+
+    for entry_point in &entry_points {
+        let worst_case = graph.worst_case(entry_point);
+
+        println!(
+            "{}: {}{} bytes{}",
+            entry_point,
+            if worst_case.unbounded { ">= " } else { "" },
+            worst_case.bytes,
+            if worst_case.unbounded {
+                " (unbounded)"
+            } else {
+                ""
+            }
+        );
+        println!("  worst-case path: {}", worst_case.path.join(" -> "));
+
+        if !worst_case.recursive.is_empty() {
+            println!(
+                "  recursion through: {}",
+                worst_case
+                    .recursive
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        if !worst_case.unknown_calls.is_empty() {
+            println!(
+                "  no call graph information for: {}",
+                worst_case
+                    .unknown_calls
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        if !worst_case.unknown.is_empty() {
+            println!(
+                "  no stack usage information for: {}",
+                worst_case
+                    .unknown
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        if entry_point == "app_main" {
+            if let Some(kconfig::Value::Integer(size)) =
+                sdkconfig.get("CONFIG_ESP_MAIN_TASK_STACK_SIZE")
+            {
+                let size = *size as u64;
+                println!("  configured main task stack: {} bytes", size);
+
+                if worst_case.bytes > size {
+                    warn!(
+                        "The worst-case stack usage of {} exceeds the configured main task stack size (CONFIG_ESP_MAIN_TASK_STACK_SIZE)",
+                        entry_point
+                    );
+                }
+            }
+        }
+    }
+
+    if elf_file.is_file() {
+        let elf = elf::ElfInfo::from_file(&elf_file)?;
+
+        let heap = [
+            ("_heap_start", "_heap_end"),
+            ("__sheap", "__eheap"),
+            ("__heap_start", "__heap_end"),
+        ]
+        .iter()
+        .find_map(|(start, end)| Some((elf.symbol(start)?.address, elf.symbol(end)?.address)));
+
+        if let Some((start, end)) = heap {
+            println!(
+                "Heap: {} bytes (0x{:08x} - 0x{:08x})",
+                end.saturating_sub(start),
+                start,
+                end
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The major version of the oldest GCC of the toolchains of the platform of the
+/// PlatformIO `environment`, `None` if it is unknown.
+fn toolchain_gcc_major(pio: &Pio, project: &Path, environment: &str) -> Option<u32> {
+    let platformio_ini = fs::read_to_string(project.join("platformio.ini")).ok()?;
+    let platform = graph::Platform::resolve(pio, &platformio_ini, environment).ok()?;
+
+    platform
+        .packages
+        .iter()
+        .filter(|package| package.package_type.as_deref() == Some("toolchain"))
+        .filter_map(|package| {
+            let gcc = fs::read_dir(package.installed.dir.join("bin"))
+                .ok()?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .find(|path| {
+                    path.file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .map_or(false, |stem| stem.ends_with("-gcc"))
+                })?;
+
+            let output = std::process::Command::new(gcc)
+                .arg("-dumpversion")
+                .output()
+                .ok()?;
+
+            stackusage::gcc_major(&String::from_utf8_lossy(&output.stdout))
+        })
+        .min()
+}
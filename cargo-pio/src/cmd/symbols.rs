@@ -0,0 +1,100 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use embuild::error::HintExt;
+use embuild::messages::Message;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct SymbolsArgs {
+    /// PlatformIO environment to check. Defaults to 'debug'
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+
+    /// Rule to check, 'panic', 'float', 'alloc' or one of cargo-pio.toml, instead of the denied ones of cargo-pio.toml
+    #[structopt(long)]
+    deny: Vec<String>,
+
+    /// The ELF file to check instead of the firmware of the environment
+    #[structopt(long, parse(from_os_str))]
+    elf: Option<PathBuf>,
+}
+
+pub fn run(args: SymbolsArgs) -> Result<()> {
+    let SymbolsArgs {
+        environment,
+        deny,
+        elf,
+    } = args;
+
+    check_symbols(
+        &env::current_dir()?,
+        environment.as_deref().unwrap_or("debug"),
+        deny,
+        elf,
+    )
+}
+
+fn check_symbols(
+    project: &Path,
+    environment: &str,
+    deny: Vec<String>,
+    elf: Option<PathBuf>,
+) -> Result<()> {
+    let config = config::Config::load(project)?.symbols;
+    let deny = if deny.is_empty() {
+        config.deny.clone()
+    } else {
+        deny
+    };
+
+    if deny.is_empty() {
+        bail!(
+            "No symbols denied, pass '--deny <rule>' or set `deny` in [symbols] of {}",
+            config::CONFIG_FILE_NAME
+        );
+    }
+
+    let rules = deny
+        .iter()
+        .map(|name| match config.rules.get(name) {
+            Some(patterns) => Ok(symcheck::Rule::new(name, patterns)),
+            None => symcheck::Rule::builtin(name)
+                .ok_or_else(|| anyhow!("Unknown rule '{}'", name))
+                .with_hint(|| {
+                    format!(
+                        "Use 'panic', 'float', 'alloc' or define it in [symbols.rules] of {}",
+                        config::CONFIG_FILE_NAME
+                    )
+                }),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let elf_file = elf.unwrap_or_else(|| {
+        project
+            .join(".pio")
+            .join("build")
+            .join(environment)
+            .join("firmware.elf")
+    });
+    if !elf_file.exists() {
+        bail!(Message::new("not-built").arg("path", elf_file.display()));
+    }
+
+    let findings = symcheck::check(&symcheck::call_graph(&elf_file)?, &rules, &config.allow);
+
+    if findings.is_empty() {
+        info!("No denied symbols found ({})", deny.join(", "));
+        return Ok(());
+    }
+
+    for finding in &findings {
+        println!("{}", finding);
+    }
+
+    bail!("Found {} denied symbol(s)", findings.len())
+}
@@ -0,0 +1,134 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use embuild::error::HintExt;
+use embuild::pio::*;
+use embuild::*;
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum ZephyrCommand {
+    /// Initializes the west workspace, or updates its projects to the pinned manifest revision
+    Update,
+    /// Builds the Rust staticlib and the Zephyr application linking it
+    Build {
+        /// Build the staticlib in release mode
+        #[structopt(long)]
+        release: bool,
+    },
+    /// Builds like 'build' and flashes the application with 'west flash'
+    Flash {
+        /// Build the staticlib in release mode
+        #[structopt(long)]
+        release: bool,
+    },
+    /// Lists the PlatformIO boards with a known Zephyr board and Rust target
+    Boards,
+}
+
+pub fn run(cmd: ZephyrCommand) -> Result<()> {
+    let project = env::current_dir()?;
+
+    match cmd {
+        ZephyrCommand::Update => {
+            zephyr_workspace(&project)?.0.update()?;
+
+            Ok(())
+        }
+        ZephyrCommand::Build { release } => {
+            let elf = zephyr_build(&project, release)?;
+            info!("Built {}", elf.display());
+
+            Ok(())
+        }
+        ZephyrCommand::Flash { release } => {
+            let elf = zephyr_build(&project, release)?;
+
+            let (workspace, _) = zephyr_workspace(&project)?;
+
+            let mut cmd = workspace.west();
+            cmd.args(["flash", "--build-dir"])
+                .arg(elf.parent().and_then(Path::parent).unwrap());
+            cmd.run()?;
+
+            Ok(())
+        }
+        ZephyrCommand::Boards => {
+            for board in zephyr::BOARDS {
+                println!("{:<24} {:<36} {}", board.pio, board.zephyr, board.target);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// The west workspace of the project in `project`, installed or updated to the pinned
+/// manifest, and the `[zephyr]` section of its configuration.
+fn zephyr_workspace(project: &Path) -> Result<(zephyr::Workspace, config::ZephyrConfig)> {
+    let zephyr_config = config::Config::load(project)?
+        .zephyr
+        .ok_or_else(|| anyhow!("No [zephyr] section in {}", config::CONFIG_FILE_NAME))
+        .hint("Add a [zephyr] section with at least the board, e.g. board = \"nrf52840_dk\"")?;
+
+    let defaults = zephyr::Manifest::default();
+    let manifest = zephyr::Manifest {
+        url: zephyr_config.manifest.clone().unwrap_or(defaults.url),
+        revision: zephyr_config.revision.clone().unwrap_or(defaults.revision),
+    };
+
+    let workspace = zephyr::Workspace::install(project.join(&zephyr_config.workspace), &manifest)?;
+
+    Ok((workspace, zephyr_config))
+}
+
+/// Build the Rust staticlib of the project in `project` and the Zephyr application
+/// linking it, returning the path of the `zephyr.elf`.
+fn zephyr_build(project: &Path, release: bool) -> Result<PathBuf> {
+    let (workspace, zephyr_config) = zephyr_workspace(project)?;
+
+    let board_name = zephyr_config.board.as_deref().ok_or_else(|| {
+        anyhow!(
+            "No board in the [zephyr] section of {}",
+            config::CONFIG_FILE_NAME
+        )
+    })?;
+    let board = zephyr::board(board_name);
+
+    let target = match (&zephyr_config.target, board) {
+        (Some(target), _) => target.as_str(),
+        (None, Some(board)) => board.target,
+        (None, None) => {
+            return Err(anyhow!(
+                "No Rust target known for the board '{}'",
+                board_name
+            ))
+            .hint("Set the target in the [zephyr] section of cargo-pio.toml")
+        }
+    };
+
+    let mut cargo_args = vec!["--target", target];
+    if release {
+        cargo_args.push("--release");
+    }
+
+    let rust_lib = cargo::Crate::new(project)
+        .build_artifacts(cargo_args)?
+        .iter()
+        .find_map(|artifact| artifact.staticlib().map(Path::to_owned))
+        .ok_or_else(|| anyhow!("The crate produced no staticlib"))
+        .hint("Add crate-type = [\"staticlib\"] to the [lib] section of Cargo.toml")?;
+
+    workspace.build(
+        project.join(&zephyr_config.app),
+        board.map_or(board_name, |board| board.zephyr),
+        project
+            .join("target")
+            .join("zephyr")
+            .join(if release { "release" } else { "debug" }),
+        Some(&rust_lib),
+        &zephyr_config.cmake_args,
+    )
+}
@@ -1,17 +1,13 @@
-use std::ffi::OsString;
+use std::env;
 use std::path::{Path, PathBuf};
-use std::{env, fs};
 
-use anyhow::{bail, Result};
-use embuild::cargo::CargoCmd;
+use anyhow::Result;
 use embuild::pio::*;
 use embuild::*;
 use log::*;
 use structopt::StructOpt;
-use tempfile::TempDir;
 
-const PLATFORMIO_ESP32_EXCEPTION_DECODER_DIFF: &[u8] =
-    include_bytes!("patches/filter_exception_decoder_esp32c3_external_conf_fix.diff");
+mod cmd;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -26,6 +22,11 @@ struct Opt {
     /// Stay quiet, don't print any output
     #[structopt(short, long)]
     quiet: bool,
+    /// The locale of the messages, e.g. 'de'. Defaults to English
+    ///
+    /// Translations are read from $CARGO_PIO_MESSAGES_DIR/<locale>.msg or from share/cargo-pio/messages of the installation
+    #[structopt(long, env = "CARGO_PIO_LOCALE")]
+    locale: Option<String>,
 
     #[structopt(subcommand)]
     cmd: Command,
@@ -34,122 +35,255 @@ struct Opt {
 #[derive(Debug, StructOpt)]
 enum Command {
     /// Installs PlatformIO
-    Installpio {
-        /// The directory where PlatformIO should be installed. Defaults to ~/.platformio
-        #[structopt(parse(from_os_str))]
-        path: Option<PathBuf>,
-    },
+    Installpio(cmd::platformio::InstallpioArgs),
     /// Checks whether PlatformIO is installed
-    Checkpio {
-        /// PlatformIO installation directory to be checked. Defaults to ~/.platformio
-        #[structopt(parse(from_os_str))]
-        path: Option<PathBuf>,
-    },
+    Checkpio(cmd::platformio::CheckpioArgs),
     /// Prints one or all Scons environment variables that would be used when PlatformIO builds a project
-    Printscons {
-        #[structopt(flatten)]
-        framework_args: PioFrameworkArgs,
-
-        /// Precise Scons environment variables calculation. Simulates a real PlatformIO build
-        #[structopt(long)]
-        precise: bool,
-
-        /// Do a release build
-        #[structopt(short, long)]
-        release: bool,
-
-        /// PlatformIO Scons environment variable to print
-        #[structopt(short = "s", long,
-                    possible_values = &["path", "incflags", "libflags", "libdirflags", "libs",
-                                        "linkflags", "link", "linkcom", "mcu", "clangargs"])]
-        var: Option<String>,
-    },
+    Printscons(cmd::platformio::PrintsconsArgs),
     /// Creates a new PIO->Cargo project
-    New {
+    New(cmd::project::NewArgs),
+    /// Creates a new PIO->Cargo project in an existing directory
+    Init(cmd::project::InitArgs),
+    /// Upgrades an existing Cargo library crate to a PIO->Cargo project
+    Upgrade(cmd::project::UpgradeArgs),
+    /// Updates an existing PIO->Cargo project with the latest PlatformIO=>Cargo integration scripts
+    Update(cmd::project::UpdateArgs),
+    /// Builds a PIO->Cargo project (both the Cargo library crate and the PlatformIO build)
+    ///
+    /// Equivalent to executing subcommand 'exec -- run -e debug', with the Cargo features and
+    /// profile configured for the environment in cargo-pio.toml applied
+    Build(cmd::build::BuildArgs),
+    /// Builds every environment with every feature set and compares the firmware sizes
+    ///
+    /// The feature sets are separated by ';' and enabled in addition to the features
+    /// configured for the environment in cargo-pio.toml, e.g. '--features ";wifi;wifi,ble"'
+    /// compares the environment without and with the 'wifi' and 'wifi,ble' features
+    Matrix(cmd::matrix::MatrixArgs),
+    /// Reports the metrics recorded for the builds of a PIO->Cargo project
+    ///
+    /// Every build records its duration, flash and RAM usage and compiler cache hits in
+    /// 'target/cargo-pio/metrics.jsonl', unless disabled with 'record = false' in the '[metrics]'
+    /// section of cargo-pio.toml
+    Metrics {
+        #[structopt(subcommand)]
+        cmd: cmd::metrics::MetricsCommand,
+    },
+    /// Builds a PIO->Cargo project on a remote build agent (experimental)
+    ///
+    /// The project sources are sent to the agent started with 'cargo pio agent', which
+    /// builds them and sends the build output and the artifacts back into '.pio/build/<environment>'
+    RemoteBuild(cmd::remote::RemoteBuildArgs),
+    /// Runs a build agent serving 'cargo pio remote-build' requests (experimental)
+    ///
+    /// The connection is not encrypted, only run the agent on trusted networks
+    Agent(cmd::remote::AgentArgs),
+    /// Builds a PIO->Cargo project and flashes it to one or more devices
+    ///
+    /// With '--all-ports', the firmware is flashed concurrently to every connected USB serial device
+    /// (matching any of the '--match' filters), and a table with the result per device is printed
+    Flash(cmd::flash::FlashArgs),
+    /// Checks that Cargo.toml, the Cargo config and platformio.ini of a PIO->Cargo project are consistent
+    ///
+    /// Checks the staticlib crate type and 'rust_lib', the 'rust_target' of every environment
+    /// against the MCU of its board, the panic strategy and cross-language LTO, and fails
+    /// if there are any errors
+    CheckConfig(cmd::check_config::CheckConfigArgs),
+    /// Analyzes the link of a PIO->Cargo project and explains common Rust<->C integration failures
+    ///
+    /// Reports duplicate symbols, missing entry points and entry points discarded by
+    /// '--gc-sections', based on the linker map file of the last build
+    Linkcheck(cmd::linkcheck::LinkcheckArgs),
+    /// Reports the worst-case stack usage of the entry points (tasks) of a PIO->Cargo project
+    ///
+    /// Uses the stack usage and call graph information emitted by GCC for the C side
+    /// ('-fstack-usage', '-fcallgraph-info=su') and the '.stack_sizes' section emitted by rustc
+    /// for the Rust side ('-Z emit-stack-sizes')
+    Stack(cmd::stack::StackArgs),
+    /// Checks the firmware for denied symbols, e.g. panics, soft-float or allocations
+    ///
+    /// The rules are the ones of '[symbols]' in cargo-pio.toml, or the ones passed with '--deny'.
+    /// Every denied symbol is reported with the functions responsible for it, and the command
+    /// fails if there are any, for use as a CI gate
+    Symbols(cmd::symbols::SymbolsArgs),
+    /// Compares two firmware ELF files
+    ///
+    /// Reports symbol-level size changes, added/removed sections and symbols,
+    /// and symbols or sections whose linker placement changed
+    Diff(cmd::diff::DiffArgs),
+    /// Retrieves an ESP-IDF core dump and prints the thread backtraces it contains
+    ///
+    /// The core dump is read from the 'coredump' flash partition of the device, or extracted from
+    /// a captured console output if '--log' is specified. It is decoded with the GDB of the
+    /// toolchain installed by PlatformIO. Requires CONFIG_ESP_COREDUMP_DATA_FORMAT_ELF
+    Coredump(cmd::coredump::CoredumpArgs),
+    /// Executes PlatformIO in the current directory
+    Exec(cmd::platformio::ExecArgs),
+    /// Invokes commands specific for the ESP-IDF SDK
+    Espidf {
         #[structopt(flatten)]
-        pio_ini_args: PioIniArgs,
-
-        /// The directory where the PIO->Cargo project should be created
-        #[structopt(parse(from_os_str))]
-        path: PathBuf,
+        pio_install: PioInstallation,
 
-        /// Pass-through arguments down to Cargo
-        #[structopt(required = false, allow_hyphen_values = true, last = true)]
-        cargo_args: Vec<String>,
+        #[structopt(subcommand)]
+        cmd: cmd::espidf::EspidfCommand,
     },
-    /// Creates a new PIO->Cargo project in an existing directory
-    Init {
+    /// Generates an attribution document with the licenses of the Cargo dependencies, PlatformIO libraries, platforms and packages of a PIO->Cargo project
+    Licenses(cmd::licenses::LicensesArgs),
+    /// Shows where a tool is resolved from: PlatformIO, its Python environment, the PlatformIO packages or PATH
+    Which(cmd::platformio::WhichArgs),
+    /// Shows the full environment builds of a PIO->Cargo project run in, with the origin of every variable
+    Env(cmd::env::EnvArgs),
+    /// Builds and packages a release of a PIO->Cargo project
+    ///
+    /// Collects the firmware artifacts of the released environments ('[release]' in
+    /// cargo-pio.toml) with a manifest of the code & toolchain versions, SHA-256 checksums and
+    /// an optional signature, and archives them into '<package>-<version>.tar.gz'
+    Release(cmd::release::ReleaseArgs),
+    /// Runs a task of the '[tasks]' in cargo-pio.toml, with its dependencies
+    ///
+    /// The steps of the task run in the project directory with PlatformIO on the PATH and the
+    /// Cargo settings of the environment of the task applied, as the hooks do. Lists the tasks
+    /// if no task is given
+    RunTask(cmd::run_task::RunTaskArgs),
+    /// Uploads releases to OTA servers
+    Ota {
+        #[structopt(subcommand)]
+        cmd: cmd::ota::OtaCommand,
+    },
+    /// Exports a PIO->Cargo project as a standalone PlatformIO project
+    ///
+    /// The exported project links prebuilt Rust libraries of the environments, so that it
+    /// builds with PlatformIO alone, without Cargo or a Rust toolchain
+    Export(cmd::export::ExportArgs),
+    /// Bumps the version of a PIO->Cargo project
+    ///
+    /// Updates the version in Cargo.toml & Cargo.lock, explicit MCUboot image versions in
+    /// cargo-pio.toml and the 'Unreleased' section of CHANGELOG.md, all or none of them
+    Bump(cmd::bump::BumpArgs),
+    /// Shows the board metadata of an environment of a PIO->Cargo project
+    Board(cmd::board::BoardArgs),
+    /// Lists the serial devices and debug probes connected to this machine
+    Ports(cmd::ports::PortsArgs),
+    /// Manages the known devices of this machine, for flashing one of several identical boards by name
+    Device {
         #[structopt(flatten)]
-        pio_ini_args: PioIniArgs,
-
-        /// The directory where the PIO->Cargo project should be created
-        #[structopt(parse(from_os_str))]
-        path: Option<PathBuf>,
+        pio_install: PioInstallation,
 
-        /// Pass-through arguments down to Cargo
-        #[structopt(required = false, allow_hyphen_values = true, last = true)]
-        cargo_args: Vec<String>,
+        #[structopt(subcommand)]
+        cmd: cmd::device::DeviceCommand,
     },
-    /// Upgrades an existing Cargo library crate to a PIO->Cargo project
-    Upgrade {
+    /// Inspects the PlatformIO packages used by a PIO->Cargo project
+    Pkg {
         #[structopt(flatten)]
-        pio_ini_args: PioIniArgs,
-
-        /// The directory of the existing Cargo library crate. Defaults to the current directory
-        #[structopt(parse(from_os_str))]
-        path: Option<PathBuf>,
+        pio_install: PioInstallation,
 
-        /// Pass-through arguments down to Cargo
-        #[structopt(required = false, allow_hyphen_values = true, last = true)]
-        cargo_args: Vec<String>,
+        #[structopt(subcommand)]
+        cmd: cmd::pkg::PkgCommand,
     },
-    /// Updates an existing PIO->Cargo project with the latest PlatformIO=>Cargo integration scripts
-    Update {
-        /// The directory of the existing Cargo library crate. Defaults to the current directory
-        #[structopt(parse(from_os_str))]
-        path: Option<PathBuf>,
+    /// Installs the vendor SDKs of the [[sdk]] sections of cargo-pio.toml as libraries of the project
+    Sdk(cmd::sdk::SdkArgs),
+    /// Builds and flashes the Rust staticlib in a Zephyr application with west, as configured in the [zephyr] section of cargo-pio.toml
+    Zephyr {
+        #[structopt(subcommand)]
+        cmd: cmd::zephyr::ZephyrCommand,
     },
-    /// Builds a PIO->Cargo project (both the Cargo library crate and the PlatformIO build)
+    /// Shows the flash layout: the partitions, the images placed into them and their free space
+    Layout(cmd::layout::LayoutArgs),
+    /// Generates the Rust and C sources embedding the assets configured in cargo-pio.toml
     ///
-    /// Equivalent to executing subcommand 'exec -- run -e debug'
-    Build {
+    /// This is done as part of every build, but can be run on its own so that the sources exist for IDEs or plain Cargo builds
+    Assets,
+    /// Generates the linker scripts of the templates configured in cargo-pio.toml from the board and partition layout
+    ///
+    /// This is done as part of every build, printing the diff of every changed script
+    Ldscript(cmd::ldscript::LdscriptArgs),
+    /// Generates a sanitized report of the tool versions, configuration and last build log of a PIO->Cargo project, for attaching to bug reports
+    Report(cmd::report::ReportArgs),
+    /// Manages the tokens sent with downloads (e.g. of SDKs and registry components), stored in the keyring of the OS
+    Auth {
+        #[structopt(subcommand)]
+        cmd: cmd::auth::AuthCommand,
+    },
+    /// Manages the PlatformIO account of PIO Remote, with its token stored in the keyring of the OS
+    ///
+    /// The token is passed to 'cargo pio exec -- remote ...' and 'cargo pio exec -- account ...',
+    /// and to flashing and monitoring devices of PIO Remote agents ('--port remote://<agent>/<port>')
+    Account {
         #[structopt(flatten)]
         pio_install: PioInstallation,
 
-        /// Performs a release build
-        ///
-        /// Equivalent to executing subcommand 'exec -- run -e release'
-        #[structopt(long, short)]
-        release: bool,
+        #[structopt(subcommand)]
+        cmd: cmd::account::AccountCommand,
     },
-    /// Executes PlatformIO in the current directory
-    Exec {
+    /// Manages secure boot and flash encryption of ESP32 chips: keys, signed and encrypted images and efuses
+    Secure {
         #[structopt(flatten)]
         pio_install: PioInstallation,
 
-        /// Pass-through arguments down to PlatformIO
-        #[structopt(required = false, allow_hyphen_values = true, last = true)]
-        pio_args: Vec<OsString>,
+        #[structopt(subcommand)]
+        cmd: cmd::secure::SecureCommand,
     },
-    /// Invokes commands specific for the ESP-IDF SDK
-    Espidf {
+    /// Reads the efuses of ESP32 chips
+    Efuse {
         #[structopt(flatten)]
         pio_install: PioInstallation,
 
         #[structopt(subcommand)]
-        cmd: EspidfCommand,
+        cmd: cmd::efuse::EfuseCommand,
     },
+    /// Runs or configures the debug server (OpenOCD or the J-Link GDB server) of the debug probe of a board
+    Probe {
+        #[structopt(flatten)]
+        pio_install: PioInstallation,
+
+        #[structopt(subcommand)]
+        cmd: cmd::probe::ProbeCommand,
+    },
+    /// Resolves, pins and checks consistent versions of the ESP32 platform, its frameworks and the GCC toolchain
+    Compat {
+        #[structopt(subcommand)]
+        cmd: cmd::compat::CompatCommand,
+    },
+    /// Generates CI pipelines for a PIO->Cargo project
+    Ci {
+        #[structopt(subcommand)]
+        cmd: cmd::ci::CiCommand,
+    },
+    /// Generates or updates a Dockerfile and a devcontainer definition with the toolchains and PlatformIO packages of the project pre-installed
+    Containerize(cmd::containerize::ContainerizeArgs),
+    /// Starts a daemon keeping PlatformIO loaded, which runs the PlatformIO commands of later invocations without the startup time
+    ///
+    /// Only available on Unix
+    Daemon(cmd::daemon::DaemonArgs),
+    /// Starts PIO Home in the background, whose JSON API answers the PlatformIO queries of later invocations (boards, platforms, packages) without the startup time
+    Home(cmd::home::HomeArgs),
+    /// Tests the whole toolchain by creating, fetching and building a throwaway PIO->Cargo project and verifying its artifacts
+    ///
+    /// Defaults to board 'esp32dev' if neither a board, MCU, platform nor target is given
+    SelfTest(cmd::self_test::SelfTestArgs),
+    /// Sets up the machine: checks the host, installs PlatformIO and the platforms and Rust targets of the selected ecosystems, and verifies each with a hello-world build
+    Setup(cmd::setup::SetupArgs),
+    /// Prints the JSON Schema of a JSON file produced by cargo-pio, or validates a file against it
+    Schema(cmd::schema::SchemaArgs),
+    /// Lists the PlatformIO environments of the project, one per line, for shell completions
+    #[structopt(name = "__list-envs", setting = structopt::clap::AppSettings::Hidden)]
+    ListEnvs(cmd::complete::ListEnvsArgs),
+    /// Lists the ids of the installed boards, one per line, for shell completions
+    #[structopt(name = "__list-boards", setting = structopt::clap::AppSettings::Hidden)]
+    ListBoards(cmd::complete::ListBoardsArgs),
+    /// Lists the serial ports of the connected devices, one per line, for shell completions
+    #[structopt(name = "__list-ports", setting = structopt::clap::AppSettings::Hidden)]
+    ListPorts(cmd::complete::ListPortsArgs),
 }
 
 #[derive(Debug, StructOpt)]
-struct PioInstallation {
+pub struct PioInstallation {
     /// PlatformIO installation directory (default is ~/.platformio)
     #[structopt(short = "i", long = "pio-installation")]
     pio_path: Option<PathBuf>,
 }
 
 #[derive(Debug, StructOpt)]
-struct PioFrameworkArgs {
+pub struct PioFrameworkArgs {
     #[structopt(flatten)]
     pio_install: PioInstallation,
 
@@ -175,7 +309,7 @@ struct PioFrameworkArgs {
 }
 
 #[derive(Debug, StructOpt)]
-struct PioIniArgs {
+pub struct PioIniArgs {
     #[structopt(flatten)]
     framework_args: PioFrameworkArgs,
     /// Selects which part of the standard library cargo should build
@@ -186,62 +320,33 @@ struct PioIniArgs {
     #[structopt(short = "s", long, parse(from_str = parse_build_std),
                 default_value = "core", possible_values = &["none", "core", "std"])]
     build_std: cargo::BuildStd,
+    /// The default panic handler of a new crate, prompted for if not set and stdin is a terminal
+    ///
+    /// The others remain selectable with the `panic-*` features of the crate.
+    #[structopt(long, possible_values = &["halt", "abort", "reset", "semihosting"])]
+    panic: Option<runtime::PanicHandler>,
 }
 
 #[derive(Debug, StructOpt)]
-enum EspidfCommand {
-    /// Generates or updates the ESP-IDF sdkconfig file using the ESP-IDF Menuconfig interactive system
-    Menuconfig {
-        /// Rust target for which the sdkconfig file will be generated or updated
-        #[structopt(short, long)]
-        target: Option<String>,
-
-        /// Indicates release configuration
-        ///
-        /// Equivalent to '-e release'
-        #[structopt(long, short)]
-        release: Option<bool>,
-
-        /// PlatformIO environment to configure
-        ///
-        /// If not specified, the PlatformIO project default environment will be used (or error will be generated if there isn't one)
-        #[structopt(long, short = "e")]
-        environment: Option<String>,
-    },
-    /// Invokes the PlatformIO monitor
-    Monitor {
-        /// Port
-        #[structopt()]
-        port: String,
-
-        /// Baud rate. Defaults to 115200
-        #[structopt(short = "b", long)]
-        baud_rate: Option<u32>,
-
-        /// Do not apply encodings/transformations
-        #[structopt(long)]
-        raw: bool,
-
-        /// Binary name built by this crate for which the monitor will be invoked (necessary for access to the ELF file)
-        #[structopt(long)]
-        binary: Option<String>,
-
-        /// Rust target for which the monitor will be invoked (necessary for access to the ELF file)
-        #[structopt(short, long)]
-        target: Option<String>,
-
-        /// Indicates release configuration
-        ///
-        /// Equivalent to '-e release'
-        #[structopt(long, short)]
-        release: Option<bool>,
-
-        /// PlatformIO environment to monitor
-        ///
-        /// If not specified, the PlatformIO project default environment will be used (or error will be generated if there isn't one)
-        #[structopt(long, short = "e")]
-        environment: Option<String>,
-    },
+pub struct GitArgs {
+    /// Initializes a git repository with an initial commit of the new project, unless it is
+    /// inside a repository already
+    #[structopt(long)]
+    git: bool,
+    /// Tracks the files matching the pattern with Git LFS, besides the 'lfs' patterns of the
+    /// [git] section of cargo-pio.toml (implies --git)
+    #[structopt(long, value_name = "PATTERN")]
+    lfs: Vec<String>,
+}
+
+impl GitArgs {
+    /// The git setup of a new project in `project_dir`, if any.
+    fn setup(self, project_dir: &Path) -> Result<Option<project::GitSetup>> {
+        let mut lfs = config::Config::load(project_dir)?.git.lfs;
+        lfs.extend(self.lfs);
+
+        Ok((self.git || !lfs.is_empty()).then(|| project::GitSetup { commit: true, lfs }))
+    }
 }
 
 fn parse_build_std(s: &str) -> cargo::BuildStd {
@@ -267,7 +372,25 @@ impl PioFrameworkArgs {
     }
 }
 
-fn main() -> Result<()> {
+fn main() {
+    interrupt::install();
+
+    if let Err(err) = run() {
+        if let Some(signal) = interrupt::signal() {
+            eprintln!("Interrupted");
+            interrupt::exit(signal);
+        }
+
+        if let Some(cmd::monitor::SessionExit(code)) = err.downcast_ref() {
+            std::process::exit(*code);
+        }
+
+        eprint!("{}", error::report(&err));
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
     let as_plugin = env::args().nth(1).iter().any(|s| s == "pio");
 
     let args = env::args_os().skip(as_plugin as usize);
@@ -303,445 +426,67 @@ fn main() -> Result<()> {
     .format_timestamp(None)
     .init();
 
-    match opt.cmd {
-        Command::Installpio { path } => {
-            Pio::install(path, pio_log_level, false)?;
-            Ok(())
-        }
-        Command::Checkpio { path } => {
-            Pio::get(path, pio_log_level, false)?;
-            Ok(())
-        }
-        Command::Printscons {
-            mut framework_args,
-            precise,
-            var,
-            release,
-        } => {
-            let pio = Pio::get(
-                framework_args.pio_install.pio_path.take(),
-                pio_log_level,
-                false,
-            )?;
-
-            let scons_vars = get_framework_scons_vars(
-                &pio,
-                release,
-                !precise,
-                &framework_args.resolve(pio.clone())?,
-            )?;
-
-            if let Some(var) = var {
-                let scons_var = match &var[..] {
-                    "path" => scons_vars.path,
-                    "incflags" => scons_vars.incflags,
-                    "libflags" => scons_vars.libflags,
-                    "libdirflags" => scons_vars.libdirflags,
-                    "libs" => scons_vars.libs,
-                    "linkflags" => scons_vars.linkflags,
-                    "link" => scons_vars.link,
-                    "linkcom" => scons_vars.linkcom,
-                    "mcu" => scons_vars.mcu,
-                    "clangargs" => scons_vars.clangargs.unwrap_or_else(|| "".into()),
-                    _ => panic!(),
-                };
-
-                println!("{}", scons_var);
-            } else {
-                println!("{:?}", scons_vars);
-            }
-
-            Ok(())
+    if let Some(locale) = &opt.locale {
+        if let Err(err) = messages::set_locale(locale) {
+            warn!("{:#}, the messages are in English", err);
         }
-        Command::Build {
-            pio_install,
-            release,
-        } => Pio::get(pio_install.pio_path, pio_log_level, false)?.run_with_args(if release {
-            &["-e", "release"]
-        } else {
-            &["-e", "debug"]
-        }),
-        Command::Exec {
-            pio_install,
-            pio_args: args,
-        } => Pio::get(pio_install.pio_path, pio_log_level, false)?.exec_with_args(&args),
-        cmd @ Command::New { .. } | cmd @ Command::Init { .. } | cmd @ Command::Upgrade { .. } => {
-            let (cargo_cmd, mut pio_ini_args, path, args) = match cmd {
-                Command::New {
-                    pio_ini_args,
-                    path,
-                    cargo_args: args,
-                } => (
-                    CargoCmd::New(pio_ini_args.build_std),
-                    pio_ini_args,
-                    Some(path),
-                    args,
-                ),
-                Command::Init {
-                    pio_ini_args,
-                    path,
-                    cargo_args: args,
-                } => (
-                    CargoCmd::Init(pio_ini_args.build_std),
-                    pio_ini_args,
-                    path,
-                    args,
-                ),
-                Command::Upgrade {
-                    pio_ini_args,
-                    path,
-                    cargo_args: args,
-                } => (CargoCmd::Upgrade, pio_ini_args, path, args),
-                _ => unreachable!(),
-            };
-
-            let pio_path = pio_ini_args.framework_args.pio_install.pio_path.take();
-            create_project(
-                path.unwrap_or(env::current_dir()?),
-                cargo_cmd,
-                args.iter(),
-                &pio_ini_args.framework_args.resolve(Pio::get(
-                    pio_path,
-                    pio_log_level,
-                    false, /*download*/
-                )?)?,
-            )?;
-
-            Ok(())
-        }
-        Command::Update { path } => {
-            update_project(path.unwrap_or(env::current_dir()?))?;
-            Ok(())
-        }
-        Command::Espidf {
-            pio_install,
-            cmd:
-                EspidfCommand::Menuconfig {
-                    target,
-                    release,
-                    environment,
-                },
-        } => {
-            run_esp_idf_menuconfig(
-                Pio::get(pio_install.pio_path, pio_log_level, false /*download*/)?,
-                env::current_dir()?,
-                target.as_deref(),
-                if environment.is_some() {
-                    environment.as_deref()
-                } else if let Some(true) = release {
-                    Some("release")
-                } else {
-                    None
-                },
-            )
-        }
-        Command::Espidf {
-            pio_install,
-            cmd:
-                EspidfCommand::Monitor {
-                    port,
-                    baud_rate,
-                    raw,
-                    binary,
-                    target,
-                    release,
-                    environment,
-                },
-        } => {
-            run_esp_idf_monitor(
-                Pio::get(pio_install.pio_path, pio_log_level, false /*download*/)?,
-                env::current_dir()?,
-                &port,
-                baud_rate.unwrap_or(115200),
-                raw,
-                binary.as_deref(),
-                target.as_deref(),
-                if environment.is_some() {
-                    environment.as_deref()
-                } else if let Some(true) = release {
-                    Some("release")
-                } else {
-                    None
-                },
-            )
-        }
-    }
-}
-
-fn run_esp_idf_menuconfig<'a>(
-    pio: Pio,
-    project: impl AsRef<Path>,
-    target: Option<&'a str>,
-    environment: Option<&'a str>,
-) -> Result<()> {
-    let args = if let Some(environment) = environment {
-        vec!["-t", "menuconfig", "-e", environment]
-    } else {
-        vec!["-t", "menuconfig"]
-    };
-
-    if check_pio_first_project(&project) {
-        call_in_dir(project, move || pio.run_with_args(&args))
-    } else {
-        let target = derive_target(project, target)?;
-
-        let resolution = resolve_esp_idf_target(pio.clone(), &target)?;
-
-        let sdkconfigs = &[
-            env::current_dir()?.join("sdkconfig"),
-            env::current_dir()?.join("sdkconfig.debug"),
-        ];
-
-        for sdkconfig in sdkconfigs {
-            if sdkconfig.exists() && sdkconfig.is_dir() {
-                bail!(
-                    "The sdkconfig entry {} is a directory, not a file",
-                    sdkconfig.display()
-                );
-            }
-        }
-
-        let temp_dir = TempDir::new()?;
-        let project_path = temp_dir.path().join("proj");
-
-        project::Builder::new(&project_path)
-            .enable_c_entry_points()
-            .generate(&resolution)?;
-
-        for sdkconfig in sdkconfigs {
-            if sdkconfig.exists() {
-                let dest_sdkconfig = project_path.join(sdkconfig.file_name().unwrap());
-
-                fs::copy(&sdkconfig, &dest_sdkconfig)?;
-            }
-        }
-
-        call_in_dir(&project_path, move || pio.run_with_args(&args))?;
-
-        for sdkconfig in sdkconfigs {
-            let dest_sdkconfig = project_path.join(sdkconfig.file_name().unwrap());
-
-            if dest_sdkconfig.exists() {
-                fs::copy(dest_sdkconfig, sdkconfig)?;
-            }
-        }
-
-        Ok(())
-    }
-}
-
-#[allow(clippy::too_many_arguments)]
-fn run_esp_idf_monitor<'a>(
-    mut pio: Pio,
-    project: impl AsRef<Path>,
-    port: &'a str,
-    baud_rate: u32,
-    raw: bool,
-    binary: Option<&'a str>,
-    target: Option<&'a str>,
-    environment: Option<&'a str>,
-) -> Result<()> {
-    let baud_rate = baud_rate.to_string();
-
-    let mut args = vec![
-        "device",
-        "monitor",
-        "-p",
-        port,
-        "-b",
-        &baud_rate,
-        "--filter",
-        "esp32_exception_decoder",
-    ];
-
-    if raw {
-        args.push("--raw");
-    }
-
-    if let Some(environment) = environment {
-        args.extend(&["-e", environment]);
     }
 
-    if check_pio_first_project(&project) {
-        call_in_dir(project, move || pio.exec_with_args(&args))
-    } else {
-        let target = derive_target(&project, target)?;
-
-        let resolution = resolve_esp_idf_target(pio.clone(), &target)?;
-
-        let elf_file = cargo::Crate::new(&project).get_binary_path(
-            Some("release") == environment,
-            Some(target.as_str()),
-            binary,
-        )?;
-        if !elf_file.exists() {
-            bail!(
-                "Elf file {} does not exist, did you build your project first?",
-                elf_file.display()
-            );
-        } else if elf_file.is_dir() {
-            bail!("Elf file {} points to a directory", elf_file.display());
-        }
-
-        let temp_dir = TempDir::new()?;
-        let project_path = temp_dir.path().join("proj");
-
-        project::Builder::new(&project_path)
-            .enable_c_entry_points()
-            .platform_package_patch(
-                PathBuf::from("patches")
-                    .join("filter_exception_decoder_esp32c3_external_conf_fix.diff"),
-                PathBuf::from("__platform__"),
-            )
-            .enable_scons_dump() // Just a trick to do an early termination of the build
-            .option(project::OPTION_TERMINATE_AFTER_DUMP, "true")
-            .option(project::OPTION_QUICK_DUMP, "true")
-            .generate(&resolution)?;
-
-        let patch_dir = project_path.join("patches");
-
-        fs::create_dir_all(&patch_dir)?;
-        fs::write(
-            patch_dir.join("filter_exception_decoder_esp32c3_external_conf_fix.diff"),
-            PLATFORMIO_ESP32_EXCEPTION_DECODER_DIFF,
-        )?;
-
-        // For now, we need to build the project, as ther build is patching the esp32_exception_decoder filter
-        // so that it supports the environment variables from below, and does proper stacktrace decoding for ESP32C3
-        pio = pio.log_level(LogLevel::Quiet);
-        pio.build(&project_path, Some("release") == environment)?;
-
-        // Need to re-generate the project again or else the filter fails with:
-        // Esp32ExceptionDecoder: disabling, exception while looking for addr2line: Warning! Ignore unknown configuration option `patches` in section [env]
-        // TODO: Address this issue to PlatformIO. Euither custom configurations are supported, or not
-        project::Builder::new(&project_path)
-            .enable_c_entry_points()
-            .generate(&resolution)?;
-
-        let mut cmd = pio.cmd();
-
-        cmd.env(
-            "esp32_exception_decoder_project_strip_dir",
-            project.as_ref().as_os_str(),
-        )
-        .env(
-            "esp32_exception_decoder_firmware_path",
-            elf_file.as_os_str(),
-        )
-        .args(args);
-
-        pio = pio.log_level(LogLevel::Standard);
-        call_in_dir(project_path, move || pio.exec(&mut cmd))
-    }
-}
-
-fn resolve_esp_idf_target(pio: Pio, target: impl AsRef<str>) -> Result<Resolution> {
-    Resolver::new(pio)
-        .params(ResolutionParams {
-            platform: Some("espressif32".into()),
-            frameworks: vec!["espidf".into()],
-            target: Some(target.as_ref().to_owned()),
-            ..Default::default()
-        })
-        .resolve(true)
-}
-
-fn check_pio_first_project(project: impl AsRef<Path>) -> bool {
-    let project = project.as_ref();
-
-    let platformio_ini = project.join("platformio.ini");
-
-    if platformio_ini.exists() && platformio_ini.is_file() {
-        // We are running the monitor on a Pio-first project (possibly a PIO->Cargo one)
-        // Just run the PlatformIO monitor then
-        info!("Found platformio.ini in {}", project.display());
-
-        true
-    } else {
-        info!(
-            "platformio.ini not found in {}, assuming a Cargo-first project",
-            project.display()
-        );
-
-        false
-    }
-}
-
-fn call_in_dir<F, R>(dir: impl AsRef<Path>, f: F) -> Result<R>
-where
-    F: FnOnce() -> Result<R>,
-{
-    let current_dir = env::current_dir()?;
-
-    env::set_current_dir(&dir)?;
-
-    let result = f();
-
-    env::set_current_dir(current_dir)?;
-
-    result
-}
-
-fn derive_target(project: impl AsRef<Path>, target: Option<&str>) -> Result<String> {
-    Ok(if let Some(target) = target {
-        info!("Using explicitly passed target {}", target);
-
-        target.to_owned()
-    } else if let Some(target) = cargo::Crate::new(project).get_default_target()? {
-        info!("Using pre-configured target {}", target);
-
-        target
-    } else {
-        bail!("Cannot find 'target=' specification in any Cargo configuration file. Please use the --target parameter to specify the target on the command line");
-    })
-}
-
-fn get_framework_scons_vars(
-    pio: &Pio,
-    release: bool,
-    quick: bool,
-    resolution: &Resolution,
-) -> Result<project::SconsVariables> {
-    let temp_dir = TempDir::new()?;
-    let project_path = temp_dir.path().join("proj");
-
-    let mut builder = project::Builder::new(&project_path);
-
-    builder
-        .enable_scons_dump()
-        .option(project::OPTION_TERMINATE_AFTER_DUMP, "true");
-
-    if quick {
-        builder.option(project::OPTION_QUICK_DUMP, "true");
+    match opt.cmd {
+        Command::Installpio(args) => cmd::platformio::installpio(args, pio_log_level),
+        Command::Checkpio(args) => cmd::platformio::checkpio(args, pio_log_level),
+        Command::Printscons(args) => cmd::platformio::printscons(args, pio_log_level),
+        Command::New(args) => cmd::project::new(args, pio_log_level),
+        Command::Init(args) => cmd::project::init(args, pio_log_level),
+        Command::Upgrade(args) => cmd::project::upgrade(args, pio_log_level),
+        Command::Update(args) => cmd::project::update(args),
+        Command::Build(args) => cmd::build::run(args, pio_log_level),
+        Command::Matrix(args) => cmd::matrix::run(args, pio_log_level),
+        Command::Metrics { cmd } => cmd::metrics::run(cmd),
+        Command::RemoteBuild(args) => cmd::remote::build(args),
+        Command::Agent(args) => cmd::remote::agent(args, pio_log_level),
+        Command::Flash(args) => cmd::flash::run(args, pio_log_level),
+        Command::CheckConfig(args) => cmd::check_config::run(args, pio_log_level),
+        Command::Linkcheck(args) => cmd::linkcheck::run(args),
+        Command::Stack(args) => cmd::stack::run(args, pio_log_level),
+        Command::Symbols(args) => cmd::symbols::run(args),
+        Command::Diff(args) => cmd::diff::run(args),
+        Command::Coredump(args) => cmd::coredump::run(args, pio_log_level),
+        Command::Exec(args) => cmd::platformio::exec(args, pio_log_level),
+        Command::Espidf { pio_install, cmd } => cmd::espidf::run(pio_install, cmd, pio_log_level),
+        Command::Licenses(args) => cmd::licenses::run(args, pio_log_level),
+        Command::Which(args) => cmd::platformio::which(args, pio_log_level),
+        Command::Env(args) => cmd::env::run(args, pio_log_level),
+        Command::Release(args) => cmd::release::run(args, pio_log_level),
+        Command::RunTask(args) => cmd::run_task::run(args, pio_log_level),
+        Command::Ota { cmd } => cmd::ota::run(cmd),
+        Command::Export(args) => cmd::export::run(args, pio_log_level),
+        Command::Bump(args) => cmd::bump::run(args),
+        Command::Board(args) => cmd::board::run(args, pio_log_level),
+        Command::Ports(args) => cmd::ports::run(args, pio_log_level),
+        Command::Device { pio_install, cmd } => cmd::device::run(pio_install, cmd, pio_log_level),
+        Command::Pkg { pio_install, cmd } => cmd::pkg::run(pio_install, cmd, pio_log_level),
+        Command::Sdk(args) => cmd::sdk::run(args),
+        Command::Zephyr { cmd } => cmd::zephyr::run(cmd),
+        Command::Layout(args) => cmd::layout::run(args),
+        Command::Assets => cmd::assets::run(),
+        Command::Ldscript(args) => cmd::ldscript::run(args, pio_log_level),
+        Command::Report(args) => cmd::report::run(args, pio_log_level),
+        Command::Auth { cmd } => cmd::auth::run(cmd),
+        Command::Account { pio_install, cmd } => cmd::account::run(pio_install, cmd, pio_log_level),
+        Command::Secure { pio_install, cmd } => cmd::secure::run(pio_install, cmd, pio_log_level),
+        Command::Efuse { pio_install, cmd } => cmd::efuse::run(pio_install, cmd, pio_log_level),
+        Command::Probe { pio_install, cmd } => cmd::probe::run(pio_install, cmd, pio_log_level),
+        Command::Compat { cmd } => cmd::compat::run(cmd, pio_log_level),
+        Command::Ci { cmd } => cmd::ci::run(cmd),
+        Command::Containerize(args) => cmd::containerize::run(args),
+        Command::Daemon(args) => cmd::daemon::run(args, pio_log_level),
+        Command::Home(args) => cmd::home::run(args, pio_log_level),
+        Command::SelfTest(args) => cmd::self_test::run(args, pio_log_level),
+        Command::Setup(args) => cmd::setup::run(args, pio_log_level),
+        Command::Schema(args) => cmd::schema::run(args),
+        Command::ListEnvs(args) => cmd::complete::list_envs(args),
+        Command::ListBoards(args) => cmd::complete::list_boards(args),
+        Command::ListPorts(args) => cmd::complete::list_ports(args),
     }
-
-    builder.generate(resolution)?;
-
-    pio.build(&project_path, release)?;
-
-    project::SconsVariables::from_dump(project_path)
-}
-
-fn create_project<I, S>(
-    project_path: impl AsRef<Path>,
-    cargo_cmd: CargoCmd,
-    cargo_args: I,
-    resolution: &Resolution,
-) -> Result<PathBuf>
-where
-    I: Iterator<Item = S>,
-    S: AsRef<str>,
-{
-    let mut builder = project::Builder::new(project_path.as_ref());
-
-    builder
-        .enable_git_repos()
-        .enable_platform_packages_patches()
-        .enable_cargo(cargo_cmd)
-        .cargo_options(cargo_args)
-        .generate(resolution)
-}
-
-fn update_project(project_path: impl AsRef<Path>) -> Result<PathBuf> {
-    project::Builder::new(project_path).update()
 }
@@ -2,6 +2,8 @@
 
 use std::ffi::OsStr;
 use std::fmt::{Display, Write};
+#[cfg(feature = "manifest")]
+use std::io::BufRead;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
@@ -9,6 +11,8 @@ use anyhow::Result;
 #[cfg(feature = "manifest")]
 use cargo_toml::{Manifest, Product};
 use log::*;
+#[cfg(feature = "manifest")]
+use serde::Deserialize;
 
 use crate::utils::{OsStrExt, PathExt};
 use crate::{cargo, cmd};
@@ -33,6 +37,85 @@ pub enum BuildStd {
     Std,
 }
 
+/// An artifact produced by a cargo build, as reported by a `compiler-artifact` message of
+/// `cargo build --message-format json`.
+#[cfg(feature = "manifest")]
+#[derive(Deserialize, Clone, Debug)]
+pub struct Artifact {
+    /// The id of the package the artifact belongs to.
+    pub package_id: String,
+    /// The path of the `Cargo.toml` of the package.
+    pub manifest_path: PathBuf,
+    /// The target (lib, bin, ...) which produced the artifact.
+    pub target: ArtifactTarget,
+    /// All files produced for the target.
+    #[serde(default)]
+    pub filenames: Vec<PathBuf>,
+    /// The path of the executable, if the target is a binary.
+    #[serde(default)]
+    pub executable: Option<PathBuf>,
+}
+
+/// The target of an [`Artifact`].
+#[cfg(feature = "manifest")]
+#[derive(Deserialize, Clone, Debug)]
+pub struct ArtifactTarget {
+    /// The name of the target.
+    pub name: String,
+    /// The kinds of the target (`lib`, `staticlib`, `bin`, ...).
+    #[serde(default)]
+    pub kind: Vec<String>,
+    /// The crate types of the target.
+    #[serde(default)]
+    pub crate_types: Vec<String>,
+}
+
+#[cfg(feature = "manifest")]
+impl Artifact {
+    /// Get the produced file of a `staticlib` target.
+    pub fn staticlib(&self) -> Option<&Path> {
+        if !self.target.crate_types.iter().any(|t| t == "staticlib") {
+            return None;
+        }
+
+        self.filenames
+            .iter()
+            .find(|f| matches!(f.extension().and_then(OsStr::to_str), Some("a" | "lib")))
+            .map(PathBuf::as_path)
+    }
+}
+
+/// Parse the output of `cargo build --message-format json` and return all artifacts.
+///
+/// Lines which are not JSON messages or messages other than `compiler-artifact` are
+/// ignored.
+#[cfg(feature = "manifest")]
+pub fn parse_artifacts(reader: impl BufRead) -> Result<Vec<Artifact>> {
+    #[derive(Deserialize)]
+    struct Message {
+        reason: String,
+    }
+
+    let mut artifacts = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+
+        if !line.starts_with('{') {
+            continue;
+        }
+
+        match serde_json::from_str::<Message>(&line) {
+            Ok(message) if message.reason == "compiler-artifact" => {
+                artifacts.push(serde_json::from_str::<Artifact>(&line)?)
+            }
+            _ => (),
+        }
+    }
+
+    Ok(artifacts)
+}
+
 /// A logical cargo crate that may or may not exist with its directory path.
 #[derive(Clone, Debug)]
 pub struct Crate(PathBuf);
@@ -90,6 +173,22 @@ impl Crate {
         Ok(name)
     }
 
+    /// Add the `dependencies` as `(name, dependency)` and the `features` to the manifest,
+    /// replacing existing ones of the same name.
+    #[cfg(feature = "manifest")]
+    pub(crate) fn add_dependencies(
+        &self,
+        dependencies: impl IntoIterator<Item = (String, cargo_toml::Dependency)>,
+        features: impl IntoIterator<Item = (String, Vec<String>)>,
+    ) -> Result<()> {
+        let mut cargo_toml = self.load_manifest()?;
+
+        cargo_toml.dependencies.extend(dependencies);
+        cargo_toml.features.extend(features);
+
+        self.save_manifest(&cargo_toml)
+    }
+
     /// Check that the library is a `staticlib` and return its name.
     #[cfg(feature = "manifest")]
     pub(crate) fn check_staticlib(&self) -> Result<String> {
@@ -296,6 +395,158 @@ build-std-features = ["panic_immediate_abort"]
             .join(bin_product.name.as_ref().unwrap()))
     }
 
+    /// Build this crate with `cargo build --message-format json` and the additional `args`
+    /// and return the artifacts of this crate.
+    ///
+    /// Cargo reports the exact location of every artifact, so this (unlike
+    /// [`Crate::get_binary_path`]) correctly handles renamed targets, workspaces, custom
+    /// target directories and cross targets. Artifacts which are up-to-date are reported
+    /// as well, in which case nothing gets rebuilt.
+    #[cfg(feature = "manifest")]
+    pub fn build_artifacts(
+        &self,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> Result<Vec<Artifact>> {
+        let manifest_path = self.0.join("Cargo.toml");
+
+        let output = cmd!(
+            "cargo", "build", "--message-format", "json-render-diagnostics", "--manifest-path", &manifest_path,
+            @args,
+            "--quiet"
+        )
+        .stdout()?;
+
+        let manifest_path = manifest_path.canonicalize()?;
+
+        Ok(parse_artifacts(output.as_bytes())?
+            .into_iter()
+            .filter(|artifact| {
+                artifact.manifest_path.canonicalize().ok().as_ref() == Some(&manifest_path)
+            })
+            .collect())
+    }
+
+    /// Get the path to a binary of this crate as reported by cargo (see
+    /// [`Crate::build_artifacts`]), building it if it is out-of-date.
+    #[cfg(feature = "manifest")]
+    pub fn get_binary_artifact<'a>(
+        &self,
+        release: bool,
+        target: Option<&'a str>,
+        binary: Option<&'a str>,
+    ) -> Result<PathBuf> {
+        let mut args = vec!["--bins"];
+
+        if release {
+            args.push("--release");
+        }
+
+        if let Some(target) = target {
+            args.extend(["--target", target]);
+        }
+
+        let binaries = self
+            .build_artifacts(args)?
+            .into_iter()
+            .filter_map(|artifact| Some((artifact.target.name, artifact.executable?)))
+            .collect::<Vec<_>>();
+
+        let binary = if let Some(binary) = binary {
+            binaries
+                .into_iter()
+                .find(|(name, _)| name == binary)
+                .ok_or_else(|| anyhow::anyhow!("Cannot locate binary with name {}", binary))?
+        } else {
+            match binaries.len() {
+                0 => anyhow::bail!("Not a binary crate"),
+                1 => binaries.into_iter().next().unwrap(),
+                _ => anyhow::bail!(
+                    "This crate defines multiple binaries ({:?}), please specify binary name",
+                    binaries.iter().map(|(name, _)| name).collect::<Vec<_>>()
+                ),
+            }
+        };
+
+        Ok(binary.1)
+    }
+
+    /// Get the path a binary of this crate is built to, without building it.
+    ///
+    /// Unlike [`Crate::get_binary_path`], the target directory and the binaries are
+    /// taken from `cargo metadata`, so workspaces, custom target directories and
+    /// renamed binaries are handled, but the binary may not exist yet.
+    #[cfg(feature = "manifest")]
+    pub fn get_binary_location<'a>(
+        &self,
+        release: bool,
+        target: Option<&'a str>,
+        binary: Option<&'a str>,
+    ) -> Result<PathBuf> {
+        #[derive(Deserialize)]
+        struct Metadata {
+            target_directory: PathBuf,
+            packages: Vec<Package>,
+        }
+
+        #[derive(Deserialize)]
+        struct Package {
+            manifest_path: PathBuf,
+            targets: Vec<ArtifactTarget>,
+        }
+
+        let manifest_path = self.0.join("Cargo.toml");
+
+        let output = cmd!(
+            "cargo",
+            "metadata",
+            "--format-version",
+            "1",
+            "--no-deps",
+            "--manifest-path",
+            &manifest_path
+        )
+        .stdout()?;
+        let metadata = serde_json::from_str::<Metadata>(&output)?;
+
+        let manifest_path = manifest_path.canonicalize()?;
+        let binaries = metadata
+            .packages
+            .into_iter()
+            .filter(|package| {
+                package.manifest_path.canonicalize().ok().as_ref() == Some(&manifest_path)
+            })
+            .flat_map(|package| package.targets)
+            .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+            .map(|target| target.name)
+            .collect::<Vec<_>>();
+
+        let binary = if let Some(binary) = binary {
+            binaries
+                .into_iter()
+                .find(|name| name == binary)
+                .ok_or_else(|| anyhow::anyhow!("Cannot locate binary with name {}", binary))?
+        } else {
+            match binaries.len() {
+                0 => anyhow::bail!("Not a binary crate"),
+                1 => binaries.into_iter().next().unwrap(),
+                _ => anyhow::bail!(
+                    "This crate defines multiple binaries ({:?}), please specify binary name",
+                    binaries
+                ),
+            }
+        };
+
+        let mut path = metadata.target_directory;
+
+        if let Some(target) = target {
+            path = path.join(target)
+        }
+
+        Ok(path
+            .join(if release { "release" } else { "debug" })
+            .join(binary))
+    }
+
     /// Get the default target that would be used when building this crate.
     #[cfg(feature = "manifest")]
     pub fn get_default_target(&self) -> Result<Option<String>> {
@@ -453,3 +704,46 @@ pub fn workspace_dir() -> Option<PathBuf> {
     };
     Some(PathBuf::from(env::var_os("OUT_DIR")?).pop_times(pop_count))
 }
+
+#[cfg(all(test, feature = "manifest"))]
+mod tests {
+    use super::*;
+
+    /// Recorded from `cargo build --message-format json-render-diagnostics` of a crate
+    /// with a build script, a library and a binary.
+    const MESSAGES: &str = r#"{"reason":"build-script-executed","package_id":"path+file:///home/user/app#0.1.0","linked_libs":[],"linked_paths":[],"cfgs":[],"env":[],"out_dir":"/home/user/app/target/xtensa-esp32-espidf/debug/build/app-8d1e3c9b5f2a7e41/out"}
+warning: unused variable: `x`
+{"reason":"compiler-artifact","package_id":"path+file:///home/user/app#0.1.0","manifest_path":"/home/user/app/Cargo.toml","target":{"kind":["lib","staticlib"],"crate_types":["lib","staticlib"],"name":"app","src_path":"/home/user/app/src/lib.rs","edition":"2021","doc":true,"doctest":true,"test":true},"profile":{"opt_level":"s","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":["std"],"filenames":["/home/user/app/target/xtensa-esp32-espidf/debug/libapp.rlib","/home/user/app/target/xtensa-esp32-espidf/debug/libapp.a"],"executable":null,"fresh":true}
+{"reason":"compiler-artifact","package_id":"path+file:///home/user/app#0.1.0","manifest_path":"/home/user/app/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"firmware","src_path":"/home/user/app/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"profile":{"opt_level":"s","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":["std"],"filenames":["/home/user/app/target/xtensa-esp32-espidf/debug/firmware"],"executable":"/home/user/app/target/xtensa-esp32-espidf/debug/firmware","fresh":false}
+{"reason":"build-finished","success":true}
+"#;
+
+    #[test]
+    fn test_parse_artifacts() {
+        let artifacts = parse_artifacts(MESSAGES.as_bytes()).unwrap();
+        assert_eq!(artifacts.len(), 2);
+
+        let lib = &artifacts[0];
+        assert_eq!(lib.target.name, "app");
+        assert_eq!(lib.manifest_path, Path::new("/home/user/app/Cargo.toml"));
+        assert_eq!(
+            lib.staticlib(),
+            Some(Path::new(
+                "/home/user/app/target/xtensa-esp32-espidf/debug/libapp.a"
+            ))
+        );
+        assert_eq!(lib.executable, None);
+
+        let bin = &artifacts[1];
+        assert_eq!(bin.target.kind, ["bin"]);
+        assert_eq!(bin.staticlib(), None);
+        assert_eq!(
+            bin.executable.as_deref(),
+            Some(Path::new(
+                "/home/user/app/target/xtensa-esp32-espidf/debug/firmware"
+            ))
+        );
+
+        assert!(parse_artifacts(&b"{\"reason\":\"compiler-artifact\"}\n"[..]).is_err());
+    }
+}
@@ -1,9 +1,11 @@
 //! CLI argument manipulation utilities.
 
 mod arg;
+mod arg_set;
 mod parse_args;
 mod separate_args;
 
 pub use arg::*;
+pub use arg_set::*;
 pub use parse_args::*;
 pub use separate_args::*;
@@ -22,6 +22,11 @@ pub enum Arg {
     /// - `-n value` if `name` is a single character,
     /// - `--name value` otherwise.
     Option,
+    /// A positional argument, which is any argument not starting with a `-` (ex.
+    /// `value`); `name` is only used in usage texts.
+    ///
+    /// Is serialized as the value.
+    Positional,
 }
 
 impl Arg {
@@ -35,6 +40,11 @@ impl Arg {
         Self::Option.with_name(name)
     }
 
+    /// Create an [`ArgDef`] from an `Arg::Positional` with `name`.
+    pub const fn positional(name: &str) -> ArgDef<'_, 'static> {
+        Self::Positional.with_name(name)
+    }
+
     /// Create an [`ArgDef`] from this `Arg` with `name`.
     pub const fn with_name<'a>(self, name: &'a str) -> ArgDef<'a, 'static> {
         ArgDef {
@@ -42,6 +52,7 @@ impl Arg {
             name,
             alias: &[],
             opts: ArgOpts::empty(),
+            help: "",
         }
     }
 }
@@ -67,6 +78,12 @@ bitflags! {
         const VALUE_SEP_NO_SPACE = (1 << 4);
         /// The argument's value is optional
         const VALUE_OPTIONAL = (1 << 5);
+        /// The argument may be given more than once, all values are collected (for
+        /// positional arguments: all remaining positional arguments)
+        ///
+        /// Only checked by [`ArgSet`](super::ArgSet), which rejects repeated arguments
+        /// without it.
+        const MULTIPLE = (1 << 6);
 
         const ALL_HYPHEN = Self::SINGLE_HYPHEN.bits | Self::DOUBLE_HYPHEN.bits;
         const ALL_VALUE_SEP = Self::VALUE_SEP_EQUALS.bits | Self::VALUE_SEP_NEXT_ARG.bits | Self::VALUE_SEP_NO_SPACE.bits;
@@ -88,6 +105,11 @@ impl ArgOpts {
         self.contains(Self::VALUE_OPTIONAL)
     }
 
+    /// Whether the argument may be given more than once.
+    pub const fn is_multiple(self) -> bool {
+        self.contains(Self::MULTIPLE)
+    }
+
     /// Whether the beginning of `s` match any of the value seperator options specified.
     ///
     /// If one seperator option matches `out_sep_len` will be set to the char-length of
@@ -124,6 +146,8 @@ pub struct ArgDef<'s, 'a> {
     pub alias: &'a [(&'a str, Option<ArgOpts>)],
     /// The default [`ArgOpts`].
     pub opts: ArgOpts,
+    /// The description of the argument in usage texts.
+    pub help: &'s str,
 }
 
 impl<'s, 'a> ArgDef<'s, 'a> {
@@ -135,9 +159,16 @@ impl<'s, 'a> ArgDef<'s, 'a> {
             arg: self.arg,
             name: self.name,
             opts: self.opts,
+            help: self.help,
         }
     }
 
+    /// Set the description of this definition in usage texts.
+    pub const fn with_help(mut self, help: &'s str) -> ArgDef<'s, 'a> {
+        self.help = help;
+        self
+    }
+
     /// Set the options for this definition.
     pub const fn with_opts(mut self, opts: ArgOpts) -> ArgDef<'s, 'a> {
         self.opts = opts;
@@ -156,6 +187,12 @@ impl<'s, 'a> ArgDef<'s, 'a> {
         self
     }
 
+    /// Set as an argument which may be given more than once.
+    pub const fn multiple(mut self) -> ArgDef<'s, 'a> {
+        self.opts = self.opts.union(ArgOpts::MULTIPLE);
+        self
+    }
+
     /// Iterate over the default and all aliases of this arg def.
     pub const fn iter(&self) -> ArgDefIter<'_> {
         ArgDefIter {
@@ -177,6 +214,9 @@ impl<'s, 'a> ArgDef<'s, 'a> {
         } = *self;
 
         match arg {
+            Arg::Positional => {
+                FormattedArg::One(value.expect("positional argument without value").into())
+            }
             Arg::Flag if opts.is_empty() => {
                 let second_hyphen = if self.name.len() > 1 { "-" } else { "" };
 
@@ -228,6 +268,40 @@ impl<'s, 'a> ArgDef<'s, 'a> {
     }
 }
 
+impl ArgDef<'_, '_> {
+    /// The syntax of this definition and all its aliases in usage texts, e.g. `-n,
+    /// --name <name>`, `[<name>]` for optional values, `<name>...` for positional
+    /// arguments given more than once.
+    pub fn usage(&self) -> String {
+        if self.arg == Arg::Positional {
+            let ellipsis = if self.opts.is_multiple() { "..." } else { "" };
+
+            return format!("<{}>{}", self.name, ellipsis);
+        }
+
+        self.iter()
+            .map(|(name, opts)| {
+                let def = ArgDef {
+                    arg: self.arg,
+                    name,
+                    alias: &[],
+                    opts,
+                    help: "",
+                };
+
+                if self.arg == Arg::Flag {
+                    def.format(None).to_string()
+                } else if opts.is_value_optional() {
+                    format!("{} [<{}>]", def.format(None), self.name)
+                } else {
+                    def.format(Some(&format!("<{}>", self.name))).to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
 /// An iterator that iterates over the default and all aliases of an [`ArgDef`].
 pub struct ArgDefIter<'d> {
     arg_def: &'d ArgDef<'d, 'd>,
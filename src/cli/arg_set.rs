@@ -0,0 +1,300 @@
+use std::fmt::Write;
+
+use super::{Arg, ArgDef, ParseError, Result};
+
+/// A set of [`ArgDef`]s parsed together, as the command line of a program.
+///
+/// Unlike [`ParseFrom`](super::ParseFrom), which only extracts the given definitions
+/// from arguments meant for another program, an `ArgSet` also:
+/// - rejects repeated arguments unless they allow [multiple](super::ArgOpts::MULTIPLE)
+///   occurrences,
+/// - rejects arguments of the same [exclusive](ArgSet::exclusive) group,
+/// - stops at `--` if it has [passthrough](ArgSet::passthrough) arguments,
+/// - recognizes `-h` and `--help` (unless defined) and generates the
+///   [usage](ArgSet::usage) text from the definitions.
+#[derive(Clone, Debug, Default)]
+pub struct ArgSet<'d> {
+    defs: Vec<&'d ArgDef<'d, 'd>>,
+    exclusive: Vec<Vec<&'d ArgDef<'d, 'd>>>,
+    passthrough: Option<&'d str>,
+}
+
+impl<'d> ArgSet<'d> {
+    /// Create a set of the definitions `defs`.
+    ///
+    /// Positional arguments are assigned in order, so a positional definition with
+    /// [multiple](super::ArgOpts::MULTIPLE) occurrences should be the last one.
+    pub fn new(defs: impl IntoIterator<Item = &'d ArgDef<'d, 'd>>) -> Self {
+        Self {
+            defs: defs.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Make the definitions `defs` mutually exclusive.
+    pub fn exclusive(mut self, defs: impl IntoIterator<Item = &'d ArgDef<'d, 'd>>) -> Self {
+        self.exclusive.push(defs.into_iter().collect());
+        self
+    }
+
+    /// Collect all arguments after `--` unparsed, named `name` in the usage text (ex.
+    /// to forward them to another program).
+    pub fn passthrough(mut self, name: &'d str) -> Self {
+        self.passthrough = Some(name);
+        self
+    }
+
+    /// Parse `args` (without the program name).
+    pub fn parse(&self, args: impl IntoIterator<Item = impl Into<String>>) -> Result<ParsedArgs> {
+        let mut args = args.into_iter().map(Into::into).collect::<Vec<String>>();
+
+        let passthrough = match self.passthrough {
+            Some(_) => args.iter().position(|arg| arg == "--").map(|index| {
+                let mut passthrough = args.split_off(index);
+                passthrough.remove(0);
+                passthrough
+            }),
+            None => None,
+        };
+
+        let help_defined = self
+            .defs
+            .iter()
+            .any(|def| def.iter().any(|(name, _)| name == "h" || name == "help"));
+
+        let mut values: Vec<Option<Vec<String>>> = vec![None; self.defs.len()];
+        let mut help = false;
+
+        let mut i = 0;
+        'args: while i < args.len() {
+            if !help_defined && (args[i] == "-h" || args[i] == "--help") {
+                args.remove(i);
+                help = true;
+                continue;
+            }
+
+            for (index, def) in self.defs.iter().enumerate() {
+                let given = values[index].is_some();
+
+                // Further positional arguments are assigned to the next definition
+                if def.arg == Arg::Positional && given && !def.opts.is_multiple() {
+                    continue;
+                }
+
+                if let Ok(value) = def.parse(i, &mut args) {
+                    if given && !def.opts.is_multiple() {
+                        return Err(ParseError::Duplicate(def.usage()));
+                    }
+
+                    values[index].get_or_insert_with(Vec::new).extend(value);
+
+                    continue 'args;
+                }
+            }
+
+            i += 1;
+        }
+
+        for group in &self.exclusive {
+            let mut given = group.iter().filter(|def| {
+                self.defs
+                    .iter()
+                    .position(|d| d == *def)
+                    .map_or(false, |index| values[index].is_some())
+            });
+
+            if let (Some(first), Some(second)) = (given.next(), given.next()) {
+                return Err(ParseError::Conflict(first.usage(), second.usage()));
+            }
+        }
+
+        Ok(ParsedArgs {
+            values: self
+                .defs
+                .iter()
+                .zip(values)
+                .map(|(def, values)| (def.arg, def.name.to_owned(), values))
+                .collect(),
+            remaining: args,
+            passthrough,
+            help,
+        })
+    }
+
+    /// The usage text of `program` with these definitions, e.g.
+    ///
+    /// ```text
+    /// Usage: ldproxy [OPTIONS] <file>... [-- <linker args>...]
+    ///
+    /// Arguments:
+    ///   <file>...                  The object files
+    ///
+    /// Options:
+    ///   --ldproxy-linker <linker>  The actual linker
+    ///   -h, --help                 Print this help
+    /// ```
+    pub fn usage(&self, program: &str) -> String {
+        let (positional, options): (Vec<&ArgDef>, Vec<&ArgDef>) = self
+            .defs
+            .iter()
+            .copied()
+            .partition(|def| def.arg == Arg::Positional);
+
+        let mut usage = format!("Usage: {}", program);
+
+        if !options.is_empty() {
+            usage.push_str(" [OPTIONS]");
+        }
+
+        for def in &positional {
+            write!(usage, " {}", def.usage()).unwrap();
+        }
+
+        if let Some(passthrough) = self.passthrough {
+            write!(usage, " [-- <{}>...]", passthrough).unwrap();
+        }
+
+        usage.push('\n');
+
+        let mut option_lines = options
+            .iter()
+            .map(|def| (def.usage(), def.help))
+            .collect::<Vec<_>>();
+
+        if !options.iter().any(|def| def.name == "help") {
+            option_lines.push(("-h, --help".to_owned(), "Print this help"));
+        }
+
+        let positional_lines = positional
+            .iter()
+            .map(|def| (def.usage(), def.help))
+            .collect::<Vec<_>>();
+
+        let width = option_lines
+            .iter()
+            .chain(&positional_lines)
+            .map(|(syntax, _)| syntax.len())
+            .max()
+            .unwrap_or_default();
+
+        for (title, lines) in [("Arguments", positional_lines), ("Options", option_lines)] {
+            if lines.is_empty() {
+                continue;
+            }
+
+            write!(usage, "\n{}:\n", title).unwrap();
+
+            for (syntax, help) in lines {
+                let line = format!("  {:<width$}  {}", syntax, help, width = width);
+                writeln!(usage, "{}", line.trim_end()).unwrap();
+            }
+        }
+
+        usage
+    }
+}
+
+/// The arguments parsed by an [`ArgSet`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParsedArgs {
+    values: Vec<(Arg, String, Option<Vec<String>>)>,
+    /// The arguments which did not match any definition.
+    pub remaining: Vec<String>,
+    /// The arguments after `--`, `None` if there was none.
+    pub passthrough: Option<Vec<String>>,
+    /// Whether `-h` or `--help` was given.
+    pub help: bool,
+}
+
+impl ParsedArgs {
+    /// The values of `def`, `None` if it was not given.
+    ///
+    /// The values are empty for flags and options without their optional value.
+    pub fn get(&self, def: &ArgDef) -> Option<&[String]> {
+        self.values
+            .iter()
+            .find(|(arg, name, _)| *arg == def.arg && name == def.name)
+            .and_then(|(_, _, values)| values.as_deref())
+    }
+
+    /// Whether `def` was given.
+    pub fn is_present(&self, def: &ArgDef) -> bool {
+        self.get(def).is_some()
+    }
+
+    /// The (last) value of `def`, `None` if it was not given or has no value.
+    pub fn value(&self, def: &ArgDef) -> Option<&str> {
+        self.get(def)?.last().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ArgOpts;
+    use super::*;
+
+    const VERBOSE: ArgDef = Arg::flag("verbose")
+        .with_alias(&[("v", Some(ArgOpts::SINGLE_HYPHEN))])
+        .with_help("Print more output");
+    const QUIET: ArgDef = Arg::flag("quiet").long();
+    const FEATURE: ArgDef = Arg::option("feature").long().multiple();
+    const TARGET: ArgDef = Arg::option("target").long().with_help("The Rust target");
+    const PROJECT: ArgDef = Arg::positional("project");
+    const FILES: ArgDef = Arg::positional("file").multiple().with_help("The files");
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn parse() {
+        let set = ArgSet::new([&VERBOSE, &QUIET, &FEATURE, &TARGET, &PROJECT, &FILES])
+            .exclusive([&VERBOSE, &QUIET])
+            .passthrough("args");
+
+        let parsed = set
+            .parse(args(
+                "proj --feature a -v a.c --target=xtensa --feature b b.c --unknown -- -v --quiet",
+            ))
+            .unwrap();
+
+        assert!(parsed.is_present(&VERBOSE));
+        assert!(!parsed.is_present(&QUIET));
+        assert_eq!(parsed.get(&FEATURE), Some(&args("a b")[..]));
+        assert_eq!(parsed.value(&TARGET), Some("xtensa"));
+        assert_eq!(parsed.value(&PROJECT), Some("proj"));
+        assert_eq!(parsed.get(&FILES), Some(&args("a.c b.c")[..]));
+        assert_eq!(parsed.remaining, args("--unknown"));
+        assert_eq!(parsed.passthrough, Some(args("-v --quiet")));
+        assert!(!parsed.help);
+
+        assert_eq!(
+            set.parse(args("--target a --target b")),
+            Err(ParseError::Duplicate("--target <target>".into()))
+        );
+        assert_eq!(
+            set.parse(args("--quiet --verbose")),
+            Err(ParseError::Conflict(
+                "--verbose, -v".into(),
+                "--quiet".into()
+            ))
+        );
+        assert!(set.parse(args("proj --help")).unwrap().help);
+
+        assert_eq!(
+            set.usage("tool"),
+            "Usage: tool [OPTIONS] <project> <file>... [-- <args>...]\n\
+             \n\
+             Arguments:\n  \
+               <project>\n  \
+               <file>...            The files\n\
+             \n\
+             Options:\n  \
+               --verbose, -v        Print more output\n  \
+               --quiet\n  \
+               --feature <feature>\n  \
+               --target <target>    The Rust target\n  \
+               -h, --help           Print this help\n"
+        );
+    }
+}
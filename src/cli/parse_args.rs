@@ -5,12 +5,23 @@ use super::{Arg, ArgDef};
 pub enum ParseError {
     /// The command line argument or flag was not found.
     NotFound,
+    /// The command line argument or flag was given more than once, but does not allow
+    /// [multiple](super::ArgOpts::MULTIPLE) occurrences.
+    Duplicate(String),
+    /// Two mutually exclusive command line arguments or flags were given.
+    Conflict(String, String),
 }
 
 impl std::error::Error for ParseError {}
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::NotFound => write!(f, "{:?}", self),
+            Self::Duplicate(arg) => write!(f, "'{}' cannot be given more than once", arg),
+            Self::Conflict(arg, other) => {
+                write!(f, "'{}' cannot be used together with '{}'", arg, other)
+            }
+        }
     }
 }
 
@@ -26,6 +37,14 @@ impl super::ArgDef<'_, '_> {
     pub fn parse(&self, i: usize, args: &mut Vec<String>) -> Result<Option<String>> {
         let arg = &args[i];
 
+        if self.arg == Arg::Positional {
+            return if !arg.starts_with('-') || arg == "-" {
+                Ok(Some(args.remove(i)))
+            } else {
+                Err(ParseError::NotFound)
+            };
+        }
+
         let hyphen_count = arg.chars().take_while(|s| *s == '-').count();
         for (arg_name, arg_opts) in self.iter() {
             if !arg_opts.is_hyphen_count(hyphen_count) {
@@ -40,6 +59,7 @@ impl super::ArgDef<'_, '_> {
                         return Ok(None);
                     }
                 }
+                Arg::Positional => unreachable!(),
                 Arg::Option => {
                     let mut sep_len = None;
 
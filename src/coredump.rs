@@ -0,0 +1,208 @@
+//! Retrieval and decoding of ESP-IDF core dumps.
+//!
+//! ESP-IDF writes a core dump either to the `coredump` flash partition or, base64
+//! encoded, to the console (UART). Only core dumps in the ELF format
+//! (`CONFIG_ESP_COREDUMP_DATA_FORMAT_ELF`) are supported: they contain a regular ELF
+//! core file which GDB can load together with the firmware ELF file.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use log::*;
+
+use crate::interrupt;
+use crate::partitions::{self, Partition};
+
+const UART_START: &str = "CORE DUMP START";
+const UART_END: &str = "CORE DUMP END";
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// Find the core dump partition in the binary partition table `data`.
+pub fn find_partition(data: &[u8]) -> Option<Partition> {
+    Partition::parse_table(data)
+        .into_iter()
+        .find(|p| p.kind == partitions::TYPE_DATA && p.subtype == partitions::SUBTYPE_COREDUMP)
+}
+
+/// A core dump as written by ESP-IDF.
+#[derive(Clone, Debug)]
+pub struct CoreDump {
+    /// The format version (without the chip id).
+    pub version: u16,
+    /// The complete core dump, including its header and checksum.
+    pub data: Vec<u8>,
+}
+
+impl CoreDump {
+    /// Read a core dump from the contents of the core dump flash partition.
+    ///
+    /// Returns `None` if the partition is erased (i.e. contains no core dump).
+    pub fn from_flash(data: &[u8]) -> Result<Option<Self>> {
+        if data.len() < 8 {
+            bail!("Core dump partition too small");
+        }
+
+        let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if len == u32::MAX {
+            return Ok(None);
+        }
+
+        let len = len as usize;
+        if len < 8 || len > data.len() {
+            bail!("Invalid core dump length {}", len);
+        }
+
+        Ok(Some(Self::from_data(data[..len].to_vec())))
+    }
+
+    /// Extract a core dump printed to the console (between the `CORE DUMP START` and
+    /// `CORE DUMP END` lines) from the captured console output `log`.
+    ///
+    /// Returns `None` if the log does not contain a core dump.
+    pub fn from_uart_log(log: &str) -> Result<Option<Self>> {
+        let encoded = log
+            .lines()
+            .skip_while(|line| !line.contains(UART_START))
+            .skip(1)
+            .take_while(|line| !line.contains(UART_END))
+            .map(|line| {
+                // Strip anything the monitor might have prefixed (e.g. timestamps)
+                line.rsplit(|c: char| c.is_whitespace() || c == ']')
+                    .next()
+                    .unwrap_or_default()
+            })
+            .collect::<String>();
+
+        if encoded.is_empty() {
+            return Ok(None);
+        }
+
+        let data = decode_base64(&encoded).context("Invalid core dump encoding")?;
+
+        Self::from_flash(&data)
+    }
+
+    fn from_data(data: Vec<u8>) -> Self {
+        Self {
+            version: u16::from_le_bytes([data[4], data[5]]),
+            data,
+        }
+    }
+
+    /// Whether this core dump is in the ELF format.
+    pub fn is_elf(&self) -> bool {
+        self.version >> 8 == 1
+    }
+
+    /// The ELF core file contained in this core dump.
+    pub fn elf_core(&self) -> Result<&[u8]> {
+        if !self.is_elf() {
+            bail!(
+                "Core dump has the binary format (version 0x{:04x}), only the ELF format is supported; \
+                 please set CONFIG_ESP_COREDUMP_DATA_FORMAT_ELF=y in sdkconfig",
+                self.version
+            );
+        }
+
+        // The ELF core follows the header, whose size differs between versions. Any
+        // trailing checksum is ignored by ELF readers
+        let start = self
+            .data
+            .windows(ELF_MAGIC.len())
+            .take(64)
+            .position(|w| w == ELF_MAGIC)
+            .context("Core dump does not contain an ELF core file")?;
+
+        Ok(&self.data[start..])
+    }
+
+    /// Write the ELF core file contained in this core dump to `path`.
+    pub fn write_elf_core(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path, self.elf_core()?)?;
+
+        Ok(())
+    }
+}
+
+/// Print the threads and their backtraces of the ELF core file `core_file` with `gdb`,
+/// using the symbols of the firmware `elf_file`.
+pub fn print_backtraces(
+    gdb: impl AsRef<Path>,
+    elf_file: impl AsRef<Path>,
+    core_file: impl AsRef<Path>,
+) -> Result<()> {
+    let mut cmd = Command::new(gdb.as_ref());
+
+    cmd.arg("--batch")
+        .arg("-q")
+        .args(["-ex", "set pagination off"])
+        .args(["-ex", "echo \\n==== Threads ====\\n"])
+        .args(["-ex", "info threads"])
+        .args(["-ex", "echo \\n==== Backtraces ====\\n"])
+        .args(["-ex", "thread apply all bt full"])
+        .arg(elf_file.as_ref())
+        .arg(core_file.as_ref());
+
+    debug!("Running GDB command: {:?}", cmd);
+
+    let status = interrupt::status(&mut cmd)
+        .with_context(|| format!("Failed to run {}", gdb.as_ref().display()))?;
+
+    if !status.success() {
+        bail!("GDB failed with {}", status);
+    }
+
+    Ok(())
+}
+
+fn decode_base64(encoded: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut buf = 0_u32;
+    let mut bits = 0;
+
+    for c in encoded.bytes().filter(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => bail!("Invalid base64 character '{}'", c as char),
+        };
+
+        buf = (buf << 6) | u32::from(value);
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buf >> bits) as u8);
+        }
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uart_log() {
+        // 20 bytes: length, version 0x0100 (ELF), 8 header bytes and the ELF magic
+        let log = "\
+I (123) esp_core_dump_uart: Press Enter to print core dump to UART...
+================= CORE DUMP START =================
+FAAAAAABAAAAAAAAAAAAAH9FTEY=
+================= CORE DUMP END ===================
+";
+
+        let coredump = CoreDump::from_uart_log(log).unwrap().unwrap();
+
+        assert!(coredump.is_elf());
+        assert_eq!(coredump.elf_core().unwrap(), ELF_MAGIC);
+        assert!(CoreDump::from_uart_log("no core dump").unwrap().is_none());
+    }
+}
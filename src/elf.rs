@@ -0,0 +1,559 @@
+//! ELF file inspection utilities.
+//!
+//! Provides an owned view of the sections and symbols of an ELF file, as needed for
+//! analyzing the firmware produced by a build (sizes, placement, stack usage, ...).
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{Error, Result};
+use xmas_elf::header::{Class, Data};
+use xmas_elf::sections::{self, SectionData, ShType};
+use xmas_elf::symbol_table::{self, Binding};
+use xmas_elf::ElfFile;
+
+/// The `e_machine` value of Xtensa ELF files.
+pub const EM_XTENSA: u16 = 94;
+/// The `e_machine` value of RISC-V ELF files.
+pub const EM_RISCV: u16 = 243;
+
+/// The kind of an ELF [`Symbol`].
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum SymbolKind {
+    /// A function or other executable code.
+    Func,
+    /// A data object (variable, array, ...).
+    Object,
+    /// Any other symbol type.
+    Other,
+}
+
+/// A symbol of an ELF file.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Symbol {
+    /// The (possibly mangled) name of the symbol.
+    pub name: String,
+    /// The address (value) of the symbol.
+    pub address: u64,
+    /// The size of the symbol in bytes.
+    pub size: u64,
+    /// The kind of the symbol.
+    pub kind: SymbolKind,
+    /// The name of the section containing the symbol, if any.
+    pub section: Option<String>,
+    /// Whether the symbol has global (or weak) binding.
+    pub global: bool,
+    /// The source file of a local symbol, as named by the `STT_FILE` symbol preceding it
+    /// in the symbol table.
+    pub file: Option<String>,
+}
+
+/// A section of an ELF file.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Section {
+    /// The name of the section.
+    pub name: String,
+    /// The address the section is loaded at.
+    pub address: u64,
+    /// The size of the section in bytes.
+    pub size: u64,
+    /// Whether the section occupies memory at runtime.
+    pub alloc: bool,
+    /// Whether the section contains executable code.
+    pub exec: bool,
+    /// Whether the section is writable at runtime.
+    pub write: bool,
+    /// Whether the section occupies space in the file (i.e. is not `.bss`-like).
+    pub has_data: bool,
+}
+
+/// The sections and symbols of an ELF file.
+#[derive(Clone, Default, Debug)]
+pub struct ElfInfo {
+    /// Whether this is a 64-bit ELF file.
+    pub is_64bit: bool,
+    /// The target architecture (`e_machine`), e.g. [`EM_XTENSA`] or [`EM_RISCV`].
+    pub machine: u16,
+    /// All named sections.
+    pub sections: Vec<Section>,
+    /// All named symbols of the symbol tables.
+    pub symbols: Vec<Symbol>,
+}
+
+impl ElfInfo {
+    /// Read the ELF file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Parse the ELF file contained in `data`.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let elf = ElfFile::new(data).map_err(Error::msg)?;
+
+        let mut info = Self {
+            is_64bit: elf.header.pt1.class() == Class::SixtyFour,
+            // `e_machine` directly follows the identification and `e_type` in both classes
+            machine: if elf.header.pt1.data() == Data::BigEndian {
+                u16::from_be_bytes([data[18], data[19]])
+            } else {
+                u16::from_le_bytes([data[18], data[19]])
+            },
+            ..Default::default()
+        };
+
+        for (index, header) in elf.section_iter().enumerate() {
+            let sh_type = header.get_type().map_err(Error::msg)?;
+            if sh_type == ShType::Null {
+                continue;
+            }
+
+            let flags = header.flags();
+
+            info.sections.push(Section {
+                name: header.get_name(&elf).map_err(Error::msg)?.to_owned(),
+                address: header.address(),
+                size: header.size(),
+                alloc: flags & sections::SHF_ALLOC != 0,
+                exec: flags & sections::SHF_EXECINSTR != 0,
+                write: flags & sections::SHF_WRITE != 0,
+                has_data: sh_type != ShType::NoBits,
+            });
+
+            if sh_type == ShType::SymTab {
+                match header.get_data(&elf).map_err(Error::msg)? {
+                    SectionData::SymbolTable32(entries) => {
+                        info.add_symbols(&elf, index, entries.iter())?
+                    }
+                    SectionData::SymbolTable64(entries) => {
+                        info.add_symbols(&elf, index, entries.iter())?
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Get the section named `name`.
+    pub fn section(&self, name: impl AsRef<str>) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name.as_ref())
+    }
+
+    /// Get the symbol named `name`.
+    pub fn symbol(&self, name: impl AsRef<str>) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name.as_ref())
+    }
+
+    /// The memory used by the allocated sections as `(flash, ram)` in bytes, i.e. the
+    /// `text + data` and `data + bss` of `size`.
+    pub fn memory_usage(&self) -> (u64, u64) {
+        self.sections
+            .iter()
+            .filter(|s| s.alloc)
+            .fold((0, 0), |(flash, ram), s| {
+                (
+                    flash + if s.has_data { s.size } else { 0 },
+                    ram + if s.write || !s.has_data { s.size } else { 0 },
+                )
+            })
+    }
+
+    /// Get the function symbol containing `address`.
+    pub fn function_at(&self, address: u64) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| {
+            s.kind == SymbolKind::Func
+                && (s.address == address || (s.address..s.address + s.size).contains(&address))
+        })
+    }
+
+    /// Find the executable `tool` (e.g. `addr2line` or `gdb`) of a toolchain for the
+    /// architecture of this ELF file, in `PATH` and in the toolchain packages installed
+    /// by PlatformIO.
+    pub fn find_tool(&self, tool: &str) -> Option<PathBuf> {
+        let prefixes: &[&str] = match self.machine {
+            EM_XTENSA => &[
+                "xtensa-esp32-elf-",
+                "xtensa-esp32s2-elf-",
+                "xtensa-esp32s3-elf-",
+                "xtensa-lx106-elf-",
+            ],
+            EM_RISCV => &[
+                "riscv32-esp-elf-",
+                "riscv64-unknown-elf-",
+                "riscv32-unknown-elf-",
+            ],
+            _ => &["arm-none-eabi-", ""],
+        };
+
+        let mut dirs = env::var_os("PATH")
+            .map(|path| env::split_paths(&path).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let pio_packages = env::var_os("PLATFORMIO_CORE_DIR")
+            .map(PathBuf::from)
+            .or_else(|| {
+                env::var_os("HOME")
+                    .or_else(|| env::var_os("USERPROFILE"))
+                    .map(|home| PathBuf::from(home).join(".platformio"))
+            })
+            .map(|core_dir| core_dir.join("packages"));
+
+        if let Some(Ok(entries)) = pio_packages.map(fs::read_dir) {
+            let mut packages = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.file_name()
+                        .map_or(false, |name| name.to_string_lossy().starts_with("tool"))
+                })
+                .collect::<Vec<_>>();
+
+            // Deterministically prefer the primary installation `tool-x` over versions
+            // installed side by side as `tool-x@<version>`
+            packages.sort();
+
+            dirs.extend(packages.into_iter().map(|path| path.join("bin")));
+        }
+
+        prefixes.iter().find_map(|prefix| {
+            let name = format!("{}{}{}", prefix, tool, env::consts::EXE_SUFFIX);
+            dirs.iter()
+                .map(|dir| dir.join(&name))
+                .find(|path| path.is_file())
+        })
+    }
+
+    fn add_symbols<'a>(
+        &mut self,
+        elf: &'a ElfFile<'a>,
+        symtable_index: usize,
+        entries: impl Iterator<Item = &'a (impl symbol_table::Entry + 'a)>,
+    ) -> Result<()> {
+        let mut file = None;
+
+        for sym in entries {
+            let name = sym.get_name(elf).map_err(Error::msg)?;
+            if name.is_empty() {
+                continue;
+            }
+
+            let kind = match sym.get_type() {
+                Ok(symbol_table::Type::Func) => SymbolKind::Func,
+                Ok(symbol_table::Type::Object) => SymbolKind::Object,
+                Ok(symbol_table::Type::File) => {
+                    file = Some(name.to_owned());
+                    SymbolKind::Other
+                }
+                _ => SymbolKind::Other,
+            };
+            let global = matches!(sym.get_binding(), Ok(Binding::Global | Binding::Weak));
+
+            let section =
+                if sym.shndx() == sections::SHN_UNDEF || sym.shndx() >= sections::SHN_LORESERVE {
+                    None
+                } else {
+                    sym.get_section_header(elf, symtable_index)
+                        .and_then(|sh| sh.get_name(elf))
+                        .ok()
+                        .map(str::to_owned)
+                };
+
+            self.symbols.push(Symbol {
+                name: name.to_owned(),
+                address: sym.value(),
+                size: sym.size(),
+                kind,
+                section,
+                global,
+                file: if global { None } else { file.clone() },
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the raw contents of the section named `name` of the ELF file contained in
+/// `data`.
+pub fn section_data(data: &[u8], name: impl AsRef<str>) -> Result<Option<&[u8]>> {
+    let elf = ElfFile::new(data).map_err(Error::msg)?;
+
+    Ok(elf
+        .find_section_by_name(name.as_ref())
+        .filter(|header| header.get_type() != Ok(ShType::NoBits))
+        .map(|header| header.raw_data(&elf)))
+}
+
+/// The difference of a [`Symbol`] or [`Section`] between two ELF files.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Change<T> {
+    /// Only present in the new file.
+    Added(T),
+    /// Only present in the old file.
+    Removed(T),
+    /// Present in both files with a different size or placement.
+    Changed { old: T, new: T },
+}
+
+impl<T> Change<T> {
+    /// The item in the old file, if any.
+    pub fn before(&self) -> Option<&T> {
+        match self {
+            Self::Added(_) => None,
+            Self::Removed(old) | Self::Changed { old, .. } => Some(old),
+        }
+    }
+
+    /// The item in the new file, if any.
+    pub fn after(&self) -> Option<&T> {
+        match self {
+            Self::Removed(_) => None,
+            Self::Added(new) | Self::Changed { new, .. } => Some(new),
+        }
+    }
+}
+
+impl Change<Symbol> {
+    /// The name of the changed symbol.
+    pub fn name(&self) -> &str {
+        &self.after().or_else(|| self.before()).unwrap().name
+    }
+
+    /// The size difference in bytes (new - old).
+    pub fn size_delta(&self) -> i64 {
+        self.after().map(|s| s.size as i64).unwrap_or(0)
+            - self.before().map(|s| s.size as i64).unwrap_or(0)
+    }
+
+    /// Whether the symbol was placed in a different section.
+    pub fn moved(&self) -> bool {
+        matches!(self, Self::Changed { old, new } if old.section != new.section)
+    }
+}
+
+impl Change<Section> {
+    /// The name of the changed section.
+    pub fn name(&self) -> &str {
+        &self.after().or_else(|| self.before()).unwrap().name
+    }
+
+    /// The size difference in bytes (new - old).
+    pub fn size_delta(&self) -> i64 {
+        self.after().map(|s| s.size as i64).unwrap_or(0)
+            - self.before().map(|s| s.size as i64).unwrap_or(0)
+    }
+
+    /// Whether the section was placed at a different address.
+    pub fn moved(&self) -> bool {
+        matches!(self, Self::Changed { old, new } if old.address != new.address)
+    }
+}
+
+/// The differences between two ELF files.
+#[derive(Clone, Default, Debug)]
+pub struct Diff {
+    /// Sections which were added, removed, resized or moved.
+    pub sections: Vec<Change<Section>>,
+    /// Function and data symbols which were added, removed, resized or placed in a
+    /// different section, ordered by decreasing absolute size difference.
+    pub symbols: Vec<Change<Symbol>>,
+}
+
+impl Diff {
+    /// Compare the `old` and `new` ELF files.
+    ///
+    /// Only sections occupying memory at runtime and function and data symbols are
+    /// compared. Symbols are matched by name, and local symbols also by their
+    /// [`Symbol::file`]. Local symbols with the same name in the same file (or in files
+    /// without `STT_FILE` symbols) are matched in the order of the symbol tables.
+    pub fn new(old: &ElfInfo, new: &ElfInfo) -> Self {
+        let sections = changes(
+            old.sections
+                .iter()
+                .filter(|s| s.alloc)
+                .map(|s| (s.name.clone(), s)),
+            new.sections
+                .iter()
+                .filter(|s| s.alloc)
+                .map(|s| (s.name.clone(), s)),
+            |old, new| old.size != new.size || old.address != new.address,
+        );
+
+        let is_compared = |s: &&Symbol| s.kind != SymbolKind::Other && s.section.is_some();
+        let mut symbols = changes(
+            symbol_keys(old.symbols.iter().filter(is_compared)),
+            symbol_keys(new.symbols.iter().filter(is_compared)),
+            |old, new| old.size != new.size || old.section != new.section,
+        );
+        symbols.sort_by_key(|c| std::cmp::Reverse(c.size_delta().abs()));
+
+        Self { sections, symbols }
+    }
+
+    /// The total size difference of all runtime sections which occupy flash or RAM.
+    pub fn size_delta(&self) -> i64 {
+        self.sections
+            .iter()
+            .map(Change::<Section>::size_delta)
+            .sum()
+    }
+
+    /// Whether there are no differences.
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty() && self.symbols.is_empty()
+    }
+}
+
+/// Key `symbols` by their name, file and their occurrence among the symbols with the
+/// same name and file.
+fn symbol_keys<'a>(
+    symbols: impl Iterator<Item = &'a Symbol>,
+) -> impl Iterator<Item = ((&'a str, Option<&'a str>, usize), &'a Symbol)> {
+    let mut occurrences = BTreeMap::new();
+
+    symbols.map(move |symbol| {
+        let name_and_file = (symbol.name.as_str(), symbol.file.as_deref());
+
+        let occurrence = occurrences.entry(name_and_file).or_insert(0);
+        *occurrence += 1;
+
+        ((name_and_file.0, name_and_file.1, *occurrence), symbol)
+    })
+}
+
+fn changes<'a, K: Ord, T: Clone + 'a>(
+    old: impl Iterator<Item = (K, &'a T)>,
+    new: impl Iterator<Item = (K, &'a T)>,
+    changed: impl Fn(&T, &T) -> bool,
+) -> Vec<Change<T>> {
+    let old = old.collect::<BTreeMap<_, _>>();
+    let new = new.collect::<BTreeMap<_, _>>();
+
+    let mut result = Vec::new();
+
+    for (name, old_item) in &old {
+        match new.get(name) {
+            None => result.push(Change::Removed((*old_item).clone())),
+            Some(new_item) if changed(old_item, new_item) => result.push(Change::Changed {
+                old: (*old_item).clone(),
+                new: (*new_item).clone(),
+            }),
+            _ => (),
+        }
+    }
+
+    result.extend(
+        new.iter()
+            .filter(|(name, _)| !old.contains_key(*name))
+            .map(|(_, new_item)| Change::Added((*new_item).clone())),
+    );
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, size: u64, section: &str) -> Symbol {
+        Symbol {
+            name: name.into(),
+            address: 0,
+            size,
+            kind: SymbolKind::Func,
+            section: Some(section.into()),
+            global: true,
+            file: None,
+        }
+    }
+
+    fn local(name: &str, size: u64, file: &str) -> Symbol {
+        Symbol {
+            global: false,
+            file: Some(file.into()),
+            ..symbol(name, size, ".text")
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = ElfInfo {
+            symbols: vec![
+                symbol("a", 10, ".text"),
+                symbol("b", 20, ".text"),
+                symbol("c", 30, ".text"),
+            ],
+            ..Default::default()
+        };
+        let new = ElfInfo {
+            symbols: vec![
+                symbol("a", 10, ".iram0.text"),
+                symbol("b", 120, ".text"),
+                symbol("d", 5, ".text"),
+            ],
+            ..Default::default()
+        };
+
+        let diff = Diff::new(&old, &new);
+        let summary = diff
+            .symbols
+            .iter()
+            .map(|c| (c.name(), c.size_delta(), c.moved()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("b", 100, false),
+                ("c", -30, false),
+                ("d", 5, false),
+                ("a", 0, true)
+            ]
+        );
+
+        // Static functions of the same name in different files, and in the same file
+        let old = ElfInfo {
+            symbols: vec![
+                local("init", 10, "uart.c"),
+                local("init", 20, "spi.c"),
+                local("helper", 4, "spi.c"),
+                local("helper", 8, "spi.c"),
+            ],
+            ..Default::default()
+        };
+        let new = ElfInfo {
+            symbols: vec![
+                local("init", 10, "uart.c"),
+                local("init", 25, "spi.c"),
+                local("init", 30, "i2c.c"),
+                local("helper", 4, "spi.c"),
+                local("helper", 6, "spi.c"),
+            ],
+            ..Default::default()
+        };
+
+        let diff = Diff::new(&old, &new);
+        let summary = diff
+            .symbols
+            .iter()
+            .map(|c| {
+                (
+                    c.name(),
+                    c.after().or_else(|| c.before()).unwrap().file.as_deref(),
+                    c.size_delta(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("init", Some("i2c.c"), 30),
+                ("init", Some("spi.c"), 5),
+                ("helper", Some("spi.c"), -2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! User-facing hints for errors, and their rendering.
+//!
+//! Errors are [`anyhow::Error`]s throughout this crate, with the context of what was
+//! being done attached with [`anyhow::Context`]. A [`Hint`] on what the user can try to
+//! resolve an error is attached the same way (see [`HintExt`]), so that library users
+//! get it as part of the source chain, while [`report`] renders it separately after the
+//! error and its causes.
+//!
+//! Errors and hints may be [`Message`]s of the message catalog, which are rendered in
+//! the selected locale.
+
+use std::error::Error;
+use std::fmt::{self, Display, Write};
+
+use crate::messages::Message;
+
+/// A hint on what the user can try to resolve its source error.
+#[derive(Debug)]
+pub struct Hint {
+    hint: String,
+    source: anyhow::Error,
+}
+
+impl Hint {
+    /// The hint.
+    pub fn hint(&self) -> &str {
+        &self.hint
+    }
+}
+
+impl Display for Hint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "hint: {}", self.hint)
+    }
+}
+
+impl Error for Hint {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// Attach [`Hint`]s to errors.
+pub trait HintExt<T> {
+    /// Attach `hint` to the error, if any.
+    fn hint(self, hint: impl Display) -> anyhow::Result<T>;
+
+    /// Attach the hint returned by `hint` to the error, if any.
+    fn with_hint<H: Display>(self, hint: impl FnOnce() -> H) -> anyhow::Result<T>;
+}
+
+impl<T, E> HintExt<T> for Result<T, E>
+where
+    E: Into<anyhow::Error>,
+{
+    fn hint(self, hint: impl Display) -> anyhow::Result<T> {
+        self.map_err(|err| {
+            Hint {
+                hint: hint.to_string(),
+                source: err.into(),
+            }
+            .into()
+        })
+    }
+
+    fn with_hint<H: Display>(self, hint: impl FnOnce() -> H) -> anyhow::Result<T> {
+        self.map_err(|err| {
+            Hint {
+                hint: hint().to_string(),
+                source: err.into(),
+            }
+            .into()
+        })
+    }
+}
+
+/// The hints attached to `err`, outermost first.
+pub fn hints(err: &anyhow::Error) -> Vec<&str> {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<Hint>())
+        .map(Hint::hint)
+        .collect()
+}
+
+/// Render `err` for users: the error, each of its causes on its own line, and then the
+/// hints attached to it.
+pub fn report(err: &anyhow::Error) -> String {
+    let mut report = String::new();
+
+    let mut causes = err
+        .chain()
+        .filter(|cause| !cause.is::<Hint>())
+        .map(ToString::to_string);
+
+    if let Some(error) = causes.next() {
+        writeln!(report, "{}", Message::new("error").arg("error", error)).unwrap();
+    }
+
+    for cause in causes {
+        writeln!(report, "{}", Message::new("caused-by").arg("cause", cause)).unwrap();
+    }
+
+    for hint in hints(err) {
+        writeln!(report, "{}", Message::new("hint").arg("hint", hint)).unwrap();
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_report() {
+        let err = Err::<(), _>(anyhow!("EOF while parsing a value"))
+            .hint("Install PlatformIO first")
+            .context("Failed to check the PlatformIO installation")
+            .unwrap_err();
+
+        assert_eq!(hints(&err), vec!["Install PlatformIO first"]);
+        assert_eq!(
+            report(&err),
+            "Error: Failed to check the PlatformIO installation\n  \
+             caused by: EOF while parsing a value\n\
+             hint: Install PlatformIO first\n"
+        );
+    }
+}
@@ -1,10 +1,24 @@
 //! Filesystem utilities.
+//!
+//! Besides copying, this has crash-safe replacements of [`fs::write`], [`fs::rename`]
+//! and [`fs::remove_dir_all`]: files are written to a temporary sibling which is synced
+//! and renamed over the destination, so that an interrupted write never leaves a
+//! truncated file behind. On Windows, where virus scanners and indexers briefly open new
+//! files, the operations are retried while they fail with a sharing violation, and
+//! read-only files (e.g. of git checkouts) are made writable before removing them.
 
 use std::fs::{self, File};
-use std::io::{self, Read};
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+
+/// How often a failed operation is retried on Windows, with the delay doubling from
+/// [`RETRY_DELAY`], i.e. for about 2.5 seconds.
+const RETRIES: u32 = 8;
+const RETRY_DELAY: Duration = Duration::from_millis(10);
 
 /// Copy `src_file` to `dest_file_or_dir` if `src_file` is different or the destination
 /// file doesn't exist.
@@ -90,7 +104,7 @@ pub fn is_file_eq(file: &File, other: &File) -> Result<bool> {
 /// comparison will always yield a new fingerprint since [`fs::copy`] doesn't take mtime into
 /// account.
 pub fn copy_with_metadata(src_file: impl AsRef<Path>, dest_file: impl AsRef<Path>) -> Result<()> {
-    fs::copy(&src_file, &dest_file)?;
+    retry(|| fs::copy(&src_file, &dest_file))?;
     let src_file_meta = fs::File::open(&src_file)?.metadata()?;
 
     let src_atime = filetime::FileTime::from_last_access_time(&src_file_meta);
@@ -101,3 +115,136 @@ pub fn copy_with_metadata(src_file: impl AsRef<Path>, dest_file: impl AsRef<Path
 
     Ok(())
 }
+
+/// Run `op`, retrying it while it fails with an error which is transient on Windows (a
+/// sharing or lock violation, or access denied as reported for files pending deletion).
+///
+/// On other platforms `op` runs once.
+pub fn retry<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = RETRY_DELAY;
+
+    for _ in 0..RETRIES {
+        match op() {
+            Err(err) if is_transient(&err) => {
+                log::debug!("Retrying in {:?} after: {}", delay, err);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+
+    op()
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    // ERROR_ACCESS_DENIED, ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION
+    cfg!(windows) && matches!(err.raw_os_error(), Some(5 | 32 | 33))
+}
+
+/// Write `contents` to `path` atomically: either the previous or the new contents are in
+/// `path`, even if the process or the machine crashes while writing.
+pub fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+    let path = path.as_ref();
+    let temp = temp_path(path);
+
+    let result = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        .map_err(anyhow::Error::from)
+        .and_then(|_| rename_durable(&temp, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+
+    result.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Rename `from` to `to`, replacing `to`, and sync the directory of `to` so that the
+/// rename survives a crash.
+pub fn rename_durable(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+
+    retry(|| fs::rename(from, to))
+        .with_context(|| format!("Failed to rename {} to {}", from.display(), to.display()))?;
+
+    // Directories cannot be opened (and need not be synced) on Windows
+    #[cfg(unix)]
+    if let Some(dir) = to.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Remove the directory `dir` with all its contents, including read-only files.
+pub fn remove_dir_all(dir: impl AsRef<Path>) -> Result<()> {
+    let dir = dir.as_ref();
+
+    match retry(|| fs::remove_dir_all(dir)) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            clear_readonly(dir)?;
+            retry(|| fs::remove_dir_all(dir))
+                .with_context(|| format!("Failed to remove {}", dir.display()))
+        }
+        Err(err) => Err(err).with_context(|| format!("Failed to remove {}", dir.display())),
+    }
+}
+
+fn clear_readonly(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            clear_readonly(&entry?.path())?;
+        }
+    }
+
+    let mut permissions = metadata.permissions();
+    if permissions.readonly() && !metadata.file_type().is_symlink() {
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(path, permissions)?;
+    }
+
+    Ok(())
+}
+
+/// The temporary sibling of `path` it is written to before being renamed.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", std::process::id()));
+
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("embuild-fs-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+
+        let file = dir.join("sub").join("state.toml");
+        write_atomic(&file, "a = 1\n").unwrap();
+        write_atomic(&file, "a = 2\n").unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "a = 2\n");
+        assert_eq!(fs::read_dir(dir.join("sub")).unwrap().count(), 1);
+
+        let mut permissions = fs::metadata(&file).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&file, permissions).unwrap();
+
+        remove_dir_all(&dir).unwrap();
+        assert!(!dir.exists());
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! Interrupt handling across child processes.
+//!
+//! Without a handler, an interrupt kills the process at once, while its children (pio
+//! and its Python processes, GDB, monitors) keep running or get killed in the middle of
+//! restoring the terminal. [`install`] installs a handler for `SIGINT`, `SIGTERM` and
+//! `SIGHUP` which instead:
+//! - forwards the signal to the child processes started with [`status`] and [`output`]
+//!   or registered with [`track`] (except a `SIGINT` from the terminal, which the
+//!   terminal sends to them already), and lets them exit on their own;
+//! - makes [`status`], [`output`] and [`check`] fail with [`io::ErrorKind::Interrupted`]
+//!   once they are done, so that the interrupted command unwinds;
+//! - exits right away if no child is running or on the second signal, killing the
+//!   children.
+//!
+//! [`exit`] then restores the terminal mode of the start and exits with the signal.
+//!
+//! On Windows, the console sends CTRL-C to all processes attached to it, so the
+//! children get it without forwarding and nothing is installed.
+
+use std::io;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Install the interrupt handler, once.
+pub fn install() {
+    imp::install();
+}
+
+/// The signal which interrupted the process, if any.
+pub fn signal() -> Option<i32> {
+    imp::signal()
+}
+
+/// Fail with [`io::ErrorKind::Interrupted`] if the process was interrupted.
+pub fn check() -> io::Result<()> {
+    match signal() {
+        Some(signal) => Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!("Interrupted by signal {}", signal),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Restore the terminal and exit the process with `signal`.
+pub fn exit(signal: i32) -> ! {
+    imp::exit(signal);
+
+    std::process::exit(128 + signal)
+}
+
+/// A child process the interrupts are forwarded to, until dropped.
+pub struct Tracked {
+    #[allow(dead_code)]
+    slot: Option<usize>,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(slot) = self.slot {
+            imp::untrack(slot);
+        }
+    }
+}
+
+/// Forward the interrupts to `child` until the returned guard is dropped.
+pub fn track(child: &Child) -> Tracked {
+    Tracked {
+        slot: imp::track(child),
+    }
+}
+
+/// Run `cmd` like [`Command::status`], forwarding the interrupts to it.
+pub fn status(cmd: &mut Command) -> io::Result<ExitStatus> {
+    let mut child = cmd.spawn()?;
+
+    let status = {
+        let _tracked = track(&child);
+        child.wait()?
+    };
+
+    check()?;
+
+    Ok(status)
+}
+
+/// Wait for `child` like [`Child::wait`], but kill it if it does not exit within
+/// `timeout`, returning `None` then.
+///
+/// Only `child` is killed, not the processes it started in turn.
+pub fn wait_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> io::Result<Option<ExitStatus>> {
+    let deadline = match timeout {
+        Some(timeout) => Instant::now() + timeout,
+        None => return child.wait().map(Some),
+    };
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+
+            return Ok(None);
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Run `cmd` like [`Command::output`], forwarding the interrupts to it.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    let child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let output = {
+        let _tracked = track(&child);
+        child.wait_with_output()?
+    };
+
+    check()?;
+
+    Ok(output)
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::os::raw::c_int;
+    use std::process::Child;
+    use std::ptr;
+    use std::sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, Ordering};
+    use std::sync::Once;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO_CHILD: AtomicI32 = AtomicI32::new(0);
+
+    /// The process ids of the tracked children, 0 for free slots.
+    static CHILDREN: [AtomicI32; 64] = [NO_CHILD; 64];
+    /// The signal which interrupted the process, 0 if none.
+    static SIGNAL: AtomicI32 = AtomicI32::new(0);
+    /// Whether stdin is a terminal, which sends `SIGINT` to all of its foreground processes.
+    static INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+    /// The terminal mode of the start, null if stdin is no terminal.
+    static TERMIOS: AtomicPtr<libc::termios> = AtomicPtr::new(ptr::null_mut());
+
+    static INSTALL: Once = Once::new();
+
+    pub fn install() {
+        INSTALL.call_once(|| unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 1 {
+                INTERACTIVE.store(true, Ordering::SeqCst);
+
+                let mut termios = std::mem::zeroed::<libc::termios>();
+                if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0 {
+                    TERMIOS.store(Box::into_raw(Box::new(termios)), Ordering::SeqCst);
+                }
+            }
+
+            for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+                libc::signal(
+                    signal,
+                    on_signal as extern "C" fn(c_int) as libc::sighandler_t,
+                );
+            }
+        });
+    }
+
+    pub fn signal() -> Option<i32> {
+        match SIGNAL.load(Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal),
+        }
+    }
+
+    pub fn exit(signal: i32) {
+        restore_terminal();
+
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    pub fn track(child: &Child) -> Option<usize> {
+        let pid = child.id() as i32;
+
+        CHILDREN.iter().position(|slot| {
+            slot.compare_exchange(0, pid, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        })
+    }
+
+    pub fn untrack(slot: usize) {
+        CHILDREN[slot].store(0, Ordering::SeqCst);
+    }
+
+    // Only async-signal-safe functions may be called in here
+    extern "C" fn on_signal(signal: c_int) {
+        let repeated = SIGNAL.swap(signal, Ordering::SeqCst) != 0;
+        let forward = signal != libc::SIGINT || !INTERACTIVE.load(Ordering::SeqCst);
+
+        let mut children = false;
+        for slot in &CHILDREN {
+            let pid = slot.load(Ordering::SeqCst);
+            if pid == 0 {
+                continue;
+            }
+
+            children = true;
+
+            if repeated {
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+            } else if forward {
+                unsafe { libc::kill(pid, signal) };
+            }
+        }
+
+        if repeated || !children {
+            // The signal is delivered, with its default action, once the handler returns
+            exit(signal);
+        }
+    }
+
+    fn restore_terminal() {
+        let termios = TERMIOS.load(Ordering::SeqCst);
+        if !termios.is_null() {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::process::Child;
+
+    pub fn install() {}
+
+    pub fn signal() -> Option<i32> {
+        None
+    }
+
+    pub fn exit(_signal: i32) {}
+
+    pub fn track(_child: &Child) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_timeout() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let status = wait_timeout(&mut child, Some(Duration::from_millis(100))).unwrap();
+        assert!(status.is_none());
+
+        let mut child = Command::new("true").spawn().unwrap();
+        let status = wait_timeout(&mut child, Some(Duration::from_secs(10))).unwrap();
+        assert!(status.unwrap().success());
+    }
+}
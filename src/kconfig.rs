@@ -1,7 +1,9 @@
-//! A quick and dirty parser for the .config files generated by kconfig systems (e.g. used
-//! in the esp-idf).
+//! A quick and dirty parser for the .config files, json files and C headers generated by
+//! kconfig systems (e.g. used in the esp-idf), and a generator of typed Rust constants
+//! from them.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
 use std::fs;
 use std::io::{self, BufRead, Read};
 use std::path::Path;
@@ -25,12 +27,17 @@ pub enum Tristate {
 }
 
 /// Value of a kconfig configuration item.
+///
+/// More kinds of values may be added, so matches need a wildcard arm.
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub enum Value {
     /// A [`Tristate`] value.
     Tristate(Tristate),
     /// A [`String`] value.
     String(String),
+    /// An integer value (`int` or `hex` items).
+    Integer(i64),
 }
 
 impl Value {
@@ -84,6 +91,7 @@ where
         serde_json::Value::Bool(true) => Some((k, Value::Tristate(Tristate::True))),
         serde_json::Value::Bool(false) => Some((k, Value::Tristate(Tristate::False))),
         serde_json::Value::String(value) => Some((k, Value::String(value))),
+        serde_json::Value::Number(value) => value.as_i64().map(|value| (k, Value::Integer(value))),
         _ => None,
     });
 
@@ -135,6 +143,113 @@ fn parse_config_value(str: impl AsRef<str>) -> Option<Value> {
     } else if str == "m" {
         Value::Tristate(Tristate::Module)
     } else {
-        return None;
+        Value::Integer(parse_integer(str)?)
     })
 }
+
+/// Parse a decimal or (`0x` prefixed) hexadecimal integer.
+fn parse_integer(str: &str) -> Option<i64> {
+    let (negative, str) = match str.strip_prefix('-') {
+        Some(str) => (true, str),
+        None => (false, str),
+    };
+
+    let value = match str.strip_prefix("0x").or_else(|| str.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => str.parse::<i64>().ok()?,
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+/// Try to load the configurations from a generated C header file (e.g. `sdkconfig.h`).
+pub fn try_from_header_file(
+    path: impl AsRef<Path>,
+) -> Result<impl Iterator<Item = (String, Value)>> {
+    try_from_header(fs::File::open(path.as_ref())?)
+}
+
+/// Try to load the configurations from a generated C header stream.
+///
+/// Only the items which are set are defined in the header, and boolean items are
+/// defined as `1`, so they are returned as [`Value::Integer`]; use
+/// [`try_from_json`] for exact types where a json file is generated as well.
+pub fn try_from_header<R>(reader: R) -> Result<impl Iterator<Item = (String, Value)>>
+where
+    R: Read,
+{
+    let iter = io::BufReader::new(reader)
+        .lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| {
+            let define = line.trim().strip_prefix("#define")?;
+            let define = define.trim_start();
+
+            let (key, value) = define.split_once(char::is_whitespace)?;
+            let value = value.trim();
+
+            let value = if let Some(value) = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+            {
+                Value::String(value.replace("\\\"", "\"").replace("\\\\", "\\"))
+            } else {
+                Value::Integer(parse_integer(value)?)
+            };
+
+            Some((key.to_owned(), value))
+        });
+
+    Ok(iter)
+}
+
+/// Generate Rust source code with one typed constant per configuration item: `bool`
+/// for tristate items (items compiled as module are `true`), `i64` for integer items
+/// and `&str` for string items. Unset items are omitted.
+///
+/// The items are sorted by key, so that the source only changes with the configuration.
+pub fn rust_source(values: impl IntoIterator<Item = (String, Value)>) -> String {
+    let values = values.into_iter().collect::<BTreeMap<_, _>>();
+
+    let mut source = String::from("// Generated from the kconfig configuration, do not edit\n\n");
+
+    for (key, value) in values {
+        let (ty, value) = match value {
+            Value::Tristate(Tristate::True | Tristate::Module) => ("bool", "true".to_owned()),
+            Value::Tristate(Tristate::False) => ("bool", "false".to_owned()),
+            Value::Tristate(Tristate::NotSet) => continue,
+            Value::String(value) => ("&str", format!("{:?}", value)),
+            Value::Integer(value) => ("i64", value.to_string()),
+        };
+
+        writeln!(source, "pub const {}: {} = {};", key, ty, value).unwrap();
+    }
+
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let header = "/* Automatically generated file; DO NOT EDIT */\n\
+                      #pragma once\n\
+                      #define CONFIG_IDF_TARGET \"esp32\"\n\
+                      #define CONFIG_ESP_MAIN_TASK_STACK_SIZE 3584\n\
+                      #define CONFIG_PARTITION_TABLE_OFFSET 0x8000\n\
+                      #define CONFIG_FREERTOS_UNICORE 1\n";
+
+        let source = rust_source(try_from_header(header.as_bytes()).unwrap());
+
+        assert_eq!(
+            source,
+            "// Generated from the kconfig configuration, do not edit\n\n\
+             pub const CONFIG_ESP_MAIN_TASK_STACK_SIZE: i64 = 3584;\n\
+             pub const CONFIG_FREERTOS_UNICORE: i64 = 1;\n\
+             pub const CONFIG_IDF_TARGET: &str = \"esp32\";\n\
+             pub const CONFIG_PARTITION_TABLE_OFFSET: i64 = 32768;\n"
+        );
+    }
+}
@@ -0,0 +1,350 @@
+//! Flash layouts: the partitions of a partition table, the images placed into them and
+//! the unallocated space in between, rendered as a text table or an SVG map.
+
+use std::fmt::Write;
+
+use crate::partitions::Partition;
+
+/// The share of a partition an image may use before it is warned about.
+pub const WARN_USAGE: f64 = 0.9;
+
+/// The offset of the first partition.
+const PARTITIONS_START: u32 = 0x9000;
+
+/// A region of the flash: a partition or unallocated space.
+#[derive(Clone, Debug)]
+pub struct Region {
+    /// The partition, `None` for unallocated space.
+    pub partition: Option<Partition>,
+    pub offset: u32,
+    pub size: u32,
+    /// The name and size of the image placed into the partition, if any.
+    pub image: Option<(String, u64)>,
+}
+
+impl Region {
+    /// The share of the region used by its image.
+    pub fn usage(&self) -> Option<f64> {
+        self.image
+            .as_ref()
+            .map(|(_, size)| *size as f64 / self.size as f64)
+    }
+}
+
+/// The layout of a flash.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    pub regions: Vec<Region>,
+}
+
+impl Layout {
+    /// The layout of `partitions` on a flash of `flash_size` bytes (if known), including
+    /// the unallocated space between the partitions.
+    pub fn new(partitions: &[Partition], flash_size: Option<u32>) -> Self {
+        let mut partitions = partitions.to_vec();
+        partitions.sort_by_key(|partition| partition.offset);
+
+        let mut regions = Vec::new();
+        let mut end = partitions
+            .first()
+            .map(|partition| partition.offset.min(PARTITIONS_START))
+            .unwrap_or(PARTITIONS_START);
+
+        for partition in partitions {
+            if partition.offset > end {
+                regions.push(Region {
+                    partition: None,
+                    offset: end,
+                    size: partition.offset - end,
+                    image: None,
+                });
+            }
+
+            end = end.max(partition.offset + partition.size);
+
+            regions.push(Region {
+                offset: partition.offset,
+                size: partition.size,
+                partition: Some(partition),
+                image: None,
+            });
+        }
+
+        if let Some(flash_size) = flash_size {
+            if flash_size > end {
+                regions.push(Region {
+                    partition: None,
+                    offset: end,
+                    size: flash_size - end,
+                    image: None,
+                });
+            }
+        }
+
+        Self { regions }
+    }
+
+    /// Place the image `name` of `size` bytes into all partitions matching `filter`.
+    pub fn place(&mut self, name: &str, size: u64, filter: impl Fn(&Partition) -> bool) {
+        for region in &mut self.regions {
+            if region.partition.as_ref().map_or(false, &filter) {
+                region.image = Some((name.to_owned(), size));
+            }
+        }
+    }
+
+    /// Warnings about images exceeding or approaching the size of their partition, and
+    /// about partitions exceeding the flash.
+    pub fn warnings(&self, flash_size: Option<u32>) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        for region in &self.regions {
+            let (partition, (image, size)) = match (&region.partition, &region.image) {
+                (Some(partition), Some(image)) => (partition, image),
+                _ => continue,
+            };
+
+            let usage = region.usage().unwrap_or_default();
+
+            if usage > 1.0 {
+                warnings.push(format!(
+                    "{} ({} bytes) does not fit into partition {} ({} bytes)",
+                    image, size, partition.label, region.size
+                ));
+            } else if usage > WARN_USAGE {
+                warnings.push(format!(
+                    "{} uses {:.1}% of partition {}, only {} bytes are left",
+                    image,
+                    usage * 100.0,
+                    partition.label,
+                    region.size as u64 - size
+                ));
+            }
+        }
+
+        if let Some(flash_size) = flash_size {
+            for region in &self.regions {
+                if region.offset as u64 + region.size as u64 > flash_size as u64 {
+                    if let Some(partition) = &region.partition {
+                        warnings.push(format!(
+                            "Partition {} ends beyond the flash size of {} bytes",
+                            partition.label, flash_size
+                        ));
+                    }
+                }
+            }
+        }
+
+        warnings
+    }
+
+    /// Render the layout as a text table.
+    pub fn table(&self) -> String {
+        let label_width = self
+            .regions
+            .iter()
+            .filter_map(|region| region.partition.as_ref())
+            .map(|partition| partition.label.len())
+            .max()
+            .unwrap_or(0)
+            .max("Partition".len());
+
+        let mut table = String::new();
+
+        writeln!(
+            table,
+            "{:<label$}  {:<14}  {:>10}  {:>10}  {:>10}  {:>10}  {:>6}  Image",
+            "Partition",
+            "Type",
+            "Offset",
+            "Size",
+            "Used",
+            "Free",
+            "Use%",
+            label = label_width
+        )
+        .unwrap();
+
+        for region in &self.regions {
+            let (label, type_name) = match &region.partition {
+                Some(partition) => (partition.label.clone(), partition.type_name()),
+                None => ("(unused)".to_owned(), String::new()),
+            };
+
+            let (used, free, usage, image) = match &region.image {
+                Some((image, size)) => (
+                    size.to_string(),
+                    (region.size as i64 - *size as i64).to_string(),
+                    format!("{:.1}", region.usage().unwrap_or_default() * 100.0),
+                    image.as_str(),
+                ),
+                None => (String::new(), String::new(), String::new(), ""),
+            };
+
+            let line = format!(
+                "{:<label$}  {:<14}  {:>#10x}  {:>10}  {:>10}  {:>10}  {:>6}  {}",
+                label,
+                type_name,
+                region.offset,
+                region.size,
+                used,
+                free,
+                usage,
+                image,
+                label = label_width
+            );
+
+            writeln!(table, "{}", line.trim_end()).unwrap();
+        }
+
+        table
+    }
+
+    /// Render the layout as an SVG map, one bar per region with the used part of the
+    /// partitions filled.
+    pub fn svg(&self) -> String {
+        const WIDTH: u32 = 720;
+        const BAR_X: u32 = 160;
+        const BAR_WIDTH: u32 = 300;
+        const MIN_HEIGHT: f64 = 28.0;
+        const SCALE_HEIGHT: f64 = 640.0;
+
+        let total = self
+            .regions
+            .iter()
+            .map(|region| region.size as f64)
+            .sum::<f64>()
+            .max(1.0);
+
+        let heights = self
+            .regions
+            .iter()
+            .map(|region| (region.size as f64 / total * SCALE_HEIGHT).max(MIN_HEIGHT))
+            .collect::<Vec<_>>();
+
+        let height = heights.iter().sum::<f64>() + 20.0;
+
+        let mut svg = String::new();
+
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{:.0}" font-family="monospace" font-size="12">"#,
+            WIDTH, height
+        )
+        .unwrap();
+
+        let mut y = 10.0;
+
+        for (region, height) in self.regions.iter().zip(heights) {
+            let (fill, label) = match &region.partition {
+                Some(partition) => (
+                    "#dde8f5",
+                    format!("{} ({})", partition.label, partition.type_name()),
+                ),
+                None => ("#f2f2f2", "(unused)".to_owned()),
+            };
+
+            writeln!(
+                svg,
+                r##"  <rect x="{}" y="{:.1}" width="{}" height="{:.1}" fill="{}" stroke="#555"/>"##,
+                BAR_X, y, BAR_WIDTH, height, fill
+            )
+            .unwrap();
+
+            if let Some(usage) = region.usage() {
+                let color = if usage > 1.0 {
+                    "#d9534f"
+                } else if usage > WARN_USAGE {
+                    "#f0ad4e"
+                } else {
+                    "#5cb85c"
+                };
+
+                writeln!(
+                    svg,
+                    r##"  <rect x="{}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}" stroke="#555"/>"##,
+                    BAR_X,
+                    y,
+                    BAR_WIDTH as f64 * usage.min(1.0),
+                    height,
+                    color
+                )
+                .unwrap();
+            }
+
+            let text_y = y + height / 2.0 + 4.0;
+
+            writeln!(
+                svg,
+                r#"  <text x="{}" y="{:.1}" text-anchor="end">{:#x}</text>"#,
+                BAR_X - 8,
+                text_y,
+                region.offset
+            )
+            .unwrap();
+
+            let mut description = format!("{}, {} KiB", escape(&label), region.size / 1024);
+            if let (Some((image, _)), Some(usage)) = (&region.image, region.usage()) {
+                write!(description, ", {} {:.1}%", escape(image), usage * 100.0).unwrap();
+            }
+
+            writeln!(
+                svg,
+                r#"  <text x="{}" y="{:.1}">{}</text>"#,
+                BAR_X + BAR_WIDTH + 8,
+                text_y,
+                description
+            )
+            .unwrap();
+
+            y += height;
+        }
+
+        svg.push_str("</svg>\n");
+
+        svg
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partitions::{SUBTYPE_NVS, TYPE_APP, TYPE_DATA};
+
+    #[test]
+    fn test_layout() {
+        let partitions = [
+            Partition {
+                label: "nvs".into(),
+                kind: TYPE_DATA,
+                subtype: SUBTYPE_NVS,
+                offset: 0x9000,
+                size: 0x6000,
+            },
+            Partition {
+                label: "factory".into(),
+                kind: TYPE_APP,
+                subtype: 0,
+                offset: 0x10000,
+                size: 0x100000,
+            },
+        ];
+
+        let mut layout = Layout::new(&partitions, Some(0x400000));
+        layout.place("firmware.bin", 0xf0000, |p| p.kind == TYPE_APP);
+
+        // nvs, unused gap, factory, unused rest of the flash
+        assert_eq!(layout.regions.len(), 4);
+        assert!(layout.regions[1].partition.is_none());
+        assert_eq!(layout.regions[1].size, 0x1000);
+        assert_eq!(layout.regions[3].size, 0x400000 - 0x110000);
+
+        assert_eq!(layout.warnings(None).len(), 1);
+    }
+}
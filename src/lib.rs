@@ -17,6 +17,9 @@ pub mod bindgen;
 #[cfg(feature = "pio")]
 pub mod pio;
 
+#[cfg(feature = "pio-model")]
+pub mod pio_model;
+
 #[cfg(feature = "cmake")]
 pub mod cmake;
 
@@ -35,10 +38,37 @@ pub mod symgen;
 #[cfg(feature = "elf")]
 pub mod bingen;
 
+#[cfg(feature = "elf")]
+pub mod elf;
+
+#[cfg(feature = "elf")]
+pub mod stackusage;
+
+#[cfg(feature = "elf")]
+pub mod symcheck;
+
+#[cfg(feature = "elf")]
+pub mod monitor;
+
+#[cfg(feature = "elf")]
+pub mod coredump;
+
+#[cfg(feature = "zephyr")]
+pub mod zephyr;
+
 pub mod build;
 pub mod cargo;
 pub mod cli;
 pub mod cmd;
+pub mod error;
 pub mod fs;
+pub mod interrupt;
+pub mod layout;
+pub mod linkmap;
+pub mod messages;
+pub mod nvs;
+pub mod partitions;
 pub mod python;
+pub mod terminal;
 pub mod utils;
+pub mod websocket;
@@ -0,0 +1,470 @@
+//! GNU ld map file parsing and diagnostics for failed or suspicious links.
+//!
+//! Raw GNU ld errors are hard to act upon when a Rust static library is linked into a C
+//! framework. This module recognizes the common Rust<->C integration failures (duplicate
+//! symbols, a missing entry point, sections discarded by `--gc-sections`) in the linker
+//! output and the map file, and explains what usually causes them.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+
+/// An input section of the link, as listed in a map file.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct InputSection {
+    /// The section name (e.g. `.text.app_main`).
+    pub name: String,
+    /// The address the section was placed at.
+    pub address: u64,
+    /// The size of the section in bytes.
+    pub size: u64,
+    /// The object file (or `archive(member)`) the section comes from.
+    pub object: String,
+    /// The symbols defined in this section, as listed in the map file.
+    pub symbols: Vec<String>,
+}
+
+/// A parsed GNU ld map file (as produced by `-Wl,-Map=<file>`).
+#[derive(Clone, Default, Debug)]
+pub struct MapFile {
+    /// Input sections removed by the linker (e.g. by `--gc-sections`).
+    pub discarded: Vec<InputSection>,
+    /// Input sections placed into the output.
+    pub placed: Vec<InputSection>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum MapPart {
+    Header,
+    Discarded,
+    Memory,
+    Map,
+}
+
+impl MapFile {
+    /// Parse the map file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Parse the contents of a map file.
+    pub fn parse(map: &str) -> Self {
+        let mut result = Self::default();
+        let mut part = MapPart::Header;
+        let mut pending_name: Option<String> = None;
+
+        for line in map.lines() {
+            match line.trim_end() {
+                "Discarded input sections" => {
+                    part = MapPart::Discarded;
+                    continue;
+                }
+                "Memory Configuration" => {
+                    part = MapPart::Memory;
+                    continue;
+                }
+                "Linker script and memory map" => {
+                    part = MapPart::Map;
+                    continue;
+                }
+                _ => (),
+            }
+
+            if part != MapPart::Discarded && part != MapPart::Map {
+                continue;
+            }
+
+            let sections = if part == MapPart::Discarded {
+                &mut result.discarded
+            } else {
+                &mut result.placed
+            };
+
+            let tokens = line.split_whitespace().collect::<Vec<_>>();
+
+            // An input section line starts with a single space followed by the section
+            // name; long section names are followed by the address, size and object on
+            // the next line
+            if line.starts_with(" .") || line.starts_with(" COMMON") {
+                match tokens.as_slice() {
+                    [name] => pending_name = Some((*name).to_owned()),
+                    [name, address, size, object @ ..] if !object.is_empty() => {
+                        pending_name = None;
+
+                        if let Some(section) = parse_section(name, address, size, object) {
+                            sections.push(section);
+                        }
+                    }
+                    _ => pending_name = None,
+                }
+            } else if let Some(name) = pending_name.take() {
+                if let [address, size, object @ ..] = tokens.as_slice() {
+                    if let Some(section) = parse_section(&name, address, size, object) {
+                        sections.push(section);
+                    }
+                }
+            } else if part == MapPart::Map {
+                // A symbol line: `<address> <symbol>` belonging to the last input section
+                if let [address, symbol] = tokens.as_slice() {
+                    if parse_hex(address).is_some() && is_symbol_name(symbol) {
+                        if let Some(section) = sections.last_mut() {
+                            section.symbols.push((*symbol).to_owned());
+                        }
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Guess the entry points the framework linked into the binary expects, based on the
+    /// objects of the link.
+    pub fn framework_entry_points(&self) -> Vec<&'static str> {
+        let objects = || self.placed.iter().chain(&self.discarded).map(|s| &s.object);
+
+        if objects().any(|o| o.contains("esp-idf") || o.contains("espidf")) {
+            vec!["app_main"]
+        } else if objects().any(|o| o.contains("arduino")) {
+            vec!["setup", "loop"]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Get the input section defining `symbol`, if it was placed into the output.
+    pub fn find_symbol(&self, symbol: impl AsRef<str>) -> Option<&InputSection> {
+        let symbol = symbol.as_ref();
+
+        self.placed
+            .iter()
+            .find(|section| section.symbols.iter().any(|s| s == symbol))
+    }
+
+    /// Get all symbols defined by more than one placed input section, together with the
+    /// objects defining them.
+    pub fn duplicate_symbols(&self) -> BTreeMap<&str, Vec<&str>> {
+        let mut symbols = BTreeMap::<_, Vec<_>>::new();
+
+        for section in &self.placed {
+            for symbol in &section.symbols {
+                symbols
+                    .entry(symbol.as_str())
+                    .or_default()
+                    .push(section.object.as_str());
+            }
+        }
+
+        symbols.retain(|_, objects| objects.len() > 1);
+        symbols
+    }
+
+    /// Analyze this map file and return the diagnostics for it.
+    ///
+    /// `entry_points` are the symbols the framework expects the Rust library to provide
+    /// (e.g. `app_main` for the ESP-IDF).
+    pub fn diagnostics(&self, entry_points: &[impl AsRef<str>]) -> Vec<Diagnostic> {
+        let mut diagnostics = self
+            .duplicate_symbols()
+            .into_iter()
+            .map(|(symbol, objects)| Diagnostic::DuplicateSymbol {
+                symbol: symbol.to_owned(),
+                objects: objects.into_iter().map(str::to_owned).collect(),
+            })
+            .collect::<Vec<_>>();
+
+        for entry_point in entry_points {
+            let entry_point = entry_point.as_ref();
+
+            if self.find_symbol(entry_point).is_some() {
+                continue;
+            }
+
+            let suffix = format!(".{}", entry_point);
+            if let Some(section) = self
+                .discarded
+                .iter()
+                .find(|section| section.name.ends_with(&suffix))
+            {
+                diagnostics.push(Diagnostic::DiscardedSection {
+                    symbol: entry_point.to_owned(),
+                    section: section.name.clone(),
+                    object: section.object.clone(),
+                });
+            } else {
+                diagnostics.push(Diagnostic::MissingEntryPoint {
+                    symbol: entry_point.to_owned(),
+                });
+            }
+        }
+
+        diagnostics
+    }
+}
+
+/// A problem detected in the link of the final binary.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Diagnostic {
+    /// The same symbol is defined by multiple objects.
+    DuplicateSymbol {
+        symbol: String,
+        objects: Vec<String>,
+    },
+    /// An entry point the framework calls is not defined by any object.
+    MissingEntryPoint { symbol: String },
+    /// The section containing an entry point was removed by `--gc-sections`.
+    DiscardedSection {
+        symbol: String,
+        section: String,
+        object: String,
+    },
+    /// A symbol is referenced but not defined by any object.
+    UndefinedSymbol {
+        symbol: String,
+        referenced_from: Option<String>,
+    },
+}
+
+impl Diagnostic {
+    /// A suggestion how the problem can usually be fixed.
+    pub fn hint(&self) -> String {
+        match self {
+            Self::DuplicateSymbol { symbol, .. } if symbol.starts_with("__") => format!(
+                "`{}` looks like a compiler intrinsic which is provided by both Rust's `compiler_builtins` and the C toolchain's libgcc; \
+                 this is usually harmless, but make sure both implementations are compatible",
+                symbol
+            ),
+            Self::DuplicateSymbol { .. } => "the symbol is defined in both the Rust library and C code (or in two Rust static libraries); \
+                 rename one of them, or link only a single Rust static library into the firmware"
+                .to_owned(),
+            Self::MissingEntryPoint { symbol } | Self::UndefinedSymbol { symbol, .. }
+                if is_entry_point(symbol) =>
+            {
+                format!(
+                    "the framework calls `{}`, but the Rust library does not export it; define it as \
+                     `#[no_mangle] pub extern \"C\" fn {}()` and make sure the crate is built as a `staticlib`",
+                    symbol, symbol
+                )
+            }
+            Self::MissingEntryPoint { symbol } => format!(
+                "no object defines `{}`; make sure it is exported with `#[no_mangle]` and the Rust library is linked",
+                symbol
+            ),
+            Self::DiscardedSection { symbol, .. } => format!(
+                "`{}` was compiled, but removed by `--gc-sections` because nothing references it; \
+                 it is probably not marked `#[no_mangle] pub extern \"C\"`, so the framework's reference does not match its (mangled) name",
+                symbol
+            ),
+            Self::UndefinedSymbol { symbol, .. } if symbol.contains("_ZN") || symbol.contains("_R") => format!(
+                "`{}` is a Rust symbol; this usually means two different Rust static libraries (or Rust versions) are linked together, \
+                 link only a single Rust static library into the firmware",
+                symbol
+            ),
+            Self::UndefinedSymbol { symbol, .. } => format!(
+                "`{}` is neither defined by the Rust library nor by the C framework; if it is a C function, \
+                 make sure the framework component providing it is enabled, if it is a Rust function, export it with `#[no_mangle]`",
+                symbol
+            ),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DuplicateSymbol { symbol, objects } => write!(
+                f,
+                "symbol `{}` is defined multiple times (in {})",
+                symbol,
+                objects.join(", ")
+            ),
+            Self::MissingEntryPoint { symbol } => write!(f, "entry point `{}` is missing", symbol),
+            Self::DiscardedSection {
+                symbol,
+                section,
+                object,
+            } => write!(
+                f,
+                "section `{}` containing `{}` was discarded (from {})",
+                section, symbol, object
+            ),
+            Self::UndefinedSymbol {
+                symbol,
+                referenced_from: Some(object),
+            } => write!(f, "undefined reference to `{}` in {}", symbol, object),
+            Self::UndefinedSymbol { symbol, .. } => {
+                write!(f, "undefined reference to `{}`", symbol)
+            }
+        }?;
+
+        write!(f, "\n  hint: {}", self.hint())
+    }
+}
+
+/// Analyze the (stdout and stderr) output of a failed link and return the diagnostics
+/// for the recognized errors.
+pub fn analyze_output(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut duplicates = BTreeMap::<String, Vec<String>>::new();
+
+    for line in output.lines() {
+        if let Some(symbol) = quoted_symbol(line, "multiple definition of ") {
+            let object = location(line, "multiple definition of ").unwrap_or_default();
+
+            duplicates.entry(symbol).or_default().push(object);
+        } else if let Some(symbol) = quoted_symbol(line, "undefined reference to ") {
+            let referenced_from = location(line, "undefined reference to ");
+
+            let diagnostic = Diagnostic::UndefinedSymbol {
+                symbol,
+                referenced_from,
+            };
+
+            if !diagnostics.contains(&diagnostic) {
+                diagnostics.push(diagnostic);
+            }
+        }
+    }
+
+    diagnostics.extend(
+        duplicates
+            .into_iter()
+            .map(|(symbol, objects)| Diagnostic::DuplicateSymbol { symbol, objects }),
+    );
+
+    diagnostics
+}
+
+fn parse_section(name: &str, address: &str, size: &str, object: &[&str]) -> Option<InputSection> {
+    Some(InputSection {
+        name: name.to_owned(),
+        address: parse_hex(address)?,
+        size: parse_hex(size)?,
+        object: object.join(" "),
+        symbols: Vec::new(),
+    })
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+fn is_symbol_name(s: &str) -> bool {
+    s.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+        && !s.starts_with('.')
+}
+
+fn is_entry_point(symbol: &str) -> bool {
+    matches!(symbol, "app_main" | "main" | "setup" | "loop")
+}
+
+/// Extract the object or source location preceding `message` in `line`, without the
+/// leading `.../ld: ` prefix.
+fn location(line: &str, message: &str) -> Option<String> {
+    let location = line[..line.find(message)?].trim().trim_end_matches(':');
+    let location = location
+        .rfind("ld: ")
+        .or_else(|| location.rfind("ld.exe: "))
+        .map(|pos| &location[location[pos..].find(": ").unwrap() + pos + 2..])
+        .unwrap_or(location)
+        .trim();
+
+    if location.is_empty() {
+        None
+    } else {
+        Some(location.to_owned())
+    }
+}
+
+/// Extract the symbol quoted as `` `sym' `` or `'sym'` after `prefix` in `line`.
+fn quoted_symbol(line: &str, prefix: &str) -> Option<String> {
+    let rest = &line[line.find(prefix)? + prefix.len()..];
+    let rest = rest.strip_prefix(['`', '\'', '"'])?;
+    let end = rest.find(['\'', '"'])?;
+
+    Some(rest[..end].to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "\
+Archive member included to satisfy reference by file (symbol)
+
+Discarded input sections
+
+ .text          0x0000000000000000        0x0 esp-idf/main/libmain.a(main.c.obj)
+ .text.app_main
+                0x0000000000000000       0x1c /target/debug/librust.a(rust-1234.o)
+
+Memory Configuration
+
+Name             Origin             Length             Attributes
+iram0_0_seg      0x0000000040080000 0x0000000000020000 xr
+
+Linker script and memory map
+
+ .text.foo      0x00000000400d0020       0x10 /target/debug/librust.a(rust-1234.o)
+                0x00000000400d0020                foo
+ .text.foo      0x00000000400d0030       0x10 libother.a(other.o)
+                0x00000000400d0030                foo
+";
+
+    #[test]
+    fn test_parse_map() {
+        let map = MapFile::parse(MAP);
+
+        assert_eq!(map.discarded.len(), 2);
+        assert_eq!(map.discarded[1].name, ".text.app_main");
+        assert_eq!(map.discarded[1].size, 0x1c);
+        assert_eq!(map.placed.len(), 2);
+        assert_eq!(map.placed[0].symbols, vec!["foo".to_owned()]);
+
+        let diagnostics = map.diagnostics(&["app_main"]);
+
+        assert_eq!(
+            diagnostics[0],
+            Diagnostic::DuplicateSymbol {
+                symbol: "foo".into(),
+                objects: vec![
+                    "/target/debug/librust.a(rust-1234.o)".into(),
+                    "libother.a(other.o)".into()
+                ],
+            }
+        );
+        assert!(matches!(
+            &diagnostics[1],
+            Diagnostic::DiscardedSection { symbol, .. } if symbol == "app_main"
+        ));
+    }
+
+    #[test]
+    fn test_analyze_output() {
+        let output = "\
+ld: esp-idf/freertos/libfreertos.a(port_common.c.obj): in function `main_task':
+port_common.c:(.text.main_task+0x2c): undefined reference to `app_main'
+ld: librust.a(b.o): multiple definition of `memcpy'; libc.a(memcpy.o): first defined here
+collect2: error: ld returned 1 exit status";
+
+        let diagnostics = analyze_output(output);
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic::UndefinedSymbol {
+                    symbol: "app_main".into(),
+                    referenced_from: Some("port_common.c:(.text.main_task+0x2c)".into()),
+                },
+                Diagnostic::DuplicateSymbol {
+                    symbol: "memcpy".into(),
+                    objects: vec!["librust.a(b.o)".into()],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! The catalog of user-facing messages, by message id.
+//!
+//! A [`Message`] is an id with named arguments, rendered with the text of the id in the
+//! selected [`Catalog`], e.g. `not-built` with `path` as `{path} does not exist, did you
+//! build your project first?` in English. Messages are errors themselves, so that tests
+//! can match errors by their ids (see [`ids`]) instead of their English text.
+//!
+//! The English texts are built in. Translations are files `<locale>.msg` of `id = text`
+//! lines (`#` starts a comment), in the directory of `CARGO_PIO_MESSAGES_DIR` or in
+//! `share/cargo-pio/messages` of the installation prefix of the executable, where
+//! distributions install them. Messages missing in a translation are rendered in
+//! English.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::{env, fs, ptr};
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+
+/// The directory of the translations, if set.
+pub const VAR_MESSAGES_DIR: &str = "CARGO_PIO_MESSAGES_DIR";
+
+/// The English texts of the messages.
+const ENGLISH: &[(&str, &str)] = &[
+    ("error", "Error: {error}"),
+    ("caused-by", "  caused by: {cause}"),
+    ("hint", "hint: {hint}"),
+    (
+        "not-built",
+        "{path} does not exist, did you build your project first?",
+    ),
+    (
+        "elf-not-built",
+        "Elf file {path} does not exist, did you build your project first?",
+    ),
+    ("specify-elf", "Specify the Elf file with --elf"),
+    (
+        "pio-check-failed",
+        "Failed to check the PlatformIO installation",
+    ),
+    (
+        "pio-not-installed",
+        "PlatformIO is probably not installed (yet) in this location, install it first",
+    ),
+];
+
+/// The catalog of the selected locale, null for English.
+static CATALOG: AtomicPtr<Catalog> = AtomicPtr::new(ptr::null_mut());
+
+/// The translations of a locale.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Catalog {
+    pub locale: String,
+    messages: HashMap<String, String>,
+}
+
+impl Catalog {
+    /// Parse the translations `text` of `locale`.
+    pub fn parse(locale: impl Into<String>, text: &str) -> Self {
+        let messages = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (id, text) = line.split_once('=')?;
+                Some((id.trim().to_owned(), text.trim().to_owned()))
+            })
+            .collect();
+
+        Self {
+            locale: locale.into(),
+            messages,
+        }
+    }
+
+    /// Load the translations of `locale`, e.g. `de` or `pt_BR`.
+    pub fn load(locale: &str) -> Result<Self> {
+        let file_name = format!("{}.msg", locale);
+
+        let mut dirs = Vec::new();
+        if let Some(dir) = env::var_os(VAR_MESSAGES_DIR) {
+            dirs.push(PathBuf::from(dir));
+        }
+        if let Some(prefix) = env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.parent()?.parent()?.to_owned()))
+        {
+            dirs.push(prefix.join("share").join("cargo-pio").join("messages"));
+        }
+
+        let path = dirs
+            .iter()
+            .map(|dir| dir.join(&file_name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| anyhow!("No translations of locale {} found", locale))?;
+
+        debug!("Loading the translations {}", path.display());
+
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        Ok(Self::parse(locale, &text))
+    }
+
+    /// The text of the message `id`, in English if it has no translation.
+    pub fn text<'a>(&'a self, id: &'a str) -> &'a str {
+        self.messages
+            .get(id)
+            .map(String::as_str)
+            .unwrap_or_else(|| english(id))
+    }
+}
+
+/// The English text of the message `id`, the id itself if it is unknown.
+fn english(id: &str) -> &str {
+    ENGLISH
+        .iter()
+        .find(|(english_id, _)| *english_id == id)
+        .map_or(id, |(_, text)| text)
+}
+
+/// Select `locale` for all messages rendered from now on, e.g. at the start of the
+/// process. `en` and the `C` and `POSIX` locales select English.
+pub fn set_locale(locale: &str) -> Result<()> {
+    // E.g. `de_DE.UTF-8`
+    let locale = locale.split(['.', '@']).next().unwrap_or_default();
+
+    let catalog = match locale {
+        "" | "C" | "POSIX" | "en" => None,
+        _ if locale.starts_with("en_") => None,
+        _ => Some(Catalog::load(locale).or_else(|err| {
+            // `de_DE` falls back to `de`
+            match locale.split_once('_') {
+                Some((language, _)) => Catalog::load(language),
+                None => Err(err),
+            }
+        })?),
+    };
+
+    let catalog = catalog.map_or(ptr::null_mut(), |catalog| Box::into_raw(Box::new(catalog)));
+
+    // The previous catalog may still be referenced, it is leaked
+    CATALOG.store(catalog, Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// The catalog of the selected locale, `None` for English.
+pub fn catalog() -> Option<&'static Catalog> {
+    unsafe { CATALOG.load(Ordering::SeqCst).as_ref() }
+}
+
+/// A user-facing message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    id: &'static str,
+    args: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    pub fn new(id: &'static str) -> Self {
+        Self {
+            id,
+            args: Vec::new(),
+        }
+    }
+
+    /// Set the argument `name`, which replaces `{name}` in the text.
+    pub fn arg(mut self, name: &'static str, value: impl Display) -> Self {
+        self.args.push((name, value.to_string()));
+        self
+    }
+
+    pub fn id(&self) -> &'static str {
+        self.id
+    }
+
+    /// Render the message with `catalog`, in English if `None`.
+    pub fn render(&self, catalog: Option<&Catalog>) -> String {
+        let text = match catalog {
+            Some(catalog) => catalog.text(self.id),
+            None => english(self.id),
+        };
+
+        self.args
+            .iter()
+            .fold(text.to_owned(), |text, (name, value)| {
+                text.replace(&format!("{{{}}}", name), value)
+            })
+    }
+}
+
+/// Renders the message with the catalog of the selected locale.
+impl Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.render(catalog()))
+    }
+}
+
+impl Error for Message {}
+
+/// The ids of the messages of `err` and its causes, outermost first.
+pub fn ids(err: &anyhow::Error) -> Vec<&'static str> {
+    // Messages attached as context are only found by the downcast of the error itself
+    let context = err.downcast_ref::<Message>();
+
+    context
+        .into_iter()
+        .chain(
+            err.chain()
+                .filter_map(|cause| cause.downcast_ref::<Message>())
+                .filter(|cause| !context.map_or(false, |context| ptr::eq(context, *cause))),
+        )
+        .map(Message::id)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let catalog = Catalog::parse(
+            "de",
+            "# German\n\
+             not-built = {path} existiert nicht, wurde das Projekt gebaut?\n",
+        );
+
+        let message = Message::new("not-built").arg("path", "firmware.elf");
+        assert_eq!(
+            message.render(None),
+            "firmware.elf does not exist, did you build your project first?"
+        );
+        assert_eq!(
+            message.render(Some(&catalog)),
+            "firmware.elf existiert nicht, wurde das Projekt gebaut?"
+        );
+        assert_eq!(
+            Message::new("hint")
+                .arg("hint", "Install it")
+                .render(Some(&catalog)),
+            "hint: Install it"
+        );
+
+        let err = Err::<(), _>(message)
+            .with_context(|| Message::new("pio-check-failed"))
+            .unwrap_err();
+        assert_eq!(ids(&err), vec!["pio-check-failed", "not-built"]);
+    }
+}
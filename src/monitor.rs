@@ -0,0 +1,599 @@
+//! Decoders for the output of a device monitor.
+//!
+//! A monitor reads the raw output of a device through a [`transport::Transport`] (e.g.
+//! from a serial port or a network bridge) and passes it through a chain of
+//! [`Decoder`]s before printing it. The decoders provided here are:
+//! - [`TimestampDecoder`]: prefixes every line with the time elapsed since the start
+//! - [`BacktraceDecoder`]: resolves the code addresses in panics and backtraces to
+//!   `function at file:line` using `addr2line` and the firmware ELF file
+//! - [`DefmtDecoder`]: decodes [defmt](https://defmt.ferrous-systems.com) frames with
+//!   `defmt-print`
+//! - [`filter::FilterDecoder`]: highlights and suppresses lines and runs triggers on
+//!   them, which may also end the session (see [`Decoder::exit_code`])
+//!
+//! A session can be logged with a [`SessionLog`]: the decoded output goes to a text
+//! file and the raw output, with the time it was received, to a recording which
+//! [`replay`] feeds through decoders again later, e.g. to analyze an intermittent
+//! failure in the field with the ELF file of the firmware that showed it.
+
+pub mod filter;
+pub mod transport;
+
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+
+use crate::elf::ElfInfo;
+use crate::error::HintExt;
+
+/// The header of a recording of the raw output of a device.
+const RECORDING_MAGIC: &[u8] = b"cargo-pio monitor recording v1\n";
+
+/// A transformation of the output of a device.
+pub trait Decoder {
+    /// Decode the next chunk of `data` and return the data to pass on to the next
+    /// decoder (or to print).
+    ///
+    /// `data` is not necessarily aligned to lines or frames.
+    fn decode(&mut self, data: &[u8]) -> Vec<u8>;
+
+    /// Called before [`Decoder::decode`] with the time the data was received at,
+    /// relative to the start of the session.
+    ///
+    /// For replayed sessions, this is the time of the recording.
+    fn set_time(&mut self, _elapsed: Duration) {}
+
+    /// Return any data still buffered, called once the device output ended.
+    fn finish(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// The exit code the session should end with, once the decoder requests its end.
+    fn exit_code(&self) -> Option<i32> {
+        None
+    }
+}
+
+/// Pass everything read from `reader` through `decoders` (in order) and write the
+/// result to `writer`, until `reader` reaches its end or a decoder requests the end of
+/// the session, whose exit code is returned.
+pub fn pipe(
+    reader: impl Read,
+    writer: impl Write,
+    decoders: &mut [Box<dyn Decoder>],
+) -> io::Result<Option<i32>> {
+    pipe_logged(reader, writer, decoders, None)
+}
+
+/// Like [`pipe`], but also log the session to `log`.
+pub fn pipe_logged(
+    mut reader: impl Read,
+    writer: impl Write,
+    decoders: &mut [Box<dyn Decoder>],
+    log: Option<&mut SessionLog>,
+) -> io::Result<Option<i32>> {
+    let start = Instant::now();
+    let mut buf = [0_u8; 1024];
+
+    let next = || loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(len) => return Ok(Some((start.elapsed(), buf[..len].to_vec()))),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    };
+
+    run(next, writer, decoders, log)
+}
+
+/// Pass the raw device output recorded in `recording` (see [`SessionLog`]) through
+/// `decoders` (in order) and write the result to `writer`.
+///
+/// The decoders get the time of the recording, not of the replay. Returns the exit code
+/// requested by a decoder, if any.
+pub fn replay(
+    recording: impl AsRef<Path>,
+    writer: impl Write,
+    decoders: &mut [Box<dyn Decoder>],
+) -> Result<Option<i32>> {
+    let recording = recording.as_ref();
+    let mut chunks = parse_recording(&fs::read(recording)?)
+        .with_context(|| anyhow!("Failed to read recording {}", recording.display()))
+        .with_hint(|| {
+            format!(
+                "Replay the raw recording {} instead of the decoded log",
+                SessionLog::recording_path(recording).display()
+            )
+        })?
+        .into_iter();
+
+    Ok(run(|| Ok(chunks.next()), writer, decoders, None)?)
+}
+
+fn run(
+    mut next: impl FnMut() -> io::Result<Option<(Duration, Vec<u8>)>>,
+    mut writer: impl Write,
+    decoders: &mut [Box<dyn Decoder>],
+    mut log: Option<&mut SessionLog>,
+) -> io::Result<Option<i32>> {
+    let mut elapsed = Duration::ZERO;
+    let exit_code = |decoders: &[Box<dyn Decoder>]| decoders.iter().find_map(|d| d.exit_code());
+
+    while let Some((time, data)) = next()? {
+        elapsed = time;
+
+        if let Some(log) = log.as_deref_mut() {
+            log.record(elapsed, &data)?;
+        }
+
+        let data = decoders.iter_mut().fold(data, |data, decoder| {
+            decoder.set_time(elapsed);
+            decoder.decode(&data)
+        });
+
+        write_decoded(&mut writer, log.as_deref_mut(), elapsed, &data)?;
+
+        if exit_code(decoders).is_some() {
+            break;
+        }
+    }
+
+    let mut data = Vec::new();
+    for decoder in decoders.iter_mut() {
+        data = decoder.decode(&data);
+        data.extend(decoder.finish());
+    }
+
+    write_decoded(&mut writer, log, elapsed, &data)?;
+
+    Ok(exit_code(decoders))
+}
+
+fn write_decoded(
+    writer: &mut impl Write,
+    log: Option<&mut SessionLog>,
+    elapsed: Duration,
+    data: &[u8],
+) -> io::Result<()> {
+    writer.write_all(data)?;
+    writer.flush()?;
+
+    if let Some(log) = log {
+        log.log(elapsed, data)?;
+    }
+
+    Ok(())
+}
+
+/// The log of a monitor session.
+///
+/// The decoded output is written to the log file itself, the raw output of the device
+/// to a recording next to it (see [`SessionLog::recording_path`]), which can be
+/// replayed with [`replay`]. Both are written as the data arrives, so that they are
+/// complete even if the monitor is killed.
+pub struct SessionLog {
+    decoded: File,
+    recording: File,
+    timestamps: Option<TimestampDecoder>,
+}
+
+impl SessionLog {
+    /// Create the log file `path` and its recording, replacing existing ones.
+    ///
+    /// If `timestamps`, every line of the log file is prefixed with the time it was
+    /// received at, like with a [`TimestampDecoder`].
+    pub fn create(path: impl AsRef<Path>, timestamps: bool) -> Result<Self> {
+        let path = path.as_ref();
+        let recording_path = Self::recording_path(path);
+
+        let decoded = File::create(path)
+            .with_context(|| anyhow!("Failed to create log file {}", path.display()))?;
+        let mut recording = File::create(&recording_path)
+            .with_context(|| anyhow!("Failed to create recording {}", recording_path.display()))?;
+
+        recording.write_all(RECORDING_MAGIC)?;
+
+        Ok(Self {
+            decoded,
+            recording,
+            timestamps: timestamps.then(TimestampDecoder::new),
+        })
+    }
+
+    /// The path of the recording of the log file `path`: `<path>.raw`.
+    pub fn recording_path(path: impl AsRef<Path>) -> PathBuf {
+        let mut path = path.as_ref().as_os_str().to_owned();
+        path.push(".raw");
+
+        path.into()
+    }
+
+    fn record(&mut self, elapsed: Duration, data: &[u8]) -> io::Result<()> {
+        self.recording.write_all(&encode_record(elapsed, data))
+    }
+
+    fn log(&mut self, elapsed: Duration, data: &[u8]) -> io::Result<()> {
+        match &mut self.timestamps {
+            Some(timestamps) => {
+                timestamps.set_time(elapsed);
+                self.decoded.write_all(&timestamps.decode(data))
+            }
+            None => self.decoded.write_all(data),
+        }
+    }
+}
+
+/// A record of a recording: the time in milliseconds (u64) and the length of the data
+/// (u32), both little endian, followed by the data.
+fn encode_record(elapsed: Duration, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(12 + data.len());
+    record.extend((elapsed.as_millis() as u64).to_le_bytes());
+    record.extend((data.len() as u32).to_le_bytes());
+    record.extend(data);
+
+    record
+}
+
+/// Parse the records of the recording `data`.
+///
+/// A truncated last record (e.g. of a monitor killed while writing it) is dropped.
+fn parse_recording(data: &[u8]) -> Result<Vec<(Duration, Vec<u8>)>> {
+    let mut rest = match data.strip_prefix(RECORDING_MAGIC) {
+        Some(rest) => rest,
+        None => bail!("Not a monitor recording"),
+    };
+
+    let mut chunks = Vec::new();
+
+    while rest.len() >= 12 {
+        let millis = u64::from_le_bytes(rest[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+
+        if rest.len() < 12 + len {
+            break;
+        }
+
+        chunks.push((Duration::from_millis(millis), rest[12..12 + len].to_vec()));
+        rest = &rest[12 + len..];
+    }
+
+    if !rest.is_empty() {
+        warn!("The recording ends with a truncated record, ignoring it");
+    }
+
+    Ok(chunks)
+}
+
+/// Prefixes every line with the time elapsed since the decoder was created (or the time
+/// of the session, see [`Decoder::set_time`]), in the format `[   12.345] `.
+pub struct TimestampDecoder {
+    start: Instant,
+    elapsed: Option<Duration>,
+    line_start: bool,
+}
+
+impl TimestampDecoder {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: None,
+            line_start: true,
+        }
+    }
+}
+
+impl Default for TimestampDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for TimestampDecoder {
+    fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            if self.line_start {
+                let elapsed = self.elapsed.unwrap_or_else(|| self.start.elapsed());
+                out.extend(
+                    format!("[{:>5}.{:03}] ", elapsed.as_secs(), elapsed.subsec_millis()).bytes(),
+                );
+            }
+
+            out.push(byte);
+            self.line_start = byte == b'\n';
+        }
+
+        out
+    }
+
+    fn set_time(&mut self, elapsed: Duration) {
+        self.elapsed = Some(elapsed);
+    }
+}
+
+/// Resolves the code addresses printed in a line (e.g. an ESP-IDF `Backtrace:` or a
+/// register dump) to functions and source locations.
+///
+/// The decoded locations are printed after the line itself, which is passed through
+/// unchanged. Only addresses inside of executable sections of the ELF file are
+/// resolved. If no `addr2line` executable is available, only the function names are
+/// resolved using the ELF symbol table.
+pub struct BacktraceDecoder {
+    elf_file: PathBuf,
+    elf: ElfInfo,
+    addr2line: Option<PathBuf>,
+    line: Vec<u8>,
+}
+
+impl BacktraceDecoder {
+    /// Create a decoder for the firmware in `elf_file`, using the `addr2line`
+    /// executable to resolve source locations.
+    ///
+    /// If `addr2line` is `None`, it is searched with [`ElfInfo::find_tool`].
+    pub fn new(elf_file: impl AsRef<Path>, addr2line: Option<PathBuf>) -> Result<Self> {
+        let elf_file = elf_file.as_ref().to_owned();
+        let elf = ElfInfo::from_file(&elf_file)
+            .with_context(|| anyhow!("Failed to read ELF file {}", elf_file.display()))?;
+        let addr2line = addr2line.or_else(|| elf.find_tool("addr2line"));
+
+        if addr2line.is_none() {
+            warn!(
+                "No addr2line executable found, backtraces will only be decoded to function names"
+            );
+        }
+
+        Ok(Self {
+            elf_file,
+            elf,
+            addr2line,
+            line: Vec::new(),
+        })
+    }
+
+    /// The ELF file backtraces are decoded against.
+    pub fn elf(&self) -> &ElfInfo {
+        &self.elf
+    }
+
+    fn decode_line(&self, line: &str) -> Vec<String> {
+        let addresses = code_addresses(line)
+            .into_iter()
+            .filter(|address| {
+                self.elf
+                    .sections
+                    .iter()
+                    .any(|s| s.exec && s.alloc && (s.address..s.address + s.size).contains(address))
+            })
+            .collect::<Vec<_>>();
+
+        if addresses.is_empty() {
+            return Vec::new();
+        }
+
+        if let Some(addr2line) = &self.addr2line {
+            match self.addr2line(addr2line, &addresses) {
+                Ok(lines) => return lines,
+                Err(err) => warn!("Failed to run {}: {}", addr2line.display(), err),
+            }
+        }
+
+        addresses
+            .iter()
+            .map(|&address| match self.elf.function_at(address) {
+                Some(symbol) => format!(
+                    "0x{:08x}: {}+0x{:x}",
+                    address,
+                    symbol.name,
+                    address - symbol.address
+                ),
+                None => format!("0x{:08x}: ??", address),
+            })
+            .collect()
+    }
+
+    fn addr2line(&self, addr2line: &Path, addresses: &[u64]) -> Result<Vec<String>> {
+        let output = Command::new(addr2line)
+            .arg("-pfiaC")
+            .arg("-e")
+            .arg(&self.elf_file)
+            .args(addresses.iter().map(|address| format!("0x{:08x}", address)))
+            .stderr(Stdio::inherit())
+            .output()?;
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| line.trim().to_owned())
+            .filter(|line| !line.is_empty())
+            .collect())
+    }
+}
+
+impl Decoder for BacktraceDecoder {
+    fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            out.push(byte);
+
+            if byte == b'\n' {
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                self.line.clear();
+
+                for decoded in self.decode_line(&line) {
+                    out.extend(format!("  => {}\n", decoded).bytes());
+                }
+            } else {
+                self.line.push(byte);
+            }
+        }
+
+        out
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+
+        self.decode_line(&line)
+            .into_iter()
+            .flat_map(|decoded| format!("\n  => {}", decoded).into_bytes())
+            .collect()
+    }
+}
+
+/// Decodes defmt frames by piping the device output into `defmt-print`.
+///
+/// `defmt-print` prints the decoded log messages itself, so this decoder consumes all
+/// data and should be the last one in the chain.
+pub struct DefmtDecoder {
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl DefmtDecoder {
+    /// Start `defmt-print` (installed with `cargo install defmt-print`) for the
+    /// firmware in `elf_file`, passing it the additional `args`.
+    pub fn new(
+        elf_file: impl AsRef<Path>,
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Result<Self> {
+        let mut child = Command::new("defmt-print")
+            .arg("-e")
+            .arg(elf_file.as_ref())
+            .args(args.into_iter().map(Into::into))
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                if err.kind() == io::ErrorKind::NotFound {
+                    anyhow!(
+                        "defmt-print not found, please install it with 'cargo install defmt-print'"
+                    )
+                } else {
+                    err.into()
+                }
+            })?;
+
+        Ok(Self {
+            stdin: child.stdin.take(),
+            child,
+        })
+    }
+}
+
+impl Decoder for DefmtDecoder {
+    fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        if let Some(stdin) = &mut self.stdin {
+            if let Err(err) = stdin.write_all(data).and_then(|_| stdin.flush()) {
+                warn!("defmt-print exited: {}", err);
+                self.stdin = None;
+            }
+        }
+
+        Vec::new()
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        // Closing stdin makes defmt-print exit
+        self.stdin = None;
+
+        if let Err(err) = self.child.wait() {
+            warn!("Failed to wait for defmt-print: {}", err);
+        }
+
+        Vec::new()
+    }
+}
+
+impl Drop for DefmtDecoder {
+    fn drop(&mut self) {
+        self.stdin = None;
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Extract all 32-bit hex addresses (`0x` followed by 8 hex digits) from `line`.
+fn code_addresses(line: &str) -> Vec<u64> {
+    let mut addresses = Vec::new();
+    let mut rest = line;
+
+    while let Some(start) = rest.find("0x") {
+        rest = &rest[start + 2..];
+
+        let digits = rest
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .unwrap_or(rest.len());
+
+        if digits == 8 {
+            if let Ok(address) = u64::from_str_radix(&rest[..digits], 16) {
+                addresses.push(address);
+            }
+        }
+
+        rest = &rest[digits..];
+    }
+
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_addresses() {
+        assert_eq!(
+            code_addresses("Backtrace:0x400d1f3a:0x3ffb5b60 0x400d2a4c:0x3ffb5b80 |<-CORRUPTED"),
+            vec![0x400d1f3a, 0x3ffb5b60, 0x400d2a4c, 0x3ffb5b80]
+        );
+        assert_eq!(
+            code_addresses("MEPC    : 0x42000abc  MSTATUS : 0x00001881 len 0x12"),
+            vec![0x42000abc, 0x00001881]
+        );
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let mut decoder = TimestampDecoder::new();
+
+        let out = [decoder.decode(b"a\nb"), decoder.decode(b"c\n")].concat();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("[    0.") && lines[0].ends_with("] a"));
+        assert!(lines[1].starts_with("[    0.") && lines[1].ends_with("] bc"));
+    }
+
+    #[test]
+    fn test_replay() {
+        let recording = [
+            RECORDING_MAGIC,
+            &encode_record(Duration::from_millis(1500), b"a\nb"),
+            &encode_record(Duration::from_millis(61_020), b"c\n"),
+            &encode_record(Duration::from_millis(62_000), b"trunc")[..10],
+        ]
+        .concat();
+
+        let mut chunks = parse_recording(&recording).unwrap().into_iter();
+        let mut decoders: Vec<Box<dyn Decoder>> = vec![Box::new(TimestampDecoder::new())];
+        let mut out = Vec::new();
+
+        run(|| Ok(chunks.next()), &mut out, &mut decoders, None).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[    1.500] a\n[    1.500] bc\n"
+        );
+        assert!(parse_recording(b"[    1.500] a\n").is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Filters and triggers on the lines of the device output.
+//!
+//! A [`FilterDecoder`] highlights the matches of patterns, suppresses lines and runs the
+//! [`Action`]s of [`Trigger`]s on matching lines: ending the session with an exit code
+//! (see [`Decoder::exit_code`]), running a command or saving the last lines to a
+//! capture file. This makes a monitor session usable as a hardware-in-the-loop test,
+//! e.g. ending with success on `TEST PASSED` and with failure on a panic.
+//!
+//! The decoder works on complete lines, so a line is only passed on once it ends.
+
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use regex::Regex;
+
+use super::Decoder;
+
+/// An action of a [`Trigger`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// End the session with the exit code.
+    Exit(i32),
+    /// Run the command with the shell, with the matching line in `CARGO_PIO_MONITOR_LINE`.
+    Run(String),
+    /// Append the last lines (up to the count, including the matching line) to the file.
+    Capture(PathBuf, usize),
+}
+
+/// The actions run on every line matching a pattern.
+#[derive(Clone, Debug)]
+pub struct Trigger {
+    pub pattern: Regex,
+    pub actions: Vec<Action>,
+}
+
+impl Trigger {
+    pub fn new(pattern: &str, actions: Vec<Action>) -> Result<Self> {
+        Ok(Self {
+            pattern: compile(pattern)?,
+            actions,
+        })
+    }
+}
+
+/// The ANSI color of a highlight, one of `red`, `green`, `yellow`, `blue`, `magenta`,
+/// `cyan` and `bold`.
+pub fn color(name: &str) -> Result<&'static str> {
+    Ok(match name {
+        "red" => "\x1b[1;31m",
+        "green" => "\x1b[1;32m",
+        "yellow" => "\x1b[1;33m",
+        "blue" => "\x1b[1;34m",
+        "magenta" => "\x1b[1;35m",
+        "cyan" => "\x1b[1;36m",
+        "bold" => "\x1b[1m",
+        _ => bail!("Unknown highlight color '{}'", name),
+    })
+}
+
+const RESET: &str = "\x1b[0m";
+
+/// Highlights, suppresses and triggers on the lines of the device output.
+#[derive(Debug, Default)]
+pub struct FilterDecoder {
+    highlights: Vec<(Regex, &'static str)>,
+    suppress: Vec<Regex>,
+    triggers: Vec<Trigger>,
+    line: Vec<u8>,
+    /// The last lines, for captures.
+    history: VecDeque<String>,
+    history_len: usize,
+    elapsed: Duration,
+    exit_code: Option<i32>,
+}
+
+impl FilterDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Highlight the matches of `pattern` with the [`color`] `color`.
+    pub fn highlight(mut self, pattern: &str, color: &str) -> Result<Self> {
+        self.highlights
+            .push((compile(pattern)?, self::color(color)?));
+        Ok(self)
+    }
+
+    /// Suppress the lines matching `pattern`.
+    ///
+    /// Triggers still run on suppressed lines.
+    pub fn suppress(mut self, pattern: &str) -> Result<Self> {
+        self.suppress.push(compile(pattern)?);
+        Ok(self)
+    }
+
+    pub fn trigger(mut self, trigger: Trigger) -> Self {
+        for action in &trigger.actions {
+            if let Action::Capture(_, lines) = action {
+                self.history_len = self.history_len.max(*lines);
+            }
+        }
+
+        self.triggers.push(trigger);
+        self
+    }
+
+    /// Whether the decoder does anything.
+    pub fn is_empty(&self) -> bool {
+        self.highlights.is_empty() && self.suppress.is_empty() && self.triggers.is_empty()
+    }
+
+    fn filter_line(&mut self, line: &[u8]) -> Vec<u8> {
+        let line = String::from_utf8_lossy(line).into_owned();
+        let text = line.trim_end_matches(['\r', '\n']);
+
+        if self.history_len > 0 {
+            if self.history.len() == self.history_len {
+                self.history.pop_front();
+            }
+            self.history.push_back(text.to_owned());
+        }
+
+        for index in 0..self.triggers.len() {
+            if self.triggers[index].pattern.is_match(text) {
+                for action in self.triggers[index].actions.clone() {
+                    self.run(&action, text);
+                }
+            }
+        }
+
+        if self.suppress.iter().any(|pattern| pattern.is_match(text)) {
+            return Vec::new();
+        }
+
+        let mut highlighted = line.clone();
+        for (pattern, color) in &self.highlights {
+            highlighted = pattern
+                .replace_all(&highlighted, |captures: &regex::Captures| {
+                    format!("{}{}{}", color, &captures[0], RESET)
+                })
+                .into_owned();
+        }
+
+        highlighted.into_bytes()
+    }
+
+    fn run(&mut self, action: &Action, line: &str) {
+        match action {
+            Action::Exit(code) => {
+                info!("Matched '{}', ending the session with {}", line, code);
+
+                // The first trigger wins
+                self.exit_code.get_or_insert(*code);
+            }
+            Action::Run(command) => {
+                debug!("Running trigger command: {}", command);
+
+                let mut cmd = if cfg!(windows) {
+                    let mut cmd = Command::new("cmd");
+                    cmd.arg("/C").arg(command);
+                    cmd
+                } else {
+                    let mut cmd = Command::new("sh");
+                    cmd.arg("-c").arg(command);
+                    cmd
+                };
+
+                // Do not hold up the session, but do not leave zombies behind either
+                match cmd.env("CARGO_PIO_MONITOR_LINE", line).spawn() {
+                    Ok(mut child) => {
+                        std::thread::spawn(move || child.wait());
+                    }
+                    Err(err) => warn!("Failed to run trigger command '{}': {}", command, err),
+                }
+            }
+            Action::Capture(path, lines) => {
+                let skip = self.history.len().saturating_sub(*lines);
+                let mut capture = format!(
+                    "--- [{:>5}.{:03}] {}\n",
+                    self.elapsed.as_secs(),
+                    self.elapsed.subsec_millis(),
+                    line
+                );
+                for line in self.history.iter().skip(skip) {
+                    capture.push_str(line);
+                    capture.push('\n');
+                }
+
+                let result = path
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| OpenOptions::new().create(true).append(true).open(path))
+                    .and_then(|mut file| file.write_all(capture.as_bytes()));
+
+                match result {
+                    Ok(()) => info!("Captured the output to {}", path.display()),
+                    Err(err) => warn!(
+                        "Failed to capture the output to {}: {}",
+                        path.display(),
+                        err
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl Decoder for FilterDecoder {
+    fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            self.line.push(byte);
+
+            if byte == b'\n' {
+                let line = std::mem::take(&mut self.line);
+                out.extend(self.filter_line(&line));
+            }
+        }
+
+        out
+    }
+
+    fn set_time(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        if self.line.is_empty() {
+            return Vec::new();
+        }
+
+        let line = std::mem::take(&mut self.line);
+        self.filter_line(&line)
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).with_context(|| format!("Invalid pattern '{}'", pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let dir = std::env::temp_dir().join(format!("embuild-filter-{}", std::process::id()));
+        let capture = dir.join("crash.log");
+
+        let mut decoder = FilterDecoder::new()
+            .highlight("ERROR", "red")
+            .unwrap()
+            .suppress("^wifi:")
+            .unwrap()
+            .trigger(Trigger::new("^abort", vec![Action::Capture(capture.clone(), 2)]).unwrap())
+            .trigger(Trigger::new("TEST (PASSED|FAILED)", vec![Action::Exit(3)]).unwrap());
+
+        let out = [
+            decoder.decode(b"boot\nwifi: beacon\nan ERROR o"),
+            decoder.decode(b"ccurred\nabort()\n"),
+        ]
+        .concat();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "boot\nan \x1b[1;31mERROR\x1b[0m occurred\nabort()\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&capture).unwrap(),
+            "--- [    0.000] abort()\nan ERROR occurred\nabort()\n"
+        );
+        assert_eq!(decoder.exit_code(), None);
+
+        assert_eq!(decoder.decode(b"TEST PASS"), b"");
+        assert_eq!(decoder.finish(), b"TEST PASS");
+        assert_eq!(decoder.exit_code(), None);
+        decoder.decode(b"TEST FAILED\n");
+        assert_eq!(decoder.exit_code(), Some(3));
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(FilterDecoder::new().highlight("(", "red").is_err());
+        assert!(FilterDecoder::new().highlight("x", "purple").is_err());
+    }
+}
@@ -0,0 +1,182 @@
+//! Transports of the raw output of a device to the monitor.
+//!
+//! The built-in transports are:
+//! - [`SerialTransport`]: a serial port (or a RFC 2217 serial-over-TCP bridge), read with
+//!   `pio device monitor`
+//! - [`TcpTransport`]: a raw TCP serial bridge (`tcp://<host>:<port>`), e.g. `ser2net` or
+//!   a BLE-serial bridge exposing the device on a socket
+//! - [`WebSocketTransport`]: a WebSocket bridge (`ws://<host>:<port>/<path>`), which sends
+//!   the device output in text or binary messages
+//!
+//! Use [`for_port`] to select the transport of a port URL.
+
+#[cfg(feature = "pio")]
+use std::io;
+use std::io::Read;
+use std::net::TcpStream;
+#[cfg(feature = "pio")]
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+
+#[cfg(feature = "pio")]
+use crate::interrupt;
+#[cfg(feature = "pio")]
+use crate::pio::Pio;
+use crate::websocket;
+
+/// A connection to the output of a device.
+pub trait Transport {
+    /// Connect to the device and return a reader of its raw output, which ends when the
+    /// device disconnects.
+    fn connect(&mut self) -> Result<Box<dyn Read>>;
+}
+
+/// The transport of `port`: [`TcpTransport`] for `tcp://` and `socket://` URLs,
+/// [`WebSocketTransport`] for `ws://` URLs and [`SerialTransport`] otherwise.
+#[cfg(feature = "pio")]
+pub fn for_port(pio: &Pio, port: &str, baud_rate: u32) -> Result<Box<dyn Transport>> {
+    if let Some(address) = port
+        .strip_prefix("tcp://")
+        .or_else(|| port.strip_prefix("socket://"))
+    {
+        Ok(Box::new(TcpTransport::new(address)))
+    } else if is_websocket(port) {
+        Ok(Box::new(WebSocketTransport::new(port)?))
+    } else {
+        Ok(Box::new(SerialTransport::new(pio, port, baud_rate)))
+    }
+}
+
+/// Whether `port` is the URL of a WebSocket bridge, which PlatformIO cannot monitor.
+pub fn is_websocket(port: &str) -> bool {
+    port.starts_with("ws://") || port.starts_with("wss://")
+}
+
+/// A serial port, read with `pio device monitor --raw`.
+///
+/// The input of the monitor is passed to the device.
+#[cfg(feature = "pio")]
+pub struct SerialTransport {
+    cmd: Command,
+}
+
+#[cfg(feature = "pio")]
+impl SerialTransport {
+    pub fn new(pio: &Pio, port: &str, baud_rate: u32) -> Self {
+        let mut cmd = pio.cmd();
+        cmd.args(["device", "monitor", "--raw", "-p", port, "-b"])
+            .arg(baud_rate.to_string())
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::piped());
+
+        Self { cmd }
+    }
+}
+
+#[cfg(feature = "pio")]
+impl Transport for SerialTransport {
+    fn connect(&mut self) -> Result<Box<dyn Read>> {
+        debug!("Running PlatformIO command: {:?}", self.cmd);
+
+        let mut child = self.cmd.spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        let tracked = interrupt::track(&child);
+
+        Ok(Box::new(ChildOutput {
+            child,
+            stdout,
+            _tracked: tracked,
+        }))
+    }
+}
+
+/// The output of a child process, which is killed (if still running) and waited for
+/// once dropped.
+#[cfg(feature = "pio")]
+struct ChildOutput {
+    child: Child,
+    stdout: ChildStdout,
+    _tracked: interrupt::Tracked,
+}
+
+#[cfg(feature = "pio")]
+impl Read for ChildOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+#[cfg(feature = "pio")]
+impl Drop for ChildOutput {
+    fn drop(&mut self) {
+        // The session may end before the output does, see `Decoder::exit_code`
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A raw TCP serial bridge at `<host>:<port>`.
+pub struct TcpTransport {
+    address: String,
+}
+
+impl TcpTransport {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(&mut self) -> Result<Box<dyn Read>> {
+        let stream = TcpStream::connect(&self.address)
+            .with_context(|| anyhow!("Failed to connect to {}", self.address))?;
+
+        info!("Connected to {}", self.address);
+
+        Ok(Box::new(stream))
+    }
+}
+
+/// A WebSocket bridge at a `ws://<host>[:<port>][/<path>]` URL.
+///
+/// The payloads of all text and binary messages are the device output. Secure
+/// WebSockets (`wss://`) are not supported.
+pub struct WebSocketTransport {
+    url: websocket::Url,
+}
+
+impl WebSocketTransport {
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        Ok(Self {
+            url: websocket::Url::parse(url)?,
+        })
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn connect(&mut self) -> Result<Box<dyn Read>> {
+        let socket = websocket::connect(&self.url)?;
+
+        info!("Connected to {}", self.url.url);
+
+        Ok(Box::new(socket))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_websocket() {
+        let ws = WebSocketTransport::new("ws://bridge.local:8080/log").unwrap();
+        assert_eq!(ws.url.address, "bridge.local:8080");
+        assert_eq!(ws.url.path, "/log");
+        assert!(WebSocketTransport::new("wss://bridge").is_err());
+        assert!(WebSocketTransport::new("tcp://bridge").is_err());
+    }
+}
@@ -0,0 +1,441 @@
+//! Generation of ESP-IDF NVS (non-volatile storage) partition images.
+//!
+//! Produces the same (version 2) format as ESP-IDF's `nvs_partition_gen.py`, so that
+//! per-device data can be flashed into an NVS partition without the Python tooling.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+
+/// The size of an NVS page (a flash sector).
+pub const PAGE_SIZE: usize = 4096;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_PAGE: usize = 126;
+const FIRST_ENTRY_OFFSET: usize = 64;
+
+const PAGE_STATE_ACTIVE: u32 = 0xffff_fffe;
+const PAGE_STATE_FULL: u32 = 0xffff_fffc;
+const PAGE_VERSION_2: u8 = 0xfe;
+
+const MAX_KEY_LEN: usize = 15;
+const MAX_STRING_LEN: usize = 4000;
+const CHUNK_INDEX_ANY: u8 = 0xff;
+
+/// A value which can be stored in NVS.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    /// A string (stored with a terminating NUL).
+    String(String),
+    /// Binary data.
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// Parse `value` as a value of the NVS `encoding` used by the CSV files of
+    /// `nvs_partition_gen.py`: `u8`, `i8`, `u16`, `i16`, `u32`, `i32`, `u64`, `i64`,
+    /// `string` and `hex2bin` (or `hex`).
+    pub fn parse(encoding: &str, value: &str) -> Result<Self> {
+        fn int<T: std::str::FromStr>(value: &str) -> Result<T> {
+            match value.trim().parse() {
+                Ok(value) => Ok(value),
+                Err(_) => bail!("Invalid integer '{}'", value),
+            }
+        }
+
+        Ok(match encoding.trim() {
+            "u8" => Self::U8(int(value)?),
+            "i8" => Self::I8(int(value)?),
+            "u16" => Self::U16(int(value)?),
+            "i16" => Self::I16(int(value)?),
+            "u32" => Self::U32(int(value)?),
+            "i32" => Self::I32(int(value)?),
+            "u64" => Self::U64(int(value)?),
+            "i64" => Self::I64(int(value)?),
+            "string" => Self::String(value.to_owned()),
+            "hex2bin" | "hex" => Self::Blob(decode_hex(value)?),
+            other => bail!("Unsupported NVS encoding '{}'", other),
+        })
+    }
+
+    fn type_id(&self) -> u8 {
+        match self {
+            Self::U8(_) => 0x01,
+            Self::I8(_) => 0x11,
+            Self::U16(_) => 0x02,
+            Self::I16(_) => 0x12,
+            Self::U32(_) => 0x04,
+            Self::I32(_) => 0x14,
+            Self::U64(_) => 0x08,
+            Self::I64(_) => 0x18,
+            Self::String(_) => 0x21,
+            Self::Blob(_) => 0x42,
+        }
+    }
+
+    fn primitive_data(&self) -> Option<Vec<u8>> {
+        Some(match self {
+            Self::U8(v) => v.to_le_bytes().to_vec(),
+            Self::I8(v) => v.to_le_bytes().to_vec(),
+            Self::U16(v) => v.to_le_bytes().to_vec(),
+            Self::I16(v) => v.to_le_bytes().to_vec(),
+            Self::U32(v) => v.to_le_bytes().to_vec(),
+            Self::I32(v) => v.to_le_bytes().to_vec(),
+            Self::U64(v) => v.to_le_bytes().to_vec(),
+            Self::I64(v) => v.to_le_bytes().to_vec(),
+            _ => return None,
+        })
+    }
+}
+
+/// A builder of an NVS partition image.
+#[derive(Clone, Default, Debug)]
+pub struct Partition {
+    entries: Vec<(String, String, Value)>,
+}
+
+impl Partition {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add `value` under `key` in `namespace`.
+    pub fn add(
+        &mut self,
+        namespace: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+    ) -> &mut Self {
+        self.entries.push((namespace.into(), key.into(), value));
+        self
+    }
+
+    /// Generate the image of a partition with `size` bytes.
+    pub fn generate(&self, size: usize) -> Result<Vec<u8>> {
+        if size % PAGE_SIZE != 0 || size < 3 * PAGE_SIZE {
+            bail!(
+                "NVS partition size 0x{:x} must be a multiple of 0x{:x} and at least 0x{:x}",
+                size,
+                PAGE_SIZE,
+                3 * PAGE_SIZE
+            );
+        }
+
+        let mut writer = Writer::new();
+        let mut namespaces = BTreeMap::new();
+
+        for (namespace, key, value) in &self.entries {
+            check_key(namespace)?;
+            check_key(key)?;
+
+            let ns_index = match namespaces.get(namespace) {
+                Some(index) => *index,
+                None => {
+                    let index = namespaces.len() as u8 + 1;
+                    if index == u8::MAX {
+                        bail!("Too many NVS namespaces");
+                    }
+
+                    namespaces.insert(namespace.clone(), index);
+                    writer.write_primitive(0, namespace, &Value::U8(index));
+
+                    index
+                }
+            };
+
+            match value {
+                Value::String(s) => {
+                    let mut data = s.as_bytes().to_vec();
+                    data.push(0);
+
+                    if data.len() > MAX_STRING_LEN {
+                        bail!("String value of key '{}' is too long", key);
+                    }
+
+                    writer.write_variable(ns_index, key, value.type_id(), CHUNK_INDEX_ANY, &data);
+                }
+                Value::Blob(data) => writer.write_blob(ns_index, key, data),
+                _ => writer.write_primitive(ns_index, key, value),
+            }
+        }
+
+        let mut image = writer.finish();
+
+        // Reserve one page, NVS needs an empty page for garbage collection
+        if image.len() + PAGE_SIZE > size {
+            bail!(
+                "NVS data needs 0x{:x} bytes, which does not fit into the partition of 0x{:x} bytes",
+                image.len() + PAGE_SIZE,
+                size
+            );
+        }
+
+        image.resize(size, 0xff);
+
+        Ok(image)
+    }
+}
+
+struct Writer {
+    pages: Vec<Vec<u8>>,
+    entry: usize,
+}
+
+impl Writer {
+    fn new() -> Self {
+        let mut writer = Self {
+            pages: Vec::new(),
+            entry: 0,
+        };
+        writer.new_page();
+
+        writer
+    }
+
+    fn new_page(&mut self) {
+        if let Some(page) = self.pages.last_mut() {
+            page[..4].copy_from_slice(&PAGE_STATE_FULL.to_le_bytes());
+            update_page_crc(page);
+        }
+
+        let mut page = vec![0xff; PAGE_SIZE];
+        page[..4].copy_from_slice(&PAGE_STATE_ACTIVE.to_le_bytes());
+        page[4..8].copy_from_slice(&(self.pages.len() as u32).to_le_bytes());
+        page[8] = PAGE_VERSION_2;
+        update_page_crc(&mut page);
+
+        self.pages.push(page);
+        self.entry = 0;
+    }
+
+    fn reserve(&mut self, span: usize) {
+        if self.entry + span > ENTRIES_PER_PAGE {
+            self.new_page();
+        }
+    }
+
+    /// Write the entries `entries` (the first being the header) at the current position.
+    fn write_entries(&mut self, entries: &[[u8; ENTRY_SIZE]]) {
+        let page = self.pages.last_mut().unwrap();
+
+        for entry in entries {
+            let offset = FIRST_ENTRY_OFFSET + self.entry * ENTRY_SIZE;
+            page[offset..offset + ENTRY_SIZE].copy_from_slice(entry);
+
+            // Mark the entry as written (0b10) in the state bitmap
+            let bit = self.entry * 2;
+            page[32 + bit / 8] &= !(1 << (bit % 8));
+
+            self.entry += 1;
+        }
+    }
+
+    fn write_primitive(&mut self, ns_index: u8, key: &str, value: &Value) {
+        let data = value.primitive_data().unwrap();
+
+        let mut entry = entry_header(ns_index, value.type_id(), 1, CHUNK_INDEX_ANY, key);
+        entry[24..24 + data.len()].copy_from_slice(&data);
+        update_entry_crc(&mut entry);
+
+        self.reserve(1);
+        self.write_entries(&[entry]);
+    }
+
+    fn write_variable(
+        &mut self,
+        ns_index: u8,
+        key: &str,
+        type_id: u8,
+        chunk_index: u8,
+        data: &[u8],
+    ) {
+        let span = 1 + (data.len() + ENTRY_SIZE - 1) / ENTRY_SIZE;
+
+        let mut header = entry_header(ns_index, type_id, span as u8, chunk_index, key);
+        header[24..26].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        header[26..28].copy_from_slice(&[0xff, 0xff]);
+        header[28..32].copy_from_slice(&crc32(data).to_le_bytes());
+        update_entry_crc(&mut header);
+
+        let mut entries = vec![header];
+        entries.extend(data.chunks(ENTRY_SIZE).map(|chunk| {
+            let mut entry = [0xff; ENTRY_SIZE];
+            entry[..chunk.len()].copy_from_slice(chunk);
+            entry
+        }));
+
+        self.reserve(span);
+        self.write_entries(&entries);
+    }
+
+    fn write_blob(&mut self, ns_index: u8, key: &str, data: &[u8]) {
+        // Blobs are split into chunks filling up the remaining space of the current page,
+        // followed by an index entry referencing the chunks
+        let mut chunk_count = 0_u8;
+        let mut offset = 0;
+
+        loop {
+            if self.entry + 1 >= ENTRIES_PER_PAGE {
+                self.new_page();
+            }
+
+            let tailroom = (ENTRIES_PER_PAGE - self.entry - 1) * ENTRY_SIZE;
+            let chunk = &data[offset..data.len().min(offset + tailroom)];
+
+            self.write_variable(ns_index, key, 0x42, chunk_count, chunk);
+
+            chunk_count += 1;
+            offset += chunk.len();
+
+            if offset >= data.len() {
+                break;
+            }
+        }
+
+        let mut index = entry_header(ns_index, 0x48, 1, CHUNK_INDEX_ANY, key);
+        index[24..28].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        index[28] = chunk_count;
+        index[29] = 0;
+        update_entry_crc(&mut index);
+
+        self.reserve(1);
+        self.write_entries(&[index]);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.entry == 0 && self.pages.len() > 1 {
+            self.pages.pop();
+        }
+
+        self.pages.concat()
+    }
+}
+
+fn check_key(key: &str) -> Result<()> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        bail!(
+            "NVS key or namespace '{}' must have between 1 and {} characters",
+            key,
+            MAX_KEY_LEN
+        );
+    }
+
+    Ok(())
+}
+
+fn entry_header(
+    ns_index: u8,
+    type_id: u8,
+    span: u8,
+    chunk_index: u8,
+    key: &str,
+) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0xff; ENTRY_SIZE];
+
+    entry[0] = ns_index;
+    entry[1] = type_id;
+    entry[2] = span;
+    entry[3] = chunk_index;
+
+    let key_field = &mut entry[8..24];
+    key_field.fill(0);
+    key_field[..key.len()].copy_from_slice(key.as_bytes());
+
+    entry
+}
+
+fn update_entry_crc(entry: &mut [u8; ENTRY_SIZE]) {
+    let mut data = entry[..4].to_vec();
+    data.extend_from_slice(&entry[8..]);
+
+    entry[4..8].copy_from_slice(&crc32(&data).to_le_bytes());
+}
+
+fn update_page_crc(page: &mut [u8]) {
+    let crc = crc32(&page[4..28]);
+    page[28..32].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// The CRC32 as computed by ESP-IDF's `crc32_le(0xffffffff, ...)`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0_u32;
+
+    for &byte in data {
+        crc ^= u32::from(byte);
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim();
+
+    if s.len() % 2 != 0 {
+        bail!("Hex value '{}' has an odd number of digits", s);
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| match u8::from_str_radix(&s[i..i + 2], 16) {
+            Ok(byte) => Ok(byte),
+            Err(_) => bail!("Invalid hex value '{}'", s),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xd202d277);
+    }
+
+    #[test]
+    fn test_generate() {
+        let image = Partition::new()
+            .add("prov", "serial", Value::String("SN-0001".into()))
+            .add("prov", "id", Value::U32(42))
+            .generate(3 * PAGE_SIZE)
+            .unwrap();
+
+        assert_eq!(image.len(), 3 * PAGE_SIZE);
+        assert_eq!(&image[..4], &PAGE_STATE_ACTIVE.to_le_bytes());
+        assert_eq!(image[8], PAGE_VERSION_2);
+
+        // Namespace, string header + 1 data entry, u32: 4 entries written
+        assert_eq!(&image[32..34], &[0b1010_1010, 0xff]);
+
+        let entry = |index: usize| &image[FIRST_ENTRY_OFFSET + index * ENTRY_SIZE..][..ENTRY_SIZE];
+
+        assert_eq!(&entry(0)[..4], &[0, 0x01, 1, 0xff]);
+        assert_eq!(&entry(0)[8..13], b"prov\0");
+        assert_eq!(entry(0)[24], 1);
+
+        assert_eq!(&entry(1)[..4], &[1, 0x21, 2, 0xff]);
+        assert_eq!(&entry(1)[24..26], &8_u16.to_le_bytes());
+        assert_eq!(&entry(2)[..8], b"SN-0001\0");
+
+        assert_eq!(&entry(3)[..4], &[1, 0x04, 1, 0xff]);
+        assert_eq!(&entry(3)[24..32], &[42, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+
+        assert!(image[PAGE_SIZE..].iter().all(|b| *b == 0xff));
+    }
+}
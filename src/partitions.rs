@@ -0,0 +1,235 @@
+//! ESP-IDF partition tables.
+
+/// The `type` of application partitions.
+pub const TYPE_APP: u8 = 0x00;
+/// The `type` of data partitions.
+pub const TYPE_DATA: u8 = 0x01;
+
+/// The `subtype` of the NVS data partition.
+pub const SUBTYPE_NVS: u8 = 0x02;
+/// The `subtype` of the core dump data partition.
+pub const SUBTYPE_COREDUMP: u8 = 0x03;
+/// The `subtype` of FAT filesystem data partitions.
+pub const SUBTYPE_FAT: u8 = 0x81;
+/// The `subtype` of SPIFFS (and, with PlatformIO, LittleFS) filesystem data partitions.
+pub const SUBTYPE_SPIFFS: u8 = 0x82;
+/// The `subtype` of LittleFS filesystem data partitions.
+pub const SUBTYPE_LITTLEFS: u8 = 0x83;
+
+/// The offset of the first partition, following the partition table at `0x8000`.
+const FIRST_OFFSET: u32 = 0x9000;
+
+const MAGIC: [u8; 2] = [0xaa, 0x50];
+const ENTRY_SIZE: usize = 32;
+
+/// An entry of an ESP-IDF (binary) partition table.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Partition {
+    pub label: String,
+    pub kind: u8,
+    pub subtype: u8,
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Partition {
+    /// Parse the entries of the binary partition table `data` (e.g. the
+    /// `partitions.bin` file of a build).
+    pub fn parse_table(data: &[u8]) -> Vec<Partition> {
+        data.chunks_exact(ENTRY_SIZE)
+            .take_while(|entry| entry[..2] == MAGIC)
+            .map(|entry| Partition {
+                kind: entry[2],
+                subtype: entry[3],
+                offset: u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+                size: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+                label: String::from_utf8_lossy(&entry[12..28])
+                    .trim_end_matches('\0')
+                    .to_owned(),
+            })
+            .collect()
+    }
+
+    /// The type and subtype of the partition as in partition table CSV files, e.g.
+    /// `app/ota_0` or `data/nvs`.
+    pub fn type_name(&self) -> String {
+        let subtype = match (self.kind, self.subtype) {
+            (TYPE_APP, 0x00) => "factory".to_owned(),
+            (TYPE_APP, subtype @ 0x10..=0x1f) => format!("ota_{}", subtype - 0x10),
+            (TYPE_APP, 0x20) => "test".to_owned(),
+            (TYPE_DATA, 0x00) => "ota".to_owned(),
+            (TYPE_DATA, 0x01) => "phy".to_owned(),
+            (TYPE_DATA, SUBTYPE_NVS) => "nvs".to_owned(),
+            (TYPE_DATA, SUBTYPE_COREDUMP) => "coredump".to_owned(),
+            (TYPE_DATA, 0x04) => "nvs_keys".to_owned(),
+            (TYPE_DATA, 0x05) => "efuse".to_owned(),
+            (TYPE_DATA, SUBTYPE_FAT) => "fat".to_owned(),
+            (TYPE_DATA, SUBTYPE_SPIFFS) => "spiffs".to_owned(),
+            (TYPE_DATA, SUBTYPE_LITTLEFS) => "littlefs".to_owned(),
+            (_, subtype) => format!("0x{:02x}", subtype),
+        };
+
+        match self.kind {
+            TYPE_APP => format!("app/{}", subtype),
+            TYPE_DATA => format!("data/{}", subtype),
+            kind => format!("0x{:02x}/{}", kind, subtype),
+        }
+    }
+
+    /// Parse the entries of the partition table CSV `csv` (e.g. the `partitions.csv` of
+    /// `board_build.partitions`), with the offsets left out computed like ESP-IDF does:
+    /// following the previous partition, aligned to 64 KiB for app partitions and 4 KiB
+    /// for the others.
+    pub fn parse_csv(csv: &str) -> anyhow::Result<Vec<Partition>> {
+        let mut partitions = Vec::new();
+        let mut next_offset = FIRST_OFFSET;
+
+        for (index, line) in csv.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+            let invalid = || anyhow::anyhow!("Invalid partition on line {}: '{}'", index + 1, line);
+
+            if fields.len() < 5 {
+                return Err(invalid());
+            }
+
+            let kind = match fields[1] {
+                "app" => TYPE_APP,
+                "data" => TYPE_DATA,
+                kind => parse_number(kind).ok_or_else(invalid)? as u8,
+            };
+            let subtype = parse_subtype(kind, fields[2]).ok_or_else(invalid)?;
+
+            let alignment = if kind == TYPE_APP { 0x10000 } else { 0x1000 };
+            let offset = match fields[3] {
+                "" => (next_offset + alignment - 1) / alignment * alignment,
+                offset => parse_number(offset).ok_or_else(invalid)?,
+            };
+            let size = parse_number(fields[4]).ok_or_else(invalid)?;
+
+            next_offset = offset + size;
+
+            partitions.push(Partition {
+                label: fields[0].to_owned(),
+                kind,
+                subtype,
+                offset,
+                size,
+            });
+        }
+
+        Ok(partitions)
+    }
+
+    /// Find the partition labeled `label` in the binary partition table `data`.
+    pub fn find_by_label(data: &[u8], label: impl AsRef<str>) -> Option<Partition> {
+        Self::parse_table(data)
+            .into_iter()
+            .find(|p| p.label == label.as_ref())
+    }
+}
+
+/// Parse the subtype `subtype` of partitions of the type `kind`, by name or number.
+fn parse_subtype(kind: u8, subtype: &str) -> Option<u8> {
+    Some(match (kind, subtype) {
+        (TYPE_APP, "factory") => 0x00,
+        (TYPE_APP, "test") => 0x20,
+        (TYPE_APP, ota) if ota.starts_with("ota_") => 0x10 + ota[4..].parse::<u8>().ok()?,
+        (TYPE_DATA, "ota") => 0x00,
+        (TYPE_DATA, "phy") => 0x01,
+        (TYPE_DATA, "nvs") => SUBTYPE_NVS,
+        (TYPE_DATA, "coredump") => SUBTYPE_COREDUMP,
+        (TYPE_DATA, "nvs_keys") => 0x04,
+        (TYPE_DATA, "efuse") => 0x05,
+        (TYPE_DATA, "fat") => SUBTYPE_FAT,
+        (TYPE_DATA, "spiffs") => SUBTYPE_SPIFFS,
+        (TYPE_DATA, "littlefs") => SUBTYPE_LITTLEFS,
+        (_, "") => 0x00,
+        (_, subtype) => parse_number(subtype)? as u8,
+    })
+}
+
+/// Parse a number of a partition table CSV, decimal or hex, with an optional `K` or `M`
+/// suffix.
+fn parse_number(number: &str) -> Option<u32> {
+    let (number, factor) = match number.char_indices().last()? {
+        (index, 'K' | 'k') => (&number[..index], 1024),
+        (index, 'M' | 'm') => (&number[..index], 1024 * 1024),
+        _ => (number, 1),
+    };
+
+    let number = match number
+        .strip_prefix("0x")
+        .or_else(|| number.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => number.parse().ok()?,
+    };
+
+    number.checked_mul(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_table() {
+        let mut table = Vec::new();
+
+        for (label, kind, subtype, offset, size) in [
+            ("nvs", TYPE_DATA, SUBTYPE_NVS, 0x9000_u32, 0x6000_u32),
+            ("factory", TYPE_APP, 0, 0x10000, 0x100000),
+            ("coredump", TYPE_DATA, SUBTYPE_COREDUMP, 0x110000, 0x10000),
+        ] {
+            table.extend_from_slice(&MAGIC);
+            table.extend_from_slice(&[kind, subtype]);
+            table.extend_from_slice(&offset.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+
+            let mut name = [0_u8; 16];
+            name[..label.len()].copy_from_slice(label.as_bytes());
+            table.extend_from_slice(&name);
+            table.extend_from_slice(&0_u32.to_le_bytes());
+        }
+        table.extend_from_slice(&[0xff; ENTRY_SIZE]);
+
+        assert_eq!(Partition::parse_table(&table).len(), 3);
+        assert_eq!(
+            Partition::find_by_label(&table, "coredump"),
+            Some(Partition {
+                label: "coredump".into(),
+                kind: TYPE_DATA,
+                subtype: SUBTYPE_COREDUMP,
+                offset: 0x110000,
+                size: 0x10000,
+            })
+        );
+
+        let csv = "# Name, Type, SubType, Offset, Size, Flags\n\
+                   nvs, data, nvs, , 0x6000,\n\
+                   phy_init, data, phy, , 4K,\n\
+                   factory, app, factory, , 1M,\n\
+                   ota_0, app, ota_0, 0x200000, 1M,\n\
+                   storage, data, spiffs, , 0x10000, # the file system\n";
+        let partitions = Partition::parse_csv(csv).unwrap();
+        assert_eq!(
+            partitions
+                .iter()
+                .map(|p| (p.label.as_str(), p.type_name(), p.offset, p.size))
+                .collect::<Vec<_>>(),
+            [
+                ("nvs", "data/nvs".to_owned(), 0x9000, 0x6000),
+                ("phy_init", "data/phy".to_owned(), 0xf000, 0x1000),
+                ("factory", "app/factory".to_owned(), 0x10000, 0x100000),
+                ("ota_0", "app/ota_0".to_owned(), 0x200000, 0x100000),
+                ("storage", "data/spiffs".to_owned(), 0x300000, 0x10000),
+            ]
+        );
+        assert!(Partition::parse_csv("nvs, data, nvs").is_err());
+    }
+}
@@ -1,22 +1,89 @@
 //! Platformio installation and manipulation support.
 
+pub mod abi;
+pub mod account;
+pub mod artifacts;
+pub mod assets;
+pub mod board;
+pub mod browse;
+pub mod bump;
+pub mod cargo_config;
+pub mod changelog;
+pub mod ci;
+pub mod compat;
+pub mod compiler_cache;
+pub mod complete;
+pub mod components;
+pub mod config;
+pub mod container;
+pub mod credentials;
+#[cfg(unix)]
+pub mod daemon;
+pub mod detect;
+pub mod device_registry;
+pub mod efuse;
+pub mod export;
+pub mod external;
+pub mod fingerprint;
+pub mod graph;
+#[cfg(feature = "elf")]
+pub mod harness;
+pub mod hash;
+pub mod home;
+pub mod idf_registry;
+pub mod images;
+pub mod inspect;
+pub mod ldscript;
+pub mod licenses;
+pub mod lint;
+pub mod lock;
+pub mod managed;
+pub mod matrix;
+pub mod mcuboot;
+pub mod metrics;
+pub mod notify;
+pub mod ota;
+pub mod postprocess;
+pub mod probe;
 pub mod project;
+pub mod provision;
+pub mod release;
+pub mod remote;
+pub mod report;
+pub mod reset;
+pub mod retention;
+pub mod runtime;
+pub mod sdk;
+pub mod setup;
+pub mod stamp;
+pub mod tasks;
+pub mod tree_digest;
+#[cfg(feature = "pio-archive")]
+pub mod unpack;
 
 use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output, Stdio};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fmt, thread};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use log::*;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tempfile::*;
 
+use crate::error::HintExt;
+use crate::interrupt;
+use crate::messages::Message;
+use crate::pio_model::compare_versions;
 use crate::python::{check_python_at_least, PYTHON};
+use crate::terminal;
 use crate::utils;
 
 const INSTALLER_URL: &str = "https://raw.githubusercontent.com/platformio/platformio-core-installer/master/get-platformio.py";
@@ -123,6 +190,138 @@ pub struct BoardDebug {
     pub tools: HashMap<String, HashMap<String, bool>>,
 }
 
+/// The URL of the serial port `port` as understood by PlatformIO and `esptool.py`
+/// (i.e. pyserial).
+///
+/// Besides local ports, devices attached to another machine can be reached through a
+/// serial-over-TCP bridge, either as `rfc2217://<host>:<port>` (RFC 2217, supporting
+/// baud rate changes and the DTR/RTS lines used to reset the device) or as
+/// `tcp://<host>:<port>` (a raw TCP socket, e.g. `ser2net` in raw mode).
+pub fn serial_port_url(port: &str) -> String {
+    match port.strip_prefix("tcp://") {
+        Some(address) => format!("socket://{}", address),
+        None => port.to_owned(),
+    }
+}
+
+/// Whether `port` is a raw TCP serial bridge (see [`serial_port_url`]), which cannot
+/// reset the device into its bootloader.
+pub fn is_raw_tcp_port(port: &str) -> bool {
+    port.starts_with("tcp://") || port.starts_with("socket://")
+}
+
+/// Append `flags` to the `PLATFORMIO_BUILD_FLAGS` of the `pio run` command `cmd`, on top
+/// of the ones already set on `cmd` or inherited from the environment.
+pub fn append_build_flags(cmd: &mut Command, flags: impl IntoIterator<Item = impl AsRef<str>>) {
+    const VAR_BUILD_FLAGS: &str = "PLATFORMIO_BUILD_FLAGS";
+
+    let mut build_flags = cmd
+        .get_envs()
+        .find(|(name, _)| *name == VAR_BUILD_FLAGS)
+        .map(|(_, value)| value.map(|value| value.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| std::env::var(VAR_BUILD_FLAGS).ok())
+        .unwrap_or_default();
+
+    for flag in flags {
+        if !build_flags.is_empty() {
+            build_flags.push(' ');
+        }
+
+        build_flags.push_str(flag.as_ref());
+    }
+
+    cmd.env(VAR_BUILD_FLAGS, build_flags);
+}
+
+/// Extract the downloaded `archive` into `dir`, for the installs of SDKs and components.
+#[cfg(feature = "pio-archive")]
+fn extract(archive: &Path, dir: &Path) -> Result<()> {
+    unpack::extract(archive, dir, 0, &mut |_, _| ())
+}
+
+#[cfg(not(feature = "pio-archive"))]
+fn extract(_archive: &Path, _dir: &Path) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "Extracting archives needs the `pio-archive` feature of embuild"
+    ))
+    .hint("Enable the `pio-archive` feature of embuild")
+}
+
+/// The longest PlatformIO core directory which still keeps the deepest files of the
+/// toolchain packages (e.g. the C++ headers in the sysroot of the Xtensa GCC, about 190
+/// characters below the core directory) within the `MAX_PATH` limit of 260 characters,
+/// which GCC does not lift even with long paths enabled in Windows.
+const MAX_CORE_DIR_LEN: usize = 60;
+
+/// Warn if the PlatformIO core directory `core_dir` is too long for the toolchains to
+/// work on Windows.
+fn check_core_dir_length(core_dir: &Path) {
+    let len = core_dir.as_os_str().len();
+
+    if len > MAX_CORE_DIR_LEN {
+        warn!(
+            "The PlatformIO directory {} is {} characters long, toolchains installed into it \
+             are likely to exceed the Windows path length limit of 260 characters and fail \
+             with 'No such file or directory' errors. Install PlatformIO into a directory of \
+             at most {} characters instead, e.g. C:\\pio",
+            core_dir.display(),
+            len,
+            MAX_CORE_DIR_LEN
+        );
+    }
+}
+
+/// A serial device, to be parsed from `platformio device list --serial --json-output`.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct SerialDevice {
+    pub port: String,
+    #[serde(default)]
+    pub description: String,
+    /// Hardware id, e.g. `USB VID:PID=10C4:EA60 SER=0001 LOCATION=1-1`
+    #[serde(default)]
+    pub hwid: String,
+}
+
+impl SerialDevice {
+    /// The USB vendor and product id of the device, if it is a USB device.
+    pub fn vid_pid(&self) -> Option<(u16, u16)> {
+        let vid_pid = self.hwid_field("VID:PID")?;
+        let (vid, pid) = vid_pid.split_once(':')?;
+
+        Some((
+            u16::from_str_radix(vid, 16).ok()?,
+            u16::from_str_radix(pid, 16).ok()?,
+        ))
+    }
+
+    /// The USB serial number of the device, if known.
+    pub fn serial_number(&self) -> Option<&str> {
+        self.hwid_field("SER")
+    }
+
+    /// Whether the device matches `pattern`, which is a `<vid>:<pid>` pair of hex
+    /// numbers, where either may be `*`.
+    pub fn matches(&self, pattern: impl AsRef<str>) -> bool {
+        let ((vid, pid), (vid_pattern, pid_pattern)) =
+            match (self.vid_pid(), pattern.as_ref().split_once(':')) {
+                (Some(vid_pid), Some(pattern)) => (vid_pid, pattern),
+                _ => return false,
+            };
+
+        let matches = |value: u16, pattern: &str| {
+            pattern == "*" || u16::from_str_radix(pattern, 16).ok() == Some(value)
+        };
+
+        matches(vid, vid_pattern) && matches(pid, pid_pattern)
+    }
+
+    fn hwid_field(&self, name: &str) -> Option<&str> {
+        self.hwid
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct PioInstallerInfo {
     pub is_develop_core: bool,
@@ -171,14 +370,58 @@ pub struct Pio {
 
     #[serde(default)]
     pub log_level: LogLevel,
+
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+}
+
+/// The error of a PlatformIO command which did not finish within its
+/// [timeout](Pio::timeout) and was killed.
+#[derive(Debug)]
+pub struct Timeout {
+    /// The command.
+    pub command: String,
+    pub timeout: Duration,
+    /// The output of the command until it was killed, empty if it was not captured.
+    pub output: String,
+}
+
+impl Timeout {
+    /// The number of lines of the output shown with the error.
+    const TAIL_LINES: usize = 20;
 }
 
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PlatformIO command timed out after {}s and was killed: {}",
+            self.timeout.as_secs(),
+            self.command
+        )?;
+
+        let lines = self.output.lines().collect::<Vec<_>>();
+        if !lines.is_empty() {
+            write!(f, "\nLast output:")?;
+
+            for line in &lines[lines.len().saturating_sub(Self::TAIL_LINES)..] {
+                write!(f, "\n  {}", line)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Timeout {}
+
 impl From<PioInstallerInfo> for Pio {
     fn from(pi: PioInstallerInfo) -> Self {
         Self {
             platformio_exe: pi.platformio_exe,
             core_dir: pi.core_dir,
             log_level: LogLevel::Standard,
+            timeout: None,
         }
     }
 }
@@ -189,6 +432,7 @@ impl From<PioInfo> for Pio {
             platformio_exe: pi.platformio_exe.value,
             core_dir: pi.core_dir.value,
             log_level: LogLevel::Standard,
+            timeout: None,
         }
     }
 }
@@ -219,7 +463,13 @@ impl Pio {
             pio_installer.pio(&pio_dir);
         }
 
-        pio_installer.update().map(Into::into)
+        let pio = pio_installer.update()?;
+
+        if cfg!(windows) {
+            check_core_dir_length(&pio.core_dir);
+        }
+
+        Ok(pio)
     }
 
     pub fn install_default() -> Result<Self> {
@@ -277,6 +527,15 @@ impl Pio {
         self
     }
 
+    /// Kill the commands run with [`Pio::exec`] and [`Pio::exec_capture`] which do not
+    /// finish within `timeout`, failing them with a [`Timeout`] error.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+
+        self
+    }
+
     pub fn check(output: &Output) -> Result<()> {
         if !output.status.success() {
             bail!(
@@ -339,6 +598,15 @@ impl Pio {
     }
 
     pub fn exec(&self, cmd: &mut Command) -> Result<()> {
+        // The daemon cannot kill the commands it runs
+        #[cfg(unix)]
+        if self.timeout.is_none() {
+            if let Some(output) = daemon::run(cmd, self.log_level != LogLevel::Quiet) {
+                output?;
+                return Ok(());
+            }
+        }
+
         debug!("Running PlatformIO command: {:?}", cmd);
 
         if self.log_level == LogLevel::Quiet {
@@ -348,16 +616,148 @@ impl Pio {
             cmd.stdout(Stdio::null());
         }
 
-        cmd.status()?;
+        if self.timeout.is_none() {
+            interrupt::status(cmd)?;
+
+            return Ok(());
+        }
+
+        let mut child = cmd.spawn()?;
+
+        let status = {
+            let _tracked = interrupt::track(&child);
+            interrupt::wait_timeout(&mut child, self.timeout)?
+        };
+
+        interrupt::check()?;
+
+        if status.is_none() {
+            return Err(self.timed_out(cmd, String::new()));
+        }
 
         Ok(())
     }
 
+    /// Execute `cmd` like [`Pio::exec`], but also capture its output.
+    ///
+    /// The output is still forwarded to stdout and stderr (unless the log level is
+    /// [`LogLevel::Quiet`]). Returns the exit status and the captured stdout and stderr
+    /// output.
+    ///
+    /// The [`Timeout`] error of a command which timed out carries its output until then.
+    pub fn exec_capture(&self, cmd: &mut Command) -> Result<(ExitStatus, String)> {
+        #[cfg(unix)]
+        if self.timeout.is_none() {
+            if let Some(output) = daemon::run(cmd, self.log_level != LogLevel::Quiet) {
+                let mut output = output?;
+                output.stdout.extend(output.stderr);
+
+                return Ok((
+                    output.status,
+                    String::from_utf8_lossy(&output.stdout).into_owned(),
+                ));
+            }
+        }
+
+        debug!("Running PlatformIO command: {:?}", cmd);
+
+        // The output is piped, but still ends up in the terminal
+        terminal::apply_size(cmd);
+
+        let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+        let quiet = self.log_level == LogLevel::Quiet;
+        let stdout_captured = Arc::new(Mutex::new(Vec::new()));
+        let stderr_captured = Arc::new(Mutex::new(Vec::new()));
+        let stdout = Self::tee(
+            child.stdout.take().unwrap(),
+            io::stdout(),
+            quiet,
+            stdout_captured.clone(),
+        );
+        let stderr = Self::tee(
+            child.stderr.take().unwrap(),
+            io::stderr(),
+            quiet,
+            stderr_captured.clone(),
+        );
+
+        let status = {
+            let _tracked = interrupt::track(&child);
+            interrupt::wait_timeout(&mut child, self.timeout)?
+        };
+
+        // The processes started by a killed command may still hold its output open, so it
+        // is not waited for
+        if status.is_some() {
+            stdout.join().ok();
+            stderr.join().ok();
+        }
+
+        let mut output = stdout_captured.lock().unwrap().clone();
+        output.extend(stderr_captured.lock().unwrap().iter());
+        let output = String::from_utf8_lossy(&output).into_owned();
+
+        interrupt::check()?;
+
+        match status {
+            Some(status) => Ok((status, output)),
+            None => Err(self.timed_out(cmd, output)),
+        }
+    }
+
+    fn tee(
+        mut reader: impl Read + Send + 'static,
+        mut writer: impl Write + Send + 'static,
+        quiet: bool,
+        captured: Arc<Mutex<Vec<u8>>>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buf = [0_u8; 4096];
+
+            while let Ok(len) = reader.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+
+                if !quiet {
+                    writer.write_all(&buf[..len]).ok();
+                    writer.flush().ok();
+                }
+
+                captured.lock().unwrap().extend_from_slice(&buf[..len]);
+            }
+        })
+    }
+
+    fn timed_out(&self, cmd: &Command, output: String) -> anyhow::Error {
+        Timeout {
+            command: format!("{:?}", cmd),
+            timeout: self.timeout.unwrap_or_default(),
+            output,
+        }
+        .into()
+    }
+
     pub fn json<T: DeserializeOwned>(cmd: &mut Command) -> Result<T> {
         cmd.arg("--json-output");
-        debug!("Running PlatformIO command {:?}", cmd);
 
-        let output = cmd.output()?;
+        #[cfg(unix)]
+        let output = daemon::run(cmd, false);
+        #[cfg(not(unix))]
+        let output = None;
+
+        let output = match output {
+            Some(output) => output?,
+            None => {
+                if let Some(json) = home::json(cmd) {
+                    return Ok(serde_json::from_value::<T>(json?)?);
+                }
+
+                debug!("Running PlatformIO command {:?}", cmd);
+                interrupt::output(cmd)?
+            }
+        };
 
         Self::check(&output)?;
 
@@ -462,6 +862,100 @@ impl Pio {
             result
         }
     }
+
+    pub fn serial_devices(&self) -> Result<Vec<SerialDevice>> {
+        let mut cmd = self.cmd();
+
+        cmd.arg("device").arg("list").arg("--serial");
+
+        let devices = Self::json::<Vec<SerialDevice>>(&mut cmd)?;
+        complete::cache_ports(self, &devices);
+
+        Ok(devices)
+    }
+
+    /// The installed versions of the package `name`, with the primary installation
+    /// (`packages/<name>`) first and the additional ones PlatformIO installs side by side
+    /// (`packages/<name>@<version>`) in descending version order.
+    pub fn installed_packages(&self, name: &str) -> Vec<InstalledPackage> {
+        let entries = match fs::read_dir(self.core_dir.join("packages")) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut packages = entries
+            .flatten()
+            .filter(|entry| {
+                let file_name = entry.file_name();
+                let file_name = file_name.to_string_lossy();
+
+                file_name == name
+                    || file_name
+                        .strip_prefix(name)
+                        .map_or(false, |suffix| suffix.starts_with('@'))
+            })
+            .map(|entry| InstalledPackage::new(entry.path()))
+            .collect::<Vec<_>>();
+
+        packages.sort_by(|a, b| {
+            b.is_primary()
+                .cmp(&a.is_primary())
+                .then_with(|| compare_versions(&b.version, &a.version))
+        });
+
+        packages
+    }
+
+    /// The active installation of the package `name`: the one whose version equals the
+    /// exact pin `requirement` (if any), else the primary installation, else the highest
+    /// version.
+    pub fn package(&self, name: &str, requirement: Option<&str>) -> Option<InstalledPackage> {
+        let mut packages = self.installed_packages(name);
+
+        let pinned = requirement
+            .map(|requirement| requirement.trim().trim_start_matches('='))
+            .filter(|requirement| requirement.starts_with(|c: char| c.is_ascii_digit()))
+            .and_then(|requirement| {
+                packages
+                    .iter()
+                    .position(|package| package.version == requirement)
+            });
+
+        match pinned {
+            Some(index) => Some(packages.swap_remove(index)),
+            None if packages.is_empty() => None,
+            None => Some(packages.remove(0)),
+        }
+    }
+}
+
+/// An installed PlatformIO package.
+#[derive(Clone, Debug)]
+pub struct InstalledPackage {
+    pub dir: PathBuf,
+    /// The version from the `package.json` manifest, empty if unknown.
+    pub version: String,
+}
+
+impl InstalledPackage {
+    fn new(dir: PathBuf) -> Self {
+        let version = fs::read_to_string(dir.join("package.json"))
+            .ok()
+            .and_then(|manifest| serde_json::from_str::<serde_json::Value>(&manifest).ok())
+            .and_then(|manifest| manifest["version"].as_str().map(str::to_owned))
+            .unwrap_or_default();
+
+        Self { dir, version }
+    }
+
+    /// Whether this is the primary installation of the package, i.e. not one installed
+    /// side by side as `<name>@<version>`.
+    pub fn is_primary(&self) -> bool {
+        !self
+            .dir
+            .file_name()
+            .map_or(false, |name| name.to_string_lossy().contains('@'))
+    }
 }
 
 #[derive(Debug)]
@@ -474,11 +968,30 @@ pub struct PioInstaller {
 
 impl PioInstaller {
     pub fn new() -> Result<Self> {
-        Self::create(false)
+        Self::create(None)
     }
 
+    /// An installer downloading the latest `get-platformio.py` with ureq.
+    ///
+    /// Fails without the `ureq` feature, use [`PioInstaller::new_download_with`] then.
     pub fn new_download() -> Result<Self> {
-        Self::create(true)
+        #[cfg(feature = "ureq")]
+        {
+            Self::new_download_with(&utils::UreqClient)
+        }
+
+        #[cfg(not(feature = "ureq"))]
+        {
+            Err(anyhow::anyhow!(
+                "Downloading the PlatformIO installer needs an HTTP client"
+            ))
+            .hint("Enable the `ureq` feature of embuild or use `PioInstaller::new_download_with`")
+        }
+    }
+
+    /// An installer downloading the latest `get-platformio.py` with `client`.
+    pub fn new_download_with(client: &dyn utils::HttpClient) -> Result<Self> {
+        Self::create(Some(client))
     }
 
     pub fn new_location(installer_location: impl Into<PathBuf>) -> Result<Self> {
@@ -498,14 +1011,14 @@ impl PioInstaller {
         self
     }
 
-    fn create(download: bool) -> Result<Self> {
+    fn create(download: Option<&dyn utils::HttpClient>) -> Result<Self> {
         check_python_at_least(3, 6)?;
 
         let mut file = NamedTempFile::new()?;
-        if download {
+        if let Some(client) = download {
             debug!("Downloading get-platformio.py from {}", INSTALLER_URL);
 
-            utils::download_file_to(INSTALLER_URL, &mut file)?;
+            client.download(INSTALLER_URL, &mut file)?;
         } else {
             debug!("Using built-in get-platformio.py");
 
@@ -555,7 +1068,7 @@ impl PioInstaller {
             cmd.stderr(Stdio::null());
         }
 
-        cmd.status()?;
+        interrupt::status(&mut cmd)?;
 
         Ok(())
     }
@@ -575,9 +1088,11 @@ impl PioInstaller {
             cmd.stderr(Stdio::null());
         }
 
-        cmd.status()?;
+        interrupt::status(&mut cmd)?;
 
-        Ok(serde_json::from_reader::<File, PioInstallerInfo>(file)?)
+        serde_json::from_reader::<File, PioInstallerInfo>(file)
+            .hint(Message::new("pio-not-installed"))
+            .with_context(|| Message::new("pio-check-failed"))
     }
 
     fn command(&self) -> Command {
@@ -1259,3 +1774,33 @@ impl Resolver {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serial_device() {
+        let device = SerialDevice {
+            port: "/dev/ttyUSB0".into(),
+            description: "CP2102".into(),
+            hwid: "USB VID:PID=10C4:EA60 SER=0001 LOCATION=1-1".into(),
+        };
+
+        assert_eq!(device.vid_pid(), Some((0x10c4, 0xea60)));
+        assert_eq!(device.serial_number(), Some("0001"));
+        assert!(device.matches("10c4:ea60"));
+        assert!(device.matches("10C4:*"));
+        assert!(device.matches("*:ea60"));
+        assert!(!device.matches("ea60:10c4"));
+        assert!(!device.matches("10c4"));
+
+        let uart = SerialDevice {
+            port: "/dev/ttyS0".into(),
+            description: "ttyS0".into(),
+            hwid: "PNP0501".into(),
+        };
+        assert_eq!(uart.vid_pid(), None);
+        assert!(!uart.matches("*:*"));
+    }
+}
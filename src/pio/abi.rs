@@ -0,0 +1,436 @@
+//! Detection of ABI mismatches between the Rust and the C/C++ code of PIO->Cargo
+//! projects.
+//!
+//! The Rust library is compiled for the `rust_target` of the environment, the C/C++
+//! code for whatever the platform and framework configure; the linker happily combines
+//! the two even if they disagree on how floating point values are passed or on the
+//! instructions the CPU implements. Such firmware works until the first call passing a
+//! float between Rust and C returns garbage, or until Rust code executes an instruction
+//! the CPU does not have.
+//!
+//! The preflight compares the Rust target against the compiler flags of the
+//! environment, as reported by `pio project metadata`:
+//! - ARM: the float ABI (`eabihf` vs. `-mfloat-abi`) and the architecture (e.g.
+//!   `thumbv7em` vs. `-mcpu=cortex-m0plus`);
+//! - RISC-V: the extensions (e.g. `riscv32imac` vs. `-march=rv32imc`) and the ABI (e.g.
+//!   `riscv32imafc` vs. `-mabi=ilp32`).
+//!
+//! Other architectures (e.g. Xtensa) have a single ABI per target and are not checked.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde_json::Value as JsonValue;
+
+use super::Pio;
+use crate::error::HintExt;
+use crate::pio_model::env_option;
+
+/// The file the key of the last successful check is stored in, in the build directory
+/// of the environment.
+pub const CHECKED_FILE: &str = "abi.checked";
+
+/// The ARM M-profile architectures, in the naming of the Rust targets.
+const ARM_M_ARCHS: &[&str] = &["v6m", "v7m", "v7em", "v8m.base", "v8m.main"];
+
+/// The ABI relevant properties of code compiled for a target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Abi {
+    Arm {
+        /// The architecture in the naming of the Rust targets (e.g. `v7em`), if known.
+        arch: Option<String>,
+        /// Whether floating point values are passed in FPU registers.
+        hard_float: bool,
+    },
+    RiscV {
+        xlen: u32,
+        /// The single letter extensions, with `g` expanded.
+        extensions: BTreeSet<char>,
+        /// The calling convention (e.g. `ilp32f`), if known.
+        abi: Option<String>,
+    },
+}
+
+impl Abi {
+    /// The ABI of the Rust `target`, if it is an ARM or RISC-V target.
+    pub fn from_target(target: &str) -> Option<Self> {
+        let arch = target.split('-').next().unwrap_or_default();
+
+        if let Some(arch) = arch
+            .strip_prefix("thumb")
+            .or_else(|| arch.strip_prefix("arm"))
+        {
+            Some(Self::Arm {
+                arch: Some(arch.to_owned()),
+                hard_float: target.ends_with("eabihf"),
+            })
+        } else if let Some((xlen, extensions)) = riscv_arch(arch.strip_prefix("riscv")?) {
+            let abi = rust_riscv_abi(xlen, &extensions);
+
+            Some(Self::RiscV {
+                xlen,
+                extensions,
+                abi: Some(abi),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The ABI of code compiled with the compiler `flags`, for the architecture family
+    /// of `self`.
+    ///
+    /// Later flags override earlier ones, as with GCC.
+    pub fn compiled_with(&self, flags: &[impl AsRef<str>]) -> Self {
+        let flag = |name: &str| {
+            flags
+                .iter()
+                .rev()
+                .find_map(|flag| flag.as_ref().strip_prefix(name))
+        };
+
+        match self {
+            Self::Arm { .. } => Self::Arm {
+                // `-march` takes precedence over the architecture of `-mcpu`
+                arch: flag("-march=")
+                    .and_then(arm_arch)
+                    .or_else(|| flag("-mcpu=").and_then(arm_cpu_arch))
+                    .map(str::to_owned),
+                // The default of the arm-none-eabi toolchains is the soft-float ABI
+                hard_float: flag("-mfloat-abi=") == Some("hard"),
+            },
+            Self::RiscV {
+                xlen, extensions, ..
+            } => {
+                let (xlen, extensions) = flag("-march=")
+                    .and_then(|march| riscv_arch(march.strip_prefix("rv")?))
+                    .unwrap_or_else(|| (*xlen, extensions.clone()));
+
+                Self::RiscV {
+                    xlen,
+                    extensions,
+                    abi: flag("-mabi=").map(str::to_owned),
+                }
+            }
+        }
+    }
+}
+
+/// Check that the Rust `target` is ABI compatible with the C/C++ code compiled with the
+/// compiler `flags`.
+pub fn check(target: &str, flags: &[impl AsRef<str>]) -> Result<()> {
+    let rust = match Abi::from_target(target) {
+        Some(rust) => rust,
+        None => return Ok(()),
+    };
+
+    match (&rust, rust.compiled_with(flags)) {
+        (
+            Abi::Arm {
+                arch: Some(rust_arch),
+                hard_float,
+            },
+            Abi::Arm {
+                arch: c_arch,
+                hard_float: c_hard_float,
+            },
+        ) => {
+            if *hard_float != c_hard_float {
+                let swapped = if *hard_float {
+                    target.trim_end_matches("hf").to_owned()
+                } else {
+                    format!("{}hf", target)
+                };
+
+                return Err(anyhow!(
+                    "The Rust target {} uses the {} ABI, but the C/C++ code is compiled for the {} ABI, \
+                     so floating point arguments and return values of calls between them end up in \
+                     the wrong registers",
+                    target,
+                    float_abi_name(*hard_float),
+                    float_abi_name(c_hard_float)
+                ))
+                .hint(format!(
+                    "Use the Rust target {} (rust_target in platformio.ini)",
+                    swapped
+                ));
+            }
+
+            if let Some(c_arch) = c_arch {
+                if !arm_executes(&c_arch, rust_arch) {
+                    return Err(anyhow!(
+                        "The Rust target {} is compiled for ARM{}, but the C/C++ code is compiled \
+                         for ARM{}, whose CPUs do not implement all instructions of ARM{}",
+                        target,
+                        rust_arch,
+                        c_arch,
+                        rust_arch
+                    ))
+                    .hint(format!(
+                        "Use the Rust target thumb{}-none-eabi{} (rust_target in platformio.ini)",
+                        c_arch,
+                        if *hard_float { "hf" } else { "" }
+                    ));
+                }
+            }
+        }
+        (
+            Abi::RiscV {
+                extensions, abi, ..
+            },
+            Abi::RiscV {
+                extensions: c_extensions,
+                abi: c_abi,
+                ..
+            },
+        ) => {
+            let missing = extensions
+                .difference(&c_extensions)
+                .map(char::to_string)
+                .collect::<Vec<_>>();
+
+            if !missing.is_empty() {
+                return Err(anyhow!(
+                    "The Rust target {} uses the RISC-V extension(s) {}, but the C/C++ code is \
+                     compiled without them, so the CPU most likely does not implement them",
+                    target,
+                    missing.join(", ")
+                ))
+                .hint("Use a Rust target (rust_target in platformio.ini) with the extensions of the -march compiler flag of the environment");
+            }
+
+            if let (Some(abi), Some(c_abi)) = (abi, c_abi) {
+                if *abi != c_abi {
+                    return Err(anyhow!(
+                        "The Rust target {} uses the {} ABI, but the C/C++ code is compiled for \
+                         the {} ABI, so floating point arguments and return values of calls \
+                         between them end up in the wrong registers",
+                        target,
+                        abi,
+                        c_abi
+                    ))
+                    .hint("Use a Rust target (rust_target in platformio.ini) with the floating point extensions of the -mabi compiler flag of the environment");
+                }
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Check that the `rust_target` of the PlatformIO `environment` of the project in
+/// `project_dir` is ABI compatible with the C/C++ code of the environment.
+///
+/// Getting the compiler flags of the environment takes a while, so the check is only
+/// done again when `platformio.ini` or the toolchain (`toolchain_key`, see
+/// [`super::fingerprint`]) changed since the last successful check.
+pub fn preflight(
+    pio: &Pio,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+    toolchain_key: &str,
+) -> Result<()> {
+    let project_dir = project_dir.as_ref();
+
+    let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))
+        .context("Failed to read platformio.ini")?;
+
+    let target = match env_option(&platformio_ini, environment, "rust_target") {
+        Some(target) if Abi::from_target(&target).is_some() => target,
+        _ => return Ok(()),
+    };
+
+    // This uses the default hasher from the standard library, which is not guaranteed
+    // to be the same across versions; a different hash only causes one more check.
+    let mut hasher = DefaultHasher::new();
+    platformio_ini.hash(&mut hasher);
+    toolchain_key.hash(&mut hasher);
+    let key = format!("{:016x}", hasher.finish());
+
+    let build_dir = project_dir.join(".pio").join("build").join(environment);
+    let checked = build_dir.join(CHECKED_FILE);
+
+    if fs::read_to_string(&checked).ok().as_deref() == Some(key.as_str()) {
+        return Ok(());
+    }
+
+    debug!(
+        "Checking the ABI of the Rust target {} against environment {}",
+        target, environment
+    );
+
+    let flags = compiler_flags(pio, project_dir, environment)?;
+    if flags.is_empty() {
+        warn!(
+            "PlatformIO reported no compiler flags for environment {}, skipping the ABI check",
+            environment
+        );
+        return Ok(());
+    }
+
+    check(&target, &flags).with_context(|| {
+        format!(
+            "The Rust and the C/C++ code of environment {} are compiled for incompatible ABIs",
+            environment
+        )
+    })?;
+
+    fs::create_dir_all(&build_dir)?;
+    fs::write(checked, key)?;
+
+    Ok(())
+}
+
+/// The C compiler flags of the PlatformIO `environment` of the project in `project_dir`.
+fn compiler_flags(pio: &Pio, project_dir: &Path, environment: &str) -> Result<Vec<String>> {
+    let mut cmd = pio.cmd();
+    cmd.arg("project")
+        .arg("metadata")
+        .arg("-d")
+        .arg(project_dir)
+        .arg("-e")
+        .arg(environment);
+
+    let metadata = Pio::json::<JsonValue>(&mut cmd)
+        .context("Failed to get the compiler flags of the environment")?;
+
+    // A list with PlatformIO 6, a single string before
+    Ok(match &metadata[environment]["cc_flags"] {
+        JsonValue::Array(flags) => flags
+            .iter()
+            .filter_map(JsonValue::as_str)
+            .map(str::to_owned)
+            .collect(),
+        JsonValue::String(flags) => flags.split_whitespace().map(str::to_owned).collect(),
+        _ => Vec::new(),
+    })
+}
+
+fn float_abi_name(hard_float: bool) -> &'static str {
+    if hard_float {
+        "hard-float"
+    } else {
+        "soft-float"
+    }
+}
+
+/// The architecture of `-march=<march>` in the naming of the Rust targets, if it is an
+/// M-profile architecture.
+fn arm_arch(march: &str) -> Option<&'static str> {
+    let march = march.split('+').next().unwrap_or_default().replace('-', "");
+
+    match march.as_str() {
+        "armv6sm" => Some("v6m"),
+        march => ARM_M_ARCHS
+            .iter()
+            .copied()
+            .find(|arch| march.strip_prefix("arm") == Some(*arch)),
+    }
+}
+
+/// The architecture of the CPU of `-mcpu=<mcpu>`, if it is an M-profile CPU.
+fn arm_cpu_arch(mcpu: &str) -> Option<&'static str> {
+    match mcpu.split('+').next().unwrap_or_default() {
+        "cortex-m0" | "cortex-m0plus" | "cortex-m1" => Some("v6m"),
+        "cortex-m3" => Some("v7m"),
+        "cortex-m4" | "cortex-m7" => Some("v7em"),
+        "cortex-m23" => Some("v8m.base"),
+        "cortex-m33" | "cortex-m35p" | "cortex-m55" => Some("v8m.main"),
+        _ => None,
+    }
+}
+
+/// Whether CPUs of the architecture `c_arch` execute code compiled for `rust_arch`.
+///
+/// Architectures other than the M-profile ones are not compared.
+fn arm_executes(c_arch: &str, rust_arch: &str) -> bool {
+    let executes: &[&str] = match c_arch {
+        "v6m" => &["v6m"],
+        "v7m" => &["v6m", "v7m"],
+        "v7em" => &["v6m", "v7m", "v7em"],
+        "v8m.base" => &["v6m", "v8m.base"],
+        // The DSP extension is optional, but implemented by most ARMv8-M Mainline CPUs
+        "v8m.main" => &["v6m", "v7m", "v7em", "v8m.base", "v8m.main"],
+        _ => return true,
+    };
+
+    !ARM_M_ARCHS.contains(&rust_arch) || executes.contains(&rust_arch)
+}
+
+/// The XLEN and the single letter extensions of a RISC-V architecture string without
+/// the `rv`/`riscv` prefix (e.g. `32imac_zicsr`).
+fn riscv_arch(arch: &str) -> Option<(u32, BTreeSet<char>)> {
+    let digits = arch.chars().take_while(char::is_ascii_digit).count();
+    let xlen = arch[..digits].parse().ok()?;
+
+    let mut extensions = BTreeSet::new();
+    for extension in arch[digits..].split('_').next().unwrap_or_default().chars() {
+        if extension == 'g' {
+            extensions.extend(['i', 'm', 'a', 'f', 'd']);
+        } else {
+            extensions.insert(extension);
+        }
+    }
+
+    Some((xlen, extensions))
+}
+
+/// The calling convention of the Rust RISC-V targets with `extensions`.
+fn rust_riscv_abi(xlen: u32, extensions: &BTreeSet<char>) -> String {
+    let base = if xlen == 64 { "lp64" } else { "ilp32" };
+
+    let float = if extensions.contains(&'d') {
+        "d"
+    } else if extensions.contains(&'f') {
+        "f"
+    } else if extensions.contains(&'e') {
+        "e"
+    } else {
+        ""
+    };
+
+    format!("{}{}", base, float)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let flags = [
+            "-mthumb",
+            "-mcpu=cortex-m4",
+            "-mfloat-abi=hard",
+            "-mfpu=fpv4-sp-d16",
+        ];
+
+        assert!(check("thumbv7em-none-eabihf", &flags).is_ok());
+        assert!(check("thumbv7m-none-eabihf", &flags).is_ok());
+        assert!(check("thumbv7em-none-eabi", &flags).is_err());
+        assert!(check("thumbv7em-none-eabihf", &["-mcpu=cortex-m4"]).is_err());
+        assert!(check("thumbv7em-none-eabi", &["-mcpu=cortex-m0plus"]).is_err());
+        assert!(check("thumbv6m-none-eabi", &["-march=armv8-m.main+fp"]).is_ok());
+
+        assert!(check("riscv32imc-unknown-none-elf", &["-march=rv32imc_zicsr"]).is_ok());
+        assert!(check("riscv32imac-unknown-none-elf", &["-march=rv32imc"]).is_err());
+        assert!(check(
+            "riscv32imafc-unknown-none-elf",
+            &["-march=rv32gc", "-mabi=ilp32"]
+        )
+        .is_err());
+        assert!(check(
+            "riscv32imac-unknown-none-elf",
+            &["-march=rv32gc", "-mabi=ilp32"]
+        )
+        .is_ok());
+
+        assert!(check("xtensa-esp32-none-elf", &["-mlongcalls"]).is_ok());
+    }
+}
@@ -0,0 +1,195 @@
+//! The PlatformIO account and PIO Remote, with the account token managed by cargo-pio.
+//!
+//! PIO Remote reaches the devices attached to the agents of an account (`pio remote
+//! agent start` on the machines the devices are attached to). Instead of the session
+//! PlatformIO keeps in its core directory, cargo-pio stores the authentication token of
+//! the account with the [`credentials`](super::credentials) of the OS and passes it to
+//! the `pio account` and `pio remote` commands in `PLATFORMIO_AUTH_TOKEN`, as in CI.
+//!
+//! The devices of the agents are addressed as ports `remote://<agent>/<port>`, e.g.
+//! `remote://lab-pi//dev/ttyUSB0`, or `remote://*/<port>` for the port on any agent.
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::Deserialize;
+
+use super::{complete, credentials, Pio, SerialDevice};
+use crate::error::HintExt;
+
+/// The host the account token is stored for.
+pub const HOST: &str = "api.accounts.platformio.org";
+
+/// The variable PlatformIO reads the account token from.
+pub const VAR_AUTH_TOKEN: &str = "PLATFORMIO_AUTH_TOKEN";
+
+const PORT_PREFIX: &str = "remote://";
+
+/// Pass the stored account token to the PlatformIO command `cmd`, unless the token is
+/// set in the environment already. Returns whether `cmd` is authenticated.
+pub fn authenticate(cmd: &mut Command) -> Result<bool> {
+    if std::env::var_os(VAR_AUTH_TOKEN).is_some() {
+        return Ok(true);
+    }
+
+    Ok(match credentials::load(HOST)? {
+        Some(token) => {
+            cmd.env(VAR_AUTH_TOKEN, token);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Log into the account of `username` with `password` and store the token of the
+/// account.
+///
+/// The session PlatformIO opens for the login is closed again, the token replaces it.
+pub fn login(pio: &Pio, username: &str, password: &str) -> Result<()> {
+    let mut cmd = pio.cmd();
+    cmd.arg("account")
+        .arg("login")
+        .arg("--username")
+        .arg(username)
+        .arg("--password")
+        .arg(password);
+
+    pio.exec(&mut cmd)
+        .context("Failed to log into the PlatformIO account")?;
+
+    let mut cmd = pio.cmd();
+    cmd.arg("account")
+        .arg("token")
+        .arg("--password")
+        .arg(password);
+
+    let token = Pio::json::<TokenOutput>(&mut cmd)
+        .context("Failed to get the token of the PlatformIO account")?
+        .result;
+
+    let mut cmd = pio.cmd();
+    cmd.arg("account").arg("logout");
+
+    if let Err(err) = pio.exec(&mut cmd) {
+        warn!("Failed to close the PlatformIO session: {:#}", err);
+    }
+
+    credentials::validate(&token)?;
+    credentials::store(HOST, &token)
+}
+
+#[derive(Deserialize)]
+struct TokenOutput {
+    result: String,
+}
+
+/// The `pio remote` command of `pio`, for the agent `agent` (any agent if `None`).
+pub fn remote_cmd(pio: &Pio, agent: Option<&str>) -> Result<Command> {
+    let mut cmd = pio.cmd();
+
+    if !authenticate(&mut cmd)? {
+        return Err(anyhow!("Not logged into a PlatformIO account"))
+            .hint("Log in with `cargo pio account login`");
+    }
+
+    cmd.arg("remote");
+
+    if let Some(agent) = agent {
+        cmd.arg("--agent").arg(agent);
+    }
+
+    Ok(cmd)
+}
+
+/// A device attached to a PIO Remote agent.
+#[derive(Clone, Debug)]
+pub struct RemoteDevice {
+    pub agent: String,
+    pub device: SerialDevice,
+}
+
+impl RemoteDevice {
+    /// The port of the device, as `remote://<agent>/<port>`.
+    pub fn port(&self) -> String {
+        format!("{}{}/{}", PORT_PREFIX, self.agent, self.device.port)
+    }
+}
+
+/// The devices attached to the agents of the account.
+pub fn remote_devices(pio: &Pio) -> Result<Vec<RemoteDevice>> {
+    let mut cmd = remote_cmd(pio, None)?;
+    cmd.arg("device").arg("list");
+
+    let devices = parse_devices(Pio::json(&mut cmd)?);
+    complete::cache_remote_ports(
+        pio,
+        &devices.iter().map(RemoteDevice::port).collect::<Vec<_>>(),
+    );
+
+    Ok(devices)
+}
+
+/// The devices of the agents of `pio remote device list --json-output`.
+fn parse_devices(agents: BTreeMap<String, Vec<SerialDevice>>) -> Vec<RemoteDevice> {
+    agents
+        .into_iter()
+        .flat_map(|(agent, devices)| {
+            devices.into_iter().map(move |device| RemoteDevice {
+                agent: agent.clone(),
+                device,
+            })
+        })
+        .collect()
+}
+
+/// The agent (`None` for any agent) and the port of the PIO Remote port `port`, `None`
+/// if it is no PIO Remote port.
+pub fn remote_port(port: &str) -> Option<(Option<&str>, &str)> {
+    let (agent, port) = port.strip_prefix(PORT_PREFIX)?.split_once('/')?;
+
+    Some(((agent != "*").then(|| agent), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_devices() {
+        let agents = serde_json::from_str(
+            r#"{
+                "lab-pi": [
+                    {"port": "/dev/ttyUSB0", "description": "CP2102", "hwid": "USB VID:PID=10C4:EA60"},
+                    {"port": "/dev/ttyACM0", "description": "n/a", "hwid": "n/a"}
+                ],
+                "desk": [{"port": "COM3", "description": "", "hwid": ""}]
+            }"#,
+        )
+        .unwrap();
+
+        let ports = parse_devices(agents)
+            .iter()
+            .map(RemoteDevice::port)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ports,
+            [
+                "remote://desk/COM3",
+                "remote://lab-pi//dev/ttyUSB0",
+                "remote://lab-pi//dev/ttyACM0"
+            ]
+        );
+
+        assert_eq!(
+            remote_port(&ports[1]),
+            Some((Some("lab-pi"), "/dev/ttyUSB0"))
+        );
+        assert_eq!(
+            remote_port("remote://*//dev/ttyUSB0"),
+            Some((None, "/dev/ttyUSB0"))
+        );
+        assert_eq!(remote_port("/dev/ttyUSB0"), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! The namespace of the build artifacts of the environments of PIO->Cargo projects.
+//!
+//! Independent environments build concurrently, e.g. the jobs of a build matrix running
+//! side by side on one machine, because nothing they write is shared:
+//! - PlatformIO builds into `.pio/build/<environment>`, where cargo-pio also keeps the
+//!   fingerprint, the ABI check and the log of the build;
+//! - Cargo builds into a shared target dir, but `platformio.cargo.py` copies the Rust
+//!   library into `.pio/build/<environment>/rust` under a lock of the target dir and links
+//!   that copy, so environments with the same Rust target and profile do not link each
+//!   other's library;
+//! - the state of cargo-pio itself lives in [`dir`].
+//!
+//! Only builds of the same environment truly conflict, which [`lock`] serializes across
+//! processes (on Unix).
+
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::*;
+
+/// The directory (relative to the project directory) of the state of the environments.
+pub const ARTIFACTS_DIR: &str = "target/cargo-pio/env";
+
+/// The lock file of the builds of an environment, in its [`dir`].
+pub const LOCK_FILE: &str = "build.lock";
+
+/// The directory of the state of cargo-pio for `environment` of the project in
+/// `project_dir`.
+pub fn dir(project_dir: impl AsRef<Path>, environment: &str) -> PathBuf {
+    project_dir.as_ref().join(ARTIFACTS_DIR).join(environment)
+}
+
+/// An exclusive lock of the builds of an environment, released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+/// Lock the builds of `environment` of the project in `project_dir`, waiting for the
+/// build of another process to finish.
+pub fn lock(project_dir: impl AsRef<Path>, environment: &str) -> Result<Lock> {
+    let dir = dir(project_dir, environment);
+    fs::create_dir_all(&dir)?;
+
+    let path = dir.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    if !imp::try_lock(&file)? {
+        info!(
+            "Waiting for another build of environment {} to finish",
+            environment
+        );
+
+        imp::lock(&file).with_context(|| format!("Failed to lock {}", path.display()))?;
+    }
+
+    Ok(Lock { _file: file })
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    pub fn try_lock(file: &File) -> io::Result<bool> {
+        match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } {
+            0 => Ok(true),
+            _ => {
+                let err = io::Error::last_os_error();
+
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    Ok(false)
+                } else {
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    pub fn lock(file: &File) -> io::Result<()> {
+        loop {
+            match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+                0 => return Ok(()),
+                _ => {
+                    let err = io::Error::last_os_error();
+
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::fs::File;
+    use std::io;
+
+    pub fn try_lock(_file: &File) -> io::Result<bool> {
+        log::debug!("Builds of the same environment are not serialized on this platform");
+
+        Ok(true)
+    }
+
+    pub fn lock(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock() {
+        let project_dir = tempfile::tempdir().unwrap();
+
+        let lock = super::lock(project_dir.path(), "debug").unwrap();
+
+        let file = File::open(dir(project_dir.path(), "debug").join(LOCK_FILE)).unwrap();
+        assert!(!imp::try_lock(&file).unwrap());
+
+        let other = File::open(dir(project_dir.path(), "release").join(LOCK_FILE));
+        assert!(other.is_err());
+        let _release = super::lock(project_dir.path(), "release").unwrap();
+
+        drop(lock);
+        assert!(imp::try_lock(&file).unwrap());
+    }
+}
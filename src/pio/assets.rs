@@ -0,0 +1,300 @@
+//! Embedding of binary assets (web UI bundles, certificates, images, ...) into the
+//! firmware of PIO->Cargo projects.
+//!
+//! The assets configured in the `[assets]` section of `cargo-pio.toml` are (optionally
+//! compressed and) converted into a generated Rust source file with one static byte
+//! slice per asset, and/or a C source & header with one array per asset, which
+//! PlatformIO compiles and links into the firmware.
+//!
+//! The sources are only rewritten when their content changes, so that unchanged assets
+//! do not trigger rebuilds.
+
+use std::fmt::Write as _;
+use std::fs;
+#[cfg(feature = "pio-archive")]
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+
+use super::config::{Asset, AssetsConfig, Compression};
+
+/// An asset, converted for embedding.
+#[derive(Clone, Debug)]
+pub struct Embedded {
+    /// The name of the generated symbol.
+    pub name: String,
+    /// The file the asset was loaded from, relative to the project directory.
+    pub path: PathBuf,
+    pub compression: Compression,
+    /// The size of the file.
+    pub size: usize,
+    /// The (compressed) data.
+    pub data: Vec<u8>,
+}
+
+/// Load and convert the assets of `config` in the project in `project_dir`.
+pub fn load(config: &AssetsConfig, project_dir: impl AsRef<Path>) -> Result<Vec<Embedded>> {
+    let project_dir = project_dir.as_ref();
+
+    let assets = config
+        .files
+        .iter()
+        .map(|asset| load_asset(asset, project_dir))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (index, asset) in assets.iter().enumerate() {
+        if assets[..index].iter().any(|other| other.name == asset.name) {
+            bail!(
+                "Duplicate asset name {}, use 'name' to rename {}",
+                asset.name,
+                asset.path.display()
+            );
+        }
+    }
+
+    Ok(assets)
+}
+
+/// Generate the sources of the assets of `config` in the project in `project_dir`.
+///
+/// Returns the paths of the sources which changed.
+pub fn generate(config: &AssetsConfig, project_dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let project_dir = project_dir.as_ref();
+
+    if config.files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if config.rust.is_none() && config.c.is_none() {
+        warn!("Assets configured, but neither 'rust' nor 'c' output; nothing is embedded");
+        return Ok(Vec::new());
+    }
+
+    let assets = load(config, project_dir)?;
+
+    let mut sources = Vec::new();
+
+    if let Some(rust) = &config.rust {
+        sources.push((project_dir.join(rust), rust_source(&assets)));
+    }
+
+    if let Some(c) = &config.c {
+        let c = project_dir.join(c);
+        let header = c.with_extension("h");
+
+        let header_name = header
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        sources.push((c.with_extension("c"), c_source(&assets, &header_name)));
+        sources.push((header, c_header(&assets)));
+    }
+
+    let mut changed = Vec::new();
+
+    for (path, content) in sources {
+        if fs::read_to_string(&path).ok().as_deref() != Some(content.as_str()) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            crate::fs::write_atomic(&path, content)?;
+
+            changed.push(path);
+        }
+    }
+
+    for path in &changed {
+        info!("Generated assets source {}", path.display());
+    }
+
+    Ok(changed)
+}
+
+fn load_asset(asset: &Asset, project_dir: &Path) -> Result<Embedded> {
+    let data = fs::read(project_dir.join(&asset.path))
+        .with_context(|| anyhow!("Failed to read asset {}", asset.path.display()))?;
+
+    let name = match &asset.name {
+        Some(name) => name.clone(),
+        None => symbol_name(
+            &asset
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        ),
+    };
+
+    let size = data.len();
+
+    let data = match asset.compress {
+        Compression::None => data,
+        #[cfg(feature = "pio-archive")]
+        Compression::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(&data)?;
+            encoder.finish()?
+        }
+        #[cfg(not(feature = "pio-archive"))]
+        Compression::Gzip => {
+            bail!("Gzip compressed assets need the `pio-archive` feature of embuild")
+        }
+    };
+
+    Ok(Embedded {
+        name,
+        path: asset.path.clone(),
+        compression: asset.compress,
+        size,
+        data,
+    })
+}
+
+/// The symbol name of the file `file_name`: upper case, with everything except for
+/// letters and digits replaced by underscores.
+fn symbol_name(file_name: &str) -> String {
+    let name = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    if name.starts_with(|c: char| c.is_ascii_digit()) || name.is_empty() {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+fn description(asset: &Embedded) -> String {
+    match asset.compression {
+        Compression::None => format!("`{}` ({} bytes)", asset.path.display(), asset.size),
+        Compression::Gzip => format!(
+            "`{}` ({} bytes, gzip compressed to {} bytes)",
+            asset.path.display(),
+            asset.size,
+            asset.data.len()
+        ),
+    }
+}
+
+fn rust_source(assets: &[Embedded]) -> String {
+    let mut source =
+        String::from("// Generated by cargo-pio from the assets in cargo-pio.toml, do not edit\n");
+
+    for asset in assets {
+        write!(
+            source,
+            "\n/// {}\npub static {}: &[u8] = b\"",
+            description(asset),
+            asset.name
+        )
+        .unwrap();
+
+        for (index, byte) in asset.data.iter().enumerate() {
+            // Keep the lines reasonably short for editors
+            let line_start = index % 64 == 0 && index > 0;
+            if line_start {
+                source.push_str("\\\n");
+            }
+
+            match byte {
+                // Leading whitespace of a continued line is skipped by the compiler
+                b' ' if line_start => source.push_str("\\x20"),
+                b'"' | b'\\' => write!(source, "\\{}", *byte as char).unwrap(),
+                b' '..=b'~' => source.push(*byte as char),
+                _ => write!(source, "\\x{:02x}", byte).unwrap(),
+            }
+        }
+
+        source.push_str("\";\n");
+    }
+
+    source
+}
+
+fn c_header(assets: &[Embedded]) -> String {
+    let mut header = String::from(
+        "// Generated by cargo-pio from the assets in cargo-pio.toml, do not edit\n\n\
+         #pragma once\n\n\
+         #include <stddef.h>\n",
+    );
+
+    for asset in assets {
+        write!(
+            header,
+            "\n// {}\nextern const unsigned char {}[];\nextern const size_t {}_LEN;\n",
+            description(asset).replace('`', ""),
+            asset.name,
+            asset.name
+        )
+        .unwrap();
+    }
+
+    header
+}
+
+fn c_source(assets: &[Embedded], header_name: &str) -> String {
+    let mut source = format!(
+        "// Generated by cargo-pio from the assets in cargo-pio.toml, do not edit\n\n\
+         #include \"{}\"\n",
+        header_name
+    );
+
+    for asset in assets {
+        write!(source, "\nconst unsigned char {}[] = {{", asset.name).unwrap();
+
+        for (index, byte) in asset.data.iter().enumerate() {
+            if index % 16 == 0 {
+                source.push_str("\n   ");
+            }
+
+            write!(source, " 0x{:02x},", byte).unwrap();
+        }
+
+        write!(
+            source,
+            "\n}};\n\nconst size_t {}_LEN = {};\n",
+            asset.name,
+            asset.data.len()
+        )
+        .unwrap();
+    }
+
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_name() {
+        assert_eq!(symbol_name("index.html"), "INDEX_HTML");
+        assert_eq!(symbol_name("ca-cert.pem"), "CA_CERT_PEM");
+        assert_eq!(symbol_name("404.html"), "_404_HTML");
+    }
+
+    #[test]
+    fn test_rust_source() {
+        let source = rust_source(&[Embedded {
+            name: "DATA".into(),
+            path: "data.bin".into(),
+            compression: Compression::None,
+            size: 5,
+            data: b"a\"\\\x00\n".to_vec(),
+        }]);
+
+        assert!(source.contains("pub static DATA: &[u8] = b\"a\\\"\\\\\\x00\\x0a\";\n"));
+    }
+}
@@ -0,0 +1,398 @@
+//! Board metadata of PIO->Cargo projects, as constants for the firmware.
+//!
+//! The metadata of the board of an environment (MCU, CPU frequency, RAM and flash
+//! sizes) is taken from the board definition of PlatformIO (`boards/<board>.json` of
+//! the project or the platform), the `board_build.*`/`board_upload.*` options of the
+//! environment in `platformio.ini` and the overrides and pin aliases in the `[board]`
+//! section of `cargo-pio.toml`, in increasing order of precedence.
+//!
+//! Like the stamp (see [`super::stamp`]), the metadata is passed to the build as C
+//! defines (`CARGO_PIO_BOARD_*`) and/or written into a generated Rust source file with
+//! constants.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use serde_json::Value as JsonValue;
+
+use super::config::{BoardConfig, BoardOverrides};
+use super::graph::Platform;
+use super::stamp::escape;
+use super::Pio;
+use crate::pio_model::env_option;
+
+/// The metadata of a board.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BoardInfo {
+    /// The PlatformIO board id, e.g. `esp32dev`.
+    pub id: String,
+    pub name: String,
+    pub mcu: String,
+    /// The CPU frequency in Hz.
+    pub f_cpu: Option<u64>,
+    /// The size of the RAM in bytes.
+    pub ram_size: Option<u64>,
+    /// The size of the flash in bytes.
+    pub flash_size: Option<u64>,
+    /// The maximum size of the firmware in bytes.
+    pub max_firmware_size: Option<u64>,
+    /// Pin aliases, e.g. `LED` -> `2`.
+    pub pins: BTreeMap<String, u32>,
+    /// The USB vendor and product ids of the board (or its onboard debug probe).
+    pub hwids: Vec<(u16, u16)>,
+}
+
+impl BoardInfo {
+    /// Load the metadata of the board of the PlatformIO `environment` of the project in
+    /// `project_dir`, with the overrides of `config`.
+    ///
+    /// Returns `None` if the environment has no board. The platform of the environment
+    /// is installed if it is not yet, for its board definitions.
+    pub fn load(
+        pio: &Pio,
+        config: &BoardConfig,
+        project_dir: impl AsRef<Path>,
+        environment: &str,
+    ) -> Result<Option<Self>> {
+        let project_dir = project_dir.as_ref();
+
+        let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))
+            .context("Failed to read platformio.ini")?;
+
+        let (id, definition) = match definition(pio, project_dir, environment)? {
+            Some(definition) => definition,
+            None => return Ok(None),
+        };
+
+        let option = |key: &str| env_option(&platformio_ini, environment, key);
+
+        let mut board = Self {
+            name: definition["name"].as_str().unwrap_or(&id).to_owned(),
+            mcu: option("board_build.mcu")
+                .or_else(|| definition["build"]["mcu"].as_str().map(str::to_owned))
+                .unwrap_or_default(),
+            f_cpu: option("board_build.f_cpu")
+                .as_deref()
+                .or_else(|| definition["build"]["f_cpu"].as_str())
+                .and_then(parse_frequency),
+            ram_size: option("board_upload.maximum_ram_size")
+                .and_then(|size| size.parse().ok())
+                .or_else(|| definition["upload"]["maximum_ram_size"].as_u64()),
+            flash_size: option("board_upload.flash_size")
+                .as_deref()
+                .or_else(|| definition["upload"]["flash_size"].as_str())
+                .and_then(parse_size),
+            max_firmware_size: option("board_upload.maximum_size")
+                .and_then(|size| size.parse().ok())
+                .or_else(|| definition["upload"]["maximum_size"].as_u64()),
+            pins: BTreeMap::new(),
+            hwids: definition["build"]["hwids"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(parse_hwid)
+                .collect(),
+            id,
+        };
+
+        if let Some(overrides) = config.overrides.get(&board.id) {
+            board.apply(overrides)?;
+        }
+
+        Ok(Some(board))
+    }
+
+    /// Apply `overrides` to this metadata.
+    pub fn apply(&mut self, overrides: &BoardOverrides) -> Result<()> {
+        if let Some(name) = &overrides.name {
+            self.name = name.clone();
+        }
+
+        if let Some(mcu) = &overrides.mcu {
+            self.mcu = mcu.clone();
+        }
+
+        self.f_cpu = overrides.f_cpu.or(self.f_cpu);
+        self.ram_size = overrides.ram_size.or(self.ram_size);
+        self.flash_size = overrides.flash_size.or(self.flash_size);
+        self.max_firmware_size = overrides.max_firmware_size.or(self.max_firmware_size);
+
+        for (alias, pin) in &overrides.pins {
+            if alias.is_empty()
+                || alias.starts_with(|c: char| c.is_ascii_digit())
+                || !alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                bail!(
+                    "Invalid pin alias '{}' of board {}, expected an identifier",
+                    alias,
+                    self.id
+                );
+            }
+
+            self.pins.insert(alias.to_uppercase(), *pin);
+        }
+
+        Ok(())
+    }
+
+    /// The numeric metadata as `(<name>, <value>)`, skipping unknown values.
+    fn sizes(&self) -> Vec<(&'static str, u64)> {
+        [
+            ("F_CPU", self.f_cpu),
+            ("RAM_SIZE", self.ram_size),
+            ("FLASH_SIZE", self.flash_size),
+            ("MAX_FIRMWARE_SIZE", self.max_firmware_size),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect()
+    }
+
+    /// The C defines of the metadata, as compiler flags.
+    ///
+    /// `BOARD`, `F_CPU` etc. are defined by many frameworks already, so all defines are
+    /// prefixed with `CARGO_PIO_BOARD_`.
+    pub fn c_defines(&self) -> Vec<String> {
+        let mut defines = vec![
+            format!("'-DCARGO_PIO_BOARD_ID=\"{}\"'", escape(&self.id)),
+            format!("'-DCARGO_PIO_BOARD_NAME=\"{}\"'", escape(&self.name)),
+            format!("'-DCARGO_PIO_BOARD_MCU=\"{}\"'", escape(&self.mcu)),
+        ];
+
+        for (name, value) in self.sizes() {
+            defines.push(format!("-DCARGO_PIO_BOARD_{}={}ULL", name, value));
+        }
+
+        for (alias, pin) in &self.pins {
+            defines.push(format!("-DCARGO_PIO_BOARD_PIN_{}={}", alias, pin));
+        }
+
+        defines
+    }
+
+    /// The Rust source of the metadata, as constants.
+    pub fn rust_source(&self) -> String {
+        let mut source = format!(
+            "// Generated by cargo-pio, do not edit\n\n\
+             pub const BOARD_ID: &str = {:?};\n\
+             pub const BOARD_NAME: &str = {:?};\n\
+             pub const MCU: &str = {:?};\n",
+            self.id, self.name, self.mcu
+        );
+
+        for (name, value) in self.sizes() {
+            source.push_str(&format!("pub const {}: u64 = {};\n", name, value));
+        }
+
+        if !self.pins.is_empty() {
+            source.push_str("\npub mod pins {\n");
+
+            for (alias, pin) in &self.pins {
+                source.push_str(&format!("    pub const {}: u32 = {};\n", alias, pin));
+            }
+
+            source.push_str("}\n");
+        }
+
+        source
+    }
+
+    /// Apply the metadata to the `pio run` command `cmd` as configured in
+    /// `board_config`: write the Rust source and add the C defines.
+    pub fn apply_to(
+        &self,
+        board_config: &BoardConfig,
+        project_dir: impl AsRef<Path>,
+        cmd: &mut Command,
+    ) -> Result<()> {
+        if let Some(rust) = &board_config.rust {
+            let path = project_dir.as_ref().join(rust);
+            let source = self.rust_source();
+
+            // Rewriting an unchanged file would trigger a rebuild of the crate
+            if fs::read_to_string(&path).ok().as_deref() != Some(source.as_str()) {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                crate::fs::write_atomic(&path, source)?;
+            }
+        }
+
+        if board_config.defines {
+            super::append_build_flags(cmd, self.c_defines());
+        }
+
+        Ok(())
+    }
+}
+
+/// The id and the board definition of the board of the PlatformIO `environment` of the
+/// project in `project_dir`, `None` if the environment has no board.
+///
+/// The platform of the environment is installed if it is not yet, for its board
+/// definitions.
+pub(crate) fn definition(
+    pio: &Pio,
+    project_dir: &Path,
+    environment: &str,
+) -> Result<Option<(String, JsonValue)>> {
+    let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))
+        .context("Failed to read platformio.ini")?;
+
+    let id = match env_option(&platformio_ini, environment, "board") {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    let definition = match board_definition(pio, &platformio_ini, project_dir, environment, &id)? {
+        Some(definition) => definition,
+        None => {
+            info!("Installing the platform of environment {}", environment);
+
+            let mut cmd = pio.cmd();
+            cmd.arg("pkg")
+                .arg("install")
+                .arg("-d")
+                .arg(project_dir)
+                .arg("-e")
+                .arg(environment);
+
+            pio.exec(&mut cmd)?;
+
+            board_definition(pio, &platformio_ini, project_dir, environment, &id)?
+                .with_context(|| format!("Board {} not found", id))?
+        }
+    };
+
+    Ok(Some((id, definition)))
+}
+
+/// The board definition `<id>.json` of the project or the platform of `environment`,
+/// `None` if the platform is not installed.
+fn board_definition(
+    pio: &Pio,
+    platformio_ini: &str,
+    project_dir: &Path,
+    environment: &str,
+    id: &str,
+) -> Result<Option<JsonValue>> {
+    let file_name = format!("{}.json", id);
+
+    let mut candidates = vec![project_dir.join("boards").join(&file_name)];
+
+    let platform = Platform::resolve(pio, platformio_ini, environment)?;
+    if platform.dir.exists() {
+        candidates.push(platform.dir.join("boards").join(&file_name));
+    }
+
+    match candidates.into_iter().find(|path| path.is_file()) {
+        Some(path) => read_definition(&path).map(Some),
+        None if platform.dir.exists() => {
+            bail!("Board {} not found in platform {}", id, platform.name)
+        }
+        None => Ok(None),
+    }
+}
+
+fn read_definition(path: &Path) -> Result<JsonValue> {
+    debug!("Loading board definition {}", path.display());
+
+    serde_json::from_slice(&fs::read(path)?)
+        .with_context(|| format!("Failed to parse board definition {}", path.display()))
+}
+
+/// Parse a USB id pair as used by PlatformIO, e.g. `["0x10C4", "0xEA60"]`.
+fn parse_hwid(hwid: &JsonValue) -> Option<(u16, u16)> {
+    let id = |index: usize| {
+        let id = hwid.get(index)?.as_str()?;
+        let id = id.trim_start_matches("0x").trim_start_matches("0X");
+
+        u16::from_str_radix(id, 16).ok()
+    };
+
+    Some((id(0)?, id(1)?))
+}
+
+/// Parse a frequency as used by PlatformIO, e.g. `240000000L`.
+fn parse_frequency(frequency: &str) -> Option<u64> {
+    frequency
+        .trim()
+        .trim_end_matches(['L', 'l', 'U', 'u'])
+        .parse()
+        .ok()
+}
+
+/// Parse a size as used by PlatformIO, e.g. `4MB` or `512KB`.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let digits = size.chars().take_while(char::is_ascii_digit).count();
+
+    let unit = match size[digits..].trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        _ => return None,
+    };
+
+    size[..digits].parse::<u64>().ok().map(|size| size * unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_board() {
+        assert_eq!(parse_frequency("240000000L"), Some(240_000_000));
+        assert_eq!(parse_size("4MB"), Some(4 * 1024 * 1024));
+        assert_eq!(parse_size("512KB"), Some(512 * 1024));
+        assert_eq!(
+            parse_hwid(&serde_json::json!(["0x10C4", "0xEA60"])),
+            Some((0x10c4, 0xea60))
+        );
+
+        let mut board = BoardInfo {
+            id: "esp32dev".into(),
+            name: "Espressif ESP32 Dev Module".into(),
+            mcu: "esp32".into(),
+            f_cpu: Some(240_000_000),
+            ram_size: Some(327_680),
+            flash_size: Some(4 * 1024 * 1024),
+            ..Default::default()
+        };
+
+        let mut overrides = BoardOverrides {
+            flash_size: Some(8 * 1024 * 1024),
+            ..Default::default()
+        };
+        overrides.pins.insert("led".into(), 2);
+
+        board.apply(&overrides).unwrap();
+
+        assert_eq!(
+            board.rust_source(),
+            "// Generated by cargo-pio, do not edit\n\n\
+             pub const BOARD_ID: &str = \"esp32dev\";\n\
+             pub const BOARD_NAME: &str = \"Espressif ESP32 Dev Module\";\n\
+             pub const MCU: &str = \"esp32\";\n\
+             pub const F_CPU: u64 = 240000000;\n\
+             pub const RAM_SIZE: u64 = 327680;\n\
+             pub const FLASH_SIZE: u64 = 8388608;\n\
+             \n\
+             pub mod pins {\n    \
+             pub const LED: u32 = 2;\n\
+             }\n"
+        );
+        assert!(board
+            .c_defines()
+            .contains(&"-DCARGO_PIO_BOARD_PIN_LED=2".to_owned()));
+
+        overrides.pins.insert("1st".into(), 3);
+        assert!(board.apply(&overrides).is_err());
+    }
+}
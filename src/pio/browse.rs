@@ -0,0 +1,567 @@
+//! The model of `cargo pio pkg browse`, a terminal UI for exploring the platforms and
+//! packages of PlatformIO.
+//!
+//! The [`Browser`] lists the platforms of the registry merged with the installed
+//! platforms and tool packages of the core directory, with their available versions,
+//! installed version and installed size. It renders itself into a string of ANSI escape
+//! sequences and turns [`Key`]s into [`Action`]s; running the actions and the terminal
+//! are left to the caller.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use log::*;
+
+use super::graph::{dir_size, format_size};
+use super::Pio;
+use crate::pio_model::compare_versions;
+
+/// Where an entry comes from.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Source {
+    /// A development platform, of the registry or installed.
+    Platform,
+    /// A tool package (toolchain, framework, uploader, ...), installed by a platform.
+    Package,
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self::Package
+    }
+}
+
+impl Source {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Platform => "platform",
+            Self::Package => "package",
+        }
+    }
+
+    /// The option of `pio pkg install` and `pio pkg uninstall` for this source.
+    fn option(&self) -> &'static str {
+        match self {
+            Self::Platform => "--platform",
+            Self::Package => "--tool",
+        }
+    }
+}
+
+/// A platform or package.
+#[derive(Clone, Debug, Default)]
+pub struct Entry {
+    pub source: Source,
+    pub name: String,
+    pub description: String,
+    /// The versions available in the registry, newest first.
+    pub versions: Vec<String>,
+    /// The installed version, `None` if not installed.
+    pub installed: Option<String>,
+    /// The installed size in bytes.
+    pub size: Option<u64>,
+}
+
+/// Collect the entries: the platforms of the registry, and the platforms and packages
+/// installed in the core directory of `pio`.
+///
+/// Without access to the registry, only the installed ones are listed.
+pub fn entries(pio: &Pio) -> Vec<Entry> {
+    let mut entries = BTreeMap::new();
+
+    match pio.platforms(Option::<&str>::None) {
+        Ok(platforms) => {
+            for platform in platforms {
+                let mut versions = platform.versions;
+                versions.sort_by(|a, b| compare_versions(b, a));
+
+                entries.insert(
+                    (Source::Platform, platform.name.clone()),
+                    Entry {
+                        source: Source::Platform,
+                        name: platform.name,
+                        description: platform.description,
+                        versions,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+        Err(err) => warn!("Listing the platforms of the registry failed: {:#}", err),
+    }
+
+    for (source, dir, manifest) in [
+        (Source::Platform, "platforms", "platform.json"),
+        (Source::Package, "packages", "package.json"),
+    ] {
+        for (name, version, description, size) in installed(&pio.core_dir.join(dir), manifest) {
+            let entry = entries
+                .entry((source, name.clone()))
+                .or_insert_with(|| Entry {
+                    source,
+                    name,
+                    description,
+                    ..Default::default()
+                });
+
+            entry.installed = Some(version);
+            entry.size = size;
+        }
+    }
+
+    entries.into_values().collect()
+}
+
+/// The name, version, description and size of the packages installed in `dir`, read
+/// from their `manifest`.
+fn installed(dir: &Path, manifest: &str) -> Vec<(String, String, String, Option<u64>)> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let json = fs::read_to_string(entry.path().join(manifest)).ok()?;
+            let json = serde_json::from_str::<serde_json::Value>(&json).ok()?;
+
+            Some((
+                json["name"].as_str()?.to_owned(),
+                json["version"].as_str().unwrap_or_default().to_owned(),
+                json["description"].as_str().unwrap_or_default().to_owned(),
+                dir_size(&entry.path()),
+            ))
+        })
+        .collect()
+}
+
+/// A key press.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Enter,
+    Escape,
+    Backspace,
+    Char(char),
+}
+
+/// Parse the key presses in `input` read from a terminal in raw mode.
+pub fn parse_keys(input: &[u8]) -> Vec<Key> {
+    let input = String::from_utf8_lossy(input);
+    let mut chars = input.chars().peekable();
+    let mut keys = Vec::new();
+
+    while let Some(c) = chars.next() {
+        keys.push(match c {
+            '\x1b' if chars.peek() == Some(&'[') || chars.peek() == Some(&'O') => {
+                chars.next();
+
+                let mut sequence = String::new();
+                for c in chars.by_ref() {
+                    sequence.push(c);
+                    if c.is_ascii_alphabetic() || c == '~' {
+                        break;
+                    }
+                }
+
+                match sequence.as_str() {
+                    "A" => Key::Up,
+                    "B" => Key::Down,
+                    "C" => Key::Right,
+                    "D" => Key::Left,
+                    "5~" => Key::PageUp,
+                    "6~" => Key::PageDown,
+                    _ => continue,
+                }
+            }
+            '\x1b' => Key::Escape,
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            c => Key::Char(c),
+        });
+    }
+
+    keys
+}
+
+/// What the caller should do after a key press.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Nothing but rendering again.
+    None,
+    /// Run `pio` with the arguments, then collect the entries again.
+    Pio(Vec<String>),
+    Quit,
+}
+
+/// The state of the browser.
+#[derive(Clone, Debug, Default)]
+pub struct Browser {
+    pub entries: Vec<Entry>,
+    /// The filter of the names and descriptions, case-insensitive.
+    pub filter: String,
+    /// Whether keys edit the filter.
+    pub filtering: bool,
+    /// The index of the selected entry among the visible ones.
+    pub selected: usize,
+    /// The index of the selected version of the selected entry.
+    pub version: usize,
+    /// The index of the first visible entry on the screen.
+    scroll: usize,
+}
+
+impl Browser {
+    pub fn new(entries: Vec<Entry>) -> Self {
+        Self {
+            entries,
+            ..Default::default()
+        }
+    }
+
+    /// Replace the entries, keeping the selection on the same entry if possible.
+    pub fn set_entries(&mut self, entries: Vec<Entry>) {
+        let selected = self
+            .selected()
+            .map(|entry| (entry.source, entry.name.clone()));
+
+        self.entries = entries;
+
+        if let Some((source, name)) = selected {
+            if let Some(index) = self
+                .visible()
+                .iter()
+                .position(|entry| entry.source == source && entry.name == name)
+            {
+                self.selected = index;
+            }
+        }
+
+        self.clamp();
+    }
+
+    /// The entries matching the filter.
+    pub fn visible(&self) -> Vec<&Entry> {
+        let filter = self.filter.to_lowercase();
+
+        self.entries
+            .iter()
+            .filter(|entry| {
+                entry.name.to_lowercase().contains(&filter)
+                    || entry.description.to_lowercase().contains(&filter)
+            })
+            .collect()
+    }
+
+    pub fn selected(&self) -> Option<&Entry> {
+        self.visible().get(self.selected).copied()
+    }
+
+    /// The version to install of the selected entry, `None` for the newest.
+    fn selected_version(&self) -> Option<&str> {
+        self.selected()?
+            .versions
+            .get(self.version)
+            .map(String::as_str)
+    }
+
+    fn clamp(&mut self) {
+        let visible = self.visible().len();
+        self.selected = self.selected.min(visible.saturating_sub(1));
+
+        let versions = self.selected().map_or(0, |entry| entry.versions.len());
+        self.version = self.version.min(versions.saturating_sub(1));
+    }
+
+    fn select(&mut self, selected: usize) {
+        if selected != self.selected {
+            self.selected = selected;
+            self.version = 0;
+        }
+
+        self.clamp();
+    }
+
+    /// Handle the key press `key`, for a screen of `rows` rows.
+    pub fn key(&mut self, key: Key, rows: usize) -> Action {
+        let page = rows.saturating_sub(HEADER_ROWS + FOOTER_ROWS).max(1);
+
+        // CTRL-C, which raw mode passes as a character instead of interrupting
+        if key == Key::Char('\x03') {
+            return Action::Quit;
+        }
+
+        if self.filtering {
+            match key {
+                Key::Enter | Key::Escape => self.filtering = false,
+                Key::Backspace => {
+                    self.filter.pop();
+                }
+                Key::Char(c) if !c.is_control() => self.filter.push(c),
+                _ => (),
+            }
+
+            self.select(0);
+
+            return Action::None;
+        }
+
+        match key {
+            Key::Up | Key::Char('k') => self.select(self.selected.saturating_sub(1)),
+            Key::Down | Key::Char('j') => self.select(self.selected + 1),
+            Key::PageUp => self.select(self.selected.saturating_sub(page)),
+            Key::PageDown => self.select(self.selected + page),
+            Key::Left | Key::Char('h') => self.version = self.version.saturating_sub(1),
+            Key::Right | Key::Char('l') => {
+                self.version += 1;
+                self.clamp();
+            }
+            Key::Char('/') => self.filtering = true,
+            Key::Char('i') => {
+                if let Some(entry) = self.selected() {
+                    let spec = match self.selected_version() {
+                        Some(version) => format!("{}@{}", entry.name, version),
+                        None => entry.name.clone(),
+                    };
+
+                    return Action::Pio(vec![
+                        "pkg".into(),
+                        "install".into(),
+                        "--global".into(),
+                        entry.source.option().into(),
+                        spec,
+                    ]);
+                }
+            }
+            Key::Char('r') => {
+                if let Some(entry) = self.selected().filter(|entry| entry.installed.is_some()) {
+                    return Action::Pio(vec![
+                        "pkg".into(),
+                        "uninstall".into(),
+                        "--global".into(),
+                        entry.source.option().into(),
+                        entry.name.clone(),
+                    ]);
+                }
+            }
+            Key::Char('q') | Key::Escape => return Action::Quit,
+            _ => (),
+        }
+
+        Action::None
+    }
+
+    /// Render the browser for a screen of `columns` x `rows`, clearing it first.
+    pub fn render(&mut self, columns: usize, rows: usize) -> String {
+        let page = rows.saturating_sub(HEADER_ROWS + FOOTER_ROWS).max(1);
+
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + page {
+            self.scroll = self.selected + 1 - page;
+        }
+
+        let visible = self.visible();
+        let line = |text: String| format!("{}\x1b[K\r\n", truncate(&text, columns));
+
+        let mut screen = String::from("\x1b[H");
+
+        screen += &line(format!(
+            "\x1b[1mcargo pio pkg browse\x1b[0m  {} of {} entries{}",
+            visible.len(),
+            self.entries.len(),
+            if self.filtering || !self.filter.is_empty() {
+                format!(
+                    "  filter: {}{}",
+                    self.filter,
+                    if self.filtering { "_" } else { "" }
+                )
+            } else {
+                String::new()
+            }
+        ));
+        screen += &line(format!(
+            "\x1b[4m  {:<9} {:<36} {:<14} {:<14} {:>10}\x1b[0m",
+            "SOURCE", "NAME", "INSTALLED", "LATEST", "SIZE"
+        ));
+
+        for row in 0..page {
+            let index = self.scroll + row;
+
+            screen += &match visible.get(index) {
+                Some(entry) => {
+                    let text = format!(
+                        "{} {:<9} {:<36} {:<14} {:<14} {:>10}",
+                        if index == self.selected { ">" } else { " " },
+                        entry.source.name(),
+                        entry.name,
+                        entry.installed.as_deref().unwrap_or("-"),
+                        entry.versions.first().map(String::as_str).unwrap_or("-"),
+                        entry.size.map(format_size).unwrap_or_else(|| "-".into()),
+                    );
+
+                    if index == self.selected {
+                        line(format!("\x1b[7m{}\x1b[0m", text))
+                    } else {
+                        line(text)
+                    }
+                }
+                None => line(String::new()),
+            };
+        }
+
+        let details = match self.selected() {
+            Some(entry) => {
+                let mut details = entry.description.clone();
+
+                if !entry.versions.is_empty() {
+                    let mut versions = String::new();
+                    for (index, version) in entry.versions.iter().enumerate() {
+                        if index == self.version {
+                            write!(versions, " [{}]", version).unwrap();
+                        } else {
+                            write!(versions, " {}", version).unwrap();
+                        }
+                    }
+
+                    details = format!("versions:{}  {}", versions, details);
+                }
+
+                details
+            }
+            None => "No entries match the filter".into(),
+        };
+
+        screen += &line(details);
+        screen += &truncate(
+            "\x1b[2m↑/↓ select  ←/→ version  / filter  i install  r remove  q quit\x1b[0m\x1b[K",
+            columns,
+        );
+
+        screen
+    }
+}
+
+/// The rows above and below the list of entries.
+const HEADER_ROWS: usize = 2;
+const FOOTER_ROWS: usize = 2;
+
+/// Truncate `text` to `columns` visible characters, not counting escape sequences.
+fn truncate(text: &str, columns: usize) -> String {
+    let mut truncated = String::new();
+    let mut visible = 0;
+    let mut escape = false;
+
+    for c in text.chars() {
+        if c == '\x1b' {
+            escape = true;
+        }
+
+        if escape {
+            truncated.push(c);
+            escape = !c.is_ascii_alphabetic();
+        } else if visible < columns {
+            truncated.push(c);
+            visible += 1;
+        }
+    }
+
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browser() {
+        assert_eq!(
+            parse_keys(b"\x1b[Bj/esp\x7f\r\x1b[6~q\x1b"),
+            [
+                Key::Down,
+                Key::Char('j'),
+                Key::Char('/'),
+                Key::Char('e'),
+                Key::Char('s'),
+                Key::Char('p'),
+                Key::Backspace,
+                Key::Enter,
+                Key::PageDown,
+                Key::Char('q'),
+                Key::Escape
+            ]
+        );
+
+        let mut browser = Browser::new(vec![
+            Entry {
+                source: Source::Platform,
+                name: "espressif32".into(),
+                description: "ESP32 SoCs".into(),
+                versions: vec!["6.1.0".into(), "5.0.0".into()],
+                installed: Some("5.0.0".into()),
+                size: Some(1 << 20),
+            },
+            Entry {
+                source: Source::Platform,
+                name: "ststm32".into(),
+                description: "STM32 MCUs".into(),
+                ..Default::default()
+            },
+            Entry {
+                source: Source::Package,
+                name: "toolchain-xtensa-esp32".into(),
+                installed: Some("8.4.0".into()),
+                ..Default::default()
+            },
+        ]);
+
+        for key in parse_keys(b"/esp\r") {
+            browser.key(key, 24);
+        }
+        assert_eq!(browser.visible().len(), 2);
+
+        browser.key(Key::Right, 24);
+        assert_eq!(
+            browser.key(Key::Char('i'), 24),
+            Action::Pio(
+                [
+                    "pkg",
+                    "install",
+                    "--global",
+                    "--platform",
+                    "espressif32@5.0.0"
+                ]
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect()
+            )
+        );
+
+        browser.key(Key::Down, 24);
+        assert_eq!(
+            browser.key(Key::Char('r'), 24),
+            Action::Pio(
+                [
+                    "pkg",
+                    "uninstall",
+                    "--global",
+                    "--tool",
+                    "toolchain-xtensa-esp32"
+                ]
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect()
+            )
+        );
+
+        let screen = browser.render(80, 24);
+        assert!(screen.contains("2 of 3 entries  filter: esp"));
+        assert!(screen.contains("> package   toolchain-xtensa-esp32"));
+
+        assert_eq!(browser.key(Key::Char('q'), 24), Action::Quit);
+    }
+}
@@ -0,0 +1,430 @@
+//! Version bumps of PIO->Cargo projects.
+//!
+//! The version of the Cargo package is the version of the firmware: it is stamped into
+//! the firmware (see [`super::stamp`]), used for MCUboot images (see [`super::mcuboot`])
+//! and releases (see [`super::release`]). A bump updates every file carrying it in one
+//! go, and restores all of them if any of the updates fails:
+//! - the `version` of the package in `Cargo.toml` and the `Cargo.lock` of its workspace;
+//! - explicitly configured MCUboot image versions in `cargo-pio.toml`;
+//! - `CHANGELOG.md`, whose `Unreleased` section becomes the section of the new version.
+//!
+//! The TOML files are edited in place, keeping their formatting, comments and line
+//! endings. The OTA manifest (`release.json`, see [`super::ota`]) is not among them, as
+//! [`super::release`] generates it from the version of the package.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use log::*;
+use serde_json::Value as JsonValue;
+use toml_edit::{Document, Item, Value};
+
+use super::config::CONFIG_FILE_NAME;
+use super::release::cargo_package;
+use crate::error::HintExt;
+
+const CHANGELOG_FILE: &str = "CHANGELOG.md";
+
+/// The version component to increment.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Level {
+    Patch,
+    Minor,
+    Major,
+}
+
+impl FromStr for Level {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "patch" => Ok(Self::Patch),
+            "minor" => Ok(Self::Minor),
+            "major" => Ok(Self::Major),
+            _ => bail!(
+                "Unknown version level '{}', expected patch, minor or major",
+                s
+            ),
+        }
+    }
+}
+
+/// A completed version bump.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bump {
+    pub old: String,
+    pub new: String,
+    /// The files which were updated.
+    pub files: Vec<PathBuf>,
+}
+
+/// Bump the version of the project in `project_dir` by `level`.
+///
+/// Fails if the project has uncommitted changes, unless `allow_dirty`, so that the bump
+/// can be committed on its own.
+pub fn bump(project_dir: impl AsRef<Path>, level: Level, allow_dirty: bool) -> Result<Bump> {
+    let project_dir = project_dir.as_ref();
+
+    if !allow_dirty {
+        check_clean(project_dir)?;
+    }
+
+    let (name, old) = cargo_package(project_dir)?;
+    let new = bump_version(&old, level)?;
+
+    let mut updates = Vec::new();
+
+    let manifest = project_dir.join("Cargo.toml");
+    let content = fs::read_to_string(&manifest)?;
+    let updated = edit_toml(&content, |document| {
+        if let Some(version) = document
+            .get_mut("package")
+            .and_then(Item::as_table_like_mut)
+            .and_then(|package| package.get_mut("version"))
+            .and_then(Item::as_value_mut)
+        {
+            set_string(version, &new);
+        }
+    })
+    .with_context(|| format!("Failed to parse {}", manifest.display()))?;
+    updates.push((manifest, content, updated));
+
+    let lock = workspace_root(project_dir).join("Cargo.lock");
+    if let Ok(content) = fs::read_to_string(&lock) {
+        let updated = edit_toml(&content, |document| {
+            set_lock_version(document, &name, &old, &new)
+        })
+        .with_context(|| format!("Failed to parse {}", lock.display()))?;
+        updates.push((lock, content, updated));
+    }
+
+    let config = project_dir.join(CONFIG_FILE_NAME);
+    if let Ok(content) = fs::read_to_string(&config) {
+        let updated = edit_toml(&content, |document| set_mcuboot_versions(document, &new))
+            .with_context(|| format!("Failed to parse {}", config.display()))?;
+        updates.push((config, content, updated));
+    }
+
+    let changelog = project_dir.join(CHANGELOG_FILE);
+    if let Ok(content) = fs::read_to_string(&changelog) {
+        match release_changelog(&content, &new, &today()?) {
+            Some(updated) => updates.push((changelog, content, updated)),
+            None => warn!(
+                "{} has no 'Unreleased' section, it is not updated",
+                CHANGELOG_FILE
+            ),
+        }
+    }
+
+    updates.retain(|(_, content, updated)| content != updated);
+
+    let mut written = Vec::new();
+    for (path, content, updated) in &updates {
+        if let Err(err) = fs::write(path, updated) {
+            for (path, content) in written {
+                // Best effort, the original error is the one to report
+                let _ = fs::write(path, content);
+            }
+
+            return Err(Error::from(err).context(format!("Failed to update {}", path.display())));
+        }
+
+        written.push((path, content));
+    }
+
+    info!("Bumped the version from {} to {}", old, new);
+
+    Ok(Bump {
+        old,
+        new,
+        files: updates.into_iter().map(|(path, ..)| path).collect(),
+    })
+}
+
+/// Increment `level` of the semantic version `version`.
+///
+/// Like Cargo's ecosystem tools, a pre-release version is released instead of bumped if
+/// the pre-release is of the bumped level already, e.g. `1.3.0-rc.1` becomes `1.3.0` on a
+/// minor bump.
+pub fn bump_version(version: &str, level: Level) -> Result<String> {
+    let invalid = || {
+        anyhow!(
+            "Invalid version '{}', expected <major>.<minor>.<patch>",
+            version
+        )
+    };
+
+    // The build metadata is dropped
+    let version_core = version.split('+').next().unwrap_or_default();
+    let (release, pre) = match version_core.split_once('-') {
+        Some((release, _)) => (release, true),
+        None => (version_core, false),
+    };
+
+    let parts = release
+        .split('.')
+        .map(|part| part.parse::<u64>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>>>()?;
+
+    let (major, minor, patch) = match parts[..] {
+        [major, minor, patch] => (major, minor, patch),
+        _ => return Err(invalid()),
+    };
+
+    Ok(match level {
+        Level::Patch if pre => format!("{}.{}.{}", major, minor, patch),
+        Level::Patch => format!("{}.{}.{}", major, minor, patch + 1),
+        Level::Minor if pre && patch == 0 => format!("{}.{}.0", major, minor),
+        Level::Minor => format!("{}.{}.0", major, minor + 1),
+        Level::Major if pre && minor == 0 && patch == 0 => format!("{}.0.0", major),
+        Level::Major => format!("{}.0.0", major + 1),
+    })
+}
+
+/// Fail if the git repository of `project_dir` has uncommitted changes.
+fn check_clean(project_dir: &Path) -> Result<()> {
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["status", "--porcelain"])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {
+            let changes = String::from_utf8_lossy(&output.stdout);
+
+            if !changes.trim().is_empty() {
+                return Err(anyhow!(
+                    "The project has uncommitted changes:\n{}",
+                    changes.trim_end()
+                ))
+                .hint("Commit or stash the changes first, so that the bump can be committed on its own");
+            }
+        }
+        _ => debug!("Not a git repository, not checking for uncommitted changes"),
+    }
+
+    Ok(())
+}
+
+/// The root directory of the Cargo workspace of the project in `project_dir`, which
+/// holds its `Cargo.lock`, or `project_dir` if `cargo metadata` fails.
+fn workspace_root(project_dir: &Path) -> PathBuf {
+    let output = Command::new("cargo")
+        .current_dir(project_dir)
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {
+            serde_json::from_slice::<JsonValue>(&output.stdout)
+                .ok()
+                .and_then(|metadata| metadata["workspace_root"].as_str().map(PathBuf::from))
+        }
+        _ => None,
+    }
+    .unwrap_or_else(|| {
+        debug!("cargo metadata failed, looking for Cargo.lock in the project directory");
+        project_dir.to_owned()
+    })
+}
+
+/// Apply `edit` to the TOML document `toml`, keeping its formatting, comments and line
+/// endings.
+fn edit_toml(toml: &str, edit: impl FnOnce(&mut Document)) -> Result<String> {
+    let mut document = toml.parse::<Document>()?;
+    edit(&mut document);
+
+    // The document is written with `\n` line endings only
+    let edited = document.to_string();
+    Ok(if toml.contains("\r\n") {
+        edited.replace("\r\n", "\n").replace('\n', "\r\n")
+    } else {
+        edited
+    })
+}
+
+/// Replace the string `value` with `new`, keeping the comments around it.
+fn set_string(value: &mut Value, new: &str) {
+    if value.is_str() {
+        let decor = value.decor().clone();
+        *value = new.into();
+        *value.decor_mut() = decor;
+    }
+}
+
+/// Set the version of the package `name` of the workspace from `old` to `new` in the
+/// `Cargo.lock` `document`. Packages of registries and git repositories (which have a
+/// `source`) are left alone, even if they have the same name.
+fn set_lock_version(document: &mut Document, name: &str, old: &str, new: &str) {
+    let packages = match document
+        .get_mut("package")
+        .and_then(Item::as_array_of_tables_mut)
+    {
+        Some(packages) => packages,
+        None => return,
+    };
+
+    for package in packages.iter_mut() {
+        let field = |key: &str| package.get(key).and_then(Item::as_str);
+
+        if field("name") == Some(name) && field("version") == Some(old) && field("source").is_none()
+        {
+            if let Some(version) = package.get_mut("version").and_then(Item::as_value_mut) {
+                set_string(version, new);
+            }
+        }
+    }
+}
+
+/// Set the explicitly configured versions of the MCUboot images of all environments in
+/// the `cargo-pio.toml` `document` to `new`.
+fn set_mcuboot_versions(document: &mut Document, new: &str) {
+    let envs = match document.get_mut("env").and_then(Item::as_table_like_mut) {
+        Some(envs) => envs,
+        None => return,
+    };
+
+    for (_, env) in envs.iter_mut() {
+        if let Some(version) = env
+            .as_table_like_mut()
+            .and_then(|env| env.get_mut("mcuboot"))
+            .and_then(Item::as_table_like_mut)
+            .and_then(|mcuboot| mcuboot.get_mut("version"))
+            .and_then(Item::as_value_mut)
+        {
+            set_string(version, new);
+        }
+    }
+}
+
+/// Turn the `Unreleased` section of the changelog `changelog` into the section of
+/// `version`, released on `date`, with a new empty `Unreleased` section above.
+///
+/// Returns `None` if the changelog has no `Unreleased` section.
+fn release_changelog(changelog: &str, version: &str, date: &str) -> Option<String> {
+    let mut result = String::new();
+    let mut found = false;
+
+    for line in changelog.split_inclusive('\n') {
+        let heading = line.trim().trim_start_matches('#').trim();
+
+        if !found
+            && line.starts_with("##")
+            && heading
+                .trim_matches(&['[', ']'][..])
+                .eq_ignore_ascii_case("unreleased")
+        {
+            let level = &line[..line.len() - line.trim_start_matches('#').len()];
+
+            result.push_str(line);
+            result.push_str(&format!("\n{} [{}] - {}\n", level, version, date));
+            found = true;
+
+            continue;
+        }
+
+        result.push_str(line);
+    }
+
+    found.then(|| result)
+}
+
+/// Today's date (UTC) as `YYYY-MM-DD`.
+fn today() -> Result<String> {
+    let days = (SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / 86400) as i64;
+    let (year, month, day) = civil_date(days);
+
+    Ok(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+/// The civil date `(year, month, day)` of `days` since the Unix epoch, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub(crate) fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // Not the `log` one glob-imported by the parent module, which older rustc picks
+    use super::Level;
+
+    #[test]
+    fn test_bump() {
+        assert_eq!(bump_version("1.2.3", Level::Patch).unwrap(), "1.2.4");
+        assert_eq!(bump_version("1.2.3", Level::Minor).unwrap(), "1.3.0");
+        assert_eq!(bump_version("1.2.3+42", Level::Major).unwrap(), "2.0.0");
+        assert_eq!(bump_version("1.3.0-rc.1", Level::Minor).unwrap(), "1.3.0");
+        assert_eq!(bump_version("1.3.1-rc.1", Level::Minor).unwrap(), "1.4.0");
+        assert!(bump_version("1.2", Level::Patch).is_err());
+
+        let edit = |toml: &str| {
+            edit_toml(toml, |document| {
+                set_string(
+                    document["package"]["version"].as_value_mut().unwrap(),
+                    "1.2.4",
+                )
+            })
+            .unwrap()
+        };
+        assert_eq!(
+            edit("[package]\nname = \"app\"\nversion = \"1.2.3\" # the version\n\n[dependencies]\nfoo = { version = \"1\" }\n"),
+            "[package]\nname = \"app\"\nversion = \"1.2.4\" # the version\n\n[dependencies]\nfoo = { version = \"1\" }\n"
+        );
+        assert_eq!(
+            edit("[package]\r\nname = \"app\"\r\nversion = \"1.2.3\"\r\n"),
+            "[package]\r\nname = \"app\"\r\nversion = \"1.2.4\"\r\n"
+        );
+
+        let lock = "[[package]]\r\n\
+                    name = \"app\"\r\n\
+                    version = \"1.2.3\"\r\n\
+                    \r\n\
+                    [[package]]\r\n\
+                    name = \"app\"\r\n\
+                    version = \"1.2.3\"\r\n\
+                    source = \"registry+https://github.com/rust-lang/crates.io-index\"\r\n";
+        assert_eq!(
+            edit_toml(lock, |document| set_lock_version(
+                document, "app", "1.2.3", "1.2.4"
+            ))
+            .unwrap(),
+            lock.replacen("1.2.3", "1.2.4", 1)
+        );
+
+        let config = "[env.debug]\nfeatures = [\"log\"]\n\n\
+                      [env.release.mcuboot]\nslot-size = 0x60000\nversion = \"1.2.3\"\n";
+        assert_eq!(
+            edit_toml(config, |document| set_mcuboot_versions(document, "1.2.4")).unwrap(),
+            config.replace("1.2.3", "1.2.4")
+        );
+
+        assert_eq!(
+            release_changelog(
+                "# Changelog\n\n## [Unreleased]\n- Fix flashing\n\n## [1.2.3] - 2022-01-01\n",
+                "1.2.4",
+                "2022-02-01"
+            )
+            .unwrap(),
+            "# Changelog\n\n## [Unreleased]\n\n## [1.2.4] - 2022-02-01\n- Fix flashing\n\n## [1.2.3] - 2022-01-01\n"
+        );
+        assert_eq!(
+            release_changelog("# Changelog\n", "1.2.4", "2022-02-01"),
+            None
+        );
+    }
+}
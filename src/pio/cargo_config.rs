@@ -0,0 +1,215 @@
+//! The target settings of the Cargo config (`.cargo/config.toml`) of a PIO->Cargo
+//! project.
+//!
+//! Cargo invoked outside of PlatformIO (`cargo check`, `cargo run`, rust-analyzer) misses
+//! what the PlatformIO build passes to it. Every build of an environment keeps a managed
+//! block of the Cargo config up to date with
+//! - the runner of its Rust target, `cargo pio flash`, which builds and flashes the
+//!   firmware instead of running the executable;
+//! - the `target-cpu` of Cortex-M and other ARM targets, from the `-mcpu` of the C flags;
+//! - `CC_<target>` and `AR_<target>` of the toolchain installed by PlatformIO, for the
+//!   `cc` crate of build scripts.
+//!
+//! Tables which the config already defines outside of the managed block are left to the
+//! user. The file is only written when the block changes.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::*;
+use serde_json::Value as JsonValue;
+
+use super::managed::ManagedFile;
+use super::postprocess::{compiler, project_metadata, tool_path};
+use super::Pio;
+use crate::pio_model::env_option;
+
+/// The Cargo config, relative to the project directory.
+pub const CONFIG_FILE: &str = ".cargo/config.toml";
+
+/// The runner of the Rust targets.
+const RUNNER: &str = "cargo pio flash";
+
+/// Update the managed block of the Cargo config of the project in `project_dir` for the
+/// PlatformIO `environment`. Returns whether the config changed.
+pub fn sync(pio: &Pio, project_dir: impl AsRef<Path>, environment: &str) -> Result<bool> {
+    let project_dir = project_dir.as_ref();
+
+    let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))
+        .context("Failed to read platformio.ini")?;
+    let target = match env_option(&platformio_ini, environment, "rust_target") {
+        Some(target) => target,
+        None => return Ok(false),
+    };
+
+    let metadata = project_metadata(pio, project_dir, environment)?;
+
+    let path = project_dir.join(CONFIG_FILE);
+    let current = fs::read_to_string(&path).unwrap_or_default();
+
+    let updated = update(
+        &ManagedFile::new(project_dir, CONFIG_FILE, "#"),
+        &current,
+        &target,
+        &metadata,
+        environment,
+    )?;
+
+    if updated == current {
+        return Ok(false);
+    }
+
+    info!("Updating the target settings of {}", path.display());
+
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(&path, updated)?;
+
+    Ok(true)
+}
+
+/// The Cargo config `current` with its managed block updated for `target` and the
+/// project `metadata` of the PlatformIO `environment`.
+fn update(
+    file: &ManagedFile,
+    current: &str,
+    target: &str,
+    metadata: &JsonValue,
+    environment: &str,
+) -> Result<String> {
+    let unmanaged = file.upsert_block(current, "", |_| false);
+    let unmanaged = toml::from_str::<toml::Value>(&unmanaged)
+        .with_context(|| format!("Failed to parse {}", CONFIG_FILE))?;
+
+    let mut block = toml::value::Table::new();
+
+    if unmanaged
+        .get("target")
+        .and_then(|targets| targets.get(target))
+        .is_some()
+    {
+        debug!("{} configures target {} itself", CONFIG_FILE, target);
+    } else {
+        let mut settings = toml::value::Table::new();
+        settings.insert("runner".into(), RUNNER.into());
+
+        if let Some(cpu) = target_cpu(target, metadata) {
+            settings.insert(
+                "rustflags".into(),
+                vec!["-C".to_owned(), format!("target-cpu={}", cpu)].into(),
+            );
+        }
+
+        let mut targets = toml::value::Table::new();
+        targets.insert(target.into(), settings.into());
+        block.insert("target".into(), targets.into());
+    }
+
+    if unmanaged.get("env").is_some() {
+        debug!("{} configures the environment itself", CONFIG_FILE);
+    } else {
+        match compiler(metadata, environment) {
+            Ok(cc) => {
+                let suffix = target.replace('-', "_");
+                let mut env = toml::value::Table::new();
+
+                env.insert(format!("AR_{}", suffix), path_value(&tool_path(&cc, "ar")?));
+                env.insert(format!("CC_{}", suffix), path_value(&cc));
+
+                block.insert("env".into(), env.into());
+            }
+            Err(err) => warn!(
+                "Not configuring the C toolchain in {}: {:#}",
+                CONFIG_FILE, err
+            ),
+        }
+    }
+
+    let content = if block.is_empty() {
+        String::new()
+    } else {
+        toml::to_string(&block)?
+    };
+
+    // Before the first table, as the block ends with a table of its own
+    Ok(file.upsert_block(current, content, |line| line.trim_start().starts_with('[')))
+}
+
+/// The `target-cpu` of ARM `target`s, from the `-mcpu` flag of the C compiler.
+fn target_cpu(target: &str, metadata: &JsonValue) -> Option<String> {
+    if !(target.starts_with("thumb") || target.starts_with("arm")) {
+        return None;
+    }
+
+    let flags = match &metadata["cc_flags"] {
+        JsonValue::Array(flags) => flags
+            .iter()
+            .filter_map(JsonValue::as_str)
+            .collect::<Vec<_>>()
+            .join(" "),
+        flags => flags.as_str().unwrap_or_default().to_owned(),
+    };
+
+    flags
+        .split_whitespace()
+        .find_map(|flag| flag.strip_prefix("-mcpu="))
+        .map(str::to_owned)
+}
+
+fn path_value(path: &Path) -> toml::Value {
+    path.to_string_lossy().into_owned().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update() {
+        let file = ManagedFile::new(".", CONFIG_FILE, "#");
+        let metadata = serde_json::json!({
+            "cc_path": "/pio/packages/toolchain-gccarmnoneeabi/bin/arm-none-eabi-gcc",
+            "cc_flags": ["-mthumb", "-mcpu=cortex-m4"],
+        });
+
+        let current = "[build]\ntarget = \"thumbv7em-none-eabihf\"\n";
+        let updated = update(&file, current, "thumbv7em-none-eabihf", &metadata, "debug").unwrap();
+
+        let config = toml::from_str::<toml::Value>(&updated).unwrap();
+        let settings = &config["target"]["thumbv7em-none-eabihf"];
+        assert_eq!(settings["runner"].as_str(), Some(RUNNER));
+        assert_eq!(
+            settings["rustflags"],
+            toml::Value::from(vec!["-C", "target-cpu=cortex-m4"])
+        );
+        assert_eq!(
+            config["env"]["AR_thumbv7em_none_eabihf"].as_str(),
+            Some("/pio/packages/toolchain-gccarmnoneeabi/bin/arm-none-eabi-ar")
+        );
+        assert_eq!(
+            config["build"]["target"].as_str(),
+            Some("thumbv7em-none-eabihf")
+        );
+
+        // Idempotent
+        assert_eq!(
+            update(&file, &updated, "thumbv7em-none-eabihf", &metadata, "debug").unwrap(),
+            updated
+        );
+
+        // The environment of the user stays, the block only keeps the target
+        let current = format!("{}\n[env]\nFOO = \"bar\"\n", updated);
+        let updated = update(&file, &current, "thumbv7em-none-eabihf", &metadata, "debug").unwrap();
+        let config = toml::from_str::<toml::Value>(&updated).unwrap();
+        assert_eq!(
+            config["env"].as_table().unwrap().keys().collect::<Vec<_>>(),
+            ["FOO"]
+        );
+        assert!(config["target"]["thumbv7em-none-eabihf"]
+            .get("runner")
+            .is_some());
+
+        // No target-cpu for other targets
+        assert_eq!(target_cpu("riscv32imc-esp-espidf", &metadata), None);
+    }
+}
@@ -0,0 +1,264 @@
+//! The release notes of installed platforms and packages between their installed and
+//! latest versions, to decide whether to upgrade.
+//!
+//! The notes are the releases of the repository of the platform or package on GitHub:
+//! the repository is taken from its manifest (`platform.json` or `package.json`) unless
+//! given, and the releases are matched to versions by their tags (`v6.5.0` or `6.5.0`).
+//! Releases whose tags are no versions are skipped.
+
+use std::cmp::Ordering;
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::Deserialize;
+
+use super::Pio;
+use crate::error::HintExt;
+use crate::pio_model::compare_versions;
+use crate::utils::HttpClient;
+
+const GITHUB_API_URL: &str = "https://api.github.com";
+
+/// A release of a platform or package.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Release {
+    pub version: String,
+    /// The title of the release, if it has one.
+    pub name: Option<String>,
+    /// The date of the release, e.g. `2023-12-01`.
+    pub date: String,
+    pub notes: String,
+    pub url: String,
+}
+
+/// The releases of a platform or package after its installed version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Changelog {
+    pub name: String,
+    pub installed: String,
+    /// The releases, newest first.
+    pub releases: Vec<Release>,
+}
+
+impl Changelog {
+    /// The changelog for humans.
+    pub fn render(&self) -> String {
+        let latest = match self.releases.first() {
+            Some(latest) => latest,
+            None => return format!("{} {} is up to date\n", self.name, self.installed),
+        };
+
+        let mut changelog = format!(
+            "{} {} -> {} ({} release(s))\n",
+            self.name,
+            self.installed,
+            latest.version,
+            self.releases.len()
+        );
+
+        for release in &self.releases {
+            changelog.push_str(&format!("\n## {} ({})", release.version, release.date));
+
+            if let Some(name) = &release.name {
+                changelog.push_str(&format!(" - {}", name));
+            }
+
+            changelog.push_str(&format!("\n{}\n\n", release.url));
+
+            let notes = release.notes.trim();
+            if notes.is_empty() {
+                changelog.push_str("No release notes.\n");
+            } else {
+                for line in notes.lines() {
+                    changelog.push_str(line.trim_end());
+                    changelog.push('\n');
+                }
+            }
+        }
+
+        changelog
+    }
+}
+
+/// Fetch the changelog of the installed platform or package `name` with `client`, from
+/// `repository` (a GitHub URL) or else the repository of its manifest.
+pub fn fetch(
+    client: &dyn HttpClient,
+    pio: &Pio,
+    name: &str,
+    repository: Option<&str>,
+) -> Result<Changelog> {
+    let (installed, manifest_repository) = installed(pio, name)?;
+
+    let repository = repository
+        .map(str::to_owned)
+        .or(manifest_repository)
+        .ok_or_else(|| anyhow!("The manifest of {} names no repository", name))
+        .hint("Specify the GitHub repository with --repository")?;
+
+    let (owner, repo) = github_repository(&repository)
+        .ok_or_else(|| anyhow!("{} is no GitHub repository", repository))
+        .hint("Specify the GitHub repository with --repository")?;
+
+    let url = format!(
+        "{}/repos/{}/{}/releases?per_page=100",
+        GITHUB_API_URL, owner, repo
+    );
+
+    debug!("Fetching {}", url);
+
+    let mut response = Vec::new();
+    client
+        .download(&url, &mut response)
+        .with_context(|| format!("Failed to fetch the releases of {}/{}", owner, repo))?;
+
+    Ok(Changelog {
+        name: name.to_owned(),
+        releases: parse_releases(&response, &installed)?,
+        installed,
+    })
+}
+
+/// The installed version of the platform or package `name`, and the repository URL of
+/// its manifest.
+fn installed(pio: &Pio, name: &str) -> Result<(String, Option<String>)> {
+    let platform = pio
+        .core_dir
+        .join("platforms")
+        .join(name)
+        .join("platform.json");
+
+    let manifest = if platform.is_file() {
+        platform
+    } else {
+        pio.package(name, None)
+            .ok_or_else(|| anyhow!("Neither a platform nor a package {} is installed", name))?
+            .dir
+            .join("package.json")
+    };
+
+    let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(&manifest)?)
+        .with_context(|| format!("Failed to parse {}", manifest.display()))?;
+
+    let repository = match &manifest["repository"] {
+        serde_json::Value::String(url) => Some(url.clone()),
+        repository => repository["url"].as_str().map(str::to_owned),
+    }
+    .or_else(|| manifest["homepage"].as_str().map(str::to_owned));
+
+    Ok((
+        manifest["version"].as_str().unwrap_or_default().to_owned(),
+        repository,
+    ))
+}
+
+/// The owner and name of the GitHub repository `url`, e.g. of
+/// `https://github.com/platformio/platform-espressif32.git` or
+/// `git@github.com:platformio/platform-espressif32.git`.
+fn github_repository(url: &str) -> Option<(String, String)> {
+    let (_, path) = url
+        .split_once("github.com/")
+        .or_else(|| url.split_once("github.com:"))?;
+
+    let mut segments = path.trim_end_matches('/').split('/');
+    let owner = segments.next().filter(|owner| !owner.is_empty())?;
+    let repo = segments.next()?.trim_end_matches(".git");
+
+    (!repo.is_empty()).then(|| (owner.to_owned(), repo.to_owned()))
+}
+
+#[derive(Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    name: Option<String>,
+    body: Option<String>,
+    published_at: Option<String>,
+    html_url: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+}
+
+/// The releases of the GitHub releases `json` after the version `installed`, newest
+/// first.
+fn parse_releases(json: &[u8], installed: &str) -> Result<Vec<Release>> {
+    let releases: Vec<GithubRelease> =
+        serde_json::from_slice(json).context("Failed to parse the releases")?;
+
+    let mut releases = releases
+        .into_iter()
+        .filter(|release| !release.draft && !release.prerelease)
+        .filter_map(|release| {
+            let version = release.tag_name.trim_start_matches('v');
+
+            if !version.starts_with(|c: char| c.is_ascii_digit())
+                || compare_versions(version, installed) != Ordering::Greater
+            {
+                return None;
+            }
+
+            Some(Release {
+                version: version.to_owned(),
+                name: release.name.filter(|name| {
+                    !name.trim().is_empty() && name.trim_start_matches('v') != version
+                }),
+                date: release
+                    .published_at
+                    .unwrap_or_default()
+                    .split('T')
+                    .next()
+                    .unwrap_or_default()
+                    .to_owned(),
+                notes: release.body.unwrap_or_default().replace("\r\n", "\n"),
+                url: release.html_url,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    releases.sort_by(|a, b| compare_versions(&b.version, &a.version));
+
+    Ok(releases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changelog() {
+        assert_eq!(
+            github_repository("git@github.com:platformio/platform-espressif32.git"),
+            Some(("platformio".into(), "platform-espressif32".into()))
+        );
+        assert_eq!(github_repository("https://gitlab.com/owner/repo"), None);
+
+        let releases = parse_releases(
+            br#"[
+                {"tag_name": "v6.5.0", "name": "6.5.0", "body": "* ESP-IDF v5.1.2\r\n* Fixes", "published_at": "2023-12-01T10:00:00Z", "html_url": "https://github.com/r/v6.5.0"},
+                {"tag_name": "v6.6.0-rc1", "name": null, "body": "", "published_at": null, "html_url": "u", "prerelease": true},
+                {"tag_name": "v6.4.0", "name": "Winter", "body": null, "published_at": "2023-09-01T10:00:00Z", "html_url": "https://github.com/r/v6.4.0"},
+                {"tag_name": "v6.3.0", "name": "", "body": "", "published_at": null, "html_url": "u"},
+                {"tag_name": "nightly", "name": "", "body": "", "published_at": null, "html_url": "u"}
+            ]"#,
+            "6.3.0",
+        )
+        .unwrap();
+
+        let changelog = Changelog {
+            name: "espressif32".into(),
+            installed: "6.3.0".into(),
+            releases,
+        };
+
+        assert_eq!(
+            changelog.render(),
+            "espressif32 6.3.0 -> 6.5.0 (2 release(s))\n\
+             \n## 6.5.0 (2023-12-01)\nhttps://github.com/r/v6.5.0\n\n\
+             * ESP-IDF v5.1.2\n* Fixes\n\
+             \n## 6.4.0 (2023-09-01) - Winter\nhttps://github.com/r/v6.4.0\n\n\
+             No release notes.\n"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Generation of CI pipeline definitions for PIO->Cargo projects.
+//!
+//! The generated pipelines fetch the PlatformIO packages and Cargo dependencies, build
+//! the project, check its link and report the firmware size, caching PlatformIO's core
+//! directory (packages & platforms) and Cargo's registry between runs.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+
+use super::managed::{ManagedFile, Outcome};
+
+const GITHUB_YML: &str = include_str!("resources/ci-github.yml.resource");
+const GITLAB_YML: &str = include_str!("resources/ci-gitlab.yml.resource");
+
+const ENVIRONMENT_PLACEHOLDER: &str = "@ENVIRONMENT@";
+
+/// A CI provider.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Provider {
+    Github,
+    Gitlab,
+}
+
+impl Provider {
+    /// The path of the pipeline definition, relative to the project directory.
+    pub fn path(&self) -> &'static str {
+        match self {
+            Self::Github => ".github/workflows/cargo-pio.yml",
+            Self::Gitlab => ".gitlab-ci.yml",
+        }
+    }
+
+    /// The pipeline definition building the PlatformIO `environment`.
+    pub fn pipeline(&self, environment: impl AsRef<str>) -> String {
+        let template = match self {
+            Self::Github => GITHUB_YML,
+            Self::Gitlab => GITLAB_YML,
+        };
+
+        template.replace(ENVIRONMENT_PLACEHOLDER, environment.as_ref())
+    }
+
+    /// Write the pipeline definition building the PlatformIO `environment` into the
+    /// project at `project_dir`, merging it with any edits made to a previously
+    /// generated one.
+    pub fn generate(
+        &self,
+        project_dir: impl AsRef<Path>,
+        environment: impl AsRef<str>,
+    ) -> Result<Outcome> {
+        let file = ManagedFile::for_path(project_dir, self.path());
+
+        file.write(file.block(self.pipeline(environment)))
+    }
+
+    /// The diff of the change [`Self::generate`] would make, `None` if the pipeline
+    /// definition is up-to-date.
+    pub fn diff(
+        &self,
+        project_dir: impl AsRef<Path>,
+        environment: impl AsRef<str>,
+    ) -> Result<Option<String>> {
+        let file = ManagedFile::for_path(project_dir, self.path());
+
+        file.diff(file.block(self.pipeline(environment)))
+    }
+}
+
+impl FromStr for Provider {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "github" => Self::Github,
+            "gitlab" => Self::Gitlab,
+            _ => bail!("Unknown CI provider '{}', expected 'github' or 'gitlab'", s),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        assert_eq!("github".parse::<Provider>().unwrap(), Provider::Github);
+        assert!("jenkins".parse::<Provider>().is_err());
+
+        for provider in [Provider::Github, Provider::Gitlab] {
+            let pipeline = provider.pipeline("release");
+            assert!(!pipeline.contains(ENVIRONMENT_PLACEHOLDER));
+            assert!(pipeline.contains("release"));
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let provider = Provider::Github;
+        let path = dir.path().join(provider.path());
+
+        assert!(provider.diff(dir.path(), "debug").unwrap().is_some());
+        assert_eq!(
+            provider.generate(dir.path(), "debug").unwrap(),
+            Outcome::Created
+        );
+        assert!(provider.diff(dir.path(), "debug").unwrap().is_none());
+
+        // Edits after the generated block survive the regeneration
+        let mut edited = fs::read_to_string(&path).unwrap();
+        edited.push_str("# Deployed by the release job\n");
+        fs::write(&path, &edited).unwrap();
+
+        assert_eq!(
+            provider.generate(dir.path(), "release").unwrap(),
+            Outcome::Updated
+        );
+        let generated = fs::read_to_string(&path).unwrap();
+        assert!(generated.ends_with("# Deployed by the release job\n"));
+        assert!(generated.contains(&provider.pipeline("release")));
+    }
+}
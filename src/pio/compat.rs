@@ -0,0 +1,490 @@
+//! The compatibility matrix of the ESP32 platform, its frameworks and the GCC toolchain.
+//!
+//! Every release of the `espressif32` platform is made for one ESP-IDF and one Arduino
+//! core version, which in turn need the GCC version the platform installs: pinning
+//! only one of them in `platformio.ini` (e.g. a newer `framework-espidf` in
+//! `platform_packages`) fails in the middle of the build, with errors about unknown
+//! compiler flags or missing headers. [`resolve`] finds the [`Release`] of a requested
+//! framework version, [`pin`] pins the platform, framework and toolchain of it together,
+//! and [`check`] reports the pins of an environment that do not fit together.
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use log::*;
+
+use super::{board, Pio};
+use crate::error::HintExt;
+use crate::pio_model::{env_option, list, set_env_option};
+
+/// A framework of the ESP32 platform.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Framework {
+    Espidf,
+    Arduino,
+}
+
+impl Framework {
+    /// The PlatformIO package of the framework.
+    pub fn package(&self) -> &'static str {
+        match self {
+            Self::Espidf => "framework-espidf",
+            Self::Arduino => "framework-arduinoespressif32",
+        }
+    }
+}
+
+impl FromStr for Framework {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "espidf" => Ok(Self::Espidf),
+            "arduino" => Ok(Self::Arduino),
+            _ => bail!("Unknown framework '{}'", s),
+        }
+    }
+}
+
+impl Display for Framework {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Espidf => "ESP-IDF",
+            Self::Arduino => "Arduino core",
+        })
+    }
+}
+
+/// A release of the `espressif32` platform and the versions it is made for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Release {
+    pub platform: &'static str,
+    pub espidf: &'static str,
+    pub arduino: &'static str,
+    /// The version of the `framework-arduinoespressif32` package, which carries a
+    /// build date.
+    arduino_package: &'static str,
+    /// The version of the toolchain packages, e.g. `12.2.0+20230208` for GCC 12.2.0.
+    pub toolchain: &'static str,
+}
+
+/// The releases, newest first.
+pub const RELEASES: &[Release] = &[
+    Release {
+        platform: "6.5.0",
+        espidf: "5.1.2",
+        arduino: "2.0.14",
+        arduino_package: "3.20014.231204",
+        toolchain: "12.2.0+20230208",
+    },
+    Release {
+        platform: "6.4.0",
+        espidf: "5.1.1",
+        arduino: "2.0.11",
+        arduino_package: "3.20011.230801",
+        toolchain: "12.2.0+20230208",
+    },
+    Release {
+        platform: "6.3.0",
+        espidf: "5.0.2",
+        arduino: "2.0.9",
+        arduino_package: "3.20009.0",
+        toolchain: "11.2.0+2022r1",
+    },
+    Release {
+        platform: "6.0.0",
+        espidf: "5.0.0",
+        arduino: "2.0.6",
+        arduino_package: "3.20006.221224",
+        toolchain: "11.2.0+2022r1",
+    },
+    Release {
+        platform: "5.2.0",
+        espidf: "4.4.2",
+        arduino: "2.0.5",
+        arduino_package: "3.20005.220925",
+        toolchain: "8.4.0+2021r2-patch5",
+    },
+    Release {
+        platform: "5.0.0",
+        espidf: "4.4.1",
+        arduino: "2.0.3",
+        arduino_package: "3.20003.220626",
+        toolchain: "8.4.0+2021r2-patch3",
+    },
+    Release {
+        platform: "4.0.0",
+        espidf: "4.3.2",
+        arduino: "2.0.2",
+        arduino_package: "3.20002.220503",
+        toolchain: "8.4.0+2021r2-patch3",
+    },
+];
+
+impl Release {
+    /// The version of `framework` the release is made for.
+    pub fn framework(&self, framework: Framework) -> &'static str {
+        match framework {
+            Framework::Espidf => self.espidf,
+            Framework::Arduino => self.arduino,
+        }
+    }
+
+    /// The version of the package of `framework`, e.g. `3.50102.0` for ESP-IDF 5.1.2.
+    pub fn framework_package(&self, framework: Framework) -> String {
+        match framework {
+            Framework::Espidf => {
+                let mut numbers = self
+                    .espidf
+                    .split('.')
+                    .map(|number| number.parse::<u32>().unwrap_or_default());
+                let mut number = || numbers.next().unwrap_or_default();
+
+                format!("3.{}{:02}{:02}.0", number(), number(), number())
+            }
+            Framework::Arduino => self.arduino_package.to_owned(),
+        }
+    }
+
+    /// The GCC version of the toolchain.
+    pub fn gcc(&self) -> &'static str {
+        gcc(self.toolchain)
+    }
+
+    /// The `platform` and the `platform_packages` of an environment of `framework` for
+    /// the chip `mcu`.
+    pub fn pins(&self, framework: Framework, mcu: &str) -> Result<(String, Vec<String>)> {
+        Ok((
+            format!("platformio/espressif32@{}", self.platform),
+            vec![
+                format!(
+                    "platformio/{}@{}",
+                    framework.package(),
+                    self.framework_package(framework)
+                ),
+                format!("platformio/{}@{}", toolchain_package(mcu)?, self.toolchain),
+            ],
+        ))
+    }
+}
+
+/// The GCC version of the toolchain package version `version`.
+fn gcc(version: &str) -> &str {
+    version.split('+').next().unwrap_or_default()
+}
+
+/// The toolchain package of the chip `mcu`, e.g. `toolchain-xtensa-esp32s3`.
+pub fn toolchain_package(mcu: &str) -> Result<&'static str> {
+    Ok(match mcu.to_ascii_lowercase().as_str() {
+        "esp32" => "toolchain-xtensa-esp32",
+        "esp32s2" => "toolchain-xtensa-esp32s2",
+        "esp32s3" => "toolchain-xtensa-esp32s3",
+        mcu if mcu.starts_with("esp32c") || mcu.starts_with("esp32h") => "toolchain-riscv32-esp",
+        mcu => bail!("{} is no ESP32 chip", mcu),
+    })
+}
+
+/// The newest release made for `version` of `framework`, e.g. `5.1` or `5.1.2`.
+pub fn resolve(framework: Framework, version: &str) -> Result<&'static Release> {
+    let version = version.trim_start_matches('v');
+
+    RELEASES
+        .iter()
+        .find(|release| {
+            let candidate = release.framework(framework);
+            candidate == version || candidate.starts_with(&format!("{}.", version))
+        })
+        .ok_or_else(|| anyhow!("No platform release is made for {} {}", framework, version))
+        .with_hint(|| {
+            format!(
+                "Known {} versions: {}",
+                framework,
+                RELEASES
+                    .iter()
+                    .map(|release| release.framework(framework))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+/// The compatibility matrix for humans.
+pub fn table() -> String {
+    let mut table = format!(
+        "{:<10} {:<9} {:<14} {}\n",
+        "PLATFORM", "ESP-IDF", "ARDUINO CORE", "GCC"
+    );
+
+    for release in RELEASES {
+        table.push_str(&format!(
+            "{:<10} {:<9} {:<14} {}\n",
+            release.platform,
+            release.espidf,
+            release.arduino,
+            release.gcc()
+        ));
+    }
+
+    table
+}
+
+/// Pin the `platform` and `platform_packages` of `release` for `framework` and the chip
+/// `mcu` in the PlatformIO `environment` of `platformio_ini`.
+///
+/// Other pinned packages than the ones of frameworks and toolchains are kept.
+pub fn pin(
+    platformio_ini: &str,
+    environment: &str,
+    release: &Release,
+    framework: Framework,
+    mcu: &str,
+) -> Result<String> {
+    let (platform, mut packages) = release.pins(framework, mcu)?;
+
+    packages.extend(
+        env_option(platformio_ini, environment, "platform_packages")
+            .map(|packages| list(&packages))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|package| {
+                let name = package_name(package);
+                !name.starts_with("framework-") && !name.starts_with("toolchain-")
+            }),
+    );
+
+    let platformio_ini = set_env_option(platformio_ini, environment, "platform", &platform)
+        .ok_or_else(|| anyhow!("platformio.ini has no environment {}", environment))?;
+
+    Ok(set_env_option(
+        &platformio_ini,
+        environment,
+        "platform_packages",
+        &packages.join("\n"),
+    )
+    .unwrap_or(platformio_ini))
+}
+
+/// Pin the versions compatible with `version` of `framework` in the PlatformIO
+/// `environment` of the project in `project_dir`, and install them.
+///
+/// The chip of the board of the environment selects the toolchain.
+pub fn pin_environment(
+    pio: &Pio,
+    project_dir: &Path,
+    environment: &str,
+    framework: Framework,
+    version: &str,
+) -> Result<&'static Release> {
+    let release = resolve(framework, version)?;
+
+    let (id, definition) = board::definition(pio, project_dir, environment)?
+        .ok_or_else(|| anyhow!("Environment {} has no board", environment))?;
+    let mcu = definition["build"]["mcu"]
+        .as_str()
+        .ok_or_else(|| anyhow!("The definition of board {} names no chip", id))?;
+
+    let path = project_dir.join("platformio.ini");
+    let platformio_ini = fs::read_to_string(&path).context("Failed to read platformio.ini")?;
+
+    fs::write(
+        &path,
+        pin(&platformio_ini, environment, release, framework, mcu)?,
+    )?;
+
+    info!(
+        "Pinned espressif32@{} with {} {} and GCC {} in environment {}",
+        release.platform,
+        framework,
+        release.framework(framework),
+        release.gcc(),
+        environment
+    );
+
+    let mut cmd = pio.cmd();
+    cmd.arg("pkg")
+        .arg("install")
+        .arg("-d")
+        .arg(project_dir)
+        .arg("-e")
+        .arg(environment);
+
+    pio.exec(&mut cmd)?;
+
+    Ok(release)
+}
+
+/// The pins of the PlatformIO `environment` of `platformio_ini` which do not fit
+/// together, as messages. Environments without pins are not checked, the platform
+/// installs consistent versions then.
+pub fn check(platformio_ini: &str, environment: &str) -> Vec<String> {
+    let platform = env_option(platformio_ini, environment, "platform")
+        .and_then(|platform| pinned_version(&platform));
+
+    let packages = env_option(platformio_ini, environment, "platform_packages")
+        .map(|packages| list(&packages))
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|package| Some((package_name(package).to_owned(), pinned_version(package)?)))
+        .collect::<Vec<_>>();
+
+    // The releases the pins have to fit, described by what they are required by
+    let mut required = Vec::new();
+
+    for framework in [Framework::Espidf, Framework::Arduino] {
+        if let Some((_, version)) = packages
+            .iter()
+            .find(|(name, _)| name == framework.package())
+        {
+            let version = framework_version(version);
+            let requirement = format!("{} {}", framework, version);
+
+            match RELEASES
+                .iter()
+                .find(|release| release.framework(framework) == version)
+            {
+                Some(release) => required.push((requirement, release)),
+                None => warn!("{} is not in the compatibility matrix", requirement),
+            }
+        }
+    }
+
+    if required.is_empty() {
+        if let Some(platform) = &platform {
+            match RELEASES
+                .iter()
+                .find(|release| release.platform == platform.as_str())
+            {
+                Some(release) => required.push((format!("espressif32@{}", platform), release)),
+                None => debug!(
+                    "espressif32@{} is not in the compatibility matrix",
+                    platform
+                ),
+            }
+        }
+    }
+
+    let mut problems = Vec::new();
+
+    for (requirement, release) in required {
+        if let Some(platform) = platform
+            .as_deref()
+            .filter(|platform| *platform != release.platform)
+        {
+            problems.push(format!(
+                "{} needs platform espressif32@{}, but the platform is pinned to {}",
+                requirement, release.platform, platform
+            ));
+        }
+
+        for (name, version) in &packages {
+            if name.starts_with("toolchain-") && gcc(version) != release.gcc() {
+                problems.push(format!(
+                    "{} needs GCC {} ({}@{}), but {} is pinned to {}",
+                    requirement,
+                    release.gcc(),
+                    name,
+                    release.toolchain,
+                    name,
+                    version
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// The name of the package `package` of `platform_packages`, e.g. `toolchain-riscv32-esp`
+/// of `platformio/toolchain-riscv32-esp @ 12.2.0+20230208`.
+pub(crate) fn package_name(package: &str) -> &str {
+    package
+        .split('@')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+}
+
+/// The version `package` (or a `platform`) is pinned to, e.g. `6.5.0` of
+/// `platformio/espressif32 @ ~6.5.0`. `None` for packages from URLs and unpinned ones.
+fn pinned_version(package: &str) -> Option<String> {
+    if package.contains("://") {
+        return None;
+    }
+
+    let (_, version) = package.split_once('@')?;
+    let version = version.trim().trim_start_matches(['^', '~', '=']);
+
+    (!version.is_empty()).then(|| version.to_owned())
+}
+
+/// The framework version of the framework package version `version`, e.g. `5.1.2` of
+/// `3.50102.0`.
+fn framework_version(version: &str) -> String {
+    match version
+        .split('.')
+        .nth(1)
+        .and_then(|number| number.parse::<u32>().ok())
+    {
+        Some(number) => format!("{}.{}.{}", number / 10000, number / 100 % 100, number % 100),
+        None => version.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compat() {
+        let release = resolve(Framework::Espidf, "v5.1").unwrap();
+        assert_eq!(release.platform, "6.5.0");
+        assert_eq!(release.framework_package(Framework::Espidf), "3.50102.0");
+        assert_eq!(release.gcc(), "12.2.0");
+        assert_eq!(
+            resolve(Framework::Arduino, "2.0.5").unwrap().platform,
+            "5.2.0"
+        );
+        assert!(resolve(Framework::Espidf, "5.2").is_err());
+        assert!(resolve(Framework::Espidf, "5.1.22").is_err());
+
+        let ini = "[env:debug]\n\
+                   platform = espressif32\n\
+                   platform_packages =\n    \
+                   platformio/toolchain-xtensa-esp32@8.4.0+2021r2-patch5\n    \
+                   platformio/tool-openocd-esp32@2.1100.20220706\n\
+                   \n\
+                   [env:release]\n\
+                   platform = espressif32@5.2.0\n\
+                   platform_packages = platformio/framework-espidf @ ~3.50102.0, toolchain-xtensa-esp32@8.4.0+2021r2-patch5\n";
+
+        assert_eq!(check(ini, "debug"), Vec::<String>::new());
+        assert_eq!(
+            check(ini, "release"),
+            vec![
+                "ESP-IDF 5.1.2 needs platform espressif32@6.5.0, but the platform is pinned to 5.2.0",
+                "ESP-IDF 5.1.2 needs GCC 12.2.0 (toolchain-xtensa-esp32@12.2.0+20230208), but toolchain-xtensa-esp32 is pinned to 8.4.0+2021r2-patch5",
+            ]
+        );
+
+        let ini = pin(ini, "debug", release, Framework::Espidf, "esp32").unwrap();
+        assert_eq!(
+            ini.split("\n\n").next().unwrap(),
+            "[env:debug]\n\
+             platform = platformio/espressif32@6.5.0\n\
+             platform_packages =\n    \
+             platformio/framework-espidf@3.50102.0\n    \
+             platformio/toolchain-xtensa-esp32@12.2.0+20230208\n    \
+             platformio/tool-openocd-esp32@2.1100.20220706"
+        );
+        assert_eq!(check(&ini, "debug"), Vec::<String>::new());
+
+        assert!(pin(&ini, "test", release, Framework::Espidf, "esp32").is_err());
+        assert!(toolchain_package("rp2040").is_err());
+    }
+}
@@ -0,0 +1,186 @@
+//! Compiler caches (ccache, sccache) for the C/C++ side of PIO->Cargo builds.
+//!
+//! The launcher configured in the `[compiler-cache]` section of `cargo-pio.toml` is
+//! passed to the build as `CARGO_PIO_CC_LAUNCHER`, which `platformio.cargo.py` prepends
+//! to the C and C++ compilers of the project, its PlatformIO libraries and the framework
+//! libraries built through PlatformIO's library builders. Frameworks which set up their
+//! own build environments in the platform's build script are not covered.
+//!
+//! The statistics of the cache are zeroed before every build, so that the hit rate
+//! reported after the build is the one of the build.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use serde_json::Value as JsonValue;
+
+use super::config::{CompilerCacheConfig, Launcher};
+use crate::error::HintExt;
+
+/// The environment variable the launcher is passed to the build in.
+pub const VAR_CC_LAUNCHER: &str = "CARGO_PIO_CC_LAUNCHER";
+
+/// A configured compiler cache.
+#[derive(Clone, Debug)]
+pub struct CompilerCache {
+    pub launcher: Launcher,
+    pub exe: PathBuf,
+}
+
+/// The statistics of a build.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Stats {
+    /// The share of the cacheable compilations which were cache hits.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
+
+impl CompilerCache {
+    /// Resolve the compiler cache configured in `config`.
+    pub fn new(config: &CompilerCacheConfig) -> Result<Self> {
+        let exe = match &config.path {
+            Some(path) => path.clone(),
+            None => which::which(config.launcher.name())
+                .with_hint(|| {
+                    format!(
+                        "Install {} or set 'path' in [compiler-cache]",
+                        config.launcher.name()
+                    )
+                })
+                .with_context(|| format!("{} not found in PATH", config.launcher.name()))?,
+        };
+
+        Ok(Self {
+            launcher: config.launcher,
+            exe,
+        })
+    }
+
+    /// Apply the compiler cache to the `pio run` command `cmd`.
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.env(VAR_CC_LAUNCHER, &self.exe);
+    }
+
+    /// Zero the statistics of the cache, before a build.
+    pub fn zero_stats(&self) -> Result<()> {
+        let zero = match self.launcher {
+            Launcher::Ccache => "-z",
+            Launcher::Sccache => "--zero-stats",
+        };
+
+        let output = Command::new(&self.exe).arg(zero).output()?;
+        if !output.status.success() {
+            bail!(
+                "Zeroing the statistics of {} failed: {}",
+                self.launcher.name(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        info!("Using {} {}", self.launcher.name(), self.exe.display());
+
+        Ok(())
+    }
+
+    /// The statistics since the last [`Self::zero_stats`].
+    pub fn stats(&self) -> Result<Stats> {
+        let args: &[&str] = match self.launcher {
+            Launcher::Ccache => &["--print-stats"],
+            Launcher::Sccache => &["--show-stats", "--stats-format", "json"],
+        };
+
+        let output = Command::new(&self.exe).args(args).output()?;
+        if !output.status.success() {
+            bail!(
+                "Querying the statistics of {} failed: {}",
+                self.launcher.name(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let output = String::from_utf8_lossy(&output.stdout);
+
+        match self.launcher {
+            Launcher::Ccache => Ok(parse_ccache_stats(&output)),
+            Launcher::Sccache => parse_sccache_stats(&output),
+        }
+    }
+}
+
+/// Parse the machine-readable statistics of `ccache --print-stats` (tab-separated
+/// `<name>\t<value>` lines).
+fn parse_ccache_stats(output: &str) -> Stats {
+    let mut stats = Stats::default();
+
+    for line in output.lines() {
+        let (name, value) = match line.split_once('\t') {
+            Some((name, value)) => (name, value.trim().parse::<u64>().unwrap_or_default()),
+            None => continue,
+        };
+
+        match name {
+            "direct_cache_hit" | "preprocessed_cache_hit" => stats.hits += value,
+            "cache_miss" => stats.misses += value,
+            _ => (),
+        }
+    }
+
+    stats
+}
+
+/// Parse the JSON statistics of `sccache --show-stats --stats-format json`, which
+/// count the hits and misses per language.
+fn parse_sccache_stats(output: &str) -> Result<Stats> {
+    let stats: JsonValue = serde_json::from_str(output)?;
+
+    let count = |name: &str| -> u64 {
+        stats["stats"][name]["counts"]
+            .as_object()
+            .map(|counts| counts.values().filter_map(JsonValue::as_u64).sum())
+            .unwrap_or_default()
+    };
+
+    Ok(Stats {
+        hits: count("cache_hits"),
+        misses: count("cache_misses"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stats() {
+        assert_eq!(
+            parse_ccache_stats(
+                "stats_updated_timestamp\t1660000000\ndirect_cache_hit\t120\npreprocessed_cache_hit\t3\ncache_miss\t7\n"
+            ),
+            Stats {
+                hits: 123,
+                misses: 7
+            }
+        );
+
+        assert_eq!(
+            parse_sccache_stats(
+                r#"{"stats":{"compile_requests":40,"cache_hits":{"counts":{"C/C++":30}},"cache_misses":{"counts":{"C/C++":8,"Rust":2}}}}"#
+            )
+            .unwrap(),
+            Stats {
+                hits: 30,
+                misses: 10
+            }
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Fast listings for shell completion scripts and editor plugins.
+//!
+//! Completions need answers within a fraction of a second, which rules out starting
+//! PlatformIO (or even its installer check). The listings here read what is on disk
+//! instead:
+//! - the environments from the `[env:<name>]` sections of `platformio.ini`;
+//! - the boards from the board definitions of the installed platforms in the PlatformIO
+//!   core directory and of the project's `boards` directory;
+//! - the serial ports from `/dev` on Unix, and elsewhere from the ports PlatformIO
+//!   listed last, which [`Pio::serial_devices`] caches in the core directory, followed
+//!   by the ports of the PIO Remote devices listed last (see [`super::account`]).
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::*;
+
+use super::{Pio, SerialDevice};
+
+pub use crate::pio_model::environments;
+
+const PORTS_CACHE_FILE: &str = "cargo-pio-ports.txt";
+const REMOTE_PORTS_CACHE_FILE: &str = "cargo-pio-remote-ports.txt";
+
+/// The PlatformIO core directory `pio_dir`, or the default one (`$PLATFORMIO_CORE_DIR`
+/// or `~/.platformio`).
+pub fn core_dir(pio_dir: Option<impl AsRef<Path>>) -> Option<PathBuf> {
+    pio_dir
+        .map(|pio_dir| pio_dir.as_ref().to_owned())
+        .or_else(|| env::var_os("PLATFORMIO_CORE_DIR").map(PathBuf::from))
+        .or_else(|| {
+            env::var_os("HOME")
+                .or_else(|| env::var_os("USERPROFILE"))
+                .map(|home| PathBuf::from(home).join(".platformio"))
+        })
+}
+
+/// The ids of the boards of the platforms installed in `core_dir` and of the project in
+/// `project_dir`, sorted and without duplicates.
+pub fn boards(core_dir: Option<&Path>, project_dir: impl AsRef<Path>) -> Vec<String> {
+    let platforms = core_dir
+        .and_then(|core_dir| fs::read_dir(core_dir.join("platforms")).ok())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path().join("boards"));
+
+    platforms
+        .chain(std::iter::once(project_dir.as_ref().join("boards")))
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+
+            (path.extension().map_or(false, |ext| ext == "json"))
+                .then(|| path.file_stem()?.to_str().map(str::to_owned))
+                .flatten()
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// The serial ports of the connected devices.
+///
+/// On Unix, the USB-serial devices in `/dev`; elsewhere, the ports PlatformIO listed
+/// last for `core_dir`. Both are followed by the PIO Remote ports listed last.
+pub fn ports(core_dir: Option<&Path>) -> Vec<String> {
+    let cached = |file_name: &str| -> Vec<String> {
+        core_dir
+            .and_then(|core_dir| fs::read_to_string(core_dir.join(file_name)).ok())
+            .map(|ports| ports.lines().map(str::to_owned).collect())
+            .unwrap_or_default()
+    };
+
+    let mut ports = if cfg!(unix) {
+        let mut ports = fs::read_dir("/dev")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str().map(str::to_owned))
+            .filter(|name| is_serial_port(name))
+            .map(|name| format!("/dev/{}", name))
+            .collect::<Vec<_>>();
+
+        ports.sort();
+        ports
+    } else {
+        cached(PORTS_CACHE_FILE)
+    };
+
+    ports.extend(cached(REMOTE_PORTS_CACHE_FILE));
+    ports
+}
+
+/// Remember the ports of `devices` listed by `pio` for [`ports`].
+pub(crate) fn cache_ports(pio: &Pio, devices: &[SerialDevice]) {
+    let ports = devices
+        .iter()
+        .map(|device| format!("{}\n", device.port))
+        .collect::<String>();
+
+    if let Err(err) = fs::write(pio.core_dir.join(PORTS_CACHE_FILE), ports) {
+        debug!("Caching the serial ports failed: {}", err);
+    }
+}
+
+/// Remember the PIO Remote ports `ports` listed by `pio` for [`ports`].
+pub(crate) fn cache_remote_ports(pio: &Pio, ports: &[String]) {
+    let ports = ports
+        .iter()
+        .map(|port| format!("{}\n", port))
+        .collect::<String>();
+
+    if let Err(err) = fs::write(pio.core_dir.join(REMOTE_PORTS_CACHE_FILE), ports) {
+        debug!("Caching the PIO Remote ports failed: {}", err);
+    }
+}
+
+/// Whether the device file `name` in `/dev` is the serial port of a USB-serial bridge or
+/// of a native USB CDC device.
+fn is_serial_port(name: &str) -> bool {
+    ["ttyUSB", "ttyACM", "cu.usb", "cu.SLAB", "cu.wchusbserial"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environments() {
+        let platformio_ini = "[platformio]\ndefault_envs = debug\n\n[env]\nboard = esp32dev\n\n\
+                              [env:debug]\nbuild_type = debug\n\n  [env:release]  \n";
+
+        assert_eq!(environments(platformio_ini), ["debug", "release"]);
+
+        assert!(is_serial_port("ttyUSB0"));
+        assert!(is_serial_port("cu.usbserial-0001"));
+        assert!(!is_serial_port("tty0"));
+        assert!(!is_serial_port("cu.Bluetooth-Incoming-Port"));
+    }
+}
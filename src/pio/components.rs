@@ -0,0 +1,272 @@
+//! Selection of the ESP-IDF components built into the firmware of PIO->Cargo projects.
+//!
+//! By default ESP-IDF builds every component it finds, which are several hundred for a
+//! clean build. With the components configured in the `[espidf]` section of
+//! `cargo-pio.toml`, cargo-pio sets the `COMPONENTS` variable in a managed block of the
+//! project's `CMakeLists.txt`, so that only these components, the components of the
+//! project itself (e.g. `src`, as PlatformIO registers the source directory) and
+//! everything they depend on are built.
+//!
+//! With the components of the ESP Component Registry installed by cargo-pio (see
+//! [`super::idf_registry`]), the block also adds their directory to the component
+//! directories, which is otherwise only done by the component manager.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use log::*;
+
+use super::config::EspidfConfig;
+use super::managed::ManagedFile;
+use super::Pio;
+use crate::pio_model::{env_option, list};
+
+const CMAKE_LISTS: &str = "CMakeLists.txt";
+
+/// The directories of the project which contain its own components.
+const PROJECT_COMPONENT_DIRS: &[&str] = &["components", "managed_components"];
+
+/// Apply the component selection of `config` to the project in `project_dir`, if the
+/// PlatformIO `environment` uses the ESP-IDF framework.
+///
+/// Returns whether `CMakeLists.txt` changed, which makes ESP-IDF reconfigure the build.
+pub fn apply(
+    pio: &Pio,
+    config: &EspidfConfig,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+) -> Result<bool> {
+    let project_dir = project_dir.as_ref();
+
+    let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))?;
+    let espidf =
+        env_option(&platformio_ini, environment, "framework").map_or(false, |frameworks| {
+            list(&frameworks)
+                .iter()
+                .any(|framework| framework == "espidf")
+        });
+
+    if !espidf {
+        if !config.components.is_empty() {
+            debug!(
+                "Environment {} does not use ESP-IDF, ignoring the component selection",
+                environment
+            );
+        }
+
+        return Ok(false);
+    }
+
+    let path = project_dir.join(CMAKE_LISTS);
+    let current = fs::read_to_string(&path).ok();
+
+    if config.components.is_empty() && !config.registry && current.is_none() {
+        return Ok(false);
+    }
+
+    let mut components = project_components(project_dir);
+
+    if !config.components.is_empty() {
+        validate(pio, project_dir, &config.components)?;
+        components.extend(config.components.iter().cloned());
+    }
+
+    let mut block = if config.components.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "set(COMPONENTS {})\n",
+            components.into_iter().collect::<Vec<_>>().join(" ")
+        )
+    };
+
+    if config.registry {
+        block.push_str(&format!(
+            "list(APPEND EXTRA_COMPONENT_DIRS ${{CMAKE_SOURCE_DIR}}/{})\n",
+            super::idf_registry::COMPONENTS_DIR
+        ));
+    }
+
+    // The default of PlatformIO, which it creates on the first build otherwise
+    let current = current.unwrap_or_else(|| {
+        format!(
+            "cmake_minimum_required(VERSION 3.16.0)\n\
+             include($ENV{{IDF_PATH}}/tools/cmake/project.cmake)\n\
+             project({})\n",
+            project_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "project".into())
+        )
+    });
+
+    let updated =
+        ManagedFile::for_path(project_dir, CMAKE_LISTS).upsert_block(&current, block, |line| {
+            line.trim_start().starts_with("project(")
+        });
+
+    if updated.lines().eq(current.lines()) && path.exists() {
+        return Ok(false);
+    }
+
+    if config.components.is_empty() {
+        info!("Building all ESP-IDF components");
+    } else {
+        info!(
+            "Building only the ESP-IDF components {}",
+            config.components.join(", ")
+        );
+    }
+
+    if path.exists() {
+        info!(
+            "Updating {}:\n{}",
+            path.display(),
+            super::managed::unified_diff(&current, &updated, CMAKE_LISTS)
+        );
+    }
+
+    fs::write(&path, updated)?;
+
+    Ok(true)
+}
+
+/// The components of the project itself: the source directory and the `main` directory
+/// if they are components, and the components in the component directories.
+fn project_components(project_dir: &Path) -> BTreeSet<String> {
+    let mut components = ["src", "main"]
+        .iter()
+        .filter(|dir| project_dir.join(dir).join(CMAKE_LISTS).is_file())
+        .map(|dir| dir.to_string())
+        .collect::<BTreeSet<_>>();
+
+    for dir in PROJECT_COMPONENT_DIRS {
+        components.extend(component_names(&project_dir.join(dir)));
+    }
+
+    components
+}
+
+/// The names of the components in `dir`.
+fn component_names(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|entry| entry.path().join(CMAKE_LISTS).is_file())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Check that all `components` exist in ESP-IDF or the project.
+fn validate(pio: &Pio, project_dir: &Path, components: &[String]) -> Result<()> {
+    let framework = match pio.package("framework-espidf", None) {
+        Some(framework) => framework,
+        None => {
+            // Installed by PlatformIO on the first build
+            debug!("ESP-IDF is not installed yet, skipping the validation of the components");
+            return Ok(());
+        }
+    };
+
+    let mut known = component_names(&framework.dir.join("components"))
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+    known.extend(project_components(project_dir));
+
+    let unknown = components
+        .iter()
+        .filter(|component| !known.contains(component.as_str()))
+        .collect::<Vec<_>>();
+
+    if !unknown.is_empty() {
+        bail!(
+            "Unknown ESP-IDF component(s) {} in {}, the components of ESP-IDF {} and the project are: {}",
+            unknown
+                .iter()
+                .map(|component| component.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            super::config::CONFIG_FILE_NAME,
+            framework.version,
+            known.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn component(dir: PathBuf) {
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(CMAKE_LISTS), "idf_component_register()\n").unwrap();
+    }
+
+    #[test]
+    fn test_apply() {
+        let core_dir = tempfile::tempdir().unwrap();
+        let project_dir = tempfile::tempdir().unwrap();
+        let project_dir = project_dir.path();
+
+        let pio = Pio {
+            platformio_exe: PathBuf::from("pio"),
+            core_dir: core_dir.path().to_owned(),
+            log_level: Default::default(),
+            timeout: None,
+        };
+
+        let framework = core_dir.path().join("packages").join("framework-espidf");
+        for name in ["esp_wifi", "nvs_flash", "freertos"] {
+            component(framework.join("components").join(name));
+        }
+        component(project_dir.join("src"));
+        component(project_dir.join("components").join("display"));
+
+        fs::write(
+            project_dir.join("platformio.ini"),
+            "[env:debug]\nframework = espidf\n\n[env:native]\nplatform = native\n",
+        )
+        .unwrap();
+
+        let mut config = EspidfConfig {
+            components: vec!["esp_wifi".into(), "nvs_flash".into()],
+            ..Default::default()
+        };
+
+        // Environments without ESP-IDF are left alone
+        assert!(!apply(&pio, &config, project_dir, "native").unwrap());
+        assert!(!project_dir.join(CMAKE_LISTS).exists());
+
+        assert!(apply(&pio, &config, project_dir, "debug").unwrap());
+        let cmake_lists = fs::read_to_string(project_dir.join(CMAKE_LISTS)).unwrap();
+        assert!(cmake_lists.contains("set(COMPONENTS display esp_wifi nvs_flash src)\n"));
+        assert!(
+            cmake_lists.find("set(COMPONENTS").unwrap() < cmake_lists.find("project(").unwrap()
+        );
+        assert!(!apply(&pio, &config, project_dir, "debug").unwrap());
+
+        config.components.push("esp_wfi".into());
+        let error = format!(
+            "{:#}",
+            apply(&pio, &config, project_dir, "debug").unwrap_err()
+        );
+        assert!(error.contains("Unknown ESP-IDF component(s) esp_wfi"));
+        assert!(error.contains("display, esp_wifi, freertos, nvs_flash, src"));
+
+        // Building all components again removes the block
+        config.components.clear();
+        assert!(apply(&pio, &config, project_dir, "debug").unwrap());
+        assert!(!fs::read_to_string(project_dir.join(CMAKE_LISTS))
+            .unwrap()
+            .contains("COMPONENTS"));
+    }
+}
@@ -0,0 +1,1282 @@
+//! The `cargo-pio.toml` project configuration.
+//!
+//! This file lives next to `platformio.ini` and carries the settings that are specific to
+//! the Cargo side of a PIO->Cargo project, which PlatformIO itself does not know about.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use super::Pio;
+
+pub const CONFIG_FILE_NAME: &str = "cargo-pio.toml";
+
+const VAR_CARGO_OPTIONS: &str = "CARGO_PIO_CARGO_OPTIONS";
+const VAR_CARGO_PROFILE: &str = "CARGO_PIO_CARGO_PROFILE";
+const VAR_CARGO_PARALLEL: &str = "CARGO_PIO_CARGO_PARALLEL";
+const VAR_LDSCRIPT: &str = "CARGO_PIO_LDSCRIPT";
+
+/// The contents of a `cargo-pio.toml` file.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Cargo settings per PlatformIO environment, keyed by the environment name (the
+    /// `<name>` part of `[env:<name>]` in `platformio.ini`).
+    pub env: BTreeMap<String, EnvConfig>,
+    /// Commands run at the lifecycle hook points of cargo-pio.
+    pub hooks: Hooks,
+    /// Version stamping of the firmware, disabled if not set.
+    pub stamp: Option<StampConfig>,
+    /// Binary assets embedded into the firmware.
+    pub assets: AssetsConfig,
+    /// The compiler cache used for the C/C++ sources, none if not set.
+    pub compiler_cache: Option<CompilerCacheConfig>,
+    /// ESP-IDF specific settings.
+    pub espidf: EspidfConfig,
+    /// The board constants generated for the firmware, disabled if not set.
+    pub board: Option<BoardConfig>,
+    /// The firmware images of projects with more than one, in flashing order.
+    pub images: Vec<ImageConfig>,
+    /// The release packaging settings.
+    pub release: ReleaseConfig,
+    /// The post-processing steps of the built firmware, in order.
+    pub post_process: Vec<PostProcessConfig>,
+    /// The reset strategies of boards, keyed by the board id.
+    pub reset: BTreeMap<String, ResetConfig>,
+    /// The notifications about builds and package changes.
+    pub notify: NotifyConfig,
+    /// The Zephyr builds driven by west instead of PlatformIO, disabled if not set.
+    pub zephyr: Option<ZephyrConfig>,
+    /// The vendor SDKs installed as libraries of the project.
+    pub sdk: Vec<SdkConfig>,
+    /// The symbols denied in the firmware, checked by `cargo pio symbols`.
+    pub symbols: SymbolsConfig,
+    /// The metrics recorded for every build, reported by `cargo pio metrics trend`.
+    pub metrics: MetricsConfig,
+    /// The timeouts of the PlatformIO commands.
+    pub timeouts: TimeoutsConfig,
+    /// The highlights, suppressions and triggers of `cargo pio espidf monitor`.
+    pub monitor: MonitorConfig,
+    /// The git repository set up for new projects.
+    pub git: GitConfig,
+    /// The installed versions of the platforms and packages kept in the core directory.
+    pub retention: RetentionConfig,
+    /// The named tasks run by `cargo pio run-task`, keyed by their names.
+    pub tasks: BTreeMap<String, TaskConfig>,
+    /// The linker scripts generated from templates.
+    pub linker_scripts: Vec<LinkerScriptConfig>,
+}
+
+/// The Cargo settings applied when building a single PlatformIO environment.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct EnvConfig {
+    /// Cargo features to enable.
+    pub features: Vec<String>,
+    /// Whether to disable the default features of the crate.
+    pub no_default_features: bool,
+    /// The Cargo profile to build with (`dev`, `release` or a custom profile).
+    ///
+    /// If not set, the profile is derived from the `build_type` of the environment.
+    pub profile: Option<String>,
+    /// The MCUboot image to produce from the firmware, none if not set.
+    pub mcuboot: Option<McubootConfig>,
+    /// The linker script (relative to the project directory) to link the firmware
+    /// with, instead of the one of the platform.
+    pub ldscript: Option<PathBuf>,
+    /// Whether to run Cargo concurrently with the compilation of the C/C++ sources,
+    /// waiting for it only when linking.
+    ///
+    /// The build script of the crate then sees the include directories and flags of the
+    /// environment as they are before the compilation. Ignored if the environment sets
+    /// `cargo_run_before_project` in `platformio.ini`, which needs Cargo to run first.
+    pub parallel: bool,
+}
+
+/// The settings of the MCUboot image produced after building an environment, e.g.
+///
+/// ```toml
+/// [env.release.mcuboot]
+/// slot-size = 0x60000
+/// key = "keys/root-ec-p256.pem"
+/// pad = true
+/// ```
+///
+/// See [`super::mcuboot`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct McubootConfig {
+    /// The size of the image slot, which the image must fit into including its trailer.
+    pub slot_size: u32,
+    /// The size of the image header, for which the firmware reserves space at its start.
+    #[serde(default = "McubootConfig::default_header_size")]
+    pub header_size: u32,
+    /// The write alignment of the flash (1, 2, 4, 8, 16 or 32).
+    #[serde(default = "McubootConfig::default_align")]
+    pub align: u32,
+    /// The image version (`major.minor.revision+build`). Defaults to the version of the
+    /// Cargo package.
+    pub version: Option<String>,
+    /// The signing key (PEM), relative to the project directory. Unsigned (hash only) if
+    /// not set.
+    pub key: Option<PathBuf>,
+    /// Whether to pad the image to the slot size and add the trailer, as needed for
+    /// images flashed directly into the secondary slot.
+    #[serde(default)]
+    pub pad: bool,
+    /// Whether to mark the image as confirmed, so that it is not reverted after a swap.
+    #[serde(default)]
+    pub confirm: bool,
+    /// Whether to prepend the (zeroed) header space to the firmware, for firmware which
+    /// is not linked with space for the header.
+    #[serde(default)]
+    pub pad_header: bool,
+}
+
+impl McubootConfig {
+    fn default_header_size() -> u32 {
+        0x200
+    }
+
+    fn default_align() -> u32 {
+        4
+    }
+}
+
+/// Commands run before and after building and flashing, e.g.
+///
+/// ```toml
+/// [hooks]
+/// pre-build = ["python scripts/gen_assets.py"]
+/// post-flash = ["notify-send 'Flashed $CARGO_PIO_ENVIRONMENT'"]
+/// ```
+///
+/// Every command is run by the shell (`sh -c`, or `cmd /C` on Windows) in the project
+/// directory, with PlatformIO on the `PATH`, the Cargo settings of the environment
+/// applied and the following variables set:
+/// - `CARGO_PIO_HOOK`: the name of the hook point, e.g. `pre-build`;
+/// - `CARGO_PIO_ENVIRONMENT`: the PlatformIO environment;
+/// - `CARGO_PIO_PROJECT_DIR`: the project directory;
+/// - `CARGO_PIO_BUILD_DIR`: the build directory of the environment.
+///
+/// A failing command aborts the build or flash.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Hooks {
+    pub pre_build: Vec<String>,
+    pub post_build: Vec<String>,
+    pub pre_flash: Vec<String>,
+    pub post_flash: Vec<String>,
+}
+
+/// The version stamping settings, e.g.
+///
+/// ```toml
+/// [stamp]
+/// rust = "src/version.rs"
+/// ```
+///
+/// See [`super::stamp`] for the stamped information.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct StampConfig {
+    /// The Rust source file (relative to the project directory) to generate with the
+    /// stamp as constants, none if not set.
+    pub rust: Option<PathBuf>,
+    /// Whether to pass the stamp to the build as C defines.
+    pub defines: bool,
+    /// Whether to include the build timestamp, which makes every build differ.
+    pub timestamp: bool,
+}
+
+impl Default for StampConfig {
+    fn default() -> Self {
+        Self {
+            rust: None,
+            defines: true,
+            timestamp: true,
+        }
+    }
+}
+
+/// The binary assets embedded into the firmware, e.g.
+///
+/// ```toml
+/// [assets]
+/// rust = "src/assets.rs"
+/// c = "src/assets"
+///
+/// [[assets.files]]
+/// path = "web/dist/index.html"
+/// compress = "gzip"
+/// ```
+///
+/// See [`super::assets`] for the generated sources.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct AssetsConfig {
+    /// The Rust source file (relative to the project directory) to generate with one
+    /// static byte slice per asset, none if not set.
+    pub rust: Option<PathBuf>,
+    /// The base path (relative to the project directory) of the C source and header
+    /// (`<c>.c` and `<c>.h`) to generate with one array per asset, none if not set.
+    ///
+    /// To be compiled & linked by PlatformIO, it should be within its `src_dir`.
+    pub c: Option<PathBuf>,
+    pub files: Vec<Asset>,
+}
+
+/// A file embedded into the firmware.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Asset {
+    /// The file, relative to the project directory.
+    pub path: PathBuf,
+    /// The name of the generated symbol. Derived from the file name if not set, e.g.
+    /// `INDEX_HTML` for `index.html`.
+    pub name: Option<String>,
+    #[serde(default)]
+    pub compress: Compression,
+}
+
+/// The compression applied to an asset.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// The compiler cache for the C/C++ sources of the project, the PlatformIO libraries and
+/// the frameworks, e.g.
+///
+/// ```toml
+/// [compiler-cache]
+/// launcher = "ccache"
+/// ```
+///
+/// See [`super::compiler_cache`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CompilerCacheConfig {
+    pub launcher: Launcher,
+    /// The executable of the launcher. Looked up in the `PATH` if not set.
+    pub path: Option<PathBuf>,
+}
+
+/// A compiler launcher.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Launcher {
+    Ccache,
+    Sccache,
+}
+
+impl Launcher {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ccache => "ccache",
+            Self::Sccache => "sccache",
+        }
+    }
+}
+
+/// The ESP-IDF specific settings, e.g.
+///
+/// ```toml
+/// [espidf]
+/// components = ["esp_wifi", "nvs_flash", "esp_http_server"]
+/// registry = true
+///
+/// # An existing ESP-IDF checkout and toolchain instead of the PlatformIO packages
+/// path = "/opt/esp-idf"
+/// version = "5.1"
+/// toolchains.toolchain-xtensa-esp32 = { path = "/opt/xtensa-esp32-elf", version = "12.2.0+20230208" }
+/// ```
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct EspidfConfig {
+    /// The ESP-IDF components to build, besides the ones they depend on and the
+    /// components of the project itself. All components are built if empty.
+    ///
+    /// See [`super::components`].
+    pub components: Vec<String>,
+    /// Whether cargo-pio installs the components of the ESP Component Registry the
+    /// project depends on, instead of the component manager of ESP-IDF.
+    ///
+    /// See [`super::idf_registry`].
+    pub registry: bool,
+    /// The date (`YYYY-MM-DD`) as of which the components of the ESP Component Registry
+    /// are resolved, to reproduce an earlier build. The newest versions if not set.
+    pub as_of: Option<String>,
+    /// An existing ESP-IDF checkout (relative to the project directory) used instead of
+    /// the `framework-espidf` package of PlatformIO.
+    ///
+    /// See [`super::external`].
+    pub path: Option<PathBuf>,
+    /// The ESP-IDF version the checkout of `path` has to be, e.g. `5.1` for any 5.1.x.
+    pub version: Option<String>,
+    /// Existing toolchains used instead of the packages of PlatformIO, keyed by the
+    /// package they replace, e.g. `toolchain-xtensa-esp32`.
+    pub toolchains: BTreeMap<String, ExternalToolchain>,
+}
+
+/// An existing toolchain, see [`EspidfConfig::toolchains`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ExternalToolchain {
+    /// The directory of the toolchain, with its executables in `bin`.
+    pub path: PathBuf,
+    /// The version of the package the toolchain replaces, e.g. `12.2.0+20230208`.
+    pub version: String,
+}
+
+/// The board constants generated for the firmware, e.g.
+///
+/// ```toml
+/// [board]
+/// rust = "src/board.rs"
+///
+/// [board.overrides.esp32dev]
+/// flash-size = 0x800000
+/// pins = { LED = 2, BUTTON = 0 }
+/// ```
+///
+/// See [`super::board`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BoardConfig {
+    /// The Rust source file (relative to the project directory) to generate with the
+    /// board constants, none if not set.
+    pub rust: Option<PathBuf>,
+    /// Whether to pass the board constants to the build as C defines.
+    pub defines: bool,
+    /// Overrides of the board metadata of PlatformIO, keyed by the board id.
+    pub overrides: BTreeMap<String, BoardOverrides>,
+}
+
+impl Default for BoardConfig {
+    fn default() -> Self {
+        Self {
+            rust: None,
+            defines: true,
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+/// Overrides of the metadata of a board, e.g. for a custom flash chip, and its pin
+/// aliases.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BoardOverrides {
+    pub name: Option<String>,
+    pub mcu: Option<String>,
+    /// The CPU frequency in Hz.
+    pub f_cpu: Option<u64>,
+    /// The size of the RAM in bytes.
+    pub ram_size: Option<u64>,
+    /// The size of the flash in bytes.
+    pub flash_size: Option<u64>,
+    /// The maximum size of the firmware in bytes.
+    pub max_firmware_size: Option<u64>,
+    /// Pin aliases, e.g. `LED = 2`.
+    pub pins: BTreeMap<String, u32>,
+}
+
+/// A firmware image of a project with several images (e.g. a bootloader, a factory and
+/// a recovery app), each built by its own PlatformIO environment, e.g.
+///
+/// ```toml
+/// [[images]]
+/// name = "app"
+/// environment = "release"
+///
+/// [[images]]
+/// name = "recovery"
+/// environment = "recovery"
+/// partition = "ota_1"
+///
+/// [env.recovery]
+/// features = ["recovery"]
+/// ```
+///
+/// Images without an offset or partition are uploaded by PlatformIO, at the location
+/// their linker script (see [`EnvConfig::ldscript`]) places them. See [`super::images`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImageConfig {
+    pub name: String,
+    /// The PlatformIO environment building the image.
+    pub environment: String,
+    /// The flash offset to write the image to.
+    pub offset: Option<u32>,
+    /// The label of the partition to write the image to, from the partition table of
+    /// the environment.
+    pub partition: Option<String>,
+}
+
+/// The release packaging settings, e.g.
+///
+/// ```toml
+/// [release]
+/// environments = ["release"]
+/// files = ["README.md"]
+/// sign = "gpg --batch --detach-sign --armor -o \"$CARGO_PIO_SIGNATURE\" \"$CARGO_PIO_SIGN_FILE\""
+/// ```
+///
+/// See [`super::release`].
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ReleaseConfig {
+    /// The PlatformIO environments whose firmware is released. Defaults to the
+    /// environments of the `[[images]]`, or `release`.
+    pub environments: Vec<String>,
+    /// Additional files (relative to the project directory) to include.
+    pub files: Vec<PathBuf>,
+    /// The command signing the checksums, run by the shell with `CARGO_PIO_SIGN_FILE`
+    /// set to the file to sign and `CARGO_PIO_SIGNATURE` to the signature to write.
+    /// Unsigned if not set.
+    pub sign: Option<String>,
+}
+
+/// The git repository set up by `cargo pio new --git` and `cargo pio init --git`, e.g.
+/// for tracking the binary assets with Git LFS:
+///
+/// ```toml
+/// [git]
+/// lfs = ["assets/*.bin", "*.png"]
+/// ```
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct GitConfig {
+    /// The patterns of the files tracked with Git LFS.
+    pub lfs: Vec<String>,
+}
+
+/// The installed versions of the platforms and packages kept by `cargo pio pkg gc`,
+/// which also runs after `cargo pio compat pin` installed new versions:
+///
+/// ```toml
+/// [retention]
+/// keep = 2
+/// min-age = 14
+/// ```
+///
+/// The versions of a platform or package beyond the newest `keep` are removed, except
+/// for its primary installation, the versions locked in `cargo-pio.lock` (unless
+/// `keep-pinned = false`) and the ones installed less than `min-age` days ago.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RetentionConfig {
+    /// The number of versions kept of every platform and package. Nothing is removed
+    /// automatically if not set.
+    pub keep: Option<usize>,
+    /// Keep the versions locked in the lockfile of the project.
+    pub keep_pinned: bool,
+    /// The minimum age in days of the removed versions.
+    pub min_age: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            keep: None,
+            keep_pinned: true,
+            min_age: 0,
+        }
+    }
+}
+
+/// A named task of the project, run by `cargo pio run-task <name>`, e.g.
+///
+/// ```toml
+/// [tasks.flash-all]
+/// description = "Builds the release and flashes every connected board"
+/// environment = "release"
+/// depends = ["gen-assets"]
+/// steps = [
+///     { cargo-pio = ["build", "-e", "release"] },
+///     { run = "python scripts/stamp_serials.py" },
+///     { cargo-pio = ["flash", "-e", "release", "--all-ports"] },
+/// ]
+/// ```
+///
+/// The steps run in the environment of the [`Hooks`], with `CARGO_PIO_TASK` set instead
+/// of `CARGO_PIO_HOOK`. See [`super::tasks`].
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TaskConfig {
+    /// The description shown by `cargo pio run-task` without a task.
+    pub description: Option<String>,
+    /// The PlatformIO environment whose settings the steps run with. Defaults to
+    /// `debug`.
+    pub environment: Option<String>,
+    /// The tasks run before this one.
+    pub depends: Vec<String>,
+    /// Additional environment variables of the steps.
+    pub env: BTreeMap<String, String>,
+    pub steps: Vec<TaskStep>,
+}
+
+/// A step of a [`TaskConfig`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskStep {
+    /// A command of cargo-pio, with its arguments.
+    CargoPio(Vec<String>),
+    /// A command run by the shell.
+    Run(String),
+}
+
+/// A linker script (or `memory.x`) generated from a template with the board metadata and
+/// the partition layout of an environment, e.g.
+///
+/// ```toml
+/// [[linker-scripts]]
+/// template = "ld/memory.x.in"
+/// output = "memory.x"
+/// ```
+///
+/// See [`super::ldscript`] for the template syntax and variables.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct LinkerScriptConfig {
+    /// The template, relative to the project directory.
+    pub template: PathBuf,
+    /// The generated linker script, relative to the project directory.
+    pub output: PathBuf,
+    /// The PlatformIO environment whose builds generate the script. Defaults to every
+    /// environment.
+    pub environment: Option<String>,
+}
+
+/// A post-processing step of the built firmware of the environments, e.g.
+///
+/// ```toml
+/// [[post-process]]
+/// step = "objcopy"
+/// output = "firmware.hex"
+/// args = ["-O", "ihex"]
+///
+/// [[post-process]]
+/// step = "compress"
+/// input = "firmware.bin"
+///
+/// [[post-process]]
+/// step = "command"
+/// command = "python scripts/pack.py \"$CARGO_PIO_INPUT\" \"$CARGO_PIO_OUTPUT\""
+/// input = "firmware.bin"
+/// output = "firmware.pack"
+/// environments = ["release"]
+/// ```
+///
+/// The paths are relative to the build directory of the environment. The outputs are
+/// released with the firmware. See [`super::postprocess`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PostProcessConfig {
+    pub step: PostProcessStep,
+    /// The input file. Defaults to `firmware.elf` for `objcopy` and `strip`, to
+    /// `firmware.bin` otherwise.
+    pub input: Option<PathBuf>,
+    /// The output file. Defaults to `<input stem>.bin` for `objcopy`, `<input
+    /// stem>.stripped.<extension>` for `strip`, `<input>.gz` for `compress` and
+    /// `<input>.<algorithm>` for `checksum`. A `command` step has no output if not set.
+    pub output: Option<PathBuf>,
+    /// The arguments of `objcopy` (defaults to `-O binary`) or `strip` (defaults to
+    /// `--strip-debug`), besides the input and the output.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The hash algorithm of a `checksum` step (`sha256`, `sha512` or `blake3`).
+    /// Defaults to `sha256`.
+    pub algorithm: Option<String>,
+    /// The command of a `command` step, run by the shell with `CARGO_PIO_INPUT` and
+    /// `CARGO_PIO_OUTPUT` set to the input and the output.
+    pub command: Option<String>,
+    /// The environments the step applies to, all if empty.
+    #[serde(default)]
+    pub environments: Vec<String>,
+}
+
+/// The kind of a post-processing step.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostProcessStep {
+    /// Convert the input with the `objcopy` of the toolchain of the environment.
+    Objcopy,
+    /// Strip the input with the `strip` of the toolchain of the environment.
+    Strip,
+    /// Compress the input with gzip.
+    Compress,
+    /// Write the checksum of the input, in the format of `sha256sum`.
+    Checksum,
+    /// Run a command.
+    Command,
+}
+
+impl PostProcessStep {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Objcopy => "objcopy",
+            Self::Strip => "strip",
+            Self::Compress => "compress",
+            Self::Checksum => "checksum",
+            Self::Command => "command",
+        }
+    }
+}
+
+/// Notifications about builds and package changes, e.g. for tracking toolchain drift
+/// across the machines of a team:
+///
+/// ```toml
+/// [notify]
+/// commands = ["python scripts/track_toolchain.py"]
+/// urls = ["https://ci.example.com/hooks/cargo-pio"]
+/// events = ["build", "toolchain-change"]
+/// ```
+///
+/// See [`super::notify`] for the events and the JSON payload.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct NotifyConfig {
+    /// The commands run by the shell with the payload on stdin and `CARGO_PIO_EVENT` set
+    /// to the event.
+    pub commands: Vec<String>,
+    /// The URLs the payload is POSTed to. Needs the `ureq` feature.
+    pub urls: Vec<String>,
+    /// The events notified about. All if empty.
+    pub events: Vec<String>,
+}
+
+/// The symbols denied in the firmware, e.g. for keeping a project free of panics and
+/// soft-float code:
+///
+/// ```toml
+/// [symbols]
+/// deny = ["panic", "float", "printf"]
+/// allow = ["defmt::*"]
+///
+/// [symbols.rules]
+/// printf = ["printf", "vfprintf", "_vfprintf_r"]
+/// ```
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct SymbolsConfig {
+    /// The denied rules: the builtin `panic`, `float` and `alloc`, or the ones of `rules`.
+    pub deny: Vec<String>,
+    /// Custom rules as lists of symbol patterns, keyed by their name. A pattern ending
+    /// with `*` matches all symbols starting with it.
+    pub rules: BTreeMap<String, Vec<String>>,
+    /// Patterns of the functions allowed to reference denied symbols.
+    pub allow: Vec<String>,
+}
+
+/// The metrics recorded for every build and the growth between two builds which is
+/// reported as a regression, in percent, e.g.
+///
+/// ```toml
+/// [metrics]
+/// flash-threshold = 0.5
+/// time-threshold = 50
+/// ```
+///
+/// See [`super::metrics`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    /// Whether to record the metrics of every build.
+    pub record: bool,
+    pub flash_threshold: f64,
+    pub ram_threshold: f64,
+    pub time_threshold: f64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            record: true,
+            flash_threshold: 1.0,
+            ram_threshold: 1.0,
+            time_threshold: 25.0,
+        }
+    }
+}
+
+/// The timeouts of the PlatformIO commands in seconds, after which they are killed and
+/// fail, e.g. so that CI jobs fail instead of hanging when flashing stalls waiting for
+/// the bootloader:
+///
+/// ```toml
+/// [timeouts]
+/// build = 1800
+/// upload = 120
+/// monitor = 600
+/// ```
+///
+/// Commands without a timeout run until they are done or interrupted.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TimeoutsConfig {
+    /// The build of an environment.
+    pub build: Option<u64>,
+    /// The upload of the firmware, per device and image.
+    pub upload: Option<u64>,
+    /// The monitor of PlatformIO, e.g. for the capture of the output of a test run.
+    pub monitor: Option<u64>,
+}
+
+impl TimeoutsConfig {
+    pub fn build(&self) -> Option<Duration> {
+        self.build.map(Duration::from_secs)
+    }
+
+    pub fn upload(&self) -> Option<Duration> {
+        self.upload.map(Duration::from_secs)
+    }
+
+    pub fn monitor(&self) -> Option<Duration> {
+        self.monitor.map(Duration::from_secs)
+    }
+}
+
+/// The filters of the device output of `cargo pio espidf monitor`, e.g. to end a test
+/// run with its result and to keep the output leading up to a crash:
+///
+/// ```toml
+/// [monitor]
+/// suppress = ["^wifi:"]
+///
+/// [[monitor.highlight]]
+/// pattern = "\\bE \\(\\d+\\)"
+/// color = "red"
+///
+/// [[monitor.trigger]]
+/// pattern = "TEST PASSED"
+/// exit = 0
+///
+/// [[monitor.trigger]]
+/// pattern = "Guru Meditation Error|panicked at"
+/// exit = 1
+/// capture = "target/crash.log"
+/// ```
+///
+/// The patterns are regular expressions matched against every line. Any filter makes
+/// the monitor read the device output itself instead of PlatformIO's monitor, see the
+/// `monitor::filter` module of embuild.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MonitorConfig {
+    pub highlight: Vec<HighlightConfig>,
+    /// The lines not to print.
+    pub suppress: Vec<String>,
+    pub trigger: Vec<TriggerConfig>,
+}
+
+/// The highlight of the matches of a pattern.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HighlightConfig {
+    pub pattern: String,
+    /// `red`, `green`, `yellow`, `blue`, `magenta`, `cyan` or `bold`.
+    pub color: String,
+}
+
+/// The actions run on every line matching a pattern.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct TriggerConfig {
+    pub pattern: String,
+    /// End the session, with this exit code of cargo-pio.
+    pub exit: Option<i32>,
+    /// Run this shell command, with the line in `CARGO_PIO_MONITOR_LINE`.
+    pub run: Option<String>,
+    /// Append the preceding output to this file.
+    pub capture: Option<PathBuf>,
+    /// The number of lines to capture, including the matching one.
+    #[serde(default = "TriggerConfig::default_capture_lines")]
+    pub capture_lines: usize,
+}
+
+impl TriggerConfig {
+    fn default_capture_lines() -> usize {
+        100
+    }
+}
+
+/// Builds of the Rust staticlib into a Zephyr application with west, for boards whose
+/// Zephyr support in PlatformIO lags behind, e.g.
+///
+/// ```toml
+/// [zephyr]
+/// revision = "v3.7.0"
+/// board = "nrf52840_dk"
+/// app = "zephyr"
+/// ```
+///
+/// See `cargo pio zephyr` and the `zephyr` module of embuild.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ZephyrConfig {
+    /// The URL of the west manifest repository. Defaults to the Zephyr repository.
+    pub manifest: Option<String>,
+    /// The pinned revision (tag, branch or commit) of the manifest repository.
+    pub revision: Option<String>,
+    /// The west workspace, relative to the project directory.
+    pub workspace: PathBuf,
+    /// The board, as PlatformIO board id or Zephyr board.
+    pub board: Option<String>,
+    /// The Rust target. Defaults to the one of known boards.
+    pub target: Option<String>,
+    /// The Zephyr application (with its `CMakeLists.txt` and `prj.conf`), relative to
+    /// the project directory.
+    pub app: PathBuf,
+    /// Additional CMake arguments, e.g. `-DEXTRA_CONF_FILE=debug.conf`.
+    pub cmake_args: Vec<String>,
+}
+
+impl Default for ZephyrConfig {
+    fn default() -> Self {
+        Self {
+            manifest: None,
+            revision: None,
+            workspace: PathBuf::from(".zephyr"),
+            board: None,
+            target: None,
+            app: PathBuf::from("zephyr"),
+            cmake_args: Vec::new(),
+        }
+    }
+}
+
+/// A vendor SDK archive, unpacked and normalized into the library `lib/<name>` of the
+/// project, e.g.
+///
+/// ```toml
+/// [[sdk]]
+/// name = "stm32cubef4"
+/// vendor = "stm32cube"
+/// archive = "https://github.com/STMicroelectronics/STM32CubeF4/archive/refs/tags/v1.27.1.tar.gz"
+///
+/// [[sdk]]
+/// name = "mcuxpresso-k64"
+/// vendor = "mcuxpresso"
+/// archive = "vendor/SDK_2.11.0_FRDM-K64F.zip"
+/// sha256 = "..."
+/// ```
+///
+/// See [`super::sdk`] for the normalized layout.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct SdkConfig {
+    /// The name of the library.
+    pub name: String,
+    pub vendor: SdkVendor,
+    /// The archive (`.zip`, `.tar.gz`, ...), as path relative to the project directory or
+    /// as HTTP(S) URL.
+    pub archive: String,
+    /// The SHA-256 checksum of the archive, not checked if not set.
+    pub sha256: Option<String>,
+    /// The device of an MCUXpresso SDK with more than one, e.g. `MK64F12`.
+    pub device: Option<String>,
+}
+
+/// The vendor of an SDK.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SdkVendor {
+    /// An NXP MCUXpresso SDK, as generated by the SDK builder.
+    Mcuxpresso,
+    /// An STM32Cube MCU package (STM32CubeF4, STM32CubeL4, ...).
+    Stm32cube,
+}
+
+/// How to reset a board when flashing and monitoring it, for boards whose reset
+/// circuitry needs a different sequence than esptool's, e.g.
+///
+/// ```toml
+/// [reset.esp32-c3-devkitm-1]
+/// flash = { sequence = "D0|R1|W0.1|D1|R0|W0.5|D0" }
+/// monitor = "auto"
+///
+/// [reset.custom-board]
+/// flash = { command = "relayctl cycle 1" }
+/// monitor = { command = "relayctl cycle 1" }
+/// ```
+///
+/// See [`super::reset`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ResetConfig {
+    /// The reset into the bootloader before flashing. Defaults to esptool's.
+    pub flash: Reset,
+    /// The reset once the monitor is attached, so that no output is missed. Defaults
+    /// to none.
+    pub monitor: Reset,
+}
+
+impl Default for ResetConfig {
+    fn default() -> Self {
+        Self {
+            flash: Reset::Auto,
+            monitor: Reset::NoReset,
+        }
+    }
+}
+
+/// A reset strategy.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reset {
+    /// esptool's reset: into the bootloader before flashing, a hard reset (an RTS pulse)
+    /// in the monitor.
+    Auto,
+    /// No reset.
+    NoReset,
+    /// A sequence of DTR/RTS changes and waits, in the syntax of esptool's
+    /// `custom_reset_sequence`, e.g. `D0|R1|W0.1|D1|R0|W0.05|D0`.
+    Sequence(String),
+    /// A command run by the shell with `CARGO_PIO_PORT` set to the port of the device,
+    /// e.g. to power-cycle the board with a relay.
+    Command(String),
+}
+
+/// A lifecycle hook point.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Hook {
+    PreBuild,
+    PostBuild,
+    PreFlash,
+    PostFlash,
+}
+
+impl Hook {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PreBuild => "pre-build",
+            Self::PostBuild => "post-build",
+            Self::PreFlash => "pre-flash",
+            Self::PostFlash => "post-flash",
+        }
+    }
+}
+
+impl Hooks {
+    /// The commands of `hook`.
+    pub fn commands(&self, hook: Hook) -> &[String] {
+        match hook {
+            Hook::PreBuild => &self.pre_build,
+            Hook::PostBuild => &self.post_build,
+            Hook::PreFlash => &self.pre_flash,
+            Hook::PostFlash => &self.post_flash,
+        }
+    }
+}
+
+impl Config {
+    /// Load the `cargo-pio.toml` file in `project_dir`.
+    ///
+    /// Returns the default configuration if the file does not exist.
+    pub fn load(project_dir: impl AsRef<Path>) -> Result<Self> {
+        let path = project_dir.as_ref().join(CONFIG_FILE_NAME);
+
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+
+        debug!("Loading {}", path.display());
+
+        toml::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Save this configuration as `cargo-pio.toml` in `project_dir`.
+    pub fn save(&self, project_dir: impl AsRef<Path>) -> Result<()> {
+        let path = project_dir.as_ref().join(CONFIG_FILE_NAME);
+
+        debug!("Saving {}", path.display());
+
+        // Serialized through a value, which puts the plain values before the tables
+        crate::fs::write_atomic(path, toml::to_string(&toml::Value::try_from(self)?)?)?;
+
+        Ok(())
+    }
+
+    /// Get the Cargo settings for the PlatformIO environment `env`, if any.
+    pub fn env(&self, env: impl AsRef<str>) -> Option<&EnvConfig> {
+        self.env.get(env.as_ref())
+    }
+
+    /// Apply the Cargo settings of the PlatformIO environment `env` to a `pio` command.
+    pub fn apply_env(&self, env: impl AsRef<str>, cmd: &mut Command) {
+        if let Some(env_config) = self.env(env.as_ref()) {
+            debug!(
+                "Applying Cargo settings {:?} for environment {}",
+                env_config,
+                env.as_ref()
+            );
+
+            env_config.apply(cmd);
+        }
+    }
+
+    /// Run the commands of `hook` for the PlatformIO environment `env` of the project in
+    /// `project_dir`.
+    pub fn run_hook(
+        &self,
+        hook: Hook,
+        pio: &Pio,
+        project_dir: impl AsRef<Path>,
+        env: impl AsRef<str>,
+    ) -> Result<()> {
+        let project_dir = project_dir.as_ref();
+        let env = env.as_ref();
+
+        for command in self.hooks.commands(hook) {
+            info!("Running {} hook: {}", hook.name(), command);
+
+            let mut cmd = shell(command);
+            cmd.env("CARGO_PIO_HOOK", hook.name());
+            self.apply_tool_env(&mut cmd, pio, project_dir, env)?;
+
+            debug!("Running hook command: {:?}", cmd);
+
+            let status = cmd.status()?;
+
+            if !status.success() {
+                bail!(
+                    "The {} hook '{}' failed with {}",
+                    hook.name(),
+                    command,
+                    status
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set up `cmd` to run in the project directory `project_dir` with PlatformIO on the
+    /// `PATH`, the Cargo settings of the PlatformIO environment `env` applied and the
+    /// variables of the [`Hooks`] set.
+    pub(crate) fn apply_tool_env(
+        &self,
+        cmd: &mut Command,
+        pio: &Pio,
+        project_dir: &Path,
+        env: &str,
+    ) -> Result<()> {
+        cmd.current_dir(project_dir)
+            .env("PLATFORMIO_CORE_DIR", &pio.core_dir)
+            .env("CARGO_PIO_ENVIRONMENT", env)
+            .env("CARGO_PIO_PROJECT_DIR", project_dir)
+            .env(
+                "CARGO_PIO_BUILD_DIR",
+                project_dir.join(".pio").join("build").join(env),
+            );
+
+        // The external ESP-IDF and toolchains as if they were installed by PlatformIO
+        if let Some(path) = &self.espidf.path {
+            cmd.env("IDF_PATH", project_dir.join(path));
+        }
+
+        let toolchains = self
+            .espidf
+            .toolchains
+            .values()
+            .map(|toolchain| project_dir.join(&toolchain.path).join("bin"));
+
+        if let Some(pio_dir) = pio.platformio_exe.parent() {
+            let paths = std::env::var_os("PATH").unwrap_or_default();
+            let paths = std::iter::once(pio_dir.to_owned())
+                .chain(toolchains)
+                .chain(std::env::split_paths(&paths));
+
+            cmd.env("PATH", std::env::join_paths(paths)?);
+        }
+
+        self.apply_env(env, cmd);
+
+        Ok(())
+    }
+}
+
+/// A command running `command` with the shell (`sh -c`, or `cmd /C` on Windows).
+pub(crate) fn shell(command: &str) -> Command {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+
+    cmd.arg(command);
+
+    cmd
+}
+
+impl EnvConfig {
+    /// The arguments which need to be passed to `cargo build` for these settings.
+    ///
+    /// The profile is not included, as its handling depends on the build script.
+    pub fn cargo_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        if !self.features.is_empty() {
+            args.push("--features".to_owned());
+            args.push(self.features.join(","));
+        }
+
+        if self.no_default_features {
+            args.push("--no-default-features".to_owned());
+        }
+
+        args
+    }
+
+    /// Pass these settings to the `platformio.cargo.py` script via the environment of
+    /// the `pio` command.
+    pub fn apply(&self, cmd: &mut Command) {
+        let args = self.cargo_args();
+
+        if !args.is_empty() {
+            cmd.env(VAR_CARGO_OPTIONS, args.join(" "));
+        }
+
+        if let Some(profile) = &self.profile {
+            cmd.env(VAR_CARGO_PROFILE, profile);
+        }
+
+        if self.parallel {
+            cmd.env(VAR_CARGO_PARALLEL, "true");
+        }
+
+        // Resolved relative to the project directory by the script
+        if let Some(ldscript) = &self.ldscript {
+            cmd.env(VAR_LDSCRIPT, ldscript);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+[env.debug]
+features = ["log", "wifi"]
+
+[env.release]
+no-default-features = true
+profile = "size"
+parallel = true
+ldscript = "memory.x"
+
+[timeouts]
+build = 1800
+
+[hooks]
+pre-build = ["echo pre"]
+"#;
+
+    fn envs(cmd: &Command) -> BTreeMap<&OsStr, Option<&OsStr>> {
+        cmd.get_envs().collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = toml::from_str::<Config>(CONFIG).unwrap();
+
+        assert_eq!(config.env.len(), 2);
+        assert_eq!(config.env("debug").unwrap().features, ["log", "wifi"]);
+        assert_eq!(
+            config.env("release").unwrap().profile.as_deref(),
+            Some("size")
+        );
+        assert!(config.env("test").is_none());
+        assert_eq!(config.hooks.commands(Hook::PreBuild), ["echo pre"]);
+        assert_eq!(config.timeouts.build(), Some(Duration::from_secs(1800)));
+        assert_eq!(config.timeouts.upload(), None);
+
+        assert!(toml::from_str::<Config>("[env.debug]\nfeatures = \"log\"\n").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Config::load(dir.path()).unwrap().env.is_empty());
+
+        config.save(dir.path()).unwrap();
+        assert_eq!(Config::load(dir.path()).unwrap().env, config.env);
+    }
+
+    #[test]
+    fn test_env_precedence() {
+        let config = toml::from_str::<Config>(CONFIG).unwrap();
+
+        // Only the settings of the built environment apply
+        let mut cmd = Command::new("pio");
+        config.apply_env("debug", &mut cmd);
+        let debug = envs(&cmd);
+        assert_eq!(
+            debug.get(OsStr::new(VAR_CARGO_OPTIONS)),
+            Some(&Some(OsStr::new("--features log,wifi")))
+        );
+        assert!(!debug.contains_key(OsStr::new(VAR_CARGO_PROFILE)));
+        assert!(!debug.contains_key(OsStr::new(VAR_CARGO_PARALLEL)));
+
+        let mut cmd = Command::new("pio");
+        config.apply_env("release", &mut cmd);
+        let release = envs(&cmd);
+        assert_eq!(
+            release.get(OsStr::new(VAR_CARGO_OPTIONS)),
+            Some(&Some(OsStr::new("--no-default-features")))
+        );
+        assert_eq!(
+            release.get(OsStr::new(VAR_CARGO_PROFILE)),
+            Some(&Some(OsStr::new("size")))
+        );
+        assert_eq!(
+            release.get(OsStr::new(VAR_CARGO_PARALLEL)),
+            Some(&Some(OsStr::new("true")))
+        );
+        assert_eq!(
+            release.get(OsStr::new(VAR_LDSCRIPT)),
+            Some(&Some(OsStr::new("memory.x")))
+        );
+
+        let mut cmd = Command::new("pio");
+        config.apply_env("test", &mut cmd);
+        assert!(envs(&cmd).is_empty());
+    }
+
+    #[test]
+    fn test_cargo_args() {
+        let env = EnvConfig {
+            features: vec!["log".into(), "wifi".into()],
+            no_default_features: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            env.cargo_args(),
+            ["--features", "log,wifi", "--no-default-features"]
+        );
+        assert!(EnvConfig::default().cargo_args().is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! Generation of a container image and a devcontainer for PIO->Cargo projects.
+//!
+//! The image pins the tools to the versions the project is configured with: the Rust
+//! toolchain from `rust-toolchain(.toml)`, the cargo-pio version generating the image,
+//! and the PlatformIO platforms & packages of all environments in `platformio.ini`,
+//! which are installed into the image so that builds in the container need no
+//! downloads.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use log::*;
+
+use super::managed::{ManagedFile, Outcome};
+use crate::pio_model::environments;
+
+/// The path of the generated Dockerfile, relative to the project directory.
+pub const DOCKERFILE: &str = "Dockerfile";
+/// The path of the generated devcontainer definition, relative to the project directory.
+pub const DEVCONTAINER_JSON: &str = ".devcontainer/devcontainer.json";
+
+/// The PlatformIO core directory inside of the image.
+pub const PLATFORMIO_CORE_DIR: &str = "/opt/platformio";
+
+/// The tool versions and environments of a project, as pinned in the generated image.
+#[derive(Clone, Debug)]
+pub struct Spec {
+    /// The name of the project.
+    pub name: String,
+    /// The Rust toolchain (channel), e.g. `nightly-2022-06-01` or `esp`.
+    pub rust_toolchain: String,
+    /// The version of cargo-pio to install.
+    pub cargo_pio_version: String,
+    /// The PlatformIO environments whose packages are installed into the image.
+    pub environments: Vec<String>,
+}
+
+impl Spec {
+    /// Derive the spec of the project at `project_dir`, installing `cargo_pio_version`.
+    pub fn from_project(project_dir: impl AsRef<Path>, cargo_pio_version: &str) -> Result<Self> {
+        let project_dir = project_dir.as_ref();
+
+        let name = project_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "project".into());
+
+        let rust_toolchain = rust_toolchain(project_dir)?.unwrap_or_else(|| {
+            warn!("No rust-toolchain.toml file found, the image will use the stable toolchain");
+            "stable".into()
+        });
+
+        let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))?;
+
+        for platform in unpinned_platforms(&platformio_ini) {
+            warn!(
+                "Platform '{}' in platformio.ini has no version, the image will not be reproducible; \
+                 pin it with 'platform = {}@<version>'",
+                platform, platform
+            );
+        }
+
+        Ok(Self {
+            name,
+            rust_toolchain,
+            cargo_pio_version: cargo_pio_version.to_owned(),
+            environments: environments(&platformio_ini),
+        })
+    }
+
+    /// The content of the Dockerfile.
+    pub fn dockerfile(&self) -> String {
+        let mut dockerfile = format!(
+            r#"# Reproducible build environment of {name}
+FROM rust:latest
+
+RUN apt-get update \
+    && apt-get install -y --no-install-recommends python3 python3-venv git clang libclang-dev \
+    && rm -rf /var/lib/apt/lists/*
+
+RUN cargo install cargo-pio --version ={cargo_pio_version} --locked
+"#,
+            name = self.name,
+            cargo_pio_version = self.cargo_pio_version,
+        );
+
+        if self.rust_toolchain == "esp" {
+            // The Xtensa toolchain is not distributed by rustup
+            dockerfile.push_str(
+                r#"RUN cargo install espup --locked && espup install && cat ~/export-esp.sh >> ~/.bashrc
+"#,
+            );
+        } else {
+            dockerfile.push_str(&format!(
+                r#"RUN rustup toolchain install {toolchain} --profile minimal --component rust-src \
+    && rustup default {toolchain}
+"#,
+                toolchain = self.rust_toolchain
+            ));
+        }
+
+        dockerfile.push_str(&format!(
+            r#"
+ENV PLATFORMIO_CORE_DIR={core_dir}
+RUN cargo pio installpio {core_dir}
+
+# Pre-install the platforms & packages of all environments
+WORKDIR /tmp/project
+COPY platformio.ini platformio.*.py ./
+"#,
+            core_dir = PLATFORMIO_CORE_DIR
+        ));
+
+        for environment in &self.environments {
+            dockerfile.push_str(&format!(
+                "RUN cargo pio exec -i {} -- pkg install -e {}\n",
+                PLATFORMIO_CORE_DIR, environment
+            ));
+        }
+
+        dockerfile.push_str(
+            r#"
+WORKDIR /workspace
+RUN rm -rf /tmp/project
+"#,
+        );
+
+        dockerfile
+    }
+
+    /// The content of the devcontainer definition.
+    pub fn devcontainer_json(&self) -> String {
+        format!(
+            r#"{{
+    "name": "{name}",
+    "build": {{
+        "dockerfile": "../{dockerfile}",
+        "context": ".."
+    }},
+    "containerEnv": {{
+        "PLATFORMIO_CORE_DIR": "{core_dir}"
+    }},
+    "customizations": {{
+        "vscode": {{
+            "extensions": [
+                "rust-lang.rust-analyzer",
+                "platformio.platformio-ide"
+            ]
+        }}
+    }}
+}}
+"#,
+            name = self.name.replace('"', "\\\""),
+            dockerfile = DOCKERFILE,
+            core_dir = PLATFORMIO_CORE_DIR,
+        )
+    }
+
+    /// Write the Dockerfile and the devcontainer definition into the project at
+    /// `project_dir`, merging them with any edits made to previously generated ones.
+    pub fn generate(&self, project_dir: impl AsRef<Path>) -> Result<Vec<(&'static str, Outcome)>> {
+        let project_dir = project_dir.as_ref();
+
+        [
+            (DOCKERFILE, self.dockerfile()),
+            (DEVCONTAINER_JSON, self.devcontainer_json()),
+        ]
+        .iter()
+        .map(|(path, content)| {
+            let file = ManagedFile::for_path(project_dir, path);
+
+            Ok((*path, file.write(file.block(content))?))
+        })
+        .collect()
+    }
+
+    /// The diffs of the changes [`Self::generate`] would make, for the files which are
+    /// not up-to-date.
+    pub fn diffs(&self, project_dir: impl AsRef<Path>) -> Result<Vec<(&'static str, String)>> {
+        let project_dir = project_dir.as_ref();
+
+        let mut diffs = Vec::new();
+
+        for (path, content) in [
+            (DOCKERFILE, self.dockerfile()),
+            (DEVCONTAINER_JSON, self.devcontainer_json()),
+        ] {
+            let file = ManagedFile::for_path(project_dir, path);
+
+            if let Some(diff) = file.diff(file.block(content))? {
+                diffs.push((path, diff));
+            }
+        }
+
+        Ok(diffs)
+    }
+}
+
+/// Read the toolchain channel from the `rust-toolchain.toml` or `rust-toolchain` file
+/// of the project at `project_dir`.
+fn rust_toolchain(project_dir: &Path) -> Result<Option<String>> {
+    for name in &["rust-toolchain.toml", "rust-toolchain"] {
+        let path = project_dir.join(name);
+        if !path.is_file() {
+            continue;
+        }
+
+        let content = fs::read_to_string(&path)?;
+
+        // The legacy `rust-toolchain` file may contain only the channel name
+        let channel = match content.parse::<toml::Value>() {
+            Ok(value) => value
+                .get("toolchain")
+                .and_then(|toolchain| toolchain.get("channel"))
+                .and_then(toml::Value::as_str)
+                .map(str::to_owned),
+            Err(_) => Some(content.trim().to_owned()),
+        };
+
+        if channel.is_some() {
+            return Ok(channel);
+        }
+    }
+
+    Ok(None)
+}
+
+/// The `platform` options of `platformio_ini` which do not specify a version.
+fn unpinned_platforms(platformio_ini: &str) -> Vec<String> {
+    platformio_ini
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            let value = value.split(';').next().unwrap_or_default().trim();
+
+            (key.trim() == "platform" && !value.contains(['@', '#']) && !value.contains("://"))
+                .then(|| value.to_owned())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec() {
+        let dir = tempfile::tempdir().unwrap();
+        let project_dir = dir.path().join("blinky");
+        fs::create_dir_all(&project_dir).unwrap();
+
+        fs::write(
+            project_dir.join("platformio.ini"),
+            "[env:debug]\n\
+             platform = espressif32 ; no version\n\
+             \n\
+             [env:release]\n\
+             platform = espressif32@6.4.0\n",
+        )
+        .unwrap();
+        fs::write(
+            project_dir.join("rust-toolchain.toml"),
+            "[toolchain]\nchannel = \"nightly-2024-01-01\"\n",
+        )
+        .unwrap();
+
+        let spec = Spec::from_project(&project_dir, "0.25.2").unwrap();
+        assert_eq!(spec.name, "blinky");
+        assert_eq!(spec.rust_toolchain, "nightly-2024-01-01");
+        assert_eq!(spec.environments, ["debug", "release"]);
+
+        let dockerfile = spec.dockerfile();
+        assert!(dockerfile.contains("cargo install cargo-pio --version =0.25.2 --locked"));
+        assert!(dockerfile.contains("rustup toolchain install nightly-2024-01-01"));
+        assert!(dockerfile.contains("-- pkg install -e release\n"));
+        assert!(spec.devcontainer_json().contains("\"name\": \"blinky\""));
+
+        assert_eq!(
+            unpinned_platforms(&fs::read_to_string(project_dir.join("platformio.ini")).unwrap()),
+            ["espressif32"]
+        );
+
+        // The legacy file with only the channel
+        fs::remove_file(project_dir.join("rust-toolchain.toml")).unwrap();
+        fs::write(project_dir.join("rust-toolchain"), "esp\n").unwrap();
+        assert_eq!(
+            rust_toolchain(&project_dir).unwrap().as_deref(),
+            Some("esp")
+        );
+
+        let outcomes = spec.generate(&project_dir).unwrap();
+        assert_eq!(
+            outcomes,
+            [
+                (DOCKERFILE, Outcome::Created),
+                (DEVCONTAINER_JSON, Outcome::Created)
+            ]
+        );
+        assert!(spec.diffs(&project_dir).unwrap().is_empty());
+    }
+}
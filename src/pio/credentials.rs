@@ -0,0 +1,293 @@
+//! Download and registry tokens, stored per host in the keyring of the OS.
+//!
+//! `cargo pio auth login <host>` stores a token once, which [`KeyringClient`] then sends
+//! as bearer token with every download from the host, so that no token needs to be kept
+//! in plaintext environment variables or configuration files. The keyrings are accessed
+//! with the tools of the OS:
+//! - the Secret Service (GNOME Keyring, KWallet) with `secret-tool` on Linux,
+//! - the login Keychain with `security` on macOS,
+//! - the Credential Manager with the `PasswordVault` of PowerShell on Windows.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use log::*;
+
+/// The service (Keychain, Secret Service) or resource (Credential Manager) under which the
+/// tokens are stored, with the host as account name.
+pub const SERVICE: &str = "cargo-pio";
+
+/// The host of `url`, lowercased and with its port, e.g. `example.com:8443` for
+/// `https://user@Example.com:8443/file.tgz`.
+pub fn host(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+
+    (!host.is_empty()).then(|| host.to_lowercase())
+}
+
+/// Store `token` for `host`, replacing a stored one.
+pub fn store(host: &str, token: &str) -> Result<()> {
+    imp::store(host, token).with_context(|| format!("Failed to store the token of {}", host))
+}
+
+/// The token stored for `host`, if any.
+pub fn load(host: &str) -> Result<Option<String>> {
+    imp::load(host).with_context(|| format!("Failed to read the token of {}", host))
+}
+
+/// Delete the token stored for `host`, returns whether there was one.
+pub fn delete(host: &str) -> Result<bool> {
+    imp::delete(host).with_context(|| format!("Failed to delete the token of {}", host))
+}
+
+/// Run `cmd` with `input` on its stdin, returning its stdout if it succeeded and `None`
+/// if it exited with an error.
+fn run(mut cmd: Command, input: Option<&str>) -> Result<Option<String>> {
+    debug!("Running {:?}", cmd);
+
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", cmd.get_program()))?;
+
+    if let Some(input) = input {
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    }
+    drop(child.stdin.take());
+
+    let output = child.wait_with_output()?;
+
+    if output.status.success() {
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    } else {
+        debug!(
+            "{:?} failed: {}",
+            cmd.get_program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(None)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use std::process::Command;
+
+    use anyhow::{bail, Result};
+
+    use super::{run, SERVICE};
+
+    fn security(args: &[&str], host: &str) -> Command {
+        let mut cmd = Command::new("security");
+        cmd.args(args).args(["-s", SERVICE, "-a", host]);
+        cmd
+    }
+
+    pub fn store(host: &str, token: &str) -> Result<()> {
+        let mut cmd = security(&["add-generic-password", "-U"], host);
+        cmd.arg("-w").arg(token);
+
+        if run(cmd, None)?.is_none() {
+            bail!("`security add-generic-password` failed");
+        }
+
+        Ok(())
+    }
+
+    pub fn load(host: &str) -> Result<Option<String>> {
+        Ok(run(security(&["find-generic-password", "-w"], host), None)?
+            .map(|token| token.trim_end().to_owned()))
+    }
+
+    pub fn delete(host: &str) -> Result<bool> {
+        Ok(run(security(&["delete-generic-password"], host), None)?.is_some())
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::process::Command;
+
+    use anyhow::{bail, Result};
+
+    use super::{run, SERVICE};
+
+    const VAULT: &str = "[void][Windows.Security.Credentials.PasswordVault,Windows.Security.Credentials,ContentType=WindowsRuntime]; \
+                         $vault = New-Object Windows.Security.Credentials.PasswordVault";
+
+    /// PowerShell running `script` after opening the vault, with the host in `$host_`.
+    fn powershell(host: &str, script: &str) -> Command {
+        let mut cmd = Command::new("powershell");
+        cmd.args(["-NoProfile", "-NonInteractive", "-Command"])
+            .arg(format!(
+                "{}; $resource = '{}'; $host_ = '{}'; {}",
+                VAULT,
+                SERVICE,
+                host.replace('\'', "''"),
+                script
+            ));
+        cmd
+    }
+
+    pub fn store(host: &str, token: &str) -> Result<()> {
+        let cmd = powershell(
+            host,
+            "$token = [Console]::In.ReadLine(); \
+             try { $vault.Remove($vault.Retrieve($resource, $host_)) } catch {}; \
+             $vault.Add((New-Object Windows.Security.Credentials.PasswordCredential($resource, $host_, $token)))",
+        );
+
+        if run(cmd, Some(&format!("{}\n", token)))?.is_none() {
+            bail!("Adding the credential to the PasswordVault failed");
+        }
+
+        Ok(())
+    }
+
+    pub fn load(host: &str) -> Result<Option<String>> {
+        let cmd = powershell(
+            host,
+            "$credential = $vault.Retrieve($resource, $host_); \
+             $credential.RetrievePassword(); \
+             [Console]::Out.Write($credential.Password)",
+        );
+
+        Ok(run(cmd, None)?.filter(|token| !token.is_empty()))
+    }
+
+    pub fn delete(host: &str) -> Result<bool> {
+        let cmd = powershell(host, "$vault.Remove($vault.Retrieve($resource, $host_))");
+
+        Ok(run(cmd, None)?.is_some())
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod imp {
+    use std::process::Command;
+
+    use anyhow::{bail, Result};
+
+    use super::{run, SERVICE};
+
+    fn secret_tool(command: &str, host: &str) -> Command {
+        let mut cmd = Command::new("secret-tool");
+        cmd.arg(command);
+
+        if command == "store" {
+            cmd.arg(format!("--label={} {}", SERVICE, host));
+        }
+
+        cmd.args(["service", SERVICE, "host", host]);
+        cmd
+    }
+
+    pub fn store(host: &str, token: &str) -> Result<()> {
+        if run(secret_tool("store", host), Some(token))?.is_none() {
+            bail!("`secret-tool store` failed, is a Secret Service (e.g. GNOME Keyring) running?");
+        }
+
+        Ok(())
+    }
+
+    pub fn load(host: &str) -> Result<Option<String>> {
+        Ok(run(secret_tool("lookup", host), None)?.filter(|token| !token.is_empty()))
+    }
+
+    pub fn delete(host: &str) -> Result<bool> {
+        let existed = load(host)?.is_some();
+
+        Ok(run(secret_tool("clear", host), None)?.is_some() && existed)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use anyhow::{bail, Result};
+
+    pub fn store(_host: &str, _token: &str) -> Result<()> {
+        bail!("No keyring is supported on this platform")
+    }
+
+    pub fn load(_host: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    pub fn delete(_host: &str) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// The [`HttpClient`](crate::utils::HttpClient) of ureq, sending the token stored for the
+/// host of every download as bearer token.
+///
+/// Hosts without a token, or a keyring which cannot be accessed, are downloaded from
+/// without authentication.
+#[cfg(feature = "ureq")]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct KeyringClient;
+
+#[cfg(feature = "ureq")]
+impl crate::utils::HttpClient for KeyringClient {
+    fn download(&self, url: &str, mut writer: &mut dyn Write) -> Result<()> {
+        let mut request = ureq::get(url);
+
+        let token = match host(url).map(|host| (load(&host), host)) {
+            Some((Ok(token), _)) => token,
+            Some((Err(err), host)) => {
+                debug!("No token for {}: {:#}", host, err);
+                None
+            }
+            None => None,
+        };
+
+        if let Some(token) = token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+
+        crate::utils::download_request_to(request, &mut writer)
+    }
+}
+
+/// Check that `token` is plausible, i.e. a single line without surrounding whitespace.
+pub fn validate(token: &str) -> Result<()> {
+    if token.is_empty() {
+        bail!("The token is empty");
+    }
+
+    if token.trim() != token || token.contains(['\n', '\r']) {
+        bail!("The token contains surrounding whitespace or line breaks");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host() {
+        assert_eq!(
+            host("https://user@Example.com:8443/file.tgz?x=1").as_deref(),
+            Some("example.com:8443")
+        );
+        assert_eq!(
+            host("https://components.espressif.com/api/components/espressif/mdns").as_deref(),
+            Some("components.espressif.com")
+        );
+        assert_eq!(host("example.com").as_deref(), Some("example.com"));
+        assert_eq!(host("file:///tmp/a"), None);
+
+        assert!(validate("ghp_abc").is_ok());
+        assert!(validate("ghp_abc\n").is_err());
+        assert!(validate("").is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! A daemon keeping PlatformIO warm between commands.
+//!
+//! Every `pio` command pays for starting Python, importing PlatformIO and loading the
+//! installed platforms and their boards, which takes seconds. The daemon is a Python
+//! process of the PlatformIO installation which does all of it once and runs the
+//! commands sent to it over a Unix socket (`cargo-pio-daemon.sock` in the PlatformIO
+//! core directory) in forks of itself.
+//!
+//! While a daemon is running, [`Pio::exec`], [`Pio::exec_capture`] and [`Pio::json`]
+//! run their commands in it; everything else, interactive commands like `pio device
+//! monitor` or `pio run -t menuconfig`, and commands run from a terminal which might read
+//! from it, still start PlatformIO. The daemon restarts itself after commands changing
+//! the installed packages and exits after being idle for the configured time.
+//!
+//! The daemon needs `fork` and Unix sockets, so it is not available on Windows.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+
+use super::{inspect, Pio};
+use crate::error::HintExt;
+use crate::terminal;
+
+const DAEMON_PY: &[u8] = include_bytes!("resources/pio-daemon.py.resource");
+
+const SOCKET_FILE: &str = "cargo-pio-daemon.sock";
+const SCRIPT_FILE: &str = "cargo-pio-daemon.py";
+const LOG_FILE: &str = "cargo-pio-daemon.log";
+
+/// PlatformIO commands which read from stdin, which the daemon does not forward.
+const INTERACTIVE_COMMANDS: &[&str] = &["account", "device", "debug", "home", "remote"];
+
+/// Targets of `pio run` which read from stdin or need a terminal.
+const INTERACTIVE_TARGETS: &[&str] = &["menuconfig", "monitor", "upload"];
+
+/// The maximum size of the payload of an output frame; the daemon sends far smaller ones.
+const MAX_FRAME: u32 = 16 * 1024 * 1024;
+
+/// The socket of the daemon of `pio`.
+pub fn socket_path(pio: &Pio) -> PathBuf {
+    pio.core_dir.join(SOCKET_FILE)
+}
+
+/// Whether a daemon is running for `pio`.
+pub fn is_running(pio: &Pio) -> bool {
+    request(socket_path(pio), "ping").is_ok()
+}
+
+/// Start a daemon for `pio`, which exits after being idle for `idle_timeout`.
+///
+/// Returns once the daemon is ready, or right away if one is running already.
+pub fn start(pio: &Pio, idle_timeout: Duration) -> Result<()> {
+    if is_running(pio) {
+        info!("The PlatformIO daemon is running already");
+        return Ok(());
+    }
+
+    let python = inspect::which(pio, "python")
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No Python found to run the PlatformIO daemon with"))?;
+
+    let script = pio.core_dir.join(SCRIPT_FILE);
+    fs::write(&script, DAEMON_PY)?;
+
+    let log_file = pio.core_dir.join(LOG_FILE);
+
+    let mut child = Command::new(python.path)
+        .arg(&script)
+        .arg(socket_path(pio))
+        .arg(idle_timeout.as_secs().to_string())
+        .env("PLATFORMIO_CORE_DIR", &pio.core_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(fs::File::create(&log_file)?)
+        .spawn()?;
+
+    let start = Instant::now();
+
+    // Loading all platforms takes a while on the first start
+    while start.elapsed() < Duration::from_secs(60) {
+        if let Some(status) = child.try_wait()? {
+            return Err(anyhow!("The PlatformIO daemon exited with {}", status))
+                .with_hint(|| format!("See its log {}", log_file.display()));
+        }
+
+        if is_running(pio) {
+            info!("Started the PlatformIO daemon");
+            return Ok(());
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    bail!("The PlatformIO daemon did not get ready in time")
+}
+
+/// Stop the daemon of `pio`, returning whether one was running.
+pub fn stop(pio: &Pio) -> Result<bool> {
+    if !is_running(pio) {
+        return Ok(false);
+    }
+
+    request(socket_path(pio), "stop")?;
+    info!("Stopped the PlatformIO daemon");
+
+    Ok(true)
+}
+
+/// Run the PlatformIO command `cmd` in the daemon of its PlatformIO core directory, if
+/// one is running, forwarding its output to stdout and stderr if `forward`.
+///
+/// Returns `None` if there is no daemon or the command has to run in a process of its
+/// own.
+pub(crate) fn run(cmd: &Command, forward: bool) -> Option<Result<Output>> {
+    let core_dir = cmd
+        .get_envs()
+        .find(|(name, _)| *name == "PLATFORMIO_CORE_DIR")
+        .and_then(|(_, value)| value)?;
+
+    let args = cmd
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    if is_interactive(&args) || (forward && terminal::is_interactive()) {
+        return None;
+    }
+
+    let mut stream = UnixStream::connect(PathBuf::from(core_dir).join(SOCKET_FILE)).ok()?;
+
+    debug!("Running PlatformIO command in the daemon: {:?}", cmd);
+
+    Some((|| {
+        let mut environment = env::vars_os().collect::<BTreeMap<_, _>>();
+        for (name, value) in cmd.get_envs() {
+            match value {
+                Some(value) => environment.insert(name.to_owned(), value.to_owned()),
+                None => environment.remove(name),
+            };
+        }
+
+        let cwd = match cmd.get_current_dir() {
+            Some(dir) => dir.to_owned(),
+            None => env::current_dir()?,
+        };
+
+        let mut request = serde_json::to_vec(&serde_json::json!({
+            "args": args,
+            "cwd": cwd.to_string_lossy(),
+            "env": environment
+                .iter()
+                .map(|(name, value)| (lossy(name), lossy(value)))
+                .collect::<BTreeMap<_, _>>(),
+        }))?;
+        request.push(b'\n');
+
+        stream.write_all(&request)?;
+
+        read_output(stream, forward).context("The PlatformIO daemon failed")
+    })())
+}
+
+/// Whether the PlatformIO command with `args` is interactive: one of
+/// [`INTERACTIVE_COMMANDS`], or with one of [`INTERACTIVE_TARGETS`].
+fn is_interactive(args: &[String]) -> bool {
+    let command = match args.first() {
+        Some(command) => command,
+        None => return true,
+    };
+
+    if INTERACTIVE_COMMANDS.contains(&command.as_str()) {
+        return true;
+    }
+
+    let mut args = args.iter().map(String::as_str);
+    while let Some(arg) = args.next() {
+        let target = match arg {
+            "-t" | "--target" => args.next(),
+            _ => arg
+                .strip_prefix("--target=")
+                .or_else(|| arg.strip_prefix("-t").filter(|target| !target.is_empty())),
+        };
+
+        if target.map_or(false, |target| INTERACTIVE_TARGETS.contains(&target)) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Send the request `name` (`ping` or `stop`) to the daemon at `socket`.
+fn request(socket: PathBuf, name: &str) -> Result<()> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    writeln!(stream, "{{\"{}\": true}}", name)?;
+    read_output(stream, false)?;
+
+    Ok(())
+}
+
+/// Read the output of a command from the frames sent by the daemon: a kind (`o` for
+/// stdout, `e` for stderr, `x` for the exit code), the length of the payload (u32, big
+/// endian) and the payload.
+fn read_output(mut reader: impl Read, forward: bool) -> Result<Output> {
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+
+    loop {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        if len > MAX_FRAME {
+            bail!(
+                "Frame of {} bytes exceeds the maximum of {}",
+                len,
+                MAX_FRAME
+            );
+        }
+
+        let mut payload = Vec::new();
+        (&mut reader).take(len.into()).read_to_end(&mut payload)?;
+        if payload.len() != len as usize {
+            bail!("Truncated frame");
+        }
+
+        match header[0] {
+            b'o' => {
+                if forward {
+                    io::stdout().write_all(&payload)?;
+                    io::stdout().flush()?;
+                }
+
+                stdout.extend(payload);
+            }
+            b'e' => {
+                if forward {
+                    io::stderr().write_all(&payload)?;
+                }
+
+                stderr.extend(payload);
+            }
+            b'x' if payload.len() == 4 => {
+                let code = i32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+
+                return Ok(Output {
+                    status: exit_status(code),
+                    stdout,
+                    stderr,
+                });
+            }
+            kind => bail!("Unknown frame '{}'", kind as char),
+        }
+    }
+}
+
+fn exit_status(code: i32) -> ExitStatus {
+    // The wait status of a process exited with `code`
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+fn lossy(value: &OsString) -> String {
+    value.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_output() {
+        let frames = [
+            &b"o\0\0\0\x05hello"[..],
+            b"e\0\0\0\x04oops",
+            b"o\0\0\0\x01!",
+            b"x\0\0\0\x04\0\0\0\x02",
+        ]
+        .concat();
+
+        let output = read_output(&frames[..], false).unwrap();
+
+        assert_eq!(output.stdout, b"hello!");
+        assert_eq!(output.stderr, b"oops");
+        assert_eq!(output.status.code(), Some(2));
+
+        assert!(read_output(&frames[..10], false).is_err());
+        assert!(read_output(&b"o\xff\xff\xff\xff"[..], false).is_err());
+
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert!(is_interactive(&args(&["device", "list"])));
+        assert!(is_interactive(&args(&[
+            "run",
+            "-e",
+            "debug",
+            "-t",
+            "menuconfig"
+        ])));
+        assert!(is_interactive(&args(&["run", "--target=upload"])));
+        assert!(is_interactive(&args(&["run", "-tmonitor"])));
+        assert!(!is_interactive(&args(&["run", "-t", "size"])));
+        assert!(!is_interactive(&args(&["pkg", "list"])));
+    }
+}
@@ -0,0 +1,461 @@
+//! Detection of the connected board, to select the environment to build and flash.
+//!
+//! The connected devices are probed in three ways:
+//! - the USB vendor and product ids of the serial devices, as listed by PlatformIO;
+//! - the chip of serial devices, as reported by `esptool.py chip_id`, as the ids of the
+//!   common USB-UART bridges (CP210x, CH340) say nothing about the board behind them;
+//! - the USB ids of the debug probes listed by `probe-rs list`, if `probe-rs` is
+//!   installed, for boards without a serial port.
+//!
+//! A device matches an environment if its chip is the MCU of the board of the
+//! environment, or, for devices with an unknown chip, if its USB ids are one of the
+//! `hwids` of the board definition.
+//!
+//! [`watch`] polls the devices and reports the devices that were connected or
+//! disconnected since the previous poll, for `cargo pio ports --watch`.
+
+use std::fmt::{self, Display};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::*;
+use serde::Serialize;
+
+use super::board::BoardInfo;
+use super::{serial_port_url, Pio};
+
+/// A device connected to this machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Device {
+    /// The serial port, `None` for debug probes.
+    pub port: Option<String>,
+    /// The USB vendor and product id.
+    pub vid_pid: Option<(u16, u16)>,
+    /// The chip in the naming of PlatformIO's MCUs, e.g. `ESP32C3`, if known.
+    pub chip: Option<String>,
+    pub description: String,
+}
+
+impl Device {
+    /// Whether this is the board `board`.
+    pub fn is_board(&self, board: &BoardInfo) -> bool {
+        match &self.chip {
+            Some(chip) => *chip == normalize_mcu(&board.mcu),
+            None => self
+                .vid_pid
+                .map_or(false, |vid_pid| board.hwids.contains(&vid_pid)),
+        }
+    }
+
+    /// The device as a JSON object, with the USB ids in hex.
+    pub fn to_json(&self) -> serde_json::Value {
+        let hex = |id: u16| format!("{:04x}", id);
+
+        serde_json::json!({
+            "port": self.port,
+            "vid": self.vid_pid.map(|(vid, _)| hex(vid)),
+            "pid": self.vid_pid.map(|(_, pid)| hex(pid)),
+            "chip": self.chip,
+            "description": self.description,
+        })
+    }
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.port {
+            Some(port) => write!(f, "{}", port)?,
+            None => write!(f, "debug probe")?,
+        }
+
+        if let Some(chip) = &self.chip {
+            write!(f, " ({})", chip)?;
+        }
+
+        if !self.description.is_empty() {
+            write!(f, " - {}", self.description)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a device was connected or disconnected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    Connected,
+    Disconnected,
+}
+
+/// A device that was connected or disconnected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub device: Device,
+    /// The time of the poll that noticed the event, in seconds since the Unix epoch.
+    pub time: u64,
+}
+
+impl Event {
+    /// The event as a single line of JSON, the [JSON](Device::to_json) of the device
+    /// with the `event` and its `time`.
+    pub fn to_json(&self) -> String {
+        let mut json = self.device.to_json();
+        json["event"] = serde_json::to_value(self.kind).unwrap();
+        json["time"] = self.time.into();
+
+        json.to_string()
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            EventKind::Connected => write!(f, "+ {}", self.device)?,
+            EventKind::Disconnected => write!(f, "- {}", self.device)?,
+        }
+
+        if let (None, Some((vid, pid))) = (&self.device.port, self.device.vid_pid) {
+            write!(f, " [{:04x}:{:04x}]", vid, pid)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The events of the devices `old` changing to `new`: the disconnected devices first,
+/// then the connected ones.
+pub fn changes(old: &[Device], new: &[Device], time: u64) -> Vec<Event> {
+    let event = |kind, device: &Device| Event {
+        kind,
+        device: device.clone(),
+        time,
+    };
+
+    old.iter()
+        .filter(|device| !new.contains(device))
+        .map(|device| event(EventKind::Disconnected, device))
+        .chain(
+            new.iter()
+                .filter(|device| !old.contains(device))
+                .map(|device| event(EventKind::Connected, device)),
+        )
+        .collect()
+}
+
+/// Poll the devices every `interval` and call `f` with the events of the devices
+/// connected or disconnected since the previous poll, until `f` fails.
+///
+/// The devices connected at the start are reported as connected if `initial`. The
+/// chips are not probed, as probing resets the devices.
+pub fn watch(
+    pio: &Pio,
+    interval: Duration,
+    initial: bool,
+    mut f: impl FnMut(&Event) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut known = devices(pio, false)?;
+
+    if initial {
+        for event in changes(&[], &known, now()) {
+            f(&event)?;
+        }
+    }
+
+    loop {
+        thread::sleep(interval);
+
+        let current = match devices(pio, false) {
+            Ok(current) => current,
+            Err(err) => {
+                warn!("Failed to list the devices: {:#}", err);
+                continue;
+            }
+        };
+
+        for event in changes(&known, &current, now()) {
+            f(&event)?;
+        }
+
+        known = current;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// An environment whose board is connected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub environment: String,
+    pub board: String,
+    pub device: Device,
+}
+
+impl Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) on {}",
+            self.environment, self.board, self.device
+        )
+    }
+}
+
+/// Probe the devices connected to this machine.
+///
+/// The chips of the serial devices are only probed with esptool if `probe_chips`, as it
+/// resets the devices.
+pub fn devices(pio: &Pio, probe_chips: bool) -> anyhow::Result<Vec<Device>> {
+    let mut devices = Vec::new();
+
+    for serial in pio.serial_devices()? {
+        let chip = if probe_chips && serial.vid_pid().is_some() {
+            esptool_chip(pio, &serial.port)
+        } else {
+            None
+        };
+
+        devices.push(Device {
+            vid_pid: serial.vid_pid(),
+            port: Some(serial.port),
+            chip,
+            description: serial.description,
+        });
+    }
+
+    if let Ok(probe_rs) = which::which("probe-rs") {
+        match Command::new(probe_rs).arg("list").output() {
+            Ok(output) => devices.extend(parse_probe_rs_list(&String::from_utf8_lossy(
+                &output.stdout,
+            ))),
+            Err(err) => warn!("Failed to list the debug probes: {}", err),
+        }
+    }
+
+    Ok(devices)
+}
+
+/// The `(environment, board)` pairs whose board is one of `devices`.
+///
+/// If both debug and release environments match, only the ones of the build type
+/// `build_type` (`debug` or `release`) are returned.
+pub fn candidates(
+    environments: &[(String, BoardInfo)],
+    build_types: impl Fn(&str) -> String,
+    build_type: &str,
+    devices: &[Device],
+) -> Vec<Candidate> {
+    let candidates = environments
+        .iter()
+        .flat_map(|(environment, board)| {
+            devices
+                .iter()
+                .filter(|device| device.is_board(board))
+                .map(|device| Candidate {
+                    environment: environment.clone(),
+                    board: board.id.clone(),
+                    device: device.clone(),
+                })
+        })
+        .collect::<Vec<_>>();
+
+    let (preferred, others): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|candidate| build_types(&candidate.environment) == build_type);
+
+    if preferred.is_empty() {
+        others
+    } else {
+        preferred
+    }
+}
+
+/// The chip of the device at `port` as reported by esptool, `None` if it is not an
+/// Espressif chip.
+fn esptool_chip(pio: &Pio, port: &str) -> Option<String> {
+    debug!("Probing the chip at {}", port);
+
+    parse_esptool_chip(&esptool(pio, port, "chip_id")?)
+}
+
+/// The output of the esptool `command` for the device at `port`, `None` if esptool
+/// could not be run.
+pub(crate) fn esptool(pio: &Pio, port: &str, command: &str) -> Option<String> {
+    let mut cmd = pio.cmd();
+    cmd.args(["pkg", "exec", "-p", "tool-esptoolpy", "--", "esptool.py"])
+        .arg("--port")
+        .arg(serial_port_url(port))
+        .arg(command);
+
+    let output = cmd.output().ok()?;
+
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse the chip of the output of `esptool.py chip_id`, e.g. `Chip is ESP32-C3 (QFN32)
+/// (revision v0.3)`.
+fn parse_esptool_chip(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let chip = line.trim().strip_prefix("Chip is ")?;
+        let chip = chip.split_whitespace().next()?;
+
+        Some(normalize_mcu(chip))
+    })
+}
+
+/// Parse the debug probes of the output of `probe-rs list`, in the format of either
+/// `[0]: STLink V2-1 -- 0483:374b:066DFF... (ST-LINK)` or of older versions, `[0]:
+/// STLink V2-1 (VID: 0483, PID: 374b, Serial: 066DFF..., StLink)`.
+fn parse_probe_rs_list(output: &str) -> Vec<Device> {
+    let hex = |id: &str| u16::from_str_radix(id.trim(), 16).ok();
+
+    output
+        .lines()
+        .filter_map(|line| {
+            let (_, probe) = line.trim().strip_prefix('[')?.split_once("]:")?;
+            let probe = probe.trim();
+
+            let (description, vid_pid) = if let Some((name, ids)) = probe.split_once(" -- ") {
+                let mut ids = ids.split(':');
+                (name, (hex(ids.next()?)?, hex(ids.next()?.get(..4)?)?))
+            } else {
+                let (name, details) = probe.split_once(" (VID: ")?;
+                let (vid, details) = details.split_once(", PID: ")?;
+                (name, (hex(vid)?, hex(details.get(..4)?)?))
+            };
+
+            Some(Device {
+                port: None,
+                vid_pid: Some(vid_pid),
+                chip: None,
+                description: description.trim().to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// The MCU `mcu` in the naming of PlatformIO, e.g. `ESP32C3` for `esp32-c3`.
+pub(crate) fn normalize_mcu(mcu: &str) -> String {
+    mcu.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        assert_eq!(
+            parse_esptool_chip(
+                "esptool.py v4.5\nConnecting....\nDetecting chip type... ESP32-C3\nChip is ESP32-C3 (QFN32) (revision v0.3)\n"
+            ),
+            Some("ESP32C3".to_owned())
+        );
+
+        let probes = parse_probe_rs_list(
+            "The following debug probes were found:\n\
+             [0]: STLink V2-1 -- 0483:374b:066DFF485550755187121723 (ST-LINK)\n\
+             [1]: J-Link (VID: 1366, PID: 0101, Serial: 000123, JLink)\n",
+        );
+        assert_eq!(
+            probes
+                .iter()
+                .map(|probe| (probe.description.as_str(), probe.vid_pid.unwrap()))
+                .collect::<Vec<_>>(),
+            [
+                ("STLink V2-1", (0x0483, 0x374b)),
+                ("J-Link", (0x1366, 0x0101))
+            ]
+        );
+
+        let cp2102 = Device {
+            port: Some("/dev/ttyUSB0".into()),
+            vid_pid: Some((0x10c4, 0xea60)),
+            description: "CP2102".into(),
+            ..Default::default()
+        };
+        let events = changes(std::slice::from_ref(&cp2102), &probes, 42);
+        assert_eq!(
+            events.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "- /dev/ttyUSB0 - CP2102",
+                "+ debug probe - STLink V2-1 [0483:374b]",
+                "+ debug probe - J-Link [1366:0101]"
+            ]
+        );
+        assert_eq!(
+            events[0].to_json(),
+            r#"{"chip":null,"description":"CP2102","event":"disconnected","pid":"ea60","port":"/dev/ttyUSB0","time":42,"vid":"10c4"}"#
+        );
+        assert!(changes(&probes, &probes, 42).is_empty());
+
+        let esp32c3 = BoardInfo {
+            id: "esp32-c3-devkitm-1".into(),
+            mcu: "esp32c3".into(),
+            hwids: vec![(0x10c4, 0xea60)],
+            ..Default::default()
+        };
+        let nucleo = BoardInfo {
+            id: "nucleo_f401re".into(),
+            mcu: "stm32f401ret6".into(),
+            hwids: vec![(0x0483, 0x374b)],
+            ..Default::default()
+        };
+        let environments = [
+            ("debug".to_owned(), esp32c3.clone()),
+            ("release".to_owned(), esp32c3),
+            ("nucleo".to_owned(), nucleo),
+        ];
+        let build_types = |environment: &str| {
+            if environment == "release" {
+                "release".to_owned()
+            } else {
+                "debug".to_owned()
+            }
+        };
+
+        // A CP2102 bridge in front of an ESP32, which is not the board of any environment
+        let esp32 = Device {
+            port: Some("/dev/ttyUSB0".into()),
+            vid_pid: Some((0x10c4, 0xea60)),
+            chip: Some("ESP32".into()),
+            description: "CP2102".into(),
+        };
+        assert!(candidates(
+            &environments,
+            build_types,
+            "debug",
+            std::slice::from_ref(&esp32)
+        )
+        .is_empty());
+
+        let esp32c3 = Device {
+            chip: Some("ESP32C3".into()),
+            ..esp32
+        };
+        assert_eq!(
+            candidates(&environments, build_types, "release", &[esp32c3])
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["release (esp32-c3-devkitm-1) on /dev/ttyUSB0 (ESP32C3) - CP2102"]
+        );
+
+        assert_eq!(
+            candidates(&environments, build_types, "release", &probes)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["nucleo (nucleo_f401re) on debug probe - STLink V2-1"]
+        );
+    }
+}
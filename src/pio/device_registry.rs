@@ -0,0 +1,297 @@
+//! The registry of the known devices of this machine, for telling identical boards apart.
+//!
+//! Five identical boards plugged into one machine have the same USB ids and chip, and
+//! their ports change with every reconnect. `cargo pio device add <name> --port <port>`
+//! records the device at the port under a name, identified by
+//! - its USB serial number, if the USB-UART bridge has one, or
+//! - its MAC address, as read by esptool, for bridges without (e.g. most CH340s),
+//!
+//! with its own settings, the port to fall back to and the environment to flash. `cargo
+//! pio flash --device <name>` then [`locate`]s the device by its identity wherever it is
+//! connected.
+//!
+//! The registry is `cargo-pio-devices.toml` in the PlatformIO core directory, as the
+//! devices belong to the machine rather than to a project:
+//!
+//! ```toml
+//! [devices.lab-3]
+//! serial = "0001A2B3"
+//! environment = "release"
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use super::detect::esptool;
+use super::{Pio, SerialDevice};
+use crate::error::HintExt;
+
+/// The registry file, in the PlatformIO core directory.
+pub const REGISTRY_FILE: &str = "cargo-pio-devices.toml";
+
+/// The known devices.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Registry {
+    /// The devices, keyed by their names.
+    pub devices: BTreeMap<String, KnownDevice>,
+}
+
+/// A known device, identified by its USB serial number or MAC address.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct KnownDevice {
+    /// The USB serial number.
+    pub serial: Option<String>,
+    /// The MAC address, as `aa:bb:cc:dd:ee:ff`.
+    pub mac: Option<String>,
+    /// The port used if the device is not found by its identity, e.g. the URL of a
+    /// serial-over-TCP bridge.
+    pub port: Option<String>,
+    /// The PlatformIO environment flashed to the device if none is given.
+    pub environment: Option<String>,
+}
+
+impl KnownDevice {
+    /// The identity of the device, for display.
+    pub fn identity(&self) -> String {
+        match (&self.serial, &self.mac) {
+            (Some(serial), _) => format!("serial {}", serial),
+            (None, Some(mac)) => format!("MAC {}", mac),
+            (None, None) => "no identity".to_owned(),
+        }
+    }
+
+    /// Whether the serial device `device` is this device, by its USB serial number.
+    fn has_serial(&self, device: &SerialDevice) -> bool {
+        match (&self.serial, device.serial_number()) {
+            (Some(serial), Some(number)) => serial.eq_ignore_ascii_case(number),
+            _ => false,
+        }
+    }
+}
+
+impl Registry {
+    /// The path of the registry of `pio`.
+    pub fn path(pio: &Pio) -> PathBuf {
+        pio.core_dir.join(REGISTRY_FILE)
+    }
+
+    /// Load the registry of `pio`, empty if there is none.
+    pub fn load(pio: &Pio) -> Result<Self> {
+        let path = Self::path(pio);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        toml::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Save the registry of `pio`.
+    pub fn save(&self, pio: &Pio) -> Result<()> {
+        crate::fs::write_atomic(Self::path(pio), toml::to_string(self)?)?;
+
+        Ok(())
+    }
+
+    /// The known device `name`.
+    pub fn get(&self, name: &str) -> Result<&KnownDevice> {
+        self.devices
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown device '{}'", name))
+            .with_hint(|| {
+                if self.devices.is_empty() {
+                    format!("Add it with 'cargo pio device add {} --port <port>'", name)
+                } else {
+                    format!(
+                        "The known devices are {}",
+                        self.devices.keys().cloned().collect::<Vec<_>>().join(", ")
+                    )
+                }
+            })
+    }
+}
+
+/// Identify the device at `port` of the serial `devices`: its USB serial number, or its
+/// MAC address if it has none or `mac`.
+pub fn identify(pio: &Pio, devices: &[SerialDevice], port: &str, mac: bool) -> Result<KnownDevice> {
+    let serial = devices
+        .iter()
+        .find(|device| device.port == port)
+        .and_then(SerialDevice::serial_number)
+        .filter(|_| !mac)
+        .map(str::to_owned);
+
+    let mac = if serial.is_none() {
+        info!("Reading the MAC address of {}", port);
+
+        let mac = esptool(pio, port, "read_mac").and_then(|output| parse_mac(&output));
+        if mac.is_none() {
+            return Err(anyhow!("Failed to read the MAC address of {}", port))
+                .hint("Devices without a USB serial number need to be Espressif chips");
+        }
+
+        mac
+    } else {
+        None
+    };
+
+    Ok(KnownDevice {
+        serial,
+        mac,
+        ..Default::default()
+    })
+}
+
+/// The port of the known `device` among the connected serial `devices`.
+///
+/// Devices identified by their MAC address are probed with esptool, which resets them.
+pub fn locate(pio: &Pio, devices: &[SerialDevice], device: &KnownDevice) -> Result<String> {
+    if let Some(found) = devices.iter().find(|serial| device.has_serial(serial)) {
+        return Ok(found.port.clone());
+    }
+
+    if let Some(mac) = &device.mac {
+        // The hinted port first, it is most likely the device
+        let mut candidates = devices
+            .iter()
+            .filter(|serial| serial.vid_pid().is_some())
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|serial| Some(&serial.port) != device.port.as_ref());
+
+        for candidate in candidates {
+            debug!("Reading the MAC address of {}", candidate.port);
+
+            let found =
+                esptool(pio, &candidate.port, "read_mac").and_then(|output| parse_mac(&output));
+            if found
+                .as_deref()
+                .map_or(false, |found| found.eq_ignore_ascii_case(mac))
+            {
+                return Ok(candidate.port.clone());
+            }
+        }
+    }
+
+    match &device.port {
+        Some(port) => {
+            debug!("{} not found, using port {}", device.identity(), port);
+            Ok(port.clone())
+        }
+        None => bail!("Device with {} is not connected", device.identity()),
+    }
+}
+
+/// Group the `devices` by the environment flashed to them, `None` for those without one.
+///
+/// The groups are in the order of their first device, and keep the order of their devices.
+pub fn by_environment<'a, T>(
+    devices: impl IntoIterator<Item = (&'a KnownDevice, T)>,
+) -> Vec<(Option<String>, Vec<T>)> {
+    let mut groups: Vec<(Option<String>, Vec<T>)> = Vec::new();
+
+    for (device, value) in devices {
+        match groups
+            .iter_mut()
+            .find(|(environment, _)| *environment == device.environment)
+        {
+            Some((_, values)) => values.push(value),
+            None => groups.push((device.environment.clone(), vec![value])),
+        }
+    }
+
+    groups
+}
+
+/// Parse the MAC address of the output of `esptool.py read_mac`, e.g. `MAC:
+/// 24:0a:c4:12:34:56`.
+fn parse_mac(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let mac = line.trim().strip_prefix("MAC:")?.trim();
+
+        (mac.split(':').count() == 6).then(|| mac.to_lowercase())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let registry = toml::from_str::<Registry>(
+            r#"
+            [devices.lab-3]
+            serial = "0001a2b3"
+            environment = "release"
+
+            [devices.bridge]
+            mac = "24:0a:c4:12:34:56"
+            port = "rfc2217://lab:4000"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(registry.get("lab-3").unwrap().identity(), "serial 0001a2b3");
+        assert!(format!("{:#}", registry.get("lab-4").unwrap_err()).contains("bridge, lab-3"));
+
+        let device = |port: &str, hwid: &str| SerialDevice {
+            port: port.into(),
+            hwid: hwid.into(),
+            ..Default::default()
+        };
+        let devices = [
+            device(
+                "/dev/ttyUSB0",
+                "USB VID:PID=10C4:EA60 SER=0001A2B4 LOCATION=1-1",
+            ),
+            device(
+                "/dev/ttyUSB1",
+                "USB VID:PID=10C4:EA60 SER=0001A2B3 LOCATION=1-2",
+            ),
+        ];
+        assert!(!registry.devices["lab-3"].has_serial(&devices[0]));
+        assert!(registry.devices["lab-3"].has_serial(&devices[1]));
+
+        assert_eq!(
+            parse_mac(
+                "esptool.py v4.5\nChip is ESP32-D0WD\nMAC: 24:0A:C4:12:34:56\nHard resetting"
+            ),
+            Some("24:0a:c4:12:34:56".to_owned())
+        );
+        assert_eq!(parse_mac("A fatal error occurred"), None);
+    }
+
+    #[test]
+    fn test_by_environment() {
+        let device = |environment: Option<&str>| KnownDevice {
+            environment: environment.map(str::to_owned),
+            ..Default::default()
+        };
+        let (release, debug, none) = (device(Some("release")), device(Some("debug")), device(None));
+
+        assert_eq!(
+            by_environment(vec![
+                (&release, "lab-1"),
+                (&none, "lab-2"),
+                (&debug, "lab-3"),
+                (&release, "lab-4"),
+            ]),
+            vec![
+                (Some("release".to_owned()), vec!["lab-1", "lab-4"]),
+                (None, vec!["lab-2"]),
+                (Some("debug".to_owned()), vec!["lab-3"]),
+            ]
+        );
+        assert_eq!(
+            by_environment(vec![(&release, 1), (&release, 2)]),
+            vec![(Some("release".to_owned()), vec![1, 2])]
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! The efuses of ESP32 chips, as read by `espefuse.py summary` of PlatformIO's esptool
+//! package.
+//!
+//! The [`Summary`] carries the chip in the naming of PlatformIO's MCUs (like the board
+//! detection), its MAC address and its [`Security`] state, and all efuses with their
+//! category, e.g. the `identity` ones describing the features of the chip (package,
+//! revision, embedded flash and PSRAM).
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use super::detect::normalize_mcu;
+use super::{serial_port_url, Pio};
+use crate::interrupt;
+
+/// An efuse (field) of the summary.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Efuse {
+    /// A boolean, a number or a string (e.g. of MAC addresses, keys and bit fields).
+    pub value: serde_json::Value,
+    /// E.g. `identity`, `security`, `config`, `flash` or `calibration`.
+    pub category: String,
+    pub description: String,
+    pub readable: bool,
+    pub writeable: bool,
+    pub block: Option<u32>,
+}
+
+impl Efuse {
+    /// The value as number, for booleans, numbers and binary, hexadecimal or decimal
+    /// strings.
+    pub fn as_u64(&self) -> Option<u64> {
+        match &self.value {
+            serde_json::Value::Bool(value) => Some(*value as u64),
+            serde_json::Value::Number(value) => value.as_u64(),
+            serde_json::Value::String(value) => {
+                let value = value.trim();
+
+                if let Some(bits) = value.strip_prefix("0b") {
+                    u64::from_str_radix(bits, 2).ok()
+                } else if let Some(hex) = value.strip_prefix("0x") {
+                    u64::from_str_radix(hex, 16).ok()
+                } else {
+                    value.parse().ok()
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The security state of a chip.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Security {
+    pub secure_boot: bool,
+    pub flash_encryption: bool,
+    pub jtag_disabled: bool,
+    pub download_mode_disabled: bool,
+}
+
+/// The efuses of a chip.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Summary {
+    /// The chip in the naming of PlatformIO's MCUs, e.g. `ESP32C3`, if reported.
+    pub chip: Option<String>,
+    /// The efuses, keyed by their name.
+    pub efuses: BTreeMap<String, Efuse>,
+}
+
+impl Summary {
+    /// Parse the JSON summary of espefuse, with the chip of its `output`, if any.
+    pub fn parse(json: &str, output: &str) -> Result<Self> {
+        Ok(Self {
+            chip: parse_chip(output),
+            efuses: serde_json::from_str(json).context("Failed to parse the efuse summary")?,
+        })
+    }
+
+    /// Whether any of the efuses `names` is set.
+    fn any_set(&self, names: &[&str]) -> bool {
+        names.iter().any(|name| {
+            self.efuses
+                .get(*name)
+                .and_then(Efuse::as_u64)
+                .map_or(false, |value| value != 0)
+        })
+    }
+
+    /// The (factory) MAC address, e.g. `24:0a:c4:00:01:02`.
+    pub fn mac(&self) -> Option<String> {
+        // E.g. `24:0a:c4:00:01:02 (OK)`, with the result of the CRC check on the ESP32
+        let mac = self.efuses.get("MAC")?.value.as_str()?;
+
+        mac.split_whitespace().next().map(str::to_owned)
+    }
+
+    /// The security state, from the efuses of the ESP32 or of its successors.
+    pub fn security(&self) -> Security {
+        // Flash encryption is enabled by an odd number of bits set in the counter
+        let crypt_cnt = ["FLASH_CRYPT_CNT", "SPI_BOOT_CRYPT_CNT"]
+            .iter()
+            .find_map(|name| self.efuses.get(*name)?.as_u64())
+            .unwrap_or_default();
+
+        Security {
+            secure_boot: self.any_set(&["ABS_DONE_0", "ABS_DONE_1", "SECURE_BOOT_EN"]),
+            flash_encryption: crypt_cnt.count_ones() % 2 == 1,
+            jtag_disabled: self.any_set(&["JTAG_DISABLE", "DIS_PAD_JTAG", "HARD_DIS_JTAG"]),
+            download_mode_disabled: self.any_set(&["UART_DOWNLOAD_DIS", "DIS_DOWNLOAD_MODE"]),
+        }
+    }
+
+    /// The efuses of `category`.
+    pub fn category<'a>(&'a self, category: &'a str) -> impl Iterator<Item = (&'a str, &'a Efuse)> {
+        self.efuses
+            .iter()
+            .filter(move |(_, efuse)| efuse.category == category)
+            .map(|(name, efuse)| (name.as_str(), efuse))
+    }
+
+    /// The summary for humans: the chip, its MAC address, its security state and the
+    /// efuses describing its features.
+    pub fn render(&self) -> String {
+        let security = self.security();
+        let state = |set: bool, on: &'static str, off: &'static str| if set { on } else { off };
+
+        let mut summary = format!(
+            "Chip:             {}\n\
+             MAC:              {}\n\
+             Secure boot:      {}\n\
+             Flash encryption: {}\n\
+             JTAG:             {}\n\
+             Download mode:    {}\n",
+            self.chip.as_deref().unwrap_or("unknown"),
+            self.mac().as_deref().unwrap_or("unknown"),
+            state(security.secure_boot, "enabled", "disabled"),
+            state(security.flash_encryption, "enabled", "disabled"),
+            state(security.jtag_disabled, "disabled", "enabled"),
+            state(security.download_mode_disabled, "disabled", "enabled"),
+        );
+
+        let identity = self.category("identity").collect::<Vec<_>>();
+        if !identity.is_empty() {
+            let width = identity
+                .iter()
+                .map(|(name, _)| name.len())
+                .max()
+                .unwrap_or_default();
+
+            summary.push_str("\nIdentity:\n");
+
+            for (name, efuse) in identity {
+                let value = match &efuse.value {
+                    serde_json::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+
+                summary.push_str(&format!(
+                    "  {:<width$}  {:<8}  {}\n",
+                    name,
+                    value,
+                    efuse.description,
+                    width = width
+                ));
+            }
+        }
+
+        summary
+    }
+}
+
+/// Read the efuses of the chip at `port` (auto-detected if `None`), of the type `chip`
+/// (e.g. `esp32c3`, auto-detected if `None`).
+pub fn read(pio: &Pio, port: Option<&str>, chip: Option<&str>) -> Result<Summary> {
+    let temp_dir = tempfile::tempdir()?;
+    let json = temp_dir.path().join("efuses.json");
+
+    let mut cmd = pio.cmd();
+    cmd.args(["pkg", "exec", "-p", "tool-esptoolpy", "--", "espefuse.py"]);
+
+    if let Some(chip) = chip {
+        cmd.arg("--chip").arg(chip);
+    }
+
+    if let Some(port) = port {
+        cmd.arg("--port").arg(serial_port_url(port));
+    }
+
+    // The JSON goes to a file of its own, the output also has the connection progress
+    cmd.args(["summary", "--format", "json", "--file"])
+        .arg(&json);
+
+    debug!("Running PlatformIO command: {:?}", cmd);
+
+    let output = interrupt::output(&mut cmd)?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    if !output.status.success() {
+        bail!(
+            "Reading the efuses failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let json = fs::read_to_string(&json)
+        .with_context(|| format!("espefuse.py wrote no summary, its output:\n{}", stdout))?;
+
+    Summary::parse(&json, &stdout)
+}
+
+/// Parse the chip of the output of espefuse or esptool, e.g. `Detecting chip type...
+/// ESP32-C3`.
+fn parse_chip(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let chip = line.trim().strip_prefix("Detecting chip type...")?;
+        let chip = chip.split_whitespace().next()?;
+
+        Some(normalize_mcu(chip))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let summary = Summary::parse(
+            r#"{
+                "MAC": {"value": "24:0a:c4:00:01:02 (OK)", "category": "identity", "description": "Factory MAC Address", "readable": true, "writeable": true, "block": 0},
+                "CHIP_VER_PKG": {"value": 1, "category": "identity", "description": "Chip package identifier", "readable": true, "writeable": true, "block": 0},
+                "ABS_DONE_0": {"value": false, "category": "security", "description": "Secure boot V1 is enabled", "readable": true, "writeable": true, "block": 0},
+                "ABS_DONE_1": {"value": true, "category": "security", "description": "Secure boot V2 is enabled", "readable": true, "writeable": true, "block": 0},
+                "FLASH_CRYPT_CNT": {"value": "0b0000011", "category": "security", "description": "Flash encryption is enabled if odd", "readable": true, "writeable": true, "block": 0},
+                "JTAG_DISABLE": {"value": true, "category": "security", "description": "Disable JTAG", "readable": true, "writeable": true, "block": 0}
+            }"#,
+            "espefuse.py v4.5\nConnecting....\nDetecting chip type... ESP32\n",
+        )
+        .unwrap();
+
+        assert_eq!(summary.chip.as_deref(), Some("ESP32"));
+        assert_eq!(summary.mac().as_deref(), Some("24:0a:c4:00:01:02"));
+        assert_eq!(
+            summary.security(),
+            Security {
+                secure_boot: true,
+                flash_encryption: false,
+                jtag_disabled: true,
+                download_mode_disabled: false,
+            }
+        );
+        assert_eq!(
+            summary.render(),
+            "Chip:             ESP32\n\
+             MAC:              24:0a:c4:00:01:02\n\
+             Secure boot:      enabled\n\
+             Flash encryption: disabled\n\
+             JTAG:             disabled\n\
+             Download mode:    enabled\n\
+             \n\
+             Identity:\n  \
+               CHIP_VER_PKG  1         Chip package identifier\n  \
+               MAC           24:0a:c4:00:01:02 (OK)  Factory MAC Address\n"
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Export of PIO->Cargo projects as standalone PlatformIO projects.
+//!
+//! A standalone project builds with PlatformIO alone, e.g. for collaborators or
+//! manufacturers who do not use Rust: the Rust static library of every exported
+//! environment is prebuilt into `rust/<environment>/`, and `platformio.cargo.py` is
+//! replaced by `platformio.rust.py`, which only links it. All other files of the project
+//! are copied, except for the Rust sources, the Cargo and cargo-pio files and the build
+//! directories; the options of `platformio.ini` which only Cargo used are removed, and
+//! the linker scripts of the environments in `cargo-pio.toml` become `board_build.ldscript`
+//! options.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::*;
+
+use super::config::{Config, EnvConfig};
+use crate::error::HintExt;
+use crate::pio_model::{env_option, environments};
+
+/// The directory (relative to the exported project) of the prebuilt Rust libraries.
+pub const RUST_DIR: &str = "rust";
+
+/// The script linking the prebuilt Rust library, replacing `platformio.cargo.py`.
+pub const SCRIPT: &str = "platformio.rust.py";
+
+const PLATFORMIO_RUST_PY: &[u8] = include_bytes!("resources/platformio.rust.py.resource");
+
+/// The scripts of cargo-pio which are not needed by a standalone project.
+const CARGO_SCRIPTS: &[&str] = &["platformio.cargo.py", "platformio.dump.py"];
+
+/// The files and directories (relative to the project directory) which are not exported.
+const EXCLUDED: &[&str] = &[
+    "target",
+    "Cargo.toml",
+    "Cargo.lock",
+    "build.rs",
+    "rust-toolchain",
+    "rust-toolchain.toml",
+    super::config::CONFIG_FILE_NAME,
+    "cargo-pio.lock",
+    "platformio.ini",
+];
+
+/// The options of `platformio.ini` which are only used by `platformio.cargo.py`.
+fn is_cargo_option(name: &str) -> bool {
+    name.starts_with("cargo_") || (name.starts_with("rust_") && name != "rust_lib")
+}
+
+/// The Rust static library of the PlatformIO `environment` of the project in
+/// `project_dir`, as built by its last build.
+pub fn staticlib(
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+    env_config: Option<&EnvConfig>,
+) -> Result<PathBuf> {
+    let project_dir = project_dir.as_ref();
+    let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))?;
+
+    let option = |key| env_option(&platformio_ini, environment, key);
+
+    let (rust_lib, target) = match (option("rust_lib"), option("rust_target")) {
+        (Some(rust_lib), Some(target)) => (rust_lib, target),
+        _ => bail!(
+            "Environment {} has no rust_lib or rust_target, it is not a PIO->Cargo environment",
+            environment
+        ),
+    };
+
+    // Like `platformio.cargo.py`, which defaults to the release profile for the default
+    // build type of PlatformIO
+    let profile = env_config
+        .and_then(|config| config.profile.clone())
+        .unwrap_or_else(|| match option("build_type").as_deref() {
+            Some("release") | None => "release".into(),
+            Some(_) => "debug".into(),
+        });
+    let profile_dir = if profile == "dev" { "debug" } else { &profile };
+
+    let target_dir = match option("cargo_target_dir") {
+        Some(dir) => project_dir.join(dir.replace("$PROJECT_DIR", ".")),
+        None if option("cargo_pio_common_build_dir").is_some() => {
+            project_dir.join(".pio").join("build").join("cargo")
+        }
+        None => project_dir.join("target"),
+    };
+
+    let staticlib = target_dir
+        .join(&target)
+        .join(profile_dir)
+        .join(format!("lib{}.a", rust_lib));
+
+    if !staticlib.is_file() {
+        return Err(anyhow::anyhow!(
+            "The Rust library of environment {} was not built, {} does not exist",
+            environment,
+            staticlib.display()
+        ))
+        .hint(format!(
+            "Build it with 'cargo pio build -e {}'",
+            environment
+        ));
+    }
+
+    Ok(staticlib)
+}
+
+/// Export the project in `project_dir` with its `environments` as a standalone
+/// PlatformIO project into `output_dir`, which must not exist or be empty.
+///
+/// The Rust libraries of the environments must have been built, see [`staticlib`].
+pub fn standalone(
+    project_dir: impl AsRef<Path>,
+    output_dir: impl AsRef<Path>,
+    config: &Config,
+    environments: &[String],
+) -> Result<()> {
+    let project_dir = project_dir.as_ref();
+    let output_dir = output_dir.as_ref();
+
+    if fs::read_dir(output_dir).map_or(false, |mut entries| entries.next().is_some()) {
+        bail!("{} exists and is not empty", output_dir.display());
+    }
+
+    let staticlibs = environments
+        .iter()
+        .map(|environment| {
+            Ok((
+                environment,
+                staticlib(project_dir, environment, config.env.get(environment))?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    fs::create_dir_all(output_dir)?;
+
+    let excluded_output = output_dir.canonicalize().ok().and_then(|output| {
+        let project_dir = project_dir.canonicalize().ok()?;
+        output.strip_prefix(project_dir).ok().map(Path::to_owned)
+    });
+
+    copy_dir(
+        project_dir,
+        output_dir,
+        Path::new(""),
+        excluded_output.as_deref(),
+    )?;
+
+    for (environment, staticlib) in &staticlibs {
+        let dir = output_dir.join(RUST_DIR).join(environment);
+        fs::create_dir_all(&dir)?;
+        crate::fs::copy_with_metadata(staticlib, dir.join(staticlib.file_name().unwrap()))?;
+
+        info!(
+            "Exported the Rust library of environment {} ({})",
+            environment,
+            staticlib.display()
+        );
+    }
+
+    let ldscripts = environments
+        .iter()
+        .filter_map(|environment| {
+            let ldscript = config.env.get(environment)?.ldscript.clone()?;
+            Some((environment.clone(), ldscript))
+        })
+        .collect();
+
+    let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))?;
+    fs::write(
+        output_dir.join("platformio.ini"),
+        standalone_platformio_ini(&platformio_ini, environments, &ldscripts),
+    )?;
+    fs::write(output_dir.join(SCRIPT), PLATFORMIO_RUST_PY)?;
+
+    Ok(())
+}
+
+/// Copy the exported files of the directory `relative` of `project_dir` to `output_dir`.
+fn copy_dir(
+    project_dir: &Path,
+    output_dir: &Path,
+    relative: &Path,
+    excluded: Option<&Path>,
+) -> Result<()> {
+    let entries = fs::read_dir(project_dir.join(relative))
+        .with_context(|| format!("Failed to read {}", project_dir.join(relative).display()))?;
+
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let path = relative.join(&name);
+        let name = name.to_string_lossy();
+
+        let skip = (relative.as_os_str().is_empty()
+            && (name.starts_with('.') || EXCLUDED.contains(&name.as_ref())))
+            || CARGO_SCRIPTS.contains(&name.as_ref())
+            || name.ends_with(".rs")
+            || Some(path.as_path()) == excluded;
+
+        if skip {
+            debug!("Not exporting {}", path.display());
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(output_dir.join(&path))?;
+            copy_dir(project_dir, output_dir, &path, excluded)?;
+        } else {
+            crate::fs::copy_with_metadata(entry.path(), output_dir.join(&path))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `platformio_ini` for a standalone project with the environments `exported`: the
+/// other environments and the options of Cargo are removed, `platformio.rust.py` replaces
+/// the scripts of cargo-pio and the `ldscripts` of the environments are set.
+fn standalone_platformio_ini(
+    platformio_ini: &str,
+    exported: &[String],
+    ldscripts: &BTreeMap<String, PathBuf>,
+) -> String {
+    // The sections with their lines, the lines before the first section have none
+    let mut sections: Vec<(Option<&str>, Vec<String>)> = vec![(None, Vec::new())];
+    let mut skipping_option = false;
+
+    for line in platformio_ini.lines() {
+        let content = line.split(';').next().unwrap_or_default().trim_end();
+        let trimmed = content.trim();
+
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            sections.push((Some(trimmed), Vec::new()));
+            skipping_option = false;
+            continue;
+        } else if trimmed.is_empty() || !line.starts_with(char::is_whitespace) {
+            skipping_option = trimmed
+                .split_once('=')
+                .map_or(false, |(name, _)| is_cargo_option(name.trim()));
+        }
+
+        if skipping_option {
+            continue;
+        }
+
+        let line = CARGO_SCRIPTS.iter().fold(line.to_owned(), |line, script| {
+            let replacement = if *script == "platformio.cargo.py" {
+                SCRIPT
+            } else {
+                ""
+            };
+
+            line.replace(script, replacement)
+        });
+
+        // Remove the entries of the removed scripts from their lists
+        let line = line
+            .replace(", ,", ",")
+            .trim_end_matches([',', ' '])
+            .to_owned();
+        if line.trim() == "pre:" || line.trim() == "post:" {
+            continue;
+        }
+
+        sections.last_mut().unwrap().1.push(line);
+    }
+
+    let mut result = String::new();
+
+    for (section, mut lines) in sections {
+        let environment = section
+            .and_then(|section| section.strip_prefix("[env:"))
+            .and_then(|section| section.strip_suffix(']'));
+
+        if let Some(environment) = environment {
+            if !exported.iter().any(|exported| exported == environment) {
+                continue;
+            }
+
+            if let Some(ldscript) = ldscripts.get(environment) {
+                let end = lines
+                    .iter()
+                    .rposition(|line| !line.trim().is_empty())
+                    .map_or(0, |index| index + 1);
+                lines.insert(
+                    end,
+                    format!("board_build.ldscript = {}", ldscript.display()),
+                );
+            }
+        }
+
+        for line in section.map(str::to_owned).into_iter().chain(lines) {
+            result.push_str(&line);
+            result.push('\n');
+        }
+    }
+
+    result.truncate(result.trim_end().len());
+    result.push('\n');
+
+    result
+}
+
+/// The environments of the project in `project_dir` which build Rust code.
+pub fn environments_with_rust(project_dir: impl AsRef<Path>) -> Result<Vec<String>> {
+    let platformio_ini = fs::read_to_string(project_dir.as_ref().join("platformio.ini"))?;
+
+    Ok(environments(&platformio_ini)
+        .into_iter()
+        .filter(|environment| env_option(&platformio_ini, environment, "rust_lib").is_some())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_standalone_platformio_ini() {
+        let platformio_ini = "\
+; cargo-pio: begin managed block (do not edit)
+[platformio]
+default_envs = debug
+
+[env]
+extra_scripts = pre:platformio.git.py, platformio.cargo.py
+board = esp32dev
+rust_lib = app
+rust_target = xtensa-esp32-espidf
+cargo_options = --features
+  wifi
+
+[env:debug]
+build_type = debug
+
+[env:release]
+build_type = release
+cargo_profile = size
+";
+
+        let ldscripts = [("release".to_owned(), PathBuf::from("ld/app.ld"))]
+            .into_iter()
+            .collect();
+
+        assert_eq!(
+            standalone_platformio_ini(platformio_ini, &["release".to_owned()], &ldscripts),
+            "\
+; cargo-pio: begin managed block (do not edit)
+[platformio]
+default_envs = debug
+
+[env]
+extra_scripts = pre:platformio.git.py, platformio.rust.py
+board = esp32dev
+rust_lib = app
+
+[env:release]
+build_type = release
+board_build.ldscript = ld/app.ld
+"
+        );
+    }
+}
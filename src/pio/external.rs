@@ -0,0 +1,245 @@
+//! Existing installations of ESP-IDF and its toolchains, used instead of the packages
+//! PlatformIO downloads (see the `path`, `version` and `toolchains` of
+//! [`EspidfConfig`]).
+//!
+//! An ESP-IDF checkout with local patches, or the installation shared with the ESP-IDF
+//! tools of the machine, is [`register`]ed as a PlatformIO package: it gets the package
+//! manifest PlatformIO and the `espressif32` platform read the name and version of, and
+//! is pinned as a `symlink://` package in the `platform_packages` of the environment.
+//! Hooks and tasks get `IDF_PATH` and the toolchains on the `PATH`, as with the managed
+//! packages.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+
+use super::compat::{package_name, Framework};
+use super::config::EspidfConfig;
+use crate::error::HintExt;
+use crate::pio_model::{env_option, list, set_env_option};
+
+/// The package manifest of PlatformIO.
+const MANIFEST: &str = "package.json";
+
+/// Register the directory `path` as the version `version` of the package `name`,
+/// returning its entry of `platform_packages`.
+///
+/// The manifest of the package is created, or updated if its name or version differ.
+pub fn register(name: &str, version: &str, path: impl AsRef<Path>) -> Result<String> {
+    let path = path.as_ref();
+
+    if !path.is_dir() {
+        bail!("{} of package {} is not a directory", path.display(), name);
+    }
+
+    let manifest_path = path.join(MANIFEST);
+    let mut manifest = match fs::read_to_string(&manifest_path) {
+        Ok(manifest) => serde_json::from_str::<serde_json::Value>(&manifest)
+            .with_context(|| format!("Failed to parse {}", manifest_path.display()))?,
+        Err(_) => serde_json::json!({}),
+    };
+
+    if manifest["name"] != name || manifest["version"] != version {
+        debug!("Registering {} as {}@{}", path.display(), name, version);
+
+        manifest["name"] = name.into();
+        manifest["version"] = version.into();
+
+        fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)
+            .with_context(|| format!("Failed to write {}", manifest_path.display()))?;
+    }
+
+    Ok(format!(
+        "{} @ symlink://{}",
+        name,
+        path.display().to_string().replace('\\', "/")
+    ))
+}
+
+/// The version (major, minor and patch) of the ESP-IDF checkout in `path`.
+pub fn espidf_version(path: impl AsRef<Path>) -> Result<(u32, u32, u32)> {
+    let version_cmake = path
+        .as_ref()
+        .join("tools")
+        .join("cmake")
+        .join("version.cmake");
+    let content = fs::read_to_string(&version_cmake)
+        .with_context(|| format!("Failed to read {}", version_cmake.display()))
+        .hint("The path needs to be an ESP-IDF checkout")?;
+
+    let component = |name: &str| {
+        content
+            .lines()
+            .find_map(|line| {
+                line.trim()
+                    .strip_prefix(&format!("set(IDF_VERSION_{}", name))?
+                    .trim()
+                    .strip_suffix(')')?
+                    .trim()
+                    .parse()
+                    .ok()
+            })
+            .ok_or_else(|| anyhow!("No IDF_VERSION_{} in {}", name, version_cmake.display()))
+    };
+
+    Ok((
+        component("MAJOR")?,
+        component("MINOR")?,
+        component("PATCH")?,
+    ))
+}
+
+/// Pin the external ESP-IDF and toolchains of `config` in the PlatformIO `environment`
+/// of the project in `project_dir`.
+///
+/// Returns whether `platformio.ini` changed.
+pub fn apply(
+    config: &EspidfConfig,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+) -> Result<bool> {
+    let project_dir = project_dir.as_ref();
+
+    if config.path.is_none() && config.toolchains.is_empty() {
+        return Ok(false);
+    }
+
+    let mut packages = Vec::new();
+
+    if let Some(path) = &config.path {
+        let path = project_dir.join(path);
+        let (major, minor, patch) = espidf_version(&path)?;
+        let version = format!("{}.{}.{}", major, minor, patch);
+
+        if let Some(required) = &config.version {
+            if !matches(required, &version) {
+                bail!(
+                    "ESP-IDF in {} is version {}, not {}",
+                    path.display(),
+                    version,
+                    required
+                );
+            }
+        }
+
+        // The platform encodes the ESP-IDF version 5.1.2 as 3.50102.0
+        packages.push(register(
+            Framework::Espidf.package(),
+            &format!("3.{}{:02}{:02}.0", major, minor, patch),
+            &path,
+        )?);
+    }
+
+    for (name, toolchain) in &config.toolchains {
+        packages.push(register(
+            name,
+            &toolchain.version,
+            project_dir.join(&toolchain.path),
+        )?);
+    }
+
+    let path = project_dir.join("platformio.ini");
+    let platformio_ini = fs::read_to_string(&path).context("Failed to read platformio.ini")?;
+    let pinned = pin(&platformio_ini, environment, &packages)?;
+
+    if pinned == platformio_ini {
+        return Ok(false);
+    }
+
+    info!(
+        "Using {} in environment {}",
+        packages.join(", "),
+        environment
+    );
+
+    fs::write(&path, pinned)?;
+
+    Ok(true)
+}
+
+/// Pin `packages` in the `platform_packages` of the PlatformIO `environment` of
+/// `platformio_ini`, replacing the other pins of the same packages.
+fn pin(platformio_ini: &str, environment: &str, packages: &[String]) -> Result<String> {
+    let mut pinned = env_option(platformio_ini, environment, "platform_packages")
+        .map(|pinned| list(&pinned))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|pinned| {
+            packages
+                .iter()
+                .all(|package| package_name(package) != package_name(pinned))
+        })
+        .collect::<Vec<_>>();
+    pinned.extend(packages.iter().cloned());
+
+    set_env_option(
+        platformio_ini,
+        environment,
+        "platform_packages",
+        &pinned.join("\n"),
+    )
+    .ok_or_else(|| anyhow!("platformio.ini has no environment {}", environment))
+}
+
+/// Whether `version` is the `required` one, or one of its patch releases if `required`
+/// omits parts of it, e.g. `5.1.2` is `5.1`.
+fn matches(required: &str, version: &str) -> bool {
+    let required = required.trim().trim_start_matches('v');
+
+    required
+        .split('.')
+        .zip(version.split('.'))
+        .all(|(required, part)| required == part)
+        && required.split('.').count() <= version.split('.').count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let dir = tempfile::tempdir().unwrap();
+        let cmake = dir.path().join("tools").join("cmake");
+        fs::create_dir_all(&cmake).unwrap();
+        fs::write(
+            cmake.join("version.cmake"),
+            "set(IDF_VERSION_MAJOR 5)\nset(IDF_VERSION_MINOR 1)\nset(IDF_VERSION_PATCH 2)\n",
+        )
+        .unwrap();
+
+        assert_eq!(espidf_version(dir.path()).unwrap(), (5, 1, 2));
+        assert!(matches("5.1", "5.1.2"));
+        assert!(matches("v5.1.2", "5.1.2"));
+        assert!(!matches("5.1.3", "5.1.2"));
+        assert!(!matches("5.10", "5.1.2"));
+
+        let package = register("framework-espidf", "3.50102.0", dir.path()).unwrap();
+        assert!(package.starts_with("framework-espidf @ symlink://"));
+
+        let manifest = serde_json::from_str::<serde_json::Value>(
+            &fs::read_to_string(dir.path().join(MANIFEST)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(manifest["version"], "3.50102.0");
+
+        let platformio_ini = "[env:debug]\n\
+                              platform = espressif32\n\
+                              platform_packages =\n    \
+                              platformio/framework-espidf @ ~3.50101.0\n    \
+                              platformio/tool-esptoolpy @ ~1.40501.0\n";
+
+        let pinned = pin(platformio_ini, "debug", std::slice::from_ref(&package)).unwrap();
+        assert_eq!(
+            list(&env_option(&pinned, "debug", "platform_packages").unwrap()),
+            ["platformio/tool-esptoolpy @ ~1.40501.0", package.as_str()]
+        );
+        assert_eq!(
+            pin(&pinned, "debug", std::slice::from_ref(&package)).unwrap(),
+            pinned
+        );
+        assert!(pin(platformio_ini, "release", &[package]).is_err());
+    }
+}
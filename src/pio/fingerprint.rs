@@ -0,0 +1,167 @@
+//! The identity of the toolchain PIO->Cargo projects are built with.
+//!
+//! Neither SCons nor Cargo notice when the compiler changes underneath them while its
+//! path stays the same (e.g. a platform update replacing `packages/toolchain-xtensa`),
+//! and keep linking objects built by the old one. The fingerprint identifies the
+//! platform and its installed toolchain and framework packages; builds whose
+//! fingerprint changed since the last successful build start from a clean environment.
+//!
+//! The key of the fingerprint is passed to the build as `CARGO_PIO_TOOLCHAIN_KEY`, for
+//! build scripts (`cargo:rerun-if-env-changed`) and compiler caches to key on.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use super::graph::Platform;
+use super::Pio;
+
+/// The environment variable the key is passed to the build in.
+pub const VAR_TOOLCHAIN_KEY: &str = "CARGO_PIO_TOOLCHAIN_KEY";
+
+/// The file the fingerprint of the last successful build is stored in, in the build
+/// directory of the environment.
+pub const FINGERPRINT_FILE: &str = "toolchain.fingerprint";
+
+/// The package types that determine the output of a build.
+const PACKAGE_TYPES: &[&str] = &["toolchain", "framework"];
+
+/// The fingerprint of the toolchain of an environment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// The identities of the platform and its packages, as `<name>@<version>`.
+    pub components: Vec<String>,
+    /// The composite key of the components and the contents of their manifests.
+    pub key: String,
+}
+
+impl Fingerprint {
+    /// Collect the fingerprint of the PlatformIO `environment` of the project in
+    /// `project_dir`.
+    ///
+    /// Installing the toolchain of another environment of the same platform changes the
+    /// fingerprint as well, at the cost of one unnecessary clean build.
+    pub fn collect(pio: &Pio, project_dir: impl AsRef<Path>, environment: &str) -> Result<Self> {
+        let platformio_ini = fs::read_to_string(project_dir.as_ref().join("platformio.ini"))
+            .context("Failed to read platformio.ini")?;
+
+        let platform = Platform::resolve(pio, &platformio_ini, environment)?;
+
+        // This uses the default hasher from the standard library, which is not guaranteed
+        // to be the same across versions; a different hash only causes one clean build.
+        let mut hasher = DefaultHasher::new();
+
+        let mut components = vec![format!(
+            "{}@{}",
+            platform.name,
+            platform.version.as_deref().unwrap_or("unknown")
+        )];
+        fs::read(platform.dir.join("platform.json"))
+            .unwrap_or_default()
+            .hash(&mut hasher);
+
+        for package in platform.packages.iter().filter(|package| {
+            package
+                .package_type
+                .as_deref()
+                .map_or(false, |package_type| PACKAGE_TYPES.contains(&package_type))
+        }) {
+            components.push(format!("{}@{}", package.name, package.installed.version));
+            fs::read(package.installed.dir.join("package.json"))
+                .unwrap_or_default()
+                .hash(&mut hasher);
+        }
+
+        components.hash(&mut hasher);
+
+        Ok(Self {
+            components,
+            key: format!("{:016x}", hasher.finish()),
+        })
+    }
+
+    /// Load the fingerprint of the last successful build from `build_dir`, if any.
+    pub fn load(build_dir: impl AsRef<Path>) -> Option<Self> {
+        let content = fs::read_to_string(build_dir.as_ref().join(FINGERPRINT_FILE)).ok()?;
+        let mut lines = content.lines();
+
+        Some(Self {
+            key: lines.next()?.to_owned(),
+            components: lines.map(str::to_owned).collect(),
+        })
+    }
+
+    /// Store the fingerprint into `build_dir`.
+    pub fn save(&self, build_dir: impl AsRef<Path>) -> Result<()> {
+        let mut content = format!("{}\n", self.key);
+        for component in &self.components {
+            content.push_str(component);
+            content.push('\n');
+        }
+
+        crate::fs::write_atomic(build_dir.as_ref().join(FINGERPRINT_FILE), content)?;
+
+        Ok(())
+    }
+
+    /// The components which differ from the ones of `other`, as `<old> -> <new>`.
+    pub fn changes(&self, other: &Self) -> Vec<String> {
+        let name = |component: &str| component.split('@').next().unwrap_or_default().to_owned();
+
+        let mut changes = Vec::new();
+
+        for component in &self.components {
+            match other.components.iter().find(|c| name(c) == name(component)) {
+                Some(old) if old != component => changes.push(format!("{} -> {}", old, component)),
+                Some(_) => (),
+                None => changes.push(format!("+ {}", component)),
+            }
+        }
+
+        for old in &other.components {
+            if !self.components.iter().any(|c| name(c) == name(old)) {
+                changes.push(format!("- {}", old));
+            }
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changes() {
+        let old = Fingerprint {
+            components: vec![
+                "espressif32@4.4.0".into(),
+                "toolchain-xtensa-esp32@8.4.0".into(),
+                "framework-arduinoespressif32@3.20003.0".into(),
+            ],
+            key: "0".into(),
+        };
+
+        let new = Fingerprint {
+            components: vec![
+                "espressif32@5.0.0".into(),
+                "toolchain-xtensa-esp32@8.4.0".into(),
+                "framework-espidf@3.40401.0".into(),
+            ],
+            key: "1".into(),
+        };
+
+        assert_eq!(
+            new.changes(&old),
+            vec![
+                "espressif32@4.4.0 -> espressif32@5.0.0",
+                "+ framework-espidf@3.40401.0",
+                "- framework-arduinoespressif32@3.20003.0",
+            ]
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! The dependency graph of the PlatformIO packages of a project environment.
+//!
+//! The graph links the environment to its platform, framework(s) and libraries, the
+//! platform to the packages it declares which are installed in PlatformIO's core
+//! directory (toolchains, SDKs, tools), and libraries to their dependencies. Every node
+//! carries the installed size, to show where the disk space goes.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+use super::{InstalledPackage, Pio};
+use crate::pio_model::{env_option, list};
+
+/// The kind of a node of the graph.
+#[derive(Serialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Environment,
+    Platform,
+    Package,
+    Library,
+}
+
+/// A node of the graph.
+#[derive(Serialize, Clone, Debug)]
+pub struct Node {
+    pub kind: Kind,
+    pub name: String,
+    pub version: Option<String>,
+    /// The package type declared by the platform, e.g. `toolchain` or `framework`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package_type: Option<String>,
+    /// The installed size in bytes, `None` if not installed.
+    pub size: Option<u64>,
+    /// The installed manifest (`platform.json`, `package.json` or `library.json`).
+    #[serde(skip)]
+    pub manifest: Option<PathBuf>,
+}
+
+/// The dependency graph of an environment.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Graph {
+    /// The nodes, keyed by `<kind>:<name>`.
+    pub nodes: BTreeMap<String, Node>,
+    /// The edges, from dependent to dependency.
+    pub edges: Vec<(String, String)>,
+}
+
+impl Graph {
+    /// Collect the graph of the PlatformIO `environment` of the project in `project_dir`.
+    pub fn collect(pio: &Pio, project_dir: impl AsRef<Path>, environment: &str) -> Result<Self> {
+        let project_dir = project_dir.as_ref();
+
+        let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))
+            .context("Failed to read platformio.ini")?;
+
+        let mut graph = Self::default();
+
+        let root = graph.add(Node {
+            kind: Kind::Environment,
+            name: environment.to_owned(),
+            version: None,
+            package_type: None,
+            size: None,
+            manifest: None,
+        });
+
+        let platform = Platform::resolve(pio, &platformio_ini, environment)?;
+
+        let platform_node = graph.add(Node {
+            kind: Kind::Platform,
+            name: platform.name.clone(),
+            version: platform.version.clone(),
+            package_type: None,
+            size: dir_size(&platform.dir),
+            manifest: Some(platform.dir.join("platform.json")),
+        });
+        graph.edges.push((root.clone(), platform_node.clone()));
+
+        let frameworks = env_option(&platformio_ini, environment, "framework")
+            .map(|frameworks| list(&frameworks))
+            .unwrap_or_default();
+
+        for package in platform.packages {
+            let node = graph.add(Node {
+                kind: Kind::Package,
+                name: package.name.clone(),
+                version: Some(package.installed.version).filter(|version| !version.is_empty()),
+                package_type: package.package_type.clone(),
+                size: dir_size(&package.installed.dir),
+                manifest: Some(package.installed.dir.join("package.json")),
+            });
+
+            graph.edges.push((platform_node.clone(), node.clone()));
+
+            // Frameworks are selected by the environment itself
+            if package.package_type.as_deref() == Some("framework")
+                && frameworks
+                    .iter()
+                    .any(|framework| package.name == format!("framework-{}", framework))
+            {
+                graph.edges.push((root.clone(), node));
+            }
+        }
+
+        let libdeps_dir = project_dir.join(".pio").join("libdeps").join(environment);
+        let lib_deps = env_option(&platformio_ini, environment, "lib_deps")
+            .map(|lib_deps| list(&lib_deps))
+            .unwrap_or_default();
+
+        let mut libraries = BTreeMap::new();
+
+        if let Ok(entries) = fs::read_dir(&libdeps_dir) {
+            for entry in entries.flatten() {
+                let manifest = match read_json(&entry.path().join("library.json")) {
+                    Some(manifest) => manifest,
+                    None => continue,
+                };
+
+                let name = manifest["name"]
+                    .as_str()
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| entry.file_name().to_string_lossy().into_owned());
+
+                let node = graph.add(Node {
+                    kind: Kind::Library,
+                    name: name.clone(),
+                    version: manifest["version"].as_str().map(str::to_owned),
+                    package_type: None,
+                    size: dir_size(&entry.path()),
+                    manifest: Some(entry.path().join("library.json")),
+                });
+
+                libraries.insert(name, (node, library_dependencies(&manifest)));
+            }
+        }
+
+        for (name, (node, dependencies)) in &libraries {
+            // `owner/name@^1.0.0` in lib_deps
+            let direct = lib_deps.iter().any(|dep| {
+                dep.split('@').next().unwrap_or_default().rsplit('/').next() == Some(name.as_str())
+            });
+
+            let dependents = libraries
+                .values()
+                .filter(|(_, other)| other.contains(name))
+                .count();
+
+            // Libraries which are neither listed nor a dependency were found by the
+            // dependency finder in the sources
+            if direct || dependents == 0 {
+                graph.edges.push((root.clone(), node.clone()));
+            }
+
+            for dependency in dependencies {
+                if let Some((dependency, _)) = libraries.get(dependency) {
+                    graph.edges.push((node.clone(), dependency.clone()));
+                }
+            }
+        }
+
+        Ok(graph)
+    }
+
+    fn add(&mut self, node: Node) -> String {
+        let id = format!("{:?}:{}", node.kind, node.name).to_lowercase();
+        self.nodes.insert(id.clone(), node);
+
+        id
+    }
+
+    /// The total installed size of the nodes.
+    pub fn size(&self) -> u64 {
+        self.nodes.values().filter_map(|node| node.size).sum()
+    }
+
+    /// Render the graph in the Graphviz DOT language.
+    pub fn dot(&self) -> String {
+        let mut dot = String::from("digraph packages {\n    rankdir=LR;\n    node [shape=box];\n");
+
+        for (id, node) in &self.nodes {
+            let mut label = node.name.clone();
+
+            if let Some(version) = &node.version {
+                write!(label, "\\n{}", version).unwrap();
+            }
+
+            if let Some(size) = node.size {
+                write!(label, "\\n{}", format_size(size)).unwrap();
+            }
+
+            let style = match node.kind {
+                Kind::Environment => ", style=bold",
+                Kind::Platform => ", style=rounded",
+                _ => "",
+            };
+
+            writeln!(
+                dot,
+                "    \"{}\" [label=\"{}\"{}];",
+                id,
+                label.replace('"', "\\\""),
+                style
+            )
+            .unwrap();
+        }
+
+        for (from, to) in &self.edges {
+            writeln!(dot, "    \"{}\" -> \"{}\";", from, to).unwrap();
+        }
+
+        dot.push_str("}\n");
+
+        dot
+    }
+
+    /// Render the graph as JSON, with `nodes` keyed by id and `edges` as `[from, to]`
+    /// pairs.
+    pub fn json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// The platform of an environment, as installed in PlatformIO's core directory.
+#[derive(Clone, Debug)]
+pub struct Platform {
+    pub name: String,
+    pub dir: PathBuf,
+    /// The version from the `platform.json` manifest, `None` if not installed.
+    pub version: Option<String>,
+    /// The packages declared by the platform which are installed.
+    pub packages: Vec<Package>,
+}
+
+/// An installed package of a platform.
+#[derive(Clone, Debug)]
+pub struct Package {
+    pub name: String,
+    /// The package type declared by the platform, e.g. `toolchain` or `framework`.
+    pub package_type: Option<String>,
+    pub installed: InstalledPackage,
+}
+
+impl Platform {
+    /// Resolve the platform of the PlatformIO `environment` of `platformio_ini`.
+    pub fn resolve(pio: &Pio, platformio_ini: &str, environment: &str) -> Result<Self> {
+        let platform = env_option(platformio_ini, environment, "platform")
+            .ok_or_else(|| anyhow!("Environment {} has no platform", environment))?;
+
+        // `espressif32@5.0.0`, `platformio/espressif32` or a URL
+        let name = platform
+            .split('@')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches(".git")
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+
+        let dir = pio.core_dir.join("platforms").join(&name);
+        let manifest = read_json(&dir.join("platform.json"));
+
+        let mut packages = Vec::new();
+
+        if let Some(declared) = manifest
+            .as_ref()
+            .and_then(|manifest| manifest["packages"].as_object())
+        {
+            for (name, package) in declared {
+                // Optional packages which are not installed are not used by the environment
+                if let Some(installed) = pio.package(name, package["version"].as_str()) {
+                    packages.push(Package {
+                        name: name.clone(),
+                        package_type: package["type"].as_str().map(str::to_owned),
+                        installed,
+                    });
+                }
+            }
+        }
+
+        Ok(Self {
+            version: manifest
+                .as_ref()
+                .and_then(|manifest| manifest["version"].as_str().map(str::to_owned)),
+            name,
+            dir,
+            packages,
+        })
+    }
+}
+
+/// The names of the dependencies of a library, declared as an array of objects or as an
+/// object mapping names to versions.
+fn library_dependencies(manifest: &JsonValue) -> Vec<String> {
+    match &manifest["dependencies"] {
+        JsonValue::Array(dependencies) => dependencies
+            .iter()
+            .filter_map(|dependency| dependency["name"].as_str())
+            .map(|name| name.rsplit('/').next().unwrap_or(name).to_owned())
+            .collect(),
+        JsonValue::Object(dependencies) => dependencies
+            .keys()
+            .map(|name| name.rsplit('/').next().unwrap_or(name).to_owned())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn read_json(path: &Path) -> Option<JsonValue> {
+    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+}
+
+pub(crate) fn dir_size(dir: &Path) -> Option<u64> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.is_dir() {
+            size += dir_size(&entry.path()).unwrap_or_default();
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Some(size)
+}
+
+/// Format `size` in bytes for humans.
+pub fn format_size(size: u64) -> String {
+    match size {
+        size if size >= 1 << 30 => format!("{:.1} GiB", size as f64 / (1u64 << 30) as f64),
+        size if size >= 1 << 20 => format!("{:.1} MiB", size as f64 / (1u64 << 20) as f64),
+        size if size >= 1 << 10 => format!("{:.1} KiB", size as f64 / (1u64 << 10) as f64),
+        size => format!("{} B", size),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: PathBuf, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_collect() {
+        let core_dir = tempfile::tempdir().unwrap();
+        let project_dir = tempfile::tempdir().unwrap();
+
+        let pio = Pio {
+            platformio_exe: PathBuf::from("pio"),
+            core_dir: core_dir.path().to_owned(),
+            log_level: Default::default(),
+            timeout: None,
+        };
+
+        write(
+            core_dir.path().join("platforms/espressif32/platform.json"),
+            r#"{"version": "6.4.0", "packages": {
+                "framework-espidf": {"type": "framework", "version": "~3.50102.0"},
+                "toolchain-xtensa-esp32": {"type": "toolchain", "version": "12.2.0"},
+                "tool-openocd-esp32": {"type": "debugger", "optional": true}
+            }}"#,
+        );
+        write(
+            core_dir
+                .path()
+                .join("packages/framework-espidf/package.json"),
+            r#"{"version": "3.50102.0"}"#,
+        );
+        write(
+            core_dir
+                .path()
+                .join("packages/toolchain-xtensa-esp32/package.json"),
+            r#"{"version": "12.2.0"}"#,
+        );
+
+        write(
+            project_dir.path().join("platformio.ini"),
+            "[env:debug]\n\
+             platform = platformio/espressif32@6.4.0\n\
+             framework = espidf\n\
+             lib_deps = bblanchon/ArduinoJson@^6\n",
+        );
+        let libdeps = project_dir.path().join(".pio/libdeps/debug");
+        write(
+            libdeps.join("ArduinoJson/library.json"),
+            r#"{"name": "ArduinoJson", "version": "6.21.3",
+                "dependencies": [{"name": "owner/Base64"}]}"#,
+        );
+        write(
+            libdeps.join("Base64/library.json"),
+            r#"{"name": "Base64", "version": "1.0.0"}"#,
+        );
+        write(
+            libdeps.join("Found/library.json"),
+            r#"{"version": "0.1.0"}"#,
+        );
+
+        let graph = Graph::collect(&pio, project_dir.path(), "debug").unwrap();
+
+        assert_eq!(
+            graph.nodes.keys().collect::<Vec<_>>(),
+            [
+                "environment:debug",
+                "library:arduinojson",
+                "library:base64",
+                "library:found",
+                "package:framework-espidf",
+                "package:toolchain-xtensa-esp32",
+                "platform:espressif32",
+            ]
+        );
+        assert_eq!(
+            graph.nodes["platform:espressif32"].version.as_deref(),
+            Some("6.4.0")
+        );
+
+        let edge = |from: &str, to: &str| graph.edges.contains(&(from.to_owned(), to.to_owned()));
+        assert!(edge("environment:debug", "platform:espressif32"));
+        assert!(edge(
+            "platform:espressif32",
+            "package:toolchain-xtensa-esp32"
+        ));
+        // The selected framework, the listed library and the one found in the sources
+        assert!(edge("environment:debug", "package:framework-espidf"));
+        assert!(edge("environment:debug", "library:arduinojson"));
+        assert!(edge("environment:debug", "library:found"));
+        assert!(edge("library:arduinojson", "library:base64"));
+        assert!(!edge("environment:debug", "library:base64"));
+        assert!(!edge("environment:debug", "package:toolchain-xtensa-esp32"));
+
+        let dot = graph.dot();
+        assert!(dot.starts_with("digraph packages {\n"));
+        assert!(dot.contains("    \"library:arduinojson\" -> \"library:base64\";\n"));
+        assert!(graph.size() > 0);
+    }
+
+    #[test]
+    fn test_library_dependencies() {
+        let mut dependencies = library_dependencies(&serde_json::json!({
+            "dependencies": {"owner/Base64": "^1.0.0", "Wire": "*"}
+        }));
+        dependencies.sort();
+        assert_eq!(dependencies, ["Base64", "Wire"]);
+        assert!(library_dependencies(&serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 << 20), "3.0 MiB");
+        assert_eq!(format_size(5 << 30), "5.0 GiB");
+    }
+}
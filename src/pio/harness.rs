@@ -0,0 +1,513 @@
+//! Hardware-in-the-loop tests of the firmware of PIO->Cargo projects.
+//!
+//! A [`Harness`] builds and flashes an environment of a project and opens a [`Session`]
+//! with the device, which a test then drives as a sequence of steps: expect a pattern
+//! in the output within a timeout, send input, power-cycle the device. E.g.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use std::time::Duration;
+//!
+//! use embuild::pio::harness::Harness;
+//! use embuild::pio::Pio;
+//!
+//! let harness = Harness::new(Pio::get_default()?, ".").port("/dev/ttyUSB0");
+//! let mut session = harness.run()?;
+//!
+//! session.expect("Ready", Duration::from_secs(10))?;
+//! session.send_line("ping")?;
+//! let matched = session.expect(r"pong (\d+)", Duration::from_secs(1))?;
+//! assert_eq!(matched.group(1), Some("1"));
+//!
+//! session.power_cycle()?;
+//! session.expect("Ready", Duration::from_secs(10))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Serial ports (and RFC 2217 bridges) are opened with pyserial of PlatformIO's Python
+//! environment, raw TCP bridges (`tcp://<host>:<port>`) directly. The device output can
+//! be passed through [`Decoder`]s before it is matched, e.g. to resolve backtraces.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use regex::Regex;
+
+use super::config::{self, Config, Reset};
+use super::{inspect, is_raw_tcp_port, reset, serial_port_url, Pio};
+use crate::error::HintExt;
+use crate::interrupt;
+use crate::monitor::Decoder;
+
+/// The number of lines of the output shown in the error of an expectation.
+const ERROR_LINES: usize = 20;
+
+/// The Python script bridging the serial port of its arguments (URL and baud rate) to
+/// its standard input and output.
+const SERIAL_BRIDGE: &str = "\
+import os, sys, threading, serial
+port = serial.serial_for_url(sys.argv[1], int(sys.argv[2]), timeout=0.1)
+def forward():
+    while True:
+        data = os.read(0, 1024)
+        if not data:
+            break
+        port.write(data)
+threading.Thread(target=forward, daemon=True).start()
+out = sys.stdout.buffer
+while True:
+    data = port.read(1024)
+    if data:
+        out.write(data)
+        out.flush()
+";
+
+/// The build, flashing and monitoring of an environment of a project, for tests.
+pub struct Harness {
+    pio: Pio,
+    project_dir: PathBuf,
+    environment: String,
+    port: Option<String>,
+    baud_rate: u32,
+    power_cycle: Option<Reset>,
+}
+
+impl Harness {
+    /// A harness of the `debug` environment of the project in `project_dir`, flashing
+    /// the device at the port detected by PlatformIO until [`Harness::port`] is set.
+    pub fn new(pio: Pio, project_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            pio,
+            project_dir: project_dir.into(),
+            environment: "debug".into(),
+            port: None,
+            baud_rate: 115200,
+            power_cycle: None,
+        }
+    }
+
+    pub fn environment(mut self, environment: impl Into<String>) -> Self {
+        self.environment = environment.into();
+        self
+    }
+
+    /// The port of the device, a serial port or a `rfc2217://` or `tcp://` URL.
+    ///
+    /// Needed by [`Harness::monitor`].
+    pub fn port(mut self, port: impl Into<String>) -> Self {
+        self.port = Some(port.into());
+        self
+    }
+
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    /// The strategy of [`Session::power_cycle`], e.g. a [`Reset::Command`] switching
+    /// the power of the board with a relay.
+    ///
+    /// Defaults to the monitor reset of the board in `cargo-pio.toml`, or to a hard
+    /// reset if it has none.
+    pub fn power_cycle(mut self, reset: Reset) -> Self {
+        self.power_cycle = Some(reset);
+        self
+    }
+
+    /// Build the environment.
+    pub fn build(&self) -> Result<()> {
+        let mut cmd = self.pio.run_cmd();
+        cmd.arg("-d")
+            .arg(&self.project_dir)
+            .arg("-e")
+            .arg(&self.environment);
+
+        self.pio.exec(&mut cmd)
+    }
+
+    /// Flash the firmware of the environment, which must have been built, with the reset
+    /// strategy of its board.
+    pub fn flash(&self) -> Result<()> {
+        let reset = self.reset()?.flash;
+        let port = self.port.as_deref();
+
+        if let Some(mut cmd) = reset::before_flash(&self.pio, &reset, port)? {
+            debug!("Running reset command: {:?}", cmd);
+
+            let status = interrupt::status(&mut cmd)?;
+            if !status.success() {
+                bail!("Resetting the device failed with {}", status);
+            }
+        }
+
+        let mut cmd = self.pio.run_cmd();
+        cmd.arg("-d")
+            .arg(&self.project_dir)
+            .arg("-e")
+            .arg(&self.environment)
+            .args(["-t", "nobuild", "-t", "upload"]);
+
+        if let Some(port) = port {
+            cmd.arg("--upload-port").arg(serial_port_url(port));
+        }
+
+        reset::apply_to_upload(&reset, &self.project_dir, &self.environment, &mut cmd);
+
+        self.pio.exec(&mut cmd)
+    }
+
+    /// Open a session with the device.
+    pub fn monitor(&self) -> Result<Session> {
+        let port = match &self.port {
+            Some(port) => port,
+            None => {
+                return Err(anyhow!("The session needs the port of the device"))
+                    .hint("Set the port with `Harness::port`")
+            }
+        };
+
+        let power_cycle = match &self.power_cycle {
+            Some(reset) => reset.clone(),
+            None => match self.reset()?.monitor {
+                Reset::NoReset => Reset::Auto,
+                reset => reset,
+            },
+        };
+
+        let mut session = if is_raw_tcp_port(port) {
+            let address = serial_port_url(port).replacen("socket://", "", 1);
+
+            let stream = TcpStream::connect(&address)
+                .with_context(|| anyhow!("Failed to connect to {}", address))?;
+
+            Session::new(stream.try_clone()?, Box::new(stream.try_clone()?)).stream(stream)
+        } else if crate::monitor::transport::is_websocket(port) {
+            bail!(
+                "Sessions with the device at WebSocket bridge {} are not supported",
+                port
+            );
+        } else {
+            let python = inspect::which(&self.pio, "python")
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("No Python found to open the serial port with"))?;
+
+            let mut cmd = Command::new(python.path);
+            cmd.arg("-c")
+                .arg(SERIAL_BRIDGE)
+                .arg(serial_port_url(port))
+                .arg(self.baud_rate.to_string())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped());
+
+            debug!("Opening {} with: {:?}", port, cmd);
+
+            let mut child = cmd.spawn()?;
+            let input = child.stdin.take().unwrap();
+            let output = child.stdout.take().unwrap();
+
+            Session::new(output, Box::new(input)).child(child)
+        };
+
+        session.power_cycle = Some((self.pio.clone(), power_cycle, port.clone()));
+
+        info!("Opened a session with the device at {}", port);
+
+        Ok(session)
+    }
+
+    /// Build and flash the environment and open a session with the device.
+    pub fn run(&self) -> Result<Session> {
+        self.build()?;
+        self.flash()?;
+        self.monitor()
+    }
+
+    fn reset(&self) -> Result<config::ResetConfig> {
+        let config = Config::load(&self.project_dir)?;
+
+        Ok(reset::of_environment(
+            &config,
+            &self.project_dir,
+            &self.environment,
+        ))
+    }
+}
+
+/// A match of [`Session::expect`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    /// The matched text.
+    pub text: String,
+    /// The capture groups of the pattern, `None` for the ones which did not participate.
+    pub groups: Vec<Option<String>>,
+}
+
+impl Match {
+    /// The capture group `index`, 0 being the whole match.
+    pub fn group(&self, index: usize) -> Option<&str> {
+        if index == 0 {
+            Some(&self.text)
+        } else {
+            self.groups.get(index - 1)?.as_deref()
+        }
+    }
+}
+
+/// A session with a device, closed when dropped.
+pub struct Session {
+    output: Receiver<Vec<u8>>,
+    input: Box<dyn Write + Send>,
+    decoders: Vec<Box<dyn Decoder>>,
+    /// The decoded output after the last match.
+    pending: String,
+    transcript: String,
+    start: Instant,
+    ended: bool,
+    child: Option<Child>,
+    stream: Option<TcpStream>,
+    power_cycle: Option<(Pio, Reset, String)>,
+}
+
+impl Session {
+    fn new(mut output: impl Read + Send + 'static, input: Box<dyn Write + Send>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut buf = [0_u8; 1024];
+
+            loop {
+                match output.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        if sender.send(buf[..len].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        debug!("Reading the device output failed: {}", err);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self {
+            output: receiver,
+            input,
+            decoders: Vec::new(),
+            pending: String::new(),
+            transcript: String::new(),
+            start: Instant::now(),
+            ended: false,
+            child: None,
+            stream: None,
+            power_cycle: None,
+        }
+    }
+
+    fn child(mut self, child: Child) -> Self {
+        self.child = Some(child);
+        self
+    }
+
+    fn stream(mut self, stream: TcpStream) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Pass the device output through `decoder` (after the previous ones) before it is
+    /// matched.
+    pub fn decoder(mut self, decoder: Box<dyn Decoder>) -> Self {
+        self.decoders.push(decoder);
+        self
+    }
+
+    /// Wait for the output to match `pattern` (a regular expression) within `timeout`.
+    ///
+    /// The output up to the end of the match is consumed, the next expectation matches
+    /// the output after it.
+    pub fn expect(&mut self, pattern: &str, timeout: Duration) -> Result<Match> {
+        let regex =
+            Regex::new(pattern).with_context(|| format!("Invalid pattern '{}'", pattern))?;
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if let Some(captures) = regex.captures(&self.pending) {
+                let whole = captures.get(0).unwrap();
+                let matched = Match {
+                    text: whole.as_str().to_owned(),
+                    groups: captures
+                        .iter()
+                        .skip(1)
+                        .map(|group| group.map(|group| group.as_str().to_owned()))
+                        .collect(),
+                };
+
+                let end = whole.end();
+                self.pending.drain(..end);
+
+                debug!("Matched '{}': {}", pattern, matched.text);
+
+                return Ok(matched);
+            }
+
+            if self.ended {
+                bail!(
+                    "The device output ended before matching '{}', the output since the last match:\n{}",
+                    pattern,
+                    self.tail()
+                );
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                bail!(
+                    "Timed out after {:?} waiting for '{}', the output since the last match:\n{}",
+                    timeout,
+                    pattern,
+                    self.tail()
+                );
+            }
+
+            match self.output.recv_timeout(deadline - now) {
+                Ok(data) => self.receive(&data),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    let data = self.decoders.iter_mut().fold(Vec::new(), |data, decoder| {
+                        let mut data = decoder.decode(&data);
+                        data.extend(decoder.finish());
+                        data
+                    });
+                    self.append(&data);
+                    self.ended = true;
+                }
+            }
+        }
+    }
+
+    /// Send `data` to the device.
+    pub fn send(&mut self, data: impl AsRef<[u8]>) -> Result<()> {
+        self.input.write_all(data.as_ref())?;
+        self.input.flush()?;
+
+        Ok(())
+    }
+
+    /// Send `line` and a line feed to the device.
+    pub fn send_line(&mut self, line: &str) -> Result<()> {
+        self.send(format!("{}\n", line))
+    }
+
+    /// Power-cycle (or reset) the device with the strategy of the harness, see
+    /// [`Harness::power_cycle`].
+    ///
+    /// The output before it is discarded.
+    pub fn power_cycle(&mut self) -> Result<()> {
+        let (pio, reset, port) = self
+            .power_cycle
+            .as_ref()
+            .ok_or_else(|| anyhow!("The session has no power-cycle strategy"))?;
+
+        if let Some(mut cmd) = reset::after_attach(pio, reset, Some(port))? {
+            debug!("Running power-cycle command: {:?}", cmd);
+
+            let status = interrupt::status(&mut cmd)?;
+            if !status.success() {
+                bail!("Power-cycling the device failed with {}", status);
+            }
+        }
+
+        while let Ok(data) = self.output.try_recv() {
+            self.receive(&data);
+        }
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    /// The whole decoded output of the session so far.
+    pub fn transcript(&self) -> &str {
+        &self.transcript
+    }
+
+    fn receive(&mut self, data: &[u8]) {
+        let elapsed = self.start.elapsed();
+
+        let data = self
+            .decoders
+            .iter_mut()
+            .fold(data.to_vec(), |data, decoder| {
+                decoder.set_time(elapsed);
+                decoder.decode(&data)
+            });
+
+        self.append(&data);
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        let data = String::from_utf8_lossy(data);
+
+        trace!("Device output: {:?}", data);
+
+        self.pending.push_str(&data);
+        self.transcript.push_str(&data);
+    }
+
+    /// The last lines of the output since the last match.
+    fn tail(&self) -> String {
+        let lines = self.pending.lines().collect::<Vec<_>>();
+
+        lines[lines.len().saturating_sub(ERROR_LINES)..].join("\n")
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+
+        if let Some(stream) = &self.stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expect() {
+        let output = io::Cursor::new(b"boot\nReady\npong 42\ntrailing".to_vec());
+        let mut session = Session::new(output, Box::new(io::sink()));
+
+        let ready = session.expect("Ready", Duration::from_secs(5)).unwrap();
+        assert_eq!(ready.group(0), Some("Ready"));
+
+        let pong = session
+            .expect(r"pong (\d+)(x)?", Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(pong.group(1), Some("42"));
+        assert_eq!(pong.group(2), None);
+
+        // Consumed by the previous match
+        let err = session.expect("Ready", Duration::from_secs(5)).unwrap_err();
+        assert!(err.to_string().contains("output ended"));
+        assert!(err.to_string().ends_with("\ntrailing"));
+
+        assert_eq!(session.transcript(), "boot\nReady\npong 42\ntrailing");
+        session.send_line("ping").unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+//! Streaming hashes of data, files and directory trees: SHA-256, SHA-512 and, with the
+//! `pio-hash` feature, BLAKE3.
+//!
+//! Files are hashed in chunks, reporting the progress to a callback, so that hashing
+//! large archives and package trees neither loads them into memory nor looks stuck.
+//! [`tree`] hashes a directory deterministically: the same files give the same digest
+//! regardless of the order the file system lists them in, their timestamps and the
+//! platform, which makes it usable in lockfiles and for comparing installations.
+
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+#[cfg(feature = "pio-hash")]
+use hmac::{Hmac, Mac};
+use sha2::Digest;
+
+/// The size of the chunks files are read and hashed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A hash algorithm.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+    #[cfg(feature = "pio-hash")]
+    Blake3,
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            #[cfg(feature = "pio-hash")]
+            "blake3" => Ok(Self::Blake3),
+            #[cfg(not(feature = "pio-hash"))]
+            "blake3" => bail!("BLAKE3 hashes need the `pio-hash` feature of embuild"),
+            _ => bail!("Unknown hash algorithm '{}'", s),
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            #[cfg(feature = "pio-hash")]
+            Self::Blake3 => "blake3",
+        })
+    }
+}
+
+/// A streaming hash of an [`Algorithm`].
+#[derive(Clone, Debug)]
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    #[cfg(feature = "pio-hash")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Self::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha512 => Self::Sha512(sha2::Sha512::new()),
+            #[cfg(feature = "pio-hash")]
+            Algorithm::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+            #[cfg(feature = "pio-hash")]
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Sha512(hasher) => hasher.finalize().to_vec(),
+            #[cfg(feature = "pio-hash")]
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// The lowercase hex encoding of `data`.
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    sha2::Sha256::digest(data).into()
+}
+
+/// The HMAC-SHA256 (RFC 2104) of `data` with `key`.
+#[cfg(feature = "pio-hash")]
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac =
+        Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// The digest of the file `path`, calling `progress` with the hashed and the total
+/// bytes after every chunk.
+pub fn file(
+    algorithm: Algorithm,
+    path: impl AsRef<Path>,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<Vec<u8>> {
+    let path = path.as_ref();
+    let total = fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .len();
+
+    let mut hasher = Hasher::new(algorithm);
+    let mut hashed = 0;
+
+    hash_file(&mut hasher, path, &mut |len| {
+        hashed += len;
+        progress(hashed, total);
+    })?;
+
+    Ok(hasher.finish())
+}
+
+/// The digest of the directory tree `dir`, calling `progress` with the hashed and the
+/// total bytes of its files after every chunk.
+///
+/// The digest covers the relative paths (with `/` separators, in byte order), the types
+/// and the contents of all files, directories and symlinks (their targets) in `dir`,
+/// but nothing else: neither permissions nor timestamps.
+pub fn tree(
+    algorithm: Algorithm,
+    dir: impl AsRef<Path>,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<Vec<u8>> {
+    let dir = dir.as_ref();
+
+    let mut entries = Vec::new();
+    walk(dir, String::new(), &mut entries)?;
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let total = entries
+        .iter()
+        .map(|(_, entry)| match entry {
+            TreeEntry::File(_, size) => *size,
+            _ => 0,
+        })
+        .sum();
+
+    let mut hasher = Hasher::new(algorithm);
+    let mut hashed = 0;
+
+    for (relative, entry) in &entries {
+        match entry {
+            TreeEntry::Dir => hasher.update(format!("d {}\0", relative).as_bytes()),
+            TreeEntry::Symlink(target) => {
+                hasher.update(format!("l {}\0{}\0", relative, target).as_bytes())
+            }
+            TreeEntry::File(path, size) => {
+                hasher.update(format!("f {}\0{}\0", relative, size).as_bytes());
+                hash_file(&mut hasher, path, &mut |len| {
+                    hashed += len;
+                    progress(hashed, total);
+                })?;
+            }
+        }
+    }
+
+    Ok(hasher.finish())
+}
+
+/// An entry of a directory tree, as walked by [`walk`].
+pub(crate) enum TreeEntry {
+    Dir,
+    Symlink(String),
+    File(PathBuf, u64),
+}
+
+/// Collect the entries of `dir` with their paths relative to it, prefixed with `prefix`.
+pub(crate) fn walk(
+    dir: &Path,
+    prefix: String,
+    entries: &mut Vec<(String, TreeEntry)>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+
+        if file_type.is_symlink() {
+            let target = fs::read_link(&path)?;
+            entries.push((
+                relative,
+                TreeEntry::Symlink(target.to_string_lossy().replace('\\', "/")),
+            ));
+        } else if file_type.is_dir() {
+            walk(&path, format!("{}/", relative), entries)?;
+            entries.push((relative, TreeEntry::Dir));
+        } else {
+            let size = entry.metadata()?.len();
+            entries.push((relative, TreeEntry::File(path, size)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Feed the file `path` to `hasher`, calling `hashed` with the length of every chunk.
+fn hash_file(hasher: &mut Hasher, path: &Path, hashed: &mut dyn FnMut(u64)) -> Result<()> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+
+    loop {
+        let len = file
+            .read(&mut chunk)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if len == 0 {
+            return Ok(());
+        }
+
+        hasher.update(&chunk[..len]);
+        hashed(len as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash() {
+        let digest = |algorithm: &str, data: &[u8]| {
+            // Fed in uneven pieces, across the block boundaries
+            let mut hasher = Hasher::new(algorithm.parse().unwrap());
+            for piece in data.chunks(7) {
+                hasher.update(piece);
+            }
+            hex(&hasher.finish())
+        };
+
+        assert_eq!(
+            digest("sha256", b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            digest("sha256", &[b'a'; 1000]),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+        assert_eq!(
+            hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            digest("SHA-512", b"abc"),
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        );
+        #[cfg(feature = "pio-hash")]
+        {
+            assert_eq!(
+                digest("blake3", b""),
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+            );
+            assert_eq!(
+                digest("blake3", b"abc"),
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+            );
+            let input = (0..2048).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            assert_eq!(
+                digest("blake3", &input),
+                "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a"
+            );
+        }
+        assert!("md5".parse::<Algorithm>().is_err());
+
+        // RFC 4231, test case 2
+        #[cfg(feature = "pio-hash")]
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let dir = std::env::temp_dir().join(format!("embuild-hash-{}", std::process::id()));
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("b").join("c.txt"), "bc").unwrap();
+
+        let mut progress = Vec::new();
+        let digest = tree(Algorithm::Sha256, &dir, &mut |done, total| {
+            progress.push((done, total))
+        })
+        .unwrap();
+        assert_eq!(progress, [(1, 3), (3, 3)]);
+
+        fs::write(dir.join("b").join("c.txt"), "cb").unwrap();
+        assert_ne!(
+            tree(Algorithm::Sha256, &dir, &mut |_, _| ()).unwrap(),
+            digest
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//! A client of the JSON-RPC API of PIO Home, the web UI of PlatformIO.
+//!
+//! PIO Home (`pio home`) serves a JSON-RPC 2.0 API over a WebSocket (`/wsrpc`), whose
+//! `core.call` method runs PlatformIO commands in its already running Python process.
+//! `cargo pio home` starts a server in the background for the core directory, which
+//! shuts down after being idle. While it is running, [`Pio::json`] queries it instead
+//! of starting PlatformIO for every command, e.g. for the boards and platforms, like
+//! the [daemon](super::daemon) does on Unix.
+
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use serde_json::{json, Value};
+
+use super::Pio;
+use crate::error::HintExt;
+use crate::websocket::{self, WebSocket};
+
+/// The file the port of the running server is stored in, in the core directory.
+const PORT_FILE: &str = "cargo-pio-home.port";
+const LOG_FILE: &str = "cargo-pio-home.log";
+
+/// A connection to the JSON-RPC API of a PIO Home server.
+pub struct Client {
+    socket: WebSocket<TcpStream>,
+    next_id: u64,
+}
+
+impl Client {
+    /// Connect to the PIO Home server listening on `port` of the local host.
+    pub fn connect(port: u16) -> Result<Self> {
+        let url = websocket::Url::parse(format!("ws://127.0.0.1:{}/wsrpc", port))?;
+
+        Ok(Self {
+            socket: websocket::connect(&url)?,
+            next_id: 1,
+        })
+    }
+
+    /// Call the method `method` with `params`, returning its result.
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.socket.send_text(
+            &json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}).to_string(),
+        )?;
+
+        loop {
+            let message = self
+                .socket
+                .read_message()?
+                .ok_or_else(|| anyhow!("PIO Home closed the connection"))?;
+
+            let mut response = serde_json::from_slice::<Value>(&message)
+                .context("Invalid response of PIO Home")?;
+
+            // Notifications and responses to other requests
+            if response["id"] != json!(id) {
+                continue;
+            }
+
+            return match response["error"].take() {
+                Value::Null => Ok(response["result"].take()),
+                error => bail!(
+                    "PIO Home failed to run {}: {}",
+                    method,
+                    error["data"]
+                        .as_str()
+                        .or_else(|| error["message"].as_str())
+                        .unwrap_or("unknown error")
+                        .trim()
+                ),
+            };
+        }
+    }
+
+    /// Run the PlatformIO command with the arguments `args` in the directory `cwd`,
+    /// returning its output.
+    ///
+    /// Commands with `--json-output` return their output as JSON.
+    pub fn core_call(&mut self, args: &[String], cwd: &Path) -> Result<Value> {
+        let result = self.call("core.call", json!([args, {"cwd": cwd.to_string_lossy()}]))?;
+
+        // Older versions of PIO Home return the JSON output unparsed
+        Ok(match result {
+            Value::String(output) if args.iter().any(|arg| arg == "--json-output") => {
+                serde_json::from_str(&output).context("Invalid JSON output of PIO Home")?
+            }
+            result => result,
+        })
+    }
+}
+
+/// The port of the PIO Home server started for the core directory `core_dir`, if it
+/// is still running.
+pub fn running(core_dir: &Path) -> Option<u16> {
+    let port = fs::read_to_string(core_dir.join(PORT_FILE))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+
+    TcpStream::connect_timeout(
+        &SocketAddr::from(([127, 0, 0, 1], port)),
+        Duration::from_millis(200),
+    )
+    .ok()
+    .map(|_| port)
+}
+
+/// Start a PIO Home server for `pio` on `port`, which shuts down after being idle for
+/// `idle_timeout`.
+///
+/// Returns once the server is ready, or right away if one is running already.
+pub fn start(pio: &Pio, port: u16, idle_timeout: Duration) -> Result<u16> {
+    if let Some(port) = running(&pio.core_dir) {
+        info!("PIO Home is running already on port {}", port);
+        return Ok(port);
+    }
+
+    let log_file = pio.core_dir.join(LOG_FILE);
+
+    let mut cmd = pio.cmd();
+    cmd.arg("home")
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--no-open")
+        .arg("--shutdown-timeout")
+        .arg(idle_timeout.as_secs().to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(fs::File::create(&log_file)?);
+
+    debug!("Running PlatformIO command {:?}", cmd);
+
+    let mut child = cmd.spawn()?;
+    let start = Instant::now();
+
+    while start.elapsed() < Duration::from_secs(60) {
+        if let Some(status) = child.try_wait()? {
+            return Err(anyhow!("PIO Home exited with {}", status))
+                .with_hint(|| format!("See its log {}", log_file.display()));
+        }
+
+        if Client::connect(port).is_ok() {
+            fs::write(pio.core_dir.join(PORT_FILE), port.to_string())?;
+            info!("Started PIO Home on port {}", port);
+
+            return Ok(port);
+        }
+
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    bail!("PIO Home did not get ready in time")
+}
+
+/// Run the PlatformIO command `cmd` with `--json-output` in the PIO Home server of its
+/// PlatformIO core directory, if one is running, returning its JSON output.
+///
+/// Returns `None` if there is no server or the command has to run in a process of its
+/// own, as it sets environment variables the server would not see.
+pub(crate) fn json(cmd: &Command) -> Option<Result<Value>> {
+    let mut envs = cmd.get_envs();
+    let core_dir = match (envs.next(), envs.next()) {
+        (Some((name, Some(core_dir))), None) if name == "PLATFORMIO_CORE_DIR" => {
+            PathBuf::from(core_dir)
+        }
+        _ => return None,
+    };
+
+    let args = cmd
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    if !args.iter().any(|arg| arg == "--json-output") {
+        return None;
+    }
+
+    let port = running(&core_dir)?;
+
+    debug!("Running PlatformIO command in PIO Home: {:?}", cmd);
+
+    Some((|| {
+        let cwd = match cmd.get_current_dir() {
+            Some(dir) => dir.to_owned(),
+            None => std::env::current_dir()?,
+        };
+
+        Client::connect(port)?.core_call(&args, &cwd)
+    })())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// A PIO Home server answering one `core.call` request with `output`.
+    fn serve(output: Value) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\n")
+                .unwrap();
+
+            let mut socket = WebSocket::new(stream);
+            let request = socket.read_message().unwrap().unwrap();
+            let request = serde_json::from_slice::<Value>(&request).unwrap();
+            assert_eq!(request["method"], "core.call");
+            assert_eq!(request["params"][0], json!(["boards", "--json-output"]));
+
+            socket
+                .send_text(r#"{"jsonrpc": "2.0", "method": "notification"}"#)
+                .unwrap();
+            socket
+                .send_text(
+                    &json!({"jsonrpc": "2.0", "id": request["id"], "result": output}).to_string(),
+                )
+                .unwrap();
+        });
+
+        port
+    }
+
+    #[test]
+    fn test_core_call() {
+        let args = ["boards".to_owned(), "--json-output".to_owned()];
+        let boards = json!([{"id": "esp32dev"}]);
+
+        for output in [boards.clone(), Value::String(boards.to_string())] {
+            let port = serve(output);
+            let result = Client::connect(port)
+                .unwrap()
+                .core_call(&args, Path::new("."))
+                .unwrap();
+
+            assert_eq!(result, boards);
+        }
+    }
+}
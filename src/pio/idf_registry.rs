@@ -0,0 +1,658 @@
+//! Components of the ESP Component Registry, installed without the Python component
+//! manager of ESP-IDF.
+//!
+//! The components are declared like for the component manager, in the `dependencies`
+//! of the `idf_component.yml` of the project (in `src/` or the project directory):
+//!
+//! ```yaml
+//! dependencies:
+//!   espressif/led_strip: "^2.4"
+//!   espressif/mdns:
+//!     version: ">=1.2,<2"
+//!   idf: ">=5.0"
+//! ```
+//!
+//! [`resolve`] selects the highest version of every component (and of the components
+//! they depend on) which matches all version specs of it; `idf` itself and components
+//! from a `path` or `git` are not resolved. [`install`] unpacks the selected versions
+//! into `managed_components/<namespace>__<name>`, where the component manager would put
+//! them. With `registry = true` in the `[espidf]` section of `cargo-pio.toml`, builds
+//! install the components and disable the component manager.
+//!
+//! To reproduce a build of a project without committed `dependencies.lock`, the versions
+//! can be resolved as of a date (`as-of` in the `[espidf]` section or `--as-of`): only
+//! the versions released by then are considered, as if the registry was queried then.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use serde::Deserialize;
+
+use crate::pio_model::compare_versions;
+use crate::utils::HttpClient;
+
+/// The default registry, overridden by `IDF_COMPONENT_REGISTRY_URL` like for the
+/// component manager.
+pub const REGISTRY_URL: &str = "https://components.espressif.com";
+
+/// The directory (relative to the project directory) the components are installed in.
+pub const COMPONENTS_DIR: &str = "managed_components";
+
+/// The directory (relative to the project directory) the archives are downloaded to.
+const DOWNLOAD_DIR: &str = ".cargo-pio/components";
+
+/// The file in an installed component which marks it as installed by cargo-pio, with
+/// its version.
+const MARKER_FILE: &str = ".cargo-pio-component";
+
+/// The namespace of components declared without one.
+const DEFAULT_NAMESPACE: &str = "espressif";
+
+/// A version of a component in the registry.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub version: String,
+    /// The URL of the archive of the version.
+    pub url: String,
+    #[serde(default)]
+    pub dependencies: Vec<Dependency>,
+    /// The release timestamp, e.g. `2023-06-01T12:00:00.000Z`.
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub yanked_at: Option<String>,
+}
+
+/// A dependency of a version of a component.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Dependency {
+    #[serde(default)]
+    pub namespace: Option<String>,
+    pub name: String,
+    pub spec: String,
+    /// `service` for components of the registry, `idf` for ESP-IDF itself.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+impl Dependency {
+    /// The full name of the component, `<namespace>/<name>`, `None` if it is not a
+    /// component of the registry.
+    fn component(&self) -> Option<String> {
+        if self.name == "idf" || self.source.as_deref().map_or(false, |s| s != "service") {
+            return None;
+        }
+
+        Some(full_name(&match &self.namespace {
+            Some(namespace) => format!("{}/{}", namespace, self.name),
+            None => self.name.clone(),
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+struct Component {
+    versions: Vec<Version>,
+}
+
+/// A selected version of a component.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolved {
+    /// The full name, `<namespace>/<name>`.
+    pub name: String,
+    pub version: Version,
+}
+
+impl Resolved {
+    /// The directory of the component, relative to the project directory.
+    pub fn dir(&self) -> PathBuf {
+        Path::new(COMPONENTS_DIR).join(self.name.replace('/', "__"))
+    }
+}
+
+/// The `idf_component.yml` of the project in `project_dir`, if it has one.
+pub fn manifest(project_dir: impl AsRef<Path>) -> Option<PathBuf> {
+    let project_dir = project_dir.as_ref();
+
+    [project_dir.join("src"), project_dir.to_owned()]
+        .into_iter()
+        .map(|dir| dir.join("idf_component.yml"))
+        .find(|path| path.is_file())
+}
+
+/// Parse the registry components of the `dependencies` of the `idf_component.yml`
+/// `manifest`, as `(<namespace>/<name>, <spec>)`.
+pub fn parse_manifest(manifest: &str) -> Vec<(String, String)> {
+    let mut dependencies = Vec::new();
+    let mut in_dependencies = false;
+    // The dependency being parsed, if it is declared as a table
+    let mut current: Option<(String, usize)> = None;
+
+    for line in manifest.lines() {
+        let content = line.split(" #").next().unwrap_or_default().trim_end();
+        if content.trim().is_empty() || content.trim_start().starts_with('#') {
+            continue;
+        }
+
+        let indent = content.len() - content.trim_start().len();
+        let (key, value) = match content.trim().split_once(':') {
+            Some((key, value)) => (unquote(key), unquote(value)),
+            None => continue,
+        };
+
+        if indent == 0 {
+            in_dependencies = key == "dependencies";
+            current = None;
+            continue;
+        }
+
+        if !in_dependencies {
+            continue;
+        }
+
+        match &current {
+            Some((name, dependency_indent)) if indent > *dependency_indent => match key.as_str() {
+                "version" => dependencies.push((name.clone(), value)),
+                "path" | "git" => {
+                    debug!("Not resolving component {} from the registry", name);
+                    dependencies.retain(|(dependency, _)| dependency != name);
+                    current = None;
+                }
+                _ => (),
+            },
+            _ if key == "idf" => current = None,
+            _ if value.is_empty() => current = Some((full_name(&key), indent)),
+            _ => {
+                dependencies.push((full_name(&key), value));
+                current = None;
+            }
+        }
+    }
+
+    dependencies
+}
+
+fn unquote(value: &str) -> String {
+    value.trim().trim_matches(&['"', '\''][..]).to_owned()
+}
+
+/// The full name of the component `name`, with the default namespace if it has none.
+fn full_name(name: &str) -> String {
+    let name = name.to_lowercase();
+
+    if name.contains('/') {
+        name
+    } else {
+        format!("{}/{}", DEFAULT_NAMESPACE, name)
+    }
+}
+
+/// Whether `version` matches the version spec `spec` of the component manager, e.g.
+/// `^2.4`, `~1.2.0`, `>=1.2,<2`, `2.4.1` (exactly) or `*`.
+pub fn matches(spec: &str, version: &str) -> bool {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|clause| !clause.is_empty())
+        .all(|clause| matches_clause(clause, version))
+}
+
+fn matches_clause(clause: &str, version: &str) -> bool {
+    if clause == "*" {
+        return true;
+    }
+
+    let (op, base) = ["^", "~=", "~", ">=", "<=", "==", "!=", ">", "<", "="]
+        .iter()
+        .find_map(|op| Some((*op, clause.strip_prefix(op)?.trim())))
+        .unwrap_or(("==", clause));
+
+    let cmp = compare_numbers(version, base);
+
+    match op {
+        ">=" => cmp != Ordering::Less,
+        "<=" => cmp != Ordering::Greater,
+        ">" => cmp == Ordering::Greater,
+        "<" => cmp == Ordering::Less,
+        "!=" => cmp != Ordering::Equal,
+        "^" | "~" | "~=" => {
+            if cmp == Ordering::Less {
+                return false;
+            }
+
+            let mut upper = numbers(base);
+            let bump = if op == "^" {
+                // The first non-zero component, e.g. `^0.2.1` allows `<0.3.0`
+                upper
+                    .iter()
+                    .position(|n| *n != 0)
+                    .unwrap_or(upper.len() - 1)
+            } else {
+                // The minor version if given, e.g. `~1.2.0` allows `<1.3.0`
+                usize::min(1, upper.len() - 1)
+            };
+
+            upper.truncate(bump + 1);
+            upper[bump] += 1;
+
+            let upper = upper
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(".");
+
+            compare_numbers(version, &upper) == Ordering::Less
+        }
+        _ => cmp == Ordering::Equal,
+    }
+}
+
+fn numbers(version: &str) -> Vec<u64> {
+    let numbers = version
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .filter_map(|number| number.parse().ok())
+        .collect::<Vec<_>>();
+
+    if numbers.is_empty() {
+        vec![0]
+    } else {
+        numbers
+    }
+}
+
+/// Compare the numeric components of `a` and `b`, with missing ones being zero.
+fn compare_numbers(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (numbers(a), numbers(b));
+    let len = usize::max(a.len(), b.len());
+    a.resize(len, 0);
+    b.resize(len, 0);
+
+    a.cmp(&b)
+}
+
+/// Resolve the `dependencies` (as `(<namespace>/<name>, <spec>)`) and the components
+/// they depend on, with `versions` returning the versions of a component.
+pub fn resolve(
+    dependencies: &[(String, String)],
+    mut versions: impl FnMut(&str) -> Result<Vec<Version>>,
+) -> Result<Vec<Resolved>> {
+    // The specs of every component, with the component (version) requiring it
+    let mut specs: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    let mut available: BTreeMap<String, Vec<Version>> = BTreeMap::new();
+    let mut selected: BTreeMap<String, Version> = BTreeMap::new();
+    let mut queue = Vec::new();
+
+    for (name, spec) in dependencies {
+        specs
+            .entry(name.clone())
+            .or_default()
+            .push((spec.clone(), "the project".into()));
+        queue.push(name.clone());
+    }
+
+    while let Some(name) = queue.pop() {
+        if !available.contains_key(&name) {
+            let mut component_versions = versions(&name)?;
+            component_versions.sort_by(|a, b| compare_versions(&b.version, &a.version));
+            available.insert(name.clone(), component_versions);
+        }
+
+        let component_specs = specs.get(&name).cloned().unwrap_or_default();
+        let version = available[&name]
+            .iter()
+            .find(|version| {
+                version.yanked_at.is_none()
+                    && component_specs
+                        .iter()
+                        .all(|(spec, _)| matches(spec, &version.version))
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "No version of component {} matches {}",
+                    name,
+                    component_specs
+                        .iter()
+                        .map(|(spec, by)| format!("'{}' of {}", spec, by))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?
+            .clone();
+
+        if let Some(previous) = selected.get(&name) {
+            if previous.version == version.version {
+                continue;
+            }
+
+            // The specs of the previously selected version no longer apply
+            let by = format!("{}@{}", name, previous.version);
+            for (dependency, dependency_specs) in &mut specs {
+                let len = dependency_specs.len();
+                dependency_specs.retain(|(_, required_by)| *required_by != by);
+
+                if dependency_specs.len() != len {
+                    queue.push(dependency.clone());
+                }
+            }
+        }
+
+        for dependency in &version.dependencies {
+            if let Some(dependency_name) = dependency.component() {
+                specs.entry(dependency_name.clone()).or_default().push((
+                    dependency.spec.clone(),
+                    format!("{}@{}", name, version.version),
+                ));
+                queue.push(dependency_name);
+            }
+        }
+
+        selected.insert(name, version);
+    }
+
+    // Components only required by a version which was not selected in the end
+    let required = specs
+        .iter()
+        .filter(|(_, specs)| !specs.is_empty())
+        .map(|(name, _)| name)
+        .collect::<BTreeSet<_>>();
+
+    Ok(selected
+        .into_iter()
+        .filter(|(name, _)| required.contains(name))
+        .map(|(name, version)| Resolved { name, version })
+        .collect())
+}
+
+/// The versions of the component `name` (`<namespace>/<name>`) in the registry.
+pub fn versions(client: &dyn HttpClient, name: &str) -> Result<Vec<Version>> {
+    let registry =
+        std::env::var("IDF_COMPONENT_REGISTRY_URL").unwrap_or_else(|_| REGISTRY_URL.to_owned());
+    let url = format!("{}/api/components/{}", registry.trim_end_matches('/'), name);
+
+    debug!("Fetching {}", url);
+
+    let mut response = Vec::new();
+    client
+        .download(&url, &mut response)
+        .with_context(|| format!("Failed to fetch component {} from the registry", name))?;
+
+    let component: Component = serde_json::from_slice(&response)
+        .with_context(|| format!("Failed to parse the registry entry of {}", name))?;
+
+    Ok(component.versions)
+}
+
+/// Check that `date` is a date `YYYY-MM-DD`, as taken by [`released_by`].
+pub fn check_date(date: &str) -> Result<()> {
+    let valid = date.len() == 10
+        && date.char_indices().all(|(index, c)| match index {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        });
+
+    if !valid {
+        bail!("'{}' is no date of the form YYYY-MM-DD", date);
+    }
+
+    Ok(())
+}
+
+/// The `versions` released on or before `date` (`YYYY-MM-DD`), as they were then:
+/// versions yanked after `date` were not yanked yet. Versions without a release timestamp
+/// are left out.
+pub fn released_by(versions: Vec<Version>, date: &str) -> Vec<Version> {
+    let by = |timestamp: &Option<String>| {
+        timestamp
+            .as_deref()
+            .and_then(|timestamp| timestamp.get(..10))
+            .map(|day| day <= date)
+    };
+
+    versions
+        .into_iter()
+        .filter(|version| by(&version.created_at).unwrap_or(false))
+        .map(|mut version| {
+            if by(&version.yanked_at) == Some(false) {
+                version.yanked_at = None;
+            }
+            version
+        })
+        .collect()
+}
+
+/// Install the components of the `idf_component.yml` of the project in `project_dir`,
+/// downloading them with `client`; already installed versions are skipped unless
+/// `force`.
+///
+/// The components are resolved as of `as_of` (`YYYY-MM-DD`), if set.
+///
+/// Returns the installed components, none if the project has no `idf_component.yml`.
+pub fn install_all(
+    project_dir: impl AsRef<Path>,
+    client: &dyn HttpClient,
+    force: bool,
+    as_of: Option<&str>,
+) -> Result<Vec<Resolved>> {
+    let project_dir = project_dir.as_ref();
+
+    let manifest = match manifest(project_dir) {
+        Some(manifest) => manifest,
+        None => return Ok(Vec::new()),
+    };
+
+    if let Some(date) = as_of {
+        check_date(date)?;
+    }
+
+    let dependencies = parse_manifest(&fs::read_to_string(&manifest)?);
+    let resolved = resolve(&dependencies, |name| {
+        let versions = versions(client, name)?;
+
+        Ok(match as_of {
+            Some(date) => released_by(versions, date),
+            None => versions,
+        })
+    })
+    .with_context(|| format!("Failed to resolve the components of {}", manifest.display()))?;
+
+    for component in &resolved {
+        install(project_dir, component, client, force)
+            .with_context(|| format!("Failed to install component {}", component.name))?;
+    }
+
+    Ok(resolved)
+}
+
+/// Install the resolved `component` into the project in `project_dir`.
+pub fn install(
+    project_dir: impl AsRef<Path>,
+    component: &Resolved,
+    client: &dyn HttpClient,
+    force: bool,
+) -> Result<()> {
+    let project_dir = project_dir.as_ref();
+    let dir = project_dir.join(component.dir());
+
+    let pin = format!("{}\n{}\n", component.version.version, component.version.url);
+
+    match fs::read_to_string(dir.join(MARKER_FILE)) {
+        Ok(installed) if installed == pin && !force => return Ok(()),
+        Ok(_) => (),
+        Err(_) if dir.exists() => bail!(
+            "'{}' exists, but was not installed by cargo-pio, remove it to install the component",
+            dir.display()
+        ),
+        Err(_) => (),
+    }
+
+    let download_dir = project_dir.join(DOWNLOAD_DIR);
+    fs::create_dir_all(&download_dir)?;
+
+    let archive = download_dir.join(format!(
+        "{}-{}.tgz",
+        component.name.replace('/', "__"),
+        component.version.version
+    ));
+
+    if !archive.exists() {
+        info!(
+            "Downloading component {} {}",
+            component.name, component.version.version
+        );
+
+        let partial = archive.with_extension("part");
+        let mut file = fs::File::create(&partial)?;
+        client.download(&component.version.url, &mut file)?;
+        file.sync_all()?;
+
+        crate::fs::rename_durable(partial, &archive)?;
+    }
+
+    if dir.exists() {
+        crate::fs::remove_dir_all(&dir)?;
+    }
+
+    super::extract(&archive, &dir)?;
+    crate::fs::write_atomic(dir.join(MARKER_FILE), pin)?;
+
+    info!(
+        "Installed component {} {} into {}",
+        component.name,
+        component.version.version,
+        dir.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            parse_manifest(
+                "version: \"1.0.0\"\n\
+                 dependencies:\n  \
+                   # A comment\n  \
+                   led_strip: \"^2.4\"\n  \
+                   espressif/mdns:\n    \
+                     version: '>=1.2,<2' # pinned below 2\n  \
+                   my_component:\n    \
+                     path: ../my_component\n  \
+                   idf: \">=5.0\"\n\
+                 targets:\n  \
+                   - esp32\n"
+            ),
+            [
+                ("espressif/led_strip".to_owned(), "^2.4".to_owned()),
+                ("espressif/mdns".to_owned(), ">=1.2,<2".to_owned()),
+            ]
+        );
+
+        assert!(matches("^2.4", "2.9.1"));
+        assert!(!matches("^2.4", "3.0.0"));
+        assert!(!matches("^0.2.1", "0.3.0"));
+        assert!(matches("~1.2.0", "1.2.7"));
+        assert!(!matches("~1.2.0", "1.3.0"));
+        assert!(matches(">=1.2,<2", "1.10.0"));
+        assert!(!matches("2.4.1", "2.4.2"));
+        assert!(matches("*", "0.0.1"));
+
+        let version = |version: &str, dependencies: &[(&str, &str)]| Version {
+            version: version.into(),
+            url: format!("https://example.com/{}.tgz", version),
+            dependencies: dependencies
+                .iter()
+                .map(|(name, spec)| Dependency {
+                    namespace: Some("espressif".into()),
+                    name: name.to_string(),
+                    spec: spec.to_string(),
+                    source: Some(if *name == "idf" { "idf" } else { "service" }.into()),
+                })
+                .collect(),
+            created_at: Some("2023-01-01T00:00:00Z".into()),
+            yanked_at: None,
+        };
+
+        let registry = |name: &str| -> Result<Vec<Version>> {
+            Ok(match name {
+                "espressif/app" => vec![
+                    version("1.0.0", &[("idf", ">=4.4"), ("util", "^1.0")]),
+                    version("1.1.0", &[("util", "^2.0")]),
+                ],
+                "espressif/util" => vec![
+                    version("1.5.0", &[]),
+                    version("2.0.0", &[]),
+                    Version {
+                        yanked_at: Some("2023-01-01".into()),
+                        ..version("2.1.0", &[])
+                    },
+                ],
+                _ => bail!("Unknown component {}", name),
+            })
+        };
+
+        let resolved = |dependencies: &[(&str, &str)]| {
+            resolve(
+                &dependencies
+                    .iter()
+                    .map(|(name, spec)| (name.to_string(), spec.to_string()))
+                    .collect::<Vec<_>>(),
+                registry,
+            )
+            .map(|resolved| {
+                resolved
+                    .into_iter()
+                    .map(|r| format!("{}@{}", r.name, r.version.version))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(
+            resolved(&[("espressif/app", "*")]).unwrap(),
+            ["espressif/app@1.1.0", "espressif/util@2.0.0"]
+        );
+        assert_eq!(
+            resolved(&[("espressif/app", "<1.1")]).unwrap(),
+            ["espressif/app@1.0.0", "espressif/util@1.5.0"]
+        );
+        assert!(resolved(&[("espressif/app", "*"), ("espressif/util", "<2")]).is_err());
+
+        let versions = vec![
+            version("1.0.0", &[]),
+            Version {
+                created_at: Some("2023-03-10T08:00:00.000Z".into()),
+                yanked_at: Some("2023-05-02T00:00:00Z".into()),
+                ..version("1.1.0", &[])
+            },
+            Version {
+                created_at: Some("2023-06-01T08:00:00.000Z".into()),
+                ..version("1.2.0", &[])
+            },
+            Version {
+                created_at: None,
+                ..version("1.3.0", &[])
+            },
+        ];
+        let released = released_by(versions, "2023-05-01");
+        assert_eq!(
+            released
+                .iter()
+                .map(|version| (version.version.as_str(), version.yanked_at.is_some()))
+                .collect::<Vec<_>>(),
+            [("1.0.0", false), ("1.1.0", false)]
+        );
+
+        assert!(check_date("2023-05-01").is_ok());
+        assert!(check_date("2023-5-1").is_err());
+        assert!(check_date("01.05.2023").is_err());
+    }
+}
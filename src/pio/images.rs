@@ -0,0 +1,173 @@
+//! Projects with several firmware images, e.g. a bootloader, a factory and a recovery
+//! app.
+//!
+//! Every image configured in the `[[images]]` section of `cargo-pio.toml` is built by its
+//! own PlatformIO environment, with the Cargo settings (features, profile, linker
+//! script) of that environment. Flashing all images writes the images with an offset or
+//! partition to their place in the flash and has PlatformIO upload the others, in the
+//! order of the configuration.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::config::ImageConfig;
+use crate::error::HintExt;
+use crate::partitions::Partition;
+
+/// A built firmware image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub name: String,
+    pub environment: String,
+    pub placement: Placement,
+}
+
+/// Where an image goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Placement {
+    /// Uploaded by PlatformIO.
+    Upload,
+    /// The binary `firmware` written to `offset`.
+    Write { firmware: PathBuf, offset: u32 },
+}
+
+/// The environments building `images`, without duplicates, in order.
+pub fn environments(images: &[ImageConfig]) -> Vec<&str> {
+    let mut environments = Vec::new();
+
+    for image in images {
+        if !environments.contains(&image.environment.as_str()) {
+            environments.push(image.environment.as_str());
+        }
+    }
+
+    environments
+}
+
+/// Resolve the built `images` of the project in `project_dir`.
+///
+/// Checks that every image with an offset or partition fits into its partition and does
+/// not overlap any other image.
+pub fn resolve(images: &[ImageConfig], project_dir: impl AsRef<Path>) -> Result<Vec<Image>> {
+    let project_dir = project_dir.as_ref();
+
+    for (index, image) in images.iter().enumerate() {
+        if images[..index].iter().any(|other| other.name == image.name) {
+            bail!("Duplicate image name {}", image.name);
+        }
+    }
+
+    let mut resolved = Vec::new();
+    let mut regions = Vec::new();
+
+    for image in images {
+        let build_dir = project_dir
+            .join(".pio")
+            .join("build")
+            .join(&image.environment);
+
+        let (offset, max_size) = match (image.offset, &image.partition) {
+            (Some(_), Some(_)) => bail!(
+                "Image {} has both an offset and a partition, only one is allowed",
+                image.name
+            ),
+            (Some(offset), None) => (offset, None),
+            (None, Some(label)) => {
+                let partition_table = build_dir.join("partitions.bin");
+                let partition = Partition::find_by_label(
+                    &fs::read(&partition_table).with_context(|| {
+                        anyhow!(
+                            "Failed to read the partition table {}",
+                            partition_table.display()
+                        )
+                    })?,
+                    label,
+                )
+                .ok_or_else(|| {
+                    anyhow!("No partition labeled '{}' for image {}", label, image.name)
+                })?;
+
+                (partition.offset, Some(partition.size))
+            }
+            (None, None) => {
+                resolved.push(Image {
+                    name: image.name.clone(),
+                    environment: image.environment.clone(),
+                    placement: Placement::Upload,
+                });
+
+                continue;
+            }
+        };
+
+        let firmware = build_dir.join("firmware.bin");
+        let size = fs::metadata(&firmware)
+            .with_context(|| anyhow!("Failed to read image {}", firmware.display()))
+            .hint(format!(
+                "Build environment {} of image {} first",
+                image.environment, image.name
+            ))?
+            .len();
+
+        if let Some(max_size) = max_size {
+            if size > max_size as u64 {
+                bail!(
+                    "Image {} ({} bytes) does not fit into partition {} ({} bytes)",
+                    image.name,
+                    size,
+                    image.partition.as_deref().unwrap_or_default(),
+                    max_size
+                );
+            }
+        }
+
+        regions.push((image.name.as_str(), offset as u64, size));
+
+        resolved.push(Image {
+            name: image.name.clone(),
+            environment: image.environment.clone(),
+            placement: Placement::Write { firmware, offset },
+        });
+    }
+
+    check_overlaps(&regions)?;
+
+    Ok(resolved)
+}
+
+/// Check that none of the `(<name>, <offset>, <size>)` regions overlap.
+fn check_overlaps(regions: &[(&str, u64, u64)]) -> Result<()> {
+    for (index, (name, offset, size)) in regions.iter().enumerate() {
+        for (other, other_offset, other_size) in &regions[..index] {
+            if offset < &(other_offset + other_size) && other_offset < &(offset + size) {
+                bail!(
+                    "Image {} (0x{:x}..0x{:x}) overlaps image {} (0x{:x}..0x{:x})",
+                    name,
+                    offset,
+                    offset + size,
+                    other,
+                    other_offset,
+                    other_offset + other_size
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_overlaps() {
+        assert!(check_overlaps(&[("app", 0x10000, 0x1000), ("recovery", 0x11000, 0x1000)]).is_ok());
+        assert!(
+            check_overlaps(&[("app", 0x10000, 0x1001), ("recovery", 0x11000, 0x1000)]).is_err()
+        );
+        assert!(check_overlaps(&[("app", 0x10000, 0x1000), ("recovery", 0xf000, 0x2000)]).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! Inspection of the environment PIO->Cargo builds run in: where tools are resolved
+//! from, and which environment variables the build commands see and where they come
+//! from.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use super::Pio;
+
+/// Where an environment variable of a build command comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// Inherited from the environment of cargo-pio.
+    Inherited,
+    /// Set by a step of cargo-pio (e.g. `PlatformIO` or `cargo-pio.toml [env.debug]`),
+    /// possibly overriding an inherited value.
+    Set { by: String, overrides: bool },
+    /// Removed by a step of cargo-pio.
+    Removed { by: String },
+}
+
+/// An environment variable of a build command.
+#[derive(Clone, Debug)]
+pub struct Variable {
+    pub name: String,
+    /// The value, `None` if removed.
+    pub value: Option<String>,
+    pub origin: Origin,
+}
+
+/// A trace of the environment variables set on a command by the steps building it.
+#[derive(Clone, Debug, Default)]
+pub struct EnvTrace {
+    set: BTreeMap<String, (Option<String>, String)>,
+}
+
+impl EnvTrace {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record the variables `cmd` sets which changed since the last step as set by the
+    /// step `by`.
+    pub fn record(&mut self, cmd: &Command, by: impl Into<String>) {
+        let by = by.into();
+
+        for (name, value) in cmd.get_envs() {
+            let name = name.to_string_lossy().into_owned();
+            let value = value.map(|value| value.to_string_lossy().into_owned());
+
+            if self.set.get(&name).map(|(set, _)| set) != Some(&value) {
+                self.set.insert(name, (value, by.clone()));
+            }
+        }
+    }
+
+    /// The full environment of the traced command: the inherited variables of this
+    /// process with the recorded changes applied.
+    pub fn variables(&self) -> Vec<Variable> {
+        let mut variables = env::vars_os()
+            .map(|(name, value)| {
+                let name = name.to_string_lossy().into_owned();
+
+                (
+                    name.clone(),
+                    Variable {
+                        name,
+                        value: Some(value.to_string_lossy().into_owned()),
+                        origin: Origin::Inherited,
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        for (name, (value, by)) in &self.set {
+            let by = by.clone();
+            let overrides = variables.contains_key(name);
+
+            let origin = match value {
+                Some(_) => Origin::Set { by, overrides },
+                None => Origin::Removed { by },
+            };
+
+            variables.insert(
+                name.clone(),
+                Variable {
+                    name: name.clone(),
+                    value: value.clone(),
+                    origin,
+                },
+            );
+        }
+
+        variables.into_values().collect()
+    }
+}
+
+/// A location `tool` was found at.
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub path: PathBuf,
+    /// Where the location comes from, e.g. `PATH` or `PlatformIO package tool-esptoolpy`.
+    pub source: String,
+}
+
+/// All locations of `tool`, in the order cargo-pio searches them: PlatformIO itself and
+/// its Python environment, the installed PlatformIO packages (primary installations
+/// first), and `PATH`.
+pub fn which(pio: &Pio, tool: &str) -> Vec<Location> {
+    let file_name = if cfg!(windows) && !tool.contains('.') {
+        format!("{}{}", tool, env::consts::EXE_SUFFIX)
+    } else {
+        tool.to_owned()
+    };
+
+    let mut locations = Vec::new();
+
+    if tool == "pio" || tool == "platformio" {
+        locations.push(Location {
+            path: pio.platformio_exe.clone(),
+            source: "PlatformIO installation".into(),
+        });
+    }
+
+    if let Some(penv_bin_dir) = pio.platformio_exe.parent() {
+        let path = penv_bin_dir.join(&file_name);
+
+        if path.is_file() && !locations.iter().any(|location| location.path == path) {
+            locations.push(Location {
+                path,
+                source: "PlatformIO Python environment".into(),
+            });
+        }
+    }
+
+    if let Ok(entries) = fs::read_dir(pio.core_dir.join("packages")) {
+        let mut packages = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+
+        // `<name>` sorts before the `<name>@<version>` installed side by side
+        packages.sort();
+
+        for package in packages {
+            for dir in [package.join("bin"), package.clone()] {
+                let path = dir.join(&file_name);
+
+                if path.is_file() {
+                    locations.push(Location {
+                        path,
+                        source: format!(
+                            "PlatformIO package {}",
+                            package
+                                .file_name()
+                                .unwrap_or_else(|| OsStr::new(""))
+                                .to_string_lossy()
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(path) = env::var_os("PATH") {
+        for dir in env::split_paths(&path) {
+            let path = dir.join(&file_name);
+
+            if path.is_file() {
+                locations.push(Location {
+                    path,
+                    source: "PATH".into(),
+                });
+            }
+        }
+    }
+
+    locations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_trace() {
+        let mut trace = EnvTrace::new();
+
+        let mut cmd = Command::new("pio");
+        cmd.env("CARGO_PKG_NAME", "app")
+            .env("CARGO_PIO_BUILD_ACTIVE", "1");
+        trace.record(&cmd, "PlatformIO");
+
+        cmd.env("CARGO_PIO_CARGO_PROFILE", "size")
+            .env_remove("RUSTFLAGS");
+        trace.record(&cmd, "cargo-pio.toml [env.release]");
+
+        let variables = trace
+            .variables()
+            .into_iter()
+            .map(|variable| (variable.name.clone(), variable))
+            .collect::<BTreeMap<_, _>>();
+
+        // Set by cargo while running the tests
+        assert_eq!(
+            variables["CARGO_PKG_NAME"].origin,
+            Origin::Set {
+                by: "PlatformIO".into(),
+                overrides: true
+            }
+        );
+        assert_eq!(
+            variables["CARGO_PIO_CARGO_PROFILE"].origin,
+            Origin::Set {
+                by: "cargo-pio.toml [env.release]".into(),
+                overrides: env::var_os("CARGO_PIO_CARGO_PROFILE").is_some()
+            }
+        );
+        assert_eq!(
+            variables["CARGO_PIO_BUILD_ACTIVE"].value.as_deref(),
+            Some("1")
+        );
+        assert_eq!(
+            variables["RUSTFLAGS"].origin,
+            Origin::Removed {
+                by: "cargo-pio.toml [env.release]".into()
+            }
+        );
+        assert_eq!(variables["RUSTFLAGS"].value, None);
+        assert_eq!(variables["CARGO_MANIFEST_DIR"].origin, Origin::Inherited);
+    }
+
+    #[test]
+    fn test_which() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_name = format!("esptool{}", env::consts::EXE_SUFFIX);
+
+        let pio = Pio {
+            platformio_exe: dir.path().join("penv").join("bin").join("platformio"),
+            core_dir: dir.path().to_owned(),
+            log_level: Default::default(),
+            timeout: None,
+        };
+
+        for dir in [
+            pio.platformio_exe.parent().unwrap().to_owned(),
+            dir.path().join("packages").join("tool-esptool"),
+            dir.path()
+                .join("packages")
+                .join("tool-esptool@1.0.0")
+                .join("bin"),
+        ] {
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(&file_name), "").unwrap();
+        }
+
+        let locations = which(&pio, "esptool");
+        let sources = locations
+            .iter()
+            .map(|location| location.source.as_str())
+            .take(3)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            [
+                "PlatformIO Python environment",
+                "PlatformIO package tool-esptool",
+                "PlatformIO package tool-esptool@1.0.0",
+            ]
+        );
+
+        assert_eq!(which(&pio, "pio")[0].source, "PlatformIO installation");
+    }
+}
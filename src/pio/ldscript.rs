@@ -0,0 +1,367 @@
+//! Linker scripts generated from templates, configured in the `[[linker-scripts]]` of
+//! `cargo-pio.toml` (see [`LinkerScriptConfig`]).
+//!
+//! Hand-written `memory.x` files and section placements drift out of sync with the
+//! flash map as soon as the board or the partition table changes. Their templates
+//! instead refer to the metadata of the board and the partitions of the environment, and
+//! every build regenerates the scripts, logging the diff of every change.
+//!
+//! Templates are the linker scripts with `{{ <expression> [| <filter>] }}` placeholders,
+//! where the expression is a variable or a number, or a sum or difference of them
+//! separated by spaces, e.g.
+//!
+//! ```text
+//! MEMORY
+//! {
+//!   FLASH : ORIGIN = {{ partitions.factory.offset + 0x20 | hex }}, LENGTH = {{ partitions.factory.size | size }}
+//!   RAM : ORIGIN = {{ ram.origin | hex }}, LENGTH = {{ ram.size | size }}
+//! }
+//! ```
+//!
+//! The filters are `hex` (`0x0001_0020` as `0x00010020`) and `size` (in `K` or `M` if
+//! divisible). The variables are
+//! - `environment`;
+//! - `board.id`, `board.name` and `board.mcu`, and the numbers `board.f_cpu`,
+//!   `board.ram_size`, `board.flash_size` and `board.max_firmware_size`, if known;
+//! - `flash.origin`, `flash.size`, `ram.origin` and `ram.size`, the memory of the MCU
+//!   (see [`Memory`]) if the sizes are known;
+//! - `partitions.<label>.offset` and `partitions.<label>.size` of every partition of the
+//!   partition table CSV of `board_build.partitions`, or else of the last build.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+
+use super::board::BoardInfo;
+use super::config::{Config, LinkerScriptConfig};
+use super::managed::unified_diff;
+use super::runtime::Memory;
+use super::Pio;
+use crate::partitions::Partition;
+use crate::pio_model::env_option;
+
+/// The value of a template variable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Number(u64),
+    Text(String),
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{}", number),
+            Self::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+/// The variables of the templates, keyed by their names.
+pub type Variables = BTreeMap<String, Value>;
+
+/// Render `template` with `variables`.
+pub fn render(template: &str, variables: &Variables) -> Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let line = template[..template.len() - rest.len() + start]
+            .matches('\n')
+            .count()
+            + 1;
+
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| anyhow!("Unclosed placeholder on line {}", line))?;
+        let placeholder = &rest[start + 2..start + end];
+
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(
+            &expand(placeholder, variables)
+                .with_context(|| format!("Invalid placeholder on line {}", line))?,
+        );
+
+        rest = &rest[start + end + 2..];
+    }
+
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// Expand the `placeholder` (without the braces).
+fn expand(placeholder: &str, variables: &Variables) -> Result<String> {
+    let (expression, filter) = match placeholder.split_once('|') {
+        Some((expression, filter)) => (expression, Some(filter.trim())),
+        None => (placeholder, None),
+    };
+
+    let operand = |token: &str| -> Result<Value> {
+        if let Some(value) = variables.get(token) {
+            return Ok(value.clone());
+        }
+
+        let number = match token.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+            None => token.replace('_', "").parse(),
+        };
+
+        number
+            .map(Value::Number)
+            .map_err(|_| anyhow!("Unknown variable '{}'", token))
+    };
+
+    let mut tokens = expression.split_whitespace();
+    let mut value = operand(tokens.next().ok_or_else(|| anyhow!("Empty placeholder"))?)?;
+
+    while let Some(operator) = tokens.next() {
+        let number = |value: Value| match value {
+            Value::Number(number) => Ok(number),
+            Value::Text(text) => Err(anyhow!("'{}' is not a number", text)),
+        };
+
+        let left = number(value)?;
+        let right =
+            number(operand(tokens.next().ok_or_else(|| {
+                anyhow!("Missing operand after '{}'", operator)
+            })?)?)?;
+
+        value = Value::Number(
+            match operator {
+                "+" => left.checked_add(right),
+                "-" => left.checked_sub(right),
+                _ => bail!("Unknown operator '{}', expected + or -", operator),
+            }
+            .ok_or_else(|| anyhow!("Overflow in '{}'", expression.trim()))?,
+        );
+    }
+
+    Ok(match (filter, value) {
+        (None, value) => value.to_string(),
+        (Some("hex"), Value::Number(number)) => format!("{:#010x}", number),
+        (Some("size"), Value::Number(number)) => {
+            if number != 0 && number % (1024 * 1024) == 0 {
+                format!("{}M", number / (1024 * 1024))
+            } else if number % 1024 == 0 {
+                format!("{}K", number / 1024)
+            } else {
+                number.to_string()
+            }
+        }
+        (Some(filter @ ("hex" | "size")), Value::Text(text)) => {
+            bail!("Filter {} needs a number, got '{}'", filter, text)
+        }
+        (Some(filter), _) => bail!("Unknown filter '{}', expected hex or size", filter),
+    })
+}
+
+/// The variables of the PlatformIO `environment` of the project in `project_dir`.
+pub fn variables(
+    pio: &Pio,
+    config: &Config,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+) -> Result<Variables> {
+    let project_dir = project_dir.as_ref();
+
+    let board = BoardInfo::load(
+        pio,
+        &config.board.clone().unwrap_or_default(),
+        project_dir,
+        environment,
+    )?;
+
+    let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))
+        .context("Failed to read platformio.ini")?;
+    let csv = env_option(&platformio_ini, environment, "board_build.partitions")
+        .map(|csv| project_dir.join(csv))
+        .filter(|csv| csv.is_file());
+
+    let partitions = match csv {
+        Some(csv) => Partition::parse_csv(&fs::read_to_string(&csv)?)
+            .with_context(|| format!("Failed to parse {}", csv.display()))?,
+        None => fs::read(
+            project_dir
+                .join(".pio")
+                .join("build")
+                .join(environment)
+                .join("partitions.bin"),
+        )
+        .map(|table| Partition::parse_table(&table))
+        .unwrap_or_default(),
+    };
+
+    Ok(collect(environment, board.as_ref(), &partitions))
+}
+
+fn collect(environment: &str, board: Option<&BoardInfo>, partitions: &[Partition]) -> Variables {
+    let mut variables = Variables::new();
+    let mut set = |name: &str, value: Value| {
+        variables.insert(name.to_owned(), value);
+    };
+
+    set("environment", Value::Text(environment.to_owned()));
+
+    if let Some(board) = board {
+        set("board.id", Value::Text(board.id.clone()));
+        set("board.name", Value::Text(board.name.clone()));
+        set("board.mcu", Value::Text(board.mcu.clone()));
+
+        for (name, value) in [
+            ("board.f_cpu", board.f_cpu),
+            ("board.ram_size", board.ram_size),
+            ("board.flash_size", board.flash_size),
+            ("board.max_firmware_size", board.max_firmware_size),
+        ] {
+            if let Some(value) = value {
+                set(name, Value::Number(value));
+            }
+        }
+
+        if let (Some(rom), Some(ram)) =
+            (board.max_firmware_size.or(board.flash_size), board.ram_size)
+        {
+            let memory = Memory::of_mcu(&board.mcu, rom, ram);
+
+            set("flash.origin", Value::Number(memory.flash_origin));
+            set("flash.size", Value::Number(memory.flash_size));
+            set("ram.origin", Value::Number(memory.ram_origin));
+            set("ram.size", Value::Number(memory.ram_size));
+        }
+    }
+
+    for partition in partitions {
+        let name = |field: &str| format!("partitions.{}.{}", partition.label, field);
+
+        set(&name("offset"), Value::Number(partition.offset.into()));
+        set(&name("size"), Value::Number(partition.size.into()));
+    }
+
+    variables
+}
+
+/// Generate the linker scripts of `config` of the PlatformIO `environment` of the project
+/// in `project_dir`, or only check them if `check`.
+///
+/// Returns the paths of the scripts which changed (or would change) with their diffs.
+pub fn generate(
+    pio: &Pio,
+    config: &Config,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+    check: bool,
+) -> Result<Vec<(PathBuf, String)>> {
+    let project_dir = project_dir.as_ref();
+
+    let scripts = config
+        .linker_scripts
+        .iter()
+        .filter(|script| {
+            script
+                .environment
+                .as_deref()
+                .map_or(true, |env| env == environment)
+        })
+        .collect::<Vec<_>>();
+
+    if scripts.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let variables = variables(pio, config, project_dir, environment)?;
+    let mut changes = Vec::new();
+
+    for script in scripts {
+        let path = project_dir.join(&script.output);
+        let generated = generate_script(project_dir, script, &variables)?;
+        let current = fs::read_to_string(&path).unwrap_or_default();
+
+        if generated == current {
+            debug!("Linker script {} is up-to-date", path.display());
+            continue;
+        }
+
+        let diff = unified_diff(&current, &generated, &script.output);
+
+        if !check {
+            info!("Updating {}:\n{}", path.display(), diff);
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            crate::fs::write_atomic(&path, &generated)?;
+        }
+
+        changes.push((path, diff));
+    }
+
+    Ok(changes)
+}
+
+fn generate_script(
+    project_dir: &Path,
+    script: &LinkerScriptConfig,
+    variables: &Variables,
+) -> Result<String> {
+    let template = project_dir.join(&script.template);
+    let content = fs::read_to_string(&template)
+        .with_context(|| format!("Failed to read {}", template.display()))?;
+
+    let rendered = render(&content, variables)
+        .with_context(|| format!("Failed to render {}", template.display()))?;
+
+    Ok(format!(
+        "/* Generated by cargo-pio from {}, edit the template instead */\n{}",
+        script.template.display(),
+        rendered
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let board = BoardInfo {
+            id: "nucleo_f401re".into(),
+            mcu: "stm32f401ret6".into(),
+            ram_size: Some(96 * 1024),
+            max_firmware_size: Some(512 * 1024),
+            ..Default::default()
+        };
+        let partitions = [Partition {
+            label: "factory".into(),
+            kind: 0,
+            subtype: 0,
+            offset: 0x10000,
+            size: 0x100000,
+        }];
+        let variables = collect("release", Some(&board), &partitions);
+
+        assert_eq!(
+            render(
+                "/* {{ board.mcu }} */\n\
+                 FLASH : ORIGIN = {{ flash.origin | hex }}, LENGTH = {{ flash.size | size }}\n\
+                 RAM : ORIGIN = {{ram.origin|hex}}, LENGTH = {{ ram.size - 0x400 | size }}\n\
+                 APP : ORIGIN = {{ partitions.factory.offset + 0x20 | hex }}, LENGTH = {{ partitions.factory.size | size }}\n",
+                &variables
+            )
+            .unwrap(),
+            "/* stm32f401ret6 */\n\
+             FLASH : ORIGIN = 0x08000000, LENGTH = 512K\n\
+             RAM : ORIGIN = 0x20000000, LENGTH = 95K\n\
+             APP : ORIGIN = 0x00010020, LENGTH = 1M\n"
+        );
+
+        let error = |template| format!("{:#}", render(template, &variables).unwrap_err());
+        assert!(error("\n{{ flash.origin").contains("Unclosed placeholder on line 2"));
+        assert!(error("{{ flash.offset }}").contains("Unknown variable 'flash.offset'"));
+        assert!(error("{{ board.mcu + 1 }}").contains("'stm32f401ret6' is not a number"));
+        assert!(error("{{ ram.size | dec }}").contains("Unknown filter 'dec'"));
+    }
+}
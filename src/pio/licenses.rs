@@ -0,0 +1,338 @@
+//! Collection of the licenses of everything linked into the firmware of a PIO->Cargo
+//! project, for attribution documents of firmware releases.
+//!
+//! Three sources are scanned:
+//! - the Cargo dependencies of the project, as reported by `cargo metadata`;
+//! - the PlatformIO libraries installed for an environment (`.pio/libdeps/<env>`);
+//! - the platforms and packages (frameworks, SDKs) installed in PlatformIO's core
+//!   directory.
+//!
+//! Licenses are taken from the package metadata (SPDX expressions where available), the
+//! license texts from the `LICENSE*`, `LICENCE*`, `COPYING*` and `NOTICE*` files of each
+//! package.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use serde_json::Value as JsonValue;
+
+use super::Pio;
+
+/// Where a component comes from.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Source {
+    Cargo,
+    PioLibrary,
+    PioPlatform,
+    PioPackage,
+}
+
+impl Source {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Cargo => "Cargo crate",
+            Self::PioLibrary => "PlatformIO library",
+            Self::PioPlatform => "PlatformIO platform",
+            Self::PioPackage => "PlatformIO package",
+        }
+    }
+}
+
+/// A third-party component of the firmware.
+#[derive(Clone, Debug)]
+pub struct Component {
+    pub source: Source,
+    pub name: String,
+    pub version: String,
+    /// The license (expression) declared in the metadata of the component.
+    pub license: Option<String>,
+    /// The license files of the component, as file name and content.
+    pub texts: Vec<(String, String)>,
+}
+
+/// Collect the components of the PlatformIO `environment` of the project in
+/// `project_dir`, sorted by source and name.
+pub fn collect(
+    pio: &Pio,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+) -> Result<Vec<Component>> {
+    let project_dir = project_dir.as_ref();
+
+    let mut components = Vec::new();
+
+    if project_dir.join("Cargo.toml").is_file() {
+        components.extend(cargo_components(project_dir)?);
+    }
+
+    components.extend(pio_libraries(
+        &project_dir.join(".pio").join("libdeps").join(environment),
+    )?);
+    components.extend(pio_packages(
+        &pio.core_dir.join("platforms"),
+        "platform.json",
+        Source::PioPlatform,
+    )?);
+    components.extend(pio_packages(
+        &pio.core_dir.join("packages"),
+        "package.json",
+        Source::PioPackage,
+    )?);
+
+    components.sort_by(|a, b| (a.source, &a.name).cmp(&(b.source, &b.name)));
+
+    for component in &components {
+        if component.license.is_none() && component.texts.is_empty() {
+            warn!(
+                "No license found for {} {} {}",
+                component.source.name(),
+                component.name,
+                component.version
+            );
+        }
+    }
+
+    Ok(components)
+}
+
+/// Render the attribution document of `components` as markdown: a summary table
+/// followed by the license texts of every component.
+pub fn report(title: &str, components: &[Component]) -> String {
+    let mut report = String::new();
+
+    writeln!(report, "# Third-party licenses of {}\n", title).unwrap();
+    writeln!(report, "| Component | Version | Source | License |").unwrap();
+    writeln!(report, "|---|---|---|---|").unwrap();
+
+    for component in components {
+        writeln!(
+            report,
+            "| {} | {} | {} | {} |",
+            component.name,
+            component.version,
+            component.source.name(),
+            component.license.as_deref().unwrap_or("UNKNOWN")
+        )
+        .unwrap();
+    }
+
+    for component in components.iter().filter(|c| !c.texts.is_empty()) {
+        writeln!(report, "\n## {} {}", component.name, component.version).unwrap();
+
+        for (name, text) in &component.texts {
+            writeln!(report, "\n{}:\n\n```text\n{}\n```", name, text.trim_end()).unwrap();
+        }
+    }
+
+    report
+}
+
+fn cargo_components(project_dir: &Path) -> Result<Vec<Component>> {
+    let output = Command::new("cargo")
+        .current_dir(project_dir)
+        .args(["metadata", "--format-version", "1"])
+        .output()?;
+
+    if !output.status.success() {
+        bail!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let metadata: JsonValue = serde_json::from_slice(&output.stdout)?;
+
+    let members = metadata["workspace_members"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    metadata["packages"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid cargo metadata output"))?
+        .iter()
+        .filter(|package| !members.contains(&package["id"]))
+        .map(|package| {
+            let dir = package["manifest_path"]
+                .as_str()
+                .map(PathBuf::from)
+                .and_then(|path| path.parent().map(Path::to_owned))
+                .ok_or_else(|| anyhow!("Invalid cargo metadata output"))?;
+
+            let mut texts = license_texts(&dir)?;
+
+            if let Some(file) = package["license_file"].as_str() {
+                let path = dir.join(file);
+
+                if !texts
+                    .iter()
+                    .any(|(name, _)| Path::new(file).ends_with(name))
+                {
+                    texts.push((file.to_owned(), fs::read_to_string(path)?));
+                }
+            }
+
+            Ok(Component {
+                source: Source::Cargo,
+                name: string(&package["name"]),
+                version: string(&package["version"]),
+                license: package["license"].as_str().map(str::to_owned),
+                texts,
+            })
+        })
+        .collect()
+}
+
+fn pio_libraries(libdeps_dir: &Path) -> Result<Vec<Component>> {
+    let mut components = Vec::new();
+
+    for dir in sub_dirs(libdeps_dir)? {
+        let json = dir.join("library.json");
+        let properties = dir.join("library.properties");
+
+        let (name, version, license) = if json.is_file() {
+            let manifest = read_json(&json)?;
+
+            (
+                manifest["name"].as_str().map(str::to_owned),
+                string(&manifest["version"]),
+                manifest["license"].as_str().map(str::to_owned),
+            )
+        } else if properties.is_file() {
+            // Arduino libraries declare no license in their metadata
+            let properties = fs::read_to_string(&properties)?;
+            let property = |key: &str| {
+                properties.lines().find_map(|line| {
+                    let (k, v) = line.split_once('=')?;
+                    (k.trim() == key).then(|| v.trim().to_owned())
+                })
+            };
+
+            (
+                property("name"),
+                property("version").unwrap_or_default(),
+                None,
+            )
+        } else {
+            (None, String::new(), None)
+        };
+
+        components.push(Component {
+            source: Source::PioLibrary,
+            name: name.unwrap_or_else(|| file_name(&dir)),
+            version,
+            license,
+            texts: license_texts(&dir)?,
+        });
+    }
+
+    Ok(components)
+}
+
+fn pio_packages(dir: &Path, manifest: &str, source: Source) -> Result<Vec<Component>> {
+    let mut components = Vec::new();
+
+    for dir in sub_dirs(dir)? {
+        let manifest = dir.join(manifest);
+
+        // Skip PlatformIO's caches and partially installed packages
+        if !manifest.is_file() {
+            continue;
+        }
+
+        let manifest = read_json(&manifest)?;
+
+        components.push(Component {
+            source,
+            name: manifest["name"]
+                .as_str()
+                .map(str::to_owned)
+                .unwrap_or_else(|| file_name(&dir)),
+            version: string(&manifest["version"]),
+            license: manifest["license"].as_str().map(str::to_owned),
+            texts: license_texts(&dir)?,
+        });
+    }
+
+    Ok(components)
+}
+
+/// The license files in `dir`.
+fn license_texts(dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut texts = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if entry.file_type()?.is_file() && is_license_file(&name) {
+            // Some packages ship license files in legacy encodings
+            let text = String::from_utf8_lossy(&fs::read(entry.path())?).into_owned();
+
+            texts.push((name, text));
+        }
+    }
+
+    texts.sort();
+
+    Ok(texts)
+}
+
+fn is_license_file(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+
+    ["LICENSE", "LICENCE", "COPYING", "NOTICE"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+fn sub_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut dirs = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+            dirs.push(entry.path());
+        }
+    }
+
+    Ok(dirs)
+}
+
+fn read_json(path: &Path) -> Result<JsonValue> {
+    serde_json::from_str(&fs::read_to_string(path)?)
+        .with_context(|| anyhow!("Failed to parse {}", path.display()))
+}
+
+fn string(value: &JsonValue) -> String {
+    value.as_str().unwrap_or_default().to_owned()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_license_file() {
+        assert!(is_license_file("LICENSE"));
+        assert!(is_license_file("LICENSE-APACHE"));
+        assert!(is_license_file("license.txt"));
+        assert!(is_license_file("COPYING"));
+        assert!(!is_license_file("README.md"));
+    }
+}
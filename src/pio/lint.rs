@@ -0,0 +1,1028 @@
+//! Consistency checks between the Cargo and the PlatformIO configuration of PIO->Cargo
+//! projects.
+//!
+//! `Cargo.toml`, `.cargo/config.toml` and `platformio.ini` all describe the same
+//! firmware, but nothing keeps them in sync: a board changed in `platformio.ini` leaves
+//! the `rust_target` of the old MCU behind, a renamed package no longer matches
+//! `rust_lib`, and settings copied from a hosted project (`panic = "unwind"`, cross
+//! language LTO) only fail at link time, if at all. The checks are:
+//! - the library is built as a `staticlib` and `rust_lib` is its name;
+//! - the `rust_target` of every environment matches the MCU of its board, and the
+//!   default target of the Cargo config is one of them;
+//! - no profile unwinds on panic, unless the C++ code of every environment is compiled
+//!   with exceptions: the C toolchains only link the unwinder with them, e.g. ESP-IDF
+//!   with `CONFIG_COMPILER_CXX_EXCEPTIONS`, and bare metal targets cannot unwind at all;
+//! - the C++ code is not compiled with exceptions while Rust aborts on panic, as a C++
+//!   exception thrown through Rust frames terminates the firmware (a warning);
+//! - no `-C linker-plugin-lto` is passed to rustc, as the GCC toolchains of PlatformIO
+//!   cannot link LLVM bitcode.
+//!
+//! Most of the problems are fixed by [`fix`], which edits the files in place. The panic
+//! strategy of the profile of an environment is also checked before every build of it,
+//! see [`preflight`].
+//!
+//! RTTI (`-fno-rtti`, `CONFIG_COMPILER_CXX_RTTI`) is independent of all this: GCC emits
+//! the type information of thrown exceptions without it.
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+
+use super::Resolver;
+use crate::error::HintExt;
+use crate::pio_model::{env_option, environments};
+
+/// A problem detected in the configuration of a project.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Issue {
+    /// The library is not built as a `staticlib`, so PlatformIO has nothing to link.
+    NotStaticlib { crate_types: Vec<String> },
+    /// The `rust_lib` of an environment is not the name of the library.
+    LibName {
+        environment: String,
+        rust_lib: String,
+        name: String,
+    },
+    /// The `rust_target` of an environment is for a different MCU than its board.
+    TargetMismatch {
+        environment: String,
+        target: String,
+        mcu: String,
+        expected: String,
+    },
+    /// The default target of the Cargo config is none of the `rust_target`s.
+    ConfigTarget { configured: String, target: String },
+    /// A profile unwinds on panic, but an environment cannot unwind.
+    PanicUnwind { profile: String },
+    /// A profile unwinds on panic, but the C++ code of an ESP-IDF environment is
+    /// compiled without exceptions, so the unwinder is not linked.
+    CxxExceptionsDisabled {
+        environment: String,
+        profile: String,
+    },
+    /// The C++ code of an environment is compiled with exceptions, but its profile
+    /// aborts on panic.
+    CxxExceptionsAbort {
+        environment: String,
+        profile: String,
+    },
+    /// Rustc emits LLVM bitcode for the linker.
+    LinkerPluginLto { rustflags: String },
+}
+
+impl Issue {
+    /// Whether the problem breaks the build or the firmware, rather than only tooling
+    /// like `cargo check` and rust-analyzer.
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            Self::ConfigTarget { .. } | Self::CxxExceptionsAbort { .. }
+        )
+    }
+
+    /// Whether [`fix`] fixes the problem.
+    pub fn is_fixable(&self) -> bool {
+        !matches!(
+            self,
+            Self::LibName { .. } | Self::LinkerPluginLto { .. } | Self::CxxExceptionsAbort { .. }
+        )
+    }
+
+    /// A suggestion how the problem can be fixed.
+    pub fn hint(&self) -> String {
+        match self {
+            Self::NotStaticlib { crate_types } => {
+                let mut crate_types = crate_types.clone();
+                crate_types.push("staticlib".to_owned());
+
+                format!(
+                    "set `crate-type = {}` in the [lib] section of Cargo.toml",
+                    toml_array(&crate_types)
+                )
+            }
+            Self::LibName {
+                environment, name, ..
+            } => format!(
+                "set `rust_lib = {}` in [env:{}] of platformio.ini",
+                name, environment
+            ),
+            Self::TargetMismatch {
+                environment,
+                expected,
+                ..
+            } => format!(
+                "set `rust_target = {}` in [env:{}] of platformio.ini, or another target of the same architecture",
+                expected, environment
+            ),
+            Self::ConfigTarget { target, .. } => format!(
+                "set `target = \"{}\"` in the [build] section of .cargo/config.toml, so that `cargo check` and rust-analyzer check what is built",
+                target
+            ),
+            Self::PanicUnwind { profile } => format!(
+                "set `panic = \"abort\"` in [profile.{}] of Cargo.toml",
+                profile
+            ),
+            Self::CxxExceptionsDisabled { profile, .. } => format!(
+                "set `{}=y` in sdkconfig.defaults, or `panic = \"abort\"` in [profile.{}] of Cargo.toml",
+                CXX_EXCEPTIONS, profile
+            ),
+            Self::CxxExceptionsAbort { profile, .. } => format!(
+                "catch all C++ exceptions before they reach Rust code, or set `panic = \"unwind\"` in [profile.{}] of Cargo.toml if the environment can unwind",
+                profile
+            ),
+            Self::LinkerPluginLto { .. } => "remove `-C linker-plugin-lto` from the rustflags; use `lto = true` in the profile for LTO of the Rust code".to_owned(),
+        }
+    }
+
+    /// The problem, without the hint.
+    pub fn problem(&self) -> String {
+        match self {
+            Self::NotStaticlib { crate_types } if crate_types.is_empty() => {
+                "the library is not built as a `staticlib`".to_owned()
+            }
+            Self::NotStaticlib { crate_types } => format!(
+                "the library is built as {}, but not as a `staticlib`",
+                crate_types.join(", ")
+            ),
+            Self::LibName {
+                environment,
+                rust_lib,
+                name,
+            } => format!(
+                "`rust_lib` of environment {} is `{}`, but the library is `{}`",
+                environment, rust_lib, name
+            ),
+            Self::TargetMismatch {
+                environment,
+                target,
+                mcu,
+                ..
+            } => format!(
+                "`rust_target` {} of environment {} does not match its MCU {}",
+                target, environment, mcu
+            ),
+            Self::ConfigTarget { configured, target } => format!(
+                "the Cargo config builds for {} by default, but the firmware is built for {}",
+                configured, target
+            ),
+            Self::PanicUnwind { profile } => format!(
+                "profile `{}` unwinds on panic, but the C toolchain links no unwinder",
+                profile
+            ),
+            Self::CxxExceptionsDisabled {
+                environment,
+                profile,
+            } => format!(
+                "profile `{}` unwinds on panic, but environment {} is built without `{}`, which links the unwinder",
+                profile, environment, CXX_EXCEPTIONS
+            ),
+            Self::CxxExceptionsAbort {
+                environment,
+                profile,
+            } => format!(
+                "the C++ code of environment {} throws exceptions, but profile `{}` aborts on panic, so an exception thrown through Rust code terminates the firmware",
+                environment, profile
+            ),
+            Self::LinkerPluginLto { rustflags } => format!(
+                "`{}` enables cross-language LTO, but the GCC toolchains of PlatformIO cannot link LLVM bitcode",
+                rustflags
+            ),
+        }
+    }
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n  hint: {}", self.problem(), self.hint())
+    }
+}
+
+/// Check the configuration of the project in `project_dir`.
+///
+/// `board_mcu` returns the MCU of a board id; the `board_build.mcu` of an environment
+/// takes precedence over it.
+pub fn check(
+    project_dir: impl AsRef<Path>,
+    board_mcu: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<Issue>> {
+    let project_dir = project_dir.as_ref();
+
+    let cargo_toml = read(project_dir.join("Cargo.toml"))?;
+    let platformio_ini = read(project_dir.join("platformio.ini"))?;
+    let config_toml = match cargo_config(project_dir) {
+        Some(path) => Some(read(path)?),
+        None => None,
+    };
+
+    check_contents(
+        &cargo_toml,
+        config_toml.as_deref(),
+        &platformio_ini,
+        board_mcu,
+        |environment| sdkconfig(project_dir, environment),
+    )
+}
+
+/// Check the panic strategy of `profile` (the one derived from the `build_type` of the
+/// environment if `None`) against the C++ code of the PlatformIO `environment` of the
+/// project in `project_dir`, before building it.
+///
+/// Fails if the environment cannot link or will crash, and warns about the firmware
+/// terminating on C++ exceptions.
+pub fn preflight(
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+    profile: Option<&str>,
+) -> Result<()> {
+    let project_dir = project_dir.as_ref();
+
+    let manifest = read(project_dir.join("Cargo.toml"))?
+        .parse::<toml::Value>()
+        .context("Failed to parse Cargo.toml")?;
+    let platformio_ini = read(project_dir.join("platformio.ini"))?;
+
+    let target = match env_option(&platformio_ini, environment, "rust_target") {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+
+    let profile = profile
+        .map(str::to_owned)
+        .unwrap_or_else(|| env_profile(&platformio_ini, environment));
+
+    let issue = panic_issue(
+        &platformio_ini,
+        environment,
+        &target,
+        &profile,
+        unwinds(&manifest, &profile),
+        sdkconfig(project_dir, environment).as_deref(),
+    );
+
+    match issue {
+        Some(issue) if issue.is_error() => Err(anyhow!(
+            "The panic strategy of environment {} does not fit its C++ code: {}",
+            environment,
+            issue.problem()
+        ))
+        .hint(issue.hint()),
+        Some(issue) => {
+            warn!("{}", issue);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Fix the fixable `issues` of the project in `project_dir` and return the files
+/// which were changed.
+pub fn fix(project_dir: impl AsRef<Path>, issues: &[Issue]) -> Result<Vec<PathBuf>> {
+    let project_dir = project_dir.as_ref();
+
+    let mut files: Vec<(PathBuf, String, String)> = Vec::new();
+    let mut edit = |path: PathBuf, f: &dyn Fn(&str) -> String| -> Result<()> {
+        let index = match files.iter().position(|(file, ..)| *file == path) {
+            Some(index) => index,
+            None => {
+                // Only `sdkconfig.defaults` may not exist yet
+                let content = if path.exists() {
+                    read(&path)?
+                } else {
+                    String::new()
+                };
+                files.push((path, content.clone(), content));
+                files.len() - 1
+            }
+        };
+
+        files[index].2 = f(&files[index].2);
+
+        Ok(())
+    };
+
+    for issue in issues {
+        match issue {
+            Issue::NotStaticlib { crate_types } => {
+                let mut crate_types = crate_types.clone();
+                crate_types.push("staticlib".to_owned());
+
+                edit(project_dir.join("Cargo.toml"), &|toml| {
+                    set_value(toml, "lib", "crate-type", &toml_array(&crate_types))
+                })?
+            }
+            Issue::TargetMismatch {
+                environment,
+                expected,
+                ..
+            } => edit(project_dir.join("platformio.ini"), &|ini| {
+                set_value(
+                    ini,
+                    &format!("env:{}", environment),
+                    "rust_target",
+                    expected,
+                )
+            })?,
+            Issue::ConfigTarget { target, .. } => {
+                if let Some(path) = cargo_config(project_dir) {
+                    edit(path, &|toml| {
+                        set_value(toml, "build", "target", &format!("\"{}\"", target))
+                    })?
+                }
+            }
+            Issue::PanicUnwind { profile } => edit(project_dir.join("Cargo.toml"), &|toml| {
+                set_value(toml, &format!("profile.{}", profile), "panic", "\"abort\"")
+            })?,
+            Issue::CxxExceptionsDisabled { environment, .. } => {
+                for path in sdkconfig_files(project_dir, environment)
+                    .into_iter()
+                    .chain(std::iter::once(project_dir.join(SDKCONFIG_DEFAULTS)))
+                {
+                    edit(path, &|sdkconfig| {
+                        enable_sdkconfig(sdkconfig, CXX_EXCEPTIONS)
+                    })?;
+                }
+            }
+            Issue::LibName { .. }
+            | Issue::LinkerPluginLto { .. }
+            | Issue::CxxExceptionsAbort { .. } => (),
+        }
+    }
+
+    let mut changed = Vec::new();
+
+    for (path, original, content) in files {
+        if content != original {
+            fs::write(&path, content)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            changed.push(path);
+        }
+    }
+
+    Ok(changed)
+}
+
+fn check_contents(
+    cargo_toml: &str,
+    config_toml: Option<&str>,
+    platformio_ini: &str,
+    board_mcu: impl Fn(&str) -> Option<String>,
+    sdkconfig: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<Issue>> {
+    let manifest = cargo_toml
+        .parse::<toml::Value>()
+        .context("Failed to parse Cargo.toml")?;
+    let config = config_toml
+        .map(|config| config.parse::<toml::Value>())
+        .transpose()
+        .context("Failed to parse the Cargo config")?;
+
+    let mut issues = Vec::new();
+
+    let crate_types = manifest
+        .get("lib")
+        .and_then(|lib| lib.get("crate-type").or_else(|| lib.get("crate_type")))
+        .and_then(toml::Value::as_array)
+        .map(|types| {
+            types
+                .iter()
+                .filter_map(toml::Value::as_str)
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    if !crate_types
+        .iter()
+        .any(|crate_type| crate_type == "staticlib")
+    {
+        issues.push(Issue::NotStaticlib { crate_types });
+    }
+
+    let lib_name = manifest
+        .get("lib")
+        .and_then(|lib| lib.get("name"))
+        .or_else(|| manifest.get("package").and_then(|p| p.get("name")))
+        .and_then(toml::Value::as_str)
+        .map(|name| name.replace('-', "_"));
+
+    let mut targets = Vec::new();
+    let mut environment_targets = Vec::new();
+
+    for environment in environments(platformio_ini) {
+        let option = |key| env_option(platformio_ini, &environment, key);
+
+        if let (Some(rust_lib), Some(name)) = (option("rust_lib"), &lib_name) {
+            if rust_lib != *name {
+                issues.push(Issue::LibName {
+                    environment: environment.clone(),
+                    rust_lib,
+                    name: name.clone(),
+                });
+            }
+        }
+
+        let target = match option("rust_target") {
+            Some(target) => target,
+            None => continue,
+        };
+
+        if !targets.contains(&target) {
+            targets.push(target.clone());
+        }
+        environment_targets.push((environment.clone(), target.clone()));
+
+        let mcu = option("board_build.mcu").or_else(|| option("board").and_then(|b| board_mcu(&b)));
+
+        if let Some(mcu) = mcu {
+            if let Ok(expected) = Resolver::derive_target(&mcu) {
+                if arch(&target) != arch(expected) {
+                    issues.push(Issue::TargetMismatch {
+                        environment: environment.clone(),
+                        target,
+                        mcu,
+                        expected: expected.to_owned(),
+                    });
+                }
+            }
+        }
+    }
+
+    let configured = config
+        .as_ref()
+        .and_then(|config| config.get("build"))
+        .and_then(|build| build.get("target"))
+        .and_then(toml::Value::as_str);
+
+    if let (Some(configured), Some(target)) = (configured, targets.first()) {
+        if !targets.iter().any(|target| target == configured) {
+            issues.push(Issue::ConfigTarget {
+                configured: configured.to_owned(),
+                target: target.clone(),
+            });
+        }
+    }
+
+    let unwinding = manifest
+        .get("profile")
+        .and_then(toml::Value::as_table)
+        .into_iter()
+        .flat_map(|profiles| profiles.keys())
+        .filter(|profile| unwinds(&manifest, profile))
+        .collect::<Vec<_>>();
+
+    let sdkconfigs = environment_targets
+        .iter()
+        .map(|(environment, _)| sdkconfig(environment))
+        .collect::<Vec<_>>();
+
+    for profile in &unwinding {
+        let profile_issues = environment_targets
+            .iter()
+            .zip(&sdkconfigs)
+            .filter_map(|((environment, target), sdkconfig)| {
+                panic_issue(
+                    platformio_ini,
+                    environment,
+                    target,
+                    profile,
+                    true,
+                    sdkconfig.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Aborting fixes all environments, enabling the C++ exceptions only some
+        if profile_issues
+            .iter()
+            .any(|issue| matches!(issue, Issue::PanicUnwind { .. }))
+        {
+            issues.push(Issue::PanicUnwind {
+                profile: (*profile).clone(),
+            });
+        } else {
+            issues.extend(profile_issues);
+        }
+    }
+
+    for ((environment, target), sdkconfig) in environment_targets.iter().zip(&sdkconfigs) {
+        let profile = env_profile(platformio_ini, environment);
+
+        if !unwinding.contains(&&profile) {
+            issues.extend(panic_issue(
+                platformio_ini,
+                environment,
+                target,
+                &profile,
+                false,
+                sdkconfig.as_deref(),
+            ));
+        }
+    }
+
+    if let Some(config) = &config {
+        let build = config.get("build").into_iter();
+        let targets = config
+            .get("target")
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flat_map(|targets| targets.values());
+
+        for rustflags in build
+            .chain(targets)
+            .filter_map(|table| table.get("rustflags"))
+        {
+            let rustflags = match rustflags {
+                toml::Value::String(flags) => flags.clone(),
+                toml::Value::Array(flags) => flags
+                    .iter()
+                    .filter_map(toml::Value::as_str)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                _ => continue,
+            };
+
+            if rustflags.contains("linker-plugin-lto") {
+                issues.push(Issue::LinkerPluginLto { rustflags });
+            }
+        }
+    }
+
+    Ok(issues)
+}
+
+/// The name of the ESP-IDF option compiling the C++ code with exceptions.
+const CXX_EXCEPTIONS: &str = "CONFIG_COMPILER_CXX_EXCEPTIONS";
+
+/// The problem of `profile`, unwinding on panic if `unwinds`, with the C++ code of
+/// `environment` (with `rust_target` `target` and the ESP-IDF configuration
+/// `sdkconfig`), if any.
+fn panic_issue(
+    platformio_ini: &str,
+    environment: &str,
+    target: &str,
+    profile: &str,
+    unwinds: bool,
+    sdkconfig: Option<&str>,
+) -> Option<Issue> {
+    let espidf = env_option(platformio_ini, environment, "framework")
+        .map_or(false, |frameworks| frameworks.contains("espidf"));
+
+    let exceptions = if espidf {
+        // Disabled by default
+        Some(sdkconfig.and_then(sdkconfig_exceptions).unwrap_or(false))
+    } else {
+        flag_exceptions(platformio_ini, environment)
+    };
+
+    // Bare metal targets only abort, their standard library has no unwinding
+    let bare_metal = target.contains("-none");
+
+    match (unwinds, exceptions) {
+        (true, _) if bare_metal => Some(Issue::PanicUnwind {
+            profile: profile.to_owned(),
+        }),
+        (true, Some(true)) => None,
+        (true, Some(false)) if espidf => Some(Issue::CxxExceptionsDisabled {
+            environment: environment.to_owned(),
+            profile: profile.to_owned(),
+        }),
+        (true, _) => Some(Issue::PanicUnwind {
+            profile: profile.to_owned(),
+        }),
+        (false, Some(true)) => Some(Issue::CxxExceptionsAbort {
+            environment: environment.to_owned(),
+            profile: profile.to_owned(),
+        }),
+        (false, _) => None,
+    }
+}
+
+/// Whether `profile` of the Cargo `manifest` unwinds on panic explicitly.
+///
+/// Without `panic = "unwind"`, the profiles get the strategy of the target, which is
+/// aborting for the targets of PlatformIO's MCUs.
+fn unwinds(manifest: &toml::Value, profile: &str) -> bool {
+    manifest
+        .get("profile")
+        .and_then(|profiles| profiles.get(profile))
+        .and_then(|settings| settings.get("panic"))
+        .and_then(toml::Value::as_str)
+        == Some("unwind")
+}
+
+/// The Cargo profile of `environment`, as chosen by `platformio.cargo.py`.
+fn env_profile(platformio_ini: &str, environment: &str) -> String {
+    let profile =
+        env_option(platformio_ini, environment, "cargo_profile").unwrap_or_else(
+            || match env_option(platformio_ini, environment, "build_type").as_deref() {
+                None | Some("release") => "release".to_owned(),
+                Some(_) => "dev".to_owned(),
+            },
+        );
+
+    if profile == "debug" {
+        "dev".to_owned()
+    } else {
+        profile
+    }
+}
+
+/// Whether the ESP-IDF configuration `sdkconfig` compiles the C++ code with exceptions,
+/// if it says.
+fn sdkconfig_exceptions(sdkconfig: &str) -> Option<bool> {
+    // Before ESP-IDF 4.0, the option was named `CONFIG_CXX_EXCEPTIONS`
+    [CXX_EXCEPTIONS, "CONFIG_CXX_EXCEPTIONS"]
+        .iter()
+        .find_map(|name| {
+            sdkconfig.lines().map(str::trim).find_map(|line| {
+                if line == format!("# {} is not set", name) {
+                    Some(false)
+                } else {
+                    let value = line.strip_prefix(name)?.strip_prefix('=')?;
+                    Some(value == "y")
+                }
+            })
+        })
+}
+
+/// Whether the `build_flags` and `build_unflags` of `environment` compile the C++ code
+/// with exceptions, if they say.
+fn flag_exceptions(platformio_ini: &str, environment: &str) -> Option<bool> {
+    let flags = |key| env_option(platformio_ini, environment, key).unwrap_or_default();
+
+    if flags("build_unflags")
+        .split_whitespace()
+        .any(|flag| flag == "-fno-exceptions")
+    {
+        return Some(true);
+    }
+
+    // The last flag wins, as with GCC
+    flags("build_flags")
+        .split_whitespace()
+        .rev()
+        .find_map(|flag| match flag {
+            "-fexceptions" => Some(true),
+            "-fno-exceptions" => Some(false),
+            _ => None,
+        })
+}
+
+/// The ESP-IDF configuration of `environment` of the project in `project_dir`: the one
+/// generated by the last build, or the defaults.
+fn sdkconfig(project_dir: &Path, environment: &str) -> Option<String> {
+    sdkconfig_files(project_dir, environment)
+        .into_iter()
+        .chain(std::iter::once(project_dir.join(SDKCONFIG_DEFAULTS)))
+        .find_map(|path| fs::read_to_string(path).ok())
+}
+
+/// The ESP-IDF defaults file of a project.
+const SDKCONFIG_DEFAULTS: &str = "sdkconfig.defaults";
+
+/// The ESP-IDF configurations of `environment` generated by PlatformIO in the project
+/// `project_dir`.
+fn sdkconfig_files(project_dir: &Path, environment: &str) -> Vec<PathBuf> {
+    [format!("sdkconfig.{}", environment), "sdkconfig".to_owned()]
+        .iter()
+        .map(|name| project_dir.join(name))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Set the ESP-IDF option `name` to `y` in the configuration `content`.
+fn enable_sdkconfig(content: &str, name: &str) -> String {
+    let line = format!("{}=y", name);
+    let mut found = false;
+
+    let mut result = content
+        .lines()
+        .map(|current| {
+            let trimmed = current.trim();
+
+            if trimmed == format!("# {} is not set", name)
+                || trimmed.starts_with(&format!("{}=", name))
+            {
+                found = true;
+                line.as_str()
+            } else {
+                current
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if !found {
+        if !result.is_empty() {
+            result.push('\n');
+        }
+        result.push_str(&line);
+    }
+
+    result.push('\n');
+    result
+}
+
+/// The architecture of the Rust `target`, which must match between targets for the
+/// same MCU: the first component, and for Xtensa also the chip.
+fn arch(target: &str) -> &str {
+    let components = if target.starts_with("xtensa-") { 2 } else { 1 };
+
+    match target.match_indices('-').nth(components - 1) {
+        Some((pos, _)) => &target[..pos],
+        None => target,
+    }
+}
+
+/// The Cargo config of the project in `project_dir`, if it has one.
+fn cargo_config(project_dir: &Path) -> Option<PathBuf> {
+    ["config.toml", "config"]
+        .into_iter()
+        .map(|name| project_dir.join(".cargo").join(name))
+        .find(|path| path.is_file())
+}
+
+fn read(path: impl AsRef<Path>) -> Result<String> {
+    let path = path.as_ref();
+
+    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+fn toml_array(values: &[String]) -> String {
+    format!(
+        "[{}]",
+        values
+            .iter()
+            .map(|value| format!("\"{}\"", value))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Set `key` of `[section]` to the already formatted `value` in the TOML or INI
+/// `content`, replacing a single line value, or adding it at the end of the section or
+/// a new section at the end of the file.
+///
+/// Keys are compared with `_` and `-` being the same, as Cargo does.
+fn set_value(content: &str, section: &str, key: &str, value: &str) -> String {
+    let header = format!("[{}]", section);
+    let same_key = |name: &str| name.trim().replace('_', "-") == key.replace('_', "-");
+
+    let mut result = String::new();
+    let mut current = String::new();
+    // Where to insert the value if the key is not found: after the last non-empty line
+    // of the section
+    let mut insert_at = None;
+    let mut done = false;
+
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+
+        if trimmed.starts_with('[') {
+            current = trimmed.to_owned();
+        } else if !done
+            && current == header
+            && trimmed
+                .split_once('=')
+                .map_or(false, |(name, _)| same_key(name))
+        {
+            let name = trimmed.split_once('=').unwrap().0.trim();
+            let ending = if line.ends_with('\n') { "\n" } else { "" };
+
+            result.push_str(&format!("{} = {}{}", name, value, ending));
+            done = true;
+            continue;
+        }
+
+        result.push_str(line);
+
+        if current == header && !trimmed.is_empty() {
+            insert_at = Some(result.len());
+        }
+    }
+
+    if done {
+        return result;
+    }
+
+    let line = format!("{} = {}\n", key, value);
+
+    match insert_at {
+        Some(pos) => {
+            if !result[..pos].ends_with('\n') {
+                result.insert(pos, '\n');
+                result.insert_str(pos + 1, &line);
+            } else {
+                result.insert_str(pos, &line);
+            }
+        }
+        None => {
+            if !result.is_empty() && !result.ends_with('\n') {
+                result.push('\n');
+            }
+            if !result.is_empty() {
+                result.push('\n');
+            }
+
+            result.push_str(&format!("{}\n{}", header, line));
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let cargo_toml = r#"
+[package]
+name = "my-app"
+version = "0.1.0"
+
+[lib]
+crate-type = ["rlib"]
+
+[profile.release]
+panic = "unwind"
+"#;
+        let config_toml = r#"
+[build]
+target = "thumbv7em-none-eabihf"
+rustflags = ["-C", "linker-plugin-lto"]
+"#;
+        let platformio_ini = "
+[env]
+board = esp32dev
+rust_lib = my_app
+rust_target = thumbv7em-none-eabihf
+
+[env:debug]
+build_type = debug
+
+[env:c3]
+board = esp32-c3-devkitm-1
+rust_lib = app
+rust_target = riscv32imc-esp-espidf
+";
+
+        let mcus = |board: &str| {
+            Some(match board {
+                "esp32dev" => "ESP32".to_owned(),
+                _ => "ESP32C3".to_owned(),
+            })
+        };
+
+        let issues = check_contents(cargo_toml, Some(config_toml), platformio_ini, mcus, |_| {
+            None
+        })
+        .unwrap();
+
+        assert_eq!(
+            issues,
+            [
+                Issue::NotStaticlib {
+                    crate_types: vec!["rlib".to_owned()]
+                },
+                Issue::TargetMismatch {
+                    environment: "debug".to_owned(),
+                    target: "thumbv7em-none-eabihf".to_owned(),
+                    mcu: "ESP32".to_owned(),
+                    expected: "xtensa-esp32-espidf".to_owned(),
+                },
+                Issue::LibName {
+                    environment: "c3".to_owned(),
+                    rust_lib: "app".to_owned(),
+                    name: "my_app".to_owned(),
+                },
+                Issue::PanicUnwind {
+                    profile: "release".to_owned(),
+                },
+                Issue::LinkerPluginLto {
+                    rustflags: "-C linker-plugin-lto".to_owned(),
+                },
+            ]
+        );
+
+        assert_eq!(arch("xtensa-esp32-none-elf"), arch("xtensa-esp32-espidf"));
+        assert_ne!(arch("xtensa-esp32s3-espidf"), arch("xtensa-esp32-espidf"));
+        assert_eq!(arch("thumbv6m-none-eabi"), "thumbv6m");
+    }
+
+    #[test]
+    fn test_panic() {
+        let cargo_toml = r#"
+[package]
+name = "app"
+
+[lib]
+crate-type = ["staticlib"]
+
+[profile.dev]
+panic = "unwind"
+"#;
+        let platformio_ini = "
+[env]
+framework = espidf
+rust_lib = app
+rust_target = xtensa-esp32-espidf
+
+[env:debug]
+build_type = debug
+
+[env:release]
+
+[env:arduino]
+framework = arduino
+build_unflags = -fno-exceptions
+";
+
+        let sdkconfig = |environment: &str| match environment {
+            "release" => Some("CONFIG_COMPILER_CXX_EXCEPTIONS=y\n".to_owned()),
+            _ => Some("# CONFIG_COMPILER_CXX_EXCEPTIONS is not set\n".to_owned()),
+        };
+
+        let issues = check_contents(cargo_toml, None, platformio_ini, |_| None, sdkconfig).unwrap();
+        assert_eq!(
+            issues,
+            [
+                Issue::CxxExceptionsDisabled {
+                    environment: "debug".to_owned(),
+                    profile: "dev".to_owned(),
+                },
+                Issue::CxxExceptionsAbort {
+                    environment: "release".to_owned(),
+                    profile: "release".to_owned(),
+                },
+                Issue::CxxExceptionsAbort {
+                    environment: "arduino".to_owned(),
+                    profile: "release".to_owned(),
+                },
+            ]
+        );
+        assert!(!issues[1].is_error());
+
+        let issues = check_contents(
+            cargo_toml,
+            None,
+            &platformio_ini.replace("xtensa-esp32-espidf", "thumbv7em-none-eabi"),
+            |_| None,
+            sdkconfig,
+        )
+        .unwrap();
+        assert_eq!(
+            issues[0],
+            Issue::PanicUnwind {
+                profile: "dev".to_owned()
+            }
+        );
+
+        assert_eq!(
+            enable_sdkconfig(
+                "CONFIG_A=y\n# CONFIG_COMPILER_CXX_EXCEPTIONS is not set\n",
+                CXX_EXCEPTIONS
+            ),
+            "CONFIG_A=y\nCONFIG_COMPILER_CXX_EXCEPTIONS=y\n"
+        );
+        assert_eq!(
+            enable_sdkconfig("", CXX_EXCEPTIONS),
+            "CONFIG_COMPILER_CXX_EXCEPTIONS=y\n"
+        );
+    }
+
+    #[test]
+    fn test_set_value() {
+        assert_eq!(
+            set_value(
+                "[package]\nname = \"app\"\n\n[lib]\ncrate_type = [\"rlib\"]\n",
+                "lib",
+                "crate-type",
+                "[\"rlib\", \"staticlib\"]"
+            ),
+            "[package]\nname = \"app\"\n\n[lib]\ncrate_type = [\"rlib\", \"staticlib\"]\n"
+        );
+        assert_eq!(
+            set_value(
+                "[env:debug]\nbuild_type = debug\n\n[env:release]\nbuild_type = release",
+                "env:debug",
+                "rust_target",
+                "xtensa-esp32-espidf"
+            ),
+            "[env:debug]\nbuild_type = debug\nrust_target = xtensa-esp32-espidf\n\n[env:release]\nbuild_type = release"
+        );
+        assert_eq!(
+            set_value(
+                "[package]\nname = \"app\"",
+                "profile.dev",
+                "panic",
+                "\"abort\""
+            ),
+            "[package]\nname = \"app\"\n\n[profile.dev]\npanic = \"abort\"\n"
+        );
+    }
+}
@@ -0,0 +1,321 @@
+//! Lockfiles of the PlatformIO packages of a project, for checking that every machine
+//! builds with the same toolchain.
+//!
+//! The lockfile (`cargo-pio.lock` by default) records the platform, the packages and the
+//! libraries of every environment of the project (the nodes of its [`Graph`]) with their
+//! version and the SHA-256 digest of their installed manifest. It is written by `cargo
+//! pio pkg lock` and committed, and `cargo pio pkg check` reports the [`Drift`] of the
+//! installed packages from it, e.g. in a pre-commit hook or a CI job.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::graph::{Graph, Kind};
+use super::hash::{hex, sha256};
+use super::Pio;
+use crate::pio_model::environments;
+
+/// The default name of the lockfile, in the project directory.
+pub const LOCK_FILE_NAME: &str = "cargo-pio.lock";
+
+/// The packages of the environments of a project.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Lockfile {
+    /// The packages of every environment, keyed by the environment and the id of their
+    /// node in the [`Graph`] (`<kind>:<name>`).
+    pub environments: BTreeMap<String, BTreeMap<String, Locked>>,
+}
+
+/// A locked package.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Locked {
+    pub version: Option<String>,
+    /// The SHA-256 digest of the installed manifest, `None` if it has none.
+    pub digest: Option<String>,
+}
+
+impl Lockfile {
+    /// Collect the installed packages of all environments of the project in
+    /// `project_dir`.
+    pub fn collect(pio: &Pio, project_dir: impl AsRef<Path>) -> Result<Self> {
+        let project_dir = project_dir.as_ref();
+
+        let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))
+            .context("Failed to read platformio.ini")?;
+
+        let mut lockfile = Self::default();
+
+        for environment in environments(&platformio_ini) {
+            let graph = Graph::collect(pio, project_dir, &environment)?;
+
+            let packages = graph
+                .nodes
+                .into_iter()
+                .filter(|(_, node)| node.kind != Kind::Environment)
+                .map(|(id, node)| {
+                    let digest = node
+                        .manifest
+                        .and_then(|manifest| fs::read(manifest).ok())
+                        .map(|manifest| hex(&sha256(&manifest)));
+
+                    (
+                        id,
+                        Locked {
+                            version: node.version,
+                            digest,
+                        },
+                    )
+                })
+                .collect();
+
+            lockfile.environments.insert(environment, packages);
+        }
+
+        Ok(lockfile)
+    }
+
+    /// Load the lockfile `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        toml::from_str(
+            &fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        )
+        .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Save this lockfile as `path`.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let content = format!(
+            "# Generated by `cargo pio pkg lock`, checked by `cargo pio pkg check`\n\n{}",
+            toml::to_string(self)?
+        );
+
+        crate::fs::write_atomic(path, content)?;
+
+        Ok(())
+    }
+
+    /// The drift of the `installed` packages from this lockfile.
+    pub fn drift(&self, installed: &Self) -> Vec<Drift> {
+        let mut drift = Vec::new();
+
+        for (environment, locked) in &self.environments {
+            let installed = match installed.environments.get(environment) {
+                Some(installed) => installed,
+                None => {
+                    drift.push(Drift::MissingEnvironment(environment.clone()));
+                    continue;
+                }
+            };
+
+            for (id, locked) in locked {
+                match installed.get(id) {
+                    None => drift.push(Drift::Missing {
+                        environment: environment.clone(),
+                        id: id.clone(),
+                        locked: locked.clone(),
+                    }),
+                    Some(package) if package.version != locked.version => {
+                        drift.push(Drift::Version {
+                            environment: environment.clone(),
+                            id: id.clone(),
+                            locked: locked.version.clone(),
+                            installed: package.version.clone(),
+                        })
+                    }
+                    Some(package) if package.digest != locked.digest => drift.push(Drift::Digest {
+                        environment: environment.clone(),
+                        id: id.clone(),
+                        version: package.version.clone(),
+                    }),
+                    Some(_) => (),
+                }
+            }
+
+            for (id, package) in installed {
+                if !locked.contains_key(id) {
+                    drift.push(Drift::Extra {
+                        environment: environment.clone(),
+                        id: id.clone(),
+                        installed: package.clone(),
+                    });
+                }
+            }
+        }
+
+        for environment in installed.environments.keys() {
+            if !self.environments.contains_key(environment) {
+                drift.push(Drift::ExtraEnvironment(environment.clone()));
+            }
+        }
+
+        drift
+    }
+}
+
+/// A difference between the installed packages and a lockfile.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Drift {
+    /// An environment of the lockfile is no longer in `platformio.ini`.
+    MissingEnvironment(String),
+    /// An environment of `platformio.ini` is not in the lockfile.
+    ExtraEnvironment(String),
+    /// A locked package is not installed.
+    Missing {
+        environment: String,
+        id: String,
+        locked: Locked,
+    },
+    /// An installed package is not locked.
+    Extra {
+        environment: String,
+        id: String,
+        installed: Locked,
+    },
+    /// A package is installed in another version than the locked one.
+    Version {
+        environment: String,
+        id: String,
+        locked: Option<String>,
+        installed: Option<String>,
+    },
+    /// A package is installed in the locked version, but with a different manifest.
+    Digest {
+        environment: String,
+        id: String,
+        version: Option<String>,
+    },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = |version: &Option<String>| version.as_deref().unwrap_or("unknown").to_owned();
+
+        match self {
+            Self::MissingEnvironment(environment) => {
+                write!(f, "{}: environment not in platformio.ini", environment)
+            }
+            Self::ExtraEnvironment(environment) => {
+                write!(f, "{}: environment not locked", environment)
+            }
+            Self::Missing {
+                environment,
+                id,
+                locked,
+            } => write!(
+                f,
+                "{}: missing {}@{}",
+                environment,
+                id,
+                version(&locked.version)
+            ),
+            Self::Extra {
+                environment,
+                id,
+                installed,
+            } => write!(
+                f,
+                "{}: extra {}@{}",
+                environment,
+                id,
+                version(&installed.version)
+            ),
+            Self::Version {
+                environment,
+                id,
+                locked,
+                installed,
+            } => write!(
+                f,
+                "{}: {} is {}, locked {}",
+                environment,
+                id,
+                version(installed),
+                version(locked)
+            ),
+            Self::Digest {
+                environment,
+                id,
+                version: v,
+            } => write!(
+                f,
+                "{}: {}@{} differs from the locked package",
+                environment,
+                id,
+                version(v)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked(version: &str, digest: &str) -> Locked {
+        Locked {
+            version: Some(version.into()),
+            digest: Some(digest.into()),
+        }
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut lockfile = Lockfile::default();
+        lockfile.environments.insert(
+            "debug".into(),
+            [
+                ("platform:espressif32".to_owned(), locked("5.0.0", "aa")),
+                ("package:toolchain-xtensa".to_owned(), locked("8.4.0", "bb")),
+                (
+                    "package:tool-esptoolpy".to_owned(),
+                    locked("1.30300.0", "cc"),
+                ),
+                ("library:arduinojson".to_owned(), locked("6.19.4", "dd")),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        lockfile.environments.insert("old".into(), BTreeMap::new());
+
+        let parsed = toml::from_str::<Lockfile>(&toml::to_string(&lockfile).unwrap()).unwrap();
+        assert_eq!(parsed, lockfile);
+
+        let mut installed = lockfile.clone();
+        installed.environments.remove("old");
+        installed
+            .environments
+            .insert("release".into(), BTreeMap::new());
+
+        let debug = installed.environments.get_mut("debug").unwrap();
+        debug.remove("library:arduinojson");
+        debug.insert("package:toolchain-xtensa".into(), locked("8.4.0", "ff"));
+        debug.insert("package:tool-esptoolpy".into(), locked("1.40000.0", "cc"));
+        debug.insert("package:tool-openocd".into(), locked("2.1100.0", "ee"));
+
+        assert_eq!(
+            lockfile
+                .drift(&installed)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "debug: missing library:arduinojson@6.19.4",
+                "debug: package:tool-esptoolpy is 1.40000.0, locked 1.30300.0",
+                "debug: package:toolchain-xtensa@8.4.0 differs from the locked package",
+                "debug: extra package:tool-openocd@2.1100.0",
+                "old: environment not in platformio.ini",
+                "release: environment not locked",
+            ]
+        );
+    }
+}
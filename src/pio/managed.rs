@@ -0,0 +1,639 @@
+//! Files generated by cargo-pio which the user may edit afterwards.
+//!
+//! The generated content of such a file is enclosed in begin/end markers and a pristine
+//! copy of what was last generated is stored in [`PRISTINE_DIR`]. When the file is
+//! regenerated, a three-way merge between the pristine copy, the file on disk and the
+//! newly generated content is performed: user edits which do not overlap with generated
+//! changes are preserved, while overlapping ones are marked with conflict markers instead
+//! of being overwritten or dropped. A file without a pristine copy (e.g. written before
+//! cargo-pio managed it) is backed up to [`ManagedFile::backup_path`] before it is
+//! replaced.
+//!
+//! Every change written to such a file is logged as a unified diff, and [`ManagedFile::diff`]
+//! computes it without writing, for `--check` modes failing on out-of-date files.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::*;
+
+/// Directory (relative to the project directory) where the pristine copies are stored.
+pub const PRISTINE_DIR: &str = ".cargo-pio/pristine";
+
+/// The extension appended to the backups of files without a pristine copy.
+const BACKUP_EXTENSION: &str = "orig";
+
+const BEGIN_MARKER: &str = "cargo-pio: begin managed block (do not edit)";
+const END_MARKER: &str = "cargo-pio: end managed block";
+
+const CONFLICT_LOCAL: &str = "<<<<<<< local";
+const CONFLICT_SEPARATOR: &str = "=======";
+const CONFLICT_GENERATED: &str = ">>>>>>> generated";
+
+/// The lines of context around the changes of a diff.
+const DIFF_CONTEXT: usize = 3;
+
+/// The result of writing a [`ManagedFile`].
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Outcome {
+    /// The file did not exist and was created.
+    Created,
+    /// The file was updated, all changes merged cleanly.
+    Updated,
+    /// The file already had the merged content.
+    Unchanged,
+    /// The file was updated, but contains conflict markers which need to be resolved
+    /// by the user.
+    Conflicted,
+    /// The file had no pristine copy to tell apart the edits of the user, so it was
+    /// backed up and replaced.
+    BackedUp,
+}
+
+/// A file inside a project which is (partially) managed by cargo-pio.
+#[derive(Clone, Debug)]
+pub struct ManagedFile {
+    project_dir: PathBuf,
+    path: PathBuf,
+    comment: String,
+}
+
+impl ManagedFile {
+    /// Create a managed file at `path` (relative to `project_dir`) whose line comments
+    /// start with `comment`.
+    pub fn new(
+        project_dir: impl AsRef<Path>,
+        path: impl AsRef<Path>,
+        comment: impl Into<String>,
+    ) -> Self {
+        Self {
+            project_dir: project_dir.as_ref().to_owned(),
+            path: path.as_ref().to_owned(),
+            comment: comment.into(),
+        }
+    }
+
+    /// Create a managed file using the line comment syntax derived from its extension.
+    pub fn for_path(project_dir: impl AsRef<Path>, path: impl AsRef<Path>) -> Self {
+        let comment = match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("ini") => ";",
+            Some("rs" | "c" | "h" | "x" | "ld" | "json") => "//",
+            _ => "#",
+        };
+
+        Self::new(project_dir, path, comment)
+    }
+
+    /// The absolute path of the file.
+    pub fn path(&self) -> PathBuf {
+        self.project_dir.join(&self.path)
+    }
+
+    /// The absolute path of the pristine copy of the file.
+    pub fn pristine_path(&self) -> PathBuf {
+        self.project_dir.join(PRISTINE_DIR).join(&self.path)
+    }
+
+    /// The absolute path of the backup of the file, written if it has no pristine copy.
+    pub fn backup_path(&self) -> PathBuf {
+        let mut path = self.path().into_os_string();
+        path.push(".");
+        path.push(BACKUP_EXTENSION);
+        path.into()
+    }
+
+    /// Enclose `content` in the begin/end markers of a managed block.
+    pub fn block(&self, content: impl AsRef<str>) -> String {
+        let content = content.as_ref();
+
+        format!(
+            "{comment} {}\n{}{}{comment} {}\n",
+            BEGIN_MARKER,
+            content,
+            if content.is_empty() || content.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            },
+            END_MARKER,
+            comment = self.comment,
+        )
+    }
+
+    /// Replace the first managed block of `text` with a block of `content`, or insert
+    /// it before the first line matching `before` (at the end, if no line matches), for
+    /// files of which cargo-pio only manages a single block. An empty `content` removes
+    /// the block.
+    pub fn upsert_block(
+        &self,
+        text: &str,
+        content: impl AsRef<str>,
+        before: impl Fn(&str) -> bool,
+    ) -> String {
+        let content = content.as_ref();
+        let lines = text.lines().collect::<Vec<_>>();
+        let managed = managed_lines(&lines, &self.comment);
+
+        let block = if content.is_empty() {
+            String::new()
+        } else {
+            self.block(content)
+        };
+
+        let mut result = String::new();
+        let mut placed = false;
+
+        for (index, line) in lines.iter().enumerate() {
+            if managed[index] {
+                if !placed {
+                    result.push_str(&block);
+                    placed = true;
+                }
+
+                continue;
+            }
+
+            if !placed && before(line) {
+                result.push_str(&block);
+                placed = true;
+            }
+
+            result.push_str(line);
+            result.push('\n');
+        }
+
+        if !placed {
+            result.push_str(&block);
+        }
+
+        result
+    }
+
+    /// Compute the content the file would have after writing `generated`, without
+    /// writing anything.
+    pub fn merged(&self, generated: impl AsRef<str>) -> Result<(String, Outcome)> {
+        let generated = generated.as_ref();
+        let path = self.path();
+
+        if !path.exists() {
+            return Ok((generated.to_owned(), Outcome::Created));
+        }
+
+        let current = fs::read_to_string(&path)?;
+
+        let pristine_path = self.pristine_path();
+        if !pristine_path.exists() {
+            // Without a pristine copy we cannot tell apart user edits
+            let outcome = if current == generated {
+                Outcome::Unchanged
+            } else {
+                Outcome::BackedUp
+            };
+
+            return Ok((generated.to_owned(), outcome));
+        }
+
+        let pristine = fs::read_to_string(&pristine_path)?;
+        let (merged, conflicts) = merge(&pristine, &current, generated);
+
+        let outcome = if conflicts {
+            Outcome::Conflicted
+        } else if merged == current {
+            Outcome::Unchanged
+        } else {
+            Outcome::Updated
+        };
+
+        Ok((merged, outcome))
+    }
+
+    /// The unified diff of the change writing `generated` would make to the file, `None`
+    /// if it is up-to-date.
+    pub fn diff(&self, generated: impl AsRef<str>) -> Result<Option<String>> {
+        let (merged, outcome) = self.merged(generated)?;
+
+        if outcome == Outcome::Unchanged {
+            return Ok(None);
+        }
+
+        let current = fs::read_to_string(self.path()).unwrap_or_default();
+
+        Ok(Some(unified_diff(&current, &merged, &self.path)))
+    }
+
+    /// Write the `generated` content to the file, merging it with the edits the user has
+    /// made since it was last generated.
+    pub fn write(&self, generated: impl AsRef<str>) -> Result<Outcome> {
+        let generated = generated.as_ref();
+        let path = self.path();
+        let (merged, outcome) = self.merged(generated)?;
+
+        let diff = || {
+            unified_diff(
+                &fs::read_to_string(&path).unwrap_or_default(),
+                &merged,
+                &self.path,
+            )
+        };
+
+        match outcome {
+            Outcome::Unchanged => debug!("File {} is up-to-date", path.display()),
+            Outcome::Conflicted => warn!(
+                "File {} has conflicting edits in its managed blocks, please resolve them:\n{}",
+                path.display(),
+                diff()
+            ),
+            Outcome::Created => debug!("Creating {}", path.display()),
+            Outcome::Updated => info!("Updating {}:\n{}", path.display(), diff()),
+            Outcome::BackedUp => {
+                let backup_path = self.backup_path();
+
+                warn!(
+                    "File {} was not generated by cargo-pio, replacing it and backing it up to {}:\n{}",
+                    path.display(),
+                    backup_path.display(),
+                    diff()
+                );
+
+                fs::copy(&path, &backup_path)
+                    .with_context(|| format!("Failed to back up {}", path.display()))?;
+            }
+        }
+
+        if outcome != Outcome::Unchanged {
+            fs::create_dir_all(path.parent().unwrap())?;
+            crate::fs::write_atomic(&path, merged)?;
+        }
+
+        let pristine_path = self.pristine_path();
+
+        fs::create_dir_all(pristine_path.parent().unwrap())?;
+        crate::fs::write_atomic(pristine_path, generated)?;
+
+        Ok(outcome)
+    }
+}
+
+/// Three-way merge `local` and `generated`, which both derive from `base`.
+///
+/// Non-overlapping changes of both sides are combined, overlapping changes are marked as
+/// conflicts. Returns the merged text and whether it contains conflicts.
+pub fn merge(base: &str, local: &str, generated: &str) -> (String, bool) {
+    let base = base.lines().collect::<Vec<_>>();
+    let local = local.lines().collect::<Vec<_>>();
+    let generated = generated.lines().collect::<Vec<_>>();
+
+    let local_matches = lcs_matches(&base, &local);
+    let generated_matches = lcs_matches(&base, &generated);
+
+    let mut out = Vec::new();
+    let mut conflicts = false;
+
+    let (mut i, mut l, mut g) = (0, 0, 0);
+
+    loop {
+        // Lines unchanged on both sides
+        while i < base.len() && local_matches[i] == Some(l) && generated_matches[i] == Some(g) {
+            out.push(base[i]);
+            i += 1;
+            l += 1;
+            g += 1;
+        }
+
+        if i == base.len() && l == local.len() && g == generated.len() {
+            break;
+        }
+
+        // The next base line which is retained by both sides ends the changed chunk
+        let (ni, nl, ng) = (i..base.len())
+            .find_map(|j| match (local_matches[j], generated_matches[j]) {
+                (Some(nl), Some(ng)) => Some((j, nl, ng)),
+                _ => None,
+            })
+            .unwrap_or((base.len(), local.len(), generated.len()));
+
+        let base_chunk = &base[i..ni];
+        let local_chunk = &local[l..nl];
+        let generated_chunk = &generated[g..ng];
+
+        if local_chunk == base_chunk || local_chunk == generated_chunk {
+            out.extend_from_slice(generated_chunk);
+        } else if generated_chunk == base_chunk {
+            out.extend_from_slice(local_chunk);
+        } else {
+            conflicts = true;
+
+            out.push(CONFLICT_LOCAL);
+            out.extend_from_slice(local_chunk);
+            out.push(CONFLICT_SEPARATOR);
+            out.extend_from_slice(generated_chunk);
+            out.push(CONFLICT_GENERATED);
+        }
+
+        i = ni;
+        l = nl;
+        g = ng;
+    }
+
+    let mut merged = out.join("\n");
+    if !merged.is_empty() {
+        merged.push('\n');
+    }
+
+    (merged, conflicts)
+}
+
+/// A line of a diff as its index in the old and in the new text, `None` for added and
+/// removed lines.
+type DiffLine = (Option<usize>, Option<usize>);
+
+/// The unified diff (with `a/` and `b/` prefixed paths, like git) of the change from `old`
+/// to `new` of the file `path`, empty if they are equal.
+pub fn unified_diff(old: &str, new: &str, path: impl AsRef<Path>) -> String {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    let matches = lcs_matches(&old, &new);
+
+    let mut lines: Vec<DiffLine> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < old.len() || j < new.len() {
+        match matches.get(i).copied().flatten() {
+            Some(m) if m == j => {
+                lines.push((Some(i), Some(j)));
+                i += 1;
+                j += 1;
+            }
+            Some(m) if m > j => {
+                lines.push((None, Some(j)));
+                j += 1;
+            }
+            _ if i < old.len() => {
+                lines.push((Some(i), None));
+                i += 1;
+            }
+            _ => {
+                lines.push((None, Some(j)));
+                j += 1;
+            }
+        }
+    }
+
+    let changed = lines
+        .iter()
+        .map(|(o, n)| o.is_none() || n.is_none())
+        .collect::<Vec<_>>();
+
+    let mut diff = String::new();
+    let mut start = 0;
+
+    while let Some(first) = (start..lines.len()).find(|k| changed[*k]) {
+        // Extend the hunk while the next change is within twice the context
+        let mut last = first;
+        while let Some(next) = (last + 1..lines.len()).find(|k| changed[*k]) {
+            if next - last > 2 * DIFF_CONTEXT {
+                break;
+            }
+            last = next;
+        }
+
+        let from = first.saturating_sub(DIFF_CONTEXT);
+        let to = usize::min(last + DIFF_CONTEXT + 1, lines.len());
+        let hunk = &lines[from..to];
+
+        let count = |side: fn(&DiffLine) -> Option<usize>| {
+            let indices = hunk.iter().filter_map(side).collect::<Vec<_>>();
+            // The line before the hunk for empty sides, like diff
+            let start = indices.first().map_or_else(
+                || {
+                    lines[..from]
+                        .iter()
+                        .rev()
+                        .find_map(side)
+                        .map_or(0, |k| k + 1)
+                },
+                |k| k + 1,
+            );
+
+            (start, indices.len())
+        };
+        let (old_start, old_len) = count(|(o, _)| *o);
+        let (new_start, new_len) = count(|(_, n)| *n);
+
+        if diff.is_empty() {
+            let path = path.as_ref().display();
+            diff.push_str(&format!("--- a/{}\n+++ b/{}\n", path, path));
+        }
+
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start, old_len, new_start, new_len
+        ));
+
+        for (o, n) in hunk {
+            match (o, n) {
+                (Some(o), Some(_)) => diff.push_str(&format!(" {}\n", old[*o])),
+                (Some(o), None) => diff.push_str(&format!("-{}\n", old[*o])),
+                (None, Some(n)) => diff.push_str(&format!("+{}\n", new[*n])),
+                (None, None) => unreachable!(),
+            }
+        }
+
+        start = to;
+    }
+
+    diff
+}
+
+/// For every line in `lines`, whether it is part of a managed block (markers included).
+fn managed_lines(lines: &[&str], comment: &str) -> Vec<bool> {
+    let mut inside = false;
+
+    lines
+        .iter()
+        .map(|line| {
+            let marker = line.trim().strip_prefix(comment).map(str::trim);
+
+            if marker == Some(BEGIN_MARKER) {
+                inside = true;
+                true
+            } else if marker == Some(END_MARKER) {
+                inside = false;
+                true
+            } else {
+                inside
+            }
+        })
+        .collect()
+}
+
+/// Compute a longest common subsequence of `a` and `b` and return for every line of `a`
+/// the index of the matching line in `b` (if it is part of the subsequence).
+fn lcs_matches(a: &[&str], b: &[&str]) -> Vec<Option<usize>> {
+    let mut lengths = vec![vec![0_usize; b.len() + 1]; a.len() + 1];
+
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut matches = vec![None; a.len()];
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            matches[i] = Some(j);
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "\
+[platformio]
+; cargo-pio: begin managed block (do not edit)
+board = esp32dev
+platform = espressif32
+; cargo-pio: end managed block
+
+[env:debug]
+build_type = debug
+";
+
+    #[test]
+    fn test_upsert_block() {
+        let file = ManagedFile::new(".", "CMakeLists.txt", "#");
+        let text = "include(project.cmake)\nproject(app)\n";
+
+        let inserted = file.upsert_block(text, "set(COMPONENTS src)", |line| {
+            line.starts_with("project(")
+        });
+        assert_eq!(
+            inserted,
+            format!(
+                "include(project.cmake)\n{}project(app)\n",
+                file.block("set(COMPONENTS src)")
+            )
+        );
+
+        let replaced = file.upsert_block(&inserted, "set(COMPONENTS main)", |_| false);
+        assert_eq!(replaced, inserted.replace("src)", "main)"));
+
+        assert_eq!(file.upsert_block(&replaced, "", |_| false), text);
+    }
+
+    #[test]
+    fn test_merge_preserves_local_edits_outside_blocks() {
+        let local = BASE.replace(
+            "build_type = debug\n",
+            "build_type = debug\nmonitor_speed = 9600\n",
+        );
+        let generated = BASE.replace("esp32dev", "esp32-c3-devkitm-1");
+
+        let (merged, conflicts) = merge(BASE, &local, &generated);
+
+        assert!(!conflicts);
+        assert_eq!(
+            merged,
+            generated.replace(
+                "build_type = debug\n",
+                "build_type = debug\nmonitor_speed = 9600\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_marks_conflicts_inside_blocks() {
+        let local = BASE.replace("esp32dev", "my-board");
+        let generated = BASE.replace("esp32dev", "esp32-c3-devkitm-1");
+
+        let (merged, conflicts) = merge(BASE, &local, &generated);
+
+        assert!(conflicts);
+        assert!(merged.contains("<<<<<<< local\nboard = my-board\n=======\nboard = esp32-c3-devkitm-1\n>>>>>>> generated\n"));
+    }
+
+    #[test]
+    fn test_merge_marks_conflicts_outside_blocks() {
+        let local = BASE.replace("build_type = debug", "build_type = release");
+        let generated = BASE.replace("build_type = debug", "build_type = test");
+
+        let (merged, conflicts) = merge(BASE, &local, &generated);
+
+        assert!(conflicts);
+        assert!(merged.contains(
+            "<<<<<<< local\nbuild_type = release\n=======\nbuild_type = test\n>>>>>>> generated\n"
+        ));
+    }
+
+    #[test]
+    fn test_write_backs_up_unmanaged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = ManagedFile::for_path(dir.path(), "platformio.ini");
+        fs::write(file.path(), "[env:debug]\n").unwrap();
+
+        assert_eq!(file.write(BASE).unwrap(), Outcome::BackedUp);
+        assert_eq!(fs::read_to_string(file.path()).unwrap(), BASE);
+        assert_eq!(
+            fs::read_to_string(file.backup_path()).unwrap(),
+            "[env:debug]\n"
+        );
+
+        let local = BASE.replace("[env:debug]\n", "[env:debug]\nmonitor_speed = 9600\n");
+        fs::write(file.path(), &local).unwrap();
+
+        let generated = BASE.replace("esp32dev", "esp32-c3-devkitm-1");
+        assert_eq!(file.write(&generated).unwrap(), Outcome::Updated);
+        assert_eq!(
+            fs::read_to_string(file.path()).unwrap(),
+            local.replace("esp32dev", "esp32-c3-devkitm-1")
+        );
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let generated = BASE.replace("esp32dev", "esp32-c3-devkitm-1");
+
+        assert_eq!(unified_diff(BASE, BASE, "platformio.ini"), "");
+        assert_eq!(
+            unified_diff(BASE, &generated, "platformio.ini"),
+            "--- a/platformio.ini\n\
+             +++ b/platformio.ini\n\
+             @@ -1,6 +1,6 @@\n \
+             [platformio]\n \
+             ; cargo-pio: begin managed block (do not edit)\n\
+             -board = esp32dev\n\
+             +board = esp32-c3-devkitm-1\n \
+             platform = espressif32\n \
+             ; cargo-pio: end managed block\n \
+             \n"
+        );
+        assert_eq!(
+            unified_diff("", "a\nb\n", "new.txt"),
+            "--- a/new.txt\n+++ b/new.txt\n@@ -0,0 +1,2 @@\n+a\n+b\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_unchanged_local() {
+        let generated = BASE.replace("debug", "release");
+
+        assert_eq!(merge(BASE, BASE, &generated), (generated, false));
+    }
+}
@@ -0,0 +1,149 @@
+//! Feature matrix builds, for comparing the cost of the optional functionality of a
+//! crate.
+//!
+//! `cargo pio matrix` builds every PlatformIO environment with every feature set (in
+//! addition to the features configured for the environment in `cargo-pio.toml`) and
+//! collects an [`Entry`] per build, printed as a [`table`] or as JSON.
+
+use std::fmt::Write;
+
+use serde::Serialize;
+
+/// Parse the feature sets `"a,b;c"` into `[["a", "b"], ["c"]]`.
+///
+/// An empty set (e.g. the first one of `";a"`) builds the environment with its
+/// configured features only, as a baseline.
+pub fn parse_feature_sets(sets: &str) -> Vec<Vec<String>> {
+    sets.split(';')
+        .map(|set| {
+            set.split(',')
+                .map(str::trim)
+                .filter(|feature| !feature.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .collect()
+}
+
+/// The result of building an environment with a feature set.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub environment: String,
+    pub features: Vec<String>,
+    pub success: bool,
+    /// The duration of the build in seconds.
+    pub build_time: f64,
+    /// The flash used by the firmware in bytes, `None` if the build failed.
+    pub flash: Option<u64>,
+    /// The statically allocated RAM of the firmware in bytes, `None` if the build failed.
+    pub ram: Option<u64>,
+}
+
+/// The entries as a table, with the size differences to the first build of each
+/// environment.
+pub fn table(entries: &[Entry]) -> String {
+    let features = |entry: &Entry| {
+        if entry.features.is_empty() {
+            "-".to_owned()
+        } else {
+            entry.features.join(",")
+        }
+    };
+
+    let width = entries
+        .iter()
+        .map(|entry| features(entry).len())
+        .chain(Some("FEATURES".len()))
+        .max()
+        .unwrap_or_default();
+    let env_width = entries
+        .iter()
+        .map(|entry| entry.environment.len())
+        .chain(Some("ENVIRONMENT".len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!(
+        "{:<env_width$}  {:<width$}  {:>9}  {:>10}  {:>9}  {:>10}  {:>8}\n",
+        "ENVIRONMENT",
+        "FEATURES",
+        "FLASH",
+        "DELTA",
+        "RAM",
+        "DELTA",
+        "TIME",
+        env_width = env_width,
+        width = width,
+    );
+
+    let size = |size: Option<u64>| {
+        size.map(|size| size.to_string())
+            .unwrap_or_else(|| "failed".into())
+    };
+    let delta = |size: Option<u64>, base: Option<u64>| match (size, base) {
+        (Some(size), Some(base)) => format!("{:+}", size as i64 - base as i64),
+        _ => "-".into(),
+    };
+
+    for (index, entry) in entries.iter().enumerate() {
+        let base = entries[..index]
+            .iter()
+            .find(|base| base.environment == entry.environment)
+            .unwrap_or(entry);
+
+        writeln!(
+            table,
+            "{:<env_width$}  {:<width$}  {:>9}  {:>10}  {:>9}  {:>10}  {:>7.1}s",
+            entry.environment,
+            features(entry),
+            size(entry.flash),
+            delta(entry.flash, base.flash),
+            size(entry.ram),
+            delta(entry.ram, base.ram),
+            entry.build_time,
+            env_width = env_width,
+            width = width,
+        )
+        .unwrap();
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            parse_feature_sets(";wifi, ble;wifi"),
+            [
+                vec![],
+                vec!["wifi".to_owned(), "ble".to_owned()],
+                vec!["wifi".to_owned()]
+            ]
+        );
+
+        let entry = |features: &[&str], flash: Option<u64>, ram| Entry {
+            environment: "release".into(),
+            features: features.iter().map(|f| f.to_string()).collect(),
+            success: flash.is_some(),
+            build_time: 12.34,
+            flash,
+            ram,
+        };
+
+        assert_eq!(
+            table(&[
+                entry(&[], Some(200_000), Some(30_000)),
+                entry(&["wifi", "ble"], Some(650_000), Some(52_000)),
+                entry(&["wifi"], None, None),
+            ]),
+            "ENVIRONMENT  FEATURES      FLASH       DELTA        RAM       DELTA      TIME\n\
+             release      -            200000          +0      30000          +0     12.3s\n\
+             release      wifi,ble     650000     +450000      52000      +22000     12.3s\n\
+             release      wifi         failed           -     failed           -     12.3s\n"
+        );
+    }
+}
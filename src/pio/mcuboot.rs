@@ -0,0 +1,186 @@
+//! MCUboot images of the firmware of PIO->Cargo projects, for dual-bank OTA setups
+//! (e.g. Zephyr or nRF Connect SDK based ones).
+//!
+//! The image (header, hash & signature TLVs, and optionally the padding and trailer of
+//! the slot) is produced by MCUboot's `imgtool`, which is installed into PlatformIO's
+//! Python environment on first use. The settings are configured per environment in
+//! `cargo-pio.toml` (see [`McubootConfig`]).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+
+use super::config::McubootConfig;
+use super::Pio;
+use crate::python::PYTHON;
+
+/// The file name of the MCUboot image, in the build directory of the environment.
+pub const IMAGE_FILE: &str = "firmware.signed.bin";
+
+/// Produce the MCUboot image of the built firmware of the PlatformIO `environment` of
+/// the project in `project_dir`.
+///
+/// Returns the path of the image.
+pub fn image(
+    pio: &Pio,
+    config: &McubootConfig,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+) -> Result<PathBuf> {
+    let project_dir = project_dir.as_ref();
+    let build_dir = project_dir.join(".pio").join("build").join(environment);
+
+    let firmware = build_dir.join("firmware.bin");
+    if !firmware.is_file() {
+        bail!(
+            "Firmware {} does not exist, did you build your project first?",
+            firmware.display()
+        );
+    }
+
+    let version = match &config.version {
+        Some(version) => version.clone(),
+        None => image_version(&cargo_version(project_dir)?),
+    };
+
+    let output = build_dir.join(IMAGE_FILE);
+
+    let mut cmd = imgtool_cmd(pio)?;
+    cmd.arg("sign")
+        .arg("--header-size")
+        .arg(format!("0x{:x}", config.header_size))
+        .arg("--slot-size")
+        .arg(format!("0x{:x}", config.slot_size))
+        .arg("--align")
+        .arg(config.align.to_string())
+        .arg("--version")
+        .arg(&version);
+
+    if let Some(key) = &config.key {
+        cmd.arg("--key").arg(project_dir.join(key));
+    } else {
+        warn!("No MCUboot signing key configured, the image is not signed");
+    }
+
+    if config.pad {
+        cmd.arg("--pad");
+    }
+
+    if config.confirm {
+        cmd.arg("--confirm");
+    }
+
+    if config.pad_header {
+        cmd.arg("--pad-header");
+    }
+
+    cmd.arg(&firmware).arg(&output);
+
+    debug!("Running imgtool: {:?}", cmd);
+
+    let result = cmd.output()?;
+    if !result.status.success() {
+        // imgtool reports e.g. images exceeding the slot on stderr
+        bail!(
+            "imgtool failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+
+    info!(
+        "MCUboot image {} (version {}, {} bytes)",
+        output.display(),
+        version,
+        fs::metadata(&output)?.len()
+    );
+
+    Ok(output)
+}
+
+/// A command running `imgtool` with the Python of PlatformIO's environment, installing
+/// it first if needed.
+fn imgtool_cmd(pio: &Pio) -> Result<Command> {
+    let python = pio
+        .platformio_exe
+        .parent()
+        .map(|dir| {
+            dir.join(if cfg!(windows) {
+                "python.exe"
+            } else {
+                "python"
+            })
+        })
+        .filter(|python| python.is_file())
+        .unwrap_or_else(|| PYTHON.into());
+
+    let installed = Command::new(&python)
+        .args(["-m", "imgtool", "version"])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+
+    if !installed {
+        info!("Installing imgtool into {}", python.display());
+
+        let status = Command::new(&python)
+            .args(["-m", "pip", "install", "imgtool"])
+            .status()?;
+
+        if !status.success() {
+            bail!("Installing imgtool failed with {}", status);
+        }
+    }
+
+    let mut cmd = Command::new(python);
+    cmd.args(["-m", "imgtool"]);
+
+    Ok(cmd)
+}
+
+/// The version of the Cargo package of the project in `project_dir`.
+fn cargo_version(project_dir: &Path) -> Result<String> {
+    let manifest = project_dir.join("Cargo.toml");
+
+    let manifest = fs::read_to_string(&manifest)
+        .with_context(|| anyhow!("Failed to read {}", manifest.display()))?
+        .parse::<toml::Value>()?;
+
+    manifest
+        .get("package")
+        .and_then(|package| package.get("version"))
+        .and_then(toml::Value::as_str)
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow!("No package version in Cargo.toml, set the MCUboot 'version'"))
+}
+
+/// Convert the semantic version `version` to an MCUboot image version: the pre-release
+/// is dropped, numeric build metadata is kept as build number.
+fn image_version(version: &str) -> String {
+    let (version, build) = match version.split_once('+') {
+        Some((version, build)) => (version, build.parse::<u32>().ok()),
+        None => (version, None),
+    };
+
+    let version = version.split('-').next().unwrap_or_default();
+
+    match build {
+        Some(build) => format!("{}+{}", version, build),
+        None => version.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_version() {
+        assert_eq!(image_version("1.2.3"), "1.2.3");
+        assert_eq!(image_version("1.2.3-rc.1"), "1.2.3");
+        assert_eq!(image_version("1.2.3+42"), "1.2.3+42");
+        assert_eq!(image_version("1.2.3-rc.1+abc"), "1.2.3");
+    }
+}
@@ -0,0 +1,308 @@
+//! Build metrics of PIO->Cargo projects, recorded over time.
+//!
+//! Every build appends a [`Record`] with its duration, the flash and RAM used by the
+//! firmware and the hits of the compiler cache to [`METRICS_FILE`], one JSON object per
+//! line, unless disabled in the `[metrics]` section of `cargo-pio.toml`. [`trend`]
+//! renders the recent builds of an environment, flagging the metrics which grew beyond
+//! the thresholds of the section since the previous build.
+
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use super::config::MetricsConfig;
+
+/// The file (relative to the project directory) the metrics are recorded in.
+pub const METRICS_FILE: &str = "target/cargo-pio/metrics.jsonl";
+
+/// The metrics of a build.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Record {
+    /// The start of the build in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub environment: String,
+    /// The Cargo features configured for the environment.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// The output of `git describe`, if the project is a git repository.
+    #[serde(default)]
+    pub git_describe: Option<String>,
+    pub success: bool,
+    /// The duration of the build in seconds.
+    pub duration: f64,
+    /// The flash used by the firmware in bytes, `None` if the build failed.
+    #[serde(default)]
+    pub flash: Option<u64>,
+    /// The statically allocated RAM of the firmware in bytes, `None` if the build failed.
+    #[serde(default)]
+    pub ram: Option<u64>,
+    /// The hits and misses of the compiler cache, if one is configured.
+    #[serde(default)]
+    pub cache_hits: Option<u64>,
+    #[serde(default)]
+    pub cache_misses: Option<u64>,
+}
+
+impl Record {
+    /// The label of the build: its `git describe`, or its timestamp.
+    fn label(&self) -> String {
+        self.git_describe
+            .clone()
+            .unwrap_or_else(|| format!("@{}", self.timestamp))
+    }
+}
+
+/// A metric which grew beyond its threshold between two builds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Regression {
+    /// `flash`, `ram` or `time`.
+    pub metric: &'static str,
+    pub from: f64,
+    pub to: f64,
+}
+
+impl Regression {
+    /// The growth in percent.
+    pub fn percent(&self) -> f64 {
+        (self.to - self.from) / self.from * 100.0
+    }
+}
+
+/// The metrics file of the project in `project_dir`.
+pub fn file(project_dir: impl AsRef<Path>) -> PathBuf {
+    project_dir.as_ref().join(METRICS_FILE)
+}
+
+/// Append `record` to the metrics of the project in `project_dir`.
+pub fn append(project_dir: impl AsRef<Path>, record: &Record) -> Result<()> {
+    let file = file(project_dir);
+
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&file)
+        .and_then(|mut f| f.write_all(line.as_bytes()))
+        .with_context(|| format!("Failed to record the metrics in {}", file.display()))
+}
+
+/// Load the recorded metrics of the project in `project_dir`, oldest first.
+///
+/// Lines which cannot be parsed (e.g. of an interrupted write) are skipped.
+pub fn load(project_dir: impl AsRef<Path>) -> Result<Vec<Record>> {
+    let file = file(project_dir);
+
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+
+    let content =
+        fs::read_to_string(&file).with_context(|| format!("Failed to read {}", file.display()))?;
+
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(record) => Some(record),
+            Err(err) => {
+                warn!("Skipping a metrics record of {}: {}", file.display(), err);
+                None
+            }
+        })
+        .collect())
+}
+
+/// The metrics of `record` which grew beyond the thresholds of `config` since
+/// `previous`.
+pub fn regressions(previous: &Record, record: &Record, config: &MetricsConfig) -> Vec<Regression> {
+    if !previous.success || !record.success {
+        return Vec::new();
+    }
+
+    let size = |size: Option<u64>| size.map(|size| size as f64);
+
+    [
+        (
+            "flash",
+            size(previous.flash),
+            size(record.flash),
+            config.flash_threshold,
+        ),
+        (
+            "ram",
+            size(previous.ram),
+            size(record.ram),
+            config.ram_threshold,
+        ),
+        (
+            "time",
+            Some(previous.duration),
+            Some(record.duration),
+            config.time_threshold,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(metric, from, to, threshold)| {
+        let regression = Regression {
+            metric,
+            from: from?,
+            to: to?,
+        };
+
+        (regression.from > 0.0 && regression.percent() > threshold).then(|| regression)
+    })
+    .collect()
+}
+
+/// The recent builds of `environment` in `records`, which have the features of its
+/// latest build.
+pub fn recent<'a>(records: &'a [Record], environment: &str, limit: usize) -> Vec<&'a Record> {
+    let latest = match records.iter().rev().find(|r| r.environment == environment) {
+        Some(latest) => latest,
+        None => return Vec::new(),
+    };
+
+    let mut recent = records
+        .iter()
+        .rev()
+        .filter(|r| r.environment == environment && r.features == latest.features)
+        .take(limit)
+        .collect::<Vec<_>>();
+    recent.reverse();
+
+    recent
+}
+
+/// The builds `records` (oldest first) as a table, with the differences to the
+/// previous successful build and its regressions.
+pub fn trend(records: &[&Record], config: &MetricsConfig) -> String {
+    let width = records
+        .iter()
+        .map(|record| record.label().len())
+        .chain(Some("BUILD".len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!(
+        "{:<width$}  {:>9}  {:>10}  {:>9}  {:>10}  {:>8}  {:>6}  REGRESSIONS\n",
+        "BUILD",
+        "FLASH",
+        "DELTA",
+        "RAM",
+        "DELTA",
+        "TIME",
+        "CACHE",
+        width = width,
+    );
+
+    let size = |size: Option<u64>| {
+        size.map(|size| size.to_string())
+            .unwrap_or_else(|| "failed".into())
+    };
+    let delta = |size: Option<u64>, base: Option<u64>| match (size, base) {
+        (Some(size), Some(base)) => format!("{:+}", size as i64 - base as i64),
+        _ => "-".into(),
+    };
+
+    let mut previous: Option<&Record> = None;
+
+    for record in records {
+        let base = previous.unwrap_or(record);
+
+        let cache = match (record.cache_hits, record.cache_misses) {
+            (Some(hits), Some(misses)) if hits + misses > 0 => {
+                format!("{:.0}%", hits as f64 / (hits + misses) as f64 * 100.0)
+            }
+            _ => "-".into(),
+        };
+
+        let regressions = regressions(base, record, config)
+            .iter()
+            .map(|regression| format!("{} +{:.1}%", regression.metric, regression.percent()))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let row = format!(
+            "{:<width$}  {:>9}  {:>10}  {:>9}  {:>10}  {:>7.1}s  {:>6}  {}",
+            record.label(),
+            size(record.flash),
+            delta(record.flash, base.flash),
+            size(record.ram),
+            delta(record.ram, base.ram),
+            record.duration,
+            cache,
+            regressions,
+            width = width,
+        );
+
+        writeln!(table, "{}", row.trim_end()).unwrap();
+
+        if record.success {
+            previous = Some(record);
+        }
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // Not the `log` one glob-imported by the parent module, which older rustc picks
+    use super::Record;
+
+    #[test]
+    fn test_trend() {
+        let record = |git: &str, flash: Option<u64>, duration: f64| Record {
+            timestamp: 1_660_000_000,
+            environment: "release".into(),
+            git_describe: Some(git.into()),
+            success: flash.is_some(),
+            duration,
+            flash,
+            ram: flash.map(|_| 30_000),
+            cache_hits: Some(90),
+            cache_misses: Some(10),
+            ..Default::default()
+        };
+
+        let records = [
+            Record {
+                environment: "debug".into(),
+                ..record("v1.0.0", Some(400_000), 60.0)
+            },
+            record("v1.0.0", Some(200_000), 40.0),
+            record("v1.0.0-1-gabc", None, 5.0),
+            record("v1.0.0-2-gdef", Some(203_000), 60.0),
+            Record {
+                features: vec!["wifi".into()],
+                ..record("v1.0.0-3-g123", Some(600_000), 60.0)
+            },
+        ];
+
+        assert_eq!(recent(&records, "release", 10).len(), 1);
+
+        let recent = recent(&records[..4], "release", 10);
+        assert_eq!(recent.len(), 3);
+
+        assert_eq!(
+            trend(&recent, &MetricsConfig::default()),
+            "BUILD              FLASH       DELTA        RAM       DELTA      TIME   CACHE  REGRESSIONS\n\
+             v1.0.0            200000          +0      30000          +0     40.0s     90%\n\
+             v1.0.0-1-gabc     failed           -     failed           -      5.0s     90%\n\
+             v1.0.0-2-gdef     203000       +3000      30000          +0     60.0s     90%  flash +1.5%, time +50.0%\n"
+        );
+    }
+}
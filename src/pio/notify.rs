@@ -0,0 +1,216 @@
+//! Notifications about builds and package changes, configured in the `[notify]` section
+//! of `cargo-pio.toml` (see [`NotifyConfig`]).
+//!
+//! Every notification is a JSON object (see [`Notification`]) passed to the configured
+//! commands on stdin and POSTed to the configured URLs, e.g. for a team to track which
+//! toolchains the machines of its developers build with. Notifications are best effort:
+//! failing to deliver one is warned about, but never fails the command.
+
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs};
+
+use log::*;
+use serde::Serialize;
+
+use super::config::{self, NotifyConfig};
+
+/// An event notified about.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// A build of an environment completed, successfully or not.
+    Build,
+    /// The toolchain of an environment changed since its last successful build.
+    ToolchainChange,
+    /// Packages (or PlatformIO itself) were installed.
+    PkgInstall,
+    /// Packages were updated or uninstalled.
+    PkgUpdate,
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Build => "build",
+            Self::ToolchainChange => "toolchain-change",
+            Self::PkgInstall => "pkg-install",
+            Self::PkgUpdate => "pkg-update",
+        }
+    }
+
+    /// The event of the PlatformIO command with the arguments `args`, if it changes the
+    /// installed packages (`pkg install`, `platform update`, ...).
+    pub fn of_pio_args(args: &[impl AsRef<OsStr>]) -> Option<Self> {
+        let mut args = args.iter().map(|arg| arg.as_ref().to_str());
+
+        match (args.next()??, args.next()??) {
+            ("pkg" | "platform", "install") => Some(Self::PkgInstall),
+            ("pkg" | "platform", "update" | "uninstall") => Some(Self::PkgUpdate),
+            _ => None,
+        }
+    }
+}
+
+/// The JSON payload of a notification.
+#[derive(Serialize, Clone, Debug)]
+pub struct Notification {
+    /// The name of the [`Event`].
+    pub event: &'static str,
+    pub success: bool,
+    /// The project directory.
+    pub project: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    /// The host name of the machine.
+    pub host: String,
+    /// The user name.
+    pub user: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The toolchain components of the environment, as `<name>@<version>`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub toolchain: Vec<String>,
+    /// The toolchain components of the last successful build, for `toolchain-change`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub previous_toolchain: Vec<String>,
+    /// The arguments of the PlatformIO command, for package events.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+}
+
+impl Notification {
+    /// A notification about `event` in the project in `project_dir`, with the other
+    /// details left empty.
+    pub fn new(event: Event, success: bool, project_dir: impl AsRef<Path>) -> Self {
+        Self {
+            event: event.name(),
+            success,
+            project: project_dir.as_ref().display().to_string(),
+            environment: None,
+            host: host_name(),
+            user: env::var("USER")
+                .or_else(|_| env::var("USERNAME"))
+                .unwrap_or_default(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default(),
+            toolchain: Vec::new(),
+            previous_toolchain: Vec::new(),
+            args: Vec::new(),
+        }
+    }
+}
+
+/// Deliver `notification` as configured in `config`, if it subscribes to its event.
+pub fn send(config: &NotifyConfig, notification: &Notification) {
+    if !config.events.is_empty() && !config.events.iter().any(|e| e == notification.event) {
+        return;
+    }
+
+    if config.commands.is_empty() && config.urls.is_empty() {
+        return;
+    }
+
+    let payload = match serde_json::to_string(notification) {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!(
+                "Failed to serialize the {} notification: {}",
+                notification.event, err
+            );
+            return;
+        }
+    };
+
+    for command in &config.commands {
+        debug!("Running notification command: {}", command);
+
+        if let Err(err) = run_command(command, notification.event, &payload) {
+            warn!("Notification command '{}' failed: {:#}", command, err);
+        }
+    }
+
+    for url in &config.urls {
+        debug!("Posting the {} notification to {}", notification.event, url);
+
+        if let Err(err) = post(url, &payload) {
+            warn!("Posting the notification to {} failed: {:#}", url, err);
+        }
+    }
+}
+
+fn run_command(command: &str, event: &str, payload: &str) -> anyhow::Result<()> {
+    let mut child = config::shell(command)
+        .env("CARGO_PIO_EVENT", event)
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    // The command may not read its stdin at all
+    let _ = child.stdin.take().unwrap().write_all(payload.as_bytes());
+
+    let status = child.wait()?;
+    if !status.success() {
+        anyhow::bail!("exited with {}", status);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "ureq")]
+fn post(url: &str, payload: &str) -> anyhow::Result<()> {
+    ureq::post(url)
+        .set("Content-Type", "application/json")
+        .send_string(payload)?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "ureq"))]
+fn post(_url: &str, _payload: &str) -> anyhow::Result<()> {
+    anyhow::bail!("posting needs the `ureq` feature of embuild")
+}
+
+fn host_name() -> String {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| {
+            fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|name| name.trim().to_owned())
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_of_pio_args() {
+        assert_eq!(
+            Event::of_pio_args(&["pkg", "install", "-d", "."]),
+            Some(Event::PkgInstall)
+        );
+        assert_eq!(
+            Event::of_pio_args(&["platform", "update"]),
+            Some(Event::PkgUpdate)
+        );
+        assert_eq!(Event::of_pio_args(&["pkg", "list"]), None);
+        assert_eq!(Event::of_pio_args(&["run"]), None);
+
+        let notification = Notification {
+            environment: Some("debug".into()),
+            ..Notification::new(Event::Build, true, "/project")
+        };
+        let json = serde_json::to_value(&notification).unwrap();
+
+        assert_eq!(json["event"], "build");
+        assert_eq!(json["environment"], "debug");
+        assert!(json.get("args").is_none());
+    }
+}
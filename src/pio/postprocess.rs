@@ -0,0 +1,426 @@
+//! The post-processing of the built firmware of PIO->Cargo projects.
+//!
+//! The steps configured in `[[post-process]]` of `cargo-pio.toml` (see
+//! [`PostProcessConfig`]) run in order after every successful build of the environments
+//! they apply to, instead of `extra_scripts` for the common transformations: converting
+//! the ELF file with `objcopy`, stripping it, compressing an image and writing its
+//! checksum, or running a command.
+//!
+//! `objcopy` and `strip` are the ones of the toolchain of the environment, as reported
+//! by `pio project metadata`. Commands run with the same tools in the `PATH`, and with
+//! the environment of the hooks. The outputs of the steps are released with the firmware
+//! (see [`super::release`]), so they end up in the release manifest.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use serde_json::Value as JsonValue;
+
+use super::config::{self, Config, PostProcessConfig, PostProcessStep};
+use super::hash::{self, Algorithm};
+use super::Pio;
+use crate::error::HintExt;
+
+/// The steps of `config` applying to `environment`, in order.
+pub fn steps<'a>(
+    config: &'a Config,
+    environment: &'a str,
+) -> impl Iterator<Item = &'a PostProcessConfig> {
+    config.post_process.iter().filter(move |step| {
+        step.environments.is_empty() || step.environments.iter().any(|env| env == environment)
+    })
+}
+
+/// The input of `step`, relative to the build directory.
+pub fn input(step: &PostProcessConfig) -> PathBuf {
+    match (&step.input, step.step) {
+        (Some(input), _) => input.clone(),
+        (None, PostProcessStep::Objcopy | PostProcessStep::Strip) => "firmware.elf".into(),
+        (None, _) => "firmware.bin".into(),
+    }
+}
+
+/// The output of `step` (`None` for a command without one), relative to the build
+/// directory.
+pub fn output(step: &PostProcessConfig) -> Result<Option<PathBuf>> {
+    if let Some(output) = &step.output {
+        return Ok(Some(output.clone()));
+    }
+
+    let input = input(step);
+    let with_suffix = |suffix: &str| {
+        let mut name = input.file_name().unwrap_or_default().to_owned();
+        name.push(suffix);
+        input.with_file_name(name)
+    };
+
+    Ok(match step.step {
+        PostProcessStep::Objcopy => Some(input.with_extension("bin")),
+        PostProcessStep::Strip => Some(match input.extension() {
+            Some(extension) => {
+                input.with_extension(format!("stripped.{}", extension.to_string_lossy()))
+            }
+            None => input.with_extension("stripped"),
+        }),
+        PostProcessStep::Compress => Some(with_suffix(".gz")),
+        PostProcessStep::Checksum => Some(with_suffix(&format!(".{}", algorithm(step)?))),
+        PostProcessStep::Command => None,
+    })
+}
+
+/// The outputs of the steps of `config` applying to `environment`, relative to the
+/// build directory.
+pub fn outputs(config: &Config, environment: &str) -> Result<Vec<PathBuf>> {
+    let mut outputs = Vec::new();
+
+    for step in steps(config, environment) {
+        if let Some(output) = output(step)? {
+            if !outputs.contains(&output) {
+                outputs.push(output);
+            }
+        }
+    }
+
+    Ok(outputs)
+}
+
+/// Run the steps of `config` applying to the PlatformIO `environment` of the project in
+/// `project_dir`, on its built firmware.
+pub fn run(
+    pio: &Pio,
+    config: &Config,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+) -> Result<()> {
+    let project_dir = project_dir.as_ref();
+    let build_dir = project_dir.join(".pio").join("build").join(environment);
+
+    // The toolchain is only looked up for the first step needing it
+    let mut compiler = None;
+
+    for step in steps(config, environment) {
+        let input = build_dir.join(input(step));
+        let output = output(step)?.map(|output| build_dir.join(output));
+
+        if !input.exists() {
+            return Err(anyhow!(
+                "The input {} of the {} post-processing step does not exist",
+                input.display(),
+                step.step.name()
+            ))
+            .hint(format!("Build environment {} first", environment));
+        }
+
+        if let Some(parent) = output.as_deref().and_then(Path::parent) {
+            fs::create_dir_all(parent)?;
+        }
+
+        info!("Post-processing {}: {}", input.display(), step.step.name());
+
+        match step.step {
+            PostProcessStep::Objcopy | PostProcessStep::Strip => {
+                if compiler.is_none() {
+                    compiler = Some(compiler_path(pio, project_dir, environment)?);
+                }
+
+                let default_args: &[&str] = match step.step {
+                    PostProcessStep::Objcopy => &["-O", "binary"],
+                    _ => &["--strip-debug"],
+                };
+
+                let mut cmd =
+                    Command::new(tool_path(compiler.as_ref().unwrap(), step.step.name())?);
+                if step.args.is_empty() {
+                    cmd.args(default_args);
+                } else {
+                    cmd.args(&step.args);
+                }
+
+                match step.step {
+                    PostProcessStep::Objcopy => cmd.arg(&input).arg(output.as_ref().unwrap()),
+                    _ => cmd.arg("-o").arg(output.as_ref().unwrap()).arg(&input),
+                };
+
+                exec(&mut cmd, step)?;
+            }
+            PostProcessStep::Compress => compress(&input, output.as_ref().unwrap())?,
+            PostProcessStep::Checksum => {
+                checksum(algorithm(step)?, &input, output.as_ref().unwrap())?
+            }
+            PostProcessStep::Command => {
+                let command = step
+                    .command
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("The command post-processing step has no command"))?;
+
+                let mut cmd = config::shell(command);
+                cmd.current_dir(project_dir)
+                    .env("PLATFORMIO_CORE_DIR", &pio.core_dir)
+                    .env("CARGO_PIO_ENVIRONMENT", environment)
+                    .env("CARGO_PIO_PROJECT_DIR", project_dir)
+                    .env("CARGO_PIO_BUILD_DIR", &build_dir)
+                    .env("CARGO_PIO_INPUT", &input);
+
+                if let Some(output) = &output {
+                    cmd.env("CARGO_PIO_OUTPUT", output);
+                }
+
+                // The toolchain is optional for commands, which may not need it
+                if compiler.is_none() {
+                    match compiler_path(pio, project_dir, environment) {
+                        Ok(path) => compiler = Some(path),
+                        Err(err) => debug!("No toolchain for the command: {:#}", err),
+                    }
+                }
+
+                let dirs = compiler
+                    .as_deref()
+                    .and_then(Path::parent)
+                    .into_iter()
+                    .chain(pio.platformio_exe.parent())
+                    .map(Path::to_owned);
+                let paths = std::env::var_os("PATH").unwrap_or_default();
+
+                cmd.env(
+                    "PATH",
+                    std::env::join_paths(dirs.chain(std::env::split_paths(&paths)))?,
+                );
+
+                config.apply_env(environment, &mut cmd);
+
+                exec(&mut cmd, step)?;
+            }
+        }
+
+        if let Some(output) = &output {
+            if !output.exists() {
+                bail!(
+                    "The {} post-processing step did not produce {}",
+                    step.step.name(),
+                    output.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn algorithm(step: &PostProcessConfig) -> Result<Algorithm> {
+    step.algorithm.as_deref().unwrap_or("sha256").parse()
+}
+
+fn exec(cmd: &mut Command, step: &PostProcessConfig) -> Result<()> {
+    debug!("Running post-processing command: {:?}", cmd);
+
+    let status = cmd.status().with_context(|| {
+        format!(
+            "Failed to run the {} post-processing step",
+            step.step.name()
+        )
+    })?;
+
+    if !status.success() {
+        bail!(
+            "The {} post-processing step failed with {}",
+            step.step.name(),
+            status
+        );
+    }
+
+    Ok(())
+}
+
+/// Compress `input` into `output` with gzip.
+#[cfg(feature = "pio-archive")]
+fn compress(input: &Path, output: &Path) -> Result<()> {
+    let mut encoder =
+        flate2::write::GzEncoder::new(fs::File::create(output)?, flate2::Compression::best());
+
+    std::io::copy(&mut fs::File::open(input)?, &mut encoder)
+        .with_context(|| format!("Failed to compress {}", input.display()))?;
+    encoder.finish()?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "pio-archive"))]
+fn compress(_input: &Path, _output: &Path) -> Result<()> {
+    bail!("The compress step needs the `pio-archive` feature of embuild")
+}
+
+/// Write the checksum of `input` to `output`, in the format of `sha256sum`.
+fn checksum(algorithm: Algorithm, input: &Path, output: &Path) -> Result<()> {
+    let digest = hash::file(algorithm, input, &mut |_, _| ())?;
+
+    fs::write(
+        output,
+        format!(
+            "{}  {}\n",
+            hash::hex(&digest),
+            input.file_name().unwrap_or_default().to_string_lossy()
+        ),
+    )?;
+
+    Ok(())
+}
+
+/// The metadata of the PlatformIO `environment` of the project in `project_dir` as of
+/// `pio project metadata`, with its toolchain and flags.
+pub(crate) fn project_metadata(
+    pio: &Pio,
+    project_dir: &Path,
+    environment: &str,
+) -> Result<JsonValue> {
+    let mut cmd = pio.cmd();
+    cmd.arg("project")
+        .arg("metadata")
+        .arg("-d")
+        .arg(project_dir)
+        .arg("-e")
+        .arg(environment);
+
+    let mut metadata = Pio::json::<JsonValue>(&mut cmd)
+        .context("Failed to get the toolchain of the environment")?;
+
+    Ok(metadata[environment].take())
+}
+
+/// The C compiler of the toolchain of the PlatformIO `environment` of the project in
+/// `project_dir`.
+fn compiler_path(pio: &Pio, project_dir: &Path, environment: &str) -> Result<PathBuf> {
+    compiler(
+        &project_metadata(pio, project_dir, environment)?,
+        environment,
+    )
+}
+
+/// The C compiler of the `metadata` of the PlatformIO `environment`.
+pub(crate) fn compiler(metadata: &JsonValue, environment: &str) -> Result<PathBuf> {
+    metadata["cc_path"]
+        .as_str()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("Environment {} has no C compiler", environment))
+}
+
+/// The `tool` (e.g. `objcopy`) of the toolchain of the C compiler `compiler`, e.g.
+/// `xtensa-esp32-elf-objcopy` for `xtensa-esp32-elf-gcc`.
+pub(crate) fn tool_path(compiler: &Path, tool: &str) -> Result<PathBuf> {
+    let name = compiler.file_name().unwrap_or_default().to_string_lossy();
+    let (stem, extension) = match name.strip_suffix(".exe") {
+        Some(stem) => (stem, ".exe"),
+        None => (&*name, ""),
+    };
+
+    let prefix = stem.strip_suffix("gcc").ok_or_else(|| {
+        anyhow!(
+            "The C compiler {} is not GCC, no {} to use",
+            compiler.display(),
+            tool
+        )
+    })?;
+
+    Ok(compiler.with_file_name(format!("{}{}{}", prefix, tool, extension)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_postprocess() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [[post-process]]
+            step = "objcopy"
+            args = ["-O", "ihex"]
+            output = "firmware.hex"
+
+            [[post-process]]
+            step = "strip"
+
+            [[post-process]]
+            step = "compress"
+            environments = ["release"]
+
+            [[post-process]]
+            step = "checksum"
+            input = "firmware.bin.gz"
+            algorithm = "sha512"
+
+            [[post-process]]
+            step = "command"
+            command = "true"
+            "#,
+        )
+        .unwrap();
+
+        let outputs = |environment| {
+            outputs(&config, environment)
+                .unwrap()
+                .into_iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            outputs("release"),
+            [
+                "firmware.hex",
+                "firmware.stripped.elf",
+                "firmware.bin.gz",
+                "firmware.bin.gz.sha512"
+            ]
+        );
+        assert_eq!(
+            outputs("debug"),
+            [
+                "firmware.hex",
+                "firmware.stripped.elf",
+                "firmware.bin.gz.sha512"
+            ]
+        );
+
+        assert_eq!(
+            tool_path(Path::new("/toolchain/bin/xtensa-esp32-elf-gcc"), "objcopy").unwrap(),
+            Path::new("/toolchain/bin/xtensa-esp32-elf-objcopy")
+        );
+        assert!(tool_path(Path::new("/usr/bin/clang"), "strip").is_err());
+
+        let dir = std::env::temp_dir().join(format!("embuild-postprocess-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("firmware.bin"), "firmware").unwrap();
+
+        checksum(
+            Algorithm::Sha256,
+            &dir.join("firmware.bin"),
+            &dir.join("firmware.bin.sha256"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("firmware.bin.sha256")).unwrap(),
+            format!("{}  firmware.bin\n", hash::hex(&hash::sha256(b"firmware")))
+        );
+
+        #[cfg(feature = "pio-archive")]
+        {
+            compress(&dir.join("firmware.bin"), &dir.join("firmware.bin.gz")).unwrap();
+
+            let mut decoded = String::new();
+            std::io::Read::read_to_string(
+                &mut flate2::read::GzDecoder::new(
+                    fs::File::open(dir.join("firmware.bin.gz")).unwrap(),
+                ),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(decoded, "firmware");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+//! Debug servers of the debug probes of boards: OpenOCD and the J-Link GDB server, run
+//! from the packages of PlatformIO.
+//!
+//! The configuration of a server is derived from the `debug` section of the board
+//! definition (see [`BoardDebug`]) and the debug tool of the environment, i.e. the probe
+//! (`debug_tool` in `platformio.ini`, else the default tool of the board):
+//! - OpenOCD gets the interface script of the probe and the board or target script of
+//!   the board, from `tool-openocd-esp32` for the Espressif platforms and `tool-openocd`
+//!   otherwise;
+//! - the J-Link GDB server (of `tool-jlink`) gets the J-Link device name of the board.
+//!
+//! The server package is installed if it is not yet.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Result};
+use log::*;
+use serde_json::Value as JsonValue;
+
+use super::{board, Pio};
+use crate::error::HintExt;
+use crate::pio_model::env_option;
+
+/// The default port of the GDB server.
+pub const GDB_PORT: u16 = 3333;
+
+/// The directory (relative to the OpenOCD packages) of the OpenOCD scripts.
+const OPENOCD_SCRIPTS: &str = "share/openocd/scripts";
+
+/// The OpenOCD interface scripts (relative to `interface/`) of the PlatformIO debug
+/// tools.
+const OPENOCD_INTERFACES: &[(&str, &str)] = &[
+    ("cmsis-dap", "cmsis-dap"),
+    ("esp-builtin", "esp_usb_jtag"),
+    ("esp-prog", "ftdi/esp32_devkitj_v1"),
+    ("esp-usb-bridge", "esp_usb_bridge"),
+    ("jlink", "jlink"),
+    ("minimodule", "ftdi/minimodule"),
+    ("olimex-arm-usb-ocd", "ftdi/olimex-arm-usb-ocd"),
+    ("olimex-arm-usb-ocd-h", "ftdi/olimex-arm-usb-ocd-h"),
+    ("olimex-arm-usb-tiny-h", "ftdi/olimex-arm-usb-tiny-h"),
+    ("olimex-jtag-tiny", "ftdi/olimex-jtag-tiny"),
+    ("raspberrypi-swd", "raspberrypi-swd"),
+    ("stlink", "stlink"),
+    ("tumpa", "ftdi/tumpa"),
+];
+
+/// The debug metadata of a board, the `debug` section of its board definition.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BoardDebug {
+    /// The OpenOCD board script (relative to `board/`), e.g. `esp-wroom-32.cfg`.
+    pub openocd_board: Option<String>,
+    /// The OpenOCD target script (relative to `target/`), e.g. `stm32f4x`.
+    pub openocd_target: Option<String>,
+    /// The J-Link device name, e.g. `STM32F401RE`.
+    pub jlink_device: Option<String>,
+    /// The debug tools used by default, e.g. `stlink`.
+    pub default_tools: Vec<String>,
+    /// The debug tools on the board.
+    pub onboard_tools: Vec<String>,
+}
+
+impl BoardDebug {
+    pub fn from_definition(definition: &JsonValue) -> Self {
+        let debug = &definition["debug"];
+
+        let string = |key: &str| debug[key].as_str().map(str::to_owned);
+        let strings = |key: &str| {
+            debug[key]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|tool| tool.as_str().map(str::to_owned))
+                .collect()
+        };
+
+        Self {
+            openocd_board: string("openocd_board"),
+            openocd_target: string("openocd_target"),
+            jlink_device: string("jlink_device"),
+            default_tools: strings("default_tools"),
+            onboard_tools: strings("onboard_tools"),
+        }
+    }
+
+    /// The default debug tool: the first default tool, else the first onboard tool.
+    pub fn default_tool(&self) -> Option<&str> {
+        self.default_tools
+            .iter()
+            .chain(&self.onboard_tools)
+            .next()
+            .map(String::as_str)
+    }
+}
+
+/// A debug server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Server {
+    OpenOcd {
+        /// The package, `tool-openocd` or `tool-openocd-esp32`.
+        package: &'static str,
+        /// The interface script, e.g. `interface/stlink.cfg`.
+        interface: String,
+        /// The board or target script, e.g. `target/stm32f4x.cfg`.
+        target: String,
+        gdb_port: u16,
+    },
+    JLink {
+        device: String,
+        /// `SWD` or `JTAG`.
+        interface: &'static str,
+        gdb_port: u16,
+    },
+}
+
+impl Server {
+    /// The server of the debug `tool` of a board with the debug metadata `debug`, of
+    /// the PlatformIO `platform`, e.g. `espressif32`.
+    pub fn new(debug: &BoardDebug, tool: &str, platform: &str, gdb_port: u16) -> Result<Self> {
+        let espressif = platform.contains("espressif");
+
+        if tool == "jlink" {
+            if let Some(device) = &debug.jlink_device {
+                return Ok(Self::JLink {
+                    device: device.clone(),
+                    interface: if espressif { "JTAG" } else { "SWD" },
+                    gdb_port,
+                });
+            }
+        }
+
+        let interface = OPENOCD_INTERFACES
+            .iter()
+            .find(|(name, _)| *name == tool)
+            .map(|(_, interface)| format!("interface/{}.cfg", interface))
+            .ok_or_else(|| anyhow!("Debug tool {} is not supported", tool))
+            .with_hint(|| {
+                format!(
+                    "Use one of {}",
+                    OPENOCD_INTERFACES
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+
+        let script = |dir: &str, script: &str| {
+            format!(
+                "{}/{}{}",
+                dir,
+                script,
+                if script.ends_with(".cfg") { "" } else { ".cfg" }
+            )
+        };
+
+        let target = match (&debug.openocd_target, &debug.openocd_board) {
+            (Some(target), _) => script("target", target),
+            (None, Some(board)) => script("board", board),
+            (None, None) => bail!("The board has no OpenOCD target or board script"),
+        };
+
+        Ok(Self::OpenOcd {
+            package: if espressif {
+                "tool-openocd-esp32"
+            } else {
+                "tool-openocd"
+            },
+            interface,
+            target,
+            gdb_port,
+        })
+    }
+
+    /// The server of the PlatformIO `environment` of the project in `project_dir`, with
+    /// the debug tool `tool` or else the one of the environment or its board.
+    pub fn of_environment(
+        pio: &Pio,
+        project_dir: impl AsRef<Path>,
+        environment: &str,
+        tool: Option<&str>,
+        gdb_port: u16,
+    ) -> Result<Self> {
+        let project_dir = project_dir.as_ref();
+        let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))?;
+        let option = |key| env_option(&platformio_ini, environment, key);
+
+        let (id, definition) = board::definition(pio, project_dir, environment)?
+            .ok_or_else(|| anyhow!("Environment {} has no board", environment))?;
+        let debug = BoardDebug::from_definition(&definition);
+
+        let tool = match tool.map(str::to_owned).or_else(|| option("debug_tool")) {
+            Some(tool) => tool,
+            None => debug
+                .default_tool()
+                .map(str::to_owned)
+                .ok_or_else(|| anyhow!("Board {} has no debug tool", id))
+                .hint("Set the debug tool with 'debug_tool' in platformio.ini")?,
+        };
+
+        Self::new(
+            &debug,
+            &tool,
+            &option("platform").unwrap_or_default(),
+            gdb_port,
+        )
+    }
+
+    /// The name of the executable of the server.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::OpenOcd { .. } => "openocd",
+            Self::JLink { .. } => "JLinkGDBServerCL",
+        }
+    }
+
+    pub fn package(&self) -> &'static str {
+        match self {
+            Self::OpenOcd { package, .. } => package,
+            Self::JLink { .. } => "tool-jlink",
+        }
+    }
+
+    /// The OpenOCD configuration of the server, `None` for J-Link.
+    pub fn openocd_cfg(&self) -> Option<String> {
+        match self {
+            Self::OpenOcd {
+                interface,
+                target,
+                gdb_port,
+                ..
+            } => Some(format!(
+                "# Generated by cargo-pio, do not edit\n\n\
+                 source [find {}]\n\
+                 source [find {}]\n\
+                 gdb_port {}\n",
+                interface, target, gdb_port
+            )),
+            Self::JLink { .. } => None,
+        }
+    }
+
+    /// The arguments of the server.
+    pub fn args(&self) -> Vec<String> {
+        match self {
+            Self::OpenOcd {
+                interface,
+                target,
+                gdb_port,
+                ..
+            } => vec![
+                "-f".into(),
+                interface.clone(),
+                "-f".into(),
+                target.clone(),
+                "-c".into(),
+                format!("gdb_port {}", gdb_port),
+            ],
+            Self::JLink {
+                device,
+                interface,
+                gdb_port,
+            } => vec![
+                "-singlerun".into(),
+                "-nogui".into(),
+                "-select".into(),
+                "USB".into(),
+                "-if".into(),
+                (*interface).into(),
+                "-device".into(),
+                device.clone(),
+                "-port".into(),
+                gdb_port.to_string(),
+            ],
+        }
+    }
+
+    /// The command running the server, installing its package if it is not yet.
+    pub fn command(&self, pio: &Pio) -> Result<Command> {
+        let dir = install(pio, self.package())?;
+
+        let mut cmd = match self {
+            Self::OpenOcd { .. } => {
+                let mut cmd = Command::new(executable(&dir, &["bin/openocd", "openocd"])?);
+                cmd.arg("-s").arg(dir.join(OPENOCD_SCRIPTS));
+                cmd
+            }
+            Self::JLink { .. } => Command::new(executable(
+                &dir,
+                &["JLinkGDBServerCL", "JLinkGDBServerCLExe", "JLinkGDBServer"],
+            )?),
+        };
+
+        cmd.args(self.args());
+
+        Ok(cmd)
+    }
+}
+
+/// The directory of the installed package `name`, installed globally if it is not yet.
+fn install(pio: &Pio, name: &str) -> Result<PathBuf> {
+    if let Some(package) = pio.package(name, None) {
+        return Ok(package.dir);
+    }
+
+    info!("Installing package {}", name);
+
+    let mut cmd = pio.cmd();
+    cmd.args(["pkg", "install", "--global", "--tool"])
+        .arg(format!("platformio/{}", name));
+
+    pio.exec(&mut cmd)?;
+
+    pio.package(name, None)
+        .map(|package| package.dir)
+        .ok_or_else(|| anyhow!("Package {} was not installed", name))
+}
+
+/// The first of the executables `candidates` (relative to `dir`) which exists.
+fn executable(dir: &Path, candidates: &[&str]) -> Result<PathBuf> {
+    candidates
+        .iter()
+        .map(|candidate| dir.join(format!("{}{}", candidate, std::env::consts::EXE_SUFFIX)))
+        .find(|path| path.is_file())
+        .ok_or_else(|| anyhow!("No debug server found in {}", dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server() {
+        let nucleo = BoardDebug::from_definition(&serde_json::json!({
+            "debug": {
+                "jlink_device": "STM32F401RE",
+                "openocd_target": "stm32f4x",
+                "default_tools": ["stlink"],
+                "onboard_tools": ["stlink"]
+            }
+        }));
+        assert_eq!(nucleo.default_tool(), Some("stlink"));
+
+        let server = Server::new(&nucleo, "stlink", "ststm32", GDB_PORT).unwrap();
+        assert_eq!(server.package(), "tool-openocd");
+        assert_eq!(
+            server.openocd_cfg().unwrap(),
+            "# Generated by cargo-pio, do not edit\n\n\
+             source [find interface/stlink.cfg]\n\
+             source [find target/stm32f4x.cfg]\n\
+             gdb_port 3333\n"
+        );
+
+        let server = Server::new(&nucleo, "jlink", "ststm32", 2331).unwrap();
+        assert_eq!(server.package(), "tool-jlink");
+        assert_eq!(
+            server.args()[4..],
+            ["-if", "SWD", "-device", "STM32F401RE", "-port", "2331"]
+        );
+
+        let esp32 = BoardDebug::from_definition(&serde_json::json!({
+            "debug": {"openocd_board": "esp-wroom-32.cfg"}
+        }));
+        assert_eq!(esp32.default_tool(), None);
+
+        let server = Server::new(&esp32, "jlink", "espressif32", GDB_PORT).unwrap();
+        assert_eq!(
+            server.args()[..4],
+            ["-f", "interface/jlink.cfg", "-f", "board/esp-wroom-32.cfg"]
+        );
+        assert_eq!(server.package(), "tool-openocd-esp32");
+
+        assert!(Server::new(&esp32, "unknown", "espressif32", GDB_PORT).is_err());
+        assert!(Server::new(&BoardDebug::default(), "stlink", "ststm32", GDB_PORT).is_err());
+    }
+}
@@ -1,15 +1,18 @@
 use std::convert::TryFrom;
 use std::env;
-use std::fs::{self, OpenOptions};
-use std::io::Write;
+use std::fs;
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use log::*;
 use serde::{Deserialize, Serialize};
 
+use super::config::Config;
+use super::managed::ManagedFile;
+use super::runtime::{Memory, PanicHandler, Scaffold};
 use super::Resolution;
 use crate::cargo::CargoCmd;
+use crate::cmd;
 use crate::utils::OsStrExt;
 use crate::{build, cargo};
 
@@ -32,14 +35,13 @@ const VAR_BUILD_MCU: &str = "CARGO_PIO_BUILD_MCU";
 const VAR_BUILD_BINDGEN_EXTRA_CLANG_ARGS: &str = "CARGO_PIO_BUILD_BINDGEN_EXTRA_CLANG_ARGS";
 const VAR_BUILD_PIO_PLATFORM_DIR: &str = "CARGO_PIO_BUILD_PIO_PLATFORM_DIR";
 const VAR_BUILD_PIO_FRAMEWORK_DIR: &str = "CARGO_PIO_BUILD_PIO_FRAMEWORK_DIR";
+const VAR_BUILD_BUILD_DIR: &str = "CARGO_PIO_BUILD_BUILD_DIR";
 
 const PLATFORMIO_GIT_PY: &[u8] = include_bytes!("resources/platformio.git.py.resource");
 const PLATFORMIO_PATCH_PY: &[u8] = include_bytes!("resources/platformio.patch.py.resource");
 const PLATFORMIO_DUMP_PY: &[u8] = include_bytes!("resources/platformio.dump.py.resource");
 const PLATFORMIO_CARGO_PY: &[u8] = include_bytes!("resources/platformio.cargo.py.resource");
 
-const LIB_RS: &[u8] = include_bytes!("resources/lib.rs.resource");
-
 const MAIN_C: &[u8] = include_bytes!("resources/main.c.resource");
 const DUMMY_C: &[u8] = include_bytes!("resources/dummy.c.resource");
 
@@ -61,6 +63,10 @@ pub struct SconsVariables {
 
     pub pio_platform_dir: String,
     pub pio_framework_dir: String,
+
+    /// The build directory of the environment, not set by older integration scripts.
+    #[serde(default)]
+    pub build_dir: Option<PathBuf>,
 }
 
 impl SconsVariables {
@@ -83,6 +89,8 @@ impl SconsVariables {
 
                 pio_platform_dir: env::var(VAR_BUILD_PIO_PLATFORM_DIR).ok()?,
                 pio_framework_dir: env::var(VAR_BUILD_PIO_FRAMEWORK_DIR).ok()?,
+
+                build_dir: env::var_os(VAR_BUILD_BUILD_DIR).map(PathBuf::from),
             })
         } else {
             None
@@ -95,6 +103,18 @@ impl SconsVariables {
         )?)?)
     }
 
+    /// The C header of the kconfig configuration generated by ESP-IDF (`sdkconfig.h`),
+    /// if the framework is ESP-IDF and the header exists.
+    ///
+    /// It can be turned into Rust constants with [`crate::kconfig::try_from_header_file`]
+    /// and [`crate::kconfig::rust_source`].
+    pub fn sdkconfig_header(&self) -> Option<PathBuf> {
+        self.build_dir
+            .as_ref()
+            .map(|build_dir| build_dir.join("config").join("sdkconfig.h"))
+            .filter(|header| header.is_file())
+    }
+
     pub fn full_path(&self, executable: impl AsRef<str>) -> Result<PathBuf> {
         Ok(which::which_in(
             executable.as_ref(),
@@ -117,8 +137,34 @@ pub struct Builder {
     cargo_options: Vec<String>,
     scons_dump_enabled: bool,
     c_entry_points_enabled: bool,
+    panic_handler: Option<PanicHandler>,
+    memory: Option<Memory>,
+    git: Option<GitSetup>,
+}
+
+/// The git repository of a new project.
+#[derive(Clone, Debug, Default)]
+pub struct GitSetup {
+    /// Whether to commit the generated project.
+    pub commit: bool,
+    /// The patterns of the files tracked with Git LFS, e.g. `*.bin`.
+    pub lfs: Vec<String>,
 }
 
+/// The ESP-IDF options of new projects: the main task runs the Rust code, which needs a
+/// bigger stack than the default of ESP-IDF.
+const SDKCONFIG_DEFAULTS: &str = "CONFIG_ESP_MAIN_TASK_STACK_SIZE=7000\n";
+
+/// The entries of the `.gitignore` of a project: the build directories of Cargo and
+/// PlatformIO (which also hold the artifacts of cargo-pio) and the download caches.
+const GITIGNORE: &[&str] = &[
+    "/target",
+    ".pio/",
+    "CMakeFiles/",
+    ".cargo-pio/sdk/",
+    ".cargo-pio/components/",
+];
+
 impl Builder {
     pub fn new(project_dir: impl AsRef<Path>) -> Self {
         Self {
@@ -134,6 +180,9 @@ impl Builder {
             cargo_options: Vec::new(),
             scons_dump_enabled: false,
             c_entry_points_enabled: false,
+            panic_handler: None,
+            memory: None,
+            git: None,
         }
     }
 
@@ -244,6 +293,25 @@ impl Builder {
         self
     }
 
+    /// Select the panic handler of a new crate, instead of the default one of its runtime.
+    pub fn panic_handler(&mut self, panic_handler: PanicHandler) -> &mut Self {
+        self.panic_handler = Some(panic_handler);
+        self
+    }
+
+    /// Set the memory of the MCU for the `memory.x` of a new crate without a framework.
+    pub fn memory(&mut self, memory: Memory) -> &mut Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Initialize a git repository in the project directory after generating the
+    /// project, unless it is inside one already.
+    pub fn git(&mut self, git: GitSetup) -> &mut Self {
+        self.git = Some(git);
+        self
+    }
+
     pub fn generate(&self, resolution: &Resolution) -> Result<PathBuf> {
         let mut options = vec![
             ("board".into(), resolution.board.clone()),
@@ -256,26 +324,30 @@ impl Builder {
         options.extend(self.options.iter().cloned());
         self.create_platformio_ini(&options)?;
 
+        if let Some(git) = &self.git {
+            self.init_git(git)?;
+        }
+
         Ok(self.project_dir.clone())
     }
 
     pub fn update(&self) -> Result<PathBuf> {
         if self.cargo_cmd.is_some() {
-            self.create_file("platformio.cargo.py", PLATFORMIO_CARGO_PY)?;
+            self.create_script("platformio.cargo.py", PLATFORMIO_CARGO_PY)?;
         } else if self.c_entry_points_enabled {
             self.create_file(PathBuf::from("src").join("main.c"), MAIN_C)?;
         }
 
         if self.git_repos_enabled {
-            self.create_file("platformio.git.py", PLATFORMIO_GIT_PY)?;
+            self.create_script("platformio.git.py", PLATFORMIO_GIT_PY)?;
         }
 
         if self.platform_packages_patches_enabled {
-            self.create_file("platformio.patch.py", PLATFORMIO_PATCH_PY)?;
+            self.create_script("platformio.patch.py", PLATFORMIO_PATCH_PY)?;
         }
 
         if self.scons_dump_enabled {
-            self.create_file("platformio.dump.py", PLATFORMIO_DUMP_PY)?;
+            self.create_script("platformio.dump.py", PLATFORMIO_DUMP_PY)?;
         }
 
         Ok(self.project_dir.clone())
@@ -303,14 +375,14 @@ impl Builder {
                     let rust_lib = cargo_crate.set_library_type(["staticlib"])?;
                     cargo_crate.create_config_toml(Some(resolution.target.clone()), build_std)?;
 
-                    self.create_file(PathBuf::from("src").join("lib.rs"), LIB_RS)?;
+                    self.create_runtime(&cargo_crate, resolution)?;
 
                     rust_lib
                 }
                 _ => cargo_crate.check_staticlib()?,
             };
 
-            self.create_file("platformio.cargo.py", PLATFORMIO_CARGO_PY)?;
+            self.create_script("platformio.cargo.py", PLATFORMIO_CARGO_PY)?;
             self.create_file(PathBuf::from("src").join("dummy.c"), DUMMY_C)?;
 
             options.push(("rust_lib".to_owned(), rust_lib));
@@ -319,10 +391,18 @@ impl Builder {
             self.create_file(PathBuf::from("src").join("main.c"), MAIN_C)?;
         }
 
+        if resolution
+            .frameworks
+            .iter()
+            .any(|framework| framework == "espidf")
+        {
+            self.create_sdkconfig_defaults()?;
+        }
+
         self.copy_files()?;
 
         if self.git_repos_enabled {
-            self.create_file("platformio.git.py", PLATFORMIO_GIT_PY)?;
+            self.create_script("platformio.git.py", PLATFORMIO_GIT_PY)?;
             extra_scripts.push("pre:platformio.git.py");
 
             if let Some(option) = self.get_git_repos_option()? {
@@ -335,7 +415,7 @@ impl Builder {
         }
 
         if self.platform_packages_patches_enabled {
-            self.create_file("platformio.patch.py", PLATFORMIO_PATCH_PY)?;
+            self.create_script("platformio.patch.py", PLATFORMIO_PATCH_PY)?;
             extra_scripts.push("pre:platformio.patch.py");
 
             if let Some(option) = self.get_platform_packages_patches_option()? {
@@ -348,7 +428,7 @@ impl Builder {
         }
 
         if self.scons_dump_enabled {
-            self.create_file("platformio.dump.py", PLATFORMIO_DUMP_PY)?;
+            self.create_script("platformio.dump.py", PLATFORMIO_DUMP_PY)?;
             extra_scripts.push("platformio.dump.py");
         }
 
@@ -361,6 +441,59 @@ impl Builder {
         Ok(())
     }
 
+    fn create_runtime(&self, cargo_crate: &cargo::Crate, resolution: &Resolution) -> Result<()> {
+        let mut scaffold = Scaffold::new(resolution);
+
+        if let Some(panic_handler) = self.panic_handler {
+            scaffold.panic_handler(panic_handler)?;
+        }
+
+        if let Some(memory) = self.memory {
+            scaffold.memory(memory);
+        }
+
+        debug!(
+            "Generating the {:?} runtime with the {} panic handler",
+            scaffold.runtime, scaffold.panic_handler
+        );
+
+        cargo_crate.add_dependencies(
+            scaffold.dependencies().into_iter().map(|dependency| {
+                (
+                    dependency.name.to_owned(),
+                    cargo_toml::Dependency::Detailed(cargo_toml::DependencyDetail {
+                        version: Some(dependency.version.to_owned()),
+                        optional: dependency.optional,
+                        default_features: (!dependency.default_features).then(|| false),
+                        ..Default::default()
+                    }),
+                )
+            }),
+            scaffold.features(),
+        )?;
+
+        self.create_file(
+            PathBuf::from("src").join("lib.rs"),
+            scaffold.lib_rs().as_bytes(),
+        )?;
+
+        for (name, content) in scaffold.linker_files() {
+            self.create_file(name, content.as_bytes())?;
+        }
+
+        if let Some(ldscript) = scaffold.ldscript() {
+            let mut config = Config::load(&self.project_dir)?;
+
+            for env in ["debug", "release"] {
+                config.env.entry(env.to_owned()).or_default().ldscript = Some(ldscript.into());
+            }
+
+            config.save(&self.project_dir)?;
+        }
+
+        Ok(())
+    }
+
     fn get_git_repos_option(&self) -> Result<Option<(String, String)>> {
         Ok(if !self.git_repos.is_empty() {
             Some((
@@ -419,52 +552,111 @@ impl Builder {
     }
 
     fn create_platformio_ini(&self, options: &[(impl AsRef<str>, impl AsRef<str>)]) -> Result<()> {
-        let platformio_ini_path = self.project_dir.join("platformio.ini");
+        let platformio_ini = ManagedFile::for_path(&self.project_dir, "platformio.ini");
+
+        let options = options
+            .iter()
+            .map(|(key, value)| format!("{} = {}", key.as_ref(), value.as_ref()))
+            .collect::<Vec<_>>()
+            .join("\n");
 
-        debug!("Creating file {}", platformio_ini_path.display());
+        // Every section gets its own managed block, so that options can be added to the
+        // sections after their blocks and new sections between them
+        let sections = [
+            ("platformio", "default_envs = debug".to_owned()),
+            ("env", options),
+            ("env:debug", "build_type = debug".to_owned()),
+            ("env:release", "build_type = release".to_owned()),
+        ];
 
-        fs::write(
-            platformio_ini_path,
-            format!(
-                r#"
-; PlatformIO Project Configuration File
+        platformio_ini.write(format!(
+            "; PlatformIO Project Configuration File
 ;
 ; Please visit documentation for options and examples
 ; https://docs.platformio.org/page/projectconf.html
-[platformio]
-default_envs = debug
-
-[env]
-{}
-
-[env:debug]
-build_type = debug
-
-[env:release]
-build_type = release
-"#,
-                options
-                    .iter()
-                    .map(|(key, value)| format!("{} = {}", key.as_ref(), value.as_ref()))
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            ),
-        )?;
+{}",
+            sections
+                .iter()
+                .map(|(section, options)| format!(
+                    "[{}]\n{}",
+                    section,
+                    platformio_ini.block(options)
+                ))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ))?;
+
+        Ok(())
+    }
+
+    /// Create the `sdkconfig.defaults` of an ESP-IDF project with the options Rust needs
+    /// in a managed block.
+    fn create_sdkconfig_defaults(&self) -> Result<()> {
+        let sdkconfig_defaults = ManagedFile::new(&self.project_dir, "sdkconfig.defaults", "#");
+
+        sdkconfig_defaults.write(sdkconfig_defaults.block(SDKCONFIG_DEFAULTS))?;
 
         Ok(())
     }
 
     fn update_gitignore(&self) -> Result<()> {
-        // TODO: Only do this if not done already
-        debug!("Adding \".pio\" and \"CMakeFiles\" directories to .gitignore");
+        debug!("Updating .gitignore");
+
+        let path = self.project_dir.join(".gitignore");
+        let current = fs::read_to_string(&path).unwrap_or_default();
+
+        let content = GITIGNORE
+            .iter()
+            .map(|entry| format!("{}\n", entry))
+            .collect::<String>();
+        let updated = ManagedFile::new(&self.project_dir, ".gitignore", "#").upsert_block(
+            &current,
+            content,
+            |_| false,
+        );
+
+        if updated != current {
+            fs::write(path, updated)?;
+        }
+
+        Ok(())
+    }
+
+    fn init_git(&self, git: &GitSetup) -> Result<()> {
+        let dir = &self.project_dir;
+
+        let inside = cmd!("git", "rev-parse", "--is-inside-work-tree"; current_dir=(dir))
+            .stdout()
+            .map_or(false, |inside| inside == "true");
+        if inside {
+            info!(
+                "{} is inside a git repository already, not initializing one",
+                dir.display()
+            );
+            return Ok(());
+        }
+
+        cmd!("git", "init", "-q"; current_dir=(dir)).run()?;
 
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(self.project_dir.join(".gitignore"))?;
+        if !git.lfs.is_empty() {
+            cmd!("git", "lfs", "install", "--local"; current_dir=(dir))
+                .run()
+                .context("Failed to set up Git LFS, is it installed?")?;
+            for pattern in &git.lfs {
+                cmd!("git", "lfs", "track", pattern; current_dir=(dir)).run()?;
+            }
+        }
+
+        if git.commit {
+            cmd!("git", "add", "-A"; current_dir=(dir)).run()?;
 
-        writeln!(file, ".pio\nCMakeFiles/")?;
+            // E.g. without a configured user name and email
+            if let Err(err) =
+                cmd!("git", "commit", "-q", "-m", "Initial commit"; current_dir=(dir)).run()
+            {
+                warn!("Failed to create the initial commit: {}", err);
+            }
+        }
 
         Ok(())
     }
@@ -476,19 +668,27 @@ build_type = release
             debug!("Creating/updating {}", dest_file.display());
 
             fs::create_dir_all(dest_file.parent().unwrap())?;
-            fs::copy(&file_pair.0, dest_file)?;
+            crate::fs::copy_with_metadata(&file_pair.0, dest_file)?;
         }
 
         Ok(())
     }
 
+    fn create_script(&self, path: impl AsRef<Path>, data: &[u8]) -> Result<()> {
+        let script = ManagedFile::for_path(&self.project_dir, path);
+
+        script.write(script.block(String::from_utf8_lossy(data)))?;
+
+        Ok(())
+    }
+
     fn create_file(&self, path: impl AsRef<Path>, data: &[u8]) -> Result<()> {
         let dest_file = self.project_dir.join(path.as_ref());
 
         debug!("Creating/updating {}", dest_file.display());
 
         fs::create_dir_all(dest_file.parent().unwrap())?;
-        fs::write(dest_file, data)?;
+        crate::fs::write_atomic(dest_file, data)?;
 
         Ok(())
     }
@@ -548,3 +748,65 @@ impl TryFrom<&SconsVariables> for build::LinkArgsBuilder {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gitignore() {
+        let dir = env::temp_dir().join(format!("embuild-gitignore-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".gitignore"), "*.log\n").unwrap();
+
+        let builder = Builder::new(&dir);
+        builder.update_gitignore().unwrap();
+        builder.update_gitignore().unwrap();
+
+        let gitignore = fs::read_to_string(dir.join(".gitignore")).unwrap();
+        assert!(gitignore.starts_with("*.log\n# cargo-pio: begin managed block"));
+        assert_eq!(gitignore.matches("/target\n").count(), 1);
+        assert!(gitignore.contains(".cargo-pio/sdk/\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_platformio_ini() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("platformio.ini");
+
+        let builder = Builder::new(dir.path());
+        builder
+            .create_platformio_ini(&[("board", "esp32dev")])
+            .unwrap();
+
+        let platformio_ini = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            platformio_ini
+                .matches("; cargo-pio: begin managed block")
+                .count(),
+            4
+        );
+        assert!(platformio_ini
+            .contains("[env]\n; cargo-pio: begin managed block (do not edit)\nboard = esp32dev\n"));
+
+        // Options after the managed block of a section are kept
+        fs::write(
+            &path,
+            platformio_ini.replace(
+                "build_type = debug\n; cargo-pio: end managed block\n",
+                "build_type = debug\n; cargo-pio: end managed block\nmonitor_speed = 9600\n",
+            ),
+        )
+        .unwrap();
+        builder
+            .create_platformio_ini(&[("board", "esp32-c3-devkitm-1")])
+            .unwrap();
+
+        let platformio_ini = fs::read_to_string(&path).unwrap();
+        assert!(platformio_ini.contains("board = esp32-c3-devkitm-1\n"));
+        assert!(platformio_ini.contains("monitor_speed = 9600\n"));
+        assert!(!platformio_ini.contains("<<<<<<<"));
+    }
+}
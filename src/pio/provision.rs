@@ -0,0 +1,375 @@
+//! Per-device provisioning data injected at flash time.
+//!
+//! A provisioning manifest contains one record per device identity (serial number,
+//! keys, Wi-Fi credentials, ...). Every flashed device gets the next unused record,
+//! as tracked by a counter file next to the manifest. The record is written as an NVS
+//! partition image, and which device got which record is appended to a log file next
+//! to the manifest.
+//!
+//! Manifests are either CSV files, whose header names the keys (optionally with their
+//! NVS encoding, e.g. `id:u32`, strings by default), or JSON files containing an array
+//! of objects.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value as JsonValue;
+
+use crate::nvs;
+
+/// The NVS namespace the records are stored in, unless specified otherwise.
+pub const DEFAULT_NAMESPACE: &str = "provision";
+
+/// The identity of a single device.
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// The index of the record in the manifest.
+    pub index: usize,
+    /// The keys and values of the record (in column order for CSV manifests, sorted by
+    /// key for JSON manifests).
+    pub values: Vec<(String, nvs::Value)>,
+}
+
+impl Record {
+    /// A short human readable identifier of the record: the value of its `serial` key
+    /// or, if there is none, of its first key.
+    pub fn id(&self) -> String {
+        let value = self
+            .values
+            .iter()
+            .find(|(key, _)| key == "serial")
+            .or_else(|| self.values.first())
+            .map(|(_, value)| value);
+
+        match value {
+            Some(nvs::Value::String(s)) => s.clone(),
+            Some(nvs::Value::U8(v)) => v.to_string(),
+            Some(nvs::Value::U16(v)) => v.to_string(),
+            Some(nvs::Value::U32(v)) => v.to_string(),
+            Some(nvs::Value::U64(v)) => v.to_string(),
+            Some(nvs::Value::I8(v)) => v.to_string(),
+            Some(nvs::Value::I16(v)) => v.to_string(),
+            Some(nvs::Value::I32(v)) => v.to_string(),
+            Some(nvs::Value::I64(v)) => v.to_string(),
+            _ => format!("#{}", self.index),
+        }
+    }
+
+    /// Generate an NVS partition image of `size` bytes containing this record in
+    /// `namespace`.
+    pub fn nvs_image(&self, namespace: &str, size: usize) -> Result<Vec<u8>> {
+        let mut partition = nvs::Partition::new();
+
+        for (key, value) in &self.values {
+            partition.add(namespace, key, value.clone());
+        }
+
+        partition
+            .generate(size)
+            .with_context(|| anyhow!("Failed to generate the NVS image of record {}", self.id()))
+    }
+}
+
+/// A provisioning manifest together with its counter and log.
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    records: Vec<Record>,
+}
+
+impl Manifest {
+    /// Load the manifest at `path` (a `.json` or a `.csv` file).
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let content = fs::read_to_string(&path)
+            .with_context(|| anyhow!("Failed to read manifest {}", path.display()))?;
+
+        let records = if path.extension().and_then(|e| e.to_str()) == Some("json") {
+            parse_json(&content)
+        } else {
+            parse_csv(&content)
+        }
+        .with_context(|| anyhow!("Invalid manifest {}", path.display()))?;
+
+        Ok(Self { path, records })
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// The file storing the index of the next unused record.
+    pub fn counter_path(&self) -> PathBuf {
+        self.path.with_extension("counter")
+    }
+
+    /// The file recording which device got which record.
+    pub fn log_path(&self) -> PathBuf {
+        self.path.with_extension("log.csv")
+    }
+
+    /// The index of the next unused record.
+    pub fn counter(&self) -> Result<usize> {
+        let path = self.counter_path();
+
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        fs::read_to_string(&path)?
+            .trim()
+            .parse()
+            .with_context(|| anyhow!("Invalid counter file {}", path.display()))
+    }
+
+    /// Take the next `count` unused records and advance the counter past them.
+    ///
+    /// Taken records are never handed out again, even if flashing the device fails,
+    /// so that no identity can end up on two devices.
+    pub fn take(&self, count: usize) -> Result<Vec<Record>> {
+        let start = self.counter()?;
+
+        if start + count > self.records.len() {
+            bail!(
+                "Manifest {} has only {} unused record(s) left, {} needed",
+                self.path.display(),
+                self.records.len().saturating_sub(start),
+                count
+            );
+        }
+
+        fs::write(self.counter_path(), format!("{}\n", start + count))?;
+
+        Ok(self.records[start..start + count].to_vec())
+    }
+
+    /// Append to the log that `record` was flashed to the device at `port` (with the
+    /// USB serial number `device`, if known).
+    pub fn log(
+        &self,
+        record: &Record,
+        port: &str,
+        device: Option<&str>,
+        success: bool,
+    ) -> Result<()> {
+        let path = self.log_path();
+        let new = !path.exists();
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+
+        if new {
+            writeln!(file, "timestamp,index,id,port,device,result")?;
+        }
+
+        writeln!(
+            file,
+            "{},{},{},{},{},{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            record.index,
+            csv_field(&record.id()),
+            csv_field(port),
+            csv_field(device.unwrap_or_default()),
+            if success { "ok" } else { "failed" }
+        )?;
+
+        Ok(())
+    }
+}
+
+fn parse_csv(content: &str) -> Result<Vec<Record>> {
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+
+    let header = match lines.next() {
+        Some(header) => split_csv_line(header)?,
+        None => bail!("Missing header"),
+    };
+
+    let columns = header
+        .iter()
+        .map(|column| match column.split_once(':') {
+            Some((key, encoding)) => (key.trim().to_owned(), encoding.trim().to_owned()),
+            None => (column.trim().to_owned(), "string".to_owned()),
+        })
+        .collect::<Vec<_>>();
+
+    lines
+        .enumerate()
+        .map(|(index, line)| {
+            let fields = split_csv_line(line)?;
+
+            if fields.len() != columns.len() {
+                bail!(
+                    "Record {} has {} fields, expected {}",
+                    index,
+                    fields.len(),
+                    columns.len()
+                );
+            }
+
+            Ok(Record {
+                index,
+                values: columns
+                    .iter()
+                    .zip(fields)
+                    .map(|((key, encoding), field)| {
+                        Ok((key.clone(), nvs::Value::parse(encoding, &field)?))
+                    })
+                    .collect::<Result<_>>()?,
+            })
+        })
+        .collect()
+}
+
+fn parse_json(content: &str) -> Result<Vec<Record>> {
+    let records = match serde_json::from_str::<JsonValue>(content)? {
+        JsonValue::Array(records) => records,
+        _ => bail!("Expected an array of records"),
+    };
+
+    records
+        .into_iter()
+        .enumerate()
+        .map(|(index, record)| {
+            let record = match record {
+                JsonValue::Object(record) => record,
+                _ => bail!("Record {} is not an object", index),
+            };
+
+            Ok(Record {
+                index,
+                values: record
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let value = json_value(&value)
+                            .with_context(|| anyhow!("Invalid value of '{}'", key))?;
+
+                        Ok((key, value))
+                    })
+                    .collect::<Result<_>>()?,
+            })
+        })
+        .collect()
+}
+
+/// Convert a JSON value to an NVS value: strings, booleans (as `u8`) and integers (as
+/// the smallest of `u32`, `i32`, `u64` and `i64` which fits), or an object with an
+/// explicit `encoding` and `value`.
+fn json_value(value: &JsonValue) -> Result<nvs::Value> {
+    Ok(match value {
+        JsonValue::String(s) => nvs::Value::String(s.clone()),
+        JsonValue::Bool(b) => nvs::Value::U8(*b as u8),
+        JsonValue::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                if n <= u64::from(u32::MAX) {
+                    nvs::Value::U32(n as u32)
+                } else {
+                    nvs::Value::U64(n)
+                }
+            } else if let Some(n) = n.as_i64() {
+                if n >= i64::from(i32::MIN) {
+                    nvs::Value::I32(n as i32)
+                } else {
+                    nvs::Value::I64(n)
+                }
+            } else {
+                bail!("Floating point numbers are not supported");
+            }
+        }
+        JsonValue::Object(object) => {
+            let encoding = object.get("encoding").and_then(JsonValue::as_str);
+            let value = object.get("value").map(|value| match value {
+                JsonValue::String(s) => s.clone(),
+                other => other.to_string(),
+            });
+
+            match (encoding, value) {
+                (Some(encoding), Some(value)) => nvs::Value::parse(encoding, &value)?,
+                _ => bail!("Expected an object with 'encoding' and 'value'"),
+            }
+        }
+        _ => bail!("Unsupported value {}", value),
+    })
+}
+
+fn split_csv_line(line: &str) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        bail!("Unterminated quote in '{}'", line);
+    }
+
+    fields.push(field);
+
+    Ok(fields)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let records = parse_csv("serial,id:u32,wifi_pass\nSN-1,1,\"a,\"\"b\"\nSN-2,2,c\n").unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id(), "SN-1");
+        assert_eq!(
+            records[0].values,
+            vec![
+                ("serial".to_owned(), nvs::Value::String("SN-1".into())),
+                ("id".to_owned(), nvs::Value::U32(1)),
+                ("wifi_pass".to_owned(), nvs::Value::String("a,\"b".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let records = parse_json(
+            r#"[{"serial": "SN-1", "id": 1, "key": {"encoding": "hex2bin", "value": "00ff"}}]"#,
+        )
+        .unwrap();
+
+        assert_eq!(records[0].id(), "SN-1");
+        assert_eq!(
+            records[0].values,
+            vec![
+                ("id".to_owned(), nvs::Value::U32(1)),
+                ("key".to_owned(), nvs::Value::Blob(vec![0, 0xff])),
+                ("serial".to_owned(), nvs::Value::String("SN-1".into())),
+            ]
+        );
+    }
+}
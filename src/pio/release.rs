@@ -0,0 +1,313 @@
+//! Release packaging of PIO->Cargo projects.
+//!
+//! A release is a directory and a `.tar.gz` archive of it, named
+//! `<package>-<version>` after the Cargo package, with:
+//! - the artifacts of every released environment (`<environment>/firmware.bin`, the ELF
+//!   file, the partition table, the bootloader and the MCUboot image, if any, and the
+//!   outputs of the [post-processing](super::postprocess) steps);
+//! - the additional files configured in the `[release]` section of `cargo-pio.toml`;
+//! - `release.json`, the manifest with the versions of the code (Cargo package, `git
+//!   describe`) and the toolchain (rustc, PlatformIO and the toolchain fingerprint of
+//!   every environment, see [`super::fingerprint`]) and the checksums of the artifacts;
+//! - `SHA256SUMS`, the SHA-256 checksums of all files, in the format of `sha256sum`;
+//! - `SHA256SUMS.sig`, the signature of the checksums, if a signing command is
+//!   configured.
+//!
+//! The archive is accompanied by `<package>-<version>.tar.gz.sha256`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+
+use super::config::{self, Config};
+use super::fingerprint::Fingerprint;
+use super::hash::{hex, sha256};
+use super::{images, mcuboot, postprocess, report, stamp, Pio};
+use crate::error::HintExt;
+
+pub use crate::pio_model::{Artifact, Manifest};
+
+/// The name of the release manifest.
+pub const MANIFEST_FILE: &str = "release.json";
+
+/// The name of the checksums file.
+pub const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+/// The build artifacts included per environment, besides `firmware.bin`, if they exist.
+const OPTIONAL_ARTIFACTS: &[&str] = &[
+    "firmware.elf",
+    "partitions.bin",
+    "bootloader.bin",
+    mcuboot::IMAGE_FILE,
+];
+
+/// The environments released according to `config`.
+pub fn environments(config: &Config) -> Vec<String> {
+    if !config.release.environments.is_empty() {
+        config.release.environments.clone()
+    } else if !config.images.is_empty() {
+        images::environments(&config.images)
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    } else {
+        vec!["release".to_owned()]
+    }
+}
+
+/// Package the release of the (already built) `environments` of the project in
+/// `project_dir` into `output_dir`.
+///
+/// Returns the path of the archive.
+pub fn package(
+    pio: &Pio,
+    config: &Config,
+    project_dir: impl AsRef<Path>,
+    environments: &[String],
+    output_dir: impl AsRef<Path>,
+) -> Result<PathBuf> {
+    let project_dir = project_dir.as_ref();
+    let output_dir = output_dir.as_ref();
+
+    let (name, version) = cargo_package(project_dir)?;
+    let release_name = format!("{}-{}", name, version);
+
+    let mut files = Vec::new();
+    let mut toolchains = BTreeMap::new();
+
+    for environment in environments {
+        let build_dir = project_dir.join(".pio").join("build").join(environment);
+
+        let firmware = build_dir.join("firmware.bin");
+        if !firmware.is_file() {
+            return Err(anyhow!(
+                "Firmware {} of environment {} does not exist",
+                firmware.display(),
+                environment
+            ))
+            .hint(format!("Build environment {} first", environment));
+        }
+
+        files.push((format!("{}/firmware.bin", environment), fs::read(firmware)?));
+
+        for artifact in OPTIONAL_ARTIFACTS {
+            let path = build_dir.join(artifact);
+
+            if path.is_file() {
+                files.push((format!("{}/{}", environment, artifact), fs::read(path)?));
+            }
+        }
+
+        for output in postprocess::outputs(config, environment)? {
+            let release_path = format!(
+                "{}/{}",
+                environment,
+                output.to_string_lossy().replace('\\', "/")
+            );
+            if files.iter().any(|(path, _)| *path == release_path) {
+                continue;
+            }
+
+            let path = build_dir.join(&output);
+            let data = fs::read(&path)
+                .with_context(|| anyhow!("Failed to read {}", path.display()))
+                .hint(format!("Build environment {} first", environment))?;
+
+            files.push((release_path, data));
+        }
+
+        toolchains.insert(
+            environment.clone(),
+            Fingerprint::collect(pio, project_dir, environment)?.components,
+        );
+    }
+
+    for file in &config.release.files {
+        let path = project_dir.join(file);
+        let data = fs::read(&path).with_context(|| anyhow!("Failed to read {}", path.display()))?;
+
+        files.push((file.to_string_lossy().replace('\\', "/"), data));
+    }
+
+    let manifest = Manifest {
+        name,
+        version,
+        git_describe: stamp::git_describe(project_dir),
+        rustc: report::version(std::process::Command::new("rustc").arg("--version")),
+        platformio: report::version(pio.cmd().arg("--version")),
+        toolchains,
+        artifacts: files
+            .iter()
+            .map(|(path, data)| Artifact {
+                path: path.clone(),
+                size: data.len() as u64,
+                sha256: hex(&sha256(data)),
+            })
+            .collect(),
+    };
+
+    files.push((
+        MANIFEST_FILE.to_owned(),
+        serde_json::to_vec_pretty(&manifest)?,
+    ));
+
+    let mut checksums = String::new();
+    for (path, data) in &files {
+        writeln!(checksums, "{}  {}", hex(&sha256(data)), path).unwrap();
+    }
+
+    files.push((CHECKSUMS_FILE.to_owned(), checksums.into_bytes()));
+
+    let release_dir = output_dir.join(&release_name);
+    if release_dir.exists() {
+        crate::fs::remove_dir_all(&release_dir)?;
+    }
+
+    for (path, data) in &files {
+        let path = release_dir.join(path);
+
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, data)?;
+    }
+
+    if let Some(sign) = &config.release.sign {
+        let signature = format!("{}.sig", CHECKSUMS_FILE);
+
+        info!("Signing {}", CHECKSUMS_FILE);
+
+        let status = config::shell(sign)
+            .current_dir(&release_dir)
+            .env("CARGO_PIO_SIGN_FILE", release_dir.join(CHECKSUMS_FILE))
+            .env("CARGO_PIO_SIGNATURE", release_dir.join(&signature))
+            .status()?;
+
+        if !status.success() {
+            bail!("The signing command '{}' failed with {}", sign, status);
+        }
+
+        files.push((signature.clone(), fs::read(release_dir.join(&signature))?));
+    }
+
+    let archive = output_dir.join(format!("{}.tar.gz", release_name));
+
+    let mtime = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.trim().parse()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+
+    let archive_data = tar_gz(&release_name, &files, mtime)?;
+    fs::write(&archive, &archive_data)?;
+    fs::write(
+        output_dir.join(format!("{}.tar.gz.sha256", release_name)),
+        format!("{}  {}.tar.gz\n", hex(&sha256(&archive_data)), release_name),
+    )?;
+
+    info!(
+        "Release {} ({} files): {}",
+        release_name,
+        files.len(),
+        archive.display()
+    );
+
+    Ok(archive)
+}
+
+/// The name and version of the Cargo package of the project in `project_dir`.
+pub(crate) fn cargo_package(project_dir: &Path) -> Result<(String, String)> {
+    let manifest = project_dir.join("Cargo.toml");
+
+    let manifest = fs::read_to_string(&manifest)
+        .with_context(|| anyhow!("Failed to read {}", manifest.display()))?
+        .parse::<toml::Value>()?;
+
+    let field = |name: &str| {
+        manifest
+            .get("package")
+            .and_then(|package| package.get(name))
+            .and_then(toml::Value::as_str)
+            .map(str::to_owned)
+            .ok_or_else(|| anyhow!("No package {} in Cargo.toml", name))
+    };
+
+    Ok((field("name")?, field("version")?))
+}
+
+/// The gzipped tar archive of `files` in the directory `dir`, with the same owner,
+/// permissions and modification time `mtime` for all of them, so that the same files
+/// always give the same archive.
+#[cfg(feature = "pio-archive")]
+fn tar_gz(dir: &str, files: &[(String, Vec<u8>)], mtime: u64) -> Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::best(),
+    ));
+
+    for (path, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(mtime);
+
+        builder.append_data(&mut header, format!("{}/{}", dir, path), data.as_slice())?;
+    }
+
+    Ok(builder.into_inner()?.finish()?)
+}
+
+#[cfg(not(feature = "pio-archive"))]
+fn tar_gz(_dir: &str, _files: &[(String, Vec<u8>)], _mtime: u64) -> Result<Vec<u8>> {
+    bail!("Release archives need the `pio-archive` feature of embuild")
+}
+
+#[cfg(all(test, feature = "pio-archive"))]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_release() {
+        let files = vec![
+            ("release/firmware.bin".to_owned(), vec![0xe9; 1000]),
+            (
+                format!("{}/long.txt", "nested/".repeat(20)),
+                b"long".to_vec(),
+            ),
+        ];
+
+        let archive = tar_gz("app-1.0.0", &files, 1_700_000_000).unwrap();
+        assert_eq!(archive, tar_gz("app-1.0.0", &files, 1_700_000_000).unwrap());
+
+        let mut unpacked = tar::Archive::new(flate2::read::GzDecoder::new(archive.as_slice()));
+        let entries = unpacked
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+
+                assert_eq!(entry.header().mode().unwrap(), 0o644);
+                assert_eq!(entry.header().mtime().unwrap(), 1_700_000_000);
+
+                (entry.path().unwrap().display().to_string(), data)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            entries,
+            files
+                .iter()
+                .map(|(path, data)| (format!("app-1.0.0/{}", path), data.clone()))
+                .collect::<Vec<_>>()
+        );
+    }
+}
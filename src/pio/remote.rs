@@ -0,0 +1,646 @@
+//! Experimental remote builds of PIO->Cargo projects.
+//!
+//! The client ships a snapshot of the project sources to an [`Agent`] running on a host
+//! which has the toolchains installed, the agent builds the requested PlatformIO
+//! environment and streams the build output and the resulting artifacts back, which are
+//! stored where a local build would have put them (`.pio/build/<environment>`).
+//!
+//! The protocol is a sequence of frames over a plain TCP connection, each consisting of
+//! a kind byte, the little-endian `u32` length of the payload and the payload itself:
+//! - client: a [`Request`], the project files, then an end-of-snapshot frame;
+//! - agent: log output and artifact files in any order, then an exit or error frame.
+//!
+//! The connection is neither encrypted nor authenticated beyond an optional shared
+//! token, so the agent should only be reachable from trusted networks. The agent checks
+//! the token before accepting any files, and limits the size of the frames and the time
+//! it waits for a client.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use super::config::Config;
+use super::Pio;
+
+/// The port the agent listens on, unless specified otherwise.
+pub const DEFAULT_PORT: u16 = 7879;
+
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Directories which are not part of the source snapshot: build outputs and VCS data.
+const EXCLUDED_DIRS: &[&str] = &[".git", ".pio", ".embuild", "target"];
+
+const FRAME_REQUEST: u8 = 0;
+const FRAME_FILE: u8 = 1;
+const FRAME_END: u8 = 2;
+const FRAME_LOG: u8 = 3;
+const FRAME_EXIT: u8 = 4;
+const FRAME_ERROR: u8 = 5;
+
+/// The maximum size of the payload of the request, log, exit and error frames.
+const MAX_CONTROL_FRAME: u32 = 4 * 1024 * 1024;
+/// The maximum size of the payload of a file frame, i.e. of a source file or artifact.
+const MAX_FILE_FRAME: u32 = 256 * 1024 * 1024;
+
+/// How long the agent waits for a client to send or receive data.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A build request, sent by the client ahead of the source snapshot.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Request {
+    pub version: u32,
+    /// The name of the project, which the agent uses to keep the build outputs of
+    /// subsequent builds of the same project around.
+    pub project: String,
+    /// The PlatformIO environment to build.
+    pub environment: String,
+    /// The token the agent was started with, if any.
+    pub token: Option<String>,
+}
+
+/// The outcome of a remote build.
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    /// The exit code of the remote `pio run`, `None` if it was terminated by a signal.
+    pub exit_code: Option<i32>,
+    /// The artifacts written to the local build directory.
+    pub artifacts: Vec<PathBuf>,
+}
+
+impl Outcome {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// Build the PlatformIO `environment` of the project in `project_dir` on the agent at
+/// `host` (`[tcp://]<host>[:<port>]`), forwarding the build output to stdout.
+pub fn build(
+    host: &str,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+    token: Option<&str>,
+) -> Result<Outcome> {
+    let project_dir = project_dir.as_ref();
+    let address = address(host);
+
+    info!("Connecting to build agent {}", address);
+
+    let mut stream = TcpStream::connect(&address)
+        .with_context(|| anyhow!("Failed to connect to build agent {}", address))?;
+
+    let request = Request {
+        version: PROTOCOL_VERSION,
+        project: project_dir
+            .canonicalize()?
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "project".into()),
+        environment: environment.to_owned(),
+        token: token.map(str::to_owned),
+    };
+
+    write_frame(&mut stream, FRAME_REQUEST, &serde_json::to_vec(&request)?)?;
+
+    let files = snapshot(project_dir)?;
+    let mut size = 0;
+
+    for file in &files {
+        let content = fs::read(project_dir.join(file))?;
+        size += content.len();
+
+        write_frame(&mut stream, FRAME_FILE, &file_payload(file, &content)?)?;
+    }
+
+    write_frame(&mut stream, FRAME_END, &[])?;
+
+    info!("Sent {} file(s), {} bytes", files.len(), size);
+
+    let build_dir = project_dir.join(".pio").join("build").join(environment);
+    let mut artifacts = Vec::new();
+    let stdout = io::stdout();
+
+    loop {
+        let (kind, payload) = read_frame(&mut stream, MAX_FILE_FRAME)?;
+
+        match kind {
+            FRAME_LOG => {
+                let mut stdout = stdout.lock();
+                stdout.write_all(&payload)?;
+                stdout.flush()?;
+            }
+            FRAME_FILE => {
+                let (name, content) = parse_file_payload(&payload)?;
+
+                // Artifacts are plain files of the build directory
+                if !is_artifact_name(&name) {
+                    bail!("Invalid artifact name '{}'", name.display());
+                }
+
+                fs::create_dir_all(&build_dir)?;
+
+                let path = build_dir.join(name);
+                fs::write(&path, content)?;
+
+                artifacts.push(path);
+            }
+            FRAME_EXIT => {
+                let code = i32::from_le_bytes(
+                    payload
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| anyhow!("Invalid exit frame"))?,
+                );
+
+                return Ok(Outcome {
+                    exit_code: if code < 0 { None } else { Some(code) },
+                    artifacts,
+                });
+            }
+            FRAME_ERROR => bail!(
+                "Build agent {} failed: {}",
+                address,
+                String::from_utf8_lossy(&payload)
+            ),
+            _ => bail!("Unexpected frame {} from build agent {}", kind, address),
+        }
+    }
+}
+
+/// A build agent, building projects shipped by [`build`].
+///
+/// Clients are served concurrently, but builds are run one at a time, as concurrent
+/// PlatformIO runs sharing one core directory interfere with each other.
+#[derive(Clone, Debug)]
+pub struct Agent {
+    pio: Pio,
+    work_dir: PathBuf,
+    token: Option<String>,
+    build_lock: Arc<Mutex<()>>,
+}
+
+impl Agent {
+    /// Create an agent building with `pio` in `work_dir`, where one directory per
+    /// project is kept so that subsequent builds are incremental.
+    pub fn new(pio: Pio, work_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            pio,
+            work_dir: work_dir.into(),
+            token: None,
+            build_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Only accept requests carrying `token`.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Listen for build requests on `address` and serve them.
+    ///
+    /// Failures of single connections are logged and do not stop the agent.
+    pub fn serve(&self, address: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(address)?;
+
+        info!("Build agent listening on {}", listener.local_addr()?);
+
+        if self.token.is_none() {
+            warn!("No token set, the agent will build for anyone who can connect to it");
+        }
+
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Failed to accept a connection: {}", err);
+                    continue;
+                }
+            };
+            let peer = stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "unknown".into());
+
+            // A stalled client must not block the others
+            if let Err(err) = stream
+                .set_read_timeout(Some(CLIENT_TIMEOUT))
+                .and_then(|_| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+            {
+                warn!("Dropping the connection from {}: {}", peer, err);
+                continue;
+            }
+
+            let agent = self.clone();
+
+            thread::spawn(move || {
+                if let Err(err) = agent.handle(&mut stream, &peer) {
+                    error!("Build for {} failed: {:#}", peer, err);
+
+                    write_frame(&mut stream, FRAME_ERROR, format!("{:#}", err).as_bytes()).ok();
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn handle(&self, stream: &mut TcpStream, peer: &str) -> Result<()> {
+        let request = match read_frame(stream, MAX_CONTROL_FRAME)? {
+            (FRAME_REQUEST, payload) => serde_json::from_slice::<Request>(&payload)?,
+            (kind, _) => bail!("Expected a request, got frame {}", kind),
+        };
+
+        if request.version != PROTOCOL_VERSION {
+            bail!(
+                "Unsupported protocol version {}, expected {}",
+                request.version,
+                PROTOCOL_VERSION
+            );
+        }
+
+        if let Some(token) = &self.token {
+            let valid = request.token.as_deref().map_or(false, |request_token| {
+                constant_time_eq(request_token.as_bytes(), token.as_bytes())
+            });
+
+            if !valid {
+                bail!("Invalid token");
+            }
+        }
+
+        if !is_file_name(&request.project) || !is_file_name(&request.environment) {
+            bail!("Invalid project or environment name");
+        }
+
+        info!(
+            "Building environment {} of project {} for {}",
+            request.environment, request.project, peer
+        );
+
+        let project_dir = self.work_dir.join(&request.project);
+
+        let _build = self
+            .build_lock
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+
+        clean(&project_dir)?;
+
+        loop {
+            match read_frame(stream, MAX_FILE_FRAME)? {
+                (FRAME_FILE, payload) => {
+                    let (path, content) = parse_file_payload(&payload)?;
+
+                    if !path
+                        .components()
+                        .all(|component| matches!(component, Component::Normal(_)))
+                    {
+                        bail!("Invalid file path '{}'", path.display());
+                    }
+
+                    let path = project_dir.join(path);
+
+                    fs::create_dir_all(path.parent().unwrap())?;
+                    fs::write(path, content)?;
+                }
+                (FRAME_END, _) => break,
+                (kind, _) => bail!("Expected a file, got frame {}", kind),
+            }
+        }
+
+        let mut cmd = self.pio.run_cmd();
+        cmd.arg("-d")
+            .arg(&project_dir)
+            .arg("-e")
+            .arg(&request.environment);
+
+        Config::load(&project_dir)?.apply_env(&request.environment, &mut cmd);
+
+        debug!("Running PlatformIO command: {:?}", cmd);
+
+        let mut child = cmd
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+
+        let log = LogWriter(Arc::new(Mutex::new(stream.try_clone()?)));
+        let stdout = Pio::tee(
+            child.stdout.take().unwrap(),
+            log.clone(),
+            false,
+            Default::default(),
+        );
+        let stderr = Pio::tee(child.stderr.take().unwrap(), log, false, Default::default());
+
+        let status = child.wait()?;
+
+        stdout.join().ok();
+        stderr.join().ok();
+
+        let build_dir = project_dir
+            .join(".pio")
+            .join("build")
+            .join(&request.environment);
+
+        if build_dir.is_dir() {
+            for entry in fs::read_dir(&build_dir)? {
+                let entry = entry?;
+
+                if entry.file_type()?.is_file() {
+                    let content = fs::read(entry.path())?;
+
+                    write_frame(
+                        stream,
+                        FRAME_FILE,
+                        &file_payload(Path::new(&entry.file_name()), &content)?,
+                    )?;
+                }
+            }
+        }
+
+        write_frame(
+            stream,
+            FRAME_EXIT,
+            &status.code().unwrap_or(-1).to_le_bytes(),
+        )?;
+
+        info!("Build for {} finished with {}", peer, status);
+
+        Ok(())
+    }
+}
+
+/// Forwards the build output to the client as log frames.
+#[derive(Clone)]
+struct LogWriter(Arc<Mutex<TcpStream>>);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_frame(&mut *self.0.lock().unwrap(), FRAME_LOG, buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+fn address(host: &str) -> String {
+    let host = host.strip_prefix("tcp://").unwrap_or(host);
+    let host = host.trim_end_matches('/');
+
+    // Bracketed IPv6 addresses contain colons even without a port
+    if host.rsplit_once(':').map_or(false, |(_, port)| {
+        !port.contains(']') && port.parse::<u16>().is_ok()
+    }) {
+        host.to_owned()
+    } else {
+        format!("{}:{}", host, DEFAULT_PORT)
+    }
+}
+
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(|c: char| c == '/' || c == '\\' || c.is_control())
+}
+
+/// Whether `name` is a plain file name, as the artifacts are files of the build directory.
+fn is_artifact_name(name: &Path) -> bool {
+    let mut components = name.components();
+
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+/// The relative paths of all files of the project in `project_dir`, except for those in
+/// [`EXCLUDED_DIRS`].
+fn snapshot(project_dir: &Path) -> Result<Vec<PathBuf>> {
+    fn walk(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in fs::read_dir(root.join(dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            // Symlinked directories are not followed, they may point back up the tree
+            // or anywhere outside of the project
+            let file_type = entry.file_type()?;
+
+            if file_type.is_dir() {
+                if !EXCLUDED_DIRS
+                    .iter()
+                    .any(|excluded| entry.file_name() == *excluded)
+                {
+                    walk(root, &path, files)?;
+                }
+            } else if file_type.is_file()
+                || file_type.is_symlink() && fs::metadata(entry.path())?.is_file()
+            {
+                files.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk(project_dir, Path::new(""), &mut files)?;
+    files.sort();
+
+    Ok(files)
+}
+
+/// Remove everything in `project_dir` except for the build outputs of previous builds.
+fn clean(project_dir: &Path) -> Result<()> {
+    if !project_dir.exists() {
+        return Ok(fs::create_dir_all(project_dir)?);
+    }
+
+    for entry in fs::read_dir(project_dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            if !EXCLUDED_DIRS
+                .iter()
+                .any(|excluded| entry.file_name() == *excluded)
+            {
+                fs::remove_dir_all(entry.path())?;
+            }
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A file frame payload: the `u16` length of the `/`-separated path, the path and the
+/// content.
+fn file_payload(path: &Path, content: &[u8]) -> Result<Vec<u8>> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("Non-UTF-8 path {}", path.display()))?
+        .replace('\\', "/");
+
+    let path_len: u16 = path
+        .len()
+        .try_into()
+        .map_err(|_| anyhow!("Path {} is too long", path))?;
+
+    let mut payload = Vec::with_capacity(2 + path.len() + content.len());
+    payload.extend_from_slice(&path_len.to_le_bytes());
+    payload.extend_from_slice(path.as_bytes());
+    payload.extend_from_slice(content);
+
+    Ok(payload)
+}
+
+fn parse_file_payload(payload: &[u8]) -> Result<(PathBuf, &[u8])> {
+    if payload.len() < 2 {
+        bail!("Truncated file frame");
+    }
+
+    let path_len = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+
+    if payload.len() < 2 + path_len {
+        bail!("Truncated file frame");
+    }
+
+    let path = std::str::from_utf8(&payload[2..2 + path_len])?;
+
+    Ok((
+        path.split('/').collect::<PathBuf>(),
+        &payload[2 + path_len..],
+    ))
+}
+
+fn write_frame(writer: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let len: u32 = payload
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Frame too large"))?;
+
+    writer.write_all(&[kind])?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Read a frame, rejecting payloads over `max_len` bytes of any kind and over
+/// [`MAX_CONTROL_FRAME`] of frames other than files.
+fn read_frame(reader: &mut impl Read, max_len: u32) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0_u8; 5];
+    reader
+        .read_exact(&mut header)
+        .context("Connection closed unexpectedly")?;
+
+    let kind = header[0];
+    let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+    let max_len = if kind == FRAME_FILE {
+        max_len
+    } else {
+        max_len.min(MAX_CONTROL_FRAME)
+    };
+
+    if len > max_len {
+        bail!(
+            "Frame {} of {} bytes exceeds the maximum of {} bytes",
+            kind,
+            len,
+            max_len
+        );
+    }
+
+    // Only what actually arrives is allocated, not what the header claims
+    let mut payload = Vec::new();
+    reader.take(len.into()).read_to_end(&mut payload)?;
+
+    if payload.len() != len as usize {
+        bail!("Connection closed unexpectedly");
+    }
+
+    Ok((kind, payload))
+}
+
+/// Compare `a` and `b` in a time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address() {
+        assert_eq!(address("tcp://builder"), "builder:7879");
+        assert_eq!(address("builder:1234"), "builder:1234");
+        assert_eq!(address("[::1]"), "[::1]:7879");
+        assert_eq!(address("[::1]:1234"), "[::1]:1234");
+    }
+
+    #[test]
+    fn test_file_frame() {
+        let mut buf = Vec::new();
+        let payload = file_payload(Path::new("src/main.rs"), b"fn main() {}").unwrap();
+        write_frame(&mut buf, FRAME_FILE, &payload).unwrap();
+
+        let (kind, payload) = read_frame(&mut buf.as_slice(), MAX_FILE_FRAME).unwrap();
+        let (path, content) = parse_file_payload(&payload).unwrap();
+
+        assert_eq!(kind, FRAME_FILE);
+        assert_eq!(path, Path::new("src").join("main.rs"));
+        assert_eq!(content, b"fn main() {}");
+
+        // Oversized and truncated frames are rejected without allocating their payload
+        let mut request = vec![FRAME_REQUEST];
+        request.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_frame(&mut request.as_slice(), MAX_FILE_FRAME).is_err());
+        assert!(read_frame(&mut &buf[..buf.len() - 1], MAX_FILE_FRAME).is_err());
+
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[test]
+    fn test_artifact_name() {
+        assert!(is_artifact_name(Path::new("firmware.bin")));
+        assert!(!is_artifact_name(Path::new("..")));
+        assert!(!is_artifact_name(Path::new("/")));
+        assert!(!is_artifact_name(Path::new("/firmware.bin")));
+        assert!(!is_artifact_name(Path::new("src/firmware.bin")));
+        assert!(!is_artifact_name(Path::new("")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_snapshot_skips_symlinked_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("project");
+
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::write(project.join("src").join("main.rs"), "fn main() {}").unwrap();
+        fs::write(dir.path().join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.path(), project.join("outside")).unwrap();
+        std::os::unix::fs::symlink(&project, project.join("src").join("loop")).unwrap();
+        std::os::unix::fs::symlink(project.join("src").join("main.rs"), project.join("main.rs"))
+            .unwrap();
+
+        assert_eq!(
+            snapshot(&project).unwrap(),
+            vec![PathBuf::from("main.rs"), Path::new("src").join("main.rs")]
+        );
+    }
+}
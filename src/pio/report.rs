@@ -0,0 +1,366 @@
+//! Environment reports of PIO->Cargo projects, for attaching to bug reports.
+//!
+//! A report is a single markdown document with the versions of the involved tools, the
+//! installed PlatformIO platforms & packages, the project configuration files and the
+//! log of the last build. It is sanitized before it is written: the user's home
+//! directory is replaced with `~` and the values of configuration options which look
+//! like credentials (passwords, keys, tokens, Wi-Fi SSIDs) are masked, as are such
+//! defines (`-DWIFI_PASSWORD=...`) and the values of arguments like `--auth` wherever they
+//! appear, e.g. in the compiler commands of the build log.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Result;
+use serde_json::Value as JsonValue;
+
+use super::Pio;
+
+/// The file the log of the last build of an environment is saved to, relative to the
+/// environment's build directory.
+pub const BUILD_LOG_FILE: &str = "build.log";
+
+/// The configuration files included in the report, relative to the project directory.
+const CONFIG_FILES: &[&str] = &[
+    "platformio.ini",
+    "cargo-pio.toml",
+    "Cargo.toml",
+    "rust-toolchain.toml",
+    "rust-toolchain",
+    ".cargo/config.toml",
+    ".cargo/config",
+    "sdkconfig.defaults",
+    "sdkconfig",
+];
+
+/// The number of trailing lines of the build log included in the report.
+const BUILD_LOG_LINES: usize = 200;
+
+/// Option names containing any of these are considered credentials.
+const SECRET_PATTERNS: &[&str] = &[
+    "PASS",
+    "SECRET",
+    "TOKEN",
+    "KEY",
+    "SSID",
+    "PSK",
+    "CREDENTIAL",
+];
+
+/// Command line options whose values are credentials, e.g. of `upload_flags`.
+const SECRET_OPTIONS: &[&str] = &["--auth", "--password", "--passwd", "--token"];
+
+const MASK: &str = "<redacted>";
+
+/// Generate the report of the PlatformIO `environment` of the project in `project_dir`,
+/// built with `tool` (name and version of the tool generating the report).
+pub fn generate(
+    pio: Option<&Pio>,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+    tool: &str,
+) -> Result<String> {
+    let project_dir = project_dir.as_ref();
+    let mut report = String::new();
+
+    writeln!(report, "# Environment report\n")?;
+    writeln!(
+        report,
+        "Generated by {}, environment `{}`.\n",
+        tool, environment
+    )?;
+
+    writeln!(report, "## Versions\n")?;
+    writeln!(report, "- OS: {} {}", env::consts::OS, env::consts::ARCH)?;
+
+    let mut tools = vec![
+        ("rustc", version(Command::new("rustc").arg("-vV"))),
+        ("cargo", version(Command::new("cargo").arg("-V"))),
+        (
+            "rustup toolchain",
+            version(
+                Command::new("rustup")
+                    .current_dir(project_dir)
+                    .args(["show", "active-toolchain"]),
+            ),
+        ),
+    ];
+
+    if let Some(pio) = pio {
+        tools.push(("PlatformIO", version(pio.cmd().arg("--version"))));
+    }
+
+    for (name, version) in tools {
+        let version = version.unwrap_or_else(|| "not found".into());
+
+        // `rustc -vV` reports one property per line
+        if version.contains('\n') {
+            writeln!(report, "- {}:", name)?;
+
+            for line in version.lines() {
+                writeln!(report, "  - {}", line)?;
+            }
+        } else {
+            writeln!(report, "- {}: {}", name, version)?;
+        }
+    }
+
+    if let Some(pio) = pio {
+        writeln!(report, "\n## PlatformIO platforms & packages\n")?;
+
+        for (dir, manifest) in &[("platforms", "platform.json"), ("packages", "package.json")] {
+            for (name, version) in installed(&pio.core_dir.join(dir), manifest)? {
+                writeln!(report, "- {} {}", name, version)?;
+            }
+        }
+    }
+
+    writeln!(report, "\n## Configuration")?;
+
+    // The sdkconfig generated by the build of the environment
+    let sdkconfig = format!("sdkconfig.{}", environment);
+
+    for file in CONFIG_FILES.iter().copied().chain([sdkconfig.as_str()]) {
+        let path = project_dir.join(file);
+
+        if path.is_file() {
+            writeln!(
+                report,
+                "\n`{}`:\n\n```{}\n{}\n```",
+                file,
+                syntax(file),
+                fs::read_to_string(&path)?.trim_end()
+            )?;
+        }
+    }
+
+    writeln!(report, "\n## Last build log\n")?;
+
+    let build_log = project_dir
+        .join(".pio")
+        .join("build")
+        .join(environment)
+        .join(BUILD_LOG_FILE);
+
+    if build_log.is_file() {
+        let log = fs::read_to_string(&build_log)?;
+        let lines = log.lines().collect::<Vec<_>>();
+
+        if lines.len() > BUILD_LOG_LINES {
+            writeln!(
+                report,
+                "(last {} of {} lines)\n",
+                BUILD_LOG_LINES,
+                lines.len()
+            )?;
+        }
+
+        writeln!(
+            report,
+            "```text\n{}\n```",
+            lines[lines.len().saturating_sub(BUILD_LOG_LINES)..].join("\n")
+        )?;
+    } else {
+        writeln!(report, "No build log, build the environment first.")?;
+    }
+
+    Ok(sanitize(&report, home_dir().as_deref()))
+}
+
+/// Mask credentials in `text` and replace the `home` directory with `~`.
+pub fn sanitize(text: &str, home: Option<&Path>) -> String {
+    let mut sanitized = text
+        .lines()
+        .map(|line| {
+            let separator = match line.find(['=', ':']) {
+                Some(separator) => separator,
+                None => return mask_arguments(line),
+            };
+
+            let key = line[..separator].trim().to_ascii_uppercase();
+            let value = line[separator + 1..].trim();
+
+            let is_secret = !key.is_empty()
+                && !key.contains(char::is_whitespace)
+                && !value.is_empty()
+                && SECRET_PATTERNS.iter().any(|pattern| key.contains(pattern));
+
+            if is_secret {
+                format!("{} {}", &line[..=separator], MASK)
+            } else {
+                mask_arguments(line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if text.ends_with('\n') {
+        sanitized.push('\n');
+    }
+
+    match home.and_then(Path::to_str) {
+        Some(home) if !home.is_empty() => sanitized.replace(home, "~"),
+        _ => sanitized,
+    }
+}
+
+/// Mask the values of the secret defines (`-D<NAME>=<value>`) and [`SECRET_OPTIONS`] in
+/// `line`.
+fn mask_arguments(line: &str) -> String {
+    let mut masked = String::new();
+    let mut rest = line;
+
+    while let Some(value) = find_secret_value(rest) {
+        let len = value_len(&rest[value..]);
+
+        masked.push_str(&rest[..value]);
+        masked.push_str(MASK);
+        rest = &rest[value + len..];
+    }
+
+    masked.push_str(rest);
+
+    masked
+}
+
+/// The index of the first value of a secret define or option in `text`.
+fn find_secret_value(text: &str) -> Option<usize> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+
+    text.char_indices().find_map(|(index, c)| {
+        let starts_argument = index == 0
+            || text[..index]
+                .ends_with(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | ',' | '['));
+        if c != '-' || !starts_argument {
+            return None;
+        }
+
+        let argument = &text[index..];
+
+        if let Some(define) = argument.strip_prefix("-D") {
+            let name_len = define.find(|c: char| !is_name(c)).unwrap_or(define.len());
+            let name = define[..name_len].to_ascii_uppercase();
+
+            let is_secret = define[name_len..].starts_with('=')
+                && SECRET_PATTERNS.iter().any(|pattern| name.contains(pattern));
+
+            return is_secret.then(|| index + 2 + name_len + 1);
+        }
+
+        let option_len = argument
+            .find(|c: char| !is_name(c))
+            .unwrap_or(argument.len());
+
+        if !SECRET_OPTIONS.contains(&&argument[..option_len]) {
+            return None;
+        }
+
+        let value = &argument[option_len..];
+        let separator_len = if value.starts_with('=') {
+            1
+        } else {
+            value.len() - value.trim_start().len()
+        };
+
+        (separator_len > 0 && value.len() > separator_len)
+            .then(|| index + option_len + separator_len)
+    })
+}
+
+/// The length of the (possibly quoted) value at the start of `text`.
+fn value_len(text: &str) -> usize {
+    for quote in ["\\\"", "\"", "'"] {
+        if let Some(quoted) = text.strip_prefix(quote) {
+            return match quoted.find(quote) {
+                Some(end) => quote.len() * 2 + end,
+                None => text.len(),
+            };
+        }
+    }
+
+    text.find(|c: char| c.is_whitespace() || c == ',')
+        .unwrap_or(text.len())
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+/// The trimmed stdout of `cmd`, if it ran successfully.
+pub(crate) fn version(cmd: &mut Command) -> Option<String> {
+    let output = cmd.output().ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// The names and versions of the PlatformIO platforms or packages in `dir`.
+fn installed(dir: &Path, manifest: &str) -> Result<Vec<(String, String)>> {
+    let mut installed = Vec::new();
+
+    if !dir.is_dir() {
+        return Ok(installed);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let manifest = entry?.path().join(manifest);
+
+        if let Ok(manifest) = fs::read_to_string(&manifest) {
+            let manifest: JsonValue = serde_json::from_str(&manifest)?;
+
+            installed.push((
+                manifest["name"].as_str().unwrap_or_default().to_owned(),
+                manifest["version"].as_str().unwrap_or_default().to_owned(),
+            ));
+        }
+    }
+
+    installed.sort();
+
+    Ok(installed)
+}
+
+fn syntax(file: &str) -> &'static str {
+    if file.ends_with(".toml") {
+        "toml"
+    } else if file.ends_with(".ini") {
+        "ini"
+    } else {
+        "text"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        let text = "CONFIG_WIFI_SSID=\"home\"\nCONFIG_WIFI_PASSWORD=\"hunter2\"\nCONFIG_LOG_LEVEL=3\nbuild_flags = -I/home/user/include\nkey = \n";
+
+        assert_eq!(
+            sanitize(text, Some(Path::new("/home/user"))),
+            "CONFIG_WIFI_SSID= <redacted>\nCONFIG_WIFI_PASSWORD= <redacted>\nCONFIG_LOG_LEVEL=3\nbuild_flags = -I~/include\nkey = \n"
+        );
+
+        let text = "build_flags = -DWIFI_PASSWORD=\\\"hunter 2\\\" -DLOG_LEVEL=3 -DAPI_KEY='abc'\n\
+                    upload_flags = --auth=s3cret --port 3232\n\
+                    xtensa-esp32-elf-gcc -o main.o -DWIFI_SSID=\"home\" -c main.c\n\
+                    espota.py --password hunter2 -f firmware.bin\n";
+
+        assert_eq!(
+            sanitize(text, None),
+            "build_flags = -DWIFI_PASSWORD=<redacted> -DLOG_LEVEL=3 -DAPI_KEY=<redacted>\n\
+             upload_flags = --auth=<redacted> --port 3232\n\
+             xtensa-esp32-elf-gcc -o main.o -DWIFI_SSID=<redacted> -c main.c\n\
+             espota.py --password <redacted> -f firmware.bin\n"
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Reset strategies of boards, applied before flashing and once the monitor is attached.
+//!
+//! By default, the upload tools reset the device into its bootloader themselves (esptool
+//! with the DTR/RTS lines of the USB-serial bridge). Boards with different reset
+//! circuitry get a strategy in the `[reset.<board>]` section of `cargo-pio.toml`, keyed
+//! by the PlatformIO board id of the environment, see [`ResetConfig`]:
+//! - a DTR/RTS sequence, in the syntax of esptool's `custom_reset_sequence`, run with the
+//!   Python environment of PlatformIO (and its pyserial);
+//! - an external command, e.g. switching a relay to power-cycle the board.
+//!
+//! When a custom reset brings the device into its bootloader, esptool is told not to
+//! reset it again (`--before no_reset`).
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use log::*;
+
+use super::config::{self, Config, Reset, ResetConfig};
+use super::{inspect, is_raw_tcp_port, serial_port_url, Pio};
+use crate::error::HintExt;
+use crate::pio_model::env_option;
+
+/// The hard reset of esptool (`--after hard_reset`): a pulse on RTS, which is connected
+/// to the enable pin of the chip.
+const HARD_RESET: &str = "R1|W0.1|R0";
+
+/// A step of a DTR/RTS reset sequence.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Step {
+    /// Set DTR (`D0`/`D1`).
+    Dtr(bool),
+    /// Set RTS (`R0`/`R1`).
+    Rts(bool),
+    /// Set DTR and RTS at once (`U<dtr>,<rts>`).
+    Both { dtr: bool, rts: bool },
+    /// Wait (`W<seconds>`).
+    Wait(Duration),
+}
+
+/// Parse a reset sequence in the syntax of esptool's `custom_reset_sequence`, e.g.
+/// `D0|R1|W0.1|D1|R0|W0.05|D0`.
+pub fn parse_sequence(sequence: &str) -> Result<Vec<Step>> {
+    let level = |value: &str| match value.trim() {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => bail!(
+            "Invalid level '{}' in reset sequence, expected 0 or 1",
+            value
+        ),
+    };
+
+    sequence
+        .split('|')
+        .map(|step| {
+            let step = step.trim();
+            let value = step.get(1..).unwrap_or_default();
+
+            Ok(match step.chars().next() {
+                Some('D') => Step::Dtr(level(value)?),
+                Some('R') => Step::Rts(level(value)?),
+                Some('U') => {
+                    let (dtr, rts) = value
+                        .split_once(',')
+                        .ok_or_else(|| anyhow!("Invalid step '{}', expected U<dtr>,<rts>", step))?;
+
+                    Step::Both {
+                        dtr: level(dtr)?,
+                        rts: level(rts)?,
+                    }
+                }
+                Some('W') => Step::Wait(
+                    value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                        .map(Duration::from_secs_f64)
+                        .ok_or_else(|| anyhow!("Invalid step '{}', expected W<seconds>", step))?,
+                ),
+                _ => bail!(
+                    "Invalid step '{}' in reset sequence '{}', expected D, R, U or W",
+                    step,
+                    sequence
+                ),
+            })
+        })
+        .collect()
+}
+
+/// The reset strategies of the board of the PlatformIO `environment` of the project in
+/// `project_dir`, the defaults if the board has none.
+pub fn of_environment(
+    config: &Config,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+) -> ResetConfig {
+    fs::read_to_string(project_dir.as_ref().join("platformio.ini"))
+        .ok()
+        .and_then(|platformio_ini| env_option(&platformio_ini, environment, "board"))
+        .and_then(|board| config.reset.get(&board).cloned())
+        .unwrap_or_default()
+}
+
+/// The `--before` option of esptool for the reset `reset` before flashing.
+pub fn esptool_before(reset: &Reset) -> &'static str {
+    match reset {
+        Reset::Auto => "default_reset",
+        _ => "no_reset",
+    }
+}
+
+/// Tell the uploader of the `pio run -t upload` command `cmd` for `environment` not to
+/// reset the device into its bootloader again after the reset `reset`.
+///
+/// Only esptool (of the Espressif platforms) is known to be told so.
+pub fn apply_to_upload(
+    reset: &Reset,
+    project_dir: impl AsRef<Path>,
+    environment: &str,
+    cmd: &mut Command,
+) {
+    if *reset == Reset::Auto {
+        return;
+    }
+
+    let espressif = fs::read_to_string(project_dir.as_ref().join("platformio.ini"))
+        .ok()
+        .and_then(|platformio_ini| env_option(&platformio_ini, environment, "platform"))
+        .map_or(false, |platform| platform.contains("espressif"));
+
+    if espressif {
+        cmd.env("PLATFORMIO_UPLOAD_FLAGS", "--before no_reset");
+    } else {
+        warn!(
+            "The uploader of environment {} may reset the device again after its reset",
+            environment
+        );
+    }
+}
+
+/// The command resetting the device at `port` into its bootloader before flashing, none
+/// if the upload tool resets it itself or if it should not be reset.
+pub fn before_flash(pio: &Pio, reset: &Reset, port: Option<&str>) -> Result<Option<Command>> {
+    match reset {
+        Reset::Auto | Reset::NoReset => Ok(None),
+        reset => reset_cmd(pio, reset, port).map(Some),
+    }
+}
+
+/// The command resetting the device at `port` once the monitor is attached, none if it
+/// should not be reset.
+pub fn after_attach(pio: &Pio, reset: &Reset, port: Option<&str>) -> Result<Option<Command>> {
+    match reset {
+        Reset::NoReset => Ok(None),
+        Reset::Auto => reset_cmd(pio, &Reset::Sequence(HARD_RESET.into()), port).map(Some),
+        reset => reset_cmd(pio, reset, port).map(Some),
+    }
+}
+
+fn reset_cmd(pio: &Pio, reset: &Reset, port: Option<&str>) -> Result<Command> {
+    match reset {
+        Reset::Sequence(sequence) => {
+            let port = match port {
+                Some(port) if is_raw_tcp_port(port) => {
+                    bail!("The DTR/RTS lines of raw TCP bridge {} cannot be set", port)
+                }
+                Some(port) => port,
+                None => {
+                    return Err(anyhow!("The reset sequence needs the port of the device"))
+                        .hint("Specify the port of the device")
+                }
+            };
+
+            let python = inspect::which(pio, "python")
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("No Python found to run the reset sequence with"))?;
+
+            debug!("Resetting {} with sequence {}", port, sequence);
+
+            let mut cmd = Command::new(python.path);
+            cmd.arg("-c")
+                .arg(sequence_script(&parse_sequence(sequence)?))
+                .arg(serial_port_url(port));
+
+            Ok(cmd)
+        }
+        Reset::Command(command) => {
+            let mut cmd = config::shell(command);
+            if let Some(port) = port {
+                cmd.env("CARGO_PIO_PORT", port);
+            }
+
+            Ok(cmd)
+        }
+        Reset::Auto | Reset::NoReset => unreachable!(),
+    }
+}
+
+/// The Python script running `steps` on the serial port passed as its argument.
+fn sequence_script(steps: &[Step]) -> String {
+    let mut script = String::from(
+        "import sys, time, serial\n\
+         port = serial.serial_for_url(sys.argv[1])\n",
+    );
+
+    let level = |level: bool| if level { "True" } else { "False" };
+
+    for step in steps {
+        match step {
+            Step::Dtr(dtr) => script.push_str(&format!("port.dtr = {}\n", level(*dtr))),
+            // Windows only propagates a change of RTS with the next change of DTR, like
+            // esptool, set DTR again
+            Step::Rts(rts) => script.push_str(&format!(
+                "port.rts = {}\nport.dtr = port.dtr\n",
+                level(*rts)
+            )),
+            Step::Both { dtr, rts } => script.push_str(&format!(
+                "port.rts = {}\nport.dtr = {}\n",
+                level(*rts),
+                level(*dtr)
+            )),
+            Step::Wait(duration) => {
+                script.push_str(&format!("time.sleep({})\n", duration.as_secs_f64()))
+            }
+        }
+    }
+
+    script.push_str("port.close()\n");
+
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence() {
+        let steps = parse_sequence("D0|R1|W0.1|U1,0").unwrap();
+
+        assert_eq!(
+            steps,
+            [
+                Step::Dtr(false),
+                Step::Rts(true),
+                Step::Wait(Duration::from_millis(100)),
+                Step::Both {
+                    dtr: true,
+                    rts: false
+                }
+            ]
+        );
+        assert_eq!(
+            sequence_script(&steps[1..3]),
+            "import sys, time, serial\n\
+             port = serial.serial_for_url(sys.argv[1])\n\
+             port.rts = True\n\
+             port.dtr = port.dtr\n\
+             time.sleep(0.1)\n\
+             port.close()\n"
+        );
+
+        assert!(parse_sequence("D2").is_err());
+        assert!(parse_sequence("W-1").is_err());
+        assert!(parse_sequence("X1").is_err());
+        assert!(parse_sequence("U1").is_err());
+    }
+}
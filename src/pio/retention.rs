@@ -0,0 +1,250 @@
+//! The retention of the installed versions of the platforms and packages, so that the
+//! core directory of a long-lived machine stops growing with every update.
+//!
+//! PlatformIO installs the versions projects ask for side by side
+//! (`packages/<name>@<version>`) and never removes one by itself. Following the
+//! [`RetentionConfig`] of the project, [`superseded`] picks the versions beyond the
+//! newest `keep` of every platform and package, sparing the primary installations, the
+//! versions locked in the [`Lockfile`] and the recently installed ones.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use log::*;
+
+use super::config::RetentionConfig;
+use super::graph::{dir_size, format_size};
+use super::lock::{Lockfile, LOCK_FILE_NAME};
+use super::Pio;
+use crate::pio_model::compare_versions;
+
+/// An installed version of a platform or package.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Installed {
+    /// The id of the platform or package as in the [`Lockfile`], `platform:<name>` or
+    /// `package:<name>`.
+    pub id: String,
+    pub version: String,
+    pub dir: PathBuf,
+    /// The time since the installation.
+    pub age: Duration,
+}
+
+impl Installed {
+    /// Whether this is the primary installation, i.e. not one installed side by side as
+    /// `<name>@<version>`.
+    pub fn is_primary(&self) -> bool {
+        !self
+            .dir
+            .file_name()
+            .map_or(false, |name| name.to_string_lossy().contains('@'))
+    }
+}
+
+/// The installed versions of the platforms and packages in the core directory of `pio`.
+pub fn installed(pio: &Pio) -> Vec<Installed> {
+    let now = SystemTime::now();
+
+    [
+        ("platform", "platforms", "platform.json"),
+        ("package", "packages", "package.json"),
+    ]
+    .into_iter()
+    .flat_map(|(kind, dir, manifest)| {
+        fs::read_dir(pio.core_dir.join(dir))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(move |entry| {
+                let json = fs::read_to_string(entry.path().join(manifest)).ok()?;
+                let json = serde_json::from_str::<serde_json::Value>(&json).ok()?;
+
+                let age = entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .unwrap_or_default();
+
+                Some(Installed {
+                    id: format!("{}:{}", kind, json["name"].as_str()?),
+                    version: json["version"].as_str().unwrap_or_default().to_owned(),
+                    dir: entry.path(),
+                    age,
+                })
+            })
+    })
+    .collect()
+}
+
+/// The versions locked in the lockfile of the project in `project_dir`, as `(id,
+/// version)`; none if the project has no lockfile.
+pub fn pinned(project_dir: impl AsRef<Path>) -> Result<BTreeSet<(String, String)>> {
+    let path = project_dir.as_ref().join(LOCK_FILE_NAME);
+    if !path.exists() {
+        return Ok(BTreeSet::new());
+    }
+
+    Ok(Lockfile::load(path)?
+        .environments
+        .into_values()
+        .flatten()
+        .filter_map(|(id, locked)| Some((id, locked.version?)))
+        .collect())
+}
+
+/// The versions of `installed` to remove following `config`, with the `pinned`
+/// versions of the project.
+pub fn superseded<'a>(
+    installed: &'a [Installed],
+    config: &RetentionConfig,
+    pinned: &BTreeSet<(String, String)>,
+) -> Vec<&'a Installed> {
+    let keep = match config.keep {
+        Some(keep) => keep,
+        None => return Vec::new(),
+    };
+
+    let mut versions = BTreeMap::<_, Vec<_>>::new();
+    for package in installed {
+        versions.entry(&package.id).or_default().push(package);
+    }
+
+    let min_age = Duration::from_secs(config.min_age * 24 * 60 * 60);
+
+    versions
+        .into_values()
+        .flat_map(|mut versions| {
+            versions.sort_by(|a, b| compare_versions(&b.version, &a.version));
+            versions.into_iter().skip(keep)
+        })
+        .filter(|package| {
+            let is_pinned = config.keep_pinned
+                && pinned.contains(&(package.id.clone(), package.version.clone()));
+
+            !package.is_primary()
+                && !package.version.is_empty()
+                && package.age >= min_age
+                && !is_pinned
+        })
+        .collect()
+}
+
+/// Remove the superseded versions of the platforms and packages in the core directory
+/// of `pio` following `config` and the lockfile of the project in `project_dir`, or
+/// only list them if `dry_run`. Returns the removed versions.
+pub fn enforce(
+    pio: &Pio,
+    config: &RetentionConfig,
+    project_dir: impl AsRef<Path>,
+    dry_run: bool,
+) -> Result<Vec<Installed>> {
+    let installed = installed(pio);
+    let pinned = if config.keep_pinned {
+        pinned(project_dir)?
+    } else {
+        BTreeSet::new()
+    };
+
+    let superseded = superseded(&installed, config, &pinned);
+    let mut freed = 0;
+
+    for package in &superseded {
+        let size = dir_size(&package.dir).unwrap_or_default();
+        freed += size;
+
+        if dry_run {
+            info!(
+                "Would remove {}@{} ({})",
+                package.id,
+                package.version,
+                format_size(size)
+            );
+        } else {
+            info!(
+                "Removing {}@{} ({})",
+                package.id,
+                package.version,
+                format_size(size)
+            );
+
+            fs::remove_dir_all(&package.dir)
+                .with_context(|| format!("Failed to remove {}", package.dir.display()))?;
+        }
+    }
+
+    if !superseded.is_empty() {
+        info!(
+            "{} {} superseded version(s), {}",
+            if dry_run { "Found" } else { "Removed" },
+            superseded.len(),
+            format_size(freed)
+        );
+    }
+
+    Ok(superseded.into_iter().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_superseded() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let package = |dir: &str, version: &str, days: u32| Installed {
+            id: "package:toolchain-xtensa-esp32".into(),
+            version: version.into(),
+            dir: PathBuf::from("packages").join(dir),
+            age: day * days,
+        };
+
+        let installed = [
+            package("toolchain-xtensa-esp32", "8.4.0", 100),
+            package("toolchain-xtensa-esp32@11.2.0", "11.2.0", 30),
+            package("toolchain-xtensa-esp32@12.2.0", "12.2.0", 1),
+            package("toolchain-xtensa-esp32@8.2.0", "8.2.0", 200),
+            package("toolchain-xtensa-esp32@5.2.0", "5.2.0", 300),
+            Installed {
+                id: "platform:espressif32".into(),
+                version: "6.1.0".into(),
+                dir: PathBuf::from("platforms").join("espressif32@6.1.0"),
+                age: day * 300,
+            },
+        ];
+        let pinned = [(
+            "package:toolchain-xtensa-esp32".to_owned(),
+            "5.2.0".to_owned(),
+        )]
+        .into_iter()
+        .collect();
+
+        let versions = |config: &RetentionConfig| {
+            superseded(&installed, config, &pinned)
+                .iter()
+                .map(|package| package.version.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(versions(&RetentionConfig::default()).is_empty());
+
+        let mut config = RetentionConfig {
+            keep: Some(1),
+            ..Default::default()
+        };
+        // 8.4.0 is the primary installation, 5.2.0 is pinned
+        assert_eq!(versions(&config), ["11.2.0", "8.2.0"]);
+
+        config.min_age = 60;
+        assert_eq!(versions(&config), ["8.2.0"]);
+
+        config.keep_pinned = false;
+        assert_eq!(versions(&config), ["8.2.0", "5.2.0"]);
+
+        config.keep = Some(0);
+        assert_eq!(versions(&config), ["8.2.0", "5.2.0", "6.1.0"]);
+    }
+}
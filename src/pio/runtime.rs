@@ -0,0 +1,495 @@
+//! The `no_std` runtime glue of generated projects, per target family.
+//!
+//! A project without a framework has no C runtime calling into the Rust staticlib, so
+//! the crate brings its own: [`Runtime::CortexMRt`] and [`Runtime::RiscvRt`] link it with
+//! cortex-m-rt or riscv-rt, a `memory.x` with the [`Memory`] of the MCU and a linker
+//! script matching the runtime, which replaces the one of the platform. Projects with a
+//! framework get the entry points the framework calls instead, and ESP-IDF projects the
+//! `app_main` shim of esp-idf-sys.
+//!
+//! The panic handler is selected with a Cargo feature of the generated crate (e.g.
+//! `panic-halt`), the [`PanicHandler`] chosen at `cargo pio new` time being the default
+//! one.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+
+use super::Resolution;
+
+const CORTEX_M_RT_LD: &str = include_str!("resources/cortex-m-rt.ld.resource");
+const RISCV_RT_LD: &str = include_str!("resources/riscv-rt.ld.resource");
+
+/// The runtime a generated crate is linked with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Runtime {
+    /// The `setup` and `loop` entry points of Arduino.
+    Arduino,
+    /// The `app_main` entry point of ESP-IDF, linking the patches of esp-idf-sys.
+    EspIdf,
+    /// The `main` entry point of the C runtime of any other framework.
+    CMain,
+    /// cortex-m-rt, for projects without a framework on ARM Cortex-M.
+    CortexMRt,
+    /// riscv-rt, for projects without a framework on RISC-V.
+    RiscvRt,
+}
+
+impl Runtime {
+    /// The runtime for the frameworks and the Rust target of `resolution`.
+    pub fn detect(resolution: &Resolution) -> Self {
+        let has = |framework: &str| resolution.frameworks.iter().any(|f| f == framework);
+
+        if has("arduino") {
+            Self::Arduino
+        } else if has("espidf") {
+            Self::EspIdf
+        } else if !resolution.frameworks.is_empty() {
+            Self::CMain
+        } else if resolution.target.starts_with("thumb") {
+            Self::CortexMRt
+        } else if resolution.target.starts_with("riscv") {
+            Self::RiscvRt
+        } else {
+            Self::CMain
+        }
+    }
+
+    /// The panic handlers available with this runtime on `target`, the first one being
+    /// the default.
+    pub fn panic_handlers(&self, target: &str) -> Vec<PanicHandler> {
+        let mut handlers = vec![PanicHandler::Halt];
+
+        if !matches!(self, Self::CortexMRt | Self::RiscvRt) {
+            handlers.push(PanicHandler::Abort);
+        }
+
+        if target.starts_with("thumb") {
+            handlers.push(PanicHandler::Reset);
+            handlers.push(PanicHandler::Semihosting);
+        }
+
+        handlers
+    }
+}
+
+/// The panic handler of a generated crate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PanicHandler {
+    /// Loop forever.
+    Halt,
+    /// Call the `abort` of the C library.
+    Abort,
+    /// Reset the MCU (Cortex-M only).
+    Reset,
+    /// Print the panic message to the debugger with panic-semihosting (Cortex-M only).
+    Semihosting,
+}
+
+impl PanicHandler {
+    pub const ALL: [Self; 4] = [Self::Halt, Self::Abort, Self::Reset, Self::Semihosting];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Halt => "halt",
+            Self::Abort => "abort",
+            Self::Reset => "reset",
+            Self::Semihosting => "semihosting",
+        }
+    }
+
+    /// The Cargo feature selecting this panic handler.
+    pub fn feature(&self) -> String {
+        format!("panic-{}", self.name())
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Halt => "loop forever",
+            Self::Abort => "call abort() of the C library",
+            Self::Reset => "reset the MCU",
+            Self::Semihosting => "print the message to the debugger",
+        }
+    }
+}
+
+impl fmt::Display for PanicHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for PanicHandler {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Self::ALL.iter().find(|handler| handler.name() == s) {
+            Some(handler) => Ok(*handler),
+            None => bail!(
+                "Unknown panic handler '{}', expected one of {}",
+                s,
+                Self::ALL.map(|handler| handler.name()).join(", ")
+            ),
+        }
+    }
+}
+
+/// The memory regions of an MCU.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Memory {
+    pub flash_origin: u64,
+    pub flash_size: u64,
+    pub ram_origin: u64,
+    pub ram_size: u64,
+}
+
+impl Memory {
+    /// The memory of `mcu`, with the `rom` and `ram` sizes of the board in bytes.
+    ///
+    /// The origins are the ones of the usual memory map of the MCU family and need to
+    /// be checked against its reference manual for others.
+    pub fn of_mcu(mcu: &str, rom: u64, ram: u64) -> Self {
+        let mcu = mcu.to_ascii_lowercase();
+        let family = |prefixes: &[&str]| prefixes.iter().any(|prefix| mcu.starts_with(prefix));
+
+        let (flash_origin, ram_origin) = if family(&["stm32", "gd32", "at32", "ch32"]) {
+            (0x0800_0000, 0x2000_0000)
+        } else if family(&["fe310"]) {
+            (0x2040_0000, 0x8000_0000)
+        } else if family(&["rp2040"]) {
+            (0x1000_0100, 0x2000_0000)
+        } else {
+            (0x0000_0000, 0x2000_0000)
+        };
+
+        Self {
+            flash_origin,
+            flash_size: rom,
+            ram_origin,
+            ram_size: ram,
+        }
+    }
+
+    /// The `memory.x` of these regions for `runtime`.
+    pub fn memory_x(&self, mcu: &str, runtime: Runtime) -> String {
+        let size = |size: u64| {
+            if size % 1024 == 0 {
+                format!("{}K", size / 1024)
+            } else {
+                size.to_string()
+            }
+        };
+
+        let mut memory_x = format!(
+            "/* The memory of the {}, generated by cargo-pio. Check it against the reference\n   \
+             manual of the MCU. */\n\
+             MEMORY\n\
+             {{\n  \
+             FLASH : ORIGIN = {:#010x}, LENGTH = {}\n  \
+             RAM : ORIGIN = {:#010x}, LENGTH = {}\n\
+             }}\n",
+            mcu,
+            self.flash_origin,
+            size(self.flash_size),
+            self.ram_origin,
+            size(self.ram_size)
+        );
+
+        if runtime == Runtime::RiscvRt {
+            memory_x.push('\n');
+
+            for (region, memory) in [
+                ("TEXT", "FLASH"),
+                ("RODATA", "FLASH"),
+                ("DATA", "RAM"),
+                ("BSS", "RAM"),
+                ("HEAP", "RAM"),
+                ("STACK", "RAM"),
+            ] {
+                writeln!(memory_x, "REGION_ALIAS(\"REGION_{}\", {});", region, memory).unwrap();
+            }
+        }
+
+        memory_x
+    }
+}
+
+/// A dependency of a generated crate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dependency {
+    pub name: &'static str,
+    pub version: &'static str,
+    /// Whether the dependency is optional, i.e. enabled by the feature of its name.
+    pub optional: bool,
+    pub default_features: bool,
+}
+
+impl Dependency {
+    fn new(name: &'static str, version: &'static str) -> Self {
+        Self {
+            name,
+            version,
+            optional: false,
+            default_features: true,
+        }
+    }
+}
+
+/// The runtime glue of a generated crate.
+#[derive(Clone, Debug)]
+pub struct Scaffold {
+    pub runtime: Runtime,
+    pub panic_handler: PanicHandler,
+    panic_handlers: Vec<PanicHandler>,
+    target: String,
+    mcu: String,
+    memory: Option<Memory>,
+}
+
+impl Scaffold {
+    /// The scaffold for `resolution` with its default panic handler.
+    pub fn new(resolution: &Resolution) -> Self {
+        let runtime = Runtime::detect(resolution);
+        let panic_handlers = runtime.panic_handlers(&resolution.target);
+
+        Self {
+            runtime,
+            panic_handler: panic_handlers[0],
+            panic_handlers,
+            target: resolution.target.clone(),
+            mcu: resolution.mcu.clone(),
+            memory: None,
+        }
+    }
+
+    /// The panic handlers available with the runtime, the first one being the default.
+    pub fn panic_handlers(&self) -> &[PanicHandler] {
+        &self.panic_handlers
+    }
+
+    /// Select `panic_handler` as the default one.
+    pub fn panic_handler(&mut self, panic_handler: PanicHandler) -> Result<&mut Self> {
+        if !self.panic_handlers.contains(&panic_handler) {
+            bail!(
+                "The '{}' panic handler is not available for {}, expected one of {}",
+                panic_handler,
+                self.target,
+                self.panic_handlers
+                    .iter()
+                    .map(PanicHandler::name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        self.panic_handler = panic_handler;
+        Ok(self)
+    }
+
+    /// Set the memory of the MCU, used for the `memory.x` of the runtimes without a
+    /// framework.
+    pub fn memory(&mut self, memory: Memory) -> &mut Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// The dependencies of the crate.
+    pub fn dependencies(&self) -> Vec<Dependency> {
+        let mut dependencies = Vec::new();
+
+        match self.runtime {
+            Runtime::EspIdf => dependencies.push(Dependency {
+                default_features: false,
+                ..Dependency::new("esp-idf-sys", "0.31")
+            }),
+            Runtime::CortexMRt => dependencies.push(Dependency::new("cortex-m-rt", "0.7")),
+            Runtime::RiscvRt => dependencies.push(Dependency::new("riscv-rt", "0.11")),
+            _ => (),
+        }
+
+        if self.target.starts_with("thumb") {
+            dependencies.push(Dependency::new("cortex-m", "0.7"));
+            dependencies.push(Dependency {
+                optional: true,
+                ..Dependency::new("panic-semihosting", "0.6")
+            });
+        }
+
+        dependencies
+    }
+
+    /// The features of the crate, selecting the panic handler.
+    pub fn features(&self) -> BTreeMap<String, Vec<String>> {
+        let mut features: BTreeMap<_, _> = self
+            .panic_handlers
+            .iter()
+            // The optional dependency is a feature of its own
+            .filter(|handler| **handler != PanicHandler::Semihosting)
+            .map(|handler| (handler.feature(), Vec::new()))
+            .collect();
+
+        features.insert("default".into(), vec![self.panic_handler.feature()]);
+
+        features
+    }
+
+    /// The `src/lib.rs` of the crate.
+    pub fn lib_rs(&self) -> String {
+        let mut lib_rs = String::from("#![no_std]\n\n");
+
+        match self.runtime {
+            Runtime::Arduino => lib_rs.push_str(
+                "#[no_mangle]\n\
+                 extern \"C\" fn setup() {}\n\n\
+                 #[no_mangle]\n\
+                 #[export_name = \"loop\"]\n\
+                 extern \"C\" fn arduino_loop() {}\n",
+            ),
+            Runtime::EspIdf => lib_rs.push_str(
+                "#[no_mangle]\n\
+                 extern \"C\" fn app_main() {\n    \
+                 // Keeps the patches of esp-idf-sys for ESP-IDF from being removed by the linker\n    \
+                 esp_idf_sys::link_patches();\n\
+                 }\n",
+            ),
+            Runtime::CMain => lib_rs.push_str(
+                "#[no_mangle]\n\
+                 extern \"C\" fn main() -> i32 {\n    \
+                 0\n\
+                 }\n",
+            ),
+            Runtime::CortexMRt => lib_rs.push_str(
+                "use cortex_m_rt::entry;\n\n\
+                 #[entry]\n\
+                 fn main() -> ! {\n    \
+                 loop {\n        \
+                 cortex_m::asm::wfi();\n    \
+                 }\n\
+                 }\n",
+            ),
+            Runtime::RiscvRt => lib_rs.push_str(
+                "use riscv_rt::entry;\n\n\
+                 #[entry]\n\
+                 fn main() -> ! {\n    \
+                 loop {\n        \
+                 core::hint::spin_loop();\n    \
+                 }\n\
+                 }\n",
+            ),
+        }
+
+        lib_rs.push_str(
+            "\n// The panic handler is selected with the `panic-*` features, enable only one\n",
+        );
+
+        for handler in &self.panic_handlers {
+            let body = match handler {
+                PanicHandler::Halt => "    loop {}\n",
+                PanicHandler::Abort => {
+                    "    extern \"C\" {\n        \
+                     fn abort() -> !;\n    \
+                     }\n\n    \
+                     unsafe { abort() }\n"
+                }
+                PanicHandler::Reset => "    cortex_m::peripheral::SCB::sys_reset()\n",
+                PanicHandler::Semihosting => {
+                    lib_rs.push_str(
+                        "\n#[cfg(feature = \"panic-semihosting\")]\n\
+                         use panic_semihosting as _;\n",
+                    );
+                    continue;
+                }
+            };
+
+            write!(
+                lib_rs,
+                "\n#[cfg(feature = \"{}\")]\n\
+                 #[panic_handler]\n\
+                 fn panic(_info: &core::panic::PanicInfo) -> ! {{\n{}}}\n",
+                handler.feature(),
+                body
+            )
+            .unwrap();
+        }
+
+        lib_rs
+    }
+
+    /// The linker files of the crate as `(file name, content)`, written into the project
+    /// directory.
+    pub fn linker_files(&self) -> Vec<(&'static str, String)> {
+        let script = match self.runtime {
+            Runtime::CortexMRt => CORTEX_M_RT_LD,
+            Runtime::RiscvRt => RISCV_RT_LD,
+            _ => return Vec::new(),
+        };
+
+        let memory = self
+            .memory
+            .unwrap_or_else(|| Memory::of_mcu(&self.mcu, 0, 0));
+
+        vec![
+            ("memory.x", memory.memory_x(&self.mcu, self.runtime)),
+            (self.ldscript().unwrap(), script.to_owned()),
+        ]
+    }
+
+    /// The linker script replacing the one of the platform, if any.
+    pub fn ldscript(&self) -> Option<&'static str> {
+        match self.runtime {
+            Runtime::CortexMRt => Some("cortex-m-rt.ld"),
+            Runtime::RiscvRt => Some("riscv-rt.ld"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cortex_m_scaffold() {
+        let resolution = Resolution {
+            board: "nucleo_f401re".into(),
+            mcu: "STM32F401RET6".into(),
+            platform: "ststm32".into(),
+            frameworks: Vec::new(),
+            target: "thumbv7em-none-eabihf".into(),
+        };
+
+        let mut scaffold = Scaffold::new(&resolution);
+        assert_eq!(scaffold.runtime, Runtime::CortexMRt);
+        assert!(scaffold.panic_handler(PanicHandler::Abort).is_err());
+
+        scaffold
+            .panic_handler(PanicHandler::Semihosting)
+            .unwrap()
+            .memory(Memory::of_mcu(&resolution.mcu, 512 * 1024, 96 * 1024));
+
+        assert_eq!(
+            scaffold.features().into_iter().collect::<Vec<_>>(),
+            [
+                ("default".to_owned(), vec!["panic-semihosting".to_owned()]),
+                ("panic-halt".to_owned(), vec![]),
+                ("panic-reset".to_owned(), vec![]),
+            ]
+        );
+
+        let lib_rs = scaffold.lib_rs();
+        assert!(lib_rs.contains("#[entry]"));
+        assert!(lib_rs.contains("#[cfg(feature = \"panic-reset\")]"));
+        assert!(lib_rs.contains("use panic_semihosting as _;"));
+
+        let files = scaffold.linker_files();
+        assert_eq!(files[1].0, "cortex-m-rt.ld");
+        assert!(files[0]
+            .1
+            .contains("FLASH : ORIGIN = 0x08000000, LENGTH = 512K"));
+        assert!(files[0]
+            .1
+            .contains("RAM : ORIGIN = 0x20000000, LENGTH = 96K"));
+    }
+}
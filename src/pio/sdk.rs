@@ -0,0 +1,451 @@
+//! Vendor SDKs (NXP MCUXpresso SDKs, STM32Cube MCU packages) installed as libraries of
+//! a project.
+//!
+//! Vendor SDK archives spread the HAL over many directories and ship far more than a
+//! firmware links (examples, middleware, docs), in a layout which differs between
+//! vendors and releases. [`install`] unpacks the archive of an [`SdkConfig`] and
+//! normalizes the HAL into the PlatformIO library `lib/<name>` of the project:
+//! - `src/`: the sources of the peripheral drivers and the device system file;
+//! - `include/`: the headers of the drivers, the device and CMSIS;
+//! - `library.json`, with the version of the SDK.
+//!
+//! PlatformIO then builds the library with the environment's compiler and links it into
+//! the firmware together with the Rust staticlib, like any other library.
+//!
+//! STM32Cube HALs need a `stm32<family>xx_hal_conf.h`; the template of the HAL is
+//! installed as one unless the project provides its own in `include/`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+
+use super::config::{SdkConfig, SdkVendor};
+use super::hash::{self, Algorithm};
+use crate::utils::HttpClient;
+
+/// The directory (relative to the project directory) downloads and unpacked archives
+/// are kept in.
+pub const SDK_DIR: &str = ".cargo-pio/sdk";
+
+/// The file in an installed SDK library which marks it as generated, with the archive
+/// it was installed from.
+const MARKER_FILE: &str = ".cargo-pio-sdk";
+
+/// The files of an unpacked SDK which make up its library.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Layout {
+    pub version: Option<String>,
+    /// The source files.
+    pub sources: Vec<PathBuf>,
+    /// The directories whose headers are included.
+    pub include_dirs: Vec<PathBuf>,
+    /// Additional headers, with the name to install them as.
+    pub headers: Vec<(PathBuf, String)>,
+}
+
+/// Find the library files of the SDK of `vendor` unpacked into `root`.
+///
+/// `device` selects the device of MCUXpresso SDKs with more than one.
+pub fn layout(vendor: SdkVendor, root: &Path, device: Option<&str>) -> Result<Layout> {
+    match vendor {
+        SdkVendor::Stm32cube => stm32cube(root),
+        SdkVendor::Mcuxpresso => mcuxpresso(root, device),
+    }
+}
+
+fn stm32cube(root: &Path) -> Result<Layout> {
+    let drivers = root.join("Drivers");
+
+    let hal = subdirs(&drivers)
+        .into_iter()
+        .find(|dir| file_name(dir).ends_with("_HAL_Driver"))
+        .ok_or_else(|| anyhow!("No HAL driver in '{}'", drivers.display()))?;
+    let family = file_name(&hal)
+        .trim_end_matches("_HAL_Driver")
+        .to_lowercase();
+
+    let cmsis = drivers.join("CMSIS");
+    let device = subdirs(&cmsis.join("Device").join("ST"))
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No CMSIS device in '{}'", cmsis.display()))?;
+
+    let mut layout = Layout {
+        version: fs::read_to_string(root.join("package.xml"))
+            .ok()
+            .and_then(|package| attribute(&package, "Release"))
+            // `FW.F4.1.27.1`
+            .map(|release| {
+                release
+                    .split('.')
+                    .skip_while(|part| !part.chars().all(|c| c.is_ascii_digit()))
+                    .collect::<Vec<_>>()
+                    .join(".")
+            }),
+        sources: files(&hal.join("Src"), "c")
+            .into_iter()
+            .filter(|source| !file_name(source).ends_with("_template.c"))
+            .collect(),
+        include_dirs: vec![
+            hal.join("Inc"),
+            device.join("Include"),
+            cmsis_include(&cmsis),
+        ],
+        headers: Vec::new(),
+    };
+
+    let template = hal
+        .join("Inc")
+        .join(format!("{}_hal_conf_template.h", family));
+    if template.exists() {
+        layout
+            .headers
+            .push((template, format!("{}_hal_conf.h", family)));
+    }
+
+    Ok(layout)
+}
+
+fn mcuxpresso(root: &Path, device: Option<&str>) -> Result<Layout> {
+    let devices = subdirs(&root.join("devices"));
+
+    let device = match device {
+        Some(device) => devices
+            .into_iter()
+            .find(|dir| file_name(dir) == device)
+            .ok_or_else(|| anyhow!("No device '{}' in the SDK", device))?,
+        None if devices.len() == 1 => devices.into_iter().next().unwrap(),
+        None => bail!(
+            "The SDK has {} devices, select one with `device`: {}",
+            devices.len(),
+            devices
+                .iter()
+                .map(|dir| file_name(dir))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let name = file_name(&device);
+
+    let version = fs::read_dir(root)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| {
+            let file_name = file_name(path);
+            file_name.contains("_manifest") && file_name.ends_with(".xml")
+        })
+        .and_then(|manifest| fs::read_to_string(manifest).ok())
+        .and_then(|manifest| {
+            manifest
+                .find("<ksdk")
+                .and_then(|start| attribute(&manifest[start..], "version"))
+        });
+
+    let mut sources = files(&device.join("drivers"), "c");
+    sources.extend(
+        files(&device, "c")
+            .into_iter()
+            .filter(|source| file_name(source) == format!("system_{}.c", name)),
+    );
+
+    Ok(Layout {
+        version,
+        sources,
+        include_dirs: vec![
+            device.join("drivers"),
+            device.clone(),
+            cmsis_include(&root.join("CMSIS")),
+        ],
+        headers: Vec::new(),
+    })
+}
+
+/// The core include directory of `cmsis`: `Core/Include` of CMSIS 5.6 and later,
+/// `Include` before.
+fn cmsis_include(cmsis: &Path) -> PathBuf {
+    let core = cmsis.join("Core").join("Include");
+
+    if core.exists() {
+        core
+    } else {
+        cmsis.join("Include")
+    }
+}
+
+/// The value of the first attribute `name` in `xml`.
+fn attribute(xml: &str, name: &str) -> Option<String> {
+    let prefix = format!("{}=\"", name);
+    let start = xml.find(&prefix)? + prefix.len();
+    let len = xml[start..].find('"')?;
+
+    Some(xml[start..start + len].to_owned())
+}
+
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+
+    dirs.sort();
+    dirs
+}
+
+/// The files with `extension` in `dir`, not recursing.
+fn files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().map_or(false, |ext| ext == extension))
+        .collect::<Vec<_>>();
+
+    files.sort();
+    files
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Install the SDKs of `sdks` into the project in `project_dir`, downloading archives
+/// with `client`; SDKs installed from the same archive before are skipped unless
+/// `force`.
+pub fn install_all(
+    project_dir: impl AsRef<Path>,
+    sdks: &[SdkConfig],
+    client: Option<&dyn HttpClient>,
+    force: bool,
+) -> Result<()> {
+    for sdk in sdks {
+        install(project_dir.as_ref(), sdk, client, force)
+            .with_context(|| format!("Failed to install the SDK '{}'", sdk.name))?;
+    }
+
+    Ok(())
+}
+
+/// Install the SDK `sdk` as the library `lib/<name>` of the project in `project_dir`,
+/// returning the directory of the library.
+pub fn install(
+    project_dir: impl AsRef<Path>,
+    sdk: &SdkConfig,
+    client: Option<&dyn HttpClient>,
+    force: bool,
+) -> Result<PathBuf> {
+    let project_dir = project_dir.as_ref();
+    let library = project_dir.join("lib").join(&sdk.name);
+
+    let pin = format!(
+        "{}\n{}\n",
+        sdk.archive,
+        sdk.sha256.as_deref().unwrap_or_default()
+    );
+
+    match fs::read_to_string(library.join(MARKER_FILE)) {
+        Ok(installed) if installed == pin && !force => return Ok(library),
+        Ok(_) => (),
+        Err(_) if library.exists() => bail!(
+            "'{}' exists, but was not installed by cargo-pio",
+            library.display()
+        ),
+        Err(_) => (),
+    }
+
+    let sdk_dir = project_dir.join(SDK_DIR);
+    fs::create_dir_all(&sdk_dir)?;
+
+    let archive = archive(project_dir, &sdk_dir, sdk, client)?;
+
+    if let Some(expected) = &sdk.sha256 {
+        let actual = hash::hex(&hash::file(Algorithm::Sha256, &archive, &mut |_, _| ())?);
+        if !actual.eq_ignore_ascii_case(expected) {
+            bail!(
+                "The SHA-256 checksum of '{}' is {}, expected {}",
+                archive.display(),
+                actual,
+                expected
+            );
+        }
+    }
+
+    info!("Unpacking {}", archive.display());
+
+    let unpacked = sdk_dir.join(&sdk.name);
+    if unpacked.exists() {
+        crate::fs::remove_dir_all(&unpacked)?;
+    }
+
+    super::extract(&archive, &unpacked)?;
+
+    // Archives of releases usually have a single top-level directory
+    let entries = fs::read_dir(&unpacked)?.flatten().collect::<Vec<_>>();
+    let root = match entries.as_slice() {
+        [entry] if entry.path().is_dir() => entry.path(),
+        _ => unpacked.clone(),
+    };
+
+    let layout = layout(sdk.vendor, &root, sdk.device.as_deref())?;
+
+    normalize(&layout, &library, &sdk.name, project_dir)?;
+    crate::fs::write_atomic(library.join(MARKER_FILE), pin)?;
+
+    crate::fs::remove_dir_all(&unpacked)?;
+
+    info!(
+        "Installed {} {} ({} sources) as {}",
+        sdk.name,
+        layout.version.as_deref().unwrap_or("(unknown version)"),
+        layout.sources.len(),
+        library.display()
+    );
+
+    Ok(library)
+}
+
+/// The archive of `sdk`, downloaded into `sdk_dir` if it is a URL.
+fn archive(
+    project_dir: &Path,
+    sdk_dir: &Path,
+    sdk: &SdkConfig,
+    client: Option<&dyn HttpClient>,
+) -> Result<PathBuf> {
+    if !(sdk.archive.starts_with("http://") || sdk.archive.starts_with("https://")) {
+        return Ok(project_dir.join(&sdk.archive));
+    }
+
+    let archive = sdk_dir.join(
+        sdk.archive
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&sdk.name),
+    );
+
+    if !archive.exists() {
+        let client = client.ok_or_else(|| {
+            anyhow!(
+                "Downloading '{}' needs an HTTP client, download it and set `archive` to its path",
+                sdk.archive
+            )
+        })?;
+
+        info!("Downloading {}", sdk.archive);
+
+        let partial = archive.with_extension("part");
+        let mut file = fs::File::create(&partial)?;
+        client.download(&sdk.archive, &mut file)?;
+        file.sync_all()?;
+
+        crate::fs::rename_durable(partial, &archive)?;
+    }
+
+    Ok(archive)
+}
+
+/// Copy the files of `layout` into the library `library`.
+fn normalize(layout: &Layout, library: &Path, name: &str, project_dir: &Path) -> Result<()> {
+    if library.exists() {
+        crate::fs::remove_dir_all(library)?;
+    }
+
+    let src = library.join("src");
+    let include = library.join("include");
+    fs::create_dir_all(&src)?;
+    fs::create_dir_all(&include)?;
+
+    for source in &layout.sources {
+        crate::fs::copy_with_metadata(source, src.join(file_name(source)))?;
+    }
+
+    for dir in &layout.include_dirs {
+        for header in files(dir, "h") {
+            crate::fs::copy_with_metadata(&header, include.join(file_name(&header)))?;
+        }
+    }
+
+    for (header, name) in &layout.headers {
+        if !project_dir.join("include").join(name).exists() {
+            crate::fs::copy_with_metadata(header, include.join(name))?;
+        }
+    }
+
+    fs::write(
+        library.join("library.json"),
+        serde_json::to_string_pretty(&serde_json::json!({
+            "name": name,
+            "version": layout.version.as_deref().unwrap_or("0.0.0"),
+            "description": "Vendor SDK installed by cargo-pio",
+            "build": {
+                "srcDir": "src",
+                "includeDir": "include",
+            },
+        }))?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stm32cube_layout() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+
+        let hal = root.join("Drivers").join("STM32F4xx_HAL_Driver");
+        let device = root
+            .join("Drivers")
+            .join("CMSIS")
+            .join("Device")
+            .join("ST")
+            .join("STM32F4xx");
+        for dir in [hal.join("Src"), hal.join("Inc"), device.join("Include")] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        for file in [
+            hal.join("Src").join("stm32f4xx_hal.c"),
+            hal.join("Src").join("stm32f4xx_hal_msp_template.c"),
+            hal.join("Inc").join("stm32f4xx_hal_conf_template.h"),
+        ] {
+            fs::write(file, "").unwrap();
+        }
+        fs::write(
+            root.join("package.xml"),
+            "<Package><PackDescription Release=\"FW.F4.1.27.1\" /></Package>",
+        )
+        .unwrap();
+
+        let layout = layout(SdkVendor::Stm32cube, root, None).unwrap();
+
+        assert_eq!(layout.version.as_deref(), Some("1.27.1"));
+        assert_eq!(layout.sources, [hal.join("Src").join("stm32f4xx_hal.c")]);
+        assert_eq!(
+            layout.include_dirs,
+            [
+                hal.join("Inc"),
+                device.join("Include"),
+                root.join("Drivers").join("CMSIS").join("Include")
+            ]
+        );
+        assert_eq!(
+            layout.headers,
+            [(
+                hal.join("Inc").join("stm32f4xx_hal_conf_template.h"),
+                "stm32f4xx_hal_conf.h".to_owned()
+            )]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! The first-run setup of `cargo pio setup`.
+//!
+//! Instead of a checklist of prerequisites, the setup checks the host for the tools
+//! cargo-pio relies on, installs the PlatformIO platforms and Rust targets of the
+//! [`Ecosystem`]s the user targets, and verifies each of them with a hello-world build
+//! of their reference board.
+
+use std::fmt::{self, Display};
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+use log::*;
+
+use super::Pio;
+
+/// A family of chips with its PlatformIO platform and Rust targets.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Ecosystem {
+    Esp32,
+    Stm32,
+    Nrf,
+    Rp2040,
+}
+
+impl Ecosystem {
+    pub const ALL: [Self; 4] = [Self::Esp32, Self::Stm32, Self::Nrf, Self::Rp2040];
+
+    /// The PlatformIO platform of the chips.
+    pub fn platform(&self) -> &'static str {
+        match self {
+            Self::Esp32 => "espressif32",
+            Self::Stm32 => "ststm32",
+            Self::Nrf => "nordicnrf52",
+            Self::Rp2040 => "raspberrypi",
+        }
+    }
+
+    /// The board of the hello-world build.
+    pub fn board(&self) -> &'static str {
+        match self {
+            Self::Esp32 => "esp32dev",
+            Self::Stm32 => "nucleo_f401re",
+            Self::Nrf => "nrf52840_dk",
+            Self::Rp2040 => "pico",
+        }
+    }
+
+    /// The Rust targets installed with rustup, none for the Xtensa targets of the ESP32
+    /// which come with the `esp` toolchain instead.
+    pub fn rust_targets(&self) -> &'static [&'static str] {
+        match self {
+            Self::Esp32 => &[],
+            Self::Stm32 | Self::Nrf => &["thumbv7em-none-eabihf"],
+            Self::Rp2040 => &["thumbv6m-none-eabi"],
+        }
+    }
+}
+
+impl FromStr for Ecosystem {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "esp32" | "esp" => Ok(Self::Esp32),
+            "stm32" | "stm" => Ok(Self::Stm32),
+            "nrf" | "nrf52" => Ok(Self::Nrf),
+            "rp2040" | "rp" => Ok(Self::Rp2040),
+            _ => bail!(
+                "Unknown ecosystem '{}', expected esp32, stm32, nrf or rp2040",
+                s
+            ),
+        }
+    }
+}
+
+impl Display for Ecosystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Esp32 => "ESP32",
+            Self::Stm32 => "STM32",
+            Self::Nrf => "nRF",
+            Self::Rp2040 => "RP2040",
+        })
+    }
+}
+
+/// Parse the answer to the question which ecosystems to set up: their numbers in
+/// [`Ecosystem::ALL`] (from 1) or names, separated by commas or spaces, or `all`.
+pub fn parse_selection(answer: &str) -> Result<Vec<Ecosystem>> {
+    let mut ecosystems = Vec::new();
+
+    for choice in answer
+        .split([',', ' '])
+        .map(str::trim)
+        .filter(|choice| !choice.is_empty())
+    {
+        if choice == "all" {
+            return Ok(Ecosystem::ALL.to_vec());
+        }
+
+        let ecosystem = match choice.parse::<usize>() {
+            Ok(number) if (1..=Ecosystem::ALL.len()).contains(&number) => {
+                Ecosystem::ALL[number - 1]
+            }
+            Ok(number) => bail!("No ecosystem {}", number),
+            Err(_) => choice.parse()?,
+        };
+
+        if !ecosystems.contains(&ecosystem) {
+            ecosystems.push(ecosystem);
+        }
+    }
+
+    if ecosystems.is_empty() {
+        bail!("No ecosystem selected");
+    }
+
+    ecosystems.sort();
+
+    Ok(ecosystems)
+}
+
+/// The host, as `<os> <arch>`.
+pub fn host() -> String {
+    format!("{} {}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// The tools missing on the host for `ecosystems`, with how to install them.
+pub fn missing_prerequisites(ecosystems: &[Ecosystem]) -> Vec<(&'static str, &'static str)> {
+    let mut missing = Vec::new();
+
+    let python = if cfg!(windows) { "python" } else { "python3" };
+    if which::which(python).is_err() {
+        missing.push((python, "PlatformIO needs Python 3.6 or newer"));
+    }
+
+    if which::which("git").is_err() {
+        missing.push(("git", "Git dependencies and ESP-IDF components need git"));
+    }
+
+    if which::which("rustup").is_err() {
+        missing.push(("rustup", "Install Rust with rustup from https://rustup.rs"));
+    } else if ecosystems.contains(&Ecosystem::Esp32) && !has_esp_toolchain() {
+        missing.push((
+            "esp toolchain",
+            "Xtensa chips need it, install it with 'cargo install espup && espup install'",
+        ));
+    }
+
+    missing
+}
+
+fn has_esp_toolchain() -> bool {
+    Command::new("rustup")
+        .args(["toolchain", "list"])
+        .output()
+        .map_or(false, |output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|toolchain| toolchain.starts_with("esp"))
+        })
+}
+
+/// Install the PlatformIO platforms and Rust targets of `ecosystems`, one step after
+/// the other.
+pub fn install(pio: &Pio, ecosystems: &[Ecosystem]) -> Result<()> {
+    let mut targets = ecosystems
+        .iter()
+        .flat_map(Ecosystem::rust_targets)
+        .copied()
+        .collect::<Vec<_>>();
+    targets.sort_unstable();
+    targets.dedup();
+
+    let steps = ecosystems.len() + targets.len();
+    let mut step = 0;
+
+    for ecosystem in ecosystems {
+        step += 1;
+        info!(
+            "[{}/{}] Installing platform {} for {}",
+            step,
+            steps,
+            ecosystem.platform(),
+            ecosystem
+        );
+
+        let mut cmd = pio.cmd();
+        cmd.args(["pkg", "install", "-g", "-p", ecosystem.platform()]);
+
+        pio.exec(&mut cmd)?;
+    }
+
+    for target in targets {
+        step += 1;
+        info!("[{}/{}] Installing Rust target {}", step, steps, target);
+
+        let status = Command::new("rustup")
+            .args(["target", "add", target])
+            .status()?;
+        if !status.success() {
+            bail!("Failed to install Rust target {}: {}", target, status);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_selection() {
+        assert_eq!(
+            parse_selection("3, 1").unwrap(),
+            [Ecosystem::Esp32, Ecosystem::Nrf]
+        );
+        assert_eq!(
+            parse_selection("rp2040 STM32 stm32").unwrap(),
+            [Ecosystem::Stm32, Ecosystem::Rp2040]
+        );
+        assert_eq!(parse_selection("all").unwrap(), Ecosystem::ALL);
+        assert!(parse_selection("5").is_err());
+        assert!(parse_selection("avr").is_err());
+        assert!(parse_selection(" ").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Version stamping of the firmware of PIO->Cargo projects.
+//!
+//! The version of the Cargo package, the output of `git describe`, the build timestamp
+//! and the Cargo profile are passed to the build as C defines (via
+//! `PLATFORMIO_BUILD_FLAGS`) and/or written into a generated Rust source file with
+//! constants, as configured in the `[stamp]` section of `cargo-pio.toml`.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::*;
+
+use super::config::{Config, StampConfig};
+
+/// The version information stamped into the firmware.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stamp {
+    /// The version of the Cargo package, `None` if the project has no Cargo package.
+    pub version: Option<String>,
+    /// The output of `git describe --always --dirty --tags`, `unknown` outside of git
+    /// repositories.
+    pub git_describe: String,
+    /// The build time in seconds since the Unix epoch (or `SOURCE_DATE_EPOCH`, if set),
+    /// `None` if disabled.
+    pub timestamp: Option<u64>,
+    /// The Cargo profile the firmware is built with.
+    pub profile: String,
+}
+
+impl Stamp {
+    /// Collect the stamp of the PlatformIO `environment` of the project in `project_dir`.
+    pub fn collect(
+        config: &Config,
+        project_dir: impl AsRef<Path>,
+        environment: &str,
+        timestamp: bool,
+    ) -> Result<Self> {
+        let project_dir = project_dir.as_ref();
+
+        let git_describe = git_describe(project_dir).unwrap_or_else(|| {
+            warn!("git describe failed, the firmware is stamped with version 'unknown'");
+            "unknown".into()
+        });
+
+        let timestamp = if timestamp {
+            Some(match std::env::var("SOURCE_DATE_EPOCH") {
+                Ok(epoch) => epoch.trim().parse()?,
+                Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            })
+        } else {
+            None
+        };
+
+        // The same fallback as in `platformio.cargo.py`
+        let profile = match config.env(environment).and_then(|env| env.profile.clone()) {
+            Some(profile) => profile,
+            None => {
+                let platformio_ini = fs::read_to_string(project_dir.join("platformio.ini"))?;
+
+                match build_type(&platformio_ini, environment).as_deref() {
+                    Some("release") | None => "release".into(),
+                    Some(_) => "debug".into(),
+                }
+            }
+        };
+
+        Ok(Self {
+            version: super::release::cargo_package(project_dir)
+                .ok()
+                .map(|(_, version)| version),
+            git_describe,
+            timestamp,
+            profile,
+        })
+    }
+
+    /// The C defines of the stamp, as compiler flags.
+    pub fn c_defines(&self) -> Vec<String> {
+        let mut defines = vec![
+            format!(
+                "'-DCARGO_PIO_GIT_DESCRIBE=\"{}\"'",
+                escape(&self.git_describe)
+            ),
+            format!("'-DCARGO_PIO_PROFILE=\"{}\"'", escape(&self.profile)),
+        ];
+
+        if let Some(version) = &self.version {
+            defines.push(format!("'-DCARGO_PIO_VERSION=\"{}\"'", escape(version)));
+        }
+
+        if let Some(timestamp) = self.timestamp {
+            defines.push(format!("-DCARGO_PIO_BUILD_TIMESTAMP={}ULL", timestamp));
+        }
+
+        defines
+    }
+
+    /// The Rust source of the stamp, as constants.
+    pub fn rust_source(&self) -> String {
+        let mut source = format!(
+            "// Generated by cargo-pio, do not edit\n\n\
+             pub const GIT_DESCRIBE: &str = {:?};\n\
+             pub const PROFILE: &str = {:?};\n",
+            self.git_describe, self.profile
+        );
+
+        if let Some(version) = &self.version {
+            source.push_str(&format!("pub const VERSION: &str = {:?};\n", version));
+        }
+
+        if let Some(timestamp) = self.timestamp {
+            source.push_str(&format!(
+                "pub const BUILD_TIMESTAMP: u64 = {};\n",
+                timestamp
+            ));
+        }
+
+        source
+    }
+
+    /// Apply the stamp to the `pio run` command `cmd` of the project in `project_dir`,
+    /// as configured in `stamp_config`.
+    pub fn apply(
+        &self,
+        stamp_config: &StampConfig,
+        project_dir: impl AsRef<Path>,
+        cmd: &mut Command,
+    ) -> Result<()> {
+        info!("Stamping the firmware with version {}", self.git_describe);
+
+        if let Some(rust) = &stamp_config.rust {
+            let path = project_dir.as_ref().join(rust);
+            let source = self.rust_source();
+
+            // Rewriting an unchanged file would trigger a rebuild of the crate
+            if fs::read_to_string(&path).ok().as_deref() != Some(source.as_str()) {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                crate::fs::write_atomic(&path, source)?;
+            }
+        }
+
+        if stamp_config.defines {
+            super::append_build_flags(cmd, self.c_defines());
+        }
+
+        Ok(())
+    }
+}
+
+/// The output of `git describe --always --dirty --tags` in `project_dir`, `None` outside
+/// of git repositories.
+pub fn git_describe(project_dir: &Path) -> Option<String> {
+    Command::new("git")
+        .current_dir(project_dir)
+        .args(["describe", "--always", "--dirty", "--tags"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// The `build_type` of `environment` in `platformio_ini`, falling back to the common
+/// `[env]` section.
+fn build_type(platformio_ini: &str, environment: &str) -> Option<String> {
+    let env_section = format!("[env:{}]", environment);
+
+    let mut section = "";
+    let mut common = None;
+
+    for line in platformio_ini.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+            continue;
+        }
+
+        let value = line.split_once('=').and_then(|(key, value)| {
+            (key.trim() == "build_type").then(|| {
+                value
+                    .split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_owned()
+            })
+        });
+
+        if let Some(value) = value {
+            if section == env_section {
+                return Some(value);
+            } else if section == "[env]" {
+                common = Some(value);
+            }
+        }
+    }
+
+    common
+}
+
+/// Make `value` safe for a quoted C string literal within a quoted build flag.
+pub(crate) fn escape(value: &str) -> String {
+    value.replace(['"', '\'', '\\'], "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_type() {
+        let ini = "[env]\nbuild_type = debug\n\n[env:release]\nbuild_type = release ; optimized\n\n[env:debug]\n";
+
+        assert_eq!(build_type(ini, "release").as_deref(), Some("release"));
+        assert_eq!(build_type(ini, "debug").as_deref(), Some("debug"));
+        assert_eq!(build_type("[env:debug]\n", "debug"), None);
+    }
+}
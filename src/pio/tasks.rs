@@ -0,0 +1,191 @@
+//! The named tasks of a project, configured in the `[tasks]` of `cargo-pio.toml` (see
+//! [`TaskConfig`]) and run by `cargo pio run-task <name>`.
+//!
+//! A task is a sequence of cargo-pio commands and shell commands, run in the same
+//! environment as the [hooks](super::config::Hooks): PlatformIO on the `PATH`, its core
+//! directory and the Cargo settings of the environment of the task. This replaces the
+//! Makefiles and scripts which otherwise duplicate the setup of that environment.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Result};
+use log::*;
+
+use super::config::{self, Config, TaskConfig, TaskStep};
+use super::Pio;
+use crate::error::HintExt;
+
+/// The tasks to run for the task `name`, its dependencies first and every task once.
+pub fn order<'a>(config: &'a Config, name: &'a str) -> Result<Vec<(&'a str, &'a TaskConfig)>> {
+    fn visit<'a>(
+        config: &'a Config,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        order: &mut Vec<(&'a str, &'a TaskConfig)>,
+    ) -> Result<()> {
+        if order.iter().any(|(done, _)| *done == name) {
+            return Ok(());
+        }
+
+        if path.contains(&name) {
+            bail!(
+                "The tasks depend on each other: {} -> {}",
+                path.join(" -> "),
+                name
+            );
+        }
+
+        let task = config.tasks.get(name).ok_or_else(|| {
+            anyhow!(
+                "Unknown task '{}'{}",
+                name,
+                path.last()
+                    .map(|parent| format!(" (a dependency of '{}')", parent))
+                    .unwrap_or_default()
+            )
+        });
+        let task = task.with_hint(|| {
+            let names = config.tasks.keys().cloned().collect::<Vec<_>>();
+
+            if names.is_empty() {
+                format!("Add it as [tasks.{}] to {}", name, config::CONFIG_FILE_NAME)
+            } else {
+                format!("The tasks are {}", names.join(", "))
+            }
+        })?;
+
+        path.push(name);
+        for dependency in &task.depends {
+            visit(config, dependency, path, order)?;
+        }
+        path.pop();
+
+        order.push((name, task));
+
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    visit(config, name, &mut Vec::new(), &mut order)?;
+
+    Ok(order)
+}
+
+/// Run the task `name` of the project in `project_dir` with its dependencies.
+pub fn run(pio: &Pio, config: &Config, project_dir: impl AsRef<Path>, name: &str) -> Result<()> {
+    let project_dir = project_dir.as_ref();
+
+    for (name, task) in order(config, name)? {
+        let environment = task.environment.as_deref().unwrap_or("debug");
+
+        info!("Running task {} (environment {})", name, environment);
+
+        for step in &task.steps {
+            let mut cmd = command(step)?;
+            cmd.env("CARGO_PIO_TASK", name).envs(&task.env);
+            config.apply_tool_env(&mut cmd, pio, project_dir, environment)?;
+
+            debug!("Running task step: {:?}", cmd);
+
+            let status = cmd.status()?;
+
+            if !status.success() {
+                bail!(
+                    "Step '{}' of task {} failed with {}",
+                    describe(step),
+                    name,
+                    status
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The tasks of `config` with their descriptions.
+pub fn list(config: &Config) -> Vec<(&str, &str)> {
+    config
+        .tasks
+        .iter()
+        .map(|(name, task)| (name.as_str(), task.description.as_deref().unwrap_or("")))
+        .collect()
+}
+
+fn command(step: &TaskStep) -> Result<Command> {
+    Ok(match step {
+        TaskStep::CargoPio(args) => {
+            let mut cmd = Command::new(std::env::current_exe()?);
+            cmd.args(args);
+            cmd
+        }
+        TaskStep::Run(command) => config::shell(command),
+    })
+}
+
+fn describe(step: &TaskStep) -> String {
+    match step {
+        TaskStep::CargoPio(args) => format!("cargo pio {}", args.join(" ")),
+        TaskStep::Run(command) => command.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [tasks.flash-all]
+            depends = ["assets", "check"]
+            steps = [
+                { cargo-pio = ["build", "-e", "release"] },
+                { run = "python scripts/stamp.py" },
+            ]
+
+            [tasks.check]
+            depends = ["assets"]
+
+            [tasks.assets]
+            steps = [{ run = "make assets" }]
+
+            [tasks.loop]
+            depends = ["loop2"]
+
+            [tasks.loop2]
+            depends = ["loop"]
+
+            [tasks.broken]
+            depends = ["nothing"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.tasks["flash-all"].steps,
+            [
+                TaskStep::CargoPio(vec!["build".into(), "-e".into(), "release".into()]),
+                TaskStep::Run("python scripts/stamp.py".into()),
+            ]
+        );
+
+        let names = |name| {
+            order(&config, name)
+                .map(|order| order.into_iter().map(|(name, _)| name).collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            names("flash-all").unwrap(),
+            ["assets", "check", "flash-all"]
+        );
+        assert!(names("loop")
+            .unwrap_err()
+            .to_string()
+            .contains("loop -> loop2 -> loop"));
+        assert!(names("unknown").is_err());
+        assert!(format!("{:#}", names("broken").unwrap_err()).contains("a dependency of 'broken'"));
+    }
+}
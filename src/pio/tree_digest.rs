@@ -0,0 +1,361 @@
+//! Digest trees of the installed packages of a project, for finding out exactly which
+//! files of a toolchain differ between two machines.
+//!
+//! Where the [`Lockfile`](super::lock::Lockfile) only records the version and the
+//! manifest of every package, the digest tree records the digest and the size of every
+//! file of the installed platform, packages and libraries of an environment, and a
+//! digest of each package over its files. It is written by `cargo pio pkg fingerprint`
+//! on one machine and read by `cargo pio pkg compare` on another, which compares the
+//! files of the packages whose digests differ.
+//!
+//! The file is plain text, one package per header line followed by its files:
+//!
+//! ```text
+//! package package:toolchain-xtensa-esp32 8.4.0 3b1f...
+//!   9f86d081884c7d65 1048 bin/xtensa-esp32-elf-gcc
+//! ```
+//!
+//! The digests of the files are truncated to 64 bits to keep the file compact, which is
+//! plenty for telling the files of two installations apart.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+
+use super::graph::{Graph, Kind};
+use super::hash::{self, hex, sha256, Algorithm, TreeEntry};
+use super::Pio;
+
+/// The length of the truncated digests of the files, in hex digits.
+const FILE_DIGEST_LEN: usize = 16;
+
+/// The digests of the installed packages of an environment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeDigest {
+    /// The packages, keyed by the id of their node in the [`Graph`] (`<kind>:<name>`).
+    pub packages: BTreeMap<String, PackageDigest>,
+}
+
+/// The digests of the files of an installed package.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PackageDigest {
+    pub version: Option<String>,
+    /// The SHA-256 digest of the paths and digests of the files.
+    pub digest: String,
+    /// The truncated digests and the sizes of the files and symlinks, keyed by their
+    /// paths relative to the package directory.
+    pub files: BTreeMap<String, (String, u64)>,
+}
+
+impl PackageDigest {
+    /// Collect the digests of the files in the package directory `dir`.
+    pub fn collect(version: Option<String>, dir: impl AsRef<Path>) -> Result<Self> {
+        let mut entries = Vec::new();
+        hash::walk(dir.as_ref(), String::new(), &mut entries)?;
+
+        let mut files = BTreeMap::new();
+
+        for (relative, entry) in entries {
+            let (digest, size) = match entry {
+                TreeEntry::Dir => continue,
+                TreeEntry::Symlink(target) => {
+                    (sha256(format!("-> {}", target).as_bytes()).to_vec(), 0)
+                }
+                TreeEntry::File(path, size) => {
+                    (hash::file(Algorithm::Sha256, path, &mut |_, _| ())?, size)
+                }
+            };
+
+            files.insert(relative, (hex(&digest)[..FILE_DIGEST_LEN].to_owned(), size));
+        }
+
+        Ok(Self::new(version, files))
+    }
+
+    fn new(version: Option<String>, files: BTreeMap<String, (String, u64)>) -> Self {
+        let listing = files
+            .iter()
+            .map(|(path, (digest, size))| format!("{}\0{}\0{}\0", path, digest, size))
+            .collect::<String>();
+
+        Self {
+            version,
+            digest: hex(&sha256(listing.as_bytes())),
+            files,
+        }
+    }
+}
+
+impl TreeDigest {
+    /// Collect the digests of the installed packages of the PlatformIO `environment` of
+    /// the project in `project_dir`.
+    pub fn collect(pio: &Pio, project_dir: impl AsRef<Path>, environment: &str) -> Result<Self> {
+        let graph = Graph::collect(pio, project_dir, environment)?;
+
+        let mut tree = Self::default();
+
+        for (id, node) in graph.nodes {
+            if node.kind == Kind::Environment || node.size.is_none() {
+                continue;
+            }
+
+            let dir = match node.manifest.as_deref().and_then(Path::parent) {
+                Some(dir) => dir.to_owned(),
+                None => continue,
+            };
+
+            info!("Hashing {}", dir.display());
+
+            tree.packages
+                .insert(id, PackageDigest::collect(node.version, &dir)?);
+        }
+
+        Ok(tree)
+    }
+
+    /// Load the digest tree `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        Self::parse(
+            &fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        )
+        .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Save this digest tree as `path`.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        crate::fs::write_atomic(path, self.render())?;
+
+        Ok(())
+    }
+
+    /// This digest tree in the format of the file.
+    pub fn render(&self) -> String {
+        let mut rendered = String::from(
+            "# Generated by `cargo pio pkg fingerprint`, compared by `cargo pio pkg compare`\n",
+        );
+
+        for (id, package) in &self.packages {
+            rendered.push_str(&format!(
+                "package {} {} {}\n",
+                id,
+                package.version.as_deref().unwrap_or("-"),
+                package.digest
+            ));
+
+            for (path, (digest, size)) in &package.files {
+                rendered.push_str(&format!("  {} {} {}\n", digest, size, path));
+            }
+        }
+
+        rendered
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let mut tree = Self::default();
+        let mut current: Option<(String, Option<String>, BTreeMap<_, _>)> = None;
+
+        let mut finish = |current: Option<(String, Option<String>, _)>| {
+            if let Some((id, version, files)) = current {
+                tree.packages.insert(id, PackageDigest::new(version, files));
+            }
+        };
+
+        for (index, line) in content.lines().enumerate() {
+            let malformed = || anyhow!("Malformed line {}: '{}'", index + 1, line);
+
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            } else if let Some(package) = line.strip_prefix("package ") {
+                let mut fields = package.split(' ');
+                let id = fields.next().ok_or_else(malformed)?;
+                let version = fields.next().ok_or_else(malformed)?;
+
+                finish(current.take());
+                current = Some((
+                    id.to_owned(),
+                    Some(version.to_owned()).filter(|version| version != "-"),
+                    BTreeMap::new(),
+                ));
+            } else if let Some(file) = line.strip_prefix("  ") {
+                let mut fields = file.splitn(3, ' ');
+                let digest = fields.next().ok_or_else(malformed)?;
+                let size = fields
+                    .next()
+                    .and_then(|size| size.parse().ok())
+                    .ok_or_else(malformed)?;
+                let path = fields.next().ok_or_else(malformed)?;
+
+                let (_, _, files) = current.as_mut().ok_or_else(malformed)?;
+                files.insert(path.to_owned(), (digest.to_owned(), size));
+            } else {
+                return Err(malformed());
+            }
+        }
+
+        finish(current);
+
+        Ok(tree)
+    }
+
+    /// The differences of the `other` digest tree from this one.
+    pub fn compare(&self, other: &Self) -> Vec<Difference> {
+        let mut differences = Vec::new();
+
+        for (id, package) in &self.packages {
+            let other = match other.packages.get(id) {
+                Some(other) => other,
+                None => {
+                    differences.push(Difference::MissingPackage(id.clone()));
+                    continue;
+                }
+            };
+
+            if package.version != other.version {
+                differences.push(Difference::Version {
+                    id: id.clone(),
+                    ours: package.version.clone(),
+                    theirs: other.version.clone(),
+                });
+            }
+
+            if package.digest == other.digest {
+                continue;
+            }
+
+            for (path, (digest, _)) in &package.files {
+                let file = |difference: fn(String, String) -> Difference| {
+                    difference(id.clone(), path.clone())
+                };
+
+                match other.files.get(path) {
+                    None => differences.push(file(Difference::Missing)),
+                    Some((other, _)) if other != digest => {
+                        differences.push(file(Difference::Changed))
+                    }
+                    Some(_) => (),
+                }
+            }
+
+            for path in other.files.keys() {
+                if !package.files.contains_key(path) {
+                    differences.push(Difference::Extra(id.clone(), path.clone()));
+                }
+            }
+        }
+
+        for id in other.packages.keys() {
+            if !self.packages.contains_key(id) {
+                differences.push(Difference::ExtraPackage(id.clone()));
+            }
+        }
+
+        differences
+    }
+}
+
+/// A difference between two digest trees, from the point of view of the first ("ours").
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// A package of ours is not installed in theirs.
+    MissingPackage(String),
+    /// A package of theirs is not installed in ours.
+    ExtraPackage(String),
+    /// A package is installed in different versions.
+    Version {
+        id: String,
+        ours: Option<String>,
+        theirs: Option<String>,
+    },
+    /// A file of a package of ours is not in theirs.
+    Missing(String, String),
+    /// A file of a package of theirs is not in ours.
+    Extra(String, String),
+    /// A file has different contents.
+    Changed(String, String),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = |version: &Option<String>| version.clone().unwrap_or_else(|| "-".into());
+
+        match self {
+            Self::MissingPackage(id) => write!(f, "{}: only installed here", id),
+            Self::ExtraPackage(id) => write!(f, "{}: only installed there", id),
+            Self::Version { id, ours, theirs } => write!(
+                f,
+                "{}: version {} here, {} there",
+                id,
+                version(ours),
+                version(theirs)
+            ),
+            Self::Missing(id, path) => write!(f, "{}: {} only exists here", id, path),
+            Self::Extra(id, path) => write!(f, "{}: {} only exists there", id, path),
+            Self::Changed(id, path) => write!(f, "{}: {} differs", id, path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let dir = std::env::temp_dir().join(format!("embuild-tree-digest-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("bin").join("gcc"), "gcc").unwrap();
+        fs::write(dir.join("bin").join("ld with space"), "ld").unwrap();
+        fs::write(dir.join("package.json"), "{}").unwrap();
+
+        let ours = TreeDigest {
+            packages: [(
+                "package:toolchain".to_owned(),
+                PackageDigest::collect(Some("8.4.0".into()), &dir).unwrap(),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        assert_eq!(ours.packages["package:toolchain"].files.len(), 3);
+        assert_eq!(TreeDigest::parse(&ours.render()).unwrap(), ours);
+        assert!(ours.compare(&ours).is_empty());
+
+        fs::write(dir.join("bin").join("gcc"), "patched gcc").unwrap();
+        fs::remove_file(dir.join("bin").join("ld with space")).unwrap();
+        fs::write(dir.join("bin").join("as"), "as").unwrap();
+
+        let mut theirs = TreeDigest::default();
+        theirs.packages.insert(
+            "package:toolchain".to_owned(),
+            PackageDigest::collect(Some("8.4.1".into()), &dir).unwrap(),
+        );
+        theirs
+            .packages
+            .insert("library:foo".to_owned(), PackageDigest::default());
+
+        assert_eq!(
+            ours.compare(&theirs)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "package:toolchain: version 8.4.0 here, 8.4.1 there",
+                "package:toolchain: bin/gcc differs",
+                "package:toolchain: bin/ld with space only exists here",
+                "package:toolchain: bin/as only exists there",
+                "library:foo: only installed there",
+            ]
+        );
+
+        assert!(TreeDigest::parse("  9f86d081884c7d65 3 bin/gcc\n").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
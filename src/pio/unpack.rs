@@ -0,0 +1,1411 @@
+//! Archives (`.tar`, `.tar.gz` or `.tgz`, `.tar.xz`, `.tar.zst`, `.zip`), listed and
+//! extracted.
+//!
+//! The archives of SDKs and components are unpacked with [`extract`], which detects the
+//! [`Format`] by the extension of the archive or else by its magic bytes, and keeps the
+//! Unix permissions and symlinks of the entries. It runs [`preflight`] first, which lists
+//! the entries to refuse archives with entries outside of the directory they are unpacked
+//! into and archives which do not fit into the free space of the disk, before anything is
+//! written. While unpacking, it refuses symlinks pointing outside of the directory and
+//! entries which would be written through a symlink unpacked before them.
+//!
+//! Archives of other formats (e.g. `.tar.bz2` or `.7z`) and symlinks which cannot be
+//! created natively (on Windows without the privilege to) are extracted with 7-Zip
+//! instead, if `7za` or `7z` is in the `PATH`.
+
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use log::*;
+
+use super::graph::format_size;
+use crate::cmd;
+use crate::error::HintExt;
+
+/// The 7-Zip executables [`extract`] falls back to, in the order they are looked for in
+/// the `PATH`: the standalone `7za` and the `7z` of the full installation.
+const SEVEN_ZIP: [&str; 2] = ["7za", "7z"];
+
+/// The type of an [`Entry`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    Other,
+}
+
+/// An entry of an archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// The path within the archive, without a trailing `/` for directories.
+    pub path: String,
+    /// The unpacked size, 0 for everything but files.
+    pub size: u64,
+    /// The Unix permissions, e.g. `0o755`.
+    pub mode: u32,
+    pub kind: Kind,
+}
+
+/// Like `ls -l`, e.g. `-rw-r--r--      1024 src/main.c`.
+impl Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::File | Kind::Hardlink => '-',
+            Kind::Dir => 'd',
+            Kind::Symlink => 'l',
+            Kind::Other => '?',
+        };
+
+        let permissions = (0..9)
+            .map(|bit| match self.mode & (0o400 >> bit) {
+                0 => '-',
+                _ => ['r', 'w', 'x'][bit % 3],
+            })
+            .collect::<String>();
+
+        write!(f, "{}{} {:>10} {}", kind, permissions, self.size, self.path)
+    }
+}
+
+/// The format of an archive.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+    Zip,
+}
+
+impl Format {
+    /// The format of `archive`, by its extension or else by its magic bytes.
+    pub fn detect(archive: &Path) -> Result<Self> {
+        let name = archive
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        for (extensions, format) in [
+            (&[".zip"][..], Self::Zip),
+            (&[".tar.gz", ".tgz"], Self::TarGz),
+            (&[".tar.xz", ".txz"], Self::TarXz),
+            (&[".tar.zst", ".tzst"], Self::TarZst),
+            (&[".tar"], Self::Tar),
+        ] {
+            if extensions.iter().any(|extension| name.ends_with(extension)) {
+                return Ok(format);
+            }
+        }
+
+        let mut magic = Vec::new();
+        File::open(archive)
+            .and_then(|file| file.take(262).read_to_end(&mut magic))
+            .with_context(|| format!("Failed to open {}", archive.display()))?;
+
+        if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06") {
+            Ok(Self::Zip)
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            Ok(Self::TarGz)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+            Ok(Self::TarXz)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Ok(Self::TarZst)
+        } else if magic.get(257..262) == Some(b"ustar") {
+            Ok(Self::Tar)
+        } else {
+            Err(Unsupported(format!(
+                "Unknown format of the archive {}",
+                archive.display()
+            )))
+            .hint("Only .tar, .tar.gz, .tgz, .tar.xz, .tar.zst and .zip archives are supported")
+        }
+    }
+}
+
+/// An archive or an entry which cannot be extracted natively, but possibly with 7-Zip.
+#[derive(Debug)]
+struct Unsupported(String);
+
+impl Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// Why `err` is, if it is one of an archive or entry which cannot be extracted natively.
+fn unsupported(err: &Error) -> Option<&Unsupported> {
+    err.chain().find_map(|cause| cause.downcast_ref())
+}
+
+/// The entries of the archive `archive`, in the order they are stored.
+pub fn list(archive: &Path) -> Result<Vec<Entry>> {
+    let entries = match Format::detect(archive)? {
+        Format::Zip => list_zip(&fs::read(archive)?),
+        format => list_tar(tar_reader(archive, format)?),
+    };
+
+    entries.with_context(|| format!("Failed to list the archive {}", archive.display()))
+}
+
+/// Whether [`list`] and [`extract`] support `archive`.
+pub fn is_listable(archive: &Path) -> bool {
+    Format::detect(archive).is_ok()
+}
+
+/// The size of the files of `entries` once unpacked.
+pub fn unpacked_size(entries: &[Entry]) -> u64 {
+    entries.iter().map(|entry| entry.size).sum()
+}
+
+/// Extract `archive` into `dir`, stripping the first `strip_components` components of
+/// the paths of its entries (and skipping the entries with no components left), as
+/// `tar --strip-components` does.
+///
+/// `progress` is called with the unpacked and the total size of the files after every
+/// file. Archives which cannot be extracted natively are extracted with 7-Zip, without
+/// progress.
+pub fn extract(
+    archive: &Path,
+    dir: &Path,
+    strip_components: usize,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<()> {
+    let err = match extract_natively(archive, dir, strip_components, progress) {
+        Err(err) => err,
+        Ok(()) => return Ok(()),
+    };
+
+    let reason = match unsupported(&err) {
+        Some(reason) => reason,
+        None => return Err(err),
+    };
+
+    let seven_zip = match SEVEN_ZIP.iter().find_map(|name| which::which(name).ok()) {
+        Some(seven_zip) => seven_zip,
+        None => return Err(err).hint("Install 7-Zip to extract it with 7za or 7z"),
+    };
+
+    warn!("{}, extracting it with {}", reason, seven_zip.display());
+
+    extract_with_7zip(&seven_zip, archive, dir, strip_components)
+        .with_context(|| format!("Failed to extract {}", archive.display()))
+}
+
+fn extract_natively(
+    archive: &Path,
+    dir: &Path,
+    strip_components: usize,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<()> {
+    let format = Format::detect(archive)?;
+    let total = unpacked_size(&preflight(archive, dir)?);
+
+    debug!(
+        "Extracting {} ({:?}) into {}",
+        archive.display(),
+        format,
+        dir.display()
+    );
+
+    fs::create_dir_all(dir)?;
+
+    let mut extraction = Extraction {
+        dir,
+        strip_components,
+        directories: Vec::new(),
+        unpacked: 0,
+        total,
+        progress,
+    };
+
+    match format {
+        Format::Zip => extraction.zip(&fs::read(archive)?),
+        format => extraction.tar(tar_reader(archive, format)?),
+    }
+    .with_context(|| format!("Failed to extract {}", archive.display()))?;
+
+    // Only now, as read-only directories could not be written into
+    for (path, mode) in extraction.directories.iter().rev() {
+        set_mode(path, *mode)?;
+    }
+
+    Ok(())
+}
+
+/// Extract `archive` into `dir` with the 7-Zip executable `seven_zip`, stripping the
+/// first `strip_components` components of the paths of its entries.
+///
+/// The archive is extracted into a temporary directory in `dir` first, twice for
+/// compressed tar archives, which 7-Zip only decompresses into the tar archive.
+fn extract_with_7zip(
+    seven_zip: &Path,
+    archive: &Path,
+    dir: &Path,
+    strip_components: usize,
+) -> Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let run = |archive: &Path| -> Result<tempfile::TempDir> {
+        let unpacked = tempfile::Builder::new().prefix(".7z-").tempdir_in(dir)?;
+
+        let mut output = std::ffi::OsString::from("-o");
+        output.push(unpacked.path());
+        cmd!(seven_zip, "x", "-y", "-bd", output, archive).stdout()?;
+
+        Ok(unpacked)
+    };
+
+    let mut unpacked = run(archive)?;
+
+    let entries = fs::read_dir(unpacked.path())?.collect::<Result<Vec<_>, _>>()?;
+    if let [entry] = &entries[..] {
+        let name = entry.file_name().to_string_lossy().to_lowercase();
+
+        if name.ends_with(".tar") && entry.file_type()?.is_file() {
+            unpacked = run(&entry.path())?;
+        }
+    }
+
+    check_symlinks(unpacked.path(), Path::new(""), dir, strip_components)?;
+    move_stripped(unpacked.path(), dir, strip_components)
+}
+
+/// Fail if a symlink in the directory `root`, moved into `dir` without the first
+/// `strip_components` components of its path `relative`, would point outside of `dir`.
+fn check_symlinks(root: &Path, relative: &Path, dir: &Path, strip_components: usize) -> Result<()> {
+    for entry in fs::read_dir(root.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            check_symlinks(root, &path, dir, strip_components)?;
+        } else if file_type.is_symlink() {
+            let stripped = path
+                .components()
+                .skip(strip_components)
+                .collect::<PathBuf>();
+
+            if !stripped.as_os_str().is_empty() {
+                check_symlink(
+                    dir,
+                    &path.to_string_lossy(),
+                    &dir.join(stripped),
+                    &fs::read_link(entry.path())?,
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Move the entries of the directory `from` into `dir`, stripping the first
+/// `strip_components` components of their paths and replacing what is there.
+fn move_stripped(from: &Path, dir: &Path, strip_components: usize) -> Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let is_dir = entry.file_type()?.is_dir();
+
+        if strip_components > 0 {
+            // Files with no components left are skipped, as `tar --strip-components` does
+            if is_dir {
+                move_stripped(&entry.path(), dir, strip_components - 1)?;
+            }
+            continue;
+        }
+
+        let target = dir.join(entry.file_name());
+        match fs::symlink_metadata(&target) {
+            Ok(metadata) if is_dir && metadata.is_dir() => {
+                move_stripped(&entry.path(), &target, 0)?;
+                continue;
+            }
+            Ok(metadata) if metadata.is_dir() => crate::fs::remove_dir_all(&target)?,
+            Ok(_) => fs::remove_file(&target)?,
+            Err(_) => (),
+        }
+
+        fs::rename(entry.path(), &target)
+            .with_context(|| format!("Failed to move {}", target.display()))?;
+    }
+
+    Ok(())
+}
+
+/// The decompressed tar archive of `archive`.
+fn tar_reader(archive: &Path, format: Format) -> Result<Box<dyn Read>> {
+    let file =
+        File::open(archive).with_context(|| format!("Failed to open {}", archive.display()))?;
+
+    Ok(match format {
+        Format::Tar => Box::new(file),
+        Format::TarGz => Box::new(GzDecoder::new(file)),
+        Format::TarXz => {
+            // `lzma-rs` only decompresses whole streams
+            let mut tar = BufWriter::new(tempfile::tempfile()?);
+            lzma_rs::xz_decompress(&mut BufReader::new(file), &mut tar)
+                .map_err(|err| anyhow!("{:?}", err))
+                .with_context(|| format!("Failed to decompress {}", archive.display()))?;
+
+            let mut tar = tar.into_inner()?;
+            tar.seek(SeekFrom::Start(0))?;
+            Box::new(tar)
+        }
+        Format::TarZst => Box::new(
+            ruzstd::StreamingDecoder::new(file)
+                .with_context(|| format!("Failed to decompress {}", archive.display()))?,
+        ),
+        Format::Zip => bail!("{} is no tar archive", archive.display()),
+    })
+}
+
+/// The state of [`extract`].
+struct Extraction<'a> {
+    dir: &'a Path,
+    strip_components: usize,
+    /// The directories with their permissions, set once everything is unpacked.
+    directories: Vec<(PathBuf, u32)>,
+    unpacked: u64,
+    total: u64,
+    progress: &'a mut dyn FnMut(u64, u64),
+}
+
+impl Extraction<'_> {
+    /// The path in [`Self::dir`] of the entry `path`, `None` if nothing is left of it
+    /// after stripping its components.
+    fn target(&self, path: &str) -> Result<Option<PathBuf>> {
+        let mut components = Path::new(path)
+            .components()
+            .filter(|component| !matches!(component, Component::CurDir));
+
+        if let Some(component) = components
+            .clone()
+            .find(|component| !matches!(component, Component::Normal(_)))
+        {
+            bail!(
+                "'{}' of '{}' is outside of {}",
+                component.as_os_str().to_string_lossy(),
+                path,
+                self.dir.display()
+            );
+        }
+
+        let stripped = components
+            .by_ref()
+            .skip(self.strip_components)
+            .collect::<PathBuf>();
+
+        if stripped.as_os_str().is_empty() {
+            return Ok(None);
+        }
+
+        // Symlinks unpacked before may point anywhere, so nothing is written through them
+        let mut target = self.dir.to_owned();
+        for component in stripped.components() {
+            target.push(component);
+
+            if fs::symlink_metadata(&target).map_or(false, |m| m.file_type().is_symlink()) {
+                bail!(
+                    "'{}' would be written through the symlink {}",
+                    path,
+                    target.display()
+                );
+            }
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        Ok(Some(target))
+    }
+
+    fn unpacked(&mut self, size: u64) {
+        self.unpacked += size;
+        (self.progress)(self.unpacked, self.total);
+    }
+
+    fn tar(&mut self, reader: impl Read) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+
+            let target = match self.target(&path)? {
+                Some(target) => target,
+                None => continue,
+            };
+
+            let kind = entry.header().entry_type();
+
+            if kind.is_dir() {
+                fs::create_dir_all(&target)?;
+                self.directories
+                    .push((target, entry.header().mode()? & 0o7777));
+            } else if kind.is_hard_link() {
+                // The link names of hard links are paths within the archive as well
+                let source = entry
+                    .link_name()?
+                    .map(|source| source.to_string_lossy().into_owned())
+                    .ok_or_else(|| anyhow!("Hard link '{}' without a source", path))?;
+                let source = self
+                    .target(&source)?
+                    .ok_or_else(|| anyhow!("Hard link '{}' to a stripped entry", path))?;
+
+                fs::hard_link(source, &target)?;
+            } else if kind.is_symlink() {
+                let source = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("Symlink '{}' without a source", path))?;
+                check_symlink(self.dir, &path, &target, &source)?;
+
+                if let Err(err) = entry.unpack(&target) {
+                    // Windows needs a privilege (or the developer mode) for symlinks
+                    if cfg!(windows) {
+                        return Err(Unsupported(format!(
+                            "Failed to create the symlink '{}': {}",
+                            path, err
+                        ))
+                        .into());
+                    }
+
+                    return Err(err).with_context(|| format!("Failed to unpack '{}'", path));
+                }
+            } else {
+                entry
+                    .unpack(&target)
+                    .with_context(|| format!("Failed to unpack '{}'", path))?;
+
+                if kind.is_file() {
+                    self.unpacked(entry.size());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn zip(&mut self, data: &[u8]) -> Result<()> {
+        for zip_entry in zip_entries(data)? {
+            let entry = &zip_entry.entry;
+
+            let target = match self.target(&entry.path)? {
+                Some(target) => target,
+                None => continue,
+            };
+
+            match entry.kind {
+                Kind::Dir => {
+                    fs::create_dir_all(&target)?;
+                    self.directories.push((target, entry.mode));
+                }
+                Kind::Symlink => {
+                    let source = String::from_utf8(zip_entry.data(data)?)?;
+                    check_symlink(self.dir, &entry.path, &target, Path::new(&source))?;
+
+                    symlink(&source, &target)?;
+                }
+                _ => {
+                    fs::write(&target, zip_entry.data(data)?)?;
+                    set_mode(&target, entry.mode)?;
+
+                    self.unpacked(entry.size);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set the permissions of {}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(source: &str, target: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(source, target)
+        .with_context(|| format!("Failed to create the symlink {}", target.display()))
+}
+
+#[cfg(not(unix))]
+fn symlink(source: &str, target: &Path) -> Result<()> {
+    warn!(
+        "Not creating the symlink {} to {}",
+        target.display(),
+        source
+    );
+    Ok(())
+}
+
+/// List the entries of `archive` before unpacking it into `dir`, failing if an entry
+/// would end up outside of `dir` or if the files do not fit into the free space of the
+/// disk of `dir`. Archives which cannot be listed are not checked.
+///
+/// Returns the entries, none if the archive is not checked.
+pub fn preflight(archive: &Path, dir: &Path) -> Result<Vec<Entry>> {
+    if !is_listable(archive) {
+        debug!("Not checking the archive {}", archive.display());
+        return Ok(Vec::new());
+    }
+
+    let entries = list(archive)?;
+
+    if let Some(entry) = entries.iter().find(|entry| {
+        Path::new(&entry.path)
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    }) {
+        bail!(
+            "The archive {} contains '{}', which is outside of the directory it is unpacked into",
+            archive.display(),
+            entry.path
+        );
+    }
+
+    let size = unpacked_size(&entries);
+
+    debug!(
+        "{}: {} entries, {} unpacked",
+        archive.display(),
+        entries.len(),
+        format_size(size)
+    );
+
+    if let Some(available) = available_space(dir) {
+        if size > available {
+            return Err(anyhow!(
+                "Unpacking {} needs {}, but only {} are free in {}",
+                archive.display(),
+                format_size(size),
+                format_size(available),
+                dir.display()
+            ))
+            .hint("Free up disk space and try again");
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Fail unless `source`, the source of the symlink entry `path` unpacked to `target`, is
+/// within `dir`.
+///
+/// Only sources which go up with `..` first and then down are allowed, so that symlinks
+/// within the source cannot take the `..` outside of `dir`.
+fn check_symlink(dir: &Path, path: &str, target: &Path, source: &Path) -> Result<()> {
+    // The depth of the directory of the symlink in `dir`
+    let mut depth = target
+        .strip_prefix(dir)?
+        .components()
+        .count()
+        .saturating_sub(1);
+    let mut down = false;
+
+    for component in source.components() {
+        match component {
+            Component::CurDir => (),
+            Component::Normal(_) => down = true,
+            Component::ParentDir if !down && depth > 0 => depth -= 1,
+            _ => bail!(
+                "The symlink '{}' points to '{}', outside of {}",
+                path,
+                source.display(),
+                dir.display()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// The free space of the disk of `dir` (or of its closest existing ancestor), `None` if
+/// unknown.
+#[cfg(unix)]
+fn available_space(dir: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = dir.ancestors().find(|dir| dir.exists())?;
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+
+    let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+const BLOCK_SIZE: u64 = 512;
+
+/// The entries of the tar archive `reader`, with the ustar, GNU and pax long names.
+fn list_tar(mut reader: impl Read) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    // The name of the next entry, of a GNU long name or pax header
+    let mut long_name = None;
+
+    loop {
+        let mut header = [0; BLOCK_SIZE as usize];
+        match reader.read_exact(&mut header) {
+            Ok(()) => (),
+            // Archives may end without the two empty blocks
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+
+        let size = number(&header[124..136])?;
+        let data = read_data(&mut reader, size)?;
+
+        let kind = match header[156] {
+            b'0' | 0 | b'7' => Kind::File,
+            b'1' => Kind::Hardlink,
+            b'2' => Kind::Symlink,
+            b'5' => Kind::Dir,
+            b'L' => {
+                long_name = Some(string(&data));
+                continue;
+            }
+            b'x' => {
+                long_name = pax_path(&data).or(long_name);
+                continue;
+            }
+            b'g' => continue,
+            _ => Kind::Other,
+        };
+
+        let path = match long_name.take() {
+            Some(path) => path,
+            None if &header[257..262] == b"ustar" && header[345] != 0 => {
+                format!("{}/{}", string(&header[345..500]), string(&header[..100]))
+            }
+            None => string(&header[..100]),
+        };
+
+        entries.push(Entry {
+            path: path.trim_end_matches('/').to_owned(),
+            size: if kind == Kind::File { size } else { 0 },
+            mode: number(&header[100..108])? as u32 & 0o7777,
+            kind,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Read the data of an entry of `size` bytes, keeping it only if it is small enough to
+/// be a header (long names), as the data of files is not needed.
+fn read_data(reader: &mut impl Read, size: u64) -> Result<Vec<u8>> {
+    let padded = (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+
+    let mut data = Vec::new();
+    if size <= 64 * 1024 {
+        reader.take(padded).read_to_end(&mut data)?;
+        data.truncate(size as usize);
+    } else {
+        io::copy(&mut reader.take(padded), &mut io::sink())?;
+    }
+
+    Ok(data)
+}
+
+/// The octal number of a header field, or its big-endian number in the base-256
+/// encoding of GNU tar.
+fn number(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |number, byte| {
+                number << 8 | u64::from(*byte)
+            }));
+    }
+
+    let digits = string(field);
+    let digits = digits.trim();
+
+    if digits.is_empty() {
+        return Ok(0);
+    }
+
+    u64::from_str_radix(digits, 8)
+        .with_context(|| format!("Invalid tar header number '{}'", digits))
+}
+
+/// The NUL-terminated string of a header field.
+fn string(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// The `path` of the pax extended header `data`, of records `<length> <key>=<value>\n`.
+fn pax_path(data: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(data).lines().find_map(|record| {
+        let (_, pair) = record.split_once(' ')?;
+        let (key, value) = pair.split_once('=')?;
+        (key == "path").then(|| value.to_owned())
+    })
+}
+
+const ZIP_END_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP_ENTRY_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP_END_LEN: usize = 22;
+/// The host system of `version made by` of archives made on Unix, whose entries carry
+/// Unix permissions.
+const ZIP_HOST_UNIX: u8 = 3;
+
+const ZIP_LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(
+        data.get(offset..offset + 2)
+            .ok_or_else(|| anyhow!("Truncated zip archive"))?
+            .try_into()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        data.get(offset..offset + 4)
+            .ok_or_else(|| anyhow!("Truncated zip archive"))?
+            .try_into()?,
+    ))
+}
+
+/// An entry of the central directory of a zip archive.
+struct ZipEntry {
+    entry: Entry,
+    method: u16,
+    compressed_size: usize,
+    /// The offset of the local header of the entry.
+    offset: usize,
+}
+
+impl ZipEntry {
+    /// The uncompressed data of the entry in the zip archive `data`.
+    fn data(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if u32_at(data, self.offset)? != ZIP_LOCAL_SIGNATURE {
+            bail!("Corrupt local header of '{}'", self.entry.path);
+        }
+
+        // The extra field of the local header may differ from the central directory's
+        let start = self.offset
+            + 30
+            + u16_at(data, self.offset + 26)? as usize
+            + u16_at(data, self.offset + 28)? as usize;
+        let compressed = data
+            .get(start..start + self.compressed_size)
+            .ok_or_else(|| anyhow!("Truncated zip archive"))?;
+
+        match self.method {
+            ZIP_STORED => Ok(compressed.to_vec()),
+            ZIP_DEFLATED => {
+                let mut uncompressed = Vec::with_capacity(self.entry.size as usize);
+                DeflateDecoder::new(compressed).read_to_end(&mut uncompressed)?;
+                Ok(uncompressed)
+            }
+            method => bail!(
+                "'{}' uses the unsupported compression method {}",
+                self.entry.path,
+                method
+            ),
+        }
+    }
+}
+
+/// The entries of the zip archive `data`, from its central directory.
+fn list_zip(data: &[u8]) -> Result<Vec<Entry>> {
+    Ok(zip_entries(data)?
+        .into_iter()
+        .map(|zip_entry| zip_entry.entry)
+        .collect())
+}
+
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>> {
+    let u16_at = |offset: usize| u16_at(data, offset);
+    let u32_at = |offset: usize| u32_at(data, offset);
+
+    // The end of central directory record is followed by a comment of up to 64 KiB
+    let end = (0..=data.len().saturating_sub(ZIP_END_LEN))
+        .rev()
+        .take(ZIP_END_LEN + u16::MAX as usize)
+        .find(|offset| u32_at(*offset).ok() == Some(ZIP_END_SIGNATURE))
+        .ok_or_else(|| anyhow!("No zip archive"))?;
+
+    let count = u16_at(end + 10)?;
+    let mut offset = u32_at(end + 16)?;
+
+    if count == u16::MAX || offset == u32::MAX {
+        bail!("ZIP64 archives are not supported");
+    }
+
+    let mut entries = Vec::new();
+
+    for _ in 0..count {
+        let offset_ = offset as usize;
+
+        if u32_at(offset_)? != ZIP_ENTRY_SIGNATURE {
+            bail!("Corrupt central directory of the zip archive");
+        }
+
+        let host = (u16_at(offset_ + 4)? >> 8) as u8;
+        let method = u16_at(offset_ + 10)?;
+        let compressed_size = u32_at(offset_ + 20)? as usize;
+        let size = u64::from(u32_at(offset_ + 24)?);
+        let name_len = u16_at(offset_ + 28)? as usize;
+        let extra_len = u16_at(offset_ + 30)? as usize;
+        let comment_len = u16_at(offset_ + 32)? as usize;
+        let attributes = u32_at(offset_ + 38)?;
+        let local_offset = u32_at(offset_ + 42)? as usize;
+
+        let name = data
+            .get(offset_ + 46..offset_ + 46 + name_len)
+            .ok_or_else(|| anyhow!("Truncated zip archive"))?;
+        let path = String::from_utf8_lossy(name).into_owned();
+
+        let unix_mode = (host == ZIP_HOST_UNIX).then(|| attributes >> 16);
+
+        let kind = if path.ends_with('/') {
+            Kind::Dir
+        } else if unix_mode.map_or(false, |mode| mode & 0o170000 == 0o120000) {
+            Kind::Symlink
+        } else {
+            Kind::File
+        };
+
+        entries.push(ZipEntry {
+            entry: Entry {
+                path: path.trim_end_matches('/').to_owned(),
+                size: if kind == Kind::File { size } else { 0 },
+                mode: unix_mode.map_or(if kind == Kind::Dir { 0o755 } else { 0o644 }, |mode| {
+                    mode & 0o7777
+                }),
+                kind,
+            },
+            method,
+            compressed_size,
+            offset: local_offset,
+        });
+
+        offset += (46 + name_len + extra_len + comment_len) as u32;
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn tar_header(name: &str, mode: u32, size: u64, kind: u8) -> Vec<u8> {
+        let mut header = vec![0; BLOCK_SIZE as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(format!("{:07o}", mode).as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header
+    }
+
+    fn tar_data(data: &[u8]) -> Vec<u8> {
+        let mut block = data.to_vec();
+        block.resize(
+            (data.len() + BLOCK_SIZE as usize - 1) / BLOCK_SIZE as usize * BLOCK_SIZE as usize,
+            0,
+        );
+        block
+    }
+
+    #[test]
+    fn test_list() {
+        let long_name = format!("sdk/{}/driver.c", "x".repeat(120));
+
+        let tar = [
+            tar_header("sdk/", 0o755, 0, b'5'),
+            tar_header("sdk/main.c", 0o644, 600, b'0'),
+            tar_data(&[b'a'; 600]),
+            tar_header("././@LongLink", 0, long_name.len() as u64 + 1, b'L'),
+            tar_data(format!("{}\0", long_name).as_bytes()),
+            tar_header("sdk/xxx", 0o644, 3, b'0'),
+            tar_data(b"abc"),
+            tar_header("sdk/latest", 0o777, 0, b'2'),
+            vec![0; 2 * BLOCK_SIZE as usize],
+        ]
+        .concat();
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&tar).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("embuild-unpack-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("sdk.tgz");
+        fs::write(&archive, encoder.finish().unwrap()).unwrap();
+
+        preflight(&archive, &dir.join("out")).unwrap();
+
+        let entries = list(&archive).unwrap();
+        assert_eq!(
+            entries.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "drwxr-xr-x          0 sdk".to_owned(),
+                "-rw-r--r--        600 sdk/main.c".to_owned(),
+                format!("-rw-r--r--          3 {}", long_name),
+                "lrwxrwxrwx          0 sdk/latest".to_owned(),
+            ]
+        );
+        assert_eq!(unpacked_size(&entries), 603);
+
+        let escaping = dir.join("escaping.tar");
+        fs::write(
+            &escaping,
+            [
+                tar_header("../evil", 0o644, 0, b'0'),
+                vec![0; 2 * BLOCK_SIZE as usize],
+            ]
+            .concat(),
+        )
+        .unwrap();
+        assert!(preflight(&escaping, &dir).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        // A central directory with a Unix file and a DOS directory, and its end record
+        let mut zip = Vec::new();
+        for (name, host, size, attributes) in [
+            ("bin/tool", ZIP_HOST_UNIX, 42u32, 0o100755u32 << 16),
+            ("docs/", 0, 0, 0x10),
+        ] {
+            zip.extend(ZIP_ENTRY_SIGNATURE.to_le_bytes());
+            zip.extend([20, host]);
+            zip.extend([0; 14]);
+            zip.extend(size.to_le_bytes());
+            zip.extend(size.to_le_bytes());
+            zip.extend((name.len() as u16).to_le_bytes());
+            zip.extend([0; 8]);
+            zip.extend(attributes.to_le_bytes());
+            zip.extend([0; 4]);
+            zip.extend(name.as_bytes());
+        }
+        let directory_len = zip.len() as u32;
+        zip.extend(ZIP_END_SIGNATURE.to_le_bytes());
+        zip.extend([0; 6]);
+        zip.extend(2u16.to_le_bytes());
+        zip.extend(directory_len.to_le_bytes());
+        zip.extend(0u32.to_le_bytes());
+        zip.extend([0; 2]);
+
+        assert_eq!(
+            list_zip(&zip).unwrap(),
+            [
+                Entry {
+                    path: "bin/tool".into(),
+                    size: 42,
+                    mode: 0o755,
+                    kind: Kind::File,
+                },
+                Entry {
+                    path: "docs".into(),
+                    size: 0,
+                    mode: 0o755,
+                    kind: Kind::Dir,
+                },
+            ]
+        );
+        assert!(list_zip(b"not a zip").is_err());
+    }
+
+    /// A zstd frame of `data` in a single raw (uncompressed) block.
+    fn zstd_raw(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x28, 0xb5, 0x2f, 0xfd];
+        // A single segment with a 2 byte content size, stored minus 256
+        frame.push(0x60);
+        frame.extend(((data.len() - 256) as u16).to_le_bytes());
+        // The last block, raw
+        frame.extend(&((data.len() as u32) << 3 | 1).to_le_bytes()[..3]);
+        frame.extend(data);
+        frame
+    }
+
+    /// A zip archive made on Unix of `(name, method, data, uncompressed size, mode)`
+    /// entries.
+    fn zip(entries: &[(&str, u16, &[u8], u32, u32)]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut directory = Vec::new();
+        for (name, method, data, size, mode) in entries {
+            let offset = zip.len() as u32;
+            zip.extend(ZIP_LOCAL_SIGNATURE.to_le_bytes());
+            zip.extend([20, 0, 0, 0]);
+            zip.extend(method.to_le_bytes());
+            zip.extend([0; 8]);
+            zip.extend((data.len() as u32).to_le_bytes());
+            zip.extend(size.to_le_bytes());
+            zip.extend((name.len() as u16).to_le_bytes());
+            zip.extend([0; 2]);
+            zip.extend(name.as_bytes());
+            zip.extend(*data);
+
+            directory.extend(ZIP_ENTRY_SIGNATURE.to_le_bytes());
+            directory.extend([20, ZIP_HOST_UNIX, 20, 0, 0, 0]);
+            directory.extend(method.to_le_bytes());
+            directory.extend([0; 8]);
+            directory.extend((data.len() as u32).to_le_bytes());
+            directory.extend(size.to_le_bytes());
+            directory.extend((name.len() as u16).to_le_bytes());
+            directory.extend([0; 8]);
+            directory.extend((mode << 16).to_le_bytes());
+            directory.extend(offset.to_le_bytes());
+            directory.extend(name.as_bytes());
+        }
+        let directory_offset = zip.len() as u32;
+        zip.extend(&directory);
+        zip.extend(ZIP_END_SIGNATURE.to_le_bytes());
+        zip.extend([0; 6]);
+        zip.extend((entries.len() as u16).to_le_bytes());
+        zip.extend((directory.len() as u32).to_le_bytes());
+        zip.extend(directory_offset.to_le_bytes());
+        zip.extend([0; 2]);
+        zip
+    }
+
+    #[test]
+    fn test_extract() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, kind: tar::EntryType, mode: u32, data: &[u8], link| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_mode(mode);
+            header.set_size(data.len() as u64);
+            if let Some(link) = link {
+                header.set_link_name(link).unwrap();
+            }
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        append("sdk/", tar::EntryType::Directory, 0o755, b"", None);
+        append(
+            "sdk/bin/tool",
+            tar::EntryType::Regular,
+            0o755,
+            b"#!/bin/sh\n",
+            None,
+        );
+        append("sdk/README", tar::EntryType::Regular, 0o644, b"SDK", None);
+        append(
+            "sdk/latest",
+            tar::EntryType::Symlink,
+            0o777,
+            b"",
+            Some("bin/tool"),
+        );
+        append(
+            "sdk/tool",
+            tar::EntryType::Link,
+            0o755,
+            b"",
+            Some("sdk/bin/tool"),
+        );
+        let tar = builder.into_inner().unwrap();
+
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut tar.as_slice(), &mut xz).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+
+        for (name, data) in [
+            ("sdk.tar", tar.clone()),
+            ("sdk-xz", xz),
+            ("sdk-zst", zstd_raw(&tar)),
+        ] {
+            let archive = dir.path().join(name);
+            fs::write(&archive, data).unwrap();
+
+            let out = dir.path().join(format!("{}.out", name));
+            let mut progress = Vec::new();
+            extract(&archive, &out, 1, &mut |done, total| {
+                progress.push((done, total))
+            })
+            .unwrap();
+
+            assert_eq!(progress, [(10, 13), (13, 13)], "{}", name);
+            assert_eq!(
+                fs::read(out.join("bin").join("tool")).unwrap(),
+                b"#!/bin/sh\n"
+            );
+            assert_eq!(fs::read(out.join("tool")).unwrap(), b"#!/bin/sh\n");
+            assert_eq!(fs::read_to_string(out.join("README")).unwrap(), "SDK");
+            assert!(!out.join("sdk").exists());
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+
+                let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+                assert_eq!(mode(&out.join("bin").join("tool")), 0o755);
+                assert_eq!(mode(&out.join("README")), 0o644);
+                assert_eq!(
+                    fs::read_link(out.join("latest")).unwrap(),
+                    Path::new("bin/tool")
+                );
+            }
+        }
+
+        assert_eq!(
+            Format::detect(&dir.path().join("sdk-xz")).unwrap(),
+            Format::TarXz
+        );
+        assert_eq!(
+            Format::detect(&dir.path().join("sdk-zst")).unwrap(),
+            Format::TarZst
+        );
+
+        // A stored file, a deflated file and a symlink, made on Unix
+        let deflated = {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(&[b'x'; 100]).unwrap();
+            encoder.finish().unwrap()
+        };
+        let zip = zip(&[
+            ("pkg/run", ZIP_STORED, &b"run"[..], 3, 0o100755),
+            ("pkg/data", ZIP_DEFLATED, &deflated[..], 100, 0o100600),
+            ("pkg/link", ZIP_STORED, &b"run"[..], 3, 0o120777),
+        ]);
+
+        let archive = dir.path().join("pkg.zip");
+        fs::write(&archive, zip).unwrap();
+
+        let out = dir.path().join("zip");
+        let mut progress = Vec::new();
+        extract(&archive, &out, 0, &mut |done, total| {
+            progress.push((done, total))
+        })
+        .unwrap();
+
+        assert_eq!(progress, [(3, 103), (103, 103)]);
+        assert_eq!(fs::read(out.join("pkg").join("run")).unwrap(), b"run");
+        assert_eq!(fs::read(out.join("pkg").join("data")).unwrap(), [b'x'; 100]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&out.join("pkg").join("run")), 0o755);
+            assert_eq!(mode(&out.join("pkg").join("data")), 0o600);
+            assert_eq!(
+                fs::read_link(out.join("pkg").join("link")).unwrap(),
+                Path::new("run")
+            );
+        }
+
+        // Left to 7-Zip
+        let unknown = dir.path().join("unknown");
+        fs::write(&unknown, "not an archive").unwrap();
+        let err = extract_natively(&unknown, &out, 0, &mut |_, _| ()).unwrap_err();
+        assert!(unsupported(&err).unwrap().0.contains("Unknown format"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_with_7zip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+
+        // Decompresses `.gz`s into the tar archive and extracts tar archives, as 7-Zip does
+        let seven_zip = dir.path().join("7za");
+        fs::write(
+            &seven_zip,
+            "#!/bin/sh\n\
+             case \"$5\" in\n\
+             *.gz) gzip -dc \"$5\" > \"${4#-o}/$(basename \"$5\" .gz)\" ;;\n\
+             *) tar -xf \"$5\" -C \"${4#-o}\" ;;\n\
+             esac\n",
+        )
+        .unwrap();
+        fs::set_permissions(&seven_zip, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let tar_gz = |entries: &[(&str, tar::EntryType, &str)]| {
+            let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::fast(),
+            ));
+            for (path, kind, link) in entries {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(*kind);
+                header.set_mode(0o755);
+                header.set_size(0);
+                if kind.is_symlink() {
+                    header.set_link_name(link).unwrap();
+                }
+                builder.append_data(&mut header, path, &[][..]).unwrap();
+            }
+            builder.into_inner().unwrap().finish().unwrap()
+        };
+
+        let archive = dir.path().join("pkg.tar.gz");
+        fs::write(
+            &archive,
+            tar_gz(&[
+                ("pkg/bin/", tar::EntryType::Directory, ""),
+                ("pkg/bin/tool", tar::EntryType::Regular, ""),
+                ("pkg/tool", tar::EntryType::Symlink, "bin/tool"),
+            ]),
+        )
+        .unwrap();
+
+        let out = dir.path().join("out");
+        fs::create_dir_all(out.join("bin")).unwrap();
+        fs::write(out.join("tool"), "old").unwrap();
+
+        extract_with_7zip(&seven_zip, &archive, &out, 1).unwrap();
+        assert!(out.join("bin").join("tool").is_file());
+        assert_eq!(
+            fs::read_link(out.join("tool")).unwrap(),
+            Path::new("bin/tool")
+        );
+        assert_eq!(fs::read_dir(&out).unwrap().count(), 2);
+
+        fs::write(
+            &archive,
+            tar_gz(&[("pkg/home", tar::EntryType::Symlink, "../../home")]),
+        )
+        .unwrap();
+        let err = extract_with_7zip(&seven_zip, &archive, &out, 1).unwrap_err();
+        assert!(format!("{:#}", err).contains("outside of"), "{:#}", err);
+        assert!(!out.join("home").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_refuses_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("home");
+        fs::create_dir_all(&outside).unwrap();
+
+        let tar = |entries: &[(&str, tar::EntryType, &str)]| {
+            let mut builder = tar::Builder::new(Vec::new());
+            for (path, kind, link) in entries {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(*kind);
+                header.set_mode(0o644);
+                header.set_size(0);
+                if kind.is_symlink() {
+                    header.set_link_name(link).unwrap();
+                }
+                builder.append_data(&mut header, path, &[][..]).unwrap();
+            }
+            builder.into_inner().unwrap()
+        };
+        let outside_link = outside.to_str().unwrap();
+
+        for (name, archive, error) in [
+            (
+                "absolute.tar",
+                tar(&[
+                    ("pkg/l", tar::EntryType::Symlink, outside_link),
+                    ("pkg/l/.bashrc", tar::EntryType::Regular, ""),
+                ]),
+                "outside of",
+            ),
+            (
+                "relative.tar",
+                tar(&[
+                    ("pkg/l", tar::EntryType::Symlink, "../../home"),
+                    ("pkg/l/.bashrc", tar::EntryType::Regular, ""),
+                ]),
+                "outside of",
+            ),
+            (
+                "down-and-up.tar",
+                tar(&[("pkg/l", tar::EntryType::Symlink, "sub/../../home")]),
+                "outside of",
+            ),
+            (
+                "through.tar",
+                tar(&[
+                    ("pkg/sub/", tar::EntryType::Directory, ""),
+                    ("pkg/l", tar::EntryType::Symlink, "sub"),
+                    ("pkg/l/.bashrc", tar::EntryType::Regular, ""),
+                ]),
+                "written through the symlink",
+            ),
+            (
+                "absolute.zip",
+                zip(&[
+                    (
+                        "pkg/l",
+                        ZIP_STORED,
+                        outside_link.as_bytes(),
+                        outside_link.len() as u32,
+                        0o120777,
+                    ),
+                    ("pkg/l/.bashrc", ZIP_STORED, b"", 0, 0o100644),
+                ]),
+                "outside of",
+            ),
+        ] {
+            let archive_path = dir.path().join(name);
+            fs::write(&archive_path, archive).unwrap();
+
+            let out = dir.path().join("out").join(name);
+            let err = extract(&archive_path, &out, 0, &mut |_, _| ()).unwrap_err();
+
+            assert!(format!("{:#}", err).contains(error), "{}: {:#}", name, err);
+            assert!(!outside.join(".bashrc").exists(), "{}", name);
+        }
+
+        // Symlinks within the directory are fine
+        let archive_path = dir.path().join("inside.tar");
+        fs::write(
+            &archive_path,
+            tar(&[
+                ("pkg/sub/", tar::EntryType::Directory, ""),
+                ("pkg/sub/l", tar::EntryType::Symlink, "../file"),
+                ("pkg/file", tar::EntryType::Regular, ""),
+            ]),
+        )
+        .unwrap();
+        extract(&archive_path, &dir.path().join("inside"), 1, &mut |_, _| ()).unwrap();
+        assert_eq!(
+            fs::read_link(dir.path().join("inside").join("sub").join("l")).unwrap(),
+            Path::new("../file")
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! The PlatformIO project model: `platformio.ini`, package versions and release
+//! manifests.
+//!
+//! Everything in here works on strings and data structures only, without filesystem,
+//! process or network access, so that it also compiles to `wasm32`, e.g. for web
+//! dashboards inspecting the release manifests and projects produced by CI. The
+//! [`pio`](crate::pio) module builds on it.
+
+pub mod schema;
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The names of all `[env:<name>]` sections of `platformio_ini`.
+pub fn environments(platformio_ini: &str) -> Vec<String> {
+    platformio_ini
+        .lines()
+        .filter_map(|line| {
+            line.trim()
+                .strip_prefix("[env:")?
+                .strip_suffix(']')
+                .map(str::to_owned)
+        })
+        .collect()
+}
+
+/// The value of `key` of the `[env:<environment>]` section of `platformio_ini`, falling
+/// back to the common `[env]` section, with continuation lines joined by newlines.
+pub fn env_option(platformio_ini: &str, environment: &str, key: &str) -> Option<String> {
+    let env_section = format!("[env:{}]", environment);
+
+    let mut section = String::new();
+    let mut current: Option<(String, String)> = None;
+    let mut values = BTreeMap::new();
+
+    let mut finish = |section: &str, current: &mut Option<(String, String)>| {
+        if let Some((name, value)) = current.take() {
+            if name == key && (section == env_section || section == "[env]") {
+                values.insert(section == env_section, value);
+            }
+        }
+    };
+
+    for line in platformio_ini.lines() {
+        let content = line.split(';').next().unwrap_or_default().trim_end();
+
+        if content.trim().is_empty() {
+            continue;
+        }
+
+        if line.starts_with(char::is_whitespace) && current.is_some() {
+            if let Some((_, value)) = &mut current {
+                value.push('\n');
+                value.push_str(content.trim());
+            }
+
+            continue;
+        }
+
+        finish(&section, &mut current);
+
+        if content.starts_with('[') {
+            section = content.trim().to_owned();
+        } else if let Some((name, value)) = content.split_once('=') {
+            current = Some((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+
+    finish(&section, &mut current);
+
+    values
+        .remove(&true)
+        .or_else(|| values.remove(&false))
+        .filter(|value| !value.is_empty())
+}
+
+/// Set the option `key` of the section `[env:<environment>]` of `platformio_ini` to
+/// `value`, replacing its current value. `None` if there is no such section.
+///
+/// A value of several lines is written as an indented list.
+pub fn set_env_option(
+    platformio_ini: &str,
+    environment: &str,
+    key: &str,
+    value: &str,
+) -> Option<String> {
+    let env_section = format!("[env:{}]", environment);
+
+    let option = if value.contains('\n') {
+        value.lines().fold(format!("{} =\n", key), |option, line| {
+            option + "    " + line + "\n"
+        })
+    } else {
+        format!("{} = {}\n", key, value)
+    };
+
+    let mut out = String::new();
+    let mut found = false;
+    let mut in_section = false;
+    let mut written = false;
+    let mut replacing = false;
+    // The empty lines at the end of the section, the option is inserted before them
+    let mut empty = String::new();
+
+    for line in platformio_ini.lines() {
+        let content = line.split(';').next().unwrap_or_default().trim();
+
+        if replacing {
+            if line.starts_with(char::is_whitespace) && !content.is_empty() {
+                continue;
+            }
+            replacing = false;
+        }
+
+        if content.starts_with('[') {
+            if in_section && !written {
+                out.push_str(&option);
+                written = true;
+            }
+            out.push_str(&std::mem::take(&mut empty));
+
+            in_section = content == env_section;
+            found |= in_section;
+        } else if in_section && !written && content.is_empty() {
+            empty.push_str(line);
+            empty.push('\n');
+            continue;
+        } else {
+            out.push_str(&std::mem::take(&mut empty));
+
+            if in_section
+                && !line.starts_with(char::is_whitespace)
+                && content
+                    .split_once('=')
+                    .map_or(false, |(name, _)| name.trim() == key)
+            {
+                if !written {
+                    out.push_str(&option);
+                    written = true;
+                }
+                replacing = true;
+                continue;
+            }
+        }
+
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    if in_section && !written {
+        out.push_str(&option);
+    }
+    out.push_str(&empty);
+
+    found.then(|| out)
+}
+
+/// Split a list option, which may be separated by commas or newlines.
+pub fn list(value: &str) -> Vec<String> {
+    value
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Compare the versions `a` and `b` by their numeric components, e.g. `1.10.0` after
+/// `1.9.2`; the remainder (`+2021r2-patch3`) only breaks ties.
+pub fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    fn numbers(version: &str) -> Vec<u64> {
+        version
+            .split(|c: char| !c.is_ascii_digit() && c != '.')
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .filter_map(|number| number.parse().ok())
+            .collect()
+    }
+
+    numbers(a).cmp(&numbers(b)).then_with(|| a.cmp(b))
+}
+
+/// The manifest of a release.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    /// The name of the Cargo package.
+    pub name: String,
+    /// The version of the Cargo package.
+    pub version: String,
+    /// The output of `git describe`, if the project is in a git repository.
+    pub git_describe: Option<String>,
+    /// The output of `rustc --version`.
+    pub rustc: Option<String>,
+    /// The output of `pio --version`.
+    pub platformio: Option<String>,
+    /// The toolchain components of every environment, as `<name>@<version>`.
+    pub toolchains: BTreeMap<String, Vec<String>>,
+    pub artifacts: Vec<Artifact>,
+}
+
+/// A file of a release.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Artifact {
+    /// The path within the release.
+    pub path: String,
+    pub size: u64,
+    /// The SHA-256 checksum, hex encoded.
+    pub sha256: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_option() {
+        let ini = "[env]\nframework = arduino\n\n[env:debug]\nplatform = espressif32 ; pinned later\nlib_deps =\n    bblanchon/ArduinoJson@^6\n    knolleary/PubSubClient\n";
+
+        assert_eq!(
+            env_option(ini, "debug", "platform").as_deref(),
+            Some("espressif32")
+        );
+        assert_eq!(
+            env_option(ini, "debug", "framework").as_deref(),
+            Some("arduino")
+        );
+        assert_eq!(
+            list(&env_option(ini, "debug", "lib_deps").unwrap()),
+            vec!["bblanchon/ArduinoJson@^6", "knolleary/PubSubClient"]
+        );
+
+        let ini = set_env_option(ini, "debug", "lib_deps", "a\nb").unwrap();
+        let ini = set_env_option(&ini, "debug", "board", "esp32dev").unwrap();
+        assert_eq!(
+            ini,
+            "[env]\nframework = arduino\n\n[env:debug]\nplatform = espressif32 ; pinned later\nlib_deps =\n    a\n    b\nboard = esp32dev\n"
+        );
+        assert_eq!(set_env_option(&ini, "release", "board", "esp32dev"), None);
+    }
+}
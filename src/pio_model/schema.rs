@@ -0,0 +1,285 @@
+//! JSON Schemas of the JSON files cargo-pio produces, and strict deserialization.
+//!
+//! The schemas are the stable contract for external tools generating or consuming the
+//! files: [`RELEASE_MANIFEST`] for `release.json` of releases (see
+//! [`Manifest`](super::Manifest)) and [`PACKAGE_GRAPH`] for the output of `cargo pio pkg
+//! graph --json`. They are JSON Schema draft 07 and reject unknown properties, so that
+//! [`from_str_strict`] reports fields the reader does not know instead of dropping them.
+//!
+//! [`validate`] implements the subset of JSON Schema the schemas use.
+
+use anyhow::{bail, Result};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+/// The name of the schema of release manifests.
+pub const RELEASE_MANIFEST: &str = "release-manifest";
+/// The name of the schema of package graphs.
+pub const PACKAGE_GRAPH: &str = "package-graph";
+
+/// The names of all schemas.
+pub const SCHEMAS: &[&str] = &[RELEASE_MANIFEST, PACKAGE_GRAPH];
+
+/// The JSON Schema named `name` (one of [`SCHEMAS`]).
+pub fn schema(name: &str) -> Option<Value> {
+    match name {
+        RELEASE_MANIFEST => Some(release_manifest()),
+        PACKAGE_GRAPH => Some(package_graph()),
+        _ => None,
+    }
+}
+
+fn release_manifest() -> Value {
+    let nullable_string = json!({ "type": ["string", "null"] });
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "cargo-pio release manifest (release.json)",
+        "type": "object",
+        "additionalProperties": false,
+        "required": ["name", "version", "git_describe", "rustc", "platformio", "toolchains", "artifacts"],
+        "properties": {
+            "name": { "type": "string", "description": "The name of the Cargo package" },
+            "version": { "type": "string", "description": "The version of the Cargo package" },
+            "git_describe": nullable_string,
+            "rustc": nullable_string,
+            "platformio": nullable_string,
+            "toolchains": {
+                "type": "object",
+                "description": "The toolchain components of every environment, as <name>@<version>",
+                "additionalProperties": { "type": "array", "items": { "type": "string" } }
+            },
+            "artifacts": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "required": ["path", "size", "sha256"],
+                    "properties": {
+                        "path": { "type": "string" },
+                        "size": { "type": "integer", "minimum": 0 },
+                        "sha256": { "type": "string" }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn package_graph() -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "cargo-pio package graph (cargo pio pkg graph --json)",
+        "type": "object",
+        "additionalProperties": false,
+        "required": ["nodes", "edges"],
+        "properties": {
+            "nodes": {
+                "type": "object",
+                "description": "The nodes, keyed by <kind>:<name>",
+                "additionalProperties": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "required": ["kind", "name", "version", "size"],
+                    "properties": {
+                        "kind": { "enum": ["environment", "platform", "package", "library"] },
+                        "name": { "type": "string" },
+                        "version": { "type": ["string", "null"] },
+                        "package_type": { "type": "string" },
+                        "size": { "type": ["integer", "null"], "minimum": 0 }
+                    }
+                }
+            },
+            "edges": {
+                "type": "array",
+                "description": "The edges, from dependent to dependency",
+                "items": {
+                    "type": "array",
+                    "minItems": 2,
+                    "maxItems": 2,
+                    "items": { "type": "string" }
+                }
+            }
+        }
+    })
+}
+
+/// Validate `value` against `schema`, returning the violations as `<path>: <message>`,
+/// e.g. `$.artifacts[0].md5: unknown property`.
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    validate_at(schema, value, "$", &mut errors);
+
+    errors
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    if let Some(types) = schema.get("type") {
+        let types = match types {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            types => types.as_str().into_iter().collect::<Vec<_>>(),
+        };
+
+        if !types.iter().any(|ty| has_type(value, ty)) {
+            errors.push(format!("{}: expected {}", path, types.join(" or ")));
+            return;
+        }
+    }
+
+    if let Some(Value::Array(variants)) = schema.get("enum") {
+        if !variants.contains(value) {
+            errors.push(format!(
+                "{}: {} is none of {}",
+                path,
+                value,
+                Value::from(variants.clone())
+            ));
+        }
+    }
+
+    if let (Some(minimum), Some(number)) = (
+        schema.get("minimum").and_then(Value::as_f64),
+        value.as_f64(),
+    ) {
+        if number < minimum {
+            errors.push(format!("{}: {} is less than {}", path, number, minimum));
+        }
+    }
+
+    if let Value::Object(object) = value {
+        let properties = schema.get("properties").and_then(Value::as_object);
+
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(required) {
+                errors.push(format!("{}: missing property '{}'", path, required));
+            }
+        }
+
+        for (name, property) in object {
+            let path = format!("{}.{}", path, name);
+
+            match (
+                properties.and_then(|properties| properties.get(name)),
+                schema.get("additionalProperties"),
+            ) {
+                (Some(schema), _) => validate_at(schema, property, &path, errors),
+                (None, Some(Value::Bool(false))) => {
+                    errors.push(format!("{}: unknown property", path))
+                }
+                (None, Some(schema @ Value::Object(_))) => {
+                    validate_at(schema, property, &path, errors)
+                }
+                (None, _) => (),
+            }
+        }
+    }
+
+    if let Value::Array(items) = value {
+        let len = items.len() as u64;
+
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if len < min {
+                errors.push(format!("{}: expected at least {} items", path, min));
+            }
+        }
+
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if len > max {
+                errors.push(format!("{}: expected at most {} items", path, max));
+            }
+        }
+
+        if let Some(schema) = schema.get("items") {
+            for (index, item) in items.iter().enumerate() {
+                validate_at(schema, item, &format!("{}[{}]", path, index), errors);
+            }
+        }
+    }
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    }
+}
+
+/// Deserialize `json` after validating it against the schema named `name`, failing on
+/// any violation, unknown properties included.
+pub fn from_str_strict<T: DeserializeOwned>(name: &str, json: &str) -> Result<T> {
+    let schema = match schema(name) {
+        Some(schema) => schema,
+        None => bail!(
+            "Unknown schema '{}', expected one of {}",
+            name,
+            SCHEMAS.join(", ")
+        ),
+    };
+
+    let value = serde_json::from_str::<Value>(json)?;
+
+    let errors = validate(&schema, &value);
+    if !errors.is_empty() {
+        bail!("Invalid {}:\n{}", name, errors.join("\n"));
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Artifact, Manifest};
+    use super::*;
+
+    #[test]
+    fn test_release_manifest() {
+        let manifest = Manifest {
+            name: "blinky".into(),
+            version: "0.1.0".into(),
+            git_describe: Some("v0.1.0".into()),
+            rustc: None,
+            platformio: None,
+            toolchains: [(
+                "release".to_owned(),
+                vec!["toolchain-xtensa-esp32@8.4.0".to_owned()],
+            )]
+            .into_iter()
+            .collect(),
+            artifacts: vec![Artifact {
+                path: "release/firmware.bin".into(),
+                size: 1024,
+                sha256: "00".repeat(32),
+            }],
+        };
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        let parsed = from_str_strict::<Manifest>(RELEASE_MANIFEST, &json).unwrap();
+        assert_eq!(parsed.artifacts[0].size, 1024);
+
+        let mut value = serde_json::to_value(&manifest).unwrap();
+        value["artifacts"][0]["md5"] = json!("");
+        value["artifacts"][0]["size"] = json!(-1);
+        value.as_object_mut().unwrap().remove("rustc");
+
+        assert_eq!(
+            validate(&schema(RELEASE_MANIFEST).unwrap(), &value),
+            [
+                "$: missing property 'rustc'",
+                "$.artifacts[0].md5: unknown property",
+                "$.artifacts[0].size: -1 is less than 0"
+            ]
+        );
+    }
+}
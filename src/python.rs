@@ -3,6 +3,7 @@
 use anyhow::{anyhow, Context, Result};
 
 use crate::cmd;
+use crate::error::HintExt;
 
 /// Python 3 executable name.
 ///
@@ -20,7 +21,13 @@ pub const PYTHON: &str = {
 pub fn check_python_at_least(major: u32, minor: u32) -> Result<()> {
     let version_str = cmd!(PYTHON, "--version")
         .stdout()
-        .context("Failed to locate python. Is python installed and in your $PATH?")?;
+        .with_hint(|| {
+            format!(
+                "Install Python 3 and make sure '{}' is in your PATH",
+                PYTHON
+            )
+        })
+        .context("Failed to locate python")?;
 
     let base_err = || anyhow!("Unexpected output from {}", PYTHON);
 
@@ -0,0 +1,402 @@
+//! Static worst-case stack usage analysis.
+//!
+//! Combines the per-function stack usage and call graph emitted by GCC for the C side
+//! (`-fstack-usage` `.su` files and `-fcallgraph-info=su` `.ci` files) with the
+//! `.stack_sizes` section rustc emits for the Rust side (`-Z emit-stack-sizes`), and
+//! computes the worst-case stack depth reachable from an entry point (e.g. a FreeRTOS
+//! task function).
+//!
+//! Only the `.ci` files know which functions a function calls. The calls of all other
+//! functions, e.g. the Rust ones or C ones built without `-fcallgraph-info` (which needs
+//! GCC 10), are unknown, and worst cases through them are lower bounds only.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use log::*;
+
+use crate::elf;
+
+/// How precise the stack usage of a [`Function`] is.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Qualifier {
+    /// The stack usage is exact.
+    Static,
+    /// The stack usage depends on runtime values but has a known upper bound.
+    Bounded,
+    /// The stack usage depends on runtime values (e.g. `alloca` or VLAs).
+    Dynamic,
+}
+
+/// A function in the call graph.
+#[derive(Clone, Default, Debug)]
+pub struct Function {
+    /// The stack usage of the function itself (excluding callees), if known.
+    pub stack: Option<(u64, Qualifier)>,
+    /// The functions called by this function.
+    pub calls: BTreeSet<String>,
+    /// Whether the function makes indirect calls (through function pointers).
+    pub indirect_calls: bool,
+    /// Whether [`Function::calls`] are known, i.e. the function is part of a call graph.
+    pub calls_known: bool,
+}
+
+/// The worst-case stack usage of an entry point.
+#[derive(Clone, Default, Debug)]
+pub struct WorstCase {
+    /// The worst-case stack usage in bytes, including all (known) callees.
+    pub bytes: u64,
+    /// The call path with the worst-case stack usage.
+    pub path: Vec<String>,
+    /// Whether the result is a lower bound only, because the call graph contains
+    /// recursion, indirect calls, dynamic stack usage or functions without stack usage
+    /// information.
+    pub unbounded: bool,
+    /// Functions reachable from the entry point for which no stack usage is known.
+    pub unknown: BTreeSet<String>,
+    /// Functions reachable from the entry point whose callees are not known.
+    pub unknown_calls: BTreeSet<String>,
+    /// Functions which are part of a recursion.
+    pub recursive: BTreeSet<String>,
+}
+
+/// A call graph annotated with stack usage information.
+#[derive(Clone, Default, Debug)]
+pub struct CallGraph {
+    /// All known functions by name.
+    pub functions: BTreeMap<String, Function>,
+}
+
+impl CallGraph {
+    /// Create an empty call graph.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add all `.su` and `.ci` files found in `dir` (recursively) to this call graph.
+    pub fn add_dir(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.is_dir() {
+                self.add_dir(&path)?;
+            } else {
+                match path.extension().and_then(|e| e.to_str()) {
+                    Some("su") => self.add_stack_usage(&fs::read_to_string(&path)?),
+                    Some("ci") => self.add_callgraph_info(&fs::read_to_string(&path)?),
+                    _ => continue,
+                }
+
+                trace!("Added stack usage information from {}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add the contents of a GCC `-fstack-usage` (`.su`) file.
+    ///
+    /// Every line has the format `<file>:<line>:<column>:<function>\t<bytes>\t<qualifier>`.
+    pub fn add_stack_usage(&mut self, su: &str) {
+        for line in su.lines() {
+            let mut fields = line.split('\t');
+
+            let (location, bytes, qualifier) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(location), Some(bytes), Some(qualifier)) => (location, bytes, qualifier),
+                _ => continue,
+            };
+
+            let name = location.rsplit(':').next().unwrap_or(location);
+
+            if let (Ok(bytes), Some(qualifier)) = (bytes.trim().parse(), parse_qualifier(qualifier))
+            {
+                self.function(name).stack = Some((bytes, qualifier));
+            }
+        }
+    }
+
+    /// Add the contents of a GCC `-fcallgraph-info=su` (`.ci`) file.
+    ///
+    /// These files are in the VCG format and contain a node per function (labeled with its
+    /// stack usage) and an edge per call.
+    pub fn add_callgraph_info(&mut self, ci: &str) {
+        for line in ci.lines() {
+            let line = line.trim();
+
+            if let Some(node) = line.strip_prefix("node:") {
+                let (title, label) = match (vcg_attr(node, "title"), vcg_attr(node, "label")) {
+                    (Some(title), Some(label)) => (title, label),
+                    _ => continue,
+                };
+
+                // The label is `<name>\n<location>\n<bytes> bytes (<qualifier>)`
+                let stack = label.split("\\n").find_map(|part| {
+                    let (bytes, qualifier) = part.split_once(" bytes (")?;
+                    Some((
+                        bytes.trim().parse().ok()?,
+                        parse_qualifier(qualifier.trim_end_matches(')'))?,
+                    ))
+                });
+
+                let function = self.function(&title);
+                function.calls_known = true;
+                if stack.is_some() {
+                    function.stack = stack;
+                }
+            } else if let Some(edge) = line.strip_prefix("edge:") {
+                if let (Some(source), Some(target)) =
+                    (vcg_attr(edge, "sourcename"), vcg_attr(edge, "targetname"))
+                {
+                    if target == "__indirect_call" {
+                        self.function(&source).indirect_calls = true;
+                    } else {
+                        self.function(&source).calls.insert(target);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add the stack usage recorded in the `.stack_sizes` section of the ELF file at
+    /// `path` (emitted by rustc with `-Z emit-stack-sizes`).
+    ///
+    /// Returns the number of functions added.
+    pub fn add_elf_stack_sizes(&mut self, path: impl AsRef<Path>) -> Result<usize> {
+        let data = fs::read(path)?;
+        let info = elf::ElfInfo::parse(&data)?;
+
+        let stack_sizes = match elf::section_data(&data, ".stack_sizes")? {
+            Some(stack_sizes) => stack_sizes,
+            None => return Ok(0),
+        };
+
+        let pointer_size = if info.is_64bit { 8 } else { 4 };
+        let mut offset = 0;
+        let mut count = 0;
+
+        while offset + pointer_size <= stack_sizes.len() {
+            let mut address = [0_u8; 8];
+            address[..pointer_size].copy_from_slice(&stack_sizes[offset..offset + pointer_size]);
+            let address = u64::from_le_bytes(address);
+            offset += pointer_size;
+
+            let (bytes, len) = match read_uleb128(&stack_sizes[offset..]) {
+                Some(value) => value,
+                None => break,
+            };
+            offset += len;
+
+            // Thumb function addresses have the lowest bit set
+            if let Some(symbol) = info
+                .function_at(address)
+                .or_else(|| info.function_at(address & !1))
+            {
+                self.function(&symbol.name).stack = Some((bytes, Qualifier::Static));
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Compute the worst-case stack usage of `entry` and all functions it calls.
+    pub fn worst_case(&self, entry: impl AsRef<str>) -> WorstCase {
+        let mut result = WorstCase::default();
+        let mut memo = BTreeMap::new();
+        let mut stack = Vec::new();
+
+        let (bytes, path) = self.visit(entry.as_ref(), &mut stack, &mut memo, &mut result);
+
+        result.bytes = bytes;
+        result.path = path;
+        result.unbounded |= !result.unknown.is_empty()
+            || !result.unknown_calls.is_empty()
+            || !result.recursive.is_empty();
+
+        result
+    }
+
+    fn visit(
+        &self,
+        name: &str,
+        stack: &mut Vec<String>,
+        memo: &mut BTreeMap<String, (u64, Vec<String>)>,
+        result: &mut WorstCase,
+    ) -> (u64, Vec<String>) {
+        if let Some(cached) = memo.get(name) {
+            return cached.clone();
+        }
+
+        if stack.iter().any(|s| s == name) {
+            result.recursive.insert(name.to_owned());
+            return (0, Vec::new());
+        }
+
+        let function = match self.functions.get(name) {
+            Some(function) => function,
+            None => {
+                result.unknown.insert(name.to_owned());
+                return (0, vec![name.to_owned()]);
+            }
+        };
+
+        let own = match function.stack {
+            Some((bytes, qualifier)) => {
+                result.unbounded |= qualifier == Qualifier::Dynamic;
+                bytes
+            }
+            None => {
+                result.unknown.insert(name.to_owned());
+                0
+            }
+        };
+
+        result.unbounded |= function.indirect_calls;
+
+        if !function.calls_known {
+            result.unknown_calls.insert(name.to_owned());
+        }
+
+        stack.push(name.to_owned());
+
+        let (callee_bytes, callee_path) = function
+            .calls
+            .iter()
+            .map(|callee| self.visit(callee, stack, memo, result))
+            .max_by_key(|(bytes, _)| *bytes)
+            .unwrap_or_default();
+
+        stack.pop();
+
+        let mut path = vec![name.to_owned()];
+        path.extend(callee_path);
+
+        let value = (own + callee_bytes, path);
+        memo.insert(name.to_owned(), value.clone());
+
+        value
+    }
+
+    fn function(&mut self, name: &str) -> &mut Function {
+        self.functions.entry(name.to_owned()).or_default()
+    }
+}
+
+/// The oldest GCC version supporting `-fcallgraph-info`.
+pub const CALLGRAPH_INFO_GCC: u32 = 10;
+
+/// The flags emitting the stack usage information when building with GCC `gcc_major`:
+/// `-fstack-usage`, and `-fcallgraph-info=su` if it is known to be supported.
+pub fn build_flags(gcc_major: Option<u32>) -> &'static str {
+    match gcc_major {
+        Some(major) if major >= CALLGRAPH_INFO_GCC => "-fstack-usage -fcallgraph-info=su",
+        _ => "-fstack-usage",
+    }
+}
+
+/// The major version of the output of `gcc -dumpversion`, e.g. `8` of `8.4.0`.
+pub fn gcc_major(version: &str) -> Option<u32> {
+    version.trim().split('.').next()?.parse().ok()
+}
+
+fn parse_qualifier(qualifier: &str) -> Option<Qualifier> {
+    // GCC emits e.g. `static`, `dynamic`, `dynamic,bounded`
+    let qualifier = qualifier.trim();
+
+    Some(if qualifier == "static" {
+        Qualifier::Static
+    } else if qualifier.contains("bounded") {
+        Qualifier::Bounded
+    } else if qualifier.starts_with("dynamic") {
+        Qualifier::Dynamic
+    } else {
+        return None;
+    })
+}
+
+/// Get the value of the quoted attribute `name` (`name: "value"`) of a VCG node or edge.
+fn vcg_attr(s: &str, name: &str) -> Option<String> {
+    let pattern = format!("{}: \"", name);
+    let start = s.find(&pattern)? + pattern.len();
+    let end = s[start..].find('"')?;
+
+    Some(s[start..start + end].to_owned())
+}
+
+fn read_uleb128(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0_u64;
+
+    for (index, byte) in data.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * index);
+
+        if byte & 0x80 == 0 {
+            return Some((value, index + 1));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worst_case() {
+        let mut graph = CallGraph::new();
+
+        graph.add_callgraph_info(
+            r#"graph: { title: "main.c"
+node: { title: "app_main" label: "app_main\nmain.c:3:6\n32 bytes (static)" }
+node: { title: "rust_main" label: "rust_main\nmain.c:10:6\n" shape : ellipse }
+node: { title: "log" label: "log\nlog.c:1:6\n200 bytes (dynamic,bounded)" }
+edge: { sourcename: "app_main" targetname: "rust_main" }
+edge: { sourcename: "app_main" targetname: "log" }
+}"#,
+        );
+        graph.add_stack_usage("lib.rs:1:1:rust_main\t64\tstatic\n");
+
+        let worst_case = graph.worst_case("app_main");
+
+        assert_eq!(worst_case.bytes, 232);
+        assert_eq!(
+            worst_case.path,
+            vec!["app_main".to_owned(), "log".to_owned()]
+        );
+        assert!(!worst_case.unbounded);
+
+        // Without a call graph, the callees of a function are unknown
+        graph.add_stack_usage("lib.rs:2:1:rust_task\t48\tstatic\n");
+        let worst_case = graph.worst_case("rust_task");
+
+        assert_eq!(worst_case.bytes, 48);
+        assert!(worst_case.unbounded);
+        assert!(worst_case.unknown_calls.contains("rust_task"));
+
+        assert_eq!(build_flags(gcc_major("8.4.0")), "-fstack-usage");
+        assert_eq!(
+            build_flags(gcc_major("12.2.0\n")),
+            "-fstack-usage -fcallgraph-info=su"
+        );
+        assert_eq!(build_flags(None), "-fstack-usage");
+    }
+
+    #[test]
+    fn test_recursion() {
+        let mut graph = CallGraph::new();
+
+        graph.add_stack_usage("a.c:1:1:a\t16\tstatic\na.c:2:1:b\t8\tstatic\n");
+        graph.function("a").calls.insert("b".into());
+        graph.function("a").calls_known = true;
+        graph.function("b").calls.insert("a".into());
+        graph.function("b").calls_known = true;
+
+        let worst_case = graph.worst_case("a");
+
+        assert_eq!(worst_case.bytes, 24);
+        assert!(worst_case.unbounded);
+        assert!(worst_case.recursive.contains("a"));
+    }
+}
@@ -0,0 +1,396 @@
+//! Deny-lists of symbols which should not end up in a firmware.
+//!
+//! Some machinery is easily pulled in by accident and costs a lot of flash: the panic
+//! and formatting code of `core`, the soft-float intrinsics on MCUs without an FPU, or
+//! the allocator in projects which are supposed to be allocation-free. A [`Rule`]
+//! denies such symbols, and [`check`] finds them in the call graph of the firmware (as
+//! disassembled by `objdump -d -C`) together with the functions outside of `core`,
+//! `alloc` and `compiler_builtins` which are responsible for them.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+use crate::elf::ElfInfo;
+
+/// The crates whose functions are not reported as responsible for a denied symbol, as
+/// they only forward to it.
+const INTERNAL_CRATES: &[&str] = &["core", "alloc", "compiler_builtins", "std"];
+
+/// A named list of denied symbol patterns.
+///
+/// A pattern ending with `*` matches all symbols starting with it, others match the
+/// symbol exactly. Symbols are compared demangled, without their hash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub name: String,
+    pub patterns: Vec<String>,
+}
+
+impl Rule {
+    pub fn new(name: impl Into<String>, patterns: &[impl AsRef<str>]) -> Self {
+        Self {
+            name: name.into(),
+            patterns: patterns.iter().map(|p| p.as_ref().to_owned()).collect(),
+        }
+    }
+
+    /// The builtin rule `name`: `panic`, `float` or `alloc`.
+    pub fn builtin(name: &str) -> Option<Self> {
+        let patterns: &[&str] = match name {
+            "panic" => &[
+                "core::panicking::*",
+                "core::result::unwrap_failed",
+                "core::option::expect_failed",
+                "core::fmt::write",
+                "core::fmt::Formatter::pad*",
+                "core::fmt::num::*",
+                "core::slice::index::*_fail",
+            ],
+            "float" => &[
+                "__aeabi_f*",
+                "__aeabi_d*",
+                "__aeabi_i2f",
+                "__aeabi_i2d",
+                "__aeabi_ui2f",
+                "__aeabi_ui2d",
+                "__addsf3",
+                "__adddf3",
+                "__subsf3",
+                "__subdf3",
+                "__mulsf3",
+                "__muldf3",
+                "__divsf3",
+                "__divdf3",
+                "__floatsisf",
+                "__floatsidf",
+                "__floatunsisf",
+                "__floatunsidf",
+                "__fixsfsi",
+                "__fixdfsi",
+                "__fixunssfsi",
+                "__fixunsdfsi",
+                "__extendsfdf2",
+                "__truncdfsf2",
+            ],
+            "alloc" => &[
+                "__rust_alloc",
+                "__rust_alloc_zeroed",
+                "__rust_realloc",
+                "alloc::alloc::handle_alloc_error",
+                "malloc",
+                "calloc",
+                "realloc",
+                "_malloc_r",
+                "_calloc_r",
+                "_realloc_r",
+            ],
+            _ => return None,
+        };
+
+        Some(Self::new(name, patterns))
+    }
+
+    /// Whether `symbol` (normalized with [`normalize`]) is denied by this rule.
+    pub fn matches(&self, symbol: &str) -> bool {
+        self.patterns.iter().any(|pattern| matches(pattern, symbol))
+    }
+}
+
+fn matches(pattern: &str, symbol: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => symbol.starts_with(prefix),
+        None => symbol == pattern,
+    }
+}
+
+fn is_allowed(allowed: &[String], function: &str) -> bool {
+    allowed.iter().any(|pattern| matches(pattern, function))
+}
+
+/// Normalize the demangled `symbol`: remove the hash of legacy mangled symbols
+/// (`::h0123456789abcdef`) and the crate disambiguators of v0 mangled ones (`core[abcd]`).
+pub fn normalize(symbol: &str) -> String {
+    let symbol = match symbol.rfind("::h") {
+        Some(pos)
+            if symbol.len() - pos == 19
+                && symbol[pos + 3..].chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            &symbol[..pos]
+        }
+        _ => symbol,
+    };
+
+    let mut normalized = String::with_capacity(symbol.len());
+    let mut disambiguator = false;
+
+    for c in symbol.chars() {
+        match c {
+            '[' => disambiguator = true,
+            ']' if disambiguator => disambiguator = false,
+            _ if disambiguator => (),
+            c => normalized.push(c),
+        }
+    }
+
+    normalized
+}
+
+/// The crate of the normalized Rust `symbol`, `None` for C symbols.
+pub fn crate_of(symbol: &str) -> Option<&str> {
+    let path = symbol.trim_start_matches('<').trim_start_matches('&');
+    let path = path.strip_prefix("mut ").unwrap_or(path);
+
+    path.find("::").map(|pos| &path[..pos])
+}
+
+/// The call graph of a firmware, as references between functions.
+#[derive(Clone, Debug, Default)]
+pub struct CallGraph {
+    /// The functions referencing every function.
+    pub callers: BTreeMap<String, BTreeSet<String>>,
+    /// All functions.
+    pub functions: BTreeSet<String>,
+}
+
+impl CallGraph {
+    /// Parse the output of `objdump -d -C`.
+    ///
+    /// Every `<symbol>` operand of an instruction (calls, jumps and loads of addresses)
+    /// is a reference of the function the instruction is in.
+    pub fn parse_disassembly(disassembly: &str) -> Self {
+        let mut graph = Self::default();
+        let mut function: Option<String> = None;
+
+        for line in disassembly.lines() {
+            let trimmed = line.trim_end();
+
+            // `00001234 <symbol>:` starts a function
+            if let Some(name) = trimmed
+                .strip_suffix(">:")
+                .and_then(|line| line.split_once(" <"))
+                .filter(|(address, _)| address.chars().all(|c| c.is_ascii_hexdigit()))
+                .map(|(_, name)| normalize(name))
+            {
+                graph.functions.insert(name.clone());
+                function = Some(name);
+                continue;
+            }
+
+            let function = match &function {
+                Some(function) if line.starts_with(' ') => function,
+                _ => continue,
+            };
+
+            if let (Some(start), Some(end)) = (trimmed.find('<'), trimmed.rfind('>')) {
+                if start >= end {
+                    continue;
+                }
+
+                let target = &trimmed[start + 1..end];
+                let target = match target.rfind("+0x") {
+                    Some(pos) => &target[..pos],
+                    None => target,
+                };
+                let target = normalize(target);
+
+                if &target != function {
+                    graph
+                        .callers
+                        .entry(target)
+                        .or_default()
+                        .insert(function.clone());
+                }
+            }
+        }
+
+        graph
+    }
+
+    /// The functions responsible for `symbol`: its nearest callers which are not in
+    /// the [`INTERNAL_CRATES`] nor denied by `rules` themselves, or which are `allowed`.
+    fn responsible(&self, symbol: &str, rules: &[Rule], allowed: &[String]) -> BTreeSet<String> {
+        let mut responsible = BTreeSet::new();
+        let mut visited = BTreeSet::new();
+        let mut queue = VecDeque::from([symbol.to_owned()]);
+
+        while let Some(function) = queue.pop_front() {
+            for caller in self.callers.get(&function).into_iter().flatten() {
+                if !visited.insert(caller.clone()) {
+                    continue;
+                }
+
+                let internal = crate_of(caller)
+                    .map(|krate| INTERNAL_CRATES.contains(&krate))
+                    .unwrap_or(false);
+                let denied = rules.iter().any(|rule| rule.matches(caller));
+
+                if is_allowed(allowed, caller) || !(internal || denied) {
+                    responsible.insert(caller.clone());
+                } else {
+                    queue.push_back(caller.clone());
+                }
+            }
+        }
+
+        responsible
+    }
+}
+
+/// A denied symbol found in a firmware.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    /// The name of the rule denying the symbol.
+    pub rule: String,
+    pub symbol: String,
+    /// The functions responsible for the symbol, empty if it is only referenced by
+    /// internal functions (e.g. from the vector table).
+    pub responsible: Vec<String>,
+}
+
+impl Finding {
+    /// The crates of the responsible functions, `C` for C functions.
+    pub fn crates(&self) -> BTreeSet<&str> {
+        self.responsible
+            .iter()
+            .map(|function| crate_of(function).unwrap_or("C"))
+            .collect()
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.rule, self.symbol)?;
+
+        if !self.responsible.is_empty() {
+            write!(
+                f,
+                " (crates {})",
+                self.crates().into_iter().collect::<Vec<_>>().join(", ")
+            )?;
+
+            for function in &self.responsible {
+                write!(f, "\n    referenced by {}", function)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the functions of `graph` denied by `rules`.
+///
+/// A denied symbol is not reported if all functions responsible for it match one of
+/// the `allowed` patterns.
+pub fn check(graph: &CallGraph, rules: &[Rule], allowed: &[String]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    for symbol in &graph.functions {
+        let rule = match rules.iter().find(|rule| rule.matches(symbol)) {
+            Some(rule) => rule,
+            None => continue,
+        };
+
+        let (allowed_functions, responsible): (Vec<_>, Vec<_>) = graph
+            .responsible(symbol, rules, allowed)
+            .into_iter()
+            .partition(|function| is_allowed(allowed, function));
+
+        if responsible.is_empty() && !allowed_functions.is_empty() {
+            continue;
+        }
+
+        findings.push(Finding {
+            rule: rule.name.clone(),
+            symbol: symbol.clone(),
+            responsible,
+        });
+    }
+
+    findings
+}
+
+/// Disassemble `elf_file` with the `objdump` of its toolchain and return its call graph.
+pub fn call_graph(elf_file: impl AsRef<Path>) -> Result<CallGraph> {
+    let elf_file = elf_file.as_ref();
+
+    let objdump = ElfInfo::from_file(elf_file)?
+        .find_tool("objdump")
+        .context("Could not find the objdump of the toolchain of the firmware")?;
+
+    let output = Command::new(&objdump)
+        .arg("-d")
+        .arg("-C")
+        .arg(elf_file)
+        .output()
+        .with_context(|| format!("Failed to run {}", objdump.display()))?;
+
+    if !output.status.success() {
+        bail!(
+            "{} failed: {}",
+            objdump.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(CallGraph::parse_disassembly(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let disassembly = "
+firmware.elf:     file format elf32-littlearm
+
+Disassembly of section .text:
+
+08000100 <app::main::h0123456789abcdef>:
+ 8000100:\tb580      \tpush\t{r7, lr}
+ 8000102:\tf000 f805 \tbl\t8000110 <app::parse::h1111111111111111>
+ 8000106:\tf000 f810 \tbl\t8000200 <__aeabi_fmul>
+
+08000110 <app::parse::h1111111111111111>:
+ 8000110:\tf000 f820 \tbl\t8000300 <core::result::unwrap_failed::h2222222222222222>
+ 8000114:\te7fe      \tb.n\t8000114 <app::parse::h1111111111111111+0x4>
+
+08000180 <sensor[5f3a]::read>:
+ 8000180:\tf000 f800 \tbl\t8000200 <__aeabi_fmul>
+
+08000200 <__aeabi_fmul>:
+ 8000200:\t4770      \tbx\tlr
+
+08000300 <core::result::unwrap_failed::h2222222222222222>:
+ 8000300:\tf000 f900 \tbl\t8000400 <core::panicking::panic_fmt::h3333333333333333>
+
+08000400 <core::panicking::panic_fmt::h3333333333333333>:
+ 8000400:\te7fe      \tb.n\t8000400 <core::panicking::panic_fmt::h3333333333333333>
+";
+
+        let graph = CallGraph::parse_disassembly(disassembly);
+        assert!(graph.functions.contains("sensor::read"));
+
+        let rules = [
+            Rule::builtin("panic").unwrap(),
+            Rule::builtin("float").unwrap(),
+        ];
+
+        let findings = check(&graph, &rules, &["sensor::*".to_owned()]);
+
+        assert_eq!(
+            findings.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "[float] __aeabi_fmul (crates app)\n    referenced by app::main",
+                "[panic] core::panicking::panic_fmt (crates app)\n    referenced by app::parse",
+                "[panic] core::result::unwrap_failed (crates app)\n    referenced by app::parse",
+            ]
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! Terminal state of interactive commands.
+//!
+//! Interactive tools (PlatformIO's serial monitor, menuconfig, GDB) switch the terminal
+//! into raw mode or change the console mode on Windows, and leave it that way when they
+//! crash or get killed. A [`Guard`] saves the state before running them and restores it
+//! once dropped, also when unwinding from an error or a panic.
+//!
+//! Resizes reach the tools without help: the terminal sends `SIGWINCH` to all of its
+//! foreground processes, and Windows consoles report them as input events. Tools whose
+//! output is piped through cargo-pio cannot query the size of the terminal though, they
+//! get it passed with [`apply_size`] instead.
+
+use std::io;
+use std::process::Command;
+
+/// The saved state of the terminal, restored once dropped.
+///
+/// Does nothing if stdin is no terminal.
+pub struct Guard {
+    saved: Option<imp::State>,
+}
+
+impl Guard {
+    /// Save the state of the terminal.
+    pub fn save() -> Self {
+        Self {
+            saved: imp::State::get(),
+        }
+    }
+
+    /// Save the state of the terminal and switch it into raw mode: no line buffering, no
+    /// echo and no processing of special characters like CTRL-C.
+    pub fn raw() -> io::Result<Self> {
+        let guard = Self::save();
+
+        if let Some(saved) = &guard.saved {
+            saved.raw().set()?;
+        }
+
+        Ok(guard)
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            let _ = saved.set();
+        }
+    }
+}
+
+/// Whether stdin is a terminal, i.e. a user can answer prompts.
+pub fn is_interactive() -> bool {
+    imp::State::get().is_some()
+}
+
+/// Read a line from stdin without echoing it, e.g. a password.
+///
+/// If stdin is no terminal, the line is read as is.
+pub fn read_secret() -> io::Result<String> {
+    use std::io::{BufRead, Read};
+
+    if !is_interactive() {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+
+        return Ok(line.trim_end_matches(['\r', '\n']).to_owned());
+    }
+
+    let _guard = Guard::raw()?;
+
+    let mut secret = Vec::new();
+    for byte in io::stdin().lock().bytes() {
+        match byte? {
+            b'\r' | b'\n' => break,
+            // CTRL-C and CTRL-D
+            3 | 4 => return Err(io::ErrorKind::Interrupted.into()),
+            // Backspace and DEL
+            8 | 127 => {
+                secret.pop();
+            }
+            byte => secret.push(byte),
+        }
+    }
+
+    String::from_utf8(secret).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// The size of the terminal as `(columns, rows)`, none if stdout is no terminal.
+pub fn size() -> Option<(u16, u16)> {
+    imp::size()
+}
+
+/// Pass the size of the terminal to `cmd` with the `COLUMNS` and `LINES` environment
+/// variables, for its output being piped.
+pub fn apply_size(cmd: &mut Command) {
+    if let Some((columns, rows)) = size() {
+        cmd.env("COLUMNS", columns.to_string())
+            .env("LINES", rows.to_string());
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+
+    pub struct State(pub(super) libc::termios);
+
+    impl State {
+        pub fn get() -> Option<Self> {
+            unsafe {
+                if libc::isatty(libc::STDIN_FILENO) != 1 {
+                    return None;
+                }
+
+                let mut termios = std::mem::zeroed::<libc::termios>();
+                (libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0).then(|| Self(termios))
+            }
+        }
+
+        pub fn raw(&self) -> Self {
+            let mut termios = self.0;
+            unsafe { libc::cfmakeraw(&mut termios) };
+
+            Self(termios)
+        }
+
+        pub fn set(&self) -> io::Result<()> {
+            if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        }
+    }
+
+    pub fn size() -> Option<(u16, u16)> {
+        unsafe {
+            let mut size = std::mem::zeroed::<libc::winsize>();
+
+            (libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0)
+                .then(|| (size.ws_col, size.ws_row))
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::ffi::c_void;
+    use std::io;
+
+    const STD_INPUT_HANDLE: u32 = -10_i32 as u32;
+    const STD_OUTPUT_HANDLE: u32 = -11_i32 as u32;
+
+    const ENABLE_PROCESSED_INPUT: u32 = 0x0001;
+    const ENABLE_LINE_INPUT: u32 = 0x0002;
+    const ENABLE_ECHO_INPUT: u32 = 0x0004;
+    const ENABLE_VIRTUAL_TERMINAL_INPUT: u32 = 0x0200;
+
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct Coord {
+        x: i16,
+        y: i16,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct SmallRect {
+        left: i16,
+        top: i16,
+        right: i16,
+        bottom: i16,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct ConsoleScreenBufferInfo {
+        size: Coord,
+        cursor_position: Coord,
+        attributes: u16,
+        window: SmallRect,
+        maximum_window_size: Coord,
+    }
+
+    extern "system" {
+        fn GetStdHandle(std_handle: u32) -> *mut c_void;
+        fn GetConsoleMode(console: *mut c_void, mode: *mut u32) -> i32;
+        fn SetConsoleMode(console: *mut c_void, mode: u32) -> i32;
+        fn GetConsoleScreenBufferInfo(
+            console: *mut c_void,
+            info: *mut ConsoleScreenBufferInfo,
+        ) -> i32;
+    }
+
+    /// The modes of the input and output console.
+    pub struct State {
+        input: u32,
+        output: Option<u32>,
+    }
+
+    fn mode(std_handle: u32) -> Option<u32> {
+        let mut mode = 0;
+        (unsafe { GetConsoleMode(GetStdHandle(std_handle), &mut mode) } != 0).then(|| mode)
+    }
+
+    fn set_mode(std_handle: u32, mode: u32) -> io::Result<()> {
+        if unsafe { SetConsoleMode(GetStdHandle(std_handle), mode) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    impl State {
+        pub fn get() -> Option<Self> {
+            Some(Self {
+                input: mode(STD_INPUT_HANDLE)?,
+                output: mode(STD_OUTPUT_HANDLE),
+            })
+        }
+
+        pub fn raw(&self) -> Self {
+            Self {
+                input: (self.input
+                    & !(ENABLE_PROCESSED_INPUT | ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT))
+                    | ENABLE_VIRTUAL_TERMINAL_INPUT,
+                output: self.output,
+            }
+        }
+
+        pub fn set(&self) -> io::Result<()> {
+            set_mode(STD_INPUT_HANDLE, self.input)?;
+
+            if let Some(output) = self.output {
+                set_mode(STD_OUTPUT_HANDLE, output)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    pub fn size() -> Option<(u16, u16)> {
+        let mut info = ConsoleScreenBufferInfo::default();
+        if unsafe { GetConsoleScreenBufferInfo(GetStdHandle(STD_OUTPUT_HANDLE), &mut info) } == 0 {
+            return None;
+        }
+
+        let window = info.window;
+        Some((
+            (window.right - window.left + 1) as u16,
+            (window.bottom - window.top + 1) as u16,
+        ))
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use std::io;
+
+    pub struct State;
+
+    impl State {
+        pub fn get() -> Option<Self> {
+            None
+        }
+
+        pub fn raw(&self) -> Self {
+            Self
+        }
+
+        pub fn set(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub fn size() -> Option<(u16, u16)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_size() {
+        let mut cmd = Command::new("pio");
+        apply_size(&mut cmd);
+
+        let columns = cmd
+            .get_envs()
+            .find(|(name, _)| *name == "COLUMNS")
+            .and_then(|(_, value)| value?.to_str()?.parse::<u16>().ok());
+        assert_eq!(columns, size().map(|(columns, _)| columns));
+    }
+
+    #[test]
+    fn test_guard() {
+        // Without a terminal (as in CI), nothing is saved nor restored
+        let guard = Guard::save();
+        assert_eq!(guard.saved.is_some(), is_interactive());
+        drop(guard);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_raw() {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        termios.c_lflag = libc::ICANON | libc::ECHO | libc::ISIG;
+
+        let raw = imp::State(termios).raw();
+        assert_eq!(raw.0.c_lflag & (libc::ICANON | libc::ECHO | libc::ISIG), 0);
+    }
+}
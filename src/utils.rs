@@ -87,12 +87,43 @@ impl OsStrExt for std::ffi::OsString {}
 impl OsStrExt for Path {}
 impl OsStrExt for PathBuf {}
 
+/// A client downloading files over HTTP(S).
+///
+/// [`UreqClient`] (feature `ureq`) is the default one, implement it to use the HTTP
+/// client a build already depends on instead.
+pub trait HttpClient {
+    /// Download the file at `url` to `writer`.
+    fn download(&self, url: &str, writer: &mut dyn std::io::Write) -> Result<()>;
+}
+
+/// The [`HttpClient`] of ureq, see [`download_file_to`].
+#[cfg(feature = "ureq")]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct UreqClient;
+
+#[cfg(feature = "ureq")]
+impl HttpClient for UreqClient {
+    fn download(&self, url: &str, mut writer: &mut dyn std::io::Write) -> Result<()> {
+        download_file_to(url, &mut writer)
+    }
+}
+
 /// Download the file at `url` to `writer`.
 ///
-/// Fails if the response status is not `200` (`OK`).
+/// Fails if the response status is not `200` (`OK`), or if the connection ended before
+/// the length announced by the server was received.
 #[cfg(feature = "ureq")]
 pub fn download_file_to(url: &str, writer: &mut impl std::io::Write) -> Result<()> {
-    let req = ureq::get(url).call()?;
+    download_request_to(ureq::get(url), writer)
+}
+
+/// Send the GET `request` (e.g. with additional headers) and download the response to
+/// `writer`, like [`download_file_to`].
+#[cfg(feature = "ureq")]
+pub fn download_request_to(request: ureq::Request, writer: &mut impl std::io::Write) -> Result<()> {
+    let url = request.url().to_owned();
+    let url = url.as_str();
+    let req = request.call()?;
     if req.status() != 200 {
         anyhow::bail!(
             "Server at url '{}' returned unexpected status {}: {}",
@@ -102,7 +133,26 @@ pub fn download_file_to(url: &str, writer: &mut impl std::io::Write) -> Result<(
         );
     }
 
+    // With a content encoding, the length is the one of the encoded body, which ureq
+    // decodes transparently
+    let expected = req
+        .header("Content-Length")
+        .filter(|_| req.header("Content-Encoding").is_none())
+        .and_then(|len| len.trim().parse::<u64>().ok());
+
     let mut reader = req.into_reader();
-    std::io::copy(&mut reader, writer)?;
+    let received = std::io::copy(&mut reader, writer)?;
+
+    if let Some(expected) = expected {
+        if received != expected {
+            anyhow::bail!(
+                "Download of '{}' is truncated: received {} of {} bytes",
+                url,
+                received,
+                expected
+            );
+        }
+    }
+
     Ok(())
 }
@@ -0,0 +1,406 @@
+//! A minimal WebSocket client (RFC 6455), for the WebSocket bridges of the monitor and
+//! the JSON-RPC API of PIO Home.
+//!
+//! Only unencrypted `ws://` URLs are supported, and messages are sent in single frames.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::error::HintExt;
+
+/// A `ws://<host>[:<port>][/<path>]` URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Url {
+    pub url: String,
+    /// The host, with the port if given.
+    pub host: String,
+    pub path: String,
+    /// The address to connect to, `<host>:<port>`.
+    pub address: String,
+}
+
+impl Url {
+    pub fn parse(url: impl Into<String>) -> Result<Self> {
+        let url = url.into();
+
+        let rest = match url.strip_prefix("ws://") {
+            Some(rest) => rest,
+            None if url.starts_with("wss://") => {
+                return Err(anyhow!("Secure WebSockets are not supported: {}", url))
+                    .hint("Connect to the bridge with ws://, e.g. through a TLS terminating proxy")
+            }
+            None => bail!("Invalid WebSocket URL {}, expected ws://<host>", url),
+        };
+
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+
+        if host.is_empty() {
+            bail!("Invalid WebSocket URL {}, expected ws://<host>", url);
+        }
+
+        let address = if host.rsplit_once(':').is_some() && !host.ends_with(']') {
+            host.to_owned()
+        } else {
+            format!("{}:80", host)
+        };
+
+        Ok(Self {
+            host: host.to_owned(),
+            path: path.to_owned(),
+            address,
+            url,
+        })
+    }
+}
+
+/// Connect to the WebSocket server at `url`.
+pub fn connect(url: &Url) -> Result<WebSocket<TcpStream>> {
+    let stream = TcpStream::connect(&url.address)
+        .with_context(|| anyhow!("Failed to connect to {}", url.url))?;
+
+    handshake(stream, url)
+}
+
+/// Open the WebSocket connection to `url` over `stream`.
+pub fn handshake<S: Read + Write>(mut stream: S, url: &Url) -> Result<WebSocket<S>> {
+    // The key only has to differ between connections, see RFC 6455 section 4.1
+    let key = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_nanos()
+        .to_le_bytes();
+
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        url.path,
+        url.host,
+        encode_base64(&key)
+    )?;
+
+    // Read the response byte by byte, so that no frame data is consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        if stream.read(&mut byte)? == 0 {
+            bail!("{} closed the connection during the handshake", url.url);
+        }
+
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+
+    if status.split_whitespace().nth(1) != Some("101") {
+        bail!("{} refused the WebSocket connection: {}", url.url, status);
+    }
+
+    Ok(WebSocket::new(stream))
+}
+
+/// A frame read by [`WebSocket::read_frame`].
+enum Frame {
+    /// A text, binary or continuation frame, whose payload is buffered.
+    Data { fin: bool },
+    /// A control frame, answered if needed.
+    Control,
+    /// The connection is closed.
+    Closed,
+}
+
+/// A WebSocket connection over `stream`, answering pings.
+///
+/// As a [`Read`], it reads the payloads of all received messages.
+pub struct WebSocket<S> {
+    stream: S,
+    payload: Vec<u8>,
+    offset: usize,
+    closed: bool,
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            payload: Vec::new(),
+            offset: 0,
+            closed: false,
+        }
+    }
+
+    /// Send the text message `text`.
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(1, text.as_bytes())
+    }
+
+    /// Read the next whole message, `None` once the connection is closed.
+    pub fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut message = self.payload.split_off(self.offset);
+        self.payload.clear();
+        self.offset = 0;
+
+        loop {
+            if self.closed {
+                return Ok(None);
+            }
+
+            match self.read_frame()? {
+                Frame::Data { fin } => {
+                    message.append(&mut self.payload);
+
+                    if fin {
+                        return Ok(Some(message));
+                    }
+                }
+                Frame::Control => (),
+                Frame::Closed => self.closed = true,
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<Frame> {
+        let mut header = [0; 2];
+        match self.stream.read_exact(&mut header) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(Frame::Closed),
+            result => result?,
+        }
+
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0f;
+        let masked = header[1] & 0x80 != 0;
+
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                self.stream.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.stream.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+
+        let mut mask = [0; 4];
+        if masked {
+            self.stream.read_exact(&mut mask)?;
+        }
+
+        let mut payload = vec![0; len.try_into().map_err(|_| invalid("Frame too large"))?];
+        self.stream.read_exact(&mut payload)?;
+
+        if masked {
+            for (index, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[index % 4];
+            }
+        }
+
+        match opcode {
+            // Continuation, text and binary frames
+            0..=2 => {
+                self.payload = payload;
+                self.offset = 0;
+
+                Ok(Frame::Data { fin })
+            }
+            // Close
+            8 => {
+                // Best effort, the connection is over anyway
+                let _ = self.write_frame(8, &[]);
+                Ok(Frame::Closed)
+            }
+            // Ping
+            9 => {
+                self.write_frame(10, &payload)?;
+                Ok(Frame::Control)
+            }
+            // Pong
+            10 => Ok(Frame::Control),
+            _ => Err(invalid("Unknown WebSocket opcode")),
+        }
+    }
+
+    /// Write a frame, masked as all frames sent by a client.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mask = [0x5a, 0xa5, 0x3c, 0xc3];
+
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        frame.extend(mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ mask[index % 4]),
+        );
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+impl<S: Read + Write> Read for WebSocket<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.payload.len() {
+            if self.closed {
+                return Ok(0);
+            }
+
+            if let Frame::Closed = self.read_frame()? {
+                self.closed = true;
+            }
+        }
+
+        let len = buf.len().min(self.payload.len() - self.offset);
+        buf[..len].copy_from_slice(&self.payload[self.offset..self.offset + len]);
+        self.offset += len;
+
+        Ok(len)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0_u32, |bits, (index, &byte)| {
+                bits | (byte as u32) << (16 - 8 * index)
+            });
+
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stream reading `input` and recording the written data.
+    struct Stream {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn socket(input: Vec<u8>) -> WebSocket<Stream> {
+        WebSocket::new(Stream {
+            input: io::Cursor::new(input),
+            output: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_websocket() {
+        assert_eq!(encode_base64(b"hello"), "aGVsbG8=");
+        assert_eq!(encode_base64(b"hell"), "aGVsbA==");
+
+        let url = Url::parse("ws://bridge.local:8080/log").unwrap();
+        assert_eq!(url.address, "bridge.local:8080");
+        assert_eq!(url.path, "/log");
+        assert_eq!(Url::parse("ws://bridge").unwrap().address, "bridge:80");
+        assert!(Url::parse("wss://bridge").is_err());
+
+        let long = vec![b'x'; 200];
+        let input = [
+            &[0x81, 2][..],
+            b"ab",
+            &[0x89, 1, b'p'],
+            &[0x82, 126, 0, 200],
+            &long,
+            &[0x88, 0],
+            &[0x81, 1, b'z'],
+        ]
+        .concat();
+
+        let mut reader = socket(input);
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+
+        assert_eq!(data, [&b"ab"[..], &long].concat());
+        assert_eq!(
+            reader.stream.output,
+            [
+                0x8a,
+                0x81,
+                0x5a,
+                0xa5,
+                0x3c,
+                0xc3,
+                b'p' ^ 0x5a,
+                0x88,
+                0x80,
+                0x5a,
+                0xa5,
+                0x3c,
+                0xc3
+            ]
+        );
+
+        // A message in two frames, with a ping in between
+        let mut socket = socket([&[0x01, 2][..], b"ab", &[0x89, 0], &[0x80, 1, b'c']].concat());
+        assert_eq!(socket.read_message().unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(socket.read_message().unwrap(), None);
+
+        socket.stream.output.clear();
+        socket.send_text(&"y".repeat(300)).unwrap();
+        assert_eq!(socket.stream.output[..4], [0x81, 0x80 | 126, 1, 44]);
+        assert_eq!(socket.stream.output.len(), 4 + 4 + 300);
+    }
+}
@@ -0,0 +1,433 @@
+//! Zephyr `west` workspaces, for building Rust staticlibs into Zephyr applications
+//! without PlatformIO.
+//!
+//! A [`Workspace`] is a west workspace with its own Python virtual env, in which
+//! [`WEST_VERSION`] of west and the Python requirements of Zephyr are installed. It
+//! checks out the manifest repository at the pinned [`Manifest::revision`] and updates
+//! the projects of the manifest whenever the pin changes.
+//!
+//! [`Workspace::build`] runs `west build` for an application, linking the Rust staticlib
+//! through an extra Zephyr module generated next to the build directory. The Zephyr
+//! SDK (or another toolchain selected with `ZEPHYR_TOOLCHAIN_VARIANT`) is not managed
+//! and needs to be installed.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::*;
+
+use crate::cmd::Cmd;
+use crate::python::PYTHON;
+use crate::{cmd, python};
+
+/// The version of west installed into the workspaces.
+pub const WEST_VERSION: &str = "1.2.0";
+
+/// The manifest repository of Zephyr itself.
+pub const DEFAULT_MANIFEST_URL: &str = "https://github.com/zephyrproject-rtos/zephyr";
+/// The default revision of the manifest repository.
+pub const DEFAULT_REVISION: &str = "v3.7.0";
+
+/// The file in `.west` the pinned manifest of the last update is stored in.
+const PIN_FILE: &str = "cargo-pio-manifest";
+
+/// The manifest repository of a workspace, pinned to a revision.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub url: String,
+    /// A tag, branch or commit.
+    pub revision: String,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_MANIFEST_URL.into(),
+            revision: DEFAULT_REVISION.into(),
+        }
+    }
+}
+
+impl Manifest {
+    fn pin(&self) -> String {
+        format!("{} {}\n", self.url, self.revision)
+    }
+}
+
+/// A west workspace.
+#[derive(Clone, Debug)]
+pub struct Workspace {
+    pub dir: PathBuf,
+    /// The Python of the virtual env of the workspace.
+    pub python: PathBuf,
+}
+
+impl Workspace {
+    /// Open the workspace in `dir`, creating it and its virtual env if needed and
+    /// updating it if `manifest` is not the one of its last update.
+    pub fn install(dir: impl AsRef<Path>, manifest: &Manifest) -> Result<Self> {
+        let dir = dir.as_ref();
+
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create the west workspace '{}'", dir.display()))?;
+
+        let workspace = Self {
+            dir: dir.canonicalize()?,
+            python: venv(dir)?,
+        };
+
+        let pin_file = dir.join(".west").join(PIN_FILE);
+
+        if !dir.join(".west").exists() {
+            info!(
+                "Initializing the west workspace {} from {} at {}",
+                dir.display(),
+                manifest.url,
+                manifest.revision
+            );
+
+            cmd!(
+                &workspace.python,
+                "-m",
+                "west",
+                "init",
+                "-m",
+                &manifest.url,
+                "--mr",
+                &manifest.revision,
+                &workspace.dir
+            )
+            .run()?;
+        } else if fs::read_to_string(&pin_file).ok().as_deref() == Some(&manifest.pin()) {
+            return Ok(workspace);
+        } else {
+            info!(
+                "Checking out {} of the west manifest {}",
+                manifest.revision, manifest.url
+            );
+
+            let manifest_dir = workspace.manifest_dir()?;
+
+            cmd!(
+                "git",
+                "-C",
+                &manifest_dir,
+                "fetch",
+                &manifest.url,
+                &manifest.revision
+            )
+            .run()?;
+            cmd!(
+                "git",
+                "-C",
+                &manifest_dir,
+                "checkout",
+                "--detach",
+                "FETCH_HEAD"
+            )
+            .run()?;
+        }
+
+        workspace.update()?;
+
+        fs::write(pin_file, manifest.pin())?;
+
+        Ok(workspace)
+    }
+
+    /// A `west` command run in the workspace.
+    pub fn west(&self) -> Cmd {
+        cmd!(&self.python, "-m", "west";
+             current_dir=(&self.dir), env=("ZEPHYR_BASE", self.zephyr_base()))
+    }
+
+    /// The Zephyr repository of the workspace.
+    pub fn zephyr_base(&self) -> PathBuf {
+        self.dir.join("zephyr")
+    }
+
+    /// The directory of the manifest repository.
+    pub fn manifest_dir(&self) -> Result<PathBuf> {
+        let mut cmd = self.west();
+        cmd.args(["manifest", "--path"]);
+
+        let manifest = cmd.stdout()?;
+
+        Path::new(manifest.trim())
+            .parent()
+            .map(Path::to_owned)
+            .context("`west manifest --path` printed no manifest file")
+    }
+
+    /// Update the projects of the manifest, the Python requirements of Zephyr and the
+    /// Zephyr CMake package.
+    pub fn update(&self) -> Result<()> {
+        info!("Updating the west workspace {}", self.dir.display());
+
+        let mut cmd = self.west();
+        cmd.args(["update", "--narrow"]);
+        cmd.run()?;
+
+        let requirements = self
+            .zephyr_base()
+            .join("scripts")
+            .join("requirements-base.txt");
+        if requirements.exists() {
+            cmd!(
+                &self.python,
+                "-m",
+                "pip",
+                "install",
+                "--quiet",
+                "-r",
+                &requirements
+            )
+            .run()?;
+        }
+
+        let mut cmd = self.west();
+        cmd.arg("zephyr-export");
+        cmd.run()?;
+
+        Ok(())
+    }
+
+    /// Build the application in `app_dir` for the Zephyr `board` into `build_dir`, linking
+    /// the Rust staticlib `rust_lib`, and return the path of the `zephyr.elf`.
+    ///
+    /// `cmake_args` are passed to CMake, e.g. `-DCONF_FILE=prj_release.conf`.
+    pub fn build(
+        &self,
+        app_dir: impl AsRef<Path>,
+        board: &str,
+        build_dir: impl AsRef<Path>,
+        rust_lib: Option<&Path>,
+        cmake_args: &[impl AsRef<OsStr>],
+    ) -> Result<PathBuf> {
+        let build_dir = build_dir.as_ref();
+
+        let mut cmd = self.west();
+        cmd.args([
+            "build",
+            "--pristine",
+            "auto",
+            "--board",
+            board,
+            "--build-dir",
+        ])
+        .arg(build_dir)
+        .arg(app_dir.as_ref())
+        .arg("--");
+
+        if let Some(rust_lib) = rust_lib {
+            let module = build_dir.with_file_name(format!(
+                "{}-rust-module",
+                build_dir
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default()
+            ));
+            rust_module(&module, rust_lib)?;
+
+            cmd.arg(format!("-DEXTRA_ZEPHYR_MODULES={}", cmake_path(&module)));
+        }
+
+        cmd.args(cmake_args);
+        cmd.run()?;
+
+        Ok(build_dir.join("zephyr").join("zephyr.elf"))
+    }
+}
+
+/// Create the Python virtual env of the workspace in `dir` with west installed, if it
+/// does not exist yet, and return its Python.
+fn venv(dir: &Path) -> Result<PathBuf> {
+    let venv = dir.join(".venv");
+
+    let python = if cfg!(windows) {
+        venv.join("Scripts").join("python.exe")
+    } else {
+        venv.join("bin").join("python")
+    };
+
+    if !python.exists() {
+        python::check_python_at_least(3, 8)?;
+
+        info!("Creating the Python virtual env {}", venv.display());
+        cmd!(PYTHON, "-m", "venv", &venv).run()?;
+    }
+
+    let installed = cmd!(&python, "-m", "west", "--version"; ignore_exitcode=())
+        .stdout()
+        .unwrap_or_default();
+
+    if !installed.contains(WEST_VERSION) {
+        info!("Installing west {}", WEST_VERSION);
+        cmd!(
+            &python,
+            "-m",
+            "pip",
+            "install",
+            "--quiet",
+            format!("west=={}", WEST_VERSION)
+        )
+        .run()?;
+    }
+
+    Ok(python)
+}
+
+/// Generate the Zephyr module in `dir` linking the staticlib `rust_lib`.
+fn rust_module(dir: &Path, rust_lib: &Path) -> Result<()> {
+    if !rust_lib.exists() {
+        bail!("The Rust staticlib '{}' does not exist", rust_lib.display());
+    }
+
+    fs::create_dir_all(dir.join("zephyr"))?;
+
+    fs::write(
+        dir.join("zephyr").join("module.yml"),
+        "name: cargo-pio-rust\nbuild:\n  cmake: .\n",
+    )?;
+    fs::write(
+        dir.join("CMakeLists.txt"),
+        format!(
+            "# Generated by cargo-pio\nzephyr_library_import(cargo_pio_rust \"{}\")\n",
+            cmake_path(&rust_lib.canonicalize()?)
+        ),
+    )?;
+
+    Ok(())
+}
+
+fn cmake_path(path: &Path) -> String {
+    path.display().to_string().replace('\\', "/")
+}
+
+/// A board known to both PlatformIO and Zephyr.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Board {
+    /// The PlatformIO board id.
+    pub pio: &'static str,
+    /// The Zephyr board, as `<board>[/<soc>[/<cpu cluster>]]`.
+    pub zephyr: &'static str,
+    /// The Rust target of the board.
+    pub target: &'static str,
+}
+
+/// The boards known to both PlatformIO and Zephyr.
+pub const BOARDS: &[Board] = &[
+    Board {
+        pio: "nrf52840_dk",
+        zephyr: "nrf52840dk/nrf52840",
+        target: "thumbv7em-none-eabihf",
+    },
+    Board {
+        pio: "nrf52_dk",
+        zephyr: "nrf52dk/nrf52832",
+        target: "thumbv7em-none-eabihf",
+    },
+    Board {
+        pio: "bbcmicrobit_v2",
+        zephyr: "bbc_microbit_v2",
+        target: "thumbv7em-none-eabihf",
+    },
+    Board {
+        pio: "nucleo_f401re",
+        zephyr: "nucleo_f401re",
+        target: "thumbv7em-none-eabihf",
+    },
+    Board {
+        pio: "nucleo_l476rg",
+        zephyr: "nucleo_l476rg",
+        target: "thumbv7em-none-eabihf",
+    },
+    Board {
+        pio: "disco_l475vg_iot01a",
+        zephyr: "disco_l475vg_iot01a",
+        target: "thumbv7em-none-eabihf",
+    },
+    Board {
+        pio: "frdm_k64f",
+        zephyr: "frdm_k64f",
+        target: "thumbv7em-none-eabihf",
+    },
+    Board {
+        pio: "pico",
+        zephyr: "rpi_pico",
+        target: "thumbv6m-none-eabi",
+    },
+    Board {
+        pio: "esp32dev",
+        zephyr: "esp32_devkitc_wroom/esp32/procpu",
+        target: "xtensa-esp32-none-elf",
+    },
+    Board {
+        pio: "esp32-c3-devkitm-1",
+        zephyr: "esp32c3_devkitm",
+        target: "riscv32imc-unknown-none-elf",
+    },
+];
+
+/// The board `name`, either a PlatformIO board id or a Zephyr board.
+pub fn board(name: &str) -> Option<&'static Board> {
+    BOARDS
+        .iter()
+        .find(|board| board.pio == name || board.zephyr == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_board() {
+        assert_eq!(board("nrf52840_dk").unwrap().zephyr, "nrf52840dk/nrf52840");
+        assert_eq!(board("rpi_pico").unwrap().pio, "pico");
+        assert!(board("esp32-s3-devkitc-1").is_none());
+
+        // Every board is found by both of its names
+        for known in BOARDS {
+            assert_eq!(board(known.pio), Some(known));
+            assert_eq!(board(known.zephyr), Some(known));
+        }
+    }
+
+    #[test]
+    fn test_rust_module() {
+        let dir = std::env::temp_dir().join(format!("embuild-zephyr-{}", std::process::id()));
+        let rust_lib = dir.join("libapp.a");
+        let module = dir.join("build-rust-module");
+
+        fs::create_dir_all(&dir).unwrap();
+        assert!(rust_module(&module, &rust_lib).is_err());
+
+        fs::write(&rust_lib, "!<arch>\n").unwrap();
+        rust_module(&module, &rust_lib).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(module.join("zephyr").join("module.yml")).unwrap(),
+            "name: cargo-pio-rust\nbuild:\n  cmake: .\n"
+        );
+        assert!(fs::read_to_string(module.join("CMakeLists.txt"))
+            .unwrap()
+            .contains(&format!(
+                "zephyr_library_import(cargo_pio_rust \"{}\")",
+                cmake_path(&rust_lib.canonicalize().unwrap())
+            )));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_pin() {
+        let manifest = Manifest::default();
+        assert_eq!(
+            manifest.pin(),
+            "https://github.com/zephyrproject-rtos/zephyr v3.7.0\n"
+        );
+        assert_eq!(cmake_path(Path::new(r"C:\zephyr\app")), "C:/zephyr/app");
+    }
+}